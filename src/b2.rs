@@ -0,0 +1,483 @@
+//! Thin wrappers around B2 API calls that raze does not provide
+//!
+//! These use the same `B2Auth` as raze, talking to the API directly with reqwest
+//! Every call returns the parsed JSON response or an error describing why it failed
+//!
+//! Large files:
+//! Files larger than what we want to send in a single request are uploaded in parts
+//! b2_start_large_file -> b2_get_upload_part_url -> b2_upload_part (n times) -> b2_finish_large_file
+//! Each part except the last must be at least 5MB. A file can have at most 10000 parts
+//!
+//! Async versions of the calls used for buffered transfers (see transfer.rs) are at the end
+//! They use an async reqwest client, and return errors that can be sent between threads
+
+use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+use std::error::Error;
+use std::collections::HashMap;
+use std::io::Read;
+use raze::api::{B2Auth, UploadAuth};
+use reqwest::blocking::Client;
+use crate::colorutil::printdebug;
+use crate::retry;
+use reqwest::StatusCode;
+use reqwest::header::HeaderMap;
+use std::time::Duration;
+
+// Files larger than this are uploaded using the large file API
+pub const LARGE_FILE_THRESHOLD: u64 = 200*1000*1000;
+// Size of each part of a large file. Every part is held in memory while uploading it
+pub const LARGE_FILE_PART_SIZE: u64 = 100*1000*1000;
+// Largest file b2_copy_file can copy, larger ones would need to be copied in parts
+pub const MAX_COPY_SIZE: u64 = 5*1000*1000*1000;
+// Every call other than this goes to the apiUrl in the authorization
+const AUTHORIZE_URL: &str = "https://api.backblazeb2.com/b2api/v2/b2_authorize_account";
+
+/// Error returned by B2 when a call fails
+#[derive(Deserialize, Debug)]
+pub struct B2ApiError {
+    pub status: u16,
+    pub code: String,
+    pub message: String,
+    // From the Retry-After header, see retry.rs
+    #[serde(skip)]
+    pub retry_after: Option<Duration>,
+}
+
+impl B2ApiError {
+    // The error in a failed response, with how long B2 asked to wait before retrying
+    // Overloaded servers may not answer with JSON, the error is then made from the status alone
+    fn from_response(status: StatusCode, headers: &HeaderMap, bytes: &[u8]) -> Self {
+        let mut error = serde_json::from_slice::<B2ApiError>(bytes).unwrap_or_else(|_| B2ApiError {
+            status: status.as_u16(),
+            code: status.canonical_reason().unwrap_or_default().to_lowercase().replace(' ', "_"),
+            message: String::from_utf8_lossy(bytes).trim().to_string(),
+            retry_after: None,
+        });
+        error.retry_after = retry::retry_after(status.as_u16(), headers);
+        error
+    }
+}
+
+impl std::fmt::Display for B2ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "B2 error {} ({}): {}", self.status, self.code, self.message)
+    }
+}
+
+impl Error for B2ApiError {}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct StartLargeFileParams<'a> {
+    bucket_id: &'a str,
+    file_name: &'a str,
+    content_type: &'a str,
+    file_info: HashMap<&'a str, String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct LargeFile {
+    pub file_id: String,
+    pub file_name: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadPartAuth {
+    pub file_id: String,
+    pub upload_url: String,
+    pub authorization_token: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadedPart {
+    pub part_number: u32,
+    pub content_length: u64,
+    pub content_sha1: String,
+}
+
+// Sends a POST to the given API call with a JSON body, parsing the response as JSON
+fn api_call<P: Serialize, T: DeserializeOwned>(client: &Client, auth: &B2Auth, call: &str, params: &P) -> Result<T, Box<dyn Error>> {
+    let url = format!("{}/b2api/v2/{}", auth.api_url, call);
+    let body = serde_json::to_vec(params)?;
+    printdebug(format!("B2 request: POST {} ({} bytes)", url, body.len()));
+    let response = client.post(&url)
+        .header("Authorization", &auth.authorization_token)
+        .body(body)
+        .send()?;
+    parse_response(response)
+}
+
+fn parse_response<T: DeserializeOwned>(response: reqwest::blocking::Response) -> Result<T, Box<dyn Error>> {
+    let status = response.status();
+    let success = status.is_success();
+    let url = response.url().to_string();
+    let headers = response.headers().clone();
+    let bytes = response.bytes()?;
+    printdebug(format!("B2 response: {} from {} ({} bytes)", status, url, bytes.len()));
+    if success {
+        Ok(serde_json::from_slice::<T>(&bytes)?)
+    } else {
+        Err(Box::new(B2ApiError::from_response(status, &headers, &bytes)))
+    }
+}
+
+/// Prepares a large file upload, returning the ID of the new file
+pub fn b2_start_large_file(client: &Client, auth: &B2Auth, bucket_id: &str, file_name: &str, last_modified_millis: u64) -> Result<LargeFile, Box<dyn Error>> {
+    let mut file_info = HashMap::new();
+    file_info.insert("src_last_modified_millis", last_modified_millis.to_string());
+    let params = StartLargeFileParams {
+        bucket_id,
+        file_name,
+        content_type: "b2/x-auto",
+        file_info,
+    };
+    api_call(client, auth, "b2_start_large_file", &params)
+}
+
+/// Gets an URL for uploading parts of the given large file
+/// Like regular upload URLs, only one thread may use it at a time
+pub fn b2_get_upload_part_url(client: &Client, auth: &B2Auth, file_id: &str) -> Result<UploadPartAuth, Box<dyn Error>> {
+    let mut params = HashMap::new();
+    params.insert("fileId", file_id);
+    api_call(client, auth, "b2_get_upload_part_url", &params)
+}
+
+/// Uploads a single part of a large file
+/// Part numbers start at 1. 'data' is expected to have the hex SHA1 of the content appended,
+/// e.g. by wrapping it in `raze::util::ReadHashAtEnd`. 'length' is the length *without* the hash
+pub fn b2_upload_part<R: Read + Send + 'static>(client: &Client, part_auth: &UploadPartAuth, part_number: u32, data: R, length: u64) -> Result<UploadedPart, Box<dyn Error>> {
+    printdebug(format!("B2 request: POST {} (part {}, {} bytes)", part_auth.upload_url, part_number, length));
+    let response = client.post(&part_auth.upload_url)
+        .header("Authorization", &part_auth.authorization_token)
+        .header("X-Bz-Part-Number", part_number.to_string())
+        .header("X-Bz-Content-Sha1", "hex_digits_at_end")
+        .body(reqwest::blocking::Body::sized(data, length+40))
+        .send()?;
+    parse_response(response)
+}
+
+/// Combines the uploaded parts into a single file
+/// 'part_sha1s' must contain the SHA1 of every part, in order
+pub fn b2_finish_large_file(client: &Client, auth: &B2Auth, file_id: &str, part_sha1s: Vec<String>) -> Result<LargeFile, Box<dyn Error>> {
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Params<'a> {
+        file_id: &'a str,
+        part_sha1_array: Vec<String>,
+    }
+    api_call(client, auth, "b2_finish_large_file", &Params { file_id, part_sha1_array: part_sha1s })
+}
+
+/// Cancels an unfinished large file, deleting all parts uploaded so far
+pub fn b2_cancel_large_file(client: &Client, auth: &B2Auth, file_id: &str) -> Result<LargeFile, Box<dyn Error>> {
+    let mut params = HashMap::new();
+    params.insert("fileId", file_id);
+    api_call(client, auth, "b2_cancel_large_file", &params)
+}
+
+/// Copies a file within the bucket, without downloading and uploading it again
+/// The copy keeps the file info of the source, e.g. its modified time
+pub fn b2_copy_file(client: &Client, auth: &B2Auth, source_file_id: &str, file_name: &str) -> Result<LargeFile, Box<dyn Error>> {
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Params<'a> {
+        source_file_id: &'a str,
+        file_name: &'a str,
+    }
+    api_call(client, auth, "b2_copy_file", &Params { source_file_id, file_name })
+}
+
+/// A version of a file, as returned by b2_list_file_versions
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct FileVersion {
+    pub file_id: String,
+    pub file_name: String,
+    // "upload", "hide", "start" (unfinished large file) or "folder"
+    pub action: String,
+    pub content_length: u64,
+    pub upload_timestamp: u64,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FileVersionList {
+    pub files: Vec<FileVersion>,
+    // Where to continue listing, None if there is nothing more
+    pub next_file_name: Option<String>,
+    pub next_file_id: Option<String>,
+}
+
+/// Lists versions of files in the bucket, sorted by name and then newest first
+/// Continues from 'start_file_name' and 'start_file_id' if given
+pub fn b2_list_file_versions(client: &Client, auth: &B2Auth, bucket_id: &str, start_file_name: Option<&str>,
+                             start_file_id: Option<&str>, max_file_count: u32) -> Result<FileVersionList, Box<dyn Error>> {
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Params<'a> {
+        bucket_id: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        start_file_name: Option<&'a str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        start_file_id: Option<&'a str>,
+        max_file_count: u32,
+    }
+    api_call(client, auth, "b2_list_file_versions", &Params { bucket_id, start_file_name, start_file_id, max_file_count })
+}
+
+/// Downloads a specific version of a file
+/// The response body is the file's content
+pub fn b2_download_file_by_id(client: &Client, auth: &B2Auth, file_id: &str) -> Result<reqwest::blocking::Response, Box<dyn Error>> {
+    let url = format!("{}/b2api/v2/b2_download_file_by_id", auth.download_url);
+    printdebug(format!("B2 request: GET {} (file {})", url, file_id));
+    let response = client.get(&url)
+        .header("Authorization", &auth.authorization_token)
+        .query(&[("fileId", file_id)])
+        .send()?;
+    printdebug(format!("B2 response: {} ({} bytes)", response.status(), response.content_length().map_or("?".to_string(), |l| l.to_string())));
+    if response.status().is_success() {
+        Ok(response)
+    } else {
+        let (status, headers) = (response.status(), response.headers().clone());
+        Err(Box::new(B2ApiError::from_response(status, &headers, &response.bytes()?)))
+    }
+}
+
+/// The value of a Range header for up to 'limit' bytes from byte 'offset' on, or all of them if None
+pub fn range(offset: u64, limit: Option<u64>) -> String {
+    match limit {
+        Some(limit) => format!("bytes={}-{}", offset, offset + limit.max(1) - 1),
+        None => format!("bytes={}-", offset),
+    }
+}
+
+/// Downloads a file from byte 'offset' on, up to 'limit' bytes if set, by ID if one is given, else the latest version by name
+/// Fails if B2 sends the whole file instead, s.t. the caller doesn't mistake its start for the requested range
+pub fn b2_download_file_range(client: &Client, auth: &B2Auth, bucket_name: &str, file_name: &str, file_id: Option<&str>,
+                              offset: u64, limit: Option<u64>) -> Result<reqwest::blocking::Response, Box<dyn Error>> {
+    let request = match file_id {
+        Some(id) => client.get(&format!("{}/b2api/v2/b2_download_file_by_id", auth.download_url)).query(&[("fileId", id)]),
+        None => client.get(&format!("{}/file/{}/{}", auth.download_url, bucket_name, encode_name(file_name))),
+    };
+    printdebug(format!("B2 request: GET {} ({}) from byte {}", file_name, file_id.unwrap_or("latest version"), offset));
+    let response = request
+        .header("Authorization", &auth.authorization_token)
+        .header("Range", range(offset, limit))
+        .send()?;
+    printdebug(format!("B2 response: {} ({} bytes)", response.status(), response.content_length().map_or("?".to_string(), |l| l.to_string())));
+    if response.status() == StatusCode::PARTIAL_CONTENT {
+        Ok(response)
+    } else if response.status().is_success() {
+        Err(format!("B2 sent all of {} instead of the range from byte {}", file_name, offset).into())
+    } else {
+        let (status, headers) = (response.status(), response.headers().clone());
+        Err(Box::new(B2ApiError::from_response(status, &headers, &response.bytes()?)))
+    }
+}
+
+/// A lifecycle rule of a bucket, see lifecycle.rs
+/// Unset days are sent as null, which B2 takes as the rule not doing that
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct LifecycleRule {
+    pub file_name_prefix: String,
+    pub days_from_hiding_to_deleting: Option<u32>,
+    pub days_from_uploading_to_hiding: Option<u32>,
+}
+
+/// A bucket, as returned by b2_list_buckets and b2_update_bucket
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Bucket {
+    pub bucket_id: String,
+    pub bucket_name: String,
+    #[serde(default)]
+    pub lifecycle_rules: Vec<LifecycleRule>,
+}
+
+/// Lists the bucket with the given name, including what raze leaves out of it (e.g. its lifecycle rules)
+/// The list is empty if there is no such bucket
+pub fn b2_list_buckets(client: &Client, auth: &B2Auth, bucket_name: &str) -> Result<Vec<Bucket>, Box<dyn Error>> {
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Params<'a> {
+        account_id: &'a str,
+        bucket_name: &'a str,
+    }
+    #[derive(Deserialize)]
+    struct BucketList {
+        buckets: Vec<Bucket>,
+    }
+    let list: BucketList = api_call(client, auth, "b2_list_buckets", &Params { account_id: &auth.account_id, bucket_name })?;
+    Ok(list.buckets)
+}
+
+/// Creates a private bucket with the given lifecycle rules
+/// Bucket names are unique across all of B2, the call fails if the name is taken
+pub fn b2_create_bucket(client: &Client, auth: &B2Auth, bucket_name: &str, lifecycle_rules: &[LifecycleRule]) -> Result<Bucket, Box<dyn Error>> {
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Params<'a> {
+        account_id: &'a str,
+        bucket_name: &'a str,
+        bucket_type: &'a str,
+        lifecycle_rules: &'a [LifecycleRule],
+    }
+    api_call(client, auth, "b2_create_bucket", &Params { account_id: &auth.account_id, bucket_name, bucket_type: "allPrivate", lifecycle_rules })
+}
+
+/// What an application key may do, as returned by b2_authorize_account. See capabilities.rs
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct KeyAllowed {
+    // e.g. "listFiles", "writeFiles"
+    pub capabilities: Vec<String>,
+    // Set if the key is restricted to one bucket
+    pub bucket_id: Option<String>,
+    pub bucket_name: Option<String>,
+    // Set if the key is restricted to names starting with it
+    pub name_prefix: Option<String>,
+}
+
+/// Authorizes the application key in 'keystring' ("KEY_ID:KEY") like raze's b2_authorize_account
+/// Also returns what the key may do, which raze leaves out of B2Auth
+pub fn b2_authorize_account(client: &Client, keystring: &str) -> Result<(B2Auth, KeyAllowed), Box<dyn Error>> {
+    #[derive(Deserialize)]
+    struct Authorization {
+        #[serde(flatten)]
+        auth: B2Auth,
+        allowed: KeyAllowed,
+    }
+    let (id, key) = keystring.split_at(keystring.find(':').ok_or("the key must be given as KEY_ID:KEY")?);
+    printdebug(format!("B2 request: GET {}", AUTHORIZE_URL));
+    let response = client.get(AUTHORIZE_URL).basic_auth(id, Some(&key[1..])).send()?;
+    let authorization: Authorization = parse_response(response)?;
+    Ok((authorization.auth, authorization.allowed))
+}
+
+/// Replaces the lifecycle rules of a bucket, returning the updated bucket
+pub fn b2_update_bucket(client: &Client, auth: &B2Auth, bucket_id: &str, lifecycle_rules: &[LifecycleRule]) -> Result<Bucket, Box<dyn Error>> {
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Params<'a> {
+        account_id: &'a str,
+        bucket_id: &'a str,
+        lifecycle_rules: &'a [LifecycleRule],
+    }
+    api_call(client, auth, "b2_update_bucket", &Params { account_id: &auth.account_id, bucket_id, lifecycle_rules })
+}
+
+/// Error returned by the async calls
+pub type AsyncError = Box<dyn Error + Send + Sync>;
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct UploadUrl {
+    bucket_id: String,
+    upload_url: String,
+    authorization_token: String,
+}
+
+async fn parse_response_async<T: DeserializeOwned>(response: reqwest::Response) -> Result<T, AsyncError> {
+    let status = response.status();
+    let url = response.url().to_string();
+    let headers = response.headers().clone();
+    let bytes = response.bytes().await?;
+    printdebug(format!("B2 response: {} from {} ({} bytes)", status, url, bytes.len()));
+    if status.is_success() {
+        Ok(serde_json::from_slice::<T>(&bytes)?)
+    } else {
+        Err(Box::new(B2ApiError::from_response(status, &headers, &bytes)))
+    }
+}
+
+/// Gets an URL for uploading files, like raze's b2_get_upload_url
+/// Only one upload at a time may use it
+pub async fn b2_get_upload_url_async(client: &reqwest::Client, auth: &B2Auth, bucket_id: &str) -> Result<UploadAuth, AsyncError> {
+    let url = format!("{}/b2api/v2/b2_get_upload_url", auth.api_url);
+    let mut params = HashMap::new();
+    params.insert("bucketId", bucket_id);
+    printdebug(format!("B2 request: POST {}", url));
+    let response = client.post(&url)
+        .header("Authorization", &auth.authorization_token)
+        .body(serde_json::to_vec(&params)?)
+        .send()
+        .await?;
+    let upload_url: UploadUrl = parse_response_async(response).await?;
+    Ok(UploadAuth {
+        bucket_id: upload_url.bucket_id,
+        upload_url: upload_url.upload_url,
+        authorization_token: upload_url.authorization_token,
+    })
+}
+
+/// Uploads a file in a single request, returning the ID of the uploaded version
+/// 'length' is the length of the body and 'sha1' its hex SHA1
+pub async fn b2_upload_file_async(client: &reqwest::Client, upload_auth: &UploadAuth, file_name: &str, body: reqwest::Body,
+                                  length: u64, sha1: &str, last_modified_millis: u64) -> Result<String, AsyncError> {
+    printdebug(format!("B2 request: POST {} ({}, {} bytes)", upload_auth.upload_url, file_name, length));
+    let response = client.post(&upload_auth.upload_url)
+        .header("Authorization", &upload_auth.authorization_token)
+        .header("X-Bz-File-Name", encode_name(file_name))
+        .header("Content-Type", "b2/x-auto")
+        .header("Content-Length", length)
+        .header("X-Bz-Content-Sha1", sha1)
+        .header("X-Bz-Info-src_last_modified_millis", last_modified_millis)
+        .body(body)
+        .send()
+        .await?;
+    parse_response_async::<LargeFile>(response).await.map(|f| f.file_id)
+}
+
+/// Downloads the file named 'file_name', or the version of it with the given ID
+/// The response body is the file's content
+pub async fn b2_download_file_async(client: &reqwest::Client, auth: &B2Auth, bucket_name: &str, file_name: &str,
+                                    file_id: Option<&str>) -> Result<reqwest::Response, AsyncError> {
+    let request = match file_id {
+        Some(id) => client.get(&format!("{}/b2api/v2/b2_download_file_by_id", auth.download_url)).query(&[("fileId", id)]),
+        None => client.get(&format!("{}/file/{}/{}", auth.download_url, bucket_name, encode_name(file_name))),
+    };
+    printdebug(format!("B2 request: GET {} ({})", file_name, file_id.unwrap_or("latest version")));
+    let response = request
+        .header("Authorization", &auth.authorization_token)
+        .send()
+        .await?;
+    printdebug(format!("B2 response: {} ({} bytes)", response.status(), response.content_length().map_or("?".to_string(), |l| l.to_string())));
+    if response.status().is_success() {
+        Ok(response)
+    } else {
+        let (status, headers) = (response.status(), response.headers().clone());
+        Err(Box::new(B2ApiError::from_response(status, &headers, &response.bytes().await?)))
+    }
+}
+
+// Percent-encodes a file name for the X-Bz-File-Name header and download URLs, leaving '/' as it is
+fn encode_name(name: &str) -> String {
+    name.bytes().map(|b| match b {
+        b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => (b as char).to_string(),
+        _ => format!("%{:02X}", b),
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::b2::{encode_name, range};
+
+    #[test]
+    fn test_encode_name() {
+        assert_eq!(encode_name("home/user/notes.txt"), "home/user/notes.txt");
+        assert_eq!(encode_name("home/user/my file+1.txt"), "home/user/my%20file%2B1.txt");
+        assert_eq!(encode_name("C:/Users/ユ"), "C%3A/Users/%E3%83%A6");
+    }
+
+    #[test]
+    fn test_range() {
+        assert_eq!(range(10, None), "bytes=10-");
+        assert_eq!(range(0, Some(64)), "bytes=0-63");
+        assert_eq!(range(10, Some(5)), "bytes=10-14");
+    }
+}
@@ -0,0 +1,386 @@
+//! The native B2 API, through raze and the calls in `crate::b2`
+//!
+//! Every upload in progress needs its own upload URL, B2 doesn't allow sharing them
+//! URLs are kept in a pool and reused by later uploads. One that failed is dropped, s.t. the next attempt gets a new one
+//!
+//! The auth token expires after a day. A call that fails because of that marks it expired, and the next call
+//! authorizes the account again first, s.t. a retry of the failed call succeeds
+
+use crate::backend::{Backend, BackendError, Download, RemoteFile};
+use crate::b2::{self, FileVersion};
+use crate::budget::Budget;
+use crate::capabilities::{self, Operation, Problems};
+use crate::colorutil::printcoln;
+use crate::config::Config;
+use crate::retry;
+use crate::transfer;
+use crate::versions;
+use futures::future::BoxFuture;
+use raze::api::{B2Auth, B2DownloadFileByNameParams, ListBucketParams, Sha1Variant, UploadAuth};
+use reqwest::blocking::Client;
+use std::io::{Cursor, Read};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+use termcolor::Color;
+
+pub struct B2Backend {
+    pub client: Client, // Used by the blocking calls
+    async_client: reqwest::Client, // Used by buffered transfers
+    auth: RwLock<B2Auth>,
+    // Set when a call found the auth token expired, see auth
+    expired: AtomicBool,
+    keystring: String,
+    pub bucket_id: String,
+    pub bucket_name: String,
+    budget: Arc<Budget>,
+    // What the App Key may do
+    allowed: b2::KeyAllowed,
+    // Upload URLs that aren't in use
+    upload_urls: Mutex<Vec<UploadAuth>>,
+}
+
+impl B2Backend {
+    /// Authenticates and resolves the bucket name to its ID
+    pub fn connect(config: &Config, budget: Arc<Budget>, t_start: Instant) -> Result<Self, String> {
+        let client = Client::builder().timeout(None).connect_timeout(transfer::CONNECT_TIMEOUT).build().map_err(|e| e.to_string())?;
+        let async_client = transfer::client()?;
+        printcoln(Color::Green, format!("[{:.3}] Authenticating...", t_start.elapsed().as_secs_f32()));
+
+        let keystring = config.keystring()?;
+        budget.spend("b2_authorize_account").map_err(|e| format!("Aborting: {}", e))?;
+        let (auth, allowed) = b2::b2_authorize_account(&client, &keystring)
+            .map_err(|_| "Authentication failure".to_string())?;
+        printcoln(Color::Green, format!("[{:.3}] Success", t_start.elapsed().as_secs_f32()));
+
+        // A key that can't access the bucket is refused now, instead of failing mid-run
+        let bucket_name = config.bucket_name.as_ref().unwrap().to_string();
+        capabilities::check_key(&allowed, &bucket_name)?;
+        printcoln(Color::Green, format!("[{:.3}] Resolving bucket name", t_start.elapsed().as_secs_f32()));
+
+        // Note that since we supply a bucket name and names are unique, we should get 0 or 1 results
+        let params = ListBucketParams {
+            bucket_id: None,
+            bucket_name: Some(bucket_name.clone()),
+            bucket_types: None
+        };
+        budget.spend("b2_list_buckets").map_err(|e| format!("Aborting: {}", e))?;
+        let buckets = raze::api::b2_list_buckets(&client, &auth, params)
+            .map_err(|e| format!("Failed to retrieve bucket list ({:?})", e))?;
+        let bucket_id = match buckets.get(0) {
+            Some(res) => res.bucket_id.clone(),
+            None => return Err(format!("No bucket with the name '{}'", bucket_name)),
+        };
+        printcoln(Color::Green, format!("[{:.3}] {} -> {}", t_start.elapsed().as_secs_f32(), bucket_name, bucket_id));
+
+        Ok(B2Backend {
+            client,
+            async_client,
+            auth: RwLock::new(auth),
+            expired: AtomicBool::new(false),
+            keystring,
+            bucket_id,
+            bucket_name,
+            budget,
+            allowed,
+            upload_urls: Mutex::new(Vec::new()),
+        })
+    }
+
+    // The auth to make a call with, authorizing the account again first if a call found the token expired
+    fn auth(&self) -> B2Auth {
+        if self.expired.swap(false, Ordering::SeqCst) {
+            self.budget.record("b2_authorize_account", 1);
+            match raze::api::b2_authorize_account(&self.client, &self.keystring) {
+                Ok(auth) => *self.auth.write().unwrap() = auth,
+                Err(e) => self.reauthorize_failed(format!("{:?}", e)),
+            }
+        }
+        self.auth.read().unwrap().clone()
+    }
+
+    async fn auth_async(&self) -> B2Auth {
+        if self.expired.swap(false, Ordering::SeqCst) {
+            self.budget.record("b2_authorize_account", 1);
+            let (client, keystring) = (self.client.clone(), self.keystring.clone());
+            match transfer::blocking(move || raze::api::b2_authorize_account(&client, &keystring)).await {
+                Ok(auth) => *self.auth.write().unwrap() = auth,
+                Err(e) => self.reauthorize_failed(format!("{:?}", e)),
+            }
+        }
+        self.auth.read().unwrap().clone()
+    }
+
+    // The next call tries again
+    fn reauthorize_failed(&self, reason: String) {
+        printcoln(Color::Yellow, format!("Failed to authenticate again ({})", reason));
+        self.expired.store(true, Ordering::SeqCst);
+    }
+
+    // Converts the error of a call made with the account's auth, marking the token expired if that is why it failed
+    fn raze_error(&self, e: raze::Error) -> BackendError {
+        if let raze::Error::B2Error(b2) = &e {
+            self.expire(retry::is_expired_token(b2.status, &b2.code));
+        }
+        raze_error(e)
+    }
+
+    fn boxed_error(&self, e: Box<dyn std::error::Error>) -> BackendError {
+        self.expire(retry::is_expired_error(e.as_ref()));
+        boxed_error(e)
+    }
+
+    fn async_error(&self, e: b2::AsyncError) -> BackendError {
+        self.expire(retry::is_expired_error(e.as_ref()));
+        async_error(e)
+    }
+
+    fn expire(&self, expired: bool) {
+        if expired {
+            self.expired.store(true, Ordering::SeqCst);
+        }
+    }
+
+    // Takes an upload URL from the pool, or gets a new one if none is free
+    fn upload_url(&self) -> Result<UploadAuth, BackendError> {
+        if let Some(upauth) = self.upload_urls.lock().unwrap().pop() {
+            return Ok(upauth);
+        }
+        self.budget.record("b2_get_upload_url", 1);
+        raze::api::b2_get_upload_url(&self.client, &self.auth(), &self.bucket_id).map_err(|e| self.raze_error(e))
+    }
+
+    async fn upload_url_async(&self) -> Result<UploadAuth, BackendError> {
+        if let Some(upauth) = self.upload_urls.lock().unwrap().pop() {
+            return Ok(upauth);
+        }
+        self.budget.record("b2_get_upload_url", 1);
+        let auth = self.auth_async().await;
+        b2::b2_get_upload_url_async(&self.async_client, &auth, &self.bucket_id).await.map_err(|e| self.async_error(e))
+    }
+}
+
+impl Backend for B2Backend {
+    fn describe(&self) -> String {
+        format!("B2 bucket {}", self.bucket_name)
+    }
+
+    fn check_access(&self, operation: Operation) -> Problems {
+        capabilities::check(&self.allowed, operation)
+    }
+
+    fn upload(&self, name: &str, data: Box<dyn Read + Send>, length: u64, modified: u64) -> Result<Option<String>, BackendError> {
+        let upauth = self.upload_url()?;
+        let params = raze::api::FileParameters {
+            file_path: name,
+            file_size: length,
+            content_type: None, // auto
+            content_sha1: Sha1Variant::HexAtEnd,
+            last_modified_millis: modified,
+        };
+        self.budget.record("b2_upload_file", 1);
+        let uploaded = raze::api::b2_upload_file(&self.client, &upauth, raze::util::ReadHashAtEnd::wrap(data), params).map_err(raze_error)?;
+        self.upload_urls.lock().unwrap().push(upauth);
+        Ok(uploaded.file_id)
+    }
+
+    fn upload_large(&self, name: &str, data: &mut dyn Read, modified: u64, max_attempts: u32) -> Result<Option<String>, String> {
+        upload_large_file(&self.client, &self.auth(), &self.budget, &self.bucket_id, name, modified, max_attempts, data)
+            .map_err(|e| e.to_string())
+    }
+
+    fn upload_buffered<'a>(&'a self, name: &'a str, data: Arc<Vec<u8>>, body: reqwest::Body, sha1: &'a str,
+                           modified: u64) -> BoxFuture<'a, Result<Option<String>, BackendError>> {
+        Box::pin(async move {
+            let upauth = self.upload_url_async().await?;
+            self.budget.record("b2_upload_file", 1);
+            let file_id = b2::b2_upload_file_async(&self.async_client, &upauth, name, body, data.len() as u64, sha1, modified).await.map_err(async_error)?;
+            self.upload_urls.lock().unwrap().push(upauth);
+            Ok(Some(file_id))
+        })
+    }
+
+    fn download(&self, name: &str, id: Option<&str>) -> Result<Download, BackendError> {
+        let response = match id {
+            Some(id) => b2::b2_download_file_by_id(&self.client, &self.auth(), id).map_err(|e| self.boxed_error(e)),
+            None => {
+                let params = B2DownloadFileByNameParams {
+                    bucket_name: self.bucket_name.clone(),
+                    file_name: name.to_string(),
+                    authorization: None // Falls back to B2Auth
+                };
+                raze::api::b2_download_file_by_name(&self.client, &self.auth(), params).map_err(|e| self.raze_error(e))
+            }
+        }?;
+        Ok(Download::from_response(response))
+    }
+
+    fn download_from(&self, name: &str, id: Option<&str>, offset: u64, limit: Option<u64>) -> Result<Download, BackendError> {
+        b2::b2_download_file_range(&self.client, &self.auth(), &self.bucket_name, name, id, offset, limit)
+            .map(Download::from_response)
+            .map_err(|e| self.boxed_error(e))
+    }
+
+    fn download_buffered<'a>(&'a self, name: &'a str, id: Option<&'a str>) -> BoxFuture<'a, Result<reqwest::Response, BackendError>> {
+        Box::pin(async move {
+            let auth = self.auth_async().await;
+            b2::b2_download_file_async(&self.async_client, &auth, &self.bucket_name, name, id).await.map_err(|e| self.async_error(e))
+        })
+    }
+
+    fn download_call(&self, version: bool) -> &'static str {
+        match version {
+            true => "b2_download_file_by_id",
+            false => "b2_download_file_by_name",
+        }
+    }
+
+    fn list(&self) -> Result<Vec<RemoteFile>, String> {
+        self.budget.spend("b2_list_file_names").map_err(|e| e.to_string())?;
+        let files = raze::util::list_all_files(&self.client, &self.auth(), &self.bucket_id, 10000)
+            .map_err(|e| format!("{:?}", e))?;
+        // Every 10000 files take another call, these can only be counted afterwards
+        self.budget.record("b2_list_file_names", files.len() as u64 / 10000);
+        Ok(files.into_iter().map(|f| RemoteFile {
+            modified: f.file_info.as_ref()
+                .and_then(|info| info.get("src_last_modified_millis"))
+                .and_then(|m| m.parse().ok()),
+            size: Some(f.content_length),
+            // Large files have no SHA1 of their whole content ("none"), and buffered uploads may not be verified yet
+            sha1: f.content_sha1
+                .map(|s| s.trim_start_matches("unverified:").to_string())
+                .filter(|s| s.len() == 40),
+            name: f.file_name,
+            id: f.file_id,
+        }).collect())
+    }
+
+    // Copying needs the ID of the file, which is the newest version of it
+    fn copy(&self, from: &str, to: &str) -> Result<Option<String>, BackendError> {
+        let spend = |call| self.budget.spend(call).map_err(|e| BackendError { retryable: false, retry_after: None, reason: e.to_string() });
+        spend("b2_list_file_versions")?;
+        let listed = b2::b2_list_file_versions(&self.client, &self.auth(), &self.bucket_id, Some(from), None, 1).map_err(|e| self.boxed_error(e))?;
+        let source = match listed.files.first() {
+            Some(v) if v.file_name == from && v.action == "upload" => v,
+            _ => return Err(BackendError { retryable: false, retry_after: None, reason: format!("{} is not in the bucket", from) }),
+        };
+        spend("b2_copy_file")?;
+        b2::b2_copy_file(&self.client, &self.auth(), &source.file_id, to).map(|f| Some(f.file_id)).map_err(|e| self.boxed_error(e))
+    }
+
+    fn hide(&self, name: &str) -> Result<(), BackendError> {
+        self.budget.record("b2_hide_file", 1);
+        raze::api::b2_hide_file(&self.client, &self.auth(), &self.bucket_id, name.to_string()).map(|_| ()).map_err(|e| self.raze_error(e))
+    }
+
+    fn delete(&self, name: &str, id: Option<&str>) -> Result<(), BackendError> {
+        let id = match id {
+            Some(id) => id.to_string(),
+            None => return Err(BackendError { retryable: false, retry_after: None, reason: format!("no file ID given for {}", name) }),
+        };
+        self.budget.record("b2_delete_file_version", 1);
+        raze::api::b2_delete_file_version(&self.client, &self.auth(), name.to_string(), id).map(|_| ()).map_err(|e| self.raze_error(e))
+    }
+
+    fn versions(&self) -> Result<Vec<FileVersion>, String> {
+        versions::list_all_file_versions(&self.client, &self.auth(), &self.budget, &self.bucket_id).map_err(|e| e.to_string())
+    }
+
+    fn prune_manifest_history(&self, name: &str, keep: u32) -> Result<usize, String> {
+        versions::prune_manifest_history(&self.client, &self.auth(), &self.budget, &self.bucket_id, name, keep).map_err(|e| e.to_string())
+    }
+}
+
+fn raze_error(e: raze::Error) -> BackendError {
+    BackendError {
+        retryable: retry::is_retryable(&e),
+        retry_after: None,
+        reason: format!("{:?}", e),
+    }
+}
+
+fn boxed_error(e: Box<dyn std::error::Error>) -> BackendError {
+    BackendError {
+        retryable: retry::is_retryable_boxed(&e),
+        retry_after: retry::retry_after_of(e.as_ref()),
+        reason: e.to_string(),
+    }
+}
+
+fn async_error(e: b2::AsyncError) -> BackendError {
+    BackendError {
+        retryable: retry::is_retryable_error(e.as_ref()),
+        retry_after: retry::retry_after_of(e.as_ref()),
+        reason: e.to_string(),
+    }
+}
+
+// Uploads a file using the large file API, splitting it into parts of LARGE_FILE_PART_SIZE
+// The reader is consumed exactly once, so an encrypted file uses one contiguous range of nonces
+// across all parts, and the finished file decrypts exactly like one uploaded in a single request
+// Each part is attempted up to 'max_attempts' times. If a part cannot be uploaded, the large file is cancelled
+fn upload_large_file(client: &Client, auth: &B2Auth, budget: &Budget, bucket_id: &str, name_in_b2: &str, modified_time: u64, max_attempts: u32, reader: &mut dyn Read) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    budget.record("b2_start_large_file", 1);
+    let large_file = b2::b2_start_large_file(client, auth, bucket_id, name_in_b2, modified_time)?;
+    budget.record("b2_get_upload_part_url", 1);
+    let mut part_auth = b2::b2_get_upload_part_url(client, auth, &large_file.file_id)?;
+
+    let mut part_sha1s = Vec::new();
+    loop {
+        // Buffer the part, s.t. it can be re-sent without reading (and encrypting) it again
+        let mut part = Vec::with_capacity(b2::LARGE_FILE_PART_SIZE as usize);
+        // E.g. the upload was cancelled, the parts uploaded so far are discarded
+        if let Err(e) = (&mut *reader).take(b2::LARGE_FILE_PART_SIZE).read_to_end(&mut part) {
+            cancel_large_file(client, auth, budget, name_in_b2, &large_file.file_id);
+            return Err(e.into());
+        }
+        if part.is_empty() {
+            break;
+        }
+        let part_number = part_sha1s.len() as u32 + 1;
+
+        let mut attempt = 0;
+        loop {
+            let length = part.len() as u64;
+            let data = raze::util::ReadHashAtEnd::wrap(Cursor::new(part.clone()));
+            budget.record("b2_upload_part", 1);
+            match b2::b2_upload_part(client, &part_auth, part_number, data, length) {
+                Ok(uploaded) => {
+                    part_sha1s.push(uploaded.content_sha1);
+                    break;
+                },
+                Err(e) => {
+                    printcoln(Color::Yellow, format!("Upload of part {} of {} failed: {}", part_number, name_in_b2, e));
+                    if !retry::is_retryable_boxed(&e) {
+                        cancel_large_file(client, auth, budget, name_in_b2, &large_file.file_id);
+                        return Err(format!("part {} failed, error is not retryable ({})", part_number, e).into());
+                    }
+                    attempt += 1;
+                    if attempt == max_attempts {
+                        cancel_large_file(client, auth, budget, name_in_b2, &large_file.file_id);
+                        return Err(format!("part {} failed after {} attempts", part_number, max_attempts).into());
+                    }
+                    // Back off and retry with a fresh upload URL, the old one may have expired
+                    std::thread::sleep(retry::delay_for(attempt-1, retry::retry_after_of(e.as_ref())));
+                    budget.record("b2_get_upload_part_url", 1);
+                    if let Ok(a) = b2::b2_get_upload_part_url(client, auth, &large_file.file_id) {
+                        part_auth = a;
+                    }
+                }
+            }
+        }
+    }
+
+    budget.record("b2_finish_large_file", 1);
+    let finished = b2::b2_finish_large_file(client, auth, &large_file.file_id, part_sha1s)?;
+    Ok(Some(finished.file_id))
+}
+
+// Cancels an unfinished large file, s.t. its parts aren't kept (and billed)
+// Only called when the upload failed, a failure to cancel is reported but the error of the upload is the one returned
+fn cancel_large_file(client: &Client, auth: &B2Auth, budget: &Budget, name_in_b2: &str, file_id: &str) {
+    budget.record("b2_cancel_large_file", 1);
+    if let Err(e) = b2::b2_cancel_large_file(client, auth, file_id) {
+        printcoln(Color::Yellow, format!("Failed to cancel the upload of {}, its parts are kept until it is cancelled ({})", name_in_b2, e));
+    }
+}
@@ -0,0 +1,161 @@
+//! The part of the bucket a job (see jobs.rs) stores its files in, s.t. jobs can share a bucket
+//!
+//! The files of a job are stored under '.jobs/NAME/', uploads, downloads and clean of the job only see those,
+//! without the prefix. The backup list of the config stores its files without a prefix, and doesn't see those of jobs
+
+use crate::backend::{Backend, BackendError, Download, RemoteFile};
+use crate::b2::FileVersion;
+use crate::capabilities::{Operation, Problems};
+use crate::jobs;
+use futures::future::BoxFuture;
+use std::io::Read;
+use std::sync::Arc;
+
+pub struct JobBackend {
+    inner: Box<dyn Backend>,
+    // The job whose files these are. None means the backup list of the config
+    job: Option<String>,
+    // What the names of the files start with in the bucket, empty for the backup list of the config
+    prefix: String,
+}
+
+impl JobBackend {
+    pub fn new(inner: Box<dyn Backend>, job: Option<&str>) -> Self {
+        JobBackend {
+            inner,
+            job: job.map(|job| job.to_string()),
+            prefix: job.map(jobs::prefix).unwrap_or_default(),
+        }
+    }
+
+    // Name in the bucket of the file named 'name'
+    fn name(&self, name: &str) -> String {
+        format!("{}{}", self.prefix, name)
+    }
+
+    // Name of the file named 'name' in the bucket, if it is one of these files
+    fn own<'a>(&self, name: &'a str) -> Option<&'a str> {
+        match self.job {
+            Some(_) => name.strip_prefix(&self.prefix),
+            None if name.starts_with(jobs::PREFIX) => None,
+            None => Some(name),
+        }
+    }
+}
+
+impl Backend for JobBackend {
+    fn describe(&self) -> String {
+        match &self.job {
+            Some(job) => format!("{}, job {}", self.inner.describe(), job),
+            None => self.inner.describe(),
+        }
+    }
+
+    fn check_access(&self, operation: Operation) -> Problems {
+        self.inner.check_access(operation)
+    }
+
+    fn upload(&self, name: &str, data: Box<dyn Read + Send>, length: u64, modified: u64) -> Result<Option<String>, BackendError> {
+        self.inner.upload(&self.name(name), data, length, modified)
+    }
+
+    fn upload_large(&self, name: &str, data: &mut dyn Read, modified: u64, max_attempts: u32) -> Result<Option<String>, String> {
+        self.inner.upload_large(&self.name(name), data, modified, max_attempts)
+    }
+
+    fn upload_buffered<'a>(&'a self, name: &'a str, data: Arc<Vec<u8>>, body: reqwest::Body, sha1: &'a str,
+                           modified: u64) -> BoxFuture<'a, Result<Option<String>, BackendError>> {
+        Box::pin(async move {
+            let name = self.name(name);
+            self.inner.upload_buffered(&name, data, body, sha1, modified).await
+        })
+    }
+
+    fn download(&self, name: &str, id: Option<&str>) -> Result<Download, BackendError> {
+        self.inner.download(&self.name(name), id)
+    }
+
+    fn download_from(&self, name: &str, id: Option<&str>, offset: u64, limit: Option<u64>) -> Result<Download, BackendError> {
+        self.inner.download_from(&self.name(name), id, offset, limit)
+    }
+
+    fn download_buffered<'a>(&'a self, name: &'a str, id: Option<&'a str>) -> BoxFuture<'a, Result<reqwest::Response, BackendError>> {
+        Box::pin(async move {
+            let name = self.name(name);
+            self.inner.download_buffered(&name, id).await
+        })
+    }
+
+    fn download_call(&self, version: bool) -> &'static str {
+        self.inner.download_call(version)
+    }
+
+    fn list(&self) -> Result<Vec<RemoteFile>, String> {
+        Ok(self.inner.list()?.into_iter()
+            .filter_map(|file| {
+                let name = self.own(&file.name)?.to_string();
+                Some(RemoteFile { name, ..file })
+            })
+            .collect())
+    }
+
+    fn copy(&self, from: &str, to: &str) -> Result<Option<String>, BackendError> {
+        self.inner.copy(&self.name(from), &self.name(to))
+    }
+
+    fn hide(&self, name: &str) -> Result<(), BackendError> {
+        self.inner.hide(&self.name(name))
+    }
+
+    fn delete(&self, name: &str, id: Option<&str>) -> Result<(), BackendError> {
+        self.inner.delete(&self.name(name), id)
+    }
+
+    fn versions(&self) -> Result<Vec<FileVersion>, String> {
+        Ok(self.inner.versions()?.into_iter()
+            .filter_map(|version| {
+                let file_name = self.own(&version.file_name)?.to_string();
+                Some(FileVersion { file_name, ..version })
+            })
+            .collect())
+    }
+
+    fn prune_manifest_history(&self, name: &str, keep: u32) -> Result<usize, String> {
+        self.inner.prune_manifest_history(&self.name(name), keep)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::Backend;
+    use crate::backend::job::JobBackend;
+    use crate::backend::local::LocalBackend;
+    use std::io::Read;
+
+    #[test]
+    fn test_job_backend() {
+        let dir = std::env::temp_dir().join("retain-test-job-backend");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let connect = |job| JobBackend::new(Box::new(LocalBackend::connect(&dir.to_string_lossy()).unwrap()), job);
+        let (main, photos, docs) = (connect(None), connect(Some("photos")), connect(Some("docs")));
+        for backend in [&main, &photos, &docs] {
+            backend.upload("manifest.json", Box::new(&b"{}"[..]), 2, 0).unwrap();
+        }
+        photos.upload("a/b.jpg", Box::new(&b"jpg"[..]), 3, 0).unwrap();
+        assert!(dir.join(".jobs/photos/a/b.jpg").exists());
+
+        let names = |backend: &JobBackend| backend.list().unwrap().into_iter().map(|f| f.name).collect::<Vec<String>>();
+        assert_eq!(names(&main), vec!["manifest.json"]);
+        assert_eq!(names(&photos), vec!["a/b.jpg", "manifest.json"]);
+        assert_eq!(names(&docs), vec!["manifest.json"]);
+
+        let mut read = String::new();
+        photos.download("a/b.jpg", None).unwrap().body.read_to_string(&mut read).unwrap();
+        assert_eq!(read, "jpg");
+        docs.delete("manifest.json", None).unwrap();
+        assert_eq!(names(&photos).len(), 2);
+        assert!(names(&docs).is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
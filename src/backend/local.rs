@@ -0,0 +1,236 @@
+//! A local directory as the bucket, e.g. on a mounted external drive
+//!
+//! Files are stored under their name in the directory, masked names and encryption work as they do in a bucket
+//! The modified time of a file is kept as the modified time of the stored file
+//! There are no file versions: hiding deletes a file and uploading replaces it. The directory only holds the latest backup
+//!
+//! The directory itself is never created, s.t. backing up to a drive that isn't mounted fails instead of
+//! filling up the mount point. Files are written next to their name first and renamed once complete
+
+use crate::backend::{Backend, BackendError, Download, RemoteFile};
+use crate::b2::FileVersion;
+use crate::transfer;
+use filetime::FileTime;
+use futures::future::BoxFuture;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+use walkdir::WalkDir;
+
+const NO_VERSIONS: &str = "a local directory only holds the latest version of each file, this needs the native B2 API";
+// Appended to the name of a file while it is being written
+const PARTIAL_SUFFIX: &str = ".partial";
+
+pub struct LocalBackend {
+    root: PathBuf,
+}
+
+impl LocalBackend {
+    /// Uses the directory at 'dir', which must exist
+    pub fn connect(dir: &str) -> Result<Self, String> {
+        match std::fs::metadata(dir) {
+            Ok(m) if m.is_dir() => Ok(LocalBackend { root: PathBuf::from(dir) }),
+            Ok(_) => Err(format!("{} is not a directory", dir)),
+            Err(e) => Err(format!("Directory {} can't be used, is the drive mounted? ({})", dir, e)),
+        }
+    }
+
+    // Path of the file named 'name'
+    // Names are relative paths with '/' as separator, names leaving the directory are refused
+    fn path(&self, name: &str) -> Result<PathBuf, BackendError> {
+        let relative = Path::new(name);
+        if name.is_empty() || relative.components().any(|c| !matches!(c, Component::Normal(_))) {
+            return Err(BackendError { retryable: false, retry_after: None, reason: format!("{} can't be stored in a directory", name) });
+        }
+        Ok(self.root.join(relative))
+    }
+
+    fn read(&self, name: &str) -> Result<File, BackendError> {
+        File::open(self.path(name)?).map_err(io_error)
+    }
+}
+
+impl Backend for LocalBackend {
+    fn describe(&self) -> String {
+        format!("directory {}", self.root.display())
+    }
+
+    fn upload(&self, name: &str, mut data: Box<dyn Read + Send>, _length: u64, modified: u64) -> Result<Option<String>, BackendError> {
+        write(&self.path(name)?, &mut data, modified).map(|_| None)
+    }
+
+    fn upload_large(&self, name: &str, data: &mut dyn Read, modified: u64, _max_attempts: u32) -> Result<Option<String>, String> {
+        self.path(name).and_then(|path| write(&path, data, modified)).map(|_| None).map_err(|e| e.to_string())
+    }
+
+    fn upload_buffered<'a>(&'a self, name: &'a str, data: Arc<Vec<u8>>, _body: reqwest::Body, _sha1: &'a str,
+                           modified: u64) -> BoxFuture<'a, Result<Option<String>, BackendError>> {
+        Box::pin(async move {
+            let path = self.path(name)?;
+            transfer::blocking(move || write(&path, &mut &data[..], modified)).await.map(|_| None)
+        })
+    }
+
+    // There is only one version of each file, the ID is ignored
+    fn download(&self, name: &str, _id: Option<&str>) -> Result<Download, BackendError> {
+        let file = self.read(name)?;
+        Ok(Download {
+            length: file.metadata().ok().map(|m| m.len()),
+            body: Box::new(file),
+        })
+    }
+
+    fn download_from(&self, name: &str, _id: Option<&str>, offset: u64, limit: Option<u64>) -> Result<Download, BackendError> {
+        let mut file = self.read(name)?;
+        let length = file.metadata().map_err(io_error)?.len().saturating_sub(offset);
+        file.seek(SeekFrom::Start(offset)).map_err(io_error)?;
+        let length = limit.map_or(length, |limit| length.min(limit));
+        Ok(Download {
+            length: Some(length),
+            body: Box::new(file.take(length)),
+        })
+    }
+
+    fn download_buffered<'a>(&'a self, name: &'a str, _id: Option<&'a str>) -> BoxFuture<'a, Result<reqwest::Response, BackendError>> {
+        Box::pin(async move {
+            let path = self.path(name)?;
+            let data = transfer::blocking(move || std::fs::read(path)).await.map_err(io_error)?;
+            Ok(reqwest::Response::from(http::Response::new(data)))
+        })
+    }
+
+    // Reading a file isn't billed, calls not named by budget::class_of count as class A which has no limit
+    fn download_call(&self, _version: bool) -> &'static str {
+        "local_read"
+    }
+
+    fn list(&self) -> Result<Vec<RemoteFile>, String> {
+        let mut files = Vec::new();
+        for entry in WalkDir::new(&self.root).sort_by(|a, b| a.file_name().cmp(b.file_name())) {
+            let entry = entry.map_err(|e| e.to_string())?;
+            if !entry.file_type().is_file() || entry.file_name().to_string_lossy().ends_with(PARTIAL_SUFFIX) {
+                continue;
+            }
+            let relative = entry.path().strip_prefix(&self.root).map_err(|e| e.to_string())?;
+            let name = relative.components().map(|c| c.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/");
+            let metadata = entry.metadata().ok();
+            let modified = metadata.as_ref()
+                .and_then(|m| m.modified().ok())
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_millis() as u64);
+            files.push(RemoteFile { name, id: None, modified, size: metadata.map(|m| m.len()), sha1: None });
+        }
+        // Walking sorts each directory on its own, 'a/b' would come after 'a.txt'
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(files)
+    }
+
+    fn copy(&self, from: &str, to: &str) -> Result<Option<String>, BackendError> {
+        let mut source = self.read(from)?;
+        let modified = source.metadata().and_then(|m| m.modified()).ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_millis() as u64);
+        write(&self.path(to)?, &mut source, modified).map(|_| None)
+    }
+
+    fn hide(&self, name: &str) -> Result<(), BackendError> {
+        self.delete(name, None)
+    }
+
+    // There is only one version of each file, the ID is ignored
+    fn delete(&self, name: &str, _id: Option<&str>) -> Result<(), BackendError> {
+        let path = self.path(name)?;
+        std::fs::remove_file(&path).map_err(io_error)?;
+        // Remove directories left empty, up to the root. Removing one that isn't empty fails, which ends it
+        let mut dir = path.parent();
+        while let Some(d) = dir.filter(|d| *d != self.root) {
+            if std::fs::remove_dir(d).is_err() {
+                break;
+            }
+            dir = d.parent();
+        }
+        Ok(())
+    }
+
+    fn versions(&self) -> Result<Vec<FileVersion>, String> {
+        Err(NO_VERSIONS.to_string())
+    }
+
+    // Only the latest manifest is kept
+    fn prune_manifest_history(&self, _name: &str, _keep: u32) -> Result<usize, String> {
+        Ok(0)
+    }
+}
+
+// Writes everything read from 'data' to 'path', with 'modified' (in milliseconds since Unix Epoch) as its modified time
+fn write(path: &Path, data: &mut dyn Read, modified: u64) -> Result<(), BackendError> {
+    let partial = PathBuf::from(format!("{}{}", path.display(), PARTIAL_SUFFIX));
+    let mtime = FileTime::from_unix_time((modified / 1000) as i64, (modified % 1000 * 1_000_000) as u32);
+    let written = path.parent().map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|_| File::create(&partial))
+        .and_then(|mut file| std::io::copy(data, &mut file).and_then(|_| file.sync_all()))
+        .and_then(|_| filetime::set_file_mtime(&partial, mtime))
+        .and_then(|_| std::fs::rename(&partial, path));
+    written.map_err(|e| {
+        std::fs::remove_file(&partial).unwrap_or(());
+        io_error(e)
+    })
+}
+
+// Failing to read or write the directory, e.g. a full or removed drive, won't be fixed by trying again
+fn io_error(e: std::io::Error) -> BackendError {
+    BackendError {
+        retryable: false,
+        retry_after: None,
+        reason: e.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::Backend;
+    use crate::backend::local::LocalBackend;
+    use std::io::{Cursor, Read};
+
+    #[test]
+    fn test_local_backend() {
+        let dir = std::env::temp_dir().join("retain-test-local-backend");
+        std::fs::remove_dir_all(&dir).unwrap_or(());
+        assert!(LocalBackend::connect(dir.to_str().unwrap()).is_err());
+        std::fs::create_dir_all(&dir).unwrap();
+        let backend = LocalBackend::connect(dir.to_str().unwrap()).unwrap();
+
+        backend.upload("home/user/a.txt", Box::new(Cursor::new(b"abc".to_vec())), 3, 1_600_000_000_123).unwrap();
+        backend.upload("home.txt", Box::new(Cursor::new(b"de".to_vec())), 2, 1_600_000_000_000).unwrap();
+        let files = backend.list().unwrap();
+        let names: Vec<&str> = files.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, vec!["home.txt", "home/user/a.txt"]);
+        assert_eq!(files[1].modified, Some(1_600_000_000_123));
+
+        let mut content = String::new();
+        let mut download = backend.download("home/user/a.txt", None).unwrap();
+        download.body.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "abc");
+        assert_eq!(download.length, Some(3));
+        let mut rest = String::new();
+        let mut download = backend.download_from("home/user/a.txt", None, 1, None).unwrap();
+        download.body.read_to_string(&mut rest).unwrap();
+        assert_eq!((rest.as_str(), download.length), ("bc", Some(2)));
+        let mut start = String::new();
+        let mut download = backend.download_from("home/user/a.txt", None, 0, Some(2)).unwrap();
+        download.body.read_to_string(&mut start).unwrap();
+        assert_eq!((start.as_str(), download.length), ("ab", Some(2)));
+
+        // Names can't leave the directory
+        assert!(backend.upload("../a.txt", Box::new(Cursor::new(vec![])), 0, 0).is_err());
+        assert!(backend.upload("/a.txt", Box::new(Cursor::new(vec![])), 0, 0).is_err());
+
+        // Emptied directories are removed with the file
+        backend.hide("home/user/a.txt").unwrap();
+        assert!(!dir.join("home").exists());
+        assert_eq!(backend.list().unwrap().len(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,155 @@
+//! Where backed up files are stored
+//!
+//! The bucket is either on B2, using its native API (see b2.rs), or on storage with an S3-compatible API,
+//! e.g. AWS S3, MinIO, Wasabi or B2's own S3 API (see s3.rs). A config with an S3 endpoint uses the S3 API,
+//! the credentials then being an access key ID and secret key instead of an App Key ID and App Key
+//! A config with a local directory backs up to it instead of a bucket, e.g. for an offline copy on an external drive (see local.rs)
+//! A simulated run (--simulate) uses neither, see simulate.rs
+//! Each of them is shared by the jobs of the config, which keep to their own part of it (see job.rs)
+//!
+//! Uploads, downloads and clean only talk to the bucket through `Backend`, s.t. encryption, compression,
+//! deltas and the manifest work the same on both. What is built on B2's file versions (restoring to a point
+//! in time, retention and the manifest history) needs the native B2 API, see `Backend::versions`
+
+pub mod b2;
+pub mod s3;
+pub mod local;
+pub mod simulate;
+pub mod job;
+
+use crate::b2::FileVersion;
+use crate::budget::Budget;
+use crate::capabilities::{Operation, Problems};
+use crate::config::Config;
+use futures::future::BoxFuture;
+use std::io::Read;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Why a request to the bucket failed
+#[derive(Debug)]
+pub struct BackendError {
+    // Whether it may succeed if attempted again, see retry.rs
+    pub retryable: bool,
+    // How long the storage asked to wait before attempting it again, if it did
+    pub retry_after: Option<Duration>,
+    pub reason: String,
+}
+
+impl std::fmt::Display for BackendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.reason)
+    }
+}
+
+impl std::error::Error for BackendError {}
+
+/// A file being downloaded, read from 'body'
+pub struct Download {
+    // Size of the file, if known before reading it
+    pub length: Option<u64>,
+    pub body: Box<dyn Read + Send>,
+}
+
+impl Download {
+    pub fn from_response(response: reqwest::blocking::Response) -> Self {
+        Download {
+            length: response.content_length(),
+            body: Box::new(response),
+        }
+    }
+}
+
+/// A file in the bucket, as listed by `Backend::list`
+#[derive(Clone, Debug)]
+pub struct RemoteFile {
+    pub name: String,
+    // ID of the listed version, if the storage has them
+    pub id: Option<String>,
+    // Modified time in milliseconds since Unix Epoch, if known
+    pub modified: Option<u64>,
+    // Size in bytes, if known
+    pub size: Option<u64>,
+    // Hex SHA1 of the stored content, if the storage records it
+    pub sha1: Option<String>,
+}
+
+/// The calls uploads, downloads and clean make to the bucket
+/// Shared by all threads of a run. Calls are counted against the run's budget by the backend itself
+pub trait Backend: Send + Sync {
+    /// What the files are stored in, e.g. "B2 bucket photos"
+    fn describe(&self) -> String;
+
+    /// What won't work for 'operation' with the credentials, see capabilities.rs
+    /// Only B2 says what an App Key may do, elsewhere nothing is known up front
+    fn check_access(&self, _operation: Operation) -> Problems {
+        Problems::default()
+    }
+
+    /// Uploads 'length' bytes read from 'data' as 'name' in a single request
+    /// 'modified' is the modified time of the file they come from, in milliseconds since Unix Epoch
+    /// Returns the ID of the uploaded version, if the storage has them (see FileEntry::file_id)
+    fn upload(&self, name: &str, data: Box<dyn Read + Send>, length: u64, modified: u64) -> Result<Option<String>, BackendError>;
+
+    /// Uploads a file larger than b2::LARGE_FILE_THRESHOLD in parts of b2::LARGE_FILE_PART_SIZE
+    /// 'data' is read exactly once, each part is attempted up to 'max_attempts' times
+    fn upload_large(&self, name: &str, data: &mut dyn Read, modified: u64, max_attempts: u32) -> Result<Option<String>, String>;
+
+    /// Uploads a buffered file (see transfer.rs). 'sha1' is the hex SHA1 of 'data'
+    /// 'body' sends 'data' within the upload limit, advancing the progress bars. Backends that don't send it write 'data' directly
+    fn upload_buffered<'a>(&'a self, name: &'a str, data: Arc<Vec<u8>>, body: reqwest::Body, sha1: &'a str,
+                           modified: u64) -> BoxFuture<'a, Result<Option<String>, BackendError>>;
+
+    /// Downloads the file named 'name', or the version of it with the given ID
+    /// Storage without IDs only looks up the latest version of each file, and downloads that. IDs only come from B2,
+    /// and restoring to a point in time fails before this, on `versions`
+    fn download(&self, name: &str, id: Option<&str>) -> Result<Download, BackendError>;
+
+    /// Downloads up to 'limit' bytes of the file from byte 'offset' on, or the rest of it if None, like `download`
+    /// Used to resume an interrupted download. 'length' of the download is how much of the file it has
+    fn download_from(&self, name: &str, id: Option<&str>, offset: u64, limit: Option<u64>) -> Result<Download, BackendError>;
+
+    /// Downloads a buffered file (see transfer.rs), like `download`
+    fn download_buffered<'a>(&'a self, name: &'a str, id: Option<&'a str>) -> BoxFuture<'a, Result<reqwest::Response, BackendError>>;
+
+    /// The call a download makes, s.t. it can be counted against the budget before starting it
+    fn download_call(&self, version: bool) -> &'static str;
+
+    /// Lists the files in the bucket, sorted by name
+    fn list(&self) -> Result<Vec<RemoteFile>, String>;
+
+    /// Copies the file named 'from' to 'to' within the bucket, keeping its modified time
+    /// Files larger than b2::MAX_COPY_SIZE can't be copied. Returns the ID of the copy, if the storage has them
+    fn copy(&self, from: &str, to: &str) -> Result<Option<String>, BackendError>;
+
+    /// Hides a file, keeping its old versions. Storage that doesn't hide files deletes it instead
+    fn hide(&self, name: &str) -> Result<(), BackendError>;
+
+    /// Deletes a file, or only the version with the given ID
+    fn delete(&self, name: &str, id: Option<&str>) -> Result<(), BackendError>;
+
+    /// Lists every version of every file in the bucket
+    /// Fails on storage whose versions can't be looked up
+    fn versions(&self) -> Result<Vec<FileVersion>, String>;
+
+    /// Deletes all but the newest 'keep' versions of the remote manifest, named 'name' (see manifest::REMOTE_MANIFEST)
+    /// Returns the amount of versions deleted
+    fn prune_manifest_history(&self, name: &str, keep: u32) -> Result<usize, String>;
+}
+
+/// Connects to the bucket in the config, which must be configured (see Config::is_configured)
+/// Prints its progress the way the rest of a run does, timestamped from 't_start'
+/// Only the part of the bucket that belongs to the job being run (if any) is seen, see job.rs
+pub fn connect(config: &Config, budget: Arc<Budget>, t_start: Instant) -> Result<Box<dyn Backend>, String> {
+    let backend: Box<dyn Backend> = if let Some(simulate) = &config.simulate {
+        simulate.connect()?
+    } else if let Some(dir) = &config.local_dir {
+        Box::new(local::LocalBackend::connect(dir)?)
+    } else {
+        match &config.s3 {
+            Some(s3) => Box::new(s3::S3Backend::connect(config, s3, budget)?),
+            None => Box::new(b2::B2Backend::connect(config, budget, t_start)?),
+        }
+    };
+    Ok(Box::new(job::JobBackend::new(backend, config.job.as_deref())))
+}
@@ -0,0 +1,237 @@
+//! Simulated runs (--simulate), to rehearse a backup and restore or exercise them in CI without B2 credentials
+//!
+//! A simulation has its own directory, with the files in 'bucket' (see local.rs) and the local manifest in 'manifest',
+//! s.t. a rehearsal never marks files as backed up for the real bucket. It is kept between runs, uploading
+//! and then downloading restores the files from it. When discarding, only the name, size and modified time of
+//! each file are recorded (in discarded.json), which can't be restored but takes no space
+//!
+//! Simulated runs are not sent to the notifications or written to the metrics file
+
+use crate::backend::{Backend, BackendError, Download, RemoteFile};
+use crate::backend::local::LocalBackend;
+use crate::b2::FileVersion;
+use crate::colorutil::printcoln;
+use crate::manifest::{self, FileManifest};
+use crate::paths;
+use futures::future::BoxFuture;
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use termcolor::Color;
+
+// Directory of a simulation if none is given, in the temporary directory
+const DEFAULT_DIR: &str = "retain-simulate";
+// Value of --simulate that discards the files
+pub const DISCARD: &str = "discard";
+const NOT_KEPT: &str = "files aren't kept when simulating with 'discard', simulate with a directory to restore them";
+
+/// Where a simulated run puts the files it uploads
+#[derive(Clone, Debug, PartialEq)]
+pub enum Simulate {
+    // Files are kept in the simulation's directory
+    Keep(PathBuf),
+    // Only metadata of the files is recorded in the simulation's directory
+    Discard(PathBuf),
+}
+
+impl Simulate {
+    /// The simulation given by the value of --simulate, a directory or 'discard'
+    pub fn from_arg(value: Option<&str>) -> Self {
+        let default = std::env::temp_dir().join(DEFAULT_DIR);
+        match value {
+            Some(v) if v.eq_ignore_ascii_case(DISCARD) => Simulate::Discard(default),
+            Some(v) => Simulate::Keep(PathBuf::from(v)),
+            None => Simulate::Keep(default),
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        match self {
+            Simulate::Keep(dir) | Simulate::Discard(dir) => dir,
+        }
+    }
+
+    /// Where the local manifest of the simulation is kept
+    pub fn manifest_dir(&self) -> PathBuf {
+        self.dir().join("manifest")
+    }
+
+    /// Creates the directories of the simulation, and an empty manifest for its first run
+    /// Names aren't masked in it, s.t. the simulation's directory shows what was uploaded
+    /// The manifest directory must already be set to `manifest_dir`, or the directory of a job in it (see jobs.rs)
+    pub fn prepare(&self) -> Result<(), String> {
+        std::fs::create_dir_all(self.dir().join("bucket")).and_then(|_| paths::create_parent(&manifest::local_path()))
+            .map_err(|e| format!("Failed to create the simulation in {} ({})", self.dir().display(), e))?;
+        if !Path::new(&manifest::local_path()).exists() {
+            FileManifest::new(false).to_file(manifest::local_path()).map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    /// Connects to the simulation, which must be prepared
+    pub fn connect(&self) -> Result<Box<dyn Backend>, String> {
+        let bucket = self.dir().join("bucket");
+        match self {
+            Simulate::Keep(_) => Ok(Box::new(LocalBackend::connect(&bucket.to_string_lossy())?)),
+            Simulate::Discard(dir) => Ok(Box::new(DiscardBackend::open(dir.join("discarded.json"))?)),
+        }
+    }
+}
+
+impl std::fmt::Display for Simulate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Simulate::Keep(dir) => write!(f, "files are kept in {}", dir.display()),
+            Simulate::Discard(dir) => write!(f, "files are discarded, what would be uploaded is recorded in {}", dir.display()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Recorded {
+    length: u64,
+    modified: u64,
+}
+
+/// Reads and discards uploads, recording what they were
+/// The record is saved when the backend is dropped, at the end of the run
+pub struct DiscardBackend {
+    index: PathBuf,
+    files: Mutex<BTreeMap<String, Recorded>>,
+}
+
+impl DiscardBackend {
+    /// Continues the record at 'index', if it exists
+    pub fn open(index: PathBuf) -> Result<Self, String> {
+        let files = match std::fs::read(&index) {
+            Ok(bytes) => serde_json::from_slice(&bytes).map_err(|e| format!("Failed to load {} ({})", index.display(), e))?,
+            Err(_) => BTreeMap::new(),
+        };
+        Ok(DiscardBackend { index, files: Mutex::new(files) })
+    }
+
+    fn record(&self, name: &str, length: u64, modified: u64) {
+        self.files.lock().unwrap().insert(name.to_string(), Recorded { length, modified });
+    }
+}
+
+impl Drop for DiscardBackend {
+    fn drop(&mut self) {
+        let saved = serde_json::to_vec_pretty(&*self.files.lock().unwrap()).map_err(|e| e.to_string())
+            .and_then(|bytes| std::fs::write(&self.index, bytes).map_err(|e| e.to_string()));
+        if let Err(e) = saved {
+            printcoln(Color::Red, format!("Failed to save {} ({})", self.index.display(), e));
+        }
+    }
+}
+
+impl Backend for DiscardBackend {
+    fn describe(&self) -> String {
+        format!("nothing (simulated, recorded in {})", self.index.display())
+    }
+
+    fn upload(&self, name: &str, mut data: Box<dyn Read + Send>, _length: u64, modified: u64) -> Result<Option<String>, BackendError> {
+        self.upload_large(name, &mut data, modified, 1).map_err(|reason| BackendError { retryable: false, retry_after: None, reason })
+    }
+
+    // The data is read all the same, s.t. reading, compressing and encrypting is rehearsed too
+    fn upload_large(&self, name: &str, data: &mut dyn Read, modified: u64, _max_attempts: u32) -> Result<Option<String>, String> {
+        let length = std::io::copy(data, &mut std::io::sink()).map_err(|e| e.to_string())?;
+        self.record(name, length, modified);
+        Ok(None)
+    }
+
+    fn upload_buffered<'a>(&'a self, name: &'a str, data: Arc<Vec<u8>>, _body: reqwest::Body, _sha1: &'a str,
+                           modified: u64) -> BoxFuture<'a, Result<Option<String>, BackendError>> {
+        self.record(name, data.len() as u64, modified);
+        Box::pin(futures::future::ready(Ok(None)))
+    }
+
+    fn download(&self, _name: &str, _id: Option<&str>) -> Result<Download, BackendError> {
+        Err(BackendError { retryable: false, retry_after: None, reason: NOT_KEPT.to_string() })
+    }
+
+    fn download_from(&self, _name: &str, _id: Option<&str>, _offset: u64, _limit: Option<u64>) -> Result<Download, BackendError> {
+        Err(BackendError { retryable: false, retry_after: None, reason: NOT_KEPT.to_string() })
+    }
+
+    fn download_buffered<'a>(&'a self, _name: &'a str, _id: Option<&'a str>) -> BoxFuture<'a, Result<reqwest::Response, BackendError>> {
+        Box::pin(futures::future::ready(Err(BackendError { retryable: false, retry_after: None, reason: NOT_KEPT.to_string() })))
+    }
+
+    fn download_call(&self, _version: bool) -> &'static str {
+        "simulated_read"
+    }
+
+    fn list(&self) -> Result<Vec<RemoteFile>, String> {
+        Ok(self.files.lock().unwrap().iter()
+            .map(|(name, r)| RemoteFile { name: name.clone(), id: None, modified: Some(r.modified), size: Some(r.length), sha1: None })
+            .collect())
+    }
+
+    fn copy(&self, from: &str, to: &str) -> Result<Option<String>, BackendError> {
+        let mut files = self.files.lock().unwrap();
+        match files.get(from).cloned() {
+            Some(recorded) => {
+                files.insert(to.to_string(), recorded);
+                Ok(None)
+            },
+            None => Err(BackendError { retryable: false, retry_after: None, reason: format!("{} was not recorded", from) }),
+        }
+    }
+
+    fn hide(&self, name: &str) -> Result<(), BackendError> {
+        self.delete(name, None)
+    }
+
+    fn delete(&self, name: &str, _id: Option<&str>) -> Result<(), BackendError> {
+        self.files.lock().unwrap().remove(name);
+        Ok(())
+    }
+
+    fn versions(&self) -> Result<Vec<FileVersion>, String> {
+        Err("a simulation has no file versions, this needs the native B2 API".to_string())
+    }
+
+    fn prune_manifest_history(&self, _name: &str, _keep: u32) -> Result<usize, String> {
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::Backend;
+    use crate::backend::simulate::{DiscardBackend, Simulate};
+    use std::io::Cursor;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_from_arg() {
+        assert_eq!(Simulate::from_arg(Some("/mnt/rehearsal")), Simulate::Keep(PathBuf::from("/mnt/rehearsal")));
+        assert!(matches!(Simulate::from_arg(Some("Discard")), Simulate::Discard(_)));
+        assert!(matches!(Simulate::from_arg(None), Simulate::Keep(_)));
+    }
+
+    #[test]
+    fn test_discard_backend() {
+        let index = std::env::temp_dir().join("retain-test-discarded.json");
+        std::fs::remove_file(&index).unwrap_or(());
+        {
+            let backend = DiscardBackend::open(index.clone()).unwrap();
+            backend.upload("b.txt", Box::new(Cursor::new(vec![0; 10])), 10, 5).unwrap();
+            backend.upload("a.txt", Box::new(Cursor::new(vec![0; 3])), 3, 7).unwrap();
+            assert!(backend.download("a.txt", None).is_err());
+        }
+        // The record is continued by the next run
+        let backend = DiscardBackend::open(index.clone()).unwrap();
+        let files = backend.list().unwrap();
+        assert_eq!(files.iter().map(|f| f.name.as_str()).collect::<Vec<_>>(), vec!["a.txt", "b.txt"]);
+        assert_eq!(files[0].modified, Some(7));
+        backend.hide("a.txt").unwrap();
+        assert_eq!(backend.list().unwrap().len(), 1);
+        drop(backend);
+        std::fs::remove_file(&index).unwrap();
+    }
+}
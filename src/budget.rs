@@ -0,0 +1,206 @@
+//! Budgeting of B2 API calls
+//!
+//! B2 bills API calls ("transactions") by class:
+//! * Class A (uploading, hiding, deleting) is free
+//! * Class B (downloading) and Class C (authorizing, listing) are free up to a daily amount, after which they
+//!   are billed, or refused if the account has a daily cap set
+//!
+//! Every call is counted per run. Limits can be configured for class B and C calls:
+//! a warning is printed once WARN_PERCENT of a limit is used, and once the limit is reached
+//! the run either pauses until the daily caps reset (00:00 UTC) or aborts
+
+use serde::{Serialize, Deserialize};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::colorutil::{printcoln, printdebug};
+use crate::config::Config;
+use termcolor::Color;
+
+// Percentage of a limit at which a warning is printed
+const WARN_PERCENT: u64 = 80;
+
+/// Transaction class of a B2 API call
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Class {
+    A,
+    B,
+    C,
+}
+
+impl Class {
+    fn index(self) -> usize {
+        match self {
+            Class::A => 0,
+            Class::B => 1,
+            Class::C => 2,
+        }
+    }
+}
+
+/// Returns the transaction class of the B2 API call with the given name
+pub fn class_of(call: &str) -> Class {
+    match call {
+        "b2_download_file_by_id" | "b2_download_file_by_name" | "b2_get_file_info" => Class::B,
+        "b2_authorize_account" | "b2_copy_file" | "b2_copy_part" | "b2_create_bucket" | "b2_create_key" |
+        "b2_delete_key" | "b2_get_download_authorization" | "b2_list_buckets" | "b2_list_file_names" |
+        "b2_list_file_versions" | "b2_list_keys" | "b2_list_parts" | "b2_list_unfinished_large_files" |
+        "b2_update_bucket" => Class::C,
+        // S3-compatible storage bills differently, but downloads and listing are what is usually limited there too
+        "s3_get_object" => Class::B,
+        "s3_list_objects" => Class::C,
+        _ => Class::A,
+    }
+}
+
+/// What to do when a limit is reached
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LimitAction {
+    // Wait until the daily caps reset, then continue
+    Pause,
+    // Stop making calls of that class
+    Abort,
+}
+
+/// Returned when a call would exceed the budget
+#[derive(Debug)]
+pub struct BudgetExceeded {
+    pub class: Class,
+    pub limit: u64,
+}
+
+impl std::fmt::Display for BudgetExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "limit of {} class {:?} calls reached", self.limit, self.class)
+    }
+}
+
+impl std::error::Error for BudgetExceeded {}
+
+/// Counts API calls made during a run, enforcing the configured limits
+/// Shared by all threads of a run
+pub struct Budget {
+    counts: [AtomicU64; 3],
+    limits: [Option<u64>; 3],
+    warned: [AtomicBool; 3],
+    action: LimitAction,
+    // Held while pausing, s.t. only one thread waits for the caps to reset
+    pause: Mutex<()>,
+}
+
+impl Budget {
+    /// Creates a budget with the limits from the config
+    pub fn new(config: &Config) -> Self {
+        Budget::with_limits(config.class_b_limit, config.class_c_limit, config.limit_action.unwrap_or(LimitAction::Abort))
+    }
+
+    pub fn with_limits(class_b: Option<u64>, class_c: Option<u64>, action: LimitAction) -> Self {
+        Budget {
+            counts: [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)],
+            limits: [None, class_b, class_c],
+            warned: [AtomicBool::new(false), AtomicBool::new(false), AtomicBool::new(false)],
+            action,
+            pause: Mutex::new(()),
+        }
+    }
+
+    /// Counts a call to the B2 API call 'call', before it is made
+    /// If the limit for its class is reached, this either pauses or returns an error, depending on the config
+    pub fn spend(&self, call: &str) -> Result<(), BudgetExceeded> {
+        let class = class_of(call);
+        printdebug(format!("B2 call: {} (class {:?})", call, class));
+        let idx = class.index();
+        let limit = match self.limits[idx] {
+            Some(l) => l,
+            None => {
+                self.counts[idx].fetch_add(1, Ordering::SeqCst);
+                return Ok(());
+            }
+        };
+
+        loop {
+            let count = self.counts[idx].fetch_add(1, Ordering::SeqCst) + 1;
+            if count <= limit {
+                if count * 100 >= limit * WARN_PERCENT && !self.warned[idx].swap(true, Ordering::SeqCst) {
+                    printcoln(Color::Yellow, format!("Warning: {} of {} class {:?} calls used", count, limit, class));
+                }
+                return Ok(());
+            }
+            // Over the limit, the call is not made
+            self.counts[idx].fetch_sub(1, Ordering::SeqCst);
+            match self.action {
+                LimitAction::Abort => return Err(BudgetExceeded { class, limit }),
+                LimitAction::Pause => {
+                    let _guard = self.pause.lock().unwrap();
+                    // Another thread may have waited already
+                    if self.counts[idx].load(Ordering::SeqCst) >= limit {
+                        let wait = until_cap_reset();
+                        printcoln(Color::Yellow, format!("Limit of {} class {:?} calls reached, pausing for {}h{:02}m until the daily caps reset",
+                                                         limit, class, wait.as_secs() / 3600, wait.as_secs() / 60 % 60));
+                        std::thread::sleep(wait);
+                        self.counts[idx].store(0, Ordering::SeqCst);
+                        self.warned[idx].store(false, Ordering::SeqCst);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Counts calls without enforcing limits
+    /// Used for class A calls, which are never limited, and for calls whose amount is only known
+    /// after they were made, e.g. paginated listings
+    pub fn record(&self, call: &str, amount: u64) {
+        printdebug(format!("B2 call: {} x{} (class {:?})", call, amount, class_of(call)));
+        self.counts[class_of(call).index()].fetch_add(amount, Ordering::SeqCst);
+    }
+
+    /// Amount of calls of the given class counted so far
+    pub fn count(&self, class: Class) -> u64 {
+        self.counts[class.index()].load(Ordering::SeqCst)
+    }
+
+    /// Summary of the calls made, e.g. for printing when a run is finished
+    pub fn summary(&self) -> String {
+        format!("{} class A, {} class B, {} class C", self.count(Class::A), self.count(Class::B), self.count(Class::C))
+    }
+}
+
+/// Time until B2's daily caps reset, which happens at 00:00 UTC
+pub fn until_cap_reset() -> Duration {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0)).as_secs();
+    Duration::from_secs(86400 - now % 86400)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::budget::{class_of, Budget, Class, LimitAction};
+
+    #[test]
+    fn test_class_of() {
+        assert_eq!(class_of("b2_upload_file"), Class::A);
+        assert_eq!(class_of("b2_hide_file"), Class::A);
+        assert_eq!(class_of("b2_download_file_by_name"), Class::B);
+        assert_eq!(class_of("b2_list_file_names"), Class::C);
+        assert_eq!(class_of("b2_authorize_account"), Class::C);
+    }
+
+    #[test]
+    fn test_spend() {
+        let budget = Budget::with_limits(Some(3), None, LimitAction::Abort);
+        for _ in 0..3 {
+            assert!(budget.spend("b2_download_file_by_name").is_ok());
+        }
+        assert!(budget.spend("b2_download_file_by_name").is_err());
+        assert_eq!(budget.count(Class::B), 3);
+
+        // Unlimited classes are only counted
+        for _ in 0..10 {
+            assert!(budget.spend("b2_list_buckets").is_ok());
+            assert!(budget.spend("b2_upload_file").is_ok());
+        }
+        budget.record("b2_list_file_names", 5);
+        assert_eq!(budget.count(Class::C), 15);
+        assert_eq!(budget.summary(), "10 class A, 3 class B, 15 class C");
+    }
+}
@@ -0,0 +1,168 @@
+//! What the App Key may do, checked right after authenticating s.t. a run doesn't fail halfway through
+//!
+//! B2 App Keys have a list of capabilities, and may be restricted to one bucket and to names starting with a prefix
+//! A key that can't access the bucket at all is refused when connecting (`check_key`). Otherwise each operation
+//! checks the capabilities it needs (`check`): without those it would fail, e.g. 'clean delete' without deleteFiles,
+//! so it doesn't start. Capabilities only some of its steps need are warned about, those steps then fail on their own
+
+use crate::b2::KeyAllowed;
+use crate::colorutil::printcoln;
+use std::time::Instant;
+use termcolor::Color;
+
+/// What the App Key is checked for
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operation {
+    Upload,
+    // 'versions' if file versions are listed, e.g. to restore as of a time
+    Download { versions: bool },
+    // 'retention' if old versions are deleted for the retention policy
+    Clean { delete: bool, retention: bool },
+    // Deleting versions nothing refers to, see gc.rs
+    Gc,
+}
+
+/// What won't work with the App Key
+#[derive(Debug, Default, PartialEq)]
+pub struct Problems {
+    // The operation fails without these
+    pub fatal: Vec<String>,
+    // Only some of its steps fail without these
+    pub warnings: Vec<String>,
+}
+
+/// Checks that the App Key can access the bucket named 'bucket_name' at all
+pub fn check_key(allowed: &KeyAllowed, bucket_name: &str) -> Result<(), String> {
+    if let Some(restricted) = allowed.bucket_name.as_ref().filter(|b| *b != bucket_name) {
+        return Err(format!("The App Key is restricted to bucket '{}', it can't access '{}'", restricted, bucket_name));
+    }
+    if let Some(prefix) = allowed.name_prefix.as_ref().filter(|p| !p.is_empty()) {
+        return Err(format!("The App Key only allows names starting with '{}', but files are stored under their own names (e.g. manifest.json)", prefix));
+    }
+    if !has(allowed, "listBuckets") {
+        return Err("The App Key can't look up the bucket (it lacks listBuckets)".to_string());
+    }
+    Ok(())
+}
+
+/// Checks the capabilities the operation needs
+pub fn check(allowed: &KeyAllowed, operation: Operation) -> Problems {
+    let mut problems = Problems::default();
+    let mut need = |capability: &str, fatal: bool, what: &str| {
+        if !has(allowed, capability) {
+            let message = format!("{} (the App Key lacks {})", what, capability);
+            match fatal {
+                true => problems.fatal.push(message),
+                false => problems.warnings.push(message),
+            }
+        }
+    };
+    match operation {
+        Operation::Upload => {
+            need("writeFiles", true, "Files can't be uploaded");
+            need("listFiles", false, "Moved files are uploaded again instead of copied, and old versions of the manifest aren't pruned");
+            need("deleteFiles", false, "Old versions of the manifest aren't pruned");
+        },
+        Operation::Download { versions } => {
+            need("readFiles", true, "Files can't be downloaded");
+            if versions {
+                need("listFiles", true, "File versions can't be listed to restore as of a time");
+            }
+        },
+        Operation::Clean { delete, retention } => {
+            need("listFiles", true, "Remote files can't be listed");
+            match delete {
+                true => need("deleteFiles", true, "Unused files can't be deleted"),
+                false => need("writeFiles", true, "Unused files can't be hidden"),
+            }
+            // The cleaned manifest is uploaded, and its old versions pruned, in either mode
+            if delete {
+                need("writeFiles", true, "The cleaned manifest can't be uploaded");
+            }
+            if retention {
+                need("deleteFiles", true, "Old versions not kept by the retention policy can't be deleted");
+            } else if !delete {
+                need("deleteFiles", false, "Old versions of the manifest aren't pruned");
+            }
+        },
+        Operation::Gc => {
+            need("listFiles", true, "File versions can't be listed");
+            need("deleteFiles", true, "Unreferenced versions can't be deleted");
+        },
+    }
+    problems
+}
+
+/// Prints the problems, returning whether the operation can go ahead
+pub fn report(problems: &Problems, t_start: Instant) -> bool {
+    for warning in &problems.warnings {
+        printcoln(Color::Yellow, format!("[{:.3}] Warning: {}", t_start.elapsed().as_secs_f32(), warning));
+    }
+    for fatal in &problems.fatal {
+        printcoln(Color::Red, format!("[{:.3}] {}", t_start.elapsed().as_secs_f32(), fatal));
+    }
+    problems.fatal.is_empty()
+}
+
+/// Like report, but returns what makes the operation fail as an error instead of printing it
+pub fn require(problems: &Problems, t_start: Instant) -> Result<(), String> {
+    for warning in &problems.warnings {
+        printcoln(Color::Yellow, format!("[{:.3}] Warning: {}", t_start.elapsed().as_secs_f32(), warning));
+    }
+    match problems.fatal.is_empty() {
+        true => Ok(()),
+        false => Err(problems.fatal.join(", ")),
+    }
+}
+
+fn has(allowed: &KeyAllowed, capability: &str) -> bool {
+    allowed.capabilities.iter().any(|c| c == capability)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::b2::KeyAllowed;
+    use crate::capabilities::{check, check_key, Operation};
+
+    fn key(capabilities: &[&str]) -> KeyAllowed {
+        KeyAllowed {
+            capabilities: capabilities.iter().map(|c| c.to_string()).collect(),
+            ..KeyAllowed::default()
+        }
+    }
+
+    #[test]
+    fn test_check_key() {
+        let mut allowed = key(&["listBuckets"]);
+        assert!(check_key(&allowed, "backups").is_ok());
+        allowed.bucket_name = Some("other".to_string());
+        assert!(check_key(&allowed, "backups").unwrap_err().contains("restricted to bucket 'other'"));
+        allowed.bucket_name = Some("backups".to_string());
+        assert!(check_key(&allowed, "backups").is_ok());
+        allowed.name_prefix = Some("photos/".to_string());
+        assert!(check_key(&allowed, "backups").is_err());
+        assert!(check_key(&key(&[]), "backups").unwrap_err().contains("listBuckets"));
+    }
+
+    #[test]
+    fn test_check() {
+        let all = key(&["listBuckets", "listFiles", "readFiles", "writeFiles", "deleteFiles"]);
+        for operation in &[Operation::Upload, Operation::Download { versions: true }, Operation::Clean { delete: true, retention: true }, Operation::Gc] {
+            assert_eq!(check(&all, *operation), Default::default());
+        }
+
+        // Without deleteFiles, uploads work but 'clean delete' doesn't
+        let no_delete = key(&["listBuckets", "listFiles", "readFiles", "writeFiles"]);
+        let upload = check(&no_delete, Operation::Upload);
+        assert!(upload.fatal.is_empty());
+        assert_eq!(upload.warnings.len(), 1);
+        assert_eq!(check(&no_delete, Operation::Clean { delete: true, retention: false }).fatal.len(), 1);
+        assert!(check(&no_delete, Operation::Clean { delete: false, retention: false }).fatal.is_empty());
+
+        // Downloads need readFiles, and listFiles only to restore as of a time
+        let read_only = key(&["readFiles"]);
+        assert!(check(&read_only, Operation::Download { versions: false }).fatal.is_empty());
+        assert_eq!(check(&read_only, Operation::Download { versions: true }).fatal.len(), 1);
+        assert!(!check(&key(&["listFiles"]), Operation::Download { versions: false }).fatal.is_empty());
+    }
+}
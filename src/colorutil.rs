@@ -1,5 +1,34 @@
 use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
 use std::io::Write;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// How much is printed, set with -q and -v
+/// Everything printed is logged regardless, see logging.rs
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum Verbosity {
+    // Only errors (red text) and the summary at the end of a run
+    Quiet,
+    Normal,
+    // Also what is decided for each file, e.g. which rule excluded it
+    Verbose,
+    // Also every B2 API request and its response
+    Debug,
+}
+
+static VERBOSITY: AtomicU8 = AtomicU8::new(Verbosity::Normal as u8);
+
+pub fn set_verbosity(verbosity: Verbosity) {
+    VERBOSITY.store(verbosity as u8, Ordering::Relaxed);
+}
+
+pub fn verbosity() -> Verbosity {
+    match VERBOSITY.load(Ordering::Relaxed) {
+        0 => Verbosity::Quiet,
+        1 => Verbosity::Normal,
+        2 => Verbosity::Verbose,
+        _ => Verbosity::Debug,
+    }
+}
 
 /// Prints the given text with the given color
 /// Does not include a newline
@@ -13,10 +42,76 @@ pub fn printcol<T: AsRef<str>>(color: Color, text: T) {
 
 /// Prints the given text with the given color
 /// Include a newline
+/// The text is logged as well, see logging.rs
+/// Only errors (red text) are printed when quiet
 pub fn printcoln<T: AsRef<str>>(color: Color, text: T) {
+    log(color, text.as_ref());
+    if verbosity() == Verbosity::Quiet && color != Color::Red {
+        return;
+    }
     let mut stdout = StandardStream::stdout(ColorChoice::Always);
     stdout.set_color(ColorSpec::new().set_fg(Some(color))).unwrap();
     writeln!(&mut stdout, "{}", text.as_ref()).unwrap();
     stdout.reset().unwrap();
     stdout.flush();
-}
\ No newline at end of file
+}
+
+/// Prints the given text with the given color to stderr
+/// Include a newline
+/// The text is logged as well, see logging.rs
+/// Only errors (red text) are printed when quiet
+pub fn eprintcoln<T: AsRef<str>>(color: Color, text: T) {
+    log(color, text.as_ref());
+    if verbosity() == Verbosity::Quiet && color != Color::Red {
+        return;
+    }
+    let mut stderr = StandardStream::stderr(ColorChoice::Always);
+    stderr.set_color(ColorSpec::new().set_fg(Some(color))).unwrap();
+    writeln!(&mut stderr, "{}", text.as_ref()).unwrap();
+    stderr.reset().unwrap();
+    stderr.flush().unwrap();
+}
+
+/// Prints the given text with the given color, even when quiet
+/// Used for the summary at the end of a run, s.t. e.g. mails from cron show what it did
+pub fn printsummary<T: AsRef<str>>(color: Color, text: T) {
+    log(color, text.as_ref());
+    print_plain(color, text.as_ref());
+}
+
+/// Prints what was decided for a single file, when verbose
+/// Logged at the debug level
+pub fn printverbose<T: AsRef<str>>(text: T) {
+    tracing::debug!("{}", text.as_ref());
+    if verbosity() >= Verbosity::Verbose {
+        print_plain(Color::White, text.as_ref());
+    }
+}
+
+/// Prints details of a B2 API request or response, when -vv is given
+/// Logged at the trace level
+pub fn printdebug<T: AsRef<str>>(text: T) {
+    tracing::trace!("{}", text.as_ref());
+    if verbosity() >= Verbosity::Debug {
+        print_plain(Color::Cyan, text.as_ref());
+    }
+}
+
+// Prints the line without logging it
+fn print_plain(color: Color, text: &str) {
+    let mut stdout = StandardStream::stdout(ColorChoice::Always);
+    stdout.set_color(ColorSpec::new().set_fg(Some(color))).unwrap();
+    writeln!(&mut stdout, "{}", text).unwrap();
+    stdout.reset().unwrap();
+    stdout.flush().unwrap();
+}
+
+// Emits the text as a tracing event, with a level matching its color
+// printcol isn't logged, it is used for prompts and the start of lines
+fn log(color: Color, text: &str) {
+    match color {
+        Color::Red => tracing::error!("{}", text),
+        Color::Yellow => tracing::warn!("{}", text),
+        _ => tracing::info!("{}", text),
+    }
+}
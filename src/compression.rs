@@ -0,0 +1,104 @@
+//! Optional zstd compression of uploaded files
+//!
+//! When enabled, files are compressed before being encrypted and uploaded (compress -> encrypt -> upload)
+//! and decompressed again when restored. Whether an entry was compressed is kept in the manifest,
+//! s.t. a backup can contain both compressed and uncompressed files, e.g. after toggling the setting
+//!
+//! Compression happens into a temporary file first, since the size of the upload must be known up front
+//! Files small enough to be uploaded from memory (see transfer.rs) are compressed in memory instead
+//! Files that are already compressed (judging by their extension) are uploaded as-is
+
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+
+// zstd compression level. 3 is zstd's default and a good trade-off between speed and ratio
+pub const COMPRESSION_LEVEL: i32 = 3;
+
+// Extensions of formats that are already compressed, compressing them again is a waste of time
+const INCOMPRESSIBLE: &[&str] = &[
+    "7z", "aac", "apk", "avi", "br", "bz2", "cab", "deb", "docx", "flac", "gif", "gz", "heic", "jar",
+    "jpeg", "jpg", "lz", "lz4", "lzma", "m4a", "m4v", "mkv", "mov", "mp3", "mp4", "ogg", "opus",
+    "png", "pptx", "rar", "rpm", "tgz", "txz", "webm", "webp", "xlsx", "xz", "zip", "zst",
+];
+
+/// Whether the file at 'path' is worth compressing, based on its extension
+pub fn is_compressible<T: AsRef<Path>>(path: T) -> bool {
+    match path.as_ref().extension().and_then(|e| e.to_str()) {
+        Some(ext) => !INCOMPRESSIBLE.iter().any(|i| i.eq_ignore_ascii_case(ext)),
+        None => true,
+    }
+}
+
+/// A compressed copy of a file, removed when dropped
+pub struct CompressedFile {
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+impl CompressedFile {
+    /// Compresses the file at 'path' into a new temporary file
+    pub fn create<T: AsRef<Path>>(path: T) -> std::io::Result<Self> {
+        Self::compress(std::fs::File::open(path)?)
+    }
+
+    /// Compresses what 'source' reads into a new temporary file
+    pub fn compress<R: Read>(source: R) -> std::io::Result<Self> {
+        let name: String = thread_rng().sample_iter(Alphanumeric).take(16).collect();
+        // Created up front, s.t. the temporary file is removed if compression fails
+        let mut temp = CompressedFile {
+            path: std::env::temp_dir().join(format!("retain-{}.zst", name)),
+            size: 0,
+        };
+        let mut target = std::fs::File::create(&temp.path)?;
+        zstd::stream::copy_encode(source, &mut target, COMPRESSION_LEVEL)?;
+        target.flush()?;
+        temp.size = target.metadata()?.len();
+        Ok(temp)
+    }
+}
+
+impl Drop for CompressedFile {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.path).unwrap_or(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compression::{is_compressible, CompressedFile};
+    use std::io::Write;
+
+    #[test]
+    fn test_is_compressible() {
+        assert!(is_compressible("/home/user/notes.txt"));
+        assert!(is_compressible("/home/user/Makefile"));
+        assert!(!is_compressible("/home/user/photo.JPG"));
+        assert!(!is_compressible("C:\\Users\\user\\archive.zip"));
+    }
+
+    #[test]
+    fn test_compress_roundtrip() {
+        let data = b"retain ".repeat(10000);
+        let path = std::env::temp_dir().join("retain-compress-test.txt");
+        std::fs::write(&path, &data).unwrap();
+
+        let compressed = CompressedFile::create(&path).unwrap();
+        assert!(compressed.size < data.len() as u64);
+        assert_eq!(std::fs::metadata(&compressed.path).unwrap().len(), compressed.size);
+
+        // Decompress the same way restoring does
+        let mut restored = Vec::new();
+        let mut writer = zstd::stream::write::Decoder::new(&mut restored).unwrap();
+        writer.write_all(&std::fs::read(&compressed.path).unwrap()).unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+        assert_eq!(restored, data);
+
+        let temp = compressed.path.clone();
+        drop(compressed);
+        assert!(!temp.exists());
+        std::fs::remove_file(path).unwrap();
+    }
+}
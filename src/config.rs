@@ -1,7 +1,26 @@
 use serde::{Serialize, Deserialize};
 use serde_json;
+use toml_edit::{Document, TableLike};
 use std::fmt::{Debug, Formatter};
 use std::sync::Mutex;
+use crate::budget::LimitAction;
+use crate::retention::Retention;
+use crate::secrets;
+use crate::paths;
+use crate::encryption::{self, NonceMode};
+use crate::encryption::cipher::Cipher;
+use crate::encryption::BlockSize;
+use crate::credentials::{self, Protection};
+use crate::logging::LogRotation;
+use crate::notify::Notifications;
+use crate::hooks::Hooks;
+use crate::jobs::{self, Job};
+use crate::lockfile::LockFile;
+use crate::shadow::SnapshotHook;
+use crate::backend::s3::S3Config;
+use crate::backend::simulate::Simulate;
+use crate::colorutil::printcoln;
+use termcolor::Color;
 
 // To be double-plus-sure we do not re-use nonces, we will pre-allocate them in blocks
 // Every time we allocate a new block, we store the end of the block and write it to disk
@@ -17,6 +36,32 @@ use std::sync::Mutex;
 const NONCE_PREALLOC_AMOUNT: u128 = 65536;
 // (8192-16) * 65536 = 535822336 (~535MB)
 
+// Amount of worker threads used for uploading, downloading and cleaning when nothing is configured
+pub const DEFAULT_THREADS: usize = 8;
+
+// Environment variables that override config values, s.t. secrets don't have to be written into the config file
+// Secrets are referred to by their variable (see secrets.rs), the keys are hex encoded. Overrides are never saved
+pub const ENV_OVERRIDES: [&str; 5] = ["RETAIN_APP_KEY_ID", "RETAIN_APP_KEY", "RETAIN_BUCKET_NAME", "RETAIN_SECRET_KEY", "RETAIN_PRIVATE_KEY"];
+
+// Written at the top of new TOML configs
+const TOML_HEADER: &str = "# Configuration of retain-rs, see 'retain-rs config --help' for what the options do\n\n";
+
+/// The format of a config file, decided by its extension: .toml is TOML, anything else JSON
+/// TOML configs can hold comments, they are kept when the config is saved
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Json,
+    Toml,
+}
+
+impl Format {
+    pub fn of<T: AsRef<str>>(path: T) -> Format {
+        match std::path::Path::new(path.as_ref()).extension().and_then(|e| e.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("toml") => Format::Toml,
+            _ => Format::Json,
+        }
+    }
+}
 
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
 pub struct Config {
@@ -29,68 +74,542 @@ pub struct Config {
     pub encrypt: Option<bool>,
     // Path key-file. Used only if encryption is enabled
     pub secret_key: Option<String>,
+    // Paths to key-files of previous secret keys, used to decrypt files they encrypted. None means no retired keys
+    pub retired_keys: Option<Vec<String>>,
+    // Paths to the key-files of a key pair, see encryption/keypair.rs
+    // With a public key, files are encrypted for it instead of with the secret key. None means no key pair
+    pub public_key: Option<String>,
+    // Only needed to restore files encrypted for the public key, best kept off the backup machine
+    pub private_key: Option<String>,
+    // Upload bandwidth limit in bytes per second, shared by all upload threads. None means unlimited
+    pub upload_limit: Option<u64>,
+    // Amount of worker threads for each operation. None means DEFAULT_THREADS
+    pub upload_threads: Option<usize>,
+    pub download_threads: Option<usize>,
+    pub clean_threads: Option<usize>,
+    // How many times a request is attempted before giving up. None means retry::DEFAULT_MAX_ATTEMPTS
+    pub max_attempts: Option<u32>,
+    // Whether files are compressed before being uploaded. None means off
+    pub compress: Option<bool>,
+    // Whether directory listings are cached between uploads, see scancache.rs. None means off
+    pub scan_cache: Option<bool>,
+    // Whether uploads read files from Volume Shadow Copies on Windows, see shadow.rs. None means off
+    pub shadow_copy: Option<bool>,
+    // File systems that are snapshotted before uploading elsewhere, e.g. btrfs subvolumes. None means none
+    pub snapshots: Option<Vec<SnapshotHook>>,
+    // Limits on class B and C API calls per run. None means unlimited
+    pub class_b_limit: Option<u64>,
+    pub class_c_limit: Option<u64>,
+    // What to do when a limit is reached. None means abort
+    pub limit_action: Option<LimitAction>,
+    // Amounts downloaded in a run (in bytes) at which a warning is printed, see egress.rs. None means no warnings
+    pub download_warnings: Option<Vec<u64>>,
+    // Which old versions of files are kept by 'clean'. None means all of them
+    pub retention: Option<Retention>,
+    // Amount of versions of the remote manifest to keep. None means manifest::DEFAULT_MANIFEST_HISTORY
+    pub manifest_history: Option<u32>,
+    // Directory the local manifest is kept in. New configs get one from paths.rs
+    // None means the working directory, as for configs made before this existed
+    pub manifest_dir: Option<String>,
+    // How the App Key ID and App Key are encrypted in the config file, see credentials.rs. None means plain text
+    pub protect_credentials: Option<Protection>,
+    // Where the nonces of encrypted files come from. None means the counter below
+    pub nonce_mode: Option<NonceMode>,
+    // Cipher new files are encrypted with. None means XChaCha20Poly1305
+    pub cipher: Option<Cipher>,
+    // Length of the blocks new files are encrypted in, see encryption/mod.rs. None means encryption::BLOCK_LENGTH
+    pub block_size: Option<BlockSize>,
+    // File the output of every run is logged to, see logging.rs. None means no log file
+    pub log_file: Option<String>,
+    // How the log file is rotated. None means never
+    pub log_rotation: Option<LogRotation>,
+    // Directory every run gets its own detailed log in, see logging.rs. None means 'logs' in the manifest directory
+    pub log_dir: Option<String>,
+    // Whether every run writes a run log to the directory above. None means on
+    pub run_logs: Option<bool>,
+    // Amount of run logs kept. None means logging::DEFAULT_LOG_KEEP
+    pub log_keep: Option<u32>,
+    // Where the outcome of runs is sent, see notify.rs. None means nowhere
+    pub notifications: Option<Notifications>,
+    // Commands run before and after uploads, see hooks.rs. None means none
+    pub hooks: Option<Hooks>,
+    // File the metrics of every run are written to, see metrics.rs. None means no metrics
+    pub metrics_file: Option<String>,
+    // S3-compatible storage the bucket is on, see backend/s3.rs. None means B2, using its native API
+    // The App Key ID and App Key are then the access key ID and secret access key
+    pub s3: Option<S3Config>,
+    // Directory files are backed up to instead of a bucket, e.g. on an external drive, see backend/local.rs
+    // None means a bucket. The credentials and bucket name aren't needed with one
+    pub local_dir: Option<String>,
+    // Other backup lists run with --job, each with its own manifest, see jobs.rs. None means none
+    pub jobs: Option<Vec<Job>>,
     // End of current nonce-allocation-block
+    #[serde(default, with = "nonce_count")]
     nonce_alloc: u128,
     #[serde(skip)]
+    pub simulate: Option<Simulate>, // Set by --simulate, uploads and downloads then use the simulation instead of the bucket
+    #[serde(skip)]
     pub location: String, // The location of the config, s.t. it can save itself
     #[serde(skip)]
     nonce_ctr: u128,
+    #[serde(skip)]
+    env_originals: Vec<(&'static str, String, Option<String>)>, // Applied environment overrides and the values they replaced
+    #[serde(skip)]
+    pub job: Option<String>, // The job being run, set by --job
+    #[serde(skip)]
+    job_base: Option<(Option<String>, Option<String>, Option<String>)>, // Backup list, bucket and manifest directory the job replaced
 }
 
 impl Config {
     pub fn is_configured(&self) -> Result<(),String> {
-        if self.app_key_id.is_none() { return Err("App Key ID is missing".to_string()) };
-        if self.app_key.is_none() { return Err("App Key is missing".to_string()) };
-        if self.bucket_name.is_none() { return Err("Bucket Name is missing".to_string()) };
+        if self.local_dir.is_none() && self.simulate.is_none() {
+            if self.app_key_id.is_none() { return Err("App Key ID is missing".to_string()) };
+            if self.app_key.is_none() { return Err("App Key is missing".to_string()) };
+            if self.bucket_name.is_none() { return Err("Bucket Name is missing".to_string()) };
+        }
         if self.backup_list.is_none() { return Err("File List Path is missing".to_string()) };
         if self.encrypt.is_none() { return Err("You must explicitly enable or disable encryption".to_string()) };
         // Secret key only needs to be set if encryption is on
         if self.encrypt.is_some() && self.encrypt.unwrap() == true {
-            if self.secret_key.is_none() { return Err("No secret key configured".to_string()) };
+            // A private key alone is enough to restore, and its public key is derived from it
+            if self.secret_key.is_none() && self.public_key.is_none() && self.private_key.is_none() { return Err("No secret key configured".to_string()) };
         }
         Ok(())
     }
 
+    /// Directory the run logs are written to, None if they are off
+    pub fn run_log_dir(&self) -> Option<String> {
+        match (self.run_logs, &self.log_dir) {
+            (Some(false), _) => None,
+            (_, Some(dir)) => Some(dir.clone()),
+            (_, None) => Some(paths::log_dir(self.manifest_dir.as_deref().unwrap_or(""))),
+        }
+    }
+
     pub fn save(&self) {
-        std::fs::write(&self.location,serde_json::to_string(self).unwrap()).unwrap();
+        self.save_to(&self.location).unwrap();
     }
 
-    pub fn from_file<T: AsRef<str>>(path: T) -> Self {
-        let contents = std::fs::read(path.as_ref());
-        let mut cfg = match contents {
-            Ok(s) => {
-                match serde_json::from_slice(&s) {
-                    Ok(cfg) => cfg,
-                    Err(_) => Self::default(),
-                }
+    /// Loads the config at 'path', or the default config if there is none
+    /// A config that can't be read is an error, s.t. it isn't replaced by the default config (and its nonce counter)
+    pub fn from_file<T: AsRef<str>>(path: T) -> Result<Self, String> {
+        let mut cfg = match std::fs::read_to_string(path.as_ref()) {
+            Ok(s) => Self::parse(&s, Format::of(&path))
+                .map_err(|e| format!("Config file {} could not be read ({})", path.as_ref(), e))?,
+            Err(_) => Config {
+                manifest_dir: Some(paths::default_manifest_dir(path.as_ref())),
+                ..Self::default()
             },
-            Err(_) => Self::default(),
         };
         cfg.location = path.as_ref().to_string();
         cfg.nonce_ctr = cfg.nonce_alloc;
-        cfg
+        cfg.open_credentials();
+        Ok(cfg)
+    }
+
+    fn parse(contents: &str, format: Format) -> Result<Config, String> {
+        match format {
+            Format::Json => serde_json::from_str(contents).map_err(|e| e.to_string()),
+            Format::Toml => toml_edit::de::from_str(contents).map_err(|e| e.to_string()),
+        }
     }
 
+    // Saves the config to 'path', in the format its extension calls for
+    // It is written next to it first and then moved into place, s.t. an interrupted save never leaves a partial config
     pub fn save_to<T: AsRef<str>>(&self, path: T) -> Result<(), std::io::Error> {
-        std::fs::write(path.as_ref(), serde_json::to_vec(self).unwrap())
+        paths::create_parent(path.as_ref())?;
+        let _lock = LockFile::acquire(Self::lock_path(path.as_ref()))?;
+        self.write_locked(path.as_ref())
+    }
+
+    // Runs sharing a config (e.g. of different jobs) take this lock while saving it or allocating nonces
+    fn lock_path(path: &str) -> String {
+        format!("{}.lock", path)
+    }
+
+    // The nonces allocated by the config saved at 'path', if it can be read
+    fn saved_nonce_alloc(path: &str) -> Option<u128> {
+        let contents = std::fs::read_to_string(path).ok()?;
+        Self::parse(&contents, Format::of(path)).ok().map(|cfg| cfg.nonce_alloc)
+    }
+
+    // Saves the config to 'path' while holding its lock
+    // The saved nonce allocation never decreases, s.t. a run that allocated less doesn't hand out the nonces of another again
+    fn write_locked(&self, path: &str) -> Result<(), std::io::Error> {
+        let mut sealed = self.sealed();
+        sealed.nonce_alloc = sealed.nonce_alloc.max(Self::saved_nonce_alloc(path).unwrap_or(0));
+        let contents = match Format::of(path) {
+            Format::Json => serde_json::to_string(&sealed).unwrap(),
+            Format::Toml => {
+                let existing = std::fs::read_to_string(path).ok();
+                sealed.to_toml(existing.as_deref())
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?
+            },
+        };
+        paths::write_atomic(path, contents)
+    }
+
+    // The config as TOML. If 'existing' is the current contents of the file, its comments and layout are kept
+    fn to_toml(&self, existing: Option<&str>) -> Result<String, String> {
+        let new = toml_edit::ser::to_document(self).map_err(|e| e.to_string())?;
+        match existing.and_then(|s| s.parse::<Document>().ok()) {
+            Some(mut doc) => {
+                merge(doc.as_table_mut(), new.as_table());
+                Ok(doc.to_string())
+            },
+            None => Ok(format!("{}{}", TOML_HEADER, new)),
+        }
+    }
+
+    // Applies the overrides in ENV_OVERRIDES that are set
+    pub fn apply_env(&mut self) {
+        for var in ENV_OVERRIDES.iter() {
+            if let Some(value) = std::env::var(var).ok().filter(|v| !v.is_empty()) {
+                let value = match *var {
+                    "RETAIN_APP_KEY_ID" | "RETAIN_BUCKET_NAME" => value,
+                    _ => format!("{}{}", secrets::ENV_PREFIX, var),
+                };
+                let original = self.env_field(var).replace(value.clone());
+                self.env_originals.push((var, value, original));
+            }
+        }
+    }
+
+    // The environment overrides that are applied
+    pub fn env_overrides(&self) -> Vec<&'static str> {
+        self.env_originals.iter().map(|(var, _, _)| *var).collect()
+    }
+
+    fn env_field(&mut self, var: &str) -> &mut Option<String> {
+        match var {
+            "RETAIN_APP_KEY_ID" => &mut self.app_key_id,
+            "RETAIN_APP_KEY" => &mut self.app_key,
+            "RETAIN_BUCKET_NAME" => &mut self.bucket_name,
+            "RETAIN_SECRET_KEY" => &mut self.secret_key,
+            "RETAIN_PRIVATE_KEY" => &mut self.private_key,
+            _ => unreachable!("{} is not in ENV_OVERRIDES", var),
+        }
+    }
+
+    /// Runs job 'name' from now on, its backup list, bucket and manifest directory replacing those of the config
+    /// None goes back to the config's own. Jobs are never saved into the config, it keeps its own values
+    pub fn apply_job(&mut self, name: Option<&str>) -> Result<(), String> {
+        if let Some((backup_list, bucket_name, manifest_dir)) = self.job_base.take() {
+            self.backup_list = backup_list;
+            self.bucket_name = bucket_name;
+            self.manifest_dir = manifest_dir;
+        }
+        self.job = None;
+        let name = match name {
+            Some(name) => name,
+            None => return Ok(()),
+        };
+        let job = self.jobs.iter().flatten().find(|job| job.name == name).cloned()
+            .ok_or_else(|| format!("There is no job named '{}', add it with 'config --add_job'", name))?;
+        let manifest_dir = job.manifest_dir.unwrap_or_else(|| jobs::default_manifest_dir(self.manifest_dir.as_deref().unwrap_or(""), name));
+        self.job_base = Some((self.backup_list.replace(job.backup_list), self.bucket_name.clone(), self.manifest_dir.replace(manifest_dir)));
+        if job.bucket_name.is_some() {
+            self.bucket_name = job.bucket_name;
+        }
+        self.job = Some(name.to_string());
+        Ok(())
+    }
+
+    // Decrypts the credentials, if they are encrypted
+    // Values that can't be decrypted are left encrypted, s.t. they aren't lost when the config is saved
+    fn open_credentials(&mut self) {
+        let secret_key = self.secret_key.clone();
+        for (name, field) in vec![("App Key ID", &mut self.app_key_id), ("App Key", &mut self.app_key)] {
+            if let Some(value) = field.as_mut().filter(|v| credentials::is_sealed(v)) {
+                match credentials::open(value, secret_key.as_deref()) {
+                    Ok(plain) => *value = plain,
+                    Err(e) => printcoln(Color::Yellow, format!("Warning: failed to decrypt {} ({})", name, e)),
+                }
+            }
+        }
+    }
+
+    // Copy of the config as it is written to disk, with the credentials encrypted if that is enabled
+    // Values from environment overrides are replaced by the ones they overrode, unless they were changed since
+    // Those of a job being run are replaced by the config's own
+    fn sealed(&self) -> Config {
+        let mut cfg = self.clone();
+        if let Some((backup_list, bucket_name, manifest_dir)) = &self.job_base {
+            cfg.backup_list = backup_list.clone();
+            cfg.bucket_name = bucket_name.clone();
+            cfg.manifest_dir = manifest_dir.clone();
+        }
+        for (var, applied, original) in &self.env_originals {
+            let field = cfg.env_field(var);
+            if field.as_ref() == Some(applied) {
+                *field = original.clone();
+            }
+        }
+        if let Some(protection) = self.protect_credentials {
+            for (name, field) in vec![("App Key ID", &mut cfg.app_key_id), ("App Key", &mut cfg.app_key)] {
+                if let Some(value) = field.as_mut().filter(|v| !credentials::is_sealed(v) && !secrets::is_reference(v)) {
+                    match credentials::seal(value, protection, cfg.secret_key.as_deref()) {
+                        Ok(sealed) => *value = sealed,
+                        Err(e) => printcoln(Color::Red, format!("Warning: failed to encrypt {}, it is saved in plain text ({})", name, e)),
+                    }
+                }
+            }
+        }
+        cfg
     }
 
     // Consume the specified amount of nonces
     // Returns the starting nonce that the consumer should use
     // Behind the scenes, this will handle pre-allocating and saving to disk
+    // In the random nonce mode, the counter isn't used at all
     pub fn consume_nonces(&mut self, amount: u128) -> u128 {
+        if self.nonce_mode == Some(NonceMode::Random) {
+            return encryption::random_start_nonce(self.cipher.unwrap_or(Cipher::XChaCha20Poly1305));
+        }
+        if self.nonce_ctr + amount < self.nonce_alloc {
+            let start = self.nonce_ctr;
+            self.nonce_ctr += amount;
+            return start;
+        }
+        // A new block is needed. Other runs with this config may have allocated blocks since it was loaded,
+        // so the block starts after whatever was saved last, under the lock s.t. no two runs get the same block
+        paths::create_parent(&self.location).unwrap();
+        let _lock = LockFile::acquire(Self::lock_path(&self.location)).unwrap();
+        let saved = Self::saved_nonce_alloc(&self.location).unwrap_or(0);
+        if saved > self.nonce_alloc {
+            self.nonce_ctr = saved;
+            self.nonce_alloc = saved;
+        }
         let start = self.nonce_ctr;
         self.nonce_ctr += amount;
-        let mut write = false;
         // In case we need to allocate a lot or pre-alloc is small, we may need multiple blocks
         while self.nonce_ctr >= self.nonce_alloc {
             self.nonce_alloc += NONCE_PREALLOC_AMOUNT;
-            write = true;
-        }
-        if write {
-            self.save();
         }
+        self.write_locked(&self.location).unwrap();
 
         start
     }
-}
\ No newline at end of file
+
+    // The App Key ID and App Key, as used for authorizing. The App Key may be in the OS keyring
+    pub fn keystring(&self) -> Result<String, String> {
+        let app_key_id = self.app_key_id.as_ref().ok_or("App Key ID is missing")?;
+        let app_key = self.app_key.as_ref().ok_or("App Key is missing")?;
+        if credentials::is_sealed(app_key_id) || credentials::is_sealed(app_key) {
+            return Err("The credentials could not be decrypted, set them again with 'config'".to_string());
+        }
+        Ok(format!("{}:{}", app_key_id, secrets::resolve(app_key)?))
+    }
+
+    // Amount of nonces allocated so far. Every nonce below this may have been used
+    pub fn nonces_allocated(&self) -> u128 {
+        self.nonce_alloc
+    }
+
+    /// Checks that the nonce counter isn't behind 'recorded', the nonces the manifest records as allocated
+    /// If it is, the config is an older copy (e.g. restored from a backup or copied from another machine),
+    /// and encrypting with it would reuse nonces that were already used with the same key
+    pub fn check_nonces(&self, recorded: u128) -> Result<(), String> {
+        if self.nonce_mode == Some(NonceMode::Random) || self.nonce_alloc >= recorded {
+            return Ok(());
+        }
+        Err(format!("The nonce counter ({}) is behind the nonces the manifest records as allocated ({}), continuing would reuse nonces. \
+                     The config may be an older copy, run 'config --advance_nonces' to continue after the recorded nonces", self.nonce_alloc, recorded))
+    }
+
+    /// Moves the nonce counter past 'recorded', see check_nonces
+    pub fn advance_nonces(&mut self, recorded: u128) {
+        if self.nonce_alloc < recorded {
+            self.nonce_alloc = recorded;
+            self.nonce_ctr = recorded;
+        }
+    }
+}
+// Writes the values of 'new' into 'old', keeping the comments and layout of 'old' where it has the same keys
+// Keys that aren't in 'new' (unset options) are removed
+fn merge(old: &mut dyn TableLike, new: &dyn TableLike) {
+    let unset: Vec<String> = old.iter().map(|(k, _)| k.to_string()).filter(|k| !new.contains_key(k)).collect();
+    for key in unset {
+        old.remove(&key);
+    }
+    for (key, item) in new.iter() {
+        match old.get_mut(key) {
+            Some(existing) if existing.is_table_like() && item.is_table_like() => {
+                merge(existing.as_table_like_mut().unwrap(), item.as_table_like().unwrap());
+            },
+            Some(existing) => match (existing.as_value_mut(), item.as_value()) {
+                (Some(value), Some(new_value)) => {
+                    let decor = value.decor().clone();
+                    *value = new_value.clone();
+                    *value.decor_mut() = decor;
+                },
+                _ => *existing = item.clone(),
+            },
+            None => {
+                old.insert(key, item.clone());
+            },
+        }
+    }
+}
+
+// The nonce counter is a u128, but TOML numbers are at most i64::MAX
+// It is written as a number while it fits, and as a string after that
+// The manifest records it the same way (see FileManifest::nonces), CBOR numbers are at most u64::MAX
+pub(crate) mod nonce_count {
+    use serde::{Serializer, Deserializer};
+    use serde::de::{self, Visitor};
+    use std::fmt::Formatter;
+
+    pub fn serialize<S: Serializer>(value: &u128, serializer: S) -> Result<S::Ok, S::Error> {
+        if *value <= i64::MAX as u128 {
+            serializer.serialize_u64(*value as u64)
+        } else {
+            serializer.serialize_str(&value.to_string())
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u128, D::Error> {
+        deserializer.deserialize_any(CountVisitor)
+    }
+
+    struct CountVisitor;
+
+    impl<'de> Visitor<'de> for CountVisitor {
+        type Value = u128;
+
+        fn expecting(&self, f: &mut Formatter) -> std::fmt::Result {
+            f.write_str("a non-negative number")
+        }
+
+        fn visit_u64<E: de::Error>(self, v: u64) -> Result<u128, E> {
+            Ok(v as u128)
+        }
+
+        fn visit_i64<E: de::Error>(self, v: i64) -> Result<u128, E> {
+            if v < 0 {
+                return Err(E::invalid_value(de::Unexpected::Signed(v), &self));
+            }
+            Ok(v as u128)
+        }
+
+        fn visit_u128<E: de::Error>(self, v: u128) -> Result<u128, E> {
+            Ok(v)
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<u128, E> {
+            v.parse().map_err(|_| E::invalid_value(de::Unexpected::Str(v), &self))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Config, Format, NONCE_PREALLOC_AMOUNT};
+    use crate::encryption::NonceMode;
+    use crate::jobs::Job;
+
+    #[test]
+    fn test_env_overrides() {
+        std::env::set_var("RETAIN_BUCKET_NAME", "from-env");
+        std::env::set_var("RETAIN_APP_KEY", "K001secret");
+        let mut config = Config::default();
+        config.bucket_name = Some("from-file".to_string());
+        config.apply_env();
+        std::env::remove_var("RETAIN_BUCKET_NAME");
+        std::env::remove_var("RETAIN_APP_KEY");
+
+        assert_eq!(config.bucket_name.as_deref(), Some("from-env"));
+        // Secrets are only referred to
+        assert_eq!(config.app_key.as_deref(), Some("env:RETAIN_APP_KEY"));
+        assert_eq!(config.env_overrides(), vec!["RETAIN_APP_KEY", "RETAIN_BUCKET_NAME"]);
+
+        // Overrides aren't saved, but values changed since are
+        config.app_key = Some("K002changed".to_string());
+        let sealed = config.sealed();
+        assert_eq!(sealed.bucket_name.as_deref(), Some("from-file"));
+        assert_eq!(sealed.app_key.as_deref(), Some("K002changed"));
+
+        // Neither are the values of a job being run
+        let mut job = Job::new("photos", "/photos.txt");
+        job.bucket_name = Some("photos".to_string());
+        config.jobs = Some(vec![job]);
+        config.apply_job(Some("photos")).unwrap();
+        let sealed = config.sealed();
+        assert_eq!(sealed.bucket_name.as_deref(), Some("from-file"));
+        assert_eq!(sealed.backup_list, None);
+    }
+
+    #[test]
+    fn test_toml() {
+        assert_eq!(Format::of("retain.toml"), Format::Toml);
+        assert_eq!(Format::of("retain.cfg"), Format::Json);
+
+        let mut config = Config::default();
+        config.bucket_name = Some("bucket".to_string());
+        config.nonce_alloc = u128::MAX;
+        let toml = config.to_toml(None).unwrap();
+        let parsed = Config::parse(&toml, Format::Toml).unwrap();
+        assert_eq!(parsed.bucket_name.as_deref(), Some("bucket"));
+        assert_eq!(parsed.nonce_alloc, u128::MAX);
+
+        // Comments are kept, unset options are removed
+        let existing = "# Which bucket\nbucket_name = \"old\" # B2 bucket\nbackup_list = \"files.txt\"\nnonce_alloc = 0\n";
+        config.nonce_alloc = 65536;
+        let toml = config.to_toml(Some(existing)).unwrap();
+        assert_eq!(toml, "# Which bucket\nbucket_name = \"bucket\" # B2 bucket\nnonce_alloc = 65536\n");
+
+        // Configs written before TOML keep working
+        let parsed = Config::parse("{\"bucket_name\":\"bucket\",\"nonce_alloc\":131072}", Format::Json).unwrap();
+        assert_eq!(parsed.nonce_alloc, 131072);
+        // Including counts stored as strings, and ones too large for a number
+        let parsed = Config::parse("{\"nonce_alloc\":\"196608\"}", Format::Json).unwrap();
+        assert_eq!(parsed.nonce_alloc, 196608);
+        let parsed = Config::parse(&format!("nonce_alloc = \"{}\"\n", u128::MAX), Format::Toml).unwrap();
+        assert_eq!(parsed.nonce_alloc, u128::MAX);
+        assert!(Config::parse("{\"nonce_alloc\":\"many\"}", Format::Json).is_err());
+        assert!(Config::parse("{\"nonce_alloc\":-1}", Format::Json).is_err());
+    }
+
+    #[test]
+    fn test_run_log_dir() {
+        // Configs made before run logs existed have no directory
+        let mut config = Config { manifest_dir: Some("manifests".to_string()), ..Config::default() };
+        assert_eq!(config.run_log_dir(), Some(std::path::Path::new("manifests").join("logs").to_string_lossy().to_string()));
+        config.log_dir = Some("/var/log/retain".to_string());
+        assert_eq!(config.run_log_dir().as_deref(), Some("/var/log/retain"));
+        config.run_logs = Some(false);
+        assert_eq!(config.run_log_dir(), None);
+    }
+
+    #[test]
+    fn test_check_nonces() {
+        let mut config = Config { nonce_alloc: 65536, ..Config::default() };
+        assert!(config.check_nonces(65536).is_ok());
+        // Another copy of the config allocated more since
+        assert!(config.check_nonces(131072).is_err());
+        config.advance_nonces(131072);
+        assert_eq!((config.nonce_alloc, config.nonce_ctr), (131072, 131072));
+        assert!(config.check_nonces(131072).is_ok());
+        config.nonce_mode = Some(NonceMode::Random);
+        assert!(config.check_nonces(u128::MAX).is_ok());
+    }
+
+    #[test]
+    fn test_concurrent_nonces() {
+        let dir = std::env::temp_dir().join("retain-test-concurrent-nonces");
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join("retain.json").to_string_lossy().to_string();
+        Config::default().save_to(&path).unwrap();
+        // Two runs loaded the same config, e.g. of two jobs
+        let (mut a, mut b) = (Config::from_file(&path).unwrap(), Config::from_file(&path).unwrap());
+        let first_a = a.consume_nonces(10);
+        let first_b = b.consume_nonces(10);
+        assert!(first_b >= first_a + 10);
+        let more_a = a.consume_nonces(NONCE_PREALLOC_AMOUNT);
+        assert!(more_a >= first_b + 10);
+        // Saving a copy that allocated less keeps the allocation of the other
+        b.save();
+        assert_eq!(Config::from_file(&path).unwrap().nonce_alloc, a.nonce_alloc);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
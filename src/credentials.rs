@@ -0,0 +1,160 @@
+//! Encryption of the B2 credentials (App Key ID and App Key) in the config file
+//!
+//! When enabled, the credentials are encrypted whenever the config is saved and decrypted when it is loaded,
+//! s.t. the rest of the program only sees them in plain text
+//! An encrypted value is stored as 'enc:<method>:<hex>', where <hex> is a random 24 byte nonce followed by the
+//! XChaCha20Poly1305 encrypted value. The method is the key used:
+//! 'key': derived from the secret key, s.t. the config is useless without it
+//! 'machine': derived from the ID of the machine, s.t. the config is useless when copied elsewhere
+//!
+//! Values in the OS keyring (see secrets.rs) are left as is, they aren't stored in the config
+
+use chacha20poly1305::{XChaCha20Poly1305, Key, XNonce};
+use chacha20poly1305::aead::{Aead, NewAead};
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use rand::{thread_rng, Rng};
+use serde::{Serialize, Deserialize};
+use crate::secrets;
+
+const PREFIX: &str = "enc:";
+
+/// Which key the credentials are encrypted with
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protection {
+    Key,
+    Machine,
+}
+
+impl Protection {
+    fn name(&self) -> &'static str {
+        match self {
+            Protection::Key => "key",
+            Protection::Machine => "machine",
+        }
+    }
+}
+
+/// Whether a config value is encrypted
+pub fn is_sealed(value: &str) -> bool {
+    value.starts_with(PREFIX)
+}
+
+/// Encrypts a config value. 'secret_key' is the location of the secret key, needed for Protection::Key
+pub fn seal(value: &str, protection: Protection, secret_key: Option<&str>) -> Result<String, String> {
+    let key = derive_key(protection, secret_key)?;
+    let mut nonce = [0u8; 24];
+    thread_rng().try_fill(&mut nonce).map_err(|e| format!("failed to generate nonce ({})", e))?;
+    let ciphertext = XChaCha20Poly1305::new(&key).encrypt(XNonce::from_slice(&nonce), value.as_bytes())
+        .map_err(|_| "encryption failed".to_string())?;
+    let mut bytes = nonce.to_vec();
+    bytes.extend(ciphertext);
+    Ok(format!("{}{}:{}", PREFIX, protection.name(), secrets::encode_hex(&bytes)))
+}
+
+/// Decrypts a config value made by 'seal'
+pub fn open(value: &str, secret_key: Option<&str>) -> Result<String, String> {
+    let mut parts = value.strip_prefix(PREFIX).ok_or("value is not encrypted")?.splitn(2, ':');
+    let protection = match parts.next() {
+        Some("key") => Protection::Key,
+        Some("machine") => Protection::Machine,
+        _ => return Err("unknown encryption method".to_string()),
+    };
+    let bytes = secrets::decode_hex(parts.next().unwrap_or(""))?;
+    if bytes.len() < 24 {
+        return Err("encrypted value is too short".to_string());
+    }
+    let key = derive_key(protection, secret_key)?;
+    let plaintext = XChaCha20Poly1305::new(&key).decrypt(XNonce::from_slice(&bytes[..24]), &bytes[24..])
+        .map_err(|_| match protection {
+            Protection::Key => "decryption failed, it was encrypted with a different secret key".to_string(),
+            Protection::Machine => "decryption failed, it was encrypted on a different machine".to_string(),
+        })?;
+    String::from_utf8(plaintext).map_err(|_| "decrypted value is not valid UTF-8".to_string())
+}
+
+// A separate key is derived, s.t. the secret key is only used directly for encryption of files
+fn derive_key(protection: Protection, secret_key: Option<&str>) -> Result<Key, String> {
+    let (material, context): (Vec<u8>, &[u8]) = match protection {
+        Protection::Key => {
+            let location = secret_key.ok_or("no secret key is configured")?;
+            let key = secrets::read_key(location).map_err(|e| format!("failed to read the secret key ({})", e))?;
+            (key, b"retain-rs credentials")
+        },
+        Protection::Machine => (machine_id()?.into_bytes(), b"retain-rs machine credentials"),
+    };
+    let mut mac = Hmac::<Sha256>::new_varkey(&material).expect("HMAC accepts any key length");
+    mac.update(context);
+    Ok(Key::clone_from_slice(&mac.finalize().into_bytes()))
+}
+
+/// ID of this machine, which stays the same across reboots
+#[cfg(any(target_os = "linux", target_os = "freebsd"))]
+pub fn machine_id() -> Result<String, String> {
+    ["/etc/machine-id", "/var/lib/dbus/machine-id", "/etc/hostid"].iter()
+        .filter_map(|path| std::fs::read_to_string(path).ok())
+        .map(|id| id.trim().to_string())
+        .find(|id| !id.is_empty())
+        .ok_or_else(|| "no machine ID found".to_string())
+}
+
+#[cfg(target_os = "macos")]
+pub fn machine_id() -> Result<String, String> {
+    let output = std::process::Command::new("ioreg").args(&["-rd1", "-c", "IOPlatformExpertDevice"]).output()
+        .map_err(|e| format!("failed to run ioreg ({})", e))?;
+    // Line looks like: "IOPlatformUUID" = "XXXXXXXX-XXXX-XXXX-XXXX-XXXXXXXXXXXX"
+    String::from_utf8_lossy(&output.stdout).lines()
+        .find(|line| line.contains("IOPlatformUUID"))
+        .and_then(|line| line.split('"').nth(3))
+        .map(|id| id.to_string())
+        .ok_or_else(|| "no machine ID found".to_string())
+}
+
+#[cfg(windows)]
+pub fn machine_id() -> Result<String, String> {
+    let output = std::process::Command::new("reg")
+        .args(&["query", r"HKLM\SOFTWARE\Microsoft\Cryptography", "/v", "MachineGuid"]).output()
+        .map_err(|e| format!("failed to run reg ({})", e))?;
+    // Line looks like: MachineGuid    REG_SZ    xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx
+    String::from_utf8_lossy(&output.stdout).lines()
+        .find(|line| line.contains("MachineGuid"))
+        .and_then(|line| line.split_whitespace().last())
+        .map(|id| id.to_string())
+        .ok_or_else(|| "no machine ID found".to_string())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "freebsd", target_os = "macos", windows)))]
+pub fn machine_id() -> Result<String, String> {
+    Err("machine IDs are not supported on this platform".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::credentials::{seal, open, is_sealed, machine_id, Protection};
+
+    #[test]
+    fn test_seal_open() {
+        let key_path = std::env::temp_dir().join("retain-test-credentials-key");
+        let other_path = std::env::temp_dir().join("retain-test-credentials-other");
+        std::fs::write(&key_path, [7u8; 32]).unwrap();
+        std::fs::write(&other_path, [8u8; 32]).unwrap();
+        let key_path = key_path.to_str().unwrap();
+
+        let sealed = seal("K001secret", Protection::Key, Some(key_path)).unwrap();
+        assert!(is_sealed(&sealed) && !sealed.contains("K001secret"));
+        // Nonces are random, s.t. equal values don't look equal
+        assert_ne!(sealed, seal("K001secret", Protection::Key, Some(key_path)).unwrap());
+        assert_eq!(open(&sealed, Some(key_path)), Ok("K001secret".to_string()));
+        assert!(open(&sealed, other_path.to_str()).is_err());
+        assert!(open(&sealed, None).is_err());
+
+        if machine_id().is_ok() {
+            let sealed = seal("K001secret", Protection::Machine, None).unwrap();
+            assert_eq!(open(&sealed, None), Ok("K001secret".to_string()));
+        }
+
+        let _ = std::fs::remove_file(key_path);
+        let _ = std::fs::remove_file(other_path);
+    }
+}
@@ -0,0 +1,97 @@
+//! Parsing and formatting of dates and ages given on the command line or in the backup list
+//!
+//! Timestamps are kept as milliseconds since Unix Epoch, like the modified times in the manifest
+
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeZone};
+
+// Accepted formats for a date with a time, interpreted as local time
+const DATETIME_FORMATS: &[&str] = &["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M"];
+
+/// Parses a date given by the user into milliseconds since Unix Epoch
+/// Accepts RFC 3339 (e.g. 2020-10-16T14:00:00+02:00), local time (e.g. 2020-10-16 14:00)
+/// and dates (e.g. 2020-10-16), which mean the end of that day
+pub fn parse_datetime(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    if let Ok(dt) = DateTime::parse_from_rfc3339(s) {
+        return to_millis(dt.timestamp_millis());
+    }
+    for format in DATETIME_FORMATS {
+        if let Ok(naive) = NaiveDateTime::parse_from_str(s, format) {
+            return local_to_millis(naive);
+        }
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        return local_to_millis(date.and_hms_milli_opt(23, 59, 59, 999).unwrap());
+    }
+    Err(format!("Invalid date '{}', expected e.g. '2020-10-16', '2020-10-16 14:00' or RFC 3339", s))
+}
+
+/// Parses an age given by the user, e.g. '30d', into milliseconds
+/// The units are s(econds), m(inutes), h(ours), d(ays) and w(eeks)
+pub fn parse_age(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let invalid = || format!("Invalid age '{}', expected a number and a unit, e.g. '30d' or '12h'", s);
+    let (number, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).ok_or_else(invalid)?);
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        "w" => 7 * 86400,
+        _ => return Err(invalid()),
+    };
+    number.parse::<u64>().ok().and_then(|n| n.checked_mul(seconds * 1000)).ok_or_else(invalid)
+}
+
+/// Formats milliseconds since Unix Epoch as local time
+pub fn format_millis(millis: u64) -> String {
+    match Local.timestamp_millis_opt(millis as i64).earliest() {
+        Some(dt) => dt.format("%Y-%m-%d %H:%M:%S").to_string(),
+        None => format!("{}ms", millis),
+    }
+}
+
+fn local_to_millis(naive: NaiveDateTime) -> Result<u64, String> {
+    match Local.from_local_datetime(&naive).earliest() {
+        Some(dt) => to_millis(dt.timestamp_millis()),
+        None => Err(format!("{} does not exist in the local time zone", naive)),
+    }
+}
+
+fn to_millis(millis: i64) -> Result<u64, String> {
+    if millis < 0 {
+        Err("Dates before 1970 are not supported".to_string())
+    } else {
+        Ok(millis as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::datetime::{parse_datetime, parse_age, format_millis};
+
+    #[test]
+    fn test_parse_datetime() {
+        assert_eq!(parse_datetime("2020-10-16T14:00:00Z").unwrap(), 1602856800000);
+        assert_eq!(parse_datetime("2020-10-16T16:00:00+02:00").unwrap(), 1602856800000);
+        // Local time, so only compare them to each other
+        let day_end = parse_datetime("2020-10-16").unwrap();
+        let afternoon = parse_datetime("2020-10-16 14:00").unwrap();
+        assert_eq!(parse_datetime("2020-10-16T14:00:00").unwrap(), afternoon);
+        assert_eq!(day_end - afternoon, (10*3600 - 1) * 1000 + 999);
+        assert_eq!(format_millis(afternoon), "2020-10-16 14:00:00");
+        assert!(parse_datetime("yesterday").is_err());
+        assert!(parse_datetime("1960-01-01").is_err());
+    }
+
+    #[test]
+    fn test_parse_age() {
+        assert_eq!(parse_age("30d").unwrap(), 30 * 86400 * 1000);
+        assert_eq!(parse_age("12h").unwrap(), 12 * 3600 * 1000);
+        assert_eq!(parse_age("2w").unwrap(), 14 * 86400 * 1000);
+        assert!(parse_age("30").is_err());
+        assert!(parse_age("d").is_err());
+        assert!(parse_age("30y").is_err());
+        assert!(parse_age("99999999999999999999d").is_err());
+    }
+}
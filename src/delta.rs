@@ -0,0 +1,224 @@
+//! Delta uploads for large files
+//!
+//! Large files that are modified often (logs, mail spools, database dumps) tend to change only in a
+//! few places, usually at the end. Instead of uploading the whole file again, we upload a delta
+//! containing only the blocks that changed since the previous upload
+//!
+//! Files are split into blocks of DELTA_BLOCK_SIZE bytes and the SHA1 of each block is kept in the manifest
+//! On the next upload, blocks whose hash differ (or that are new) are put in a delta file,
+//! which is uploaded as `<name>.delta<n>`. Restoring downloads the base file and applies each delta in order
+//!
+//! Delta format (before encryption):
+//! ```text
+//! [u64 LE: length of the new file]
+//! repeated for each changed block:
+//! [u64 LE: block index][u32 LE: block length][block data]
+//! ```
+//!
+//! A full upload is done instead if too much changed or there are already MAX_DELTAS deltas,
+//! s.t. restoring doesn't require downloading an ever-growing chain of deltas
+
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::fs::File;
+
+// Files smaller than this are always uploaded in full
+pub const DELTA_THRESHOLD: u64 = 64*1024*1024;
+// Size of the blocks that are compared between versions
+pub const DELTA_BLOCK_SIZE: u64 = 4*1024*1024;
+// Amount of deltas that can be stacked on top of a full upload
+pub const MAX_DELTAS: usize = 8;
+
+/// Blocks of a file that changed compared to a previous version
+pub struct Changes {
+    pub length: u64, // Length of the file
+    pub hashes: Vec<String>, // Hash of every block in the file
+    pub sha1: String, // Hash of the whole file
+    pub changed: Vec<u64>, // Indices of blocks that differ from the previous version
+}
+
+impl Changes {
+    /// Size of the delta containing the changed blocks
+    pub fn delta_size(&self) -> u64 {
+        let mut size = 8;
+        for idx in &self.changed {
+            size += 12 + block_length(*idx, self.length);
+        }
+        size
+    }
+
+    /// Whether uploading a delta is worthwhile, given how many deltas already exist
+    /// If more than half the file changed, a full upload is better
+    pub fn use_delta(&self, existing_deltas: usize, previous: &[String]) -> bool {
+        !previous.is_empty() && existing_deltas < MAX_DELTAS && self.delta_size() < self.length / 2
+    }
+}
+
+// Length of the block with the given index, in a file of the given length
+fn block_length(idx: u64, length: u64) -> u64 {
+    DELTA_BLOCK_SIZE.min(length - idx * DELTA_BLOCK_SIZE)
+}
+
+/// Hashes every block of 'reader', comparing them to the hashes of the previous version
+pub fn changed_blocks<R: Read>(mut reader: R, previous: &[String]) -> std::io::Result<Changes> {
+    let mut changes = Changes {
+        length: 0,
+        hashes: Vec::new(),
+        sha1: String::new(),
+        changed: Vec::new(),
+    };
+    let mut whole = sha1::Sha1::new();
+    let mut buf = Vec::with_capacity(DELTA_BLOCK_SIZE as usize);
+    loop {
+        buf.clear();
+        (&mut reader).take(DELTA_BLOCK_SIZE).read_to_end(&mut buf)?;
+        if buf.is_empty() {
+            break;
+        }
+        let idx = changes.hashes.len();
+        whole.update(&buf);
+        let hash = sha1::Sha1::from(&buf).digest().to_string();
+        if previous.get(idx) != Some(&hash) {
+            changes.changed.push(idx as u64);
+        }
+        changes.hashes.push(hash);
+        changes.length += buf.len() as u64;
+    }
+    changes.sha1 = whole.digest().to_string();
+    Ok(changes)
+}
+
+/// Reads the changed blocks of a file, producing a delta
+pub struct DeltaReader {
+    file: File,
+    length: u64,
+    blocks: std::vec::IntoIter<u64>,
+    header: Vec<u8>, // Pending header bytes
+    remaining: u64, // Remaining data bytes of the current block
+}
+
+impl DeltaReader {
+    /// Opens the file at 'path' to produce a delta with the changed blocks
+    pub fn open<T: AsRef<std::path::Path>>(path: T, changes: &Changes) -> std::io::Result<Self> {
+        Ok(DeltaReader {
+            file: File::open(path)?,
+            length: changes.length,
+            blocks: changes.changed.clone().into_iter(),
+            header: changes.length.to_le_bytes().to_vec(),
+            remaining: 0,
+        })
+    }
+}
+
+impl Read for DeltaReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        if !self.header.is_empty() {
+            let n = buf.len().min(self.header.len());
+            buf[..n].copy_from_slice(&self.header[..n]);
+            self.header.drain(..n);
+            return Ok(n);
+        }
+        if self.remaining > 0 {
+            let n = (buf.len() as u64).min(self.remaining) as usize;
+            let read = self.file.read(&mut buf[..n])?;
+            if read == 0 {
+                return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "File shrunk while creating delta"));
+            }
+            self.remaining -= read as u64;
+            return Ok(read);
+        }
+        match self.blocks.next() {
+            Some(idx) => {
+                let len = block_length(idx, self.length);
+                self.file.seek(SeekFrom::Start(idx * DELTA_BLOCK_SIZE))?;
+                self.header.extend_from_slice(&idx.to_le_bytes());
+                self.header.extend_from_slice(&(len as u32).to_le_bytes());
+                self.remaining = len;
+                self.read(buf)
+            }
+            None => Ok(0),
+        }
+    }
+}
+
+/// Applies a delta to 'file', turning the previous version into the version the delta was made from
+pub fn apply_delta<R: Read>(file: &mut File, mut delta: R) -> std::io::Result<()> {
+    let mut u64_bytes = [0u8; 8];
+    let mut u32_bytes = [0u8; 4];
+    delta.read_exact(&mut u64_bytes)?;
+    file.set_len(u64::from_le_bytes(u64_bytes))?;
+
+    let mut buf = Vec::with_capacity(DELTA_BLOCK_SIZE as usize);
+    loop {
+        // Either another block or the end of the delta
+        match delta.read_exact(&mut u64_bytes) {
+            Ok(_) => (),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+        delta.read_exact(&mut u32_bytes)?;
+        let idx = u64::from_le_bytes(u64_bytes);
+        let len = u32::from_le_bytes(u32_bytes) as u64;
+
+        buf.clear();
+        (&mut delta).take(len).read_to_end(&mut buf)?;
+        if buf.len() as u64 != len {
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Delta is truncated"));
+        }
+        file.seek(SeekFrom::Start(idx * DELTA_BLOCK_SIZE))?;
+        file.write_all(&buf)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::delta::{changed_blocks, apply_delta, DeltaReader, DELTA_BLOCK_SIZE};
+    use std::io::{Cursor, Read};
+
+    #[test]
+    fn test_delta_roundtrip() {
+        let bs = DELTA_BLOCK_SIZE as usize;
+        let old = vec![1u8; bs*3 + 100];
+        let mut new = old.clone();
+        new[bs + 5] = 2; // Change block 1
+        new.truncate(bs*3 + 50); // Shrink last block
+        new.extend_from_slice(&vec![3u8; bs]); // Append
+
+        let dir = std::env::temp_dir();
+        let new_path = dir.join("retain-delta-new.dat");
+        let old_path = dir.join("retain-delta-old.dat");
+        std::fs::write(&new_path, &new).unwrap();
+        std::fs::write(&old_path, &old).unwrap();
+
+        let previous = changed_blocks(Cursor::new(&old), &[]).unwrap();
+        assert_eq!(previous.changed, vec![0, 1, 2, 3]);
+        let changes = changed_blocks(Cursor::new(&new), &previous.hashes).unwrap();
+        assert_eq!(changes.changed, vec![1, 3, 4]);
+        assert_eq!(changes.length, new.len() as u64);
+
+        let mut delta = Vec::new();
+        DeltaReader::open(&new_path, &changes).unwrap().read_to_end(&mut delta).unwrap();
+        assert_eq!(delta.len() as u64, changes.delta_size());
+
+        let mut file = std::fs::OpenOptions::new().read(true).write(true).open(&old_path).unwrap();
+        apply_delta(&mut file, Cursor::new(delta)).unwrap();
+        drop(file);
+        assert_eq!(std::fs::read(&old_path).unwrap(), new);
+
+        std::fs::remove_file(new_path).unwrap();
+        std::fs::remove_file(old_path).unwrap();
+    }
+
+    #[test]
+    fn test_use_delta() {
+        let previous = changed_blocks(Cursor::new(vec![0u8; DELTA_BLOCK_SIZE as usize * 4]), &[]).unwrap();
+        let mut data = vec![0u8; DELTA_BLOCK_SIZE as usize * 4];
+        data[0] = 1;
+        let changes = changed_blocks(Cursor::new(&data), &previous.hashes).unwrap();
+        assert!(changes.use_delta(0, &previous.hashes));
+        assert!(!changes.use_delta(crate::delta::MAX_DELTAS, &previous.hashes));
+        assert!(!changes.use_delta(0, &[]));
+        // Everything changed, full upload is better
+        assert!(!previous.use_delta(0, &previous.hashes));
+    }
+}
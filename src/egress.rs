@@ -0,0 +1,178 @@
+//! Tracking of the amount of data downloaded during a restore
+//!
+//! B2 allows downloading some data for free, beyond that downloads ("egress") are billed per GB
+//! A full restore can easily go past the allowance, so the bytes downloaded in a run are counted:
+//! a warning is printed once each configured threshold is passed, and with a maximum (--max-download)
+//! no new download is started once it would exceed it. The run then either pauses until the daily caps
+//! reset (00:00 UTC) or stops, as for API call limits (see budget.rs)
+//!
+//! Downloads in progress are finished, and the size of files whose size isn't known up front (e.g. compressed ones)
+//! is only counted once they are downloaded, so a run can go past the maximum by what is downloaded concurrently
+
+use crate::budget::{self, LimitAction};
+use crate::colorutil::printcoln;
+use indicatif::HumanBytes;
+use std::io::Read;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use termcolor::Color;
+
+// Percentage of the maximum at which a warning is printed, as with API call limits
+const WARN_PERCENT: u64 = 80;
+
+/// Returned when a download would exceed the maximum
+#[derive(Debug)]
+pub struct CapReached {
+    pub max: u64,
+}
+
+impl std::fmt::Display for CapReached {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "limit of {} downloaded reached", HumanBytes(self.max))
+    }
+}
+
+impl std::error::Error for CapReached {}
+
+/// Counts the bytes downloaded in a run, shared by all download tasks
+pub struct DownloadCap {
+    downloaded: AtomicU64,
+    max: Option<u64>,
+    // Amounts at which a warning is printed, ascending
+    thresholds: Vec<u64>,
+    // Amount of thresholds warned about
+    warned: AtomicUsize,
+    action: LimitAction,
+    // Held while pausing, s.t. only one task waits for the caps to reset
+    pause: Mutex<()>,
+}
+
+impl DownloadCap {
+    /// Counts downloads, warning at each of 'thresholds' and stopping at 'max' if set
+    /// With a maximum, a warning is also printed once WARN_PERCENT of it is downloaded
+    pub fn new(max: Option<u64>, thresholds: &[u64], action: LimitAction) -> Self {
+        let mut thresholds = thresholds.to_vec();
+        if let Some(max) = max {
+            thresholds.push(max * WARN_PERCENT / 100);
+        }
+        thresholds.sort_unstable();
+        thresholds.dedup();
+        DownloadCap {
+            downloaded: AtomicU64::new(0),
+            max,
+            thresholds,
+            warned: AtomicUsize::new(0),
+            action,
+            pause: Mutex::new(()),
+        }
+    }
+
+    /// Checks whether a download of 'size' bytes (0 if unknown) can be started
+    /// If it would exceed the maximum, this either pauses or returns an error, depending on the config
+    pub fn admit(&self, size: u64) -> Result<(), CapReached> {
+        let max = match self.max {
+            Some(m) => m,
+            None => return Ok(()),
+        };
+        loop {
+            let downloaded = self.downloaded();
+            // A file larger than the maximum is allowed when nothing has been downloaded yet, or it would never be
+            // Files of unknown size are allowed until the maximum is reached
+            let fits = match size {
+                0 => downloaded < max,
+                _ => downloaded + size <= max,
+            };
+            if fits || downloaded == 0 {
+                return Ok(());
+            }
+            match self.action {
+                LimitAction::Abort => return Err(CapReached { max }),
+                LimitAction::Pause => {
+                    let _guard = self.pause.lock().unwrap();
+                    // Another task may have waited already
+                    if self.downloaded() >= downloaded {
+                        let wait = budget::until_cap_reset();
+                        printcoln(Color::Yellow, format!("Limit of {} downloaded reached, pausing for {}h{:02}m until the daily caps reset",
+                                                         HumanBytes(max), wait.as_secs() / 3600, wait.as_secs() / 60 % 60));
+                        std::thread::sleep(wait);
+                        self.downloaded.store(0, Ordering::SeqCst);
+                        self.warned.store(0, Ordering::SeqCst);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Counts downloaded bytes, warning about each threshold that is passed
+    pub fn add(&self, bytes: u64) {
+        let downloaded = self.downloaded.fetch_add(bytes, Ordering::SeqCst) + bytes;
+        let passed = self.thresholds.iter().take_while(|t| **t <= downloaded).count();
+        let warned = self.warned.fetch_max(passed, Ordering::SeqCst);
+        if passed > warned {
+            let message = match self.max {
+                Some(max) => format!("Warning: {} of at most {} downloaded", HumanBytes(downloaded), HumanBytes(max)),
+                None => format!("Warning: {} downloaded", HumanBytes(downloaded)),
+            };
+            printcoln(Color::Yellow, message);
+        }
+    }
+
+    /// Wraps a reader, s.t. the bytes read from it are counted
+    pub fn wrap_read<R: Read>(&self, reader: R) -> CountedReader<'_, R> {
+        CountedReader { inner: reader, cap: self }
+    }
+
+    /// Amount of bytes downloaded so far, since the last pause
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded.load(Ordering::SeqCst)
+    }
+
+    pub fn max(&self) -> Option<u64> {
+        self.max
+    }
+}
+
+pub struct CountedReader<'a, R: Read> {
+    inner: R,
+    cap: &'a DownloadCap,
+}
+
+impl<R: Read> Read for CountedReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        let n = self.inner.read(buf)?;
+        self.cap.add(n as u64);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::budget::LimitAction;
+    use crate::egress::DownloadCap;
+    use std::io::Read;
+
+    #[test]
+    fn test_download_cap() {
+        let cap = DownloadCap::new(Some(1000), &[200, 500], LimitAction::Abort);
+        // Warnings at 200, 500 and 80% of the maximum
+        assert_eq!(cap.thresholds, vec![200, 500, 800]);
+        assert!(cap.admit(600).is_ok());
+        cap.add(600);
+        assert_eq!(cap.warned.load(std::sync::atomic::Ordering::SeqCst), 2);
+        assert!(cap.admit(400).is_ok());
+        assert!(cap.admit(401).is_err());
+        // Sizes that aren't known are allowed until the maximum is reached
+        assert!(cap.admit(0).is_ok());
+        let mut out = Vec::new();
+        cap.wrap_read(&[1u8; 400][..]).read_to_end(&mut out).unwrap();
+        assert_eq!(cap.downloaded(), 1000);
+        assert!(cap.admit(0).is_err());
+
+        // A file larger than the maximum is still downloaded if it is the first
+        assert!(DownloadCap::new(Some(10), &[], LimitAction::Abort).admit(20).is_ok());
+        // Without a maximum, downloads are only counted
+        let unlimited = DownloadCap::new(None, &[100], LimitAction::Abort);
+        unlimited.add(1 << 40);
+        assert!(unlimited.admit(1 << 40).is_ok());
+    }
+}
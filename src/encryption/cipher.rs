@@ -0,0 +1,86 @@
+/// Provides the ciphers files can be encrypted with
+///
+/// XChaCha20Poly1305 is the default. AES-256-GCM is much faster on CPUs with AES instructions,
+/// which are used when building with RUSTFLAGS="-C target-feature=+aes,+ssse3"
+/// The cipher of a file is stored in its key header, files without one always use XChaCha20Poly1305
+///
+/// Both use the same 256 bit keys and the nonce counter, see mod.rs
+/// AES-256-GCM nonces are only 96 bits, which are the lower 96 bits of the counter
+
+use chacha20poly1305::{XChaCha20Poly1305, Key};
+use chacha20poly1305::aead::{Aead, NewAead, Error};
+use chacha20poly1305::aead::generic_array::GenericArray;
+use aes_gcm::Aes256Gcm;
+use serde::{Serialize, Deserialize};
+use crate::encryption::nonce_from_u128;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Cipher {
+    XChaCha20Poly1305,
+    Aes256Gcm,
+}
+
+impl Cipher {
+    /// ID stored in the key header
+    pub fn id(&self) -> u8 {
+        match self {
+            Cipher::XChaCha20Poly1305 => 0,
+            Cipher::Aes256Gcm => 1,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Cipher::XChaCha20Poly1305),
+            1 => Some(Cipher::Aes256Gcm),
+            _ => None,
+        }
+    }
+
+    /// Largest nonce that fits the cipher, plus one
+    pub fn nonce_limit(&self) -> u128 {
+        match self {
+            Cipher::XChaCha20Poly1305 => u128::MAX,
+            Cipher::Aes256Gcm => 1 << 96,
+        }
+    }
+}
+
+impl std::fmt::Display for Cipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Cipher::XChaCha20Poly1305 => write!(f, "XChaCha20Poly1305"),
+            Cipher::Aes256Gcm => write!(f, "AES-256-GCM"),
+        }
+    }
+}
+
+/// A cipher together with its key, encrypting and decrypting single blocks
+pub enum AeadCipher {
+    XChaCha20Poly1305(XChaCha20Poly1305),
+    Aes256Gcm(Aes256Gcm),
+}
+
+impl AeadCipher {
+    pub fn new(cipher: Cipher, key: &Key) -> Self {
+        match cipher {
+            Cipher::XChaCha20Poly1305 => AeadCipher::XChaCha20Poly1305(XChaCha20Poly1305::new(key)),
+            Cipher::Aes256Gcm => AeadCipher::Aes256Gcm(Aes256Gcm::new(key)),
+        }
+    }
+
+    pub fn encrypt(&self, nonce: u128, plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+        match self {
+            AeadCipher::XChaCha20Poly1305(aead) => aead.encrypt(&nonce_from_u128(nonce), plaintext),
+            AeadCipher::Aes256Gcm(aead) => aead.encrypt(GenericArray::from_slice(&nonce.to_le_bytes()[..12]), plaintext),
+        }
+    }
+
+    pub fn decrypt(&self, nonce: u128, ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
+        match self {
+            AeadCipher::XChaCha20Poly1305(aead) => aead.decrypt(&nonce_from_u128(nonce), ciphertext),
+            AeadCipher::Aes256Gcm(aead) => aead.decrypt(GenericArray::from_slice(&nonce.to_le_bytes()[..12]), ciphertext),
+        }
+    }
+}
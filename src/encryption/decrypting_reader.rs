@@ -0,0 +1,63 @@
+/// Provides the decrypting `Read` part
+/// Wraps another Reader, decrypting everything from it
+///
+/// Decryption is done by a DecryptingWriter, which the encrypted data is fed into as it is read
+/// This keeps the handling of headers and padding in one place
+
+use std::io::{Read, Write};
+
+use crate::encryption::keys::Keys;
+use crate::encryption::writer::DecryptingWriter;
+use crate::transfer::IO_BUFFER_LENGTH;
+
+pub struct DecryptingReader<R: Read> {
+    inner: R, // Inner reader, data from this will be decrypted
+    writer: DecryptingWriter<Vec<u8>>, // Decrypts into its buffer, which is returned from 'read'
+    input: Vec<u8>, // Encrypted data read from 'inner', before it is fed to the writer
+    returned: usize, // Tracks amount returned from the writer's buffer
+    done: bool, // Whether the inner reader is exhausted
+}
+
+impl<R: Read> Read for DecryptingReader<R> {
+    fn read(&mut self, mut buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        loop {
+            // Return pending output first
+            let output = self.writer.get_mut();
+            if self.returned < output.len() {
+                let written = buf.write(&output[self.returned..])?;
+                self.returned += written;
+                if self.returned == output.len() {
+                    output.clear();
+                    self.returned = 0;
+                }
+                return Ok(written);
+            }
+            if self.done {
+                return Ok(0);
+            }
+
+            // Feed more encrypted data to the writer
+            // The writer holds back the last blocks until it knows where the padding is, so this may output nothing
+            let n = self.inner.read(&mut self.input)?;
+            if n == 0 {
+                self.writer.flush()?;
+                self.done = true;
+            } else {
+                self.writer.write_all(&self.input[..n])?;
+            }
+        }
+    }
+}
+
+impl<R: Read> DecryptingReader<R> {
+    // Wrap another reader, decrypting with whichever of 'keys' the data was encrypted with
+    pub fn wrap(reader: R, keys: &Keys) -> Self {
+        DecryptingReader {
+            inner: reader,
+            writer: DecryptingWriter::with_keys(Vec::with_capacity(IO_BUFFER_LENGTH), keys),
+            input: vec![0u8; IO_BUFFER_LENGTH],
+            returned: 0,
+            done: false,
+        }
+    }
+}
@@ -0,0 +1,112 @@
+/// Provides the encrypting `Write` part
+/// Targets another Writer, sending encrypted data to it
+///
+/// The output is the same as that of an EncryptingReader, s.t. it can be decrypted the same way
+
+use std::io::Write;
+use chacha20poly1305::Key;
+use x25519_dalek::PublicKey;
+
+use crate::encryption::{BlockSize, key_header};
+use crate::encryption::cipher::{Cipher, AeadCipher};
+use crate::encryption::keys::key_id;
+use crate::encryption::keypair;
+
+// Encrypts anything written to it, writing encrypted output into 'target'
+//
+// WARNING: you **MUST** call finish() when the input is done
+// WARNING: The last block is only padded and written by finish(), without it the output can't be decrypted!
+pub struct EncryptingWriter<W: Write> {
+    target: W, // Inner writer, this will receive encrypted data
+    aead: AeadCipher,
+    header: Option<Vec<u8>>, // Key header and initial nonce, until they are written
+    nonce: u128, // Current nonce (counter)
+    nonce_max: u128, // The maximum allowed value of 'nonce'
+    input_buffer: Vec<u8>, // Buffered data, until we have a full block of data
+    received: usize, // Tracks amount written to the input buffer
+}
+
+impl<W: Write> Write for EncryptingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+        self.write_header()?;
+        let read_len = buf.len().min(self.input_buffer.len()-self.received);
+        self.input_buffer[self.received..self.received+read_len].copy_from_slice(&buf[..read_len]);
+        self.received += read_len;
+        // Full blocks are never padded, so they can be encrypted right away
+        if self.received == self.input_buffer.len() {
+            self.write_block()?;
+            self.received = 0;
+        }
+        Ok(read_len)
+    }
+
+    // Only flushes the target, the partial block can't be written until finish() is called
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        self.target.flush()
+    }
+}
+
+impl<W: Write> EncryptingWriter<W> {
+    // Target another writer, encrypting with 'key' using 'cipher', in blocks of 'block'
+    // Nonces work the same as for EncryptingReader::wrap
+    pub fn target(writer: W, key: &Key, cipher: Cipher, block: BlockSize, start_nonce: u128, allocated_nonces: u128) -> Self {
+        EncryptingWriter {
+            target: writer,
+            aead: AeadCipher::new(cipher, key),
+            header: Some(key_header(key_id(key), cipher, block, None, start_nonce)),
+            nonce: start_nonce,
+            nonce_max: start_nonce.saturating_add(allocated_nonces).min(cipher.nonce_limit()),
+            input_buffer: vec![0u8; block.data_length()],
+            received: 0,
+        }
+    }
+
+    // Target another writer, encrypting for 'recipient', see EncryptingReader::wrap_for
+    pub fn target_for(writer: W, recipient: &PublicKey, cipher: Cipher, block: BlockSize, start_nonce: u128, allocated_nonces: u128) -> Self {
+        let (key, ephemeral) = keypair::wrap(recipient);
+        let mut encrypting = Self::target(writer, &key, cipher, block, start_nonce, allocated_nonces);
+        encrypting.header = Some(key_header(keypair::public_id(recipient), cipher, block, Some(&ephemeral), start_nonce));
+        encrypting
+    }
+
+    // Pads and writes the last block, returning the target
+    // Padding works the same as in EncryptingReader
+    pub fn finish(mut self) -> Result<W, std::io::Error> {
+        self.write_header()?;
+        let data_length = self.input_buffer.len();
+        // If there isn't room for the amount padded, pad this block and add a full block of padding
+        let mut pad_extra = 0;
+        if data_length-self.received < 4 {
+            pad_extra = (data_length-self.received) as u32;
+            self.input_buffer[self.received..].iter_mut().for_each(|b| *b = 0);
+            self.write_block()?;
+            self.received = 0;
+        }
+        let pad_num = (data_length-self.received) as u32 + pad_extra;
+        self.input_buffer[self.received..data_length-4].iter_mut().for_each(|b| *b = 0);
+        self.input_buffer[data_length-4..].copy_from_slice(&pad_num.to_le_bytes());
+        self.write_block()?;
+        self.target.flush()?;
+        Ok(self.target)
+    }
+
+    fn write_header(&mut self) -> Result<(), std::io::Error> {
+        if let Some(header) = self.header.take() {
+            self.target.write_all(&header)?;
+        }
+        Ok(())
+    }
+
+    // Encrypts the input buffer, which must be full
+    fn write_block(&mut self) -> Result<(), std::io::Error> {
+        // Make sure we didn't run out of nonces before finishing
+        if self.nonce >= self.nonce_max {
+            return Err(std::io::Error::new(std::io::ErrorKind::Other, "Ran out of allocated nonces"));
+        }
+        let nonce = self.nonce;
+        self.nonce += 1;
+        let ciphertext = self.aead.encrypt(nonce, self.input_buffer.as_ref())
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "Encryption failed"))?;
+        self.target.write_all(&ciphertext)
+    }
+}
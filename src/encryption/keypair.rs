@@ -0,0 +1,76 @@
+/// Provides public key encryption, s.t. the machine that makes backups can't decrypt them
+///
+/// The backup machine only holds the public key of an X25519 key pair. Every file is encrypted with its own key:
+/// a new ephemeral key pair is made for the file, and the file key is derived from the Diffie-Hellman shared
+/// secret of the ephemeral private key and the public key. Only the ephemeral public key is kept, in the header
+/// Restoring derives the same file key from the private key and the ephemeral public key
+///
+/// The key ID in the header is the ID of the public key, s.t. restores can pick the right private key
+/// Key files hold the raw 32 byte key, like the secret key
+
+use chacha20poly1305::Key;
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use rand::rngs::OsRng;
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+use crate::encryption::keys::{key_id, KeyId};
+use crate::secrets;
+
+// Length of the ephemeral public key in the header
+pub const WRAP_LENGTH: usize = 32;
+
+/// Generates a new key pair
+pub fn generate() -> (StaticSecret, PublicKey) {
+    let private = StaticSecret::new(OsRng);
+    let public = PublicKey::from(&private);
+    (private, public)
+}
+
+pub fn public_key_from_file<T: AsRef<str>>(path: T) -> std::io::Result<PublicKey> {
+    Ok(PublicKey::from(to_array(path.as_ref(), &std::fs::read(path.as_ref())?)?))
+}
+
+// The private key may also be in the OS keyring
+pub fn private_key_from_file<T: AsRef<str>>(path: T) -> std::io::Result<StaticSecret> {
+    Ok(StaticSecret::from(to_array(path.as_ref(), &secrets::read_key(path.as_ref())?)?))
+}
+
+/// ID of a public key, written in the header of the files encrypted for it
+pub fn public_id(public: &PublicKey) -> KeyId {
+    key_id(Key::from_slice(public.as_bytes()))
+}
+
+/// Makes a new file key for 'recipient'
+/// Returns the key and the ephemeral public key needed to recover it
+pub fn wrap(recipient: &PublicKey) -> (Key, [u8; WRAP_LENGTH]) {
+    let ephemeral = EphemeralSecret::new(OsRng);
+    let ephemeral_public = PublicKey::from(&ephemeral);
+    let shared = ephemeral.diffie_hellman(recipient);
+    (derive(shared.as_bytes(), &ephemeral_public, recipient), *ephemeral_public.as_bytes())
+}
+
+/// Recovers the file key from the ephemeral public key in the header
+pub fn unwrap(private: &StaticSecret, ephemeral_public: [u8; WRAP_LENGTH]) -> Key {
+    let ephemeral_public = PublicKey::from(ephemeral_public);
+    let shared = private.diffie_hellman(&ephemeral_public);
+    derive(shared.as_bytes(), &ephemeral_public, &PublicKey::from(private))
+}
+
+// Both public keys are included, s.t. the file key is bound to this exact exchange
+fn derive(shared: &[u8], ephemeral_public: &PublicKey, recipient: &PublicKey) -> Key {
+    let mut mac = Hmac::<Sha256>::new_varkey(shared).expect("HMAC accepts any key length");
+    mac.update(b"retain-rs file key");
+    mac.update(ephemeral_public.as_bytes());
+    mac.update(recipient.as_bytes());
+    Key::clone_from_slice(&mac.finalize().into_bytes())
+}
+
+// 'location' is where the key was read from, for the error
+fn to_array(location: &str, bytes: &[u8]) -> std::io::Result<[u8; 32]> {
+    if bytes.len() != 32 {
+        return Err(super::wrong_key_length(location, bytes.len()));
+    }
+    let mut array = [0u8; 32];
+    array.copy_from_slice(bytes);
+    Ok(array)
+}
@@ -0,0 +1,150 @@
+/// Provides the set of secret keys used for encryption
+///
+/// Files are always encrypted with the active key (the configured secret key)
+/// Keys that were replaced are kept as retired keys, s.t. files they encrypted can still be restored
+///
+/// Every key has a 4 byte ID, which is written in the header of the files it encrypts
+/// It is derived from the key, s.t. it doesn't need to be stored anywhere and doesn't depend on the order of the keys
+///
+/// With a key pair (see keypair.rs), files are encrypted for the public key instead and restored with the private key
+/// The public key takes precedence over the secret key for new files, files made with either can be restored
+
+use std::io::{Read, Write};
+use chacha20poly1305::Key;
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+use crate::config::Config;
+use crate::encryption::{key_from_file, get_encrypted_size, keypair, BlockSize};
+use crate::encryption::cipher::Cipher;
+use crate::encryption::reader::EncryptingReader;
+use crate::encryption::encrypting_writer::EncryptingWriter;
+
+pub type KeyId = u32;
+
+/// Computes the ID of a key
+pub fn key_id(key: &Key) -> KeyId {
+    // A one-way function of the key, s.t. the ID reveals nothing about it
+    let mut mac = Hmac::<Sha256>::new_varkey(&key[..]).expect("HMAC accepts any key length");
+    mac.update(b"retain-rs key id");
+    let tag = mac.finalize().into_bytes();
+    let mut id = [0u8; 4];
+    id.copy_from_slice(&tag[..4]);
+    KeyId::from_le_bytes(id)
+}
+
+#[derive(Clone)]
+pub struct Keys {
+    secret: Vec<Key>, // The active key first, then the retired keys
+    public: Option<PublicKey>,
+    private: Vec<StaticSecret>,
+}
+
+impl Keys {
+    pub fn new(active: Key, retired: Vec<Key>) -> Self {
+        Keys {
+            secret: std::iter::once(active).chain(retired).collect(),
+            public: None,
+            private: Vec::new(),
+        }
+    }
+
+    /// Reads the configured secret key, retired keys and key pair
+    /// Only one of the secret key and the public or private key needs to be configured
+    pub fn from_config(config: &Config) -> Result<Self, String> {
+        let mut secret = Vec::new();
+        if let Some(path) = &config.secret_key {
+            secret.push(key_from_file(path).map_err(|e| format!("Failed to open key-file {:?}", e))?);
+        }
+        for path in config.retired_keys.iter().flatten() {
+            secret.push(key_from_file(path).map_err(|e| format!("Failed to open retired key-file {} ({:?})", path, e))?);
+        }
+        let mut private = Vec::new();
+        if let Some(path) = &config.private_key {
+            private.push(keypair::private_key_from_file(path).map_err(|e| format!("Failed to open private key-file {:?}", e))?);
+        }
+        let public = match &config.public_key {
+            Some(path) => Some(keypair::public_key_from_file(path).map_err(|e| format!("Failed to open public key-file {:?}", e))?),
+            None => private.first().map(PublicKey::from),
+        };
+        if secret.is_empty() && public.is_none() {
+            return Err("No secret key configured".to_string());
+        }
+        Ok(Keys {
+            secret,
+            public,
+            private,
+        })
+    }
+
+    /// Finds the key with the given ID, if it is known
+    pub fn find(&self, id: KeyId) -> Option<&Key> {
+        self.all().find(|key| key_id(key) == id)
+    }
+
+    /// Finds the private key whose public key has the given ID, if it is known
+    pub fn find_private(&self, id: KeyId) -> Option<&StaticSecret> {
+        self.private.iter().find(|private| keypair::public_id(&PublicKey::from(*private)) == id)
+    }
+
+    /// All secret keys, starting with the active key
+    pub fn all(&self) -> impl Iterator<Item=&Key> {
+        self.secret.iter()
+    }
+
+    /// Key material manifests are authenticated with, starting with the one used for new manifests
+    /// Only the secret keys, the public key of a key pair is no secret. Without them manifests aren't authenticated
+    pub fn mac_material(&self) -> impl Iterator<Item=&[u8]> {
+        self.all().map(|key| &key[..])
+    }
+}
+
+/// The key new files are encrypted with
+#[derive(Clone, Copy)]
+pub enum EncryptionKey {
+    Secret(Key),
+    // Each file gets its own key, which only the private key can recover
+    Public(PublicKey),
+}
+
+impl EncryptionKey {
+    /// Reads the configured public key, or the secret key if there is none
+    pub fn from_config(config: &Config) -> Result<Self, String> {
+        match (&config.public_key, &config.secret_key) {
+            (Some(path), _) => keypair::public_key_from_file(path).map(EncryptionKey::Public)
+                .map_err(|e| format!("Failed to open public key-file {:?}", e)),
+            (None, Some(path)) => key_from_file(path).map(EncryptionKey::Secret)
+                .map_err(|e| format!("Failed to open key-file {:?}", e)),
+            // The public key of a private-key-only config is derived from it
+            (None, None) => match &config.private_key {
+                Some(path) => keypair::private_key_from_file(path).map(|private| EncryptionKey::Public(PublicKey::from(&private)))
+                    .map_err(|e| format!("Failed to open private key-file {:?}", e)),
+                None => Err("No secret key configured".to_string()),
+            },
+        }
+    }
+
+    /// Computes how many bytes a file will be after it is encrypted with this key, in blocks of 'block'
+    pub fn encrypted_size(&self, unencrypted_size: u64, block: BlockSize) -> u64 {
+        match self {
+            EncryptionKey::Secret(_) => get_encrypted_size(unencrypted_size, block),
+            EncryptionKey::Public(_) => get_encrypted_size(unencrypted_size, block) + keypair::WRAP_LENGTH as u64,
+        }
+    }
+
+    /// Wraps a reader, encrypting with this key. See EncryptingReader::wrap
+    pub fn wrap<R: Read>(&self, reader: R, cipher: Cipher, block: BlockSize, start_nonce: u128, allocated_nonces: u128) -> EncryptingReader<R> {
+        match self {
+            EncryptionKey::Secret(key) => EncryptingReader::wrap(reader, key, cipher, block, start_nonce, allocated_nonces),
+            EncryptionKey::Public(public) => EncryptingReader::wrap_for(reader, public, cipher, block, start_nonce, allocated_nonces),
+        }
+    }
+
+    /// Targets a writer, encrypting with this key. See EncryptingWriter::target
+    pub fn target<W: Write>(&self, writer: W, cipher: Cipher, block: BlockSize, start_nonce: u128, allocated_nonces: u128) -> EncryptingWriter<W> {
+        match self {
+            EncryptionKey::Secret(key) => EncryptingWriter::target(writer, key, cipher, block, start_nonce, allocated_nonces),
+            EncryptionKey::Public(public) => EncryptingWriter::target_for(writer, public, cipher, block, start_nonce, allocated_nonces),
+        }
+    }
+}
@@ -0,0 +1,46 @@
+/// Converts secret keys to and from a list of words (BIP39), s.t. they can be written down on paper
+///
+/// A 32 byte key is 24 words from the BIP39 English word list. The last word includes a checksum,
+/// which catches most typos when importing
+
+use bip39::Mnemonic;
+use chacha20poly1305::Key;
+
+pub const WORD_COUNT: usize = 24;
+
+/// Returns the words for 'key', separated by spaces
+pub fn to_words(key: &Key) -> String {
+    Mnemonic::from_entropy(&key[..]).expect("32 bytes is a valid mnemonic length").to_string()
+}
+
+/// Reads a key from its words. Case and whitespace don't matter
+pub fn from_words(words: &str) -> Result<Key, String> {
+    let words: Vec<String> = words.split_whitespace().map(|w| w.to_lowercase()).collect();
+    if words.len() != WORD_COUNT {
+        return Err(format!("expected {} words, got {}", WORD_COUNT, words.len()));
+    }
+    let mnemonic = Mnemonic::parse(words.join(" ")).map_err(|e| format!("{}", e))?;
+    Ok(Key::clone_from_slice(&mnemonic.to_entropy()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encryption::mnemonic::{to_words, from_words};
+    use chacha20poly1305::Key;
+
+    #[test]
+    fn test_words() {
+        let key = Key::from_slice(b"an example very very secret key.");
+        let words = to_words(key);
+        assert_eq!(words.split(' ').count(), 24);
+        assert_eq!(from_words(&words), Ok(*key));
+        // Case and line breaks don't matter
+        assert_eq!(from_words(&words.to_uppercase().replacen(' ', "\n", 5)), Ok(*key));
+
+        // Wrong length and typos are caught
+        assert!(from_words("abandon abandon abandon").is_err());
+        let mut typo: Vec<&str> = words.split(' ').collect();
+        typo.swap(0, 1);
+        assert!(from_words(&typo.join(" ")).is_err());
+    }
+}
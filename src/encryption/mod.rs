@@ -1,4 +1,13 @@
 use chacha20poly1305::{XNonce, Key};
+use crate::secrets;
+use crate::throttle;
+use rand::{thread_rng, Rng};
+use cipher::Cipher;
+use keys::KeyId;
+use keypair::WRAP_LENGTH;
+use serde::{Serialize, Deserialize};
+use std::io::Read;
+use std::convert::TryFrom;
 
 /// This module defines the functionality required to encrypt and decrypt files
 ///
@@ -7,6 +16,19 @@ use chacha20poly1305::{XNonce, Key};
 /// At the start of an encrypted file, the initial nonce value is written, unencrypted and unauthenticated
 /// Every subsequent block simply increments this value by 1
 ///
+/// The nonce is preceded by a 16 byte key header, identifying the key the file was encrypted with (see keys.rs):
+/// the key ID (4 bytes, little-endian), the cipher ID (1 byte, see cipher.rs), flags (1 byte), the block shift (1 byte),
+/// a reserved byte (zero) and KEY_MAGIC
+/// If the file is encrypted for a public key (FLAG_WRAPPED), the header is followed by the ephemeral public key the
+/// file key is recovered from (see keypair.rs), and the key ID is the ID of the public key
+/// Files written by older versions start with the nonce directly. Nonces never reach 2^64, so the last 8 bytes
+/// of their first 16 bytes are zero, which tells them apart. Those are decrypted with whichever key fits
+///
+/// The initial nonce comes from a counter kept in the config by default (see config.rs)
+/// In the random nonce mode, each file instead starts at a random nonce in [2^126, 2^127), which needs no state
+/// ([2^94, 2^95) for AES-256-GCM, which has 96 bit nonces)
+/// Files are far too small for their nonces to overlap, and the range never overlaps with counter nonces
+///
 /// If there is not enough data to fill a block, it will be padded to fit
 /// The last 4 bytes of an encrypted file is the big-endian u32 number of padded bytes
 /// Padding is anywhere from 4 to BLOCK_LENGTH bytes
@@ -14,6 +36,10 @@ use chacha20poly1305::{XNonce, Key};
 /// We can fit BLOCK_LENGTH-16 data-bytes in a block
 /// If the last block has more than BLOCK_LENGTH-16-4 data-bytes, it will pad a full block + 1 to 3 bytes
 /// This is because it can't fit the amount padded otherwise
+///
+/// Blocks are BLOCK_LENGTH bytes shifted left by the block shift of the key header, up to 1 MiB
+/// Larger blocks take fewer system calls and AEAD calls for large files. Files without a key header always use BLOCK_LENGTH
+/// Files smaller than the configured block length use a smaller one, s.t. they aren't padded to a large block
 
 
 // Length of a block of data
@@ -25,18 +51,135 @@ use chacha20poly1305::{XNonce, Key};
 // As a result, this value must be strictly greater than 16
 pub const BLOCK_LENGTH: usize = 8192;
 pub const DATA_LENGTH: usize = BLOCK_LENGTH-16;
+// Largest block shift, blocks are at most BLOCK_LENGTH << MAX_BLOCK_SHIFT bytes
+pub const MAX_BLOCK_SHIFT: u8 = 7;
+
+// Last 8 bytes of the key header
+pub const KEY_MAGIC: [u8; 8] = *b"rrs-key1";
+// Length of the key header + initial nonce
+pub const HEADER_LENGTH: usize = 32;
+/// Longest header an encrypted file can start with, see read_header
+pub const MAX_HEADER_LENGTH: usize = HEADER_LENGTH+WRAP_LENGTH;
+// Set in the flags of files encrypted for a public key
+pub const FLAG_WRAPPED: u8 = 1;
+// Length of a key-file: a secret key, or either half of a key pair
+pub const KEY_LENGTH: usize = 32;
 
 pub mod reader;
 pub mod writer;
+pub mod decrypting_reader;
+pub mod encrypting_writer;
+pub mod keys;
+pub mod cipher;
+pub mod keypair;
+pub mod mnemonic;
+
+/// Where the initial nonce of each file comes from
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NonceMode {
+    // The counter in the config
+    Counter,
+    // A random number, see above
+    Random,
+}
+
+/// Length of the blocks a file is encrypted in, see above
+/// Configured as the length in bytes, which must be BLOCK_LENGTH times a power of two
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "u64", into = "u64")]
+pub struct BlockSize {
+    shift: u8,
+}
+
+impl BlockSize {
+    pub fn from_shift(shift: u8) -> Option<Self> {
+        match shift <= MAX_BLOCK_SHIFT {
+            true => Some(BlockSize { shift }),
+            false => None,
+        }
+    }
+
+    pub fn from_length(length: u64) -> Result<Self, String> {
+        (0..=MAX_BLOCK_SHIFT).find(|shift| (BLOCK_LENGTH as u64) << shift == length)
+            .map(|shift| BlockSize { shift })
+            .ok_or_else(|| format!("block size must be {} bytes times a power of two, at most {} bytes",
+                                   BLOCK_LENGTH, BLOCK_LENGTH << MAX_BLOCK_SHIFT))
+    }
+
+    /// Parses a length such as `64KiB`, see throttle::parse_size
+    pub fn parse(text: &str) -> Result<Self, String> {
+        throttle::parse_size(text).and_then(Self::from_length)
+    }
+
+    /// Written in the key header
+    pub fn shift(&self) -> u8 {
+        self.shift
+    }
+
+    pub fn block_length(&self) -> usize {
+        BLOCK_LENGTH << self.shift
+    }
+
+    /// Data-bytes in a block, the rest is the MAC
+    pub fn data_length(&self) -> usize {
+        self.block_length()-16
+    }
+
+    /// The block size a file of 'size' bytes is encrypted with: this one, or the largest smaller one it fills a block of
+    pub fn fitting(self, size: u64) -> Self {
+        let mut block = self;
+        while block.shift > 0 && (block.data_length() as u64) > size {
+            block.shift -= 1;
+        }
+        block
+    }
+}
+
+impl TryFrom<u64> for BlockSize {
+    type Error = String;
+
+    fn try_from(length: u64) -> Result<Self, String> {
+        Self::from_length(length)
+    }
+}
+
+impl From<BlockSize> for u64 {
+    fn from(block: BlockSize) -> u64 {
+        block.block_length() as u64
+    }
+}
 
 mod test;
 
-/// Computes the required amount of nonces to encrypt 'length' bytes
+/// Computes the required amount of nonces to encrypt 'length' bytes in blocks of 'block'
 ///
 /// This accounts for the encryption overhead
 #[allow(dead_code)]
-pub fn get_nonces_required(length: u64) -> u128 {
-    return ((length+3)/(BLOCK_LENGTH as u64-16)+1) as u128;
+pub fn get_nonces_required(length: u64, block: BlockSize) -> u128 {
+    return ((length+3)/(block.data_length() as u64)+1) as u128;
+}
+
+/// Picks a random initial nonce for a file, see above
+pub fn random_start_nonce(cipher: Cipher) -> u128 {
+    match cipher {
+        Cipher::XChaCha20Poly1305 => (thread_rng().gen::<u128>() >> 2) | (1 << 126),
+        Cipher::Aes256Gcm => (thread_rng().gen::<u128>() >> 34) | (1 << 94),
+    }
+}
+
+// Builds the key header, followed by the ephemeral public key (if any) and the initial nonce
+fn key_header(key_id: KeyId, cipher: Cipher, block: BlockSize, ephemeral: Option<&[u8; WRAP_LENGTH]>, nonce: u128) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(HEADER_LENGTH+WRAP_LENGTH);
+    let flags = if ephemeral.is_some() { FLAG_WRAPPED } else { 0 };
+    bytes.extend_from_slice(&key_id.to_le_bytes());
+    bytes.extend_from_slice(&[cipher.id(), flags, block.shift(), 0]);
+    bytes.extend_from_slice(&KEY_MAGIC);
+    if let Some(ephemeral) = ephemeral {
+        bytes.extend_from_slice(ephemeral);
+    }
+    bytes.extend_from_slice(&nonce.to_le_bytes());
+    bytes
 }
 
 fn nonce_from_u128(number: u128) -> XNonce {
@@ -45,14 +188,50 @@ fn nonce_from_u128(number: u128) -> XNonce {
     XNonce::from_slice(&nonce_arr).to_owned()
 }
 
+// Reads a key from a key-file, or from the OS keyring (see secrets.rs)
+// Anything but KEY_LENGTH bytes is an error, wherever it is stored
 pub fn key_from_file<T: AsRef<str>>(path: T) -> Result<Key,std::io::Error> {
-    let bytes = std::fs::read(path.as_ref())?;
+    let bytes = secrets::read_key(path.as_ref())?;
+    if bytes.len() != KEY_LENGTH {
+        return Err(wrong_key_length(path.as_ref(), bytes.len()));
+    }
     Ok(Key::clone_from_slice(&bytes))
 }
 
-// Compute how many bytes a file will be after it is encrypted
-pub fn get_encrypted_size(unencrypted_size: u64) -> u64 {
-    // 16 byte nonce + 16 byte MAC per DATA_LENGTH bytes (Accounts for padding)
-    16 + (((unencrypted_size+3)/DATA_LENGTH as u64)+1)*BLOCK_LENGTH as u64
+// Error for a key at 'location' that is 'length' bytes instead of KEY_LENGTH
+fn wrong_key_length(location: &str, length: usize) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{} is {} bytes, a key must be exactly {} bytes", location, length, KEY_LENGTH))
+}
+
+/// Reads the start of an encrypted file: the key header, ephemeral public key (if any) and initial nonce
+/// Given those, the rest of the file can be decrypted from any block, see DecryptingWriter::resume
+pub fn read_header<R: Read>(data: &mut R) -> Result<Vec<u8>, std::io::Error> {
+    let mut header = vec![0u8; 16];
+    data.read_exact(&mut header)?;
+    // Files written by older versions start with the nonce
+    if header[8..16] != KEY_MAGIC {
+        return Ok(header);
+    }
+    let length = match header[5] & FLAG_WRAPPED {
+        0 => HEADER_LENGTH,
+        _ => HEADER_LENGTH+WRAP_LENGTH,
+    };
+    header.resize(length, 0);
+    data.read_exact(&mut header[16..])?;
+    Ok(header)
+}
+
+/// The block size of an encrypted file, given its header (see read_header). None if it isn't one this version knows
+pub fn block_size_of(header: &[u8]) -> Option<BlockSize> {
+    match header.len() >= 16 && header[8..16] == KEY_MAGIC {
+        true => BlockSize::from_shift(header[6]),
+        false => Some(BlockSize::default()),
+    }
+}
+
+// Compute how many bytes a file will be after it is encrypted in blocks of 'block'
+pub fn get_encrypted_size(unencrypted_size: u64, block: BlockSize) -> u64 {
+    // 32 byte header + 16 byte MAC per data length bytes (Accounts for padding)
+    HEADER_LENGTH as u64 + (((unencrypted_size+3)/block.data_length() as u64)+1)*block.block_length() as u64
 }
 
@@ -2,15 +2,16 @@
 /// Wraps another Reader, encrypting everything from it
 
 use std::io::{Read, Write};
-use chacha20poly1305::{XChaCha20Poly1305, Key};
-use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::Key;
 
-// Size of a 'block'
-use super::BLOCK_LENGTH;
-use crate::encryption::{DATA_LENGTH, nonce_from_u128};
+use x25519_dalek::PublicKey;
+use crate::encryption::{BlockSize, key_header};
+use crate::encryption::cipher::{Cipher, AeadCipher};
+use crate::encryption::keys::{key_id, KeyId};
+use crate::encryption::keypair::{self, WRAP_LENGTH};
 
 // Represents the state of the reader. It progresses through them in order
-// Nonce: write the initial nonce to the file
+// Nonce: write the key header (and ephemeral public key) and initial nonce to the file
 // Data: read and encrypt inner data
 // Pad: pad (and encrypt) to the goal length
 // Done: once output buffer has been read, return 0
@@ -24,12 +25,16 @@ enum EncReadState {
 
 pub struct EncryptingReader<R: Read> {
     inner: R, // Inner reader, data from this will be encrypted
-    aead: XChaCha20Poly1305,
+    aead: AeadCipher,
+    cipher: Cipher, // Written in the header
+    block: BlockSize, // Size of a 'block', written in the header
+    key_id: KeyId, // ID of the key, written in the header
+    ephemeral: Option<[u8; WRAP_LENGTH]>, // Ephemeral public key, if encrypting for a public key
     state: EncReadState,
     nonce: u128, // Current nonce (counter)
     nonce_max: u128, // The maximum allowed value of 'nonce'
-    input_buffer: [u8; DATA_LENGTH as usize], // Buffered data read from 'inner', until we have a full block of data
-    output_buffer: [u8; BLOCK_LENGTH as usize], // Buffered output, in case our supplied buffer isn't large enough
+    input_buffer: Vec<u8>, // Buffered data read from 'inner', until we have a full block of data
+    output_buffer: Vec<u8>, // Buffered output, in case our supplied buffer isn't large enough
     read: usize, // Tracks amount read to the input buffer
     written: usize, // Tracks amount returned from the output buffer
     total_size: u64, // Tracks how much we've read, in total, from the inner reader
@@ -39,50 +44,56 @@ pub struct EncryptingReader<R: Read> {
 impl<R: Read> Read for EncryptingReader<R> {
     fn read(&mut self, mut buf: &mut [u8]) -> Result<usize, std::io::Error> {
         // Check if we have any pending output
-        // This is the case if 'written' is between 1 and BLOCK_LENGTH-1
-        if self.written != 0 && self.written != BLOCK_LENGTH as usize {
+        // This is the case if 'written' is between 1 and the block length-1
+        if self.written != 0 && self.written != self.output_buffer.len() {
             let written = buf.write(&self.output_buffer[self.written..])?;
             self.written += written;
             return Ok(written);
         }
 
         // Make sure we didn't run out of nonces before finishing
+        // This happens if the inner reader returns more data than it was allocated nonces for, e.g. a file that grew
         if self.nonce >= self.nonce_max && self.state != EncReadState::Done {
-            panic!("Ran out of allocated nonces!");
+            return Err(std::io::Error::new(std::io::ErrorKind::Other, "Ran out of allocated nonces"));
         }
 
         match self.state {
-            // Return the nonce, unencrypted
-            // If the buffer isn't at least 16 bytes then IDK go buy a bigger one?
+            // Return the key header and the nonce, unencrypted
+            // They are placed at the end of the output buffer, s.t. they are returned as pending output if 'buf' is too small
             EncReadState::Nonce => {
-                let bytes = self.nonce.to_le_bytes();
-                buf.write_all(&bytes)?;
+                let bytes = key_header(self.key_id, self.cipher, self.block, self.ephemeral.as_ref(), self.nonce);
+                let start = self.output_buffer.len() - bytes.len();
+                self.output_buffer[start..].copy_from_slice(&bytes);
+                self.written = start + buf.write(&self.output_buffer[start..])?;
                 self.state = EncReadState::Data;
-                Ok(bytes.len())
+                Ok(self.written - start)
             }
             // Encrypt and return data from inner reader
             EncReadState::Data => {
                 // Keep trying to read until we fill our input buffer or reach the end of it
+                // Errors (e.g. a cancelled upload) are returned, s.t. a failed read is never sent as the end of the file
                 self.read = 0;
-                while let Ok(n) = self.inner.read(&mut self.input_buffer[self.read..]) {
-                    if n == 0 { // Nothing more to read in the inner reader
-                        break;
+                loop {
+                    match self.inner.read(&mut self.input_buffer[self.read..]) {
+                        Ok(0) => break, // Nothing more to read in the inner reader
+                        Ok(n) => self.read += n,
+                        Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                        Err(e) => return Err(e),
                     }
-                    self.read += n;
                 }
                 // If we didn't read a full block, start padding
                 if self.read != self.input_buffer.len() {
                     self.state = EncReadState::Pad;
                     return Ok(self.read(buf)?);
                 }
-                // At this point, the buffer contains exactly DATA_LENGTH bytes
-                let nonce = nonce_from_u128(self.nonce);
+                // At this point, the buffer contains exactly a block of data
+                let nonce = self.nonce;
                 self.nonce += 1;
-                let ciphertext = self.aead.encrypt(&nonce, self.input_buffer.as_ref()).expect("Encryption failed!");
+                let ciphertext = self.aead.encrypt(nonce, self.input_buffer.as_ref()).map_err(encryption_failed)?;
                 self.output_buffer.copy_from_slice(&ciphertext);
                 self.written = 0;
                 self.written += buf.write(&self.output_buffer)?;
-                self.total_size += BLOCK_LENGTH as u64;
+                self.total_size += self.output_buffer.len() as u64;
                 Ok(self.written)
             } // Add (encrypted) padding
             EncReadState::Pad => {
@@ -90,11 +101,12 @@ impl<R: Read> Read for EncryptingReader<R> {
                 // This is enough bytes to get us to DATA_LEN bytes of data
                 // If this is less than 4 bytes we cannot fit the amount of padding added
                 // In that case we pad that amount + a full block
+                let data_length = self.block.data_length();
                 let pad_amount: u32;
                 if self.pad_extra == 0 { // First pass, how much pad is needed
-                    pad_amount = ((DATA_LENGTH as u64) - self.read as u64) as u32;
+                    pad_amount = ((data_length as u64) - self.read as u64) as u32;
                 } else { // If we needed less than 1-3 bytes of padding, add a full block
-                    pad_amount = DATA_LENGTH as u32;
+                    pad_amount = data_length as u32;
                 }
 
                 // Due to the BLOCK_LENGTH being 4 bytes, we need at least 4 bytes pad for the scheme
@@ -105,23 +117,23 @@ impl<R: Read> Read for EncryptingReader<R> {
                 // 4. Set it as output buffer
                 // 5. Increment total_size by amount padded and return
                 // Next time read is called, after finishing the output buffer, we will hit the pad case again, but:
-                // * total_size is now a multiple of BLOCK_LENGTH, so another full block of pad is added
-                // * We use BLOCK_LENGTH+self.pad_extra as the amount padded
+                // * total_size is now a multiple of the block length, so another full block of pad is added
+                // * We use the data length+self.pad_extra as the amount padded
                 if pad_amount < 4 {
                     self.pad_extra = pad_amount;
-                    let nonce = nonce_from_u128(self.nonce);
+                    let nonce = self.nonce;
                     self.nonce += 1;
                     (&mut self.input_buffer[self.read..]).write(vec![0u8; pad_amount as usize].as_ref())?;
-                    let ciphertext = self.aead.encrypt(&nonce, self.input_buffer.as_ref()).expect("Encryption failed!");
+                    let ciphertext = self.aead.encrypt(nonce, self.input_buffer.as_ref()).map_err(encryption_failed)?;
                     self.output_buffer.copy_from_slice(&ciphertext);
                     self.written = 0;
                     self.written += buf.write(&self.output_buffer)?;
-                    self.total_size += BLOCK_LENGTH as u64;
+                    self.total_size += self.output_buffer.len() as u64;
                     self.read = 0; // We've accounted for it now
                     return Ok(self.written);
                 }
 
-                // Here we know that the amount to pad is 4 to BLOCK_LENGTH bytes and thus fits in 1 output_buffer
+                // Here we know that the amount to pad is 4 to data length bytes and thus fits in 1 output_buffer
                 // We also know that we have enough room for the scheme
                 // Note that we have to add 'self.pad_extra' to the amount padded, since we might have hit the above case
                 let pad_num: u32 = pad_amount + self.pad_extra;
@@ -133,13 +145,13 @@ impl<R: Read> Read for EncryptingReader<R> {
                 (&mut self.input_buffer[idx-4..]).copy_from_slice(&pad_num.to_le_bytes());
 
                 // Encrypt, write to output buffer etc.
-                let nonce = nonce_from_u128(self.nonce);
+                let nonce = self.nonce;
                 self.nonce += 1;
-                let ciphertext = self.aead.encrypt(&nonce, self.input_buffer.as_ref()).expect("Encryption failed!");
+                let ciphertext = self.aead.encrypt(nonce, self.input_buffer.as_ref()).map_err(encryption_failed)?;
                 self.output_buffer.copy_from_slice(&ciphertext);
                 self.written = 0;
                 self.written += buf.write(&self.output_buffer)?;
-                self.total_size += BLOCK_LENGTH as u64;
+                self.total_size += self.output_buffer.len() as u64;
                 self.state = EncReadState::Done;
                 Ok(self.written)
             }
@@ -151,22 +163,41 @@ impl<R: Read> Read for EncryptingReader<R> {
 }
 
 impl<R: Read> EncryptingReader<R> {
-    // Wrap another reader, encrypting with 'key'.
-    // Requires the initial nonce and the amount of nonces it may use
+    // Wrap another reader, encrypting with 'key' using 'cipher', in blocks of 'block'.
+    // Requires the initial nonce and the amount of nonces it may use (see get_nonces_required)
     // For subsequents calls, start_nonce should be at least `start_nonce+allocated_nonces´ to avoid repeat use
-    pub fn wrap(reader: R, key: &Key, start_nonce: u128, allocated_nonces: u128) -> Self {
+    // Nonces that don't fit the cipher are never used, reading fails as if it ran out of nonces instead
+    pub fn wrap(reader: R, key: &Key, cipher: Cipher, block: BlockSize, start_nonce: u128, allocated_nonces: u128) -> Self {
         EncryptingReader {
             inner: reader,
-            aead: XChaCha20Poly1305::new(key),
+            aead: AeadCipher::new(cipher, key),
+            cipher,
+            block,
+            key_id: key_id(key),
+            ephemeral: None,
             state: EncReadState::Nonce,
             nonce: start_nonce,
-            nonce_max: start_nonce+allocated_nonces,
-            input_buffer: [0u8; (BLOCK_LENGTH-16) as usize],
-            output_buffer: [0u8; BLOCK_LENGTH as usize],
+            nonce_max: start_nonce.saturating_add(allocated_nonces).min(cipher.nonce_limit()),
+            input_buffer: vec![0u8; block.data_length()],
+            output_buffer: vec![0u8; block.block_length()],
             read: 0,
             written: 0,
             total_size: 0,
             pad_extra: 0,
         }
     }
-}
\ No newline at end of file
+
+    // Wrap another reader, encrypting for 'recipient' with a new key, which is only recoverable with its private key
+    // Nonces work the same as with 'wrap'
+    pub fn wrap_for(reader: R, recipient: &PublicKey, cipher: Cipher, block: BlockSize, start_nonce: u128, allocated_nonces: u128) -> Self {
+        let (key, ephemeral) = keypair::wrap(recipient);
+        let mut encrypting = Self::wrap(reader, &key, cipher, block, start_nonce, allocated_nonces);
+        encrypting.key_id = keypair::public_id(recipient);
+        encrypting.ephemeral = Some(ephemeral);
+        encrypting
+    }
+}
+
+fn encryption_failed(_: chacha20poly1305::aead::Error) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Other, "Encryption failed")
+}
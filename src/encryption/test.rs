@@ -5,16 +5,22 @@
 mod tests {
     use crate::encryption::reader::EncryptingReader;
     use chacha20poly1305::Key;
-    use crate::encryption::{BLOCK_LENGTH, get_nonces_required, get_encrypted_size};
+    use crate::encryption::{BLOCK_LENGTH, DATA_LENGTH, HEADER_LENGTH, read_header, get_nonces_required, get_encrypted_size, random_start_nonce, block_size_of, key_from_file, BlockSize};
+    use crate::encryption::keys::{Keys, EncryptionKey, key_id};
+    use crate::encryption::keypair;
+    use crate::config::Config;
+    use crate::encryption::cipher::Cipher;
     use std::io::{Cursor, Read, Write};
     use crate::encryption::writer::DecryptingWriter;
+    use crate::encryption::decrypting_reader::DecryptingReader;
+    use crate::encryption::encrypting_writer::EncryptingWriter;
 
     #[test]
     fn test_write_to_file() {
         let filebuf = vec![1u8;43863];
         let mut reader = EncryptingReader::wrap(Cursor::new(filebuf),
                                                 Key::from_slice(b"an example very very secret key."),
-                                                0, get_nonces_required(43863));
+                                                Cipher::XChaCha20Poly1305, BlockSize::default(), 0, get_nonces_required(43863, BlockSize::default()));
 
         let mut buf = [0u8; 4096];
         let mut out = std::fs::File::create("encrypted.dat").unwrap();
@@ -30,20 +36,20 @@ mod tests {
                 break;
             }
         }
-        assert_eq!(get_encrypted_size(43863u64),written as u64);
+        assert_eq!(get_encrypted_size(43863u64, BlockSize::default()),written as u64);
     }
 
     #[test]
     // Verify the output from the encrypting reader is as expected
     fn test_output_length_small() {
-        // This should be nonce (16 bytes) + 8192 (data + padding)
+        // This should be header (32 bytes) + 8192 (data + padding)
         // We can fit 8192 - 16 (MAC) - 4 (Padding length) at most in 1 block
         for x in 0..8173 {
             let buf = vec![1u8; x];
-            assert_eq!(1, get_nonces_required(x as u64));
+            assert_eq!(1, get_nonces_required(x as u64, BlockSize::default()));
             let mut reader = EncryptingReader::wrap(Cursor::new(buf),
                                                     Key::from_slice(b"an example very very secret key."),
-                                                    0, 1);
+                                                    Cipher::XChaCha20Poly1305, BlockSize::default(), 0, 1);
             let mut out = [0u8; 32768]; // Sufficiently large buffer
             let mut read = 0;
             while let Ok(n) = reader.read(&mut out[read..]) {
@@ -53,23 +59,23 @@ mod tests {
                     break;
                 }
             }
-            assert_eq!(read, 8192 + 16);
-            assert_eq!(get_encrypted_size(x as u64),read as u64);
+            assert_eq!(read, 8192 + HEADER_LENGTH);
+            assert_eq!(get_encrypted_size(x as u64, BlockSize::default()),read as u64);
         }
     }
 
     #[test]
     // Verify the output from the encrypting reader is as expected
     fn test_output_length_scheme_needs_extra() {
-        // Should be nonce (16 bytes) + 16384 (data + padding)
+        // Should be header (32 bytes) + 16384 (data + padding)
         // These 3 (8173, 8174 and 8175) and do not have enough room for the padding scheme
         // As a result they should pad BLOCK_LENGTH + an extra 1-3 bytes for the scheme to fit
         for x in 8173..8176 {
             let buf = vec![1u8;x];
-            assert_eq!(2, get_nonces_required(x as u64));
+            assert_eq!(2, get_nonces_required(x as u64, BlockSize::default()));
             let mut reader = EncryptingReader::wrap(Cursor::new(buf),
                                                     Key::from_slice(b"an example very very secret key."),
-                                                    0, 2);
+                                                    Cipher::XChaCha20Poly1305, BlockSize::default(), 0, 2);
             let mut out = [0u8; 32768]; // Sufficiently large buffer
             let mut read = 0;
             while let Ok(n) = reader.read(&mut out[read..]) {
@@ -80,21 +86,21 @@ mod tests {
                 }
 
             }
-            assert_eq!(read, 16384+16);
-            assert_eq!(get_encrypted_size(x as u64),read as u64);
+            assert_eq!(read, 16384 + HEADER_LENGTH);
+            assert_eq!(get_encrypted_size(x as u64, BlockSize::default()),read as u64);
         }
     }
 
     #[test]
     // Verify the output from the encrypting reader is as expected
     fn test_output_length_long() {
-        // Should be nonce (16 bytes) + 16384 (data + padding)
+        // Should be header (32 bytes) + 16384 (data + padding)
         for x in 8176..13384-16 {
             let buf = vec![1u8; x];
-            assert_eq!(2, get_nonces_required(x as u64));
+            assert_eq!(2, get_nonces_required(x as u64, BlockSize::default()));
             let mut reader = EncryptingReader::wrap(Cursor::new(buf),
                                                     Key::from_slice(b"an example very very secret key."),
-                                                    0, 2);
+                                                    Cipher::XChaCha20Poly1305, BlockSize::default(), 0, 2);
             let mut out = [0u8; 32768]; // Sufficiently large buffer
             let mut read = 0;
             while let Ok(n) = reader.read(&mut out[read..]) {
@@ -104,8 +110,8 @@ mod tests {
                     break;
                 }
             }
-            assert_eq!(read, 16384 + 16);
-            assert_eq!(get_encrypted_size(x as u64),read as u64);
+            assert_eq!(read, 16384 + HEADER_LENGTH);
+            assert_eq!(get_encrypted_size(x as u64, BlockSize::default()),read as u64);
         }
     }
 
@@ -116,7 +122,7 @@ mod tests {
         let len = std::fs::metadata("secret.jpg").unwrap().len();
         let mut reader = EncryptingReader::wrap(buf,
                                                 Key::from_slice(b"an example very very secret key."),
-                                                0, get_nonces_required(len));
+                                                Cipher::XChaCha20Poly1305, BlockSize::default(), 0, get_nonces_required(len, BlockSize::default()));
 
         let mut buf = [0u8; 4096];
         let mut out = std::fs::File::create("secret.encrypted").unwrap();
@@ -163,7 +169,7 @@ mod tests {
                 let len = std::fs::metadata("secret.jpg").unwrap().len();
                 let mut reader = EncryptingReader::wrap(buf,
                                                         Key::from_slice(b"an example very very secret key."),
-                                                        i*get_nonces_required(len), get_nonces_required(len));
+                                                        Cipher::XChaCha20Poly1305, BlockSize::default(), i*get_nonces_required(len, BlockSize::default()), get_nonces_required(len, BlockSize::default()));
 
                 let mut buf = [0u8; 4096];
                 let mut out = std::fs::File::create(format!("secret{}.encrypted",i)).unwrap();
@@ -181,7 +187,7 @@ mod tests {
                         break;
                     }
                 }
-                assert_eq!(get_encrypted_size(len as u64),read as u64);
+                assert_eq!(get_encrypted_size(len as u64, BlockSize::default()),read as u64);
                 out.sync_all().unwrap();
             }
         }
@@ -199,7 +205,7 @@ mod tests {
             let indata = Cursor::new(&mut orig_data);
             let mut reader = EncryptingReader::wrap(indata,
                                                     Key::from_slice(b"an example very very secret key."),
-                                                    0, get_nonces_required(x as u64));
+                                                    Cipher::XChaCha20Poly1305, BlockSize::default(), 0, get_nonces_required(x as u64, BlockSize::default()));
 
             let mut buf = [0u8; 4096];
             let mut read = 0;
@@ -213,8 +219,8 @@ mod tests {
                 if n != 0 {
                     writer.write_all(&mut buf[..n]).unwrap();
                     written += n as u64;
-                    if written > (get_nonces_required(x as u64) as usize*BLOCK_LENGTH + 16) as u64 {
-                        panic!("Wrote way too much x{} ({} expected, got {})", x, (get_nonces_required(x as u64) as usize*BLOCK_LENGTH + 16), written);
+                    if written > (get_nonces_required(x as u64, BlockSize::default()) as usize*BLOCK_LENGTH + HEADER_LENGTH) as u64 {
+                        panic!("Wrote way too much x{} ({} expected, got {})", x, (get_nonces_required(x as u64, BlockSize::default()) as usize*BLOCK_LENGTH + HEADER_LENGTH), written);
                     }
                 } else {
                     break;
@@ -223,8 +229,348 @@ mod tests {
             writer.flush().unwrap();
 
             assert_eq!(orig_data, decr);
-            assert_eq!(get_encrypted_size(x as u64),read as u64);
+            assert_eq!(get_encrypted_size(x as u64, BlockSize::default()),read as u64);
         }
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn test_retired_keys() {
+        let old_key = Key::from_slice(b"an example very very secret key.");
+        let new_key = Key::from_slice(b"another example of a secret key!");
+        assert_ne!(key_id(old_key), key_id(new_key));
+        let keys = Keys::new(*new_key, vec![*old_key]);
+
+        let data = vec![3u8; 3*BLOCK_LENGTH];
+        let mut encrypted = Vec::new();
+        EncryptingReader::wrap(Cursor::new(&data), old_key, Cipher::XChaCha20Poly1305, BlockSize::default(), 0, get_nonces_required(data.len() as u64, BlockSize::default()))
+            .read_to_end(&mut encrypted).unwrap();
+        assert_eq!(&encrypted[..4], &key_id(old_key).to_le_bytes());
+
+        // The key header selects the retired key
+        let mut decrypted = Vec::new();
+        let mut writer = DecryptingWriter::with_keys(&mut decrypted, &keys);
+        writer.write_all(&encrypted).unwrap();
+        writer.flush().unwrap();
+        assert_eq!(decrypted, data);
+
+        // Files written before key headers existed start with the nonce, the right key is found by trying them
+        let mut decrypted = Vec::new();
+        let mut writer = DecryptingWriter::with_keys(&mut decrypted, &keys);
+        writer.write_all(&encrypted[16..]).unwrap();
+        writer.flush().unwrap();
+        assert_eq!(decrypted, data);
+
+        // Files encrypted with a key that isn't configured are rejected up front
+        let mut writer = DecryptingWriter::with_keys(Vec::new(), &Keys::new(*new_key, vec![]));
+        assert!(writer.write_all(&encrypted).is_err());
+    }
+
+    #[test]
+    fn test_random_start_nonce() {
+        let key = Key::from_slice(b"an example very very secret key.");
+        for _ in 0..100 {
+            let start = random_start_nonce(Cipher::XChaCha20Poly1305);
+            assert!(start >= 1 << 126 && start < 1 << 127);
+
+            let data = vec![5u8; 2*BLOCK_LENGTH];
+            let mut encrypted = Vec::new();
+            EncryptingReader::wrap(Cursor::new(&data), key, Cipher::XChaCha20Poly1305, BlockSize::default(), start, get_nonces_required(data.len() as u64, BlockSize::default()))
+                .read_to_end(&mut encrypted).unwrap();
+            let mut decrypted = Vec::new();
+            let mut writer = DecryptingWriter::target(&mut decrypted, key);
+            writer.write_all(&encrypted).unwrap();
+            writer.flush().unwrap();
+            assert_eq!(decrypted, data);
+        }
+    }
+
+    #[test]
+    fn test_aes_gcm() {
+        let key = Key::from_slice(b"an example very very secret key.");
+        let keys = Keys::new(*key, vec![]);
+        for start in vec![0, random_start_nonce(Cipher::Aes256Gcm)] {
+            let data = vec![9u8; 2*BLOCK_LENGTH + 100];
+            let mut encrypted = Vec::new();
+            EncryptingReader::wrap(Cursor::new(&data), key, Cipher::Aes256Gcm, BlockSize::default(), start, get_nonces_required(data.len() as u64, BlockSize::default()))
+                .read_to_end(&mut encrypted).unwrap();
+            assert_eq!(encrypted.len() as u64, get_encrypted_size(data.len() as u64, BlockSize::default()));
+            assert_eq!(encrypted[4], Cipher::Aes256Gcm.id());
+
+            // The writer picks the cipher from the header
+            let mut decrypted = Vec::new();
+            let mut writer = DecryptingWriter::with_keys(&mut decrypted, &keys);
+            writer.write_all(&encrypted).unwrap();
+            writer.flush().unwrap();
+            assert_eq!(decrypted, data);
+        }
+    }
+
+    #[test]
+    fn test_public_key() {
+        let (private, public) = keypair::generate();
+        let key = EncryptionKey::Public(public);
+        let data = vec![3u8; BLOCK_LENGTH + 10];
+        let mut encrypted = Vec::new();
+        key.wrap(Cursor::new(&data), Cipher::XChaCha20Poly1305, BlockSize::default(), 0, get_nonces_required(data.len() as u64, BlockSize::default()))
+            .read_to_end(&mut encrypted).unwrap();
+        assert_eq!(encrypted.len() as u64, key.encrypted_size(data.len() as u64, BlockSize::default()));
+
+        // Every file gets its own key
+        let mut again = Vec::new();
+        key.wrap(Cursor::new(&data), Cipher::XChaCha20Poly1305, BlockSize::default(), 0, get_nonces_required(data.len() as u64, BlockSize::default()))
+            .read_to_end(&mut again).unwrap();
+        assert_ne!(encrypted[HEADER_LENGTH..], again[HEADER_LENGTH..]);
+
+        // Only the private key can decrypt it
+        let path = std::env::temp_dir().join("retain-test-private-key");
+        std::fs::write(&path, private.to_bytes()).unwrap();
+        let mut config = Config::default();
+        config.private_key = Some(path.to_str().unwrap().to_string());
+        let keys = Keys::from_config(&config).unwrap();
+        let mut decrypted = Vec::new();
+        let mut writer = DecryptingWriter::with_keys(&mut decrypted, &keys);
+        writer.write_all(&encrypted).unwrap();
+        writer.flush().unwrap();
+        assert_eq!(decrypted, data);
+
+        let secret = Keys::new(*Key::from_slice(b"an example very very secret key."), vec![]);
+        let mut writer = DecryptingWriter::with_keys(Vec::new(), &secret);
+        assert!(writer.write_all(&encrypted).is_err());
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_resume() {
+        let key = Key::from_slice(b"an example very very secret key.");
+        let keys = Keys::new(*key, vec![]);
+        let data: Vec<u8> = (0..6*BLOCK_LENGTH).map(|i| (i % 251) as u8).collect();
+        let mut encrypted = Vec::new();
+        EncryptingReader::wrap(Cursor::new(&data), key, Cipher::XChaCha20Poly1305, BlockSize::default(), 0, get_nonces_required(data.len() as u64, BlockSize::default()))
+            .read_to_end(&mut encrypted).unwrap();
+
+        let header = read_header(&mut &encrypted[..]).unwrap();
+        assert_eq!(header.len(), HEADER_LENGTH);
+        // Decrypting from any block gives the rest of the data
+        for block in 0..5 {
+            let mut decrypted = Vec::new();
+            let mut writer = DecryptingWriter::resume(&mut decrypted, &keys, &header, block as u64).unwrap();
+            writer.write_all(&encrypted[HEADER_LENGTH + block*BLOCK_LENGTH..]).unwrap();
+            writer.flush().unwrap();
+            assert_eq!(decrypted, data[block*DATA_LENGTH..]);
+        }
+
+        // The wrong block fails to decrypt
+        let mut writer = DecryptingWriter::resume(Vec::new(), &keys, &header, 1).unwrap();
+        let result = writer.write_all(&encrypted[HEADER_LENGTH + 2*BLOCK_LENGTH..]).and_then(|_| writer.flush());
+        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+        assert!(DecryptingWriter::resume(Vec::new(), &keys, &header[..20], 1).is_err());
+    }
+
+    #[test]
+    fn test_decrypting_reader() {
+        let key = Key::from_slice(b"an example very very secret key.");
+        let keys = Keys::new(*key, vec![]);
+        for size in vec![0, 100, BLOCK_LENGTH-16-3, BLOCK_LENGTH, 3*BLOCK_LENGTH + 7] {
+            let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+            let mut encrypted = Vec::new();
+            EncryptingReader::wrap(Cursor::new(&data), key, Cipher::XChaCha20Poly1305, BlockSize::default(), 0, get_nonces_required(size as u64, BlockSize::default()))
+                .read_to_end(&mut encrypted).unwrap();
+
+            let mut decrypted = Vec::new();
+            DecryptingReader::wrap(Cursor::new(&encrypted), &keys).read_to_end(&mut decrypted).unwrap();
+            assert_eq!(decrypted, data);
+
+            // Works with reads smaller than a block
+            let mut reader = DecryptingReader::wrap(Cursor::new(&encrypted), &keys);
+            let mut decrypted = Vec::new();
+            let mut buf = [0u8; 100];
+            loop {
+                match reader.read(&mut buf).unwrap() {
+                    0 => break,
+                    n => decrypted.extend_from_slice(&buf[..n]),
+                }
+            }
+            assert_eq!(decrypted, data);
+        }
+    }
+
+    #[test]
+    fn test_encrypting_writer() {
+        let key = Key::from_slice(b"an example very very secret key.");
+        for size in vec![0, 100, BLOCK_LENGTH-16-3, BLOCK_LENGTH-16-4, BLOCK_LENGTH-16, 3*BLOCK_LENGTH + 7] {
+            let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+            let nonces = get_nonces_required(size as u64, BlockSize::default());
+            let mut expected = Vec::new();
+            EncryptingReader::wrap(Cursor::new(&data), key, Cipher::XChaCha20Poly1305, BlockSize::default(), 5, nonces)
+                .read_to_end(&mut expected).unwrap();
+
+            // Pushed in uneven pieces, the output matches that of the reader
+            let mut writer = EncryptingWriter::target(Vec::new(), key, Cipher::XChaCha20Poly1305, BlockSize::default(), 5, nonces);
+            for chunk in data.chunks(1000) {
+                writer.write_all(chunk).unwrap();
+            }
+            assert_eq!(writer.finish().unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        let key = Key::from_slice(b"an example very very secret key.");
+        let keys = Keys::new(*key, vec![]);
+        let data = vec![5u8; 2*BLOCK_LENGTH];
+        let mut encrypted = Vec::new();
+        EncryptingReader::wrap(Cursor::new(&data), key, Cipher::XChaCha20Poly1305, BlockSize::default(), 0, get_nonces_required(data.len() as u64, BlockSize::default()))
+            .read_to_end(&mut encrypted).unwrap();
+
+        // Modified, truncated and incomplete data are errors, not panics
+        let mut modified = encrypted.clone();
+        modified[HEADER_LENGTH + 10] ^= 1;
+        let mut writer = DecryptingWriter::with_keys(Vec::new(), &keys);
+        let result = writer.write_all(&modified).and_then(|_| writer.flush());
+        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+        // Data that ends early, e.g. a download that was cut off, may be complete when tried again
+        let truncated = &encrypted[..encrypted.len() - 10];
+        let blocks = &encrypted[..HEADER_LENGTH + 2*BLOCK_LENGTH];
+        let block = &encrypted[..HEADER_LENGTH + BLOCK_LENGTH];
+        for input in vec![truncated, blocks, block, &encrypted[..20]] {
+            let mut writer = DecryptingWriter::with_keys(Vec::new(), &keys);
+            let result = writer.write_all(input).and_then(|_| writer.flush());
+            assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+        }
+
+        // A source that fails, before or after a whole block, is an error and not the end of the data
+        for fail_at in [0, BLOCK_LENGTH, data.len() - 1] {
+            let failing = Failing::new(&data, fail_at, std::io::ErrorKind::BrokenPipe);
+            let mut reader = EncryptingReader::wrap(failing, key, Cipher::XChaCha20Poly1305, BlockSize::default(), 0, get_nonces_required(data.len() as u64, BlockSize::default()));
+            assert_eq!(reader.read_to_end(&mut Vec::new()).unwrap_err().kind(), std::io::ErrorKind::BrokenPipe);
+        }
+
+        // Running out of nonces is an error too
+        let mut reader = EncryptingReader::wrap(Cursor::new(&data), key, Cipher::XChaCha20Poly1305, BlockSize::default(), 0, 1);
+        assert!(reader.read_to_end(&mut Vec::new()).is_err());
+        // As are nonces that don't fit the cipher
+        let nonces = get_nonces_required(data.len() as u64, BlockSize::default());
+        let mut reader = EncryptingReader::wrap(Cursor::new(&data), key, Cipher::Aes256Gcm, BlockSize::default(), Cipher::Aes256Gcm.nonce_limit() - 1, nonces);
+        assert!(reader.read_to_end(&mut Vec::new()).is_err());
+        let mut writer = EncryptingWriter::target(Vec::new(), key, Cipher::XChaCha20Poly1305, BlockSize::default(), u128::MAX - 1, nonces);
+        assert!(writer.write_all(&data).and_then(|_| writer.finish().map(|_| ())).is_err());
+    }
+
+    #[test]
+    fn test_key_length() {
+        // E.g. a keyring entry or environment variable holding something else than a key
+        std::env::set_var("RETAIN_TEST_SHORT_KEY", "0a0b0c0d");
+        for result in [key_from_file("env:RETAIN_TEST_SHORT_KEY").map(|_| ()),
+                           keypair::private_key_from_file("env:RETAIN_TEST_SHORT_KEY").map(|_| ())] {
+            assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+        }
+        std::env::set_var("RETAIN_TEST_SHORT_KEY", "0a".repeat(32));
+        assert!(key_from_file("env:RETAIN_TEST_SHORT_KEY").is_ok());
+        std::env::remove_var("RETAIN_TEST_SHORT_KEY");
+    }
+
+    #[test]
+    fn test_block_sizes() {
+        let key = Key::from_slice(b"an example very very secret key.");
+        let keys = Keys::new(*key, vec![]);
+        assert_eq!(BlockSize::parse("64KiB").unwrap().block_length(), 64*1024);
+        assert!(BlockSize::parse("100KiB").is_err());
+        assert!(BlockSize::parse("4KiB").is_err());
+        assert!(BlockSize::parse("2MiB").is_err());
+        let block = BlockSize::parse("64KiB").unwrap();
+        // Files that don't fill a block use a smaller one
+        assert_eq!(block.fitting(100), BlockSize::default());
+        assert_eq!(block.fitting(20000).block_length(), 16*1024);
+        assert_eq!(block.fitting(1 << 30), block);
+
+        let length = block.block_length();
+        for size in [0, 100, length-16-3, length-16-4, length, 3*length + 7] {
+            let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+            let nonces = get_nonces_required(size as u64, block);
+            let mut encrypted = Vec::new();
+            EncryptingReader::wrap(Cursor::new(&data), key, Cipher::XChaCha20Poly1305, block, 0, nonces)
+                .read_to_end(&mut encrypted).unwrap();
+            assert_eq!(encrypted.len() as u64, get_encrypted_size(size as u64, block));
+            // The block size is recorded in the header, s.t. it is decrypted without being told
+            assert_eq!(encrypted[6], block.shift());
+            let mut decrypted = Vec::new();
+            DecryptingReader::wrap(Cursor::new(&encrypted), &keys).read_to_end(&mut decrypted).unwrap();
+            assert_eq!(decrypted, data);
+
+            let mut writer = EncryptingWriter::target(Vec::new(), key, Cipher::XChaCha20Poly1305, block, 0, nonces);
+            writer.write_all(&data).unwrap();
+            assert_eq!(writer.finish().unwrap(), encrypted);
+        }
+
+        // Resuming uses the block size of the header
+        let data: Vec<u8> = (0..4*length).map(|i| (i % 251) as u8).collect();
+        let mut encrypted = Vec::new();
+        EncryptingReader::wrap(Cursor::new(&data), key, Cipher::XChaCha20Poly1305, block, 0, get_nonces_required(data.len() as u64, block))
+            .read_to_end(&mut encrypted).unwrap();
+        let header = read_header(&mut &encrypted[..]).unwrap();
+        assert_eq!(block_size_of(&header), Some(block));
+        let mut decrypted = Vec::new();
+        let mut writer = DecryptingWriter::resume(&mut decrypted, &keys, &header, 2).unwrap();
+        writer.write_all(&encrypted[HEADER_LENGTH + 2*length..]).unwrap();
+        writer.flush().unwrap();
+        assert_eq!(decrypted, data[2*block.data_length()..]);
+
+        // Block sizes this version doesn't know are errors
+        encrypted[6] = 8;
+        let mut writer = DecryptingWriter::with_keys(Vec::new(), &keys);
+        let result = writer.write_all(&encrypted).and_then(|_| writer.flush());
+        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+    }
+    // Reads 'data', failing once with 'error' when 'fail_at' bytes were read
+    struct Failing {
+        data: Cursor<Vec<u8>>,
+        fail_at: u64,
+        error: std::io::ErrorKind,
+        failed: bool,
+    }
+
+    impl Failing {
+        fn new(data: &[u8], fail_at: usize, error: std::io::ErrorKind) -> Self {
+            Failing { data: Cursor::new(data.to_vec()), fail_at: fail_at as u64, error, failed: false }
+        }
+    }
+
+    impl Read for Failing {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.failed {
+                return self.data.read(buf);
+            }
+            if self.data.position() == self.fail_at {
+                self.failed = true;
+                return Err(std::io::Error::new(self.error, "cancelled"));
+            }
+            let len = buf.len().min((self.fail_at - self.data.position()) as usize);
+            self.data.read(&mut buf[..len])
+        }
+    }
+
+    #[test]
+    fn test_failing_reader() {
+        let key = Key::from_slice(b"an example very very secret key.");
+        let keys = Keys::new(*key, vec![]);
+        let data = vec![3u8; 3*BLOCK_LENGTH];
+        let nonces = get_nonces_required(data.len() as u64, BlockSize::default());
+
+        // A read that fails partway, e.g. a cancelled upload, fails the encrypted stream instead of ending it early
+        let failing = Failing::new(&data, 2*BLOCK_LENGTH + 100, std::io::ErrorKind::Other);
+        let mut reader = EncryptingReader::wrap(failing, key, Cipher::XChaCha20Poly1305, BlockSize::default(), 0, nonces);
+        assert_eq!(reader.read_to_end(&mut Vec::new()).unwrap_err().kind(), std::io::ErrorKind::Other);
+
+        // An interrupted read is tried again
+        let interrupted = Failing::new(&data, 100, std::io::ErrorKind::Interrupted);
+        let mut encrypted = Vec::new();
+        EncryptingReader::wrap(interrupted, key, Cipher::XChaCha20Poly1305, BlockSize::default(), 0, nonces)
+            .read_to_end(&mut encrypted).unwrap();
+        let mut decrypted = Vec::new();
+        let mut writer = DecryptingWriter::with_keys(&mut decrypted, &keys);
+        writer.write_all(&encrypted).unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+        assert_eq!(decrypted, data);
+    }
+}
@@ -2,19 +2,23 @@
 /// Targets another Writer, sending decrypted data to it
 
 use std::io::{Write};
-use chacha20poly1305::{XChaCha20Poly1305, Key, XNonce};
-use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::Key;
 
-// Size of a 'block'
-use super::BLOCK_LENGTH;
-use crate::encryption::{DATA_LENGTH, nonce_from_u128};
+use crate::encryption::{BlockSize, KEY_MAGIC, FLAG_WRAPPED};
+use crate::encryption::cipher::{Cipher, AeadCipher};
+use crate::encryption::keys::{Keys, KeyId};
+use crate::encryption::keypair::{self, WRAP_LENGTH};
 
 // State of the writer
+// Header: waiting to get the key header, or the initial nonce if the file has none
+// Wrap: waiting to get the ephemeral public key, if the file was encrypted for a public key
 // Nonce: waiting to get the initial nonce
 // Data: decrypting data blocks
 // Done: Returns only Ok(0)
 #[derive(Debug, PartialEq)]
 enum DecWriteState {
+    Header,
+    Wrap,
     Nonce,
     Data,
     Done,
@@ -27,18 +31,83 @@ enum DecWriteState {
 // WARNING: Beware that write_all will _NOT_ call write() if given an empty buffer!
 pub struct DecryptingWriter<W: Write> {
     target: W, // Inner write, this will receive decrypted data
-    aead: XChaCha20Poly1305,
+    keys: Keys, // Keys the file may be encrypted with
+    aead: Option<AeadCipher>, // None until the key is known
     state: DecWriteState,
     nonce: u128, // Current nonce (counter)
-    input_buffer: [u8; 3*BLOCK_LENGTH as usize], // Triple length buffer
+    block: BlockSize, // Size of a 'block', from the key header
+    input_buffer: Vec<u8>, // Triple block length buffer
     received: usize,
 }
 
 impl<W: Write> Write for DecryptingWriter<W> {
     fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
         match self.state {
+            // Receive the key header, which tells which key to use
+            DecWriteState::Header => {
+                if buf.is_empty() {
+                    return Err(truncated("Encrypted data ended within the header"));
+                }
+                let read_len = buf.len().min(16-self.received);
+                self.input_buffer[self.received..self.received+read_len].copy_from_slice(&buf[..read_len]);
+                self.received += read_len;
+                if self.received == 16 {
+                    if self.input_buffer[8..16] == KEY_MAGIC {
+                        if self.input_buffer[5] & FLAG_WRAPPED != 0 {
+                            // The key is only known once we have the ephemeral public key
+                            self.state = DecWriteState::Wrap;
+                            return Ok(read_len);
+                        }
+                        self.received = 0;
+                        let (id, cipher, block) = read_header(&self.input_buffer[..16])?;
+                        let key = match self.keys.find(id) {
+                            Some(k) => k,
+                            None => return Err(invalid_data(format!("Encrypted with an unknown key (ID {:08x})", id))),
+                        };
+                        self.aead = Some(AeadCipher::new(cipher, key));
+                        self.set_block(block);
+                        self.state = DecWriteState::Nonce;
+                    } else {
+                        // Written by an older version, this was the nonce
+                        self.received = 0;
+                        let mut le_bytes = [0u8; 16];
+                        le_bytes.copy_from_slice(&self.input_buffer[..16]);
+                        self.nonce = u128::from_le_bytes(le_bytes);
+                        self.state = DecWriteState::Data;
+                    }
+                }
+
+                Ok(read_len)
+            }
+            // Receive the ephemeral public key, which follows the key header
+            DecWriteState::Wrap => {
+                if buf.is_empty() {
+                    return Err(truncated("Encrypted data ended within the header"));
+                }
+                let read_len = buf.len().min(16+WRAP_LENGTH-self.received);
+                self.input_buffer[self.received..self.received+read_len].copy_from_slice(&buf[..read_len]);
+                self.received += read_len;
+                if self.received == 16+WRAP_LENGTH {
+                    self.received = 0;
+                    let (id, cipher, block) = read_header(&self.input_buffer[..16])?;
+                    let private = match self.keys.find_private(id) {
+                        Some(k) => k,
+                        None => return Err(invalid_data(format!("Encrypted for an unknown public key (ID {:08x})", id))),
+                    };
+                    let mut ephemeral = [0u8; WRAP_LENGTH];
+                    ephemeral.copy_from_slice(&self.input_buffer[16..16+WRAP_LENGTH]);
+                    self.aead = Some(AeadCipher::new(cipher, &keypair::unwrap(private, ephemeral)));
+                    self.set_block(block);
+                    self.state = DecWriteState::Nonce;
+                }
+
+                Ok(read_len)
+            }
             // Receive the initial nonce value
             DecWriteState::Nonce => {
+                if buf.is_empty() {
+                    return Err(truncated("Encrypted data ended within the header"));
+                }
                 let read_len = buf.len().min(16-self.received);
                 self.input_buffer[self.received..self.received+read_len].copy_from_slice(&buf[..read_len]);
                 self.received += read_len;
@@ -54,6 +123,7 @@ impl<W: Write> Write for DecryptingWriter<W> {
             }
             // Receive and decrypt data
             DecWriteState::Data => {
+                let block_length = self.block.block_length();
                 // Read into our internal buffer. At most, enough to fill the buffer
                 let read_len = buf.len().min(self.input_buffer.len()-self.received);
                 self.input_buffer[self.received..self.received+read_len].copy_from_slice(&buf[..read_len]);
@@ -66,55 +136,56 @@ impl<W: Write> Write for DecryptingWriter<W> {
                 // 3. If there were more data, we know block 1 isn't padded
                 if self.received == self.input_buffer.len() {
                     // We got 3 blocks. Block 1 is not padded, decrypt and write it
-                    let mut nonce_arr = vec![0u8; 8];
-                    nonce_arr.append(&mut self.nonce.to_le_bytes().to_vec());
-                    let nonce = XNonce::from_slice(&nonce_arr);
+                    let nonce = self.nonce;
                     self.nonce += 1;
-                    let plaintext = self.aead.decrypt(nonce, &self.input_buffer[..BLOCK_LENGTH])
-                        .expect("Decryption failed!");
+                    let plaintext = decrypt(&mut self.aead, &self.keys, nonce, &self.input_buffer[..block_length])
+                        .ok_or_else(corrupt)?;
                     self.target.write_all(&plaintext)?;
                     // Move current items s.t. block 2 is now block 1, block 3 is now block 2
-                    self.input_buffer.rotate_left(BLOCK_LENGTH as usize);
-                    self.received -= BLOCK_LENGTH;
+                    self.input_buffer.rotate_left(block_length);
+                    self.received -= block_length;
                 } else if read_len == 0 { // 0-size buffer, assume we get no more input and finish up
                     self.state = DecWriteState::Done;
                     // Ensure we have the right amount of bytes
-                    if self.received % BLOCK_LENGTH != 0 {
-                        panic!("Decryption received an incorrect amount of input");
+                    if self.received % block_length != 0 {
+                        return Err(truncated("Encrypted data has an incorrect length"));
                     }
                     // Two cases here
-                    // We only have one block (small file, <= BLOCK_LENGTH)
-                    // We have two blocks (file size >= BLOCK_LENGTH)
-                    if self.received == BLOCK_LENGTH as usize { // 1 block only
-                        let nonce = nonce_from_u128(self.nonce);
+                    // We only have one block (small file, <= block length)
+                    // We have two blocks (file size >= block length)
+                    if self.received == block_length { // 1 block only
+                        let nonce = self.nonce;
                         self.nonce += 1;
-                        let plaintext = self.aead.decrypt(&nonce, &self.input_buffer[..BLOCK_LENGTH])
-                            .expect("Decryption failed!");
+                        let plaintext = decrypt(&mut self.aead, &self.keys, nonce, &self.input_buffer[..block_length])
+                            .ok_or_else(corrupt)?;
                         let mut le_bytes = [0u8; 4];
                         le_bytes.copy_from_slice(&plaintext[plaintext.len()-4..]);
                         let pad_amount = u32::from_le_bytes(le_bytes) as usize;
-                        self.target.write_all(&plaintext[..plaintext.len()-pad_amount])?;
-                    } else if self.received == 2*BLOCK_LENGTH as usize { // 2 blocks
-                        let nonce = nonce_from_u128(self.nonce);
+                        // More padding than data means this wasn't the last block, the rest is missing
+                        let kept = plaintext.len().checked_sub(pad_amount).ok_or_else(|| truncated("Encrypted data ended early"))?;
+                        self.target.write_all(&plaintext[..kept])?;
+                    } else if self.received == 2*block_length { // 2 blocks
+                        let nonce = self.nonce;
                         self.nonce += 1;
-                        let plaintext1 = self.aead.decrypt(&nonce, &self.input_buffer[..BLOCK_LENGTH])
-                            .expect("Decryption failed!");
-                        let nonce = nonce_from_u128(self.nonce);
+                        let plaintext1 = decrypt(&mut self.aead, &self.keys, nonce, &self.input_buffer[..block_length])
+                            .ok_or_else(corrupt)?;
+                        let nonce = self.nonce;
                         self.nonce += 1;
-                        let plaintext2 = self.aead.decrypt(&nonce, &self.input_buffer[BLOCK_LENGTH..2*BLOCK_LENGTH])
-                            .expect("Decryption failed!");
+                        let plaintext2 = decrypt(&mut self.aead, &self.keys, nonce, &self.input_buffer[block_length..2*block_length])
+                            .ok_or_else(corrupt)?;
                         let mut le_bytes = [0u8; 4];
                         le_bytes.copy_from_slice(&plaintext2[plaintext2.len()-4..]);
                         let mut pad_amount = u32::from_le_bytes(le_bytes) as usize;
-                        if pad_amount >= DATA_LENGTH { // Full block pad, ignore plaintext2
-                            pad_amount -= DATA_LENGTH;
-                            self.target.write_all(&plaintext1[..plaintext1.len()-pad_amount])?;
+                        if pad_amount >= self.block.data_length() { // Full block pad, ignore plaintext2
+                            pad_amount -= self.block.data_length();
+                            let kept = plaintext1.len().checked_sub(pad_amount).ok_or_else(|| truncated("Encrypted data ended early"))?;
+                            self.target.write_all(&plaintext1[..kept])?;
                         } else {
                             self.target.write_all(&plaintext1)?;
                             self.target.write_all(&plaintext2[..plaintext2.len()-pad_amount])?;
                         }
                     } else {
-                        panic!("Invalid amount of data!");
+                        return Err(truncated("Encrypted data has an incorrect length"));
                     }
                 }
 
@@ -130,19 +201,95 @@ impl<W: Write> Write for DecryptingWriter<W> {
 
     fn flush(&mut self) -> Result<(),std::io::Error> {
         self.write(&[])?;
-        Ok(())
+        self.target.flush()
+    }
+}
+
+fn invalid_data(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+// The data ended too soon, e.g. a download that was cut off. Unlike corrupt data, trying again may help
+fn truncated(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::UnexpectedEof, message)
+}
+
+// A block failed authentication
+fn corrupt() -> std::io::Error {
+    invalid_data("Decryption failed, the data is corrupt or was modified".to_string())
+}
+
+// Reads the key ID, cipher and block size from a key header
+fn read_header(header: &[u8]) -> Result<(KeyId, Cipher, BlockSize), std::io::Error> {
+    let mut le_bytes = [0u8; 4];
+    le_bytes.copy_from_slice(&header[..4]);
+    let cipher = match Cipher::from_id(header[4]) {
+        Some(cipher) => cipher,
+        None => return Err(invalid_data(format!("Encrypted with an unknown cipher (ID {})", header[4]))),
+    };
+    match BlockSize::from_shift(header[6]) {
+        Some(block) => Ok((KeyId::from_le_bytes(le_bytes), cipher, block)),
+        None => Err(invalid_data(format!("Encrypted with an unknown block size (shift {})", header[6]))),
+    }
+}
+
+// Decrypts a block with the key of the file
+// Files without a key header don't say which key they use, so their first block is tried with every key
+// The key that fits is then used for the rest of the file. Those files always use XChaCha20Poly1305
+fn decrypt(aead: &mut Option<AeadCipher>, keys: &Keys, nonce: u128, block: &[u8]) -> Option<Vec<u8>> {
+    if let Some(aead) = aead {
+        return aead.decrypt(nonce, block).ok();
     }
+    for key in keys.all() {
+        let candidate = AeadCipher::new(Cipher::XChaCha20Poly1305, key);
+        if let Ok(plaintext) = candidate.decrypt(nonce, block) {
+            *aead = Some(candidate);
+            return Some(plaintext);
+        }
+    }
+    None
 }
 
 impl<W: Write> DecryptingWriter<W> {
+    #[allow(dead_code)]
     pub fn target(writer: W, key: &Key) -> Self {
+        Self::with_keys(writer, &Keys::new(*key, vec![]))
+    }
+
+    // Decrypts with whichever of 'keys' the data was encrypted with
+    pub fn with_keys(writer: W, keys: &Keys) -> Self {
         DecryptingWriter {
             target: writer,
-            aead: XChaCha20Poly1305::new(key),
-            state: DecWriteState::Nonce,
+            keys: keys.clone(),
+            aead: None,
+            state: DecWriteState::Header,
             nonce: 0,
-            input_buffer: [0u8; 3*BLOCK_LENGTH as usize],
+            block: BlockSize::default(),
+            input_buffer: vec![0u8; 3*BlockSize::default().block_length()],
             received: 0,
         }
     }
+
+    // Decrypts a file from block 'block' on, given its header (see encryption::read_header)
+    // The nonce counter is re-seeded from the initial nonce, s.t. the blocks before don't have to be written
+    pub fn resume(writer: W, keys: &Keys, header: &[u8], block: u64) -> Result<Self, std::io::Error> {
+        let mut decrypting = Self::with_keys(writer, keys);
+        decrypting.write_all(header)?;
+        if decrypting.state != DecWriteState::Data || decrypting.received != 0 {
+            return Err(invalid_data("Not the header of an encrypted file".to_string()));
+        }
+        decrypting.nonce += block as u128;
+        Ok(decrypting)
+    }
+
+    // Uses blocks of 'block' from now on, which is only known once the key header is read
+    fn set_block(&mut self, block: BlockSize) {
+        self.block = block;
+        self.input_buffer.resize(3*block.block_length(), 0);
+    }
+
+    // Gets a mutable reference to the target writer
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.target
+    }
 }
\ No newline at end of file
@@ -9,11 +9,12 @@
 //! A rule starts with a path to a file or directory
 //! If it's a file, that file will be uploaded and no filtering can be applied
 //! If it's a directory, it can be followed by any number of filtering rules
-//! Each filter rule is a regular expression. Anything that matches this regex is excluded
-//! Filter rules start with a '-' followed by the expression
+//! Each filter rule is a regular expression, starting with a '-' (exclude) or a '+' (include) followed by the expression
+//! A file is checked against every filter of its directory. The last filter that matches decides whether it is uploaded
+//! Files that match no filter are uploaded, s.t. '+' filters only matter after a '-' filter that matches the same files
 //!
 //! Example:
-//! ```
+//! ```text
 //! /home/user/
 //! - target/
 //! - \.txt$
@@ -25,11 +26,104 @@
 //! Consider a file with path `/home/user/documents/target/books/book.pdf` \
 //! It is included by the `/home/user/` rule. The filters are then applied only on the sub-path, i.e. `documents/target/books/book.pdf` \
 //! Since this matches `- target/`, it will not be uploaded
+//!
+//! Include filters make exceptions to exclude filters above them:
+//! ```text
+//! /home/user/project/
+//! - target/
+//! + target/release/[^/]*\.bin$
+//! ```
+//! This skips everything in `target/`, except the `.bin` files directly in `target/release/`
+//!
+//! Age filters:
+//! Instead of an expression, a filter can be `older-than:AGE` or `newer-than:AGE`, matching files by when they were last modified
+//! The age is a number and a unit: s(econds), m(inutes), h(ours), d(ays) or w(eeks), e.g. `- older-than:365d`
+//! They are ordered like the other filters, e.g. `- .*` followed by `+ newer-than:30d` only uploads files modified in the last 30 days
+//! Age filters are not available in the gitignore dialect
+//!
+//! Globs:
+//! A path can be a glob, e.g. `/home/*/Documents/` or `/var/log/*.log`, which is expanded every time files are collected
+//! Everything it matches at that time gets its filters and directives, s.t. e.g. the documents of a new user are backed up without editing the list
+//! `*` and `?` don't match a '/', `**` matches any amount of directories, and `[...]` matches one of the characters in it
+//! A path that exists as it is written is never treated as a glob, even if it has these characters
+//!
+//! Directives:
+//! A path can also be followed by directives, lines starting with a '!', which set how its directory is walked
+//! `!follow-symlinks` follows symlinks in it, uploading what they link to under the path of the link
+//! `!no-follow` skips symlinks in it, which is the default
+//! When following symlinks, a link to a directory it is in (a loop) is skipped with a warning
+//! `!skip-hidden` skips hidden files and directories in it: names starting with a '.', and on Windows files with the Hidden attribute
+//! `!one-file-system` doesn't walk into directories on other file systems than the path, e.g. /proc, network shares or external drives
+//! The `--one-file-system` flag does this for every path in the backup list
+//!
+//! Directories with a CACHEDIR.TAG (see https://bford.info/cachedir/) are skipped, e.g. cargo's target directories and browser caches
+//! `!include-caches` backs them up anyway. A path in the backup list that has a CACHEDIR.TAG itself is always backed up
+//! `!exclude-if-present NAME` skips directories with a file called NAME in them, e.g. `!exclude-if-present .nobackup`
+//! It can be given multiple times for several names. As with caches, the path in the backup list itself is never skipped
+//!
+//! Gitignore dialect:
+//! A backup list starting with the line `# syntax: gitignore` uses gitignore patterns instead of regular expressions
+//! Each path is written as `[path]`, followed by the patterns for it, as they would be in a `.gitignore` in that directory
+//! Patterns are globs (with `**`), which exclude what they match. A leading `!` re-includes, and a leading `/` anchors the pattern to the path
+//! As with the regular expressions, the last pattern that matches decides, s.t. a `!` pattern can re-include files in an excluded directory
+//! Lines starting with `#` are comments. A pattern that is a bracket expression on its own, e.g. `[ab]`, must be written as `**/[ab]`
+//! The directives are the same, a pattern re-including a file called e.g. `follow-symlinks` must be written as `!/follow-symlinks`
+//!
+//! Example:
+//! ```text
+//! # syntax: gitignore
+//! [/home/user/project/]
+//! !follow-symlinks
+//! target/
+//! !target/release/*.bin
+//! *.txt
+//! [/etc/foo/config.cfg]
+//! ```
 
-use std::path::Path;
-use regex::{Regex,RegexSet};
+use std::path::{Path, PathBuf};
+use regex::Regex;
 use walkdir::WalkDir;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use crate::winpath;
+use crate::shadow;
+use crate::scancache::{self, ScanCache};
+use crate::datetime;
+use crate::colorutil::{printcoln, printverbose};
+use termcolor::Color;
 use std::fs::FileType;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::io::Read;
+
+/// First line of a backup list in the gitignore dialect
+pub const GITIGNORE_HEADER: &str = "# syntax: gitignore";
+
+// Directives for how a path is walked
+const FOLLOW_SYMLINKS: &str = "!follow-symlinks";
+const NO_FOLLOW: &str = "!no-follow";
+const SKIP_HIDDEN: &str = "!skip-hidden";
+const ONE_FILE_SYSTEM: &str = "!one-file-system";
+const INCLUDE_CACHES: &str = "!include-caches";
+const DIRECTIVES: &[&str] = &[FOLLOW_SYMLINKS, NO_FOLLOW, SKIP_HIDDEN, ONE_FILE_SYSTEM, INCLUDE_CACHES];
+// Followed by the name of the marker file
+const EXCLUDE_IF_PRESENT: &str = "!exclude-if-present";
+
+// Marks a directory as a cache, if its CACHEDIR.TAG starts with CACHEDIR_SIGNATURE
+const CACHEDIR_TAG: &str = "CACHEDIR.TAG";
+const CACHEDIR_SIGNATURE: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
+
+// Whether no path in the backup list is walked across file systems, as if they all had '!one-file-system'
+static ONE_FILE_SYSTEM_ALL: AtomicBool = AtomicBool::new(false);
+
+/// Keeps every path in the backup list on its file system from now on, see '!one-file-system'
+pub fn set_one_file_system(on: bool) {
+    ONE_FILE_SYSTEM_ALL.store(on, Ordering::Relaxed);
+}
+
+/// Whether every path in the backup list is kept on its file system
+pub fn one_file_system() -> bool {
+    ONE_FILE_SYSTEM_ALL.load(Ordering::Relaxed)
+}
 
 /// Verifies the structure of the backup list is correct without collecting files
 /// Returns OK or an Err with where in the file it encountered an error
@@ -37,75 +131,1134 @@ pub fn verify_structure<T: AsRef<Path>>(file: T) -> Result<(),String> {
     let text = match std::fs::read_to_string(file) {
         Ok(s) => s,
         Err(err) => {
-            format!("Failed to open backup list {:?}", err)
+            return Err(format!("Failed to open backup list {:?}", err));
         }
     };
+    if is_gitignore(&text) {
+        return verify_gitignore(&text);
+    }
 
     let mut lines = text.lines();
     let mut dir = match lines.next() {
         Some(s) => s.trim(),
         None => return Err("Backup list contains no entries".to_owned()),
     };
-    if dir.starts_with("-") {
+    if is_filter(dir) || is_directive(dir) {
         return Err("Backup list started with a filter, not a path".to_string());
     }
     for line in text.lines() {
         let line = line.trim();
-        if line.starts_with('-') {
-            if Regex::new(line).is_err() {
-                return Err(format!("Invalid RegEx - {}", line))
-            }
+        // Empty lines are skipped, see parse_rules
+        if line.is_empty() {
+            continue;
+        }
+        if is_filter(line) {
+            parse_filter(line[1..].trim()).map_err(|e| format!("{} - {}", e, line))?;
+        } else if is_directive(line) {
+            parse_directive(line, &mut WalkOptions::default())?;
         } else {
-            if !std::path::Path::new(line).exists() {
-                return Err(format!("File/Directory not found - {}", line))
-            }
+            check_root(line)?;
         }
     }
 
     Ok(())
 }
 
+fn verify_gitignore(text: &str) -> Result<(),String> {
+    let sections = gitignore_sections(text)?;
+    if sections.is_empty() {
+        return Err("Backup list contains no entries".to_owned());
+    }
+    for section in sections {
+        check_root(section.root)?;
+        build_gitignore(section.root, &section.patterns)?;
+    }
+    Ok(())
+}
+
+/// Returns the paths in the backup list, without their filters
+/// Globs are returned as they are written, see expand_root
+pub fn list_roots<T: AsRef<Path>>(file: T) -> Result<Vec<String>, std::io::Error> {
+    let text = std::fs::read_to_string(file)?;
+    if is_gitignore(&text) {
+        return gitignore_sections(&text)
+            .map(|sections| sections.into_iter().map(|section| section.root.to_string()).collect())
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e));
+    }
+    Ok(text.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !is_filter(line) && !is_directive(line))
+        .map(|line| line.to_string())
+        .collect())
+}
+
 /// Applies each rule in the backup list, returning a Vec with each file that is to be uploaded
 pub fn build_file_list<T: AsRef<Path>>(file: T) -> Vec<String> {
+    collect_files(file).0
+}
+
+/// Like build_file_list, but also returns how many files were excluded by filters or skipped as symlinks
+/// Files in skipped directories aren't counted, since those aren't walked
+pub fn collect_files<T: AsRef<Path>>(file: T) -> (Vec<String>, usize) {
+    let mut files: Vec<String> = Vec::new();
+    let text = std::fs::read_to_string(file).unwrap();
+    let excluded = parse_rules(&text).iter().map(|rule| walk_rule(rule, &mut files)).sum();
+    (files, excluded)
+}
+
+/// Same as collect_files, but directories that didn't change since 'cache' was made are not read again, see scancache.rs
+/// Also returns the cache for the next scan. Paths that follow symlinks or have age filters are walked as usual
+pub fn collect_files_cached<T: AsRef<Path>>(file: T, cache: &ScanCache) -> (Vec<String>, usize, ScanCache) {
     let mut files: Vec<String> = Vec::new();
+    let mut excluded = 0;
+    let mut next = ScanCache::new();
     let text = std::fs::read_to_string(file).unwrap();
 
-    let mut regex_str = Vec::new();
+    for rule in parse_rules(&text) {
+        let root = winpath::extended(&rule.path);
+        let is_dir = match std::fs::symlink_metadata(&root) {
+            Ok(metadata) => metadata.is_dir(),
+            Err(_) => false,
+        };
+        if is_dir && rule.cacheable() {
+            let root_device = device(Path::new(&root)).filter(|_| rule.on_one_file_system());
+            excluded += walk_cached(&rule, Path::new(&root), root_device, cache, &mut next, &mut files);
+        } else {
+            excluded += walk_rule(&rule, &mut files);
+        }
+    }
+
+    (files, excluded, next)
+}
+
+// Adds the files 'rule' uploads to 'files', returns how many were excluded
+fn walk_rule(rule: &Rule, files: &mut Vec<String>) -> usize {
+    let mut excluded = 0;
+    // Walked in the extended-length form on Windows, s.t. long paths are found
+    let walk = WalkDir::new(winpath::extended(&rule.path))
+        .follow_links(rule.walk.follow_links)
+        .same_file_system(rule.on_one_file_system())
+        .into_iter()
+        // Hidden and skipped directories are skipped as a whole. The path itself is walked even if it is either
+        .filter_entry(|e| e.depth() == 0 || match rule.skipped(e.path(), e.file_type().is_dir()) {
+            Some(reason) => {
+                printverbose(format!("Skipped {} ({})", e.path().display(), reason));
+                false
+            },
+            None => true,
+        });
+    for entry in walk {
+        let entry = match entry {
+            Ok(entry) => entry,
+            // Only reported when following symlinks, walkdir detects loops s.t. they aren't walked forever
+            Err(e) if e.loop_ancestor().is_some() => {
+                printcoln(Color::Yellow, format!("Warning: skipped symlink loop at {}", e.path().map_or("?".into(), |p| p.to_string_lossy())));
+                continue;
+            },
+            Err(_) => continue,
+        };
+        let name = match entry.path().to_str() {
+            Some(s) => winpath::normalize(s),
+            None => continue,
+        };
+        if entry.file_type().is_file() {
+            excluded += rule.add_file(name, files);
+        } else if entry.file_type().is_symlink() {
+            printverbose(format!("Skipped symlink {} ({})", name, NO_FOLLOW));
+            excluded += 1;
+        }
+    }
+    excluded
+}
+
+// Walks 'dir' like walk_rule, using the listings in 'cache' of directories that didn't change and adding all listings to 'next'
+// 'root_device' is the file system the walk stays on, if it stays on one
+fn walk_cached(rule: &Rule, dir: &Path, root_device: Option<u64>, cache: &ScanCache, next: &mut ScanCache, files: &mut Vec<String>) -> usize {
+    let key = match dir.to_str() {
+        Some(s) => winpath::normalize(s),
+        None => return 0,
+    };
+    let modified = scancache::modified(dir);
+    let listing = match cache.get(&key, modified) {
+        Some(listing) => listing.clone(),
+        None => match scancache::read_dir(dir, modified) {
+            Ok(listing) => listing,
+            Err(_) => return 0,
+        },
+    };
+
+    let mut excluded = 0;
+    for name in &listing.files {
+        let path = dir.join(name);
+        match rule.skipped(&path, false) {
+            Some(reason) => printverbose(format!("Skipped {} ({})", path.display(), reason)),
+            None => excluded += rule.add_file(winpath::normalize(&path.to_string_lossy()), files),
+        }
+    }
+    for name in &listing.links {
+        let path = dir.join(name);
+        match rule.skipped(&path, false) {
+            Some(reason) => printverbose(format!("Skipped {} ({})", path.display(), reason)),
+            None => {
+                printverbose(format!("Skipped symlink {} ({})", winpath::normalize(&path.to_string_lossy()), NO_FOLLOW));
+                excluded += 1;
+            },
+        }
+    }
+    for name in &listing.dirs {
+        let path = dir.join(name);
+        if let Some(reason) = rule.skipped(&path, true) {
+            printverbose(format!("Skipped {} ({})", path.display(), reason));
+            continue;
+        }
+        if root_device.is_some() && device(&path) != root_device {
+            continue;
+        }
+        excluded += walk_cached(rule, &path, root_device, cache, next, files);
+    }
+    next.insert(key, listing);
+    excluded
+}
+
+/// Keeps only the paths that are included by the backup list, without walking any directories
+/// Used to check changed files, e.g. when watching for changes. Does not check that the files exist
+pub fn filter_included<T: AsRef<Path>>(file: T, mut paths: Vec<String>) -> Result<Vec<String>, std::io::Error> {
+    let rules = parse_rules(&std::fs::read_to_string(file)?);
+    paths.retain(|path| rules.iter().any(|rule| Path::new(path).starts_with(&rule.path) && !rule.in_hidden(path) && rule.in_skipped_dir(path).is_none()
+        && !rule.across_file_systems(path) && rule.includes(path)));
+    Ok(paths)
+}
+
+/// How one of the paths in the backup list decides whether a file is uploaded, see 'explain'
+pub struct Explanation {
+    /// The path in the backup list that contains the file
+    pub root: String,
+    /// Whether this path uploads the file
+    pub included: bool,
+    /// The filter or directive that decided it, None if nothing matches the file
+    pub rule: Option<String>,
+}
+
+/// Explains whether the file at 'path' is uploaded, for each path in the backup list that contains it
+/// The file is uploaded if any of them includes it. Does not check that the file exists
+pub fn explain<T: AsRef<Path>>(file: T, path: &str) -> Result<Vec<Explanation>, std::io::Error> {
+    let rules = parse_rules(&std::fs::read_to_string(file)?);
+    Ok(rules.iter().filter(|rule| Path::new(path).starts_with(&rule.path)).map(|rule| {
+        let (included, decided_by) = if rule.in_hidden(path) {
+            (false, Some(SKIP_HIDDEN.to_string()))
+        } else if rule.via_symlink(path) {
+            (false, Some(NO_FOLLOW.to_string()))
+        } else if rule.across_file_systems(path) {
+            (false, Some(ONE_FILE_SYSTEM.to_string()))
+        } else if let Some(reason) = rule.in_skipped_dir(path) {
+            (false, Some(reason))
+        } else {
+            rule.decide(path)
+        };
+        Explanation { root: rule.path.clone(), included, rule: decided_by }
+    }).collect())
+}
+
+/// The paths in the backup list 'text', each with the filters and directives under it as they are written
+pub fn entries(text: &str) -> Vec<(String, Vec<String>)> {
+    let gitignore = is_gitignore(text);
+    let lines: Vec<&str> = text.lines().collect();
+    entry_ranges(&lines, gitignore).into_iter()
+        .map(|(start, end)| (entry_root(lines[start].trim(), gitignore).unwrap().to_string(),
+            lines[start + 1..end].iter().map(|l| l.trim()).filter(|l| !l.is_empty() && !l.starts_with('#')).map(|l| l.to_string()).collect()))
+        .collect()
+}
+
+/// Adds 'root' to the backup list 'text' if it isn't in it yet, and the filter or directive 'line' under it if given
+/// Returns the new backup list, or why the entry can't be added. The rest of the list is kept as it is written
+pub fn add_entry(text: &str, root: &str, line: Option<&str>) -> Result<String, String> {
+    let gitignore = is_gitignore(text);
+    let mut lines: Vec<&str> = text.lines().collect();
+    let root_line = if gitignore { format!("[{}]", root) } else { root.to_string() };
+    let (start, end) = match find_entry(&lines, gitignore, root) {
+        Some(_) if line.is_none() => return Err(format!("{} is already in the backup list", root)),
+        Some(range) => range,
+        None => {
+            check_root(root)?;
+            lines.push(&root_line);
+            (lines.len() - 1, lines.len())
+        },
+    };
+    if let Some(line) = line.map(|l| l.trim()) {
+        check_line(line, root, gitignore)?;
+        if lines[start + 1..end].iter().any(|l| l.trim() == line) {
+            return Err(format!("{} is already under {}", line, root));
+        }
+        lines.insert(end, line);
+    }
+    Ok(lines.join("\n") + "\n")
+}
+
+/// Removes 'root' and everything under it from the backup list 'text', or only the filter or directive 'line' under it if given
+/// Returns the new backup list and the removed lines, or why nothing can be removed
+pub fn remove_entry(text: &str, root: &str, line: Option<&str>) -> Result<(String, Vec<String>), String> {
+    let gitignore = is_gitignore(text);
+    let mut lines: Vec<&str> = text.lines().collect();
+    let (start, end) = find_entry(&lines, gitignore, root).ok_or(format!("{} is not in the backup list", root))?;
+    let removed: Vec<String> = match line.map(|l| l.trim()) {
+        Some(line) => match (start + 1..end).find(|i| lines[*i].trim() == line) {
+            Some(i) => lines.drain(i..=i).map(|l| l.to_string()).collect(),
+            None => return Err(format!("{} is not under {}", line, root)),
+        },
+        None => lines.drain(start..end).map(|l| l.to_string()).collect(),
+    };
+    // Blank lines that separated it from the entry before it
+    while lines.last().map_or(false, |l| l.trim().is_empty()) {
+        lines.pop();
+    }
+    Ok((lines.join("\n") + "\n", removed))
+}
+
+// A path from the backup list, the filters applied to everything in it and how it is walked
+struct Rule {
+    path: String,
+    filters: Filters,
+    walk: WalkOptions,
+}
+
+// How the directory of a path is walked, set by directives
+#[derive(Default, Clone)]
+struct WalkOptions {
+    follow_links: bool,
+    skip_hidden: bool,
+    one_file_system: bool,
+    include_caches: bool,
+    // Names of files that mark a directory to be skipped
+    markers: Vec<String>,
+}
+
+enum Filters {
+    // Each filter, in order
+    List(Vec<FilterLine>),
+    Gitignore(Gitignore),
+}
+
+// A '-' or '+' line of the backup list
+#[derive(Clone)]
+struct FilterLine {
+    line: String,
+    include: bool,
+    filter: Filter,
+}
+
+#[derive(Clone)]
+enum Filter {
+    Regex(Regex),
+    // Files modified more than this many milliseconds ago
+    OlderThan(u64),
+    // Files modified less than this many milliseconds ago
+    NewerThan(u64),
+}
+
+impl Filter {
+    // 'age' is the age of the file at 'path', read (once) only if an age filter is checked
+    fn is_match(&self, path: &str, age: &mut Option<Option<u64>>) -> bool {
+        match self {
+            Filter::Regex(regex) => regex.is_match(path),
+            Filter::OlderThan(limit) => age.get_or_insert_with(|| file_age(path)).map_or(false, |age| age > *limit),
+            Filter::NewerThan(limit) => age.get_or_insert_with(|| file_age(path)).map_or(false, |age| age < *limit),
+        }
+    }
+}
+
+// Milliseconds since the file at 'path' was modified, None if that can't be read
+fn file_age(path: &str) -> Option<u64> {
+    let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok()?;
+    // Modified times in the future are age 0
+    Some(modified.elapsed().map_or(0, |d| d.as_millis() as u64))
+}
+
+impl Rule {
+    // Whether 'path' is skipped by '!skip-hidden', i.e. it or a directory it is in is hidden
+    fn in_hidden(&self, path: &str) -> bool {
+        self.walk.skip_hidden && self.any_in_path(path, is_hidden)
+    }
+
+    // Whether 'path' is skipped because symlinks aren't followed, i.e. it or a directory it is in is a symlink
+    fn via_symlink(&self, path: &str) -> bool {
+        !self.walk.follow_links && self.any_in_path(path, |p| p.symlink_metadata().map_or(false, |m| m.file_type().is_symlink()))
+    }
+
+    // Why the walk skips 'path', with everything in it if it is a directory. None if it doesn't
+    fn skipped(&self, path: &Path, is_dir: bool) -> Option<String> {
+        if self.walk.skip_hidden && is_hidden(path) {
+            return Some(SKIP_HIDDEN.to_string());
+        }
+        if is_dir { self.skipped_dir(path) } else { None }
+    }
+
+    // Why the directory 'dir' is skipped with everything in it: the CACHEDIR.TAG or marker file in it. None if it isn't
+    fn skipped_dir(&self, dir: &Path) -> Option<String> {
+        if shadow::is_mount(dir) {
+            return Some("snapshot being backed up".to_string());
+        }
+        if !self.walk.include_caches && is_cache_dir(dir) {
+            return Some(CACHEDIR_TAG.to_string());
+        }
+        self.walk.markers.iter().find(|m| dir.join(m).exists()).map(|m| format!("{} {}", EXCLUDE_IF_PRESENT, m))
+    }
+
+    // Why 'path' is in a skipped directory, see skipped_dir. None if it isn't
+    fn in_skipped_dir(&self, path: &str) -> Option<String> {
+        self.in_path(path).iter().filter(|p| p.is_dir()).find_map(|p| self.skipped_dir(p))
+    }
+
+    fn on_one_file_system(&self) -> bool {
+        self.walk.one_file_system || one_file_system()
+    }
+
+    // Whether 'path' is skipped by '!one-file-system', i.e. a directory it is in is on another file system than the rule's path
+    fn across_file_systems(&self, path: &str) -> bool {
+        let root = match device(Path::new(&self.path)) {
+            Some(d) if self.on_one_file_system() => d,
+            _ => return false,
+        };
+        self.any_in_path(path, |p| p.is_dir() && device(p).map_or(false, |d| d != root))
+    }
+
+    // Whether 'check' holds for 'path' or a directory it is in
+    fn any_in_path(&self, path: &str, check: impl Fn(&Path) -> bool) -> bool {
+        self.in_path(path).iter().any(|p| check(p))
+    }
+
+    // The directories 'path' is in, from the outermost, and 'path' itself
+    // Only the part of 'path' in the rule's path is included, as that is what is walked
+    fn in_path(&self, path: &str) -> Vec<PathBuf> {
+        let relative = match Path::new(path).strip_prefix(&self.path) {
+            Ok(relative) => relative,
+            Err(_) => return Vec::new(),
+        };
+        let mut current = PathBuf::from(&self.path);
+        relative.components().map(|component| {
+            current.push(component);
+            current.clone()
+        }).collect()
+    }
+
+    // Adds 'path' to 'files' if it is uploaded, returns 1 if it is excluded
+    fn add_file(&self, path: String, files: &mut Vec<String>) -> usize {
+        match self.decide(&path) {
+            (false, Some(filter)) => {
+                printverbose(format!("Excluded {} by '{}'", path, filter));
+                1
+            },
+            _ => {
+                files.push(path);
+                0
+            },
+        }
+    }
+
+    // Whether the listings of its directories can be cached, i.e. they are all that decides what is uploaded
+    // Age filters depend on when the scan is, and following symlinks on what they link to
+    fn cacheable(&self) -> bool {
+        let has_age_filter = match &self.filters {
+            Filters::List(filters) => filters.iter().any(|f| !matches!(f.filter, Filter::Regex(_))),
+            Filters::Gitignore(_) => false,
+        };
+        !self.walk.follow_links && !has_age_filter
+    }
+
+    // Whether 'path' is uploaded, i.e. the last filter matching it is an include filter, or none match
+    // 'path' must be in the rule's path
+    fn includes(&self, path: &str) -> bool {
+        self.decide(path).0
+    }
+
+    // Whether 'path' is uploaded, and the filter that decided it, None if no filter matches it
+    fn decide(&self, path: &str) -> (bool, Option<String>) {
+        match &self.filters {
+            Filters::List(filters) => {
+                let mut age = None;
+                match filters.iter().rev().find(|f| f.filter.is_match(path, &mut age)) {
+                    Some(f) => (f.include, Some(f.line.clone())),
+                    None => (true, None),
+                }
+            },
+            // Files in an excluded directory are excluded too, unless a pattern for the file itself re-includes it
+            Filters::Gitignore(gitignore) => match gitignore.matched_path_or_any_parents(path, false) {
+                Match::None => (true, None),
+                Match::Ignore(glob) => (false, Some(glob.original().to_string())),
+                Match::Whitelist(glob) => (true, Some(glob.original().to_string())),
+            },
+        }
+    }
+}
+
+// Whether a line of the backup list is a filter rather than a path
+fn is_filter(line: &str) -> bool {
+    line.starts_with('-') || line.starts_with('+')
+}
+
+// Parses what follows the '-' or '+' of a filter
+fn parse_filter(filter: &str) -> Result<Filter, String> {
+    if let Some(age) = filter.strip_prefix("older-than:") {
+        return datetime::parse_age(age).map(Filter::OlderThan);
+    }
+    if let Some(age) = filter.strip_prefix("newer-than:") {
+        return datetime::parse_age(age).map(Filter::NewerThan);
+    }
+    Regex::new(filter).map(Filter::Regex).map_err(|_| "Invalid RegEx".to_string())
+}
+
+// Whether a line of the backup list is a directive rather than a path
+fn is_directive(line: &str) -> bool {
+    line.starts_with('!')
+}
+
+// Applies a directive to 'options'
+fn parse_directive(line: &str, options: &mut WalkOptions) -> Result<(), String> {
+    match line {
+        FOLLOW_SYMLINKS => options.follow_links = true,
+        NO_FOLLOW => options.follow_links = false,
+        SKIP_HIDDEN => options.skip_hidden = true,
+        ONE_FILE_SYSTEM => options.one_file_system = true,
+        INCLUDE_CACHES => options.include_caches = true,
+        _ => match line.strip_prefix(EXCLUDE_IF_PRESENT).map(|name| name.trim()) {
+            Some(name) if !name.is_empty() && line.as_bytes()[EXCLUDE_IF_PRESENT.len()].is_ascii_whitespace() => {
+                options.markers.push(name.to_string())
+            },
+            Some(_) => return Err(format!("{} needs the name of the marker file, e.g. {} .nobackup", EXCLUDE_IF_PRESENT, EXCLUDE_IF_PRESENT)),
+            None => return Err(format!("Unknown directive - {}, expected one of {} or {} NAME", line, DIRECTIVES.join(", "), EXCLUDE_IF_PRESENT)),
+        },
+    }
+    Ok(())
+}
+
+// Whether the file or directory at 'path' is hidden: its name starts with a '.', or on Windows it has the Hidden attribute
+fn is_hidden(path: &Path) -> bool {
+    if path.file_name().map_or(false, |name| name.to_string_lossy().starts_with('.')) {
+        return true;
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::MetadataExt;
+        const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+        if let Ok(metadata) = std::fs::symlink_metadata(path) {
+            return metadata.file_attributes() & FILE_ATTRIBUTE_HIDDEN != 0;
+        }
+    }
+    false
+}
+
+// Whether 'dir' has a CACHEDIR.TAG with the right signature
+fn is_cache_dir(dir: &Path) -> bool {
+    let mut signature = [0; CACHEDIR_SIGNATURE.len()];
+    match std::fs::File::open(dir.join(CACHEDIR_TAG)) {
+        Ok(mut tag) => tag.read_exact(&mut signature).is_ok() && signature == CACHEDIR_SIGNATURE,
+        Err(_) => false,
+    }
+}
+
+// The file system the file at 'path' is on, None if that isn't known
+#[cfg(unix)]
+fn device(path: &Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    std::fs::metadata(path).ok().map(|m| m.dev())
+}
+
+// The serial number of the volume, as walkdir uses for same_file_system
+#[cfg(windows)]
+fn device(path: &Path) -> Option<u64> {
+    let handle = winapi_util::Handle::from_path_any(path).ok()?;
+    winapi_util::file::information(&handle).ok().map(|info| info.volume_serial_number())
+}
+
+#[cfg(not(any(unix, windows)))]
+fn device(_path: &Path) -> Option<u64> {
+    None
+}
+
+fn parse_rules(text: &str) -> Vec<Rule> {
+    if is_gitignore(text) {
+        return gitignore_sections(text).and_then(|sections| sections.into_iter()
+            // The patterns are relative to each path a glob matches
+            .flat_map(|section| expand_root(section.root).into_iter().map(move |path| {
+                let gitignore = build_gitignore(&path, &section.patterns)?;
+                Ok(Rule { path, filters: Filters::Gitignore(gitignore), walk: section.walk.clone() })
+            }))
+            .collect())
+            .unwrap_or_else(|e| panic!("{}", e));
+    }
+    let mut rules = Vec::new();
+    let mut filters = Vec::new();
+    let mut walk = WalkOptions::default();
     let mut lines = text.lines();
     let mut dir = lines.next().unwrap().trim();
-    if dir.starts_with("-") {
+    if is_filter(dir) || is_directive(dir) {
         panic!("Backup list started with a filter, not a path")
     }
 
     // Check for new filters until we encounter a path
-    // When we encounter a path, collect files for the current using discovered rules
-    // Then, reset rules and repeat
+    // When we encounter a path, finish the rule for the current path using discovered filters
+    // Then, reset filters and repeat
     // Note: we chain an empty string to make sure it adds the last entry
     for line in lines.chain(vec![""]) {
         let line = line.trim();
-        if line.starts_with("-") {
-            regex_str.push(line[1..].trim());
+        if is_filter(line) {
+            let filter = parse_filter(line[1..].trim()).unwrap_or_else(|e| panic!("{} - {}", e, line));
+            filters.push(FilterLine { line: line.to_string(), include: line.starts_with('+'), filter });
+        } else if is_directive(line) {
+            parse_directive(line, &mut walk).unwrap_or_else(|e| panic!("{}", e));
         } else {
             if dir != "" {
-                // New path encountered
-                // Construct RegEx set, recursively walk directory
-                let reg_set = RegexSet::new(&regex_str).unwrap();
-
-                regex_str.clear();
-                for entry in WalkDir::new(dir).into_iter().filter_map(|e| e.ok()) {
-                    let name = match entry.path().to_str() {
-                        Some(s) => s,
-                        None => continue,
-                    };
-                    if !reg_set.is_match(name) && entry.file_type().is_file() {
-                        files.push(name.to_string());
-                    }
+                // New path encountered, a glob adds a rule for every path it matches
+                let filters = std::mem::take(&mut filters);
+                for path in expand_root(dir) {
+                    rules.push(Rule { path, filters: Filters::List(filters.clone()), walk: walk.clone() });
                 }
+                walk = WalkOptions::default();
             }
 
             dir = line;
         }
     }
 
-    files
+    rules
+}
+
+/// The paths a path in the backup list stands for: the paths a glob currently matches, or the path itself
+pub fn expand_root(root: &str) -> Vec<String> {
+    if !is_glob(root) || Path::new(root).exists() {
+        return vec![root.to_string()];
+    }
+    match glob::glob(root) {
+        Ok(paths) => paths.filter_map(|p| p.ok()).map(|p| p.to_string_lossy().to_string()).collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// The directory to watch for changes to a path in the backup list: the path itself, or the directory a glob starts in
+pub fn watch_root(root: &str) -> String {
+    if !is_glob(root) || Path::new(root).exists() {
+        return root.to_string();
+    }
+    let base: PathBuf = Path::new(root).components()
+        .take_while(|c| !is_glob(&c.as_os_str().to_string_lossy()))
+        .collect();
+    if base.as_os_str().is_empty() {
+        ".".to_string()
+    } else {
+        base.to_string_lossy().to_string()
+    }
+}
+
+fn is_glob(root: &str) -> bool {
+    root.contains(|c| c == '*' || c == '?' || c == '[')
+}
+
+// Checks that a path in the backup list exists, or is a valid glob
+// A glob that currently matches nothing is valid, what it matches is only known when files are collected
+fn check_root(root: &str) -> Result<(), String> {
+    if Path::new(root).exists() {
+        Ok(())
+    } else if is_glob(root) {
+        glob::Pattern::new(root).map(|_| ()).map_err(|e| format!("Invalid glob - {} ({})", root, e))
+    } else {
+        Err(format!("File/Directory not found - {}", root))
+    }
+}
+
+fn is_gitignore(text: &str) -> bool {
+    text.lines().next().map_or(false, |line| line.trim() == GITIGNORE_HEADER)
+}
+
+// A path in the gitignore dialect and the lines for it
+struct Section<'a> {
+    root: &'a str,
+    patterns: Vec<&'a str>,
+    walk: WalkOptions,
+}
+
+// Splits a backup list in the gitignore dialect into its paths and the patterns for each
+fn gitignore_sections(text: &str) -> Result<Vec<Section<'_>>, String> {
+    let mut sections: Vec<Section> = Vec::new();
+    for line in text.lines().skip(1) {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        if let Some(root) = section_root(trimmed) {
+            sections.push(Section { root, patterns: Vec::new(), walk: WalkOptions::default() });
+            continue;
+        }
+        let section = match sections.last_mut() {
+            Some(section) => section,
+            None => return Err(format!("Backup list has a pattern before the first [path] - {}", trimmed)),
+        };
+        // Any other line starting with '!' is a pattern re-including files
+        if parse_directive(trimmed, &mut section.walk).is_err() {
+            section.patterns.push(line);
+        }
+    }
+    Ok(sections)
+}
+
+// The path of a '[path]' line in the gitignore dialect
+fn section_root(line: &str) -> Option<&str> {
+    if line.len() > 2 && line.starts_with('[') && line.ends_with(']') {
+        Some(line[1..line.len() - 1].trim())
+    } else {
+        None
+    }
+}
+
+// The path of a (trimmed) line that starts an entry in the backup list
+fn entry_root(line: &str, gitignore: bool) -> Option<&str> {
+    if gitignore {
+        section_root(line)
+    } else if line.is_empty() || is_filter(line) || is_directive(line) {
+        None
+    } else {
+        Some(line)
+    }
+}
+
+// The range of lines of each entry in the backup list: the line with the path and the lines under it
+fn entry_ranges(lines: &[&str], gitignore: bool) -> Vec<(usize, usize)> {
+    let starts: Vec<usize> = (0..lines.len()).filter(|i| entry_root(lines[*i].trim(), gitignore).is_some()).collect();
+    starts.iter().enumerate().map(|(n, &start)| {
+        let mut end = start + 1;
+        if gitignore {
+            // Everything up to the next path, except blank lines separating them
+            end = starts.get(n + 1).copied().unwrap_or(lines.len());
+            while end > start + 1 && lines[end - 1].trim().is_empty() {
+                end -= 1;
+            }
+        } else {
+            while end < lines.len() && (is_filter(lines[end].trim()) || is_directive(lines[end].trim())) {
+                end += 1;
+            }
+        }
+        (start, end)
+    }).collect()
+}
+
+// Finds the range of lines of the entry for 'root'
+fn find_entry(lines: &[&str], gitignore: bool, root: &str) -> Option<(usize, usize)> {
+    entry_ranges(lines, gitignore).into_iter()
+        .find(|(start, _)| entry_root(lines[*start].trim(), gitignore).map_or(false, |r| Path::new(r) == Path::new(root)))
+}
+
+// Checks that 'line' is a valid filter or directive for 'root'
+fn check_line(line: &str, root: &str, gitignore: bool) -> Result<(), String> {
+    if parse_directive(line, &mut WalkOptions::default()).is_ok() {
+        return Ok(());
+    }
+    if gitignore {
+        if line.is_empty() || line.starts_with('#') || section_root(line).is_some() {
+            return Err(format!("'{}' is not a pattern", line));
+        }
+        build_gitignore(root, &[line]).map(|_| ())
+    } else if is_filter(line) {
+        parse_filter(line[1..].trim()).map(|_| ()).map_err(|e| format!("{} - {}", e, line))
+    } else if is_directive(line) {
+        parse_directive(line, &mut WalkOptions::default())
+    } else {
+        Err(format!("'{}' is not a filter, it must start with '-' (exclude), '+' (include) or '!' (directive)", line))
+    }
+}
+
+// Builds the patterns of a path in the gitignore dialect, relative to that path
+fn build_gitignore(root: &str, patterns: &[&str]) -> Result<Gitignore, String> {
+    let mut builder = GitignoreBuilder::new(root);
+    for pattern in patterns {
+        if let Err(e) = builder.add_line(None, pattern) {
+            return Err(format!("Invalid pattern - {} ({})", pattern.trim(), e));
+        }
+    }
+    builder.build().map_err(|e| format!("Invalid patterns for {} ({})", root, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::filelist::{add_entry, build_file_list, collect_files, collect_files_cached, entries, explain, filter_included, list_roots, remove_entry, verify_structure, watch_root};
+    use crate::scancache::ScanCache;
+
+    #[test]
+    fn test_filter_included() {
+        let list = std::env::temp_dir().join("retain-test-backup-list.txt");
+        std::fs::write(&list, "/home/user/\n- target/\n- \\.txt$\n/etc/foo/config.cfg\n").unwrap();
+        let paths = vec![
+            "/home/user/documents/book.pdf",
+            "/home/user/project/target/debug/app",
+            "/home/user/notes.txt",
+            "/etc/foo/config.cfg",
+            "/etc/foo/other.cfg",
+            "/home/username/book.pdf",
+        ];
+        let included = filter_included(&list, paths.into_iter().map(|p| p.to_string()).collect()).unwrap();
+        assert_eq!(included, vec!["/home/user/documents/book.pdf", "/etc/foo/config.cfg"]);
+        std::fs::remove_file(&list).unwrap();
+    }
+
+    #[test]
+    fn test_include_filters() {
+        let list = std::env::temp_dir().join("retain-test-include-list.txt");
+        std::fs::write(&list, "/home/user/project/\n- target/\n+ target/release/[^/]*\\.bin$\n- secret\n").unwrap();
+        let paths = vec![
+            "/home/user/project/src/main.rs",
+            "/home/user/project/target/debug/app.bin",
+            "/home/user/project/target/release/app.bin",
+            "/home/user/project/target/release/deps/dep.bin",
+            "/home/user/project/target/release/secret.bin",
+        ];
+        let included = filter_included(&list, paths.into_iter().map(|p| p.to_string()).collect()).unwrap();
+        // The last matching filter wins, s.t. '- secret' excludes the file '+' included before it
+        assert_eq!(included, vec!["/home/user/project/src/main.rs", "/home/user/project/target/release/app.bin"]);
+        std::fs::remove_file(&list).unwrap();
+    }
+
+    #[test]
+    fn test_gitignore_syntax() {
+        let list = std::env::temp_dir().join("retain-test-gitignore-list.txt");
+        std::fs::write(&list, "# syntax: gitignore\n[/home/user/project/]\n# build output\ntarget/\n!target/release/*.bin\n*.txt\n/secret\n[/etc/foo/config.cfg]\n").unwrap();
+        let paths = vec![
+            "/home/user/project/src/main.rs",
+            "/home/user/project/target/debug/app.bin",
+            "/home/user/project/target/release/app.bin",
+            "/home/user/project/docs/notes.txt",
+            "/home/user/project/secret",
+            "/home/user/project/docs/secret",
+            "/etc/foo/config.cfg",
+            "/etc/foo/other.cfg",
+        ];
+        let included = filter_included(&list, paths.into_iter().map(|p| p.to_string()).collect()).unwrap();
+        // '/secret' is anchored, s.t. only the one directly in the path is excluded
+        assert_eq!(included, vec![
+            "/home/user/project/src/main.rs",
+            "/home/user/project/target/release/app.bin",
+            "/home/user/project/docs/secret",
+            "/etc/foo/config.cfg",
+        ]);
+        assert_eq!(list_roots(&list).unwrap(), vec!["/home/user/project/", "/etc/foo/config.cfg"]);
+        std::fs::remove_file(&list).unwrap();
+    }
+
+    #[test]
+    fn test_age_filters() {
+        let dir = std::env::temp_dir().join("retain-test-age-filters");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let day = std::time::Duration::from_secs(86400);
+        for (name, days) in &[("new", 1), ("month", 40), ("stale", 400)] {
+            let file = std::fs::File::create(dir.join(name)).unwrap();
+            file.set_modified(std::time::SystemTime::now() - day * *days).unwrap();
+        }
+        let paths: Vec<String> = ["new", "month", "stale"].iter().map(|n| dir.join(n).to_string_lossy().to_string()).collect();
+        let list = std::env::temp_dir().join("retain-test-age-list.txt");
+
+        std::fs::write(&list, format!("{}\n- older-than:365d\n", dir.display())).unwrap();
+        assert_eq!(filter_included(&list, paths.clone()).unwrap(), &paths[..2]);
+        // Only recent files, except the ones matched by a later filter
+        std::fs::write(&list, format!("{}\n- .*\n+ newer-than:30d\n- stale\n", dir.display())).unwrap();
+        assert_eq!(filter_included(&list, paths.clone()).unwrap(), &paths[..1]);
+        std::fs::write(&list, format!("{}\n- older-than:1y\n", dir.display())).unwrap();
+        assert!(verify_structure(&list).is_err());
+        std::fs::remove_file(&list).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_follow_symlinks() {
+        let dir = std::env::temp_dir().join("retain-test-symlinks");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("root")).unwrap();
+        std::fs::create_dir_all(dir.join("other")).unwrap();
+        std::fs::write(dir.join("root").join("file"), "").unwrap();
+        std::fs::write(dir.join("other").join("linked"), "").unwrap();
+        std::os::unix::fs::symlink(dir.join("other"), dir.join("root").join("link")).unwrap();
+        std::os::unix::fs::symlink(dir.join("root"), dir.join("root").join("loop")).unwrap();
+        let root = dir.join("root");
+        let list = dir.join("list.txt");
+
+        std::fs::write(&list, format!("{}\n", root.display())).unwrap();
+        let mut files = build_file_list(&list);
+        files.sort();
+        assert_eq!(files, vec![root.join("file").to_string_lossy()]);
+        // Both symlinks are counted as excluded
+        assert_eq!(collect_files(&list).1, 2);
+
+        // The loop is skipped instead of walked until the path is too long
+        std::fs::write(&list, format!("{}\n!follow-symlinks\n", root.display())).unwrap();
+        let mut files = build_file_list(&list);
+        files.sort();
+        assert_eq!(files, vec![root.join("file").to_string_lossy(), root.join("link").join("linked").to_string_lossy()]);
+
+        std::fs::write(&list, format!("# syntax: gitignore\n[{}]\n!follow-symlinks\n", root.display())).unwrap();
+        let mut files = build_file_list(&list);
+        files.sort();
+        assert_eq!(files, vec![root.join("file").to_string_lossy(), root.join("link").join("linked").to_string_lossy()]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_skip_hidden() {
+        let dir = std::env::temp_dir().join("retain-test-skip-hidden");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join(".cache")).unwrap();
+        std::fs::create_dir_all(dir.join("docs")).unwrap();
+        for name in &["file", ".bashrc", ".cache/data", "docs/.hidden", "docs/book.pdf"] {
+            std::fs::write(dir.join(name), "").unwrap();
+        }
+        let list = std::env::temp_dir().join("retain-test-hidden-list.txt");
+        let visible = vec![dir.join("docs").join("book.pdf").to_string_lossy().to_string(), dir.join("file").to_string_lossy().to_string()];
+
+        std::fs::write(&list, format!("{}\n!skip-hidden\n", dir.display())).unwrap();
+        let mut files = build_file_list(&list);
+        files.sort();
+        assert_eq!(files, visible);
+        let all: Vec<String> = ["file", ".bashrc", ".cache/data", "docs/.hidden", "docs/book.pdf"].iter()
+            .map(|n| dir.join(n).to_string_lossy().to_string()).collect();
+        let mut included = filter_included(&list, all).unwrap();
+        included.sort();
+        assert_eq!(included, visible);
+
+        std::fs::write(&list, format!("# syntax: gitignore\n[{}]\n!skip-hidden\n", dir.display())).unwrap();
+        let mut files = build_file_list(&list);
+        files.sort();
+        assert_eq!(files, visible);
+
+        std::fs::write(&list, format!("{}\n", dir.display())).unwrap();
+        assert_eq!(build_file_list(&list).len(), 5);
+        std::fs::remove_file(&list).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_explain() {
+        let list = std::env::temp_dir().join("retain-test-explain-list.txt");
+        std::fs::write(&list, "/home/user/\n- target/\n+ \\.bin$\n/home/\n- user/\n").unwrap();
+        let explained = explain(&list, "/home/user/target/app.bin").unwrap();
+        assert_eq!(explained.len(), 2);
+        assert_eq!((explained[0].root.as_str(), explained[0].included, explained[0].rule.as_deref()), ("/home/user/", true, Some("+ \\.bin$")));
+        assert_eq!((explained[1].root.as_str(), explained[1].included, explained[1].rule.as_deref()), ("/home/", false, Some("- user/")));
+        let explained = explain(&list, "/home/user/target/app").unwrap();
+        assert_eq!(explained[0].rule.as_deref(), Some("- target/"));
+        assert!(!explained[0].included);
+        let explained = explain(&list, "/home/user/book.pdf").unwrap();
+        assert_eq!((explained[0].included, explained[0].rule.as_deref()), (true, None));
+        assert!(explain(&list, "/etc/config").unwrap().is_empty());
+
+        std::fs::write(&list, "# syntax: gitignore\n[/home/user/]\ntarget/\n!target/*.bin\n").unwrap();
+        let explained = explain(&list, "/home/user/target/app.bin").unwrap();
+        assert_eq!((explained[0].included, explained[0].rule.as_deref()), (true, Some("!target/*.bin")));
+        let explained = explain(&list, "/home/user/target/app").unwrap();
+        assert_eq!((explained[0].included, explained[0].rule.as_deref()), (false, Some("target/")));
+        std::fs::remove_file(&list).unwrap();
+    }
+
+    #[test]
+    fn test_edit_entries() {
+        let dir = std::env::temp_dir();
+        let root = dir.to_string_lossy().to_string();
+        let list = format!("{}\n- target/\n!skip-hidden\n/missing\n", root);
+        assert_eq!(entries(&list), vec![
+            (root.clone(), vec!["- target/".to_string(), "!skip-hidden".to_string()]),
+            ("/missing".to_string(), vec![]),
+        ]);
+        let added = add_entry(&list, &root, Some("+ target/release/")).unwrap();
+        assert_eq!(added, format!("{}\n- target/\n!skip-hidden\n+ target/release/\n/missing\n", root));
+        assert!(add_entry(&added, &root, Some("+ target/release/")).is_err());
+        assert!(add_entry(&list, &root, Some("- (unclosed")).is_err());
+        assert!(add_entry(&list, &root, Some("target/")).is_err());
+        assert!(add_entry(&list, &root, None).is_err());
+        assert!(add_entry(&list, "/does/not/exist", None).is_err());
+
+        let (removed, lines) = remove_entry(&added, &root, Some("- target/")).unwrap();
+        assert_eq!(removed, format!("{}\n!skip-hidden\n+ target/release/\n/missing\n", root));
+        assert_eq!(lines, vec!["- target/"]);
+        let (removed, lines) = remove_entry(&added, &root, None).unwrap();
+        assert_eq!(removed, "/missing\n");
+        assert_eq!(lines.len(), 4);
+        assert!(remove_entry(&added, "/etc", None).is_err());
+
+        let list = format!("# syntax: gitignore\n[{}]\n# build output\ntarget/\n\n[/missing]\n", root);
+        let added = add_entry(&list, &root, Some("!target/*.bin")).unwrap();
+        assert_eq!(added, format!("# syntax: gitignore\n[{}]\n# build output\ntarget/\n!target/*.bin\n\n[/missing]\n", root));
+        assert_eq!(entries(&added)[0].1, vec!["target/", "!target/*.bin"]);
+        assert_eq!(remove_entry(&added, "/missing", None).unwrap().0, format!("# syntax: gitignore\n[{}]\n# build output\ntarget/\n!target/*.bin\n", root));
+    }
+
+    #[test]
+    fn test_glob_roots() {
+        let dir = std::env::temp_dir().join("retain-test-glob-roots");
+        let _ = std::fs::remove_dir_all(&dir);
+        for user in &["alice", "bob", "carol"] {
+            std::fs::create_dir_all(dir.join(user).join("Documents")).unwrap();
+            std::fs::write(dir.join(user).join("Documents").join("notes.txt"), "").unwrap();
+            std::fs::write(dir.join(user).join("Documents").join("book.pdf"), "").unwrap();
+        }
+        std::fs::remove_dir_all(dir.join("carol").join("Documents")).unwrap();
+        std::fs::write(dir.join("app.log"), "").unwrap();
+        let list = dir.join("list.txt");
+        let file = |path: &[&str]| path.iter().fold(dir.clone(), |p, c| p.join(c)).to_string_lossy().to_string();
+
+        std::fs::write(&list, format!("{}/*/Documents/\n- \\.txt$\n{}/*.log\n", dir.display(), dir.display())).unwrap();
+        assert_eq!(verify_structure(&list), Ok(()));
+        let mut files = build_file_list(&list);
+        files.sort();
+        assert_eq!(files, vec![file(&["alice", "Documents", "book.pdf"]), file(&["app.log"]), file(&["bob", "Documents", "book.pdf"])]);
+
+        // Picked up without changing the list
+        std::fs::create_dir_all(dir.join("carol").join("Documents")).unwrap();
+        std::fs::write(dir.join("carol").join("Documents").join("book.pdf"), "").unwrap();
+        assert_eq!(build_file_list(&list).len(), 4);
+        assert_eq!(filter_included(&list, vec![file(&["carol", "Documents", "book.pdf"]), file(&["carol", "other.pdf"])]).unwrap(),
+            vec![file(&["carol", "Documents", "book.pdf"])]);
+
+        std::fs::write(&list, format!("# syntax: gitignore\n[{}/*/Documents]\n*.pdf\n", dir.display())).unwrap();
+        let mut files = build_file_list(&list);
+        files.sort();
+        assert_eq!(files, vec![file(&["alice", "Documents", "notes.txt"]), file(&["bob", "Documents", "notes.txt"])]);
+
+        assert_eq!(watch_root(&format!("{}/*/Documents/", dir.display())), dir.to_string_lossy());
+        assert_eq!(watch_root("*.log"), ".");
+
+        std::fs::write(&list, format!("{}/[unclosed\n", dir.display())).unwrap();
+        assert!(verify_structure(&list).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_one_file_system() {
+        let list = std::env::temp_dir().join("retain-test-one-fs-list.txt");
+        // /proc is its own file system
+        std::fs::write(&list, "/\n!one-file-system\n").unwrap();
+        assert_eq!(verify_structure(&list), Ok(()));
+        let explained = explain(&list, "/proc/cpuinfo").unwrap();
+        assert_eq!((explained[0].included, explained[0].rule.as_deref()), (false, Some("!one-file-system")));
+        std::fs::write(&list, "/\n").unwrap();
+        assert!(explain(&list, "/proc/cpuinfo").unwrap()[0].included);
+        std::fs::remove_file(&list).unwrap();
+    }
+
+    #[test]
+    fn test_cachedir_tag() {
+        let dir = std::env::temp_dir().join("retain-test-cachedir");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("target").join("debug")).unwrap();
+        std::fs::create_dir_all(dir.join("fake")).unwrap();
+        std::fs::write(dir.join("target").join("CACHEDIR.TAG"), "Signature: 8a477f597d28d172789f06886806bc55\n# cache\n").unwrap();
+        std::fs::write(dir.join("target").join("debug").join("app"), "").unwrap();
+        // Without the signature it is an ordinary file
+        std::fs::write(dir.join("fake").join("CACHEDIR.TAG"), "Signature: none").unwrap();
+        std::fs::write(dir.join("src.rs"), "").unwrap();
+        let list = std::env::temp_dir().join("retain-test-cachedir-list.txt");
+        let file = |path: &[&str]| path.iter().fold(dir.clone(), |p, c| p.join(c)).to_string_lossy().to_string();
+
+        std::fs::write(&list, format!("{}\n", dir.display())).unwrap();
+        let mut files = build_file_list(&list);
+        files.sort();
+        assert_eq!(files, vec![file(&["fake", "CACHEDIR.TAG"]), file(&["src.rs"])]);
+        assert_eq!(explain(&list, &file(&["target", "debug", "app"])).unwrap()[0].rule.as_deref(), Some("CACHEDIR.TAG"));
+        assert!(filter_included(&list, vec![file(&["target", "debug", "app"])]).unwrap().is_empty());
+
+        std::fs::write(&list, format!("{}\n!include-caches\n", dir.display())).unwrap();
+        assert_eq!(build_file_list(&list).len(), 4);
+        // A path in the list is backed up even if it is a cache
+        std::fs::write(&list, format!("{}\n", dir.join("target").display())).unwrap();
+        assert_eq!(build_file_list(&list).len(), 2);
+        std::fs::remove_file(&list).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_exclude_if_present() {
+        let dir = std::env::temp_dir().join("retain-test-marker");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("scratch").join("deep")).unwrap();
+        std::fs::write(dir.join("scratch").join(".nobackup"), "").unwrap();
+        std::fs::write(dir.join("scratch").join("deep").join("data"), "").unwrap();
+        std::fs::write(dir.join("keep"), "").unwrap();
+        let list = std::env::temp_dir().join("retain-test-marker-list.txt");
+        let file = |path: &[&str]| path.iter().fold(dir.clone(), |p, c| p.join(c)).to_string_lossy().to_string();
+
+        std::fs::write(&list, format!("{}\n!exclude-if-present .nobackup\n", dir.display())).unwrap();
+        assert_eq!(verify_structure(&list), Ok(()));
+        assert_eq!(build_file_list(&list), vec![file(&["keep"])]);
+        assert_eq!(explain(&list, &file(&["scratch", "deep", "data"])).unwrap()[0].rule.as_deref(), Some("!exclude-if-present .nobackup"));
+        assert!(filter_included(&list, vec![file(&["scratch", "deep", "data"])]).unwrap().is_empty());
+
+        // Other names don't mark anything
+        std::fs::write(&list, format!("{}\n!exclude-if-present .skip\n", dir.display())).unwrap();
+        assert_eq!(build_file_list(&list).len(), 3);
+        // A path in the list is backed up even if it has the marker
+        std::fs::write(&list, format!("{}\n!exclude-if-present .nobackup\n", dir.join("scratch").display())).unwrap();
+        assert_eq!(build_file_list(&list).len(), 2);
+
+        std::fs::write(&list, format!("{}\n!exclude-if-present\n", dir.display())).unwrap();
+        assert!(verify_structure(&list).is_err());
+        std::fs::write(&list, format!("{}\n!exclude-if-present.nobackup\n", dir.display())).unwrap();
+        assert!(verify_structure(&list).is_err());
+        std::fs::remove_file(&list).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_collect_files_cached() {
+        let dir = std::env::temp_dir().join("retain-test-collect-cached");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("sub").join("deep")).unwrap();
+        std::fs::create_dir_all(dir.join("scratch")).unwrap();
+        std::fs::create_dir_all(dir.join(".hidden")).unwrap();
+        std::fs::write(dir.join("a.txt"), "").unwrap();
+        std::fs::write(dir.join("b.log"), "").unwrap();
+        std::fs::write(dir.join("sub").join("deep").join("c.txt"), "").unwrap();
+        std::fs::write(dir.join("scratch").join(".nobackup"), "").unwrap();
+        std::fs::write(dir.join(".hidden").join("d.txt"), "").unwrap();
+        let list = std::env::temp_dir().join("retain-test-collect-cached-list.txt");
+        std::fs::write(&list, format!("{}\n- \\.log$\n!skip-hidden\n!exclude-if-present .nobackup\n", dir.display())).unwrap();
+        let sorted = |mut files: Vec<String>| { files.sort(); files };
+
+        let (files, excluded) = collect_files(&list);
+        let (cached, cached_excluded, cache) = collect_files_cached(&list, &ScanCache::default());
+        assert_eq!((sorted(cached), cached_excluded), (sorted(files.clone()), excluded));
+        assert_eq!((files.len(), excluded), (2, 1));
+        // The root, sub and sub/deep
+        assert_eq!(cache.len(), 3);
+
+        // The filters are applied to cached listings too
+        std::fs::write(&list, format!("{}\n- \\.txt$\n", dir.display())).unwrap();
+        let (files, excluded) = collect_files(&list);
+        let (cached, cached_excluded, _) = collect_files_cached(&list, &cache);
+        assert_eq!((sorted(cached), cached_excluded), (sorted(files), excluded));
+        std::fs::remove_file(&list).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_verify_structure() {
+        let dir = std::env::temp_dir();
+        let list = dir.join("retain-test-verify-list.txt");
+        std::fs::write(&list, format!("{}\n- target/\n\n- \\.txt$\n", dir.display())).unwrap();
+        assert_eq!(verify_structure(&list), Ok(()));
+        std::fs::write(&list, format!("{}\n- (unclosed\n", dir.display())).unwrap();
+        assert!(verify_structure(&list).is_err());
+        std::fs::write(&list, "- target/\n").unwrap();
+        assert!(verify_structure(&list).is_err());
+        std::fs::write(&list, format!("{}\n- target/\n+ target/release/\n", dir.display())).unwrap();
+        assert_eq!(verify_structure(&list), Ok(()));
+        std::fs::write(&list, "+ target/\n").unwrap();
+        assert!(verify_structure(&list).is_err());
+        std::fs::write(&list, format!("{}\n!follow-symlinks\n- target/\n", dir.display())).unwrap();
+        assert_eq!(verify_structure(&list), Ok(()));
+        std::fs::write(&list, format!("{}\n!follow\n", dir.display())).unwrap();
+        assert!(verify_structure(&list).is_err());
+        std::fs::write(&list, format!("# syntax: gitignore\n[{}]\ntarget/\n!target/release/*.bin\n", dir.display())).unwrap();
+        assert_eq!(verify_structure(&list), Ok(()));
+        std::fs::write(&list, "# syntax: gitignore\ntarget/\n").unwrap();
+        assert!(verify_structure(&list).is_err());
+        std::fs::write(&list, format!("# syntax: gitignore\n[{}]\n{{unclosed\n", dir.display())).unwrap();
+        assert!(verify_structure(&list).is_err());
+        std::fs::remove_file(&list).unwrap();
+        assert!(verify_structure(&list).is_err());
+    }
 }
@@ -0,0 +1,185 @@
+//! The files in a manifest as a directory tree, to browse and select backed up files (see subcommands/browse.rs)
+//!
+//! Paths are split on both '/' and '\', s.t. paths from any platform form a tree
+//! Marking a directory marks everything in it. The marked files are selected with as few patterns as possible:
+//! one for each marked directory whose parent isn't marked, and one for each other marked file
+
+use crate::manifest::FileEntry;
+use crate::pattern::PathPattern;
+use std::collections::HashMap;
+
+/// Index of the root, it holds the first component of every path
+pub const ROOT: usize = 0;
+
+pub struct Node {
+    // Name of the file or directory, empty for the root of Unix paths
+    pub name: String,
+    pub parent: Option<usize>,
+    // Directories first, then files, each sorted by name
+    pub children: Vec<usize>,
+    // Index of the manifest entry of a file, None for directories
+    pub entry: Option<usize>,
+    // Amount of files in the directory (1 for a file) and their total size when backed up
+    pub files: usize,
+    pub size: u64,
+    pub marked: bool,
+}
+
+pub struct FileTree {
+    pub nodes: Vec<Node>,
+}
+
+impl FileTree {
+    /// Builds the tree of the paths of 'files', entries are referred to by their index in it
+    pub fn build(files: &[FileEntry]) -> Self {
+        let mut nodes = vec![Node::new(String::new(), None, None)];
+        let mut lookup: HashMap<(usize, String), usize> = HashMap::new();
+        for (index, entry) in files.iter().enumerate() {
+            let path = entry.path.replace("\\", "/");
+            let components: Vec<&str> = path.split('/').enumerate()
+                .filter(|(n, c)| *n == 0 || !c.is_empty())
+                .map(|(_, c)| c)
+                .collect();
+            let mut parent = ROOT;
+            for (n, name) in components.iter().enumerate() {
+                let is_file = n == components.len() - 1;
+                parent = *lookup.entry((parent, name.to_string())).or_insert_with(|| {
+                    let node = nodes.len();
+                    nodes.push(Node::new(name.to_string(), Some(parent), if is_file { Some(index) } else { None }));
+                    nodes[parent].children.push(node);
+                    node
+                });
+            }
+        }
+
+        // Children are always added after their parent, so going backwards adds up each directory before its parent
+        for node in (1..nodes.len()).rev() {
+            if let Some(index) = nodes[node].entry {
+                nodes[node].files = 1;
+                nodes[node].size = files[index].size;
+            }
+            let (count, size) = (nodes[node].files, nodes[node].size);
+            let parent = nodes[node].parent.unwrap();
+            nodes[parent].files += count;
+            nodes[parent].size += size;
+        }
+        for node in 0..nodes.len() {
+            let mut children = std::mem::take(&mut nodes[node].children);
+            children.sort_by(|a, b| (nodes[*a].entry.is_some(), &nodes[*a].name).cmp(&(nodes[*b].entry.is_some(), &nodes[*b].name)));
+            nodes[node].children = children;
+        }
+        FileTree { nodes }
+    }
+
+    pub fn is_dir(&self, node: usize) -> bool {
+        self.nodes[node].entry.is_none()
+    }
+
+    /// The path of a node, with '/' as separator
+    pub fn path(&self, node: usize) -> String {
+        let mut names = Vec::new();
+        let mut current = node;
+        while let Some(parent) = self.nodes[current].parent {
+            names.push(self.nodes[current].name.as_str());
+            current = parent;
+        }
+        names.reverse();
+        names.join("/")
+    }
+
+    /// Marks or unmarks a node and everything in it
+    /// A directory is marked once everything in it is, and unmarked once anything in it is unmarked
+    pub fn set_marked(&mut self, node: usize, marked: bool) {
+        let mut stack = vec![node];
+        while let Some(n) = stack.pop() {
+            self.nodes[n].marked = marked;
+            stack.extend(self.nodes[n].children.iter().copied());
+        }
+        let mut current = node;
+        while let Some(parent) = self.nodes[current].parent {
+            let all = self.nodes[parent].children.iter().all(|c| self.nodes[*c].marked);
+            if self.nodes[parent].marked == all {
+                break;
+            }
+            self.nodes[parent].marked = all;
+            current = parent;
+        }
+    }
+
+    /// The marked nodes that aren't in a marked directory
+    pub fn selection(&self) -> Vec<usize> {
+        let mut selected = Vec::new();
+        let mut stack: Vec<usize> = self.nodes[ROOT].children.iter().rev().copied().collect();
+        while let Some(n) = stack.pop() {
+            if self.nodes[n].marked {
+                selected.push(n);
+            } else {
+                stack.extend(self.nodes[n].children.iter().rev().copied());
+            }
+        }
+        selected
+    }
+
+    /// Amount of files selected and their total size
+    pub fn selected(&self) -> (usize, u64) {
+        self.selection().iter()
+            .fold((0, 0), |(files, size), n| (files + self.nodes[*n].files, size + self.nodes[*n].size))
+    }
+
+    /// Patterns matching exactly the selected files
+    pub fn patterns(&self) -> Vec<PathPattern> {
+        self.selection().iter().map(|n| match self.is_dir(*n) {
+            true => PathPattern::within(&self.path(*n)),
+            false => PathPattern::exact(&self.path(*n)),
+        }).collect()
+    }
+}
+
+impl Node {
+    fn new(name: String, parent: Option<usize>, entry: Option<usize>) -> Self {
+        Node { name, parent, children: vec![], entry, files: 0, size: 0, marked: false }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::filetree::{FileTree, ROOT};
+    use crate::manifest::FileManifest;
+
+    #[test]
+    fn test_file_tree() {
+        let mut manifest = FileManifest::new(false);
+        for (path, size) in &[("/home/user/notes.txt", 10), ("/home/user/photos/a.jpg", 100), ("/home/user/photos/b.jpg", 200),
+                              ("/home/user/photos.txt", 1), ("C:\\Users\\user\\c.txt", 5)] {
+            manifest.get_mask(path, 0);
+            manifest.update_size(path, *size);
+        }
+        let mut tree = FileTree::build(&manifest.files);
+        assert_eq!(tree.nodes[ROOT].files, 5);
+        assert_eq!(tree.nodes[ROOT].size, 316);
+
+        let find = |tree: &FileTree, path: &str| (0..tree.nodes.len()).find(|n| tree.path(*n) == path).unwrap();
+        let user = find(&tree, "/home/user");
+        // Directories first
+        let names: Vec<&str> = tree.nodes[user].children.iter().map(|c| tree.nodes[*c].name.as_str()).collect();
+        assert_eq!(names, vec!["photos", "notes.txt", "photos.txt"]);
+        let photos = find(&tree, "/home/user/photos");
+        assert_eq!((tree.nodes[photos].files, tree.nodes[photos].size), (2, 300));
+
+        // Marking both photos marks the directory, unmarking one unmarks it again
+        tree.set_marked(find(&tree, "/home/user/photos/a.jpg"), true);
+        tree.set_marked(find(&tree, "/home/user/photos/b.jpg"), true);
+        assert!(tree.nodes[photos].marked);
+        assert_eq!(tree.selection(), vec![photos]);
+        tree.set_marked(find(&tree, "/home/user/photos/b.jpg"), false);
+        assert!(!tree.nodes[photos].marked);
+        tree.set_marked(find(&tree, "C:/Users"), true);
+        assert_eq!(tree.selected(), (2, 105));
+
+        let patterns = tree.patterns();
+        let matched: Vec<&str> = manifest.files.iter().map(|e| e.path.as_str())
+            .filter(|p| patterns.iter().any(|pattern| pattern.matches(p)))
+            .collect();
+        assert_eq!(matched, vec!["/home/user/photos/a.jpg", "C:\\Users\\user\\c.txt"]);
+    }
+}
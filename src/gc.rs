@@ -0,0 +1,154 @@
+//! Finding remote file versions that nothing refers to anymore, for 'gc'
+//!
+//! A version is referenced if an entry of the manifest, or a past version kept for a snapshot, was uploaded
+//! as it (its recorded file id or delta ids). The latest upload of every name they use is referenced too, since
+//! entries and deltas from before ids were recorded are downloaded by name. With a retention policy, the old
+//! versions of those names that it keeps are kept as well, for restoring as of a time
+//! A past version kept for a snapshot from before ids were recorded may be any upload of its names, so those are all kept
+//!
+//! Versions of manifest.json, hide markers of names in use and unfinished large files are never collected
+//!
+//! Only the local manifest decides what is referenced, so it is checked against the remote one first, see 'behind'
+
+use std::collections::{HashMap, HashSet};
+use crate::b2::FileVersion;
+use crate::manifest::{self, FileManifest};
+use crate::retention::Retention;
+
+/// The remote names used by the entries and past versions in 'manifest', including those of deltas
+pub fn referenced_names(manifest: &FileManifest) -> HashSet<String> {
+    let mut names = HashSet::new();
+    for entry in manifest.files.iter().chain(manifest.past.iter().map(|p| &p.entry)) {
+        names.insert(entry.mask.clone());
+        names.extend(entry.deltas.iter().cloned());
+    }
+    names
+}
+
+/// Why the local manifest 'local' is behind the remote manifest 'remote', e.g. if another machine uploaded since
+/// Collecting by it would delete versions that 'remote' still refers to. None if it is up to date
+pub fn behind(local: &FileManifest, remote: &FileManifest) -> Option<String> {
+    let latest = |fm: &FileManifest| fm.snapshots.last().map_or(0, |s| s.generation);
+    if latest(remote) > latest(local) {
+        return Some(format!("the remote manifest has newer snapshots (generation {}, the local one is at {})", latest(remote), latest(local)));
+    }
+    let names = referenced_names(local);
+    let mut missing: Vec<String> = referenced_names(remote).into_iter().filter(|n| !names.contains(n)).collect();
+    missing.sort();
+    missing.first().map(|name| format!("the remote manifest refers to {} names the local one doesn't, e.g. '{}'", missing.len(), name))
+}
+
+/// Returns the versions in 'versions' that neither 'manifest' nor 'retention' need
+pub fn garbage(versions: Vec<FileVersion>, manifest: &FileManifest, retention: Option<Retention>) -> Vec<FileVersion> {
+    let names = referenced_names(manifest);
+    let ids: HashSet<String> = manifest.files.iter().chain(manifest.past.iter().map(|p| &p.entry))
+        .flat_map(|e| e.file_id.iter().chain(e.delta_ids.iter()).cloned())
+        .collect();
+
+    let mut latest: HashMap<&str, &FileVersion> = HashMap::new();
+    for version in versions.iter().filter(|v| v.action == "upload") {
+        let newest = latest.entry(&version.file_name).or_insert(version);
+        if version.upload_timestamp > newest.upload_timestamp {
+            *newest = version;
+        }
+    }
+    let latest: HashSet<String> = latest.values().map(|v| v.file_id.clone()).collect();
+    let expired: Option<HashSet<String>> = retention.map(|r| r.expired(versions.clone()).into_iter().map(|v| v.file_id).collect());
+
+    let mut garbage: Vec<FileVersion> = versions.into_iter().filter(|v| {
+        if v.file_name == manifest::REMOTE_MANIFEST || (v.action != "upload" && v.action != "hide") {
+            return false;
+        }
+        if !names.contains(&v.file_name) {
+            return true;
+        }
+        let kept_by_policy = match &expired {
+            Some(expired) => !expired.contains(&v.file_id),
+            None => false,
+        };
+        let kept = ids.contains(&v.file_id) || latest.contains(&v.file_id) || kept_by_policy;
+        v.action == "upload" && !kept
+    }).collect();
+    Retention::unreferenced(&mut garbage, manifest.past.iter().map(|p| &p.entry));
+    garbage
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::gc::{behind, garbage};
+    use crate::b2::FileVersion;
+    use crate::manifest::{FileManifest, REMOTE_MANIFEST};
+    use crate::retention::Retention;
+
+    const DAY: u64 = 24*3600*1000;
+
+    fn version(name: &str, id: &str, action: &str, day: u64) -> FileVersion {
+        FileVersion {
+            file_id: id.to_string(),
+            file_name: name.to_string(),
+            action: action.to_string(),
+            content_length: 10,
+            upload_timestamp: 1577880000000 + day*DAY,
+        }
+    }
+
+    fn ids(versions: Vec<FileVersion>) -> Vec<String> {
+        let mut ids: Vec<String> = versions.into_iter().map(|v| v.file_id).collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn test_garbage() {
+        let mut fm = FileManifest::new(false);
+        fm.get_mask("/a.txt", 1);
+        fm.get_entry_mut("/a.txt").unwrap().file_id = Some("a-1".to_string());
+        fm.get_mask("/b.txt", 1);
+        fm.get_entry_mut("/b.txt").unwrap().deltas = vec!["b.txt.delta".to_string()];
+        let versions = vec![
+            version(REMOTE_MANIFEST, "m-0", "upload", 0),
+            // a.txt was uploaded as a-1, a-2 is the result of an interrupted upload
+            version("a.txt", "a-0", "upload", 0),
+            version("a.txt", "a-1", "upload", 1),
+            version("a.txt", "a-2", "upload", 2),
+            // b.txt has no recorded id, its latest version is kept
+            version("b.txt", "b-0", "upload", 0),
+            version("b.txt", "b-1", "upload", 1),
+            version("b.txt.delta", "d-0", "upload", 1),
+            // c.txt is no longer backed up
+            version("c.txt", "c-0", "upload", 0),
+            version("c.txt", "c-1", "hide", 1),
+            version("large.bin", "l-0", "start", 0),
+        ];
+        assert_eq!(ids(garbage(versions.clone(), &fm, None)), vec!["a-0", "b-0", "c-0", "c-1"]);
+
+        // The policy keeps the latest version of each day, unless the file is no longer backed up
+        let daily = Retention { daily: 7, weekly: 0, monthly: 0 };
+        assert_eq!(ids(garbage(versions.clone(), &fm, Some(daily))), vec!["c-0", "c-1"]);
+
+        // A version kept for a snapshot is referenced
+        fm.record_snapshot(1);
+        fm.get_entry_mut("/a.txt").unwrap().file_id = Some("a-2".to_string());
+        fm.get_entry_mut("/a.txt").unwrap().uploaded = 2;
+        fm.record_snapshot(2);
+        assert_eq!(ids(garbage(versions.clone(), &fm, None)), vec!["a-0", "b-0", "c-0", "c-1"]);
+
+        // b.txt changed after a snapshot, which has no id for the version it kept, so all its versions are kept
+        fm.get_entry_mut("/b.txt").unwrap().uploaded = 2;
+        fm.record_snapshot(3);
+        assert_eq!(ids(garbage(versions, &fm, None)), vec!["a-0", "c-0", "c-1"]);
+
+        // A local manifest that is behind the remote one isn't collected by, what remote refers to isn't garbage
+        let copy = |fm: &FileManifest| FileManifest::from_bytes(&fm.to_bytes().unwrap()).unwrap();
+        let remote = copy(&fm);
+        assert_eq!(behind(&fm, &remote), None);
+        let mut local = copy(&fm);
+        local.snapshots.pop();
+        assert!(behind(&local, &remote).unwrap().contains("newer snapshots"));
+        let mut remote = copy(&fm);
+        remote.get_mask("/c.txt", 1);
+        assert!(behind(&fm, &remote).unwrap().contains("'c.txt'"));
+        // Names the remote manifest no longer uses are fine, the local one may be ahead
+        assert_eq!(behind(&remote, &fm), None);
+    }
+}
@@ -0,0 +1,80 @@
+//! Hard link detection
+//!
+//! Paths that are hard links to the same file (same device and inode) share their content
+//! Only one of them is uploaded. The others are recorded in the manifest as links to it,
+//! and are recreated as hard links when restoring
+//!
+//! Only Unix exposes inodes, elsewhere every path is treated as a separate file
+
+use std::collections::HashMap;
+
+/// Finds the paths that are hard links to another path in 'paths'
+/// Returns a map of each such path to the path whose content is uploaded, which is the first of its links in sorted order
+#[cfg(unix)]
+pub fn find_links(paths: &[String]) -> HashMap<String, String> {
+    use std::os::unix::fs::MetadataExt;
+    let mut groups: HashMap<(u64, u64), Vec<&String>> = HashMap::new();
+    for path in paths {
+        match std::fs::metadata(path) {
+            Ok(m) if m.is_file() && m.nlink() > 1 => groups.entry((m.dev(), m.ino())).or_default().push(path),
+            _ => (),
+        }
+    }
+
+    let mut links = HashMap::new();
+    for (_, mut group) in groups {
+        group.sort();
+        group.dedup();
+        for path in &group[1..] {
+            links.insert(path.to_string(), group[0].to_string());
+        }
+    }
+    links
+}
+
+#[cfg(not(unix))]
+pub fn find_links(_paths: &[String]) -> HashMap<String, String> {
+    HashMap::new()
+}
+
+/// Makes 'path' a hard link to 'target', replacing it if it exists
+/// Falls back to copying if a hard link can't be made, e.g. because they are on different file systems
+pub fn restore_link(target: &str, path: &str) -> Result<(), std::io::Error> {
+    if let Some(parent) = std::path::Path::new(path).parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    if std::fs::symlink_metadata(path).is_ok() {
+        std::fs::remove_file(path)?;
+    }
+    match std::fs::hard_link(target, path) {
+        Ok(_) => Ok(()),
+        Err(_) => std::fs::copy(target, path).map(|_| ()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hardlink::{find_links, restore_link};
+
+    #[test]
+    #[cfg(unix)]
+    fn test_find_links() {
+        let dir = std::env::temp_dir().join("retain-test-hardlink");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+
+        std::fs::write(path("a"), b"content").unwrap();
+        std::fs::write(path("single"), b"content").unwrap();
+        restore_link(&path("a"), &path("b")).unwrap();
+        restore_link(&path("a"), &path("c")).unwrap();
+
+        let links = find_links(&[path("c"), path("single"), path("b"), path("a")]);
+        assert_eq!(links.len(), 2);
+        assert_eq!(links[&path("b")], path("a"));
+        assert_eq!(links[&path("c")], path("a"));
+        assert_eq!(std::fs::read(path("c")).unwrap(), b"content");
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
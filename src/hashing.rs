@@ -0,0 +1,64 @@
+//! Hashing many files at once, e.g. when rebuilding the manifest or looking for moved files
+//!
+//! Hashing is limited by the disks and CPU rather than the network, so it runs on rayon's pool, which has a thread
+//! per CPU core, instead of on the transfer workers (whose number is set for uploading). This way hashing
+//! terabytes doesn't wait for uploads, and uploads don't wait for hashing
+//! Files that can't be read are left out, the caller handles them as if they didn't match
+
+use std::collections::HashMap;
+use rayon::prelude::*;
+use crate::moves::{self, Content};
+
+/// Hex SHA1 of each of 'paths' that can be read, by path
+pub fn sha1s(paths: &[String]) -> HashMap<String, String> {
+    sha1s_where(paths, |_| true)
+}
+
+/// Like sha1s, but only for the paths 'check' holds for. It runs on the pool as well, e.g. to compare fingerprints first
+pub fn sha1s_where<F>(paths: &[String], check: F) -> HashMap<String, String> where F: Fn(&str) -> bool + Sync {
+    paths.par_iter()
+        .filter(|path| check(path))
+        .filter_map(|path| moves::hash_file(path).ok().map(|sha1| (path.clone(), sha1)))
+        .collect()
+}
+
+/// Content (SHA1 and fingerprint) of each of 'paths' that can be read, by path
+pub fn contents(paths: &[String]) -> HashMap<String, Content> {
+    paths.par_iter()
+        .filter_map(|path| Content::of_file(path).ok().map(|content| (path.clone(), content)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hashing::{contents, sha1s, sha1s_where};
+    use crate::moves::Content;
+
+    #[test]
+    fn test_hashing() {
+        let dir = std::env::temp_dir().join("retain-test-hashing");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let paths: Vec<String> = (0..20).map(|n| {
+            let path = dir.join(format!("{}.txt", n));
+            std::fs::write(&path, format!("content {}", n)).unwrap();
+            path.to_string_lossy().to_string()
+        }).collect();
+        let missing = dir.join("missing.txt").to_string_lossy().to_string();
+        let mut all = paths.clone();
+        all.push(missing.clone());
+
+        let hashes = sha1s(&all);
+        assert_eq!(hashes.len(), 20);
+        assert!(!hashes.contains_key(&missing));
+        for (n, path) in paths.iter().enumerate() {
+            assert_eq!(hashes[path], sha1::Sha1::from(format!("content {}", n)).digest().to_string());
+        }
+        assert_eq!(sha1s_where(&all, |p| p.ends_with("1.txt")).len(), 2);
+
+        let found = contents(&all);
+        assert_eq!(found.len(), 20);
+        assert_eq!(found[&paths[3]], Content::of(b"content 3"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
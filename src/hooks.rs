@@ -0,0 +1,244 @@
+//! Commands run before and after uploads, e.g. to dump a database s.t. the dump is backed up, or to report the outcome
+//!
+//! Configured in the hooks section of the config:
+//! ```toml
+//! [[hooks.before]]
+//! command = "pg_dump mydb > /var/backups/mydb.sql"
+//!
+//! [[hooks.before]]
+//! command = "systemctl stop myservice"
+//! required = false
+//!
+//! [[hooks.after]]
+//! command = "systemctl start myservice"
+//! timeout = 60
+//! ```
+//! Commands are run with sh (cmd on Windows), in order, and what they print is shown with -v
+//! Commands may hold secrets, so they are only referred to by their position (e.g. 'before hook 2') when run
+//! A hook that runs longer than its timeout (in seconds, DEFAULT_TIMEOUT if not set) is killed and counts as failed
+//! Simulated uploads (see backend/simulate.rs) don't run hooks
+//! Before hooks run before anything is scanned or snapshotted (see shadow.rs). If one that is required (the default)
+//! fails, the upload is stopped, otherwise it is only a warning. Later before hooks aren't run after a required one fails
+//!
+//! After hooks run once the upload is done, also when it stopped early (e.g. because a before hook failed)
+//! They get the summary of the run (see notify.rs) as JSON on stdin, and the main parts of it as variables:
+//! RETAIN_RUN, RETAIN_JOB (see jobs.rs, empty without one), RETAIN_SUCCESS, RETAIN_COMPLETED, RETAIN_DONE and RETAIN_FAILED (the amount of failed files)
+//! An after hook that fails is a warning, it doesn't fail the run
+
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use serde::{Serialize, Deserialize};
+use termcolor::Color;
+use crate::colorutil::{printcoln, printverbose};
+use crate::notify::Report;
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Hooks {
+    // Run before uploading. None means none
+    pub before: Option<Vec<Hook>>,
+    // Run after uploading. None means none
+    pub after: Option<Vec<Hook>>,
+}
+
+// Seconds a hook may run if it doesn't set a timeout
+pub const DEFAULT_TIMEOUT: u64 = 3600;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Hook {
+    pub command: String,
+    // Whether the upload stops if the hook fails, only for before hooks. None means it does
+    pub required: Option<bool>,
+    // Seconds the hook may run before it is killed. None means DEFAULT_TIMEOUT
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+}
+
+impl Hook {
+    pub fn new(command: &str) -> Hook {
+        Hook { command: command.to_string(), required: None, timeout: None }
+    }
+
+    fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout.unwrap_or(DEFAULT_TIMEOUT))
+    }
+}
+
+impl Hooks {
+    pub fn is_empty(&self) -> bool {
+        self.before.iter().chain(self.after.iter()).all(|hooks| hooks.is_empty())
+    }
+
+    /// Runs the before hooks of a 'run' (e.g. upload). Returns an error once a required one fails
+    pub fn run_before(&self, run: &str) -> Result<(), String> {
+        for (n, hook) in self.before.iter().flatten().enumerate() {
+            printcoln(Color::Green, format!("Running before hook {}", n+1));
+            match execute(&hook.command, &[("RETAIN_RUN", run.to_string())], None, hook.timeout()) {
+                Ok(_) => (),
+                Err(e) if hook.required.unwrap_or(true) => return Err(format!("Before hook {} failed ({})", n+1, e)),
+                Err(e) => printcoln(Color::Yellow, format!("Warning: before hook {} failed ({})", n+1, e)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the after hooks with the summary of the run. Failures are printed as warnings
+    pub fn run_after(&self, report: &Report) {
+        let hooks = match &self.after {
+            Some(hooks) if !hooks.is_empty() => hooks,
+            _ => return,
+        };
+        let json = match serde_json::to_vec(report) {
+            Ok(json) => json,
+            Err(e) => {
+                printcoln(Color::Yellow, format!("Warning: after hooks aren't run, failed to encode the summary ({})", e));
+                return;
+            },
+        };
+        let env = [
+            ("RETAIN_RUN", report.run.to_string()),
+            ("RETAIN_JOB", report.job.clone().unwrap_or_default()),
+            ("RETAIN_SUCCESS", report.success.to_string()),
+            ("RETAIN_COMPLETED", report.completed.to_string()),
+            ("RETAIN_DONE", report.done.to_string()),
+            ("RETAIN_FAILED", report.failed.len().to_string()),
+        ];
+        for (n, hook) in hooks.iter().enumerate() {
+            printcoln(Color::Green, format!("Running after hook {}", n+1));
+            if let Err(e) = execute(&hook.command, &env, Some(&json), hook.timeout()) {
+                printcoln(Color::Yellow, format!("Warning: after hook {} failed ({})", n+1, e));
+            }
+        }
+    }
+}
+
+// Runs 'command' in the shell with 'env' set and 'stdin' written to it, killing it after 'timeout'
+// Returns what it printed, or why it failed
+fn execute(command: &str, env: &[(&str, String)], stdin: Option<&[u8]>, timeout: Duration) -> Result<String, String> {
+    let mut child = shell(command)
+        .envs(env.iter().map(|(k, v)| (k, v)))
+        .stdin(if stdin.is_some() { Stdio::piped() } else { Stdio::null() })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("failed to start it ({})", e))?;
+    // The input is written and the output read on threads of their own, s.t. a hook that fills its output
+    // before reading all of its input doesn't wait on us while we wait on it
+    if let (Some(input), Some(mut pipe)) = (stdin, child.stdin.take()) {
+        let input = input.to_vec();
+        // A hook that doesn't read its input closes the pipe early, which isn't an error
+        std::thread::spawn(move || pipe.write_all(&input));
+    }
+    let (stdout, stderr) = (read_all(child.stdout.take()), read_all(child.stderr.take()));
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        match child.try_wait().map_err(|e| e.to_string())? {
+            Some(status) => break status,
+            None if Instant::now() >= deadline => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(format!("timed out after {} seconds", timeout.as_secs()));
+            },
+            None => std::thread::sleep(Duration::from_millis(50)),
+        }
+    };
+    let output = |reader: JoinHandle<Vec<u8>>| reader.join().map(|bytes| String::from_utf8_lossy(&bytes).to_string()).unwrap_or_default();
+    let stdout = output(stdout).trim_end().to_string();
+    for line in stdout.lines() {
+        printverbose(format!("\t{}", line));
+    }
+    match status.success() {
+        true => Ok(stdout),
+        false => {
+            let stderr = output(stderr).trim().to_string();
+            match status.code() {
+                Some(code) if stderr.is_empty() => Err(format!("exit code {}", code)),
+                Some(code) => Err(format!("exit code {}: {}", code, stderr)),
+                None => Err("killed by a signal".to_string()),
+            }
+        },
+    }
+}
+
+// Reads all of 'pipe' on a thread
+fn read_all<R: Read + Send + 'static>(pipe: Option<R>) -> JoinHandle<Vec<u8>> {
+    std::thread::spawn(move || {
+        let mut bytes = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut bytes);
+        }
+        bytes
+    })
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.args(["/C", command]);
+    shell
+}
+
+#[cfg(not(windows))]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.args(["-c", command]);
+    shell
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use crate::hooks::{execute, Hooks, Hook};
+    use std::time::Duration;
+    use crate::notify::Report;
+
+    #[test]
+    fn test_hooks() {
+        let out = std::env::temp_dir().join("retain-test-hooks");
+        let _ = std::fs::remove_file(&out);
+        let mut optional = Hook::new("exit 3");
+        optional.required = Some(false);
+        let hooks = Hooks {
+            before: Some(vec![Hook::new(&format!("echo $RETAIN_RUN > {}", out.display())), optional]),
+            after: None,
+        };
+        assert_eq!(hooks.run_before("upload"), Ok(()));
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "upload\n");
+
+        let never = std::env::temp_dir().join("retain-test-hooks-never");
+        let failing = Hooks {
+            before: Some(vec![Hook::new("echo oops >&2; exit 2"), Hook::new(&format!("touch {}", never.display()))]),
+            after: None,
+        };
+        let err = failing.run_before("upload").unwrap_err();
+        assert!(err.contains("exit code 2: oops"), "{}", err);
+        assert!(!never.exists());
+
+        let report = Report {
+            run: "upload", success: false, completed: true, bucket: None, job: None, scanned: 3, excluded: 0, unchanged: 1, done: 1,
+            failed: vec!["/a".to_string()], bytes: Some(10), seconds: 1, nonces: 0, tracked: None,
+        };
+        let after = Hooks {
+            before: None,
+            after: Some(vec![Hook::new(&format!("echo $RETAIN_SUCCESS $RETAIN_FAILED > {0}; cat >> {0}", out.display()))]),
+        };
+        after.run_after(&report);
+        let written = std::fs::read_to_string(&out).unwrap();
+        assert!(written.starts_with("false 1\n{\"run\":\"upload\""), "{}", written);
+        std::fs::remove_file(&out).unwrap();
+    }
+
+    #[test]
+    fn test_hook_limits() {
+        // More input than a pipe holds, to a hook that prints before reading it
+        let input = vec![b'x'; 1 << 20];
+        let printed = execute("head -c 200000 /dev/zero; wc -c", &[], Some(&input), Duration::from_secs(30)).unwrap();
+        assert!(printed.ends_with("1048576"), "{}", &printed[printed.len().saturating_sub(20)..]);
+
+        let mut slow = Hook::new("sleep 5");
+        slow.timeout = Some(1);
+        let hooks = Hooks { before: Some(vec![slow]), after: None };
+        let err = hooks.run_before("upload").unwrap_err();
+        assert_eq!(err, "Before hook 1 failed (timed out after 1 seconds)");
+    }
+}
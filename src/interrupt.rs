@@ -0,0 +1,74 @@
+//! Ctrl-C handling
+//!
+//! ctrlc only allows setting a handler once per process, but e.g. 'watch' runs many uploads in one process
+//! The handler is set once, and forwards interrupts to whichever run subscribed last
+//! If nothing is listening, the process exits immediately, like it would without a handler
+//! A run that was interrupted stops cooperatively (see transfer::Cancel), after which the process exits with EXIT_CODE
+//! Pressing Ctrl-C again exits right away, after saving what the run set to be saved with on_exit
+
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Mutex, Once};
+use std::sync::atomic::{AtomicBool, Ordering};
+use crate::shadow;
+
+/// Exit code of a process that was interrupted, like that of a shell command stopped by SIGINT
+pub const EXIT_CODE: i32 = 130;
+
+static SUBSCRIBER: Mutex<Option<Sender<u32>>> = Mutex::new(None);
+static SET_HANDLER: Once = Once::new();
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static ON_EXIT: Mutex<Option<Box<dyn Fn() + Send>>> = Mutex::new(None);
+
+/// Saves the progress of a run when Ctrl-C exits the process, until dropped
+pub struct ExitHook(());
+
+impl Drop for ExitHook {
+    fn drop(&mut self) {
+        ON_EXIT.lock().unwrap().take();
+    }
+}
+
+/// Whether Ctrl-C was pressed during a run, which stopped early
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Handles Ctrl-C from now on, exiting the process (see exit) while nothing is subscribed
+pub fn set_handler() {
+    SET_HANDLER.call_once(|| {
+        ctrlc::set_handler(|| {
+            INTERRUPTED.store(true, Ordering::SeqCst);
+            let delivered = match SUBSCRIBER.lock().unwrap().as_ref() {
+                Some(tx) => tx.send(1).is_ok(),
+                None => false,
+            };
+            if !delivered {
+                exit();
+            }
+        }).expect("Failed to set Ctrl-C handler!");
+    });
+}
+
+/// Exits the process with EXIT_CODE, running the hook set by on_exit and removing the snapshots that are read from first
+pub fn exit() -> ! {
+    if let Some(save) = ON_EXIT.lock().unwrap().take() {
+        save();
+    }
+    shadow::remove_active();
+    std::process::exit(EXIT_CODE);
+}
+
+/// Runs 'save' if Ctrl-C exits the process before the returned hook is dropped, replacing the one set before
+pub fn on_exit<F: Fn() + Send + 'static>(save: F) -> ExitHook {
+    *ON_EXIT.lock().unwrap() = Some(Box::new(save));
+    ExitHook(())
+}
+
+/// Returns a receiver that gets a message when Ctrl-C is pressed
+/// Any receiver returned by a previous call stops receiving interrupts
+pub fn subscribe() -> Receiver<u32> {
+    set_handler();
+    let (tx, rx) = mpsc::channel();
+    *SUBSCRIBER.lock().unwrap() = Some(tx);
+    rx
+}
@@ -0,0 +1,177 @@
+//! Several backups in one config, e.g. photos to one bucket once a day and documents to another every hour
+//!
+//! Configured in the jobs section of the config:
+//! ```toml
+//! [[jobs]]
+//! name = "photos"
+//! backup_list = "/home/me/photos.txt"
+//! bucket_name = "photos"
+//! interval = 1440
+//! ```
+//! A job is run with --job NAME, e.g. 'backup upload --job photos'. '--job all' uploads the backup list of the config
+//! and then every job, 'daemon --job all' does so on the interval of each job
+//! A job has its own backup list and local manifest (in 'jobs/NAME' in the manifest directory, unless it sets one),
+//! and may have its own bucket. Everything else (credentials, keys, limits, hooks...) is that of the config
+//!
+//! Jobs sharing a bucket are kept apart by storing the files of each under '.jobs/NAME/' (see backend/job.rs),
+//! which the backup list of the config leaves alone. This way 'clean' of one never removes the files of another
+//! Jobs may run at the same time, e.g. on their own timers. They allocate nonces from the shared config under a lock
+//! (see lockfile.rs), s.t. no two of them encrypt with the same nonces
+
+use serde::{Serialize, Deserialize};
+use termcolor::Color;
+use crate::colorutil::printcoln;
+use crate::config::Config;
+use crate::{interrupt, manifest, paths};
+
+// Value of --job that runs every job
+pub const ALL: &str = "all";
+
+// Names of the files of jobs in the bucket start with this, followed by the name of the job
+pub const PREFIX: &str = ".jobs/";
+
+// Sub-directory of the manifest directory the local manifests of jobs are kept in
+const JOBS_DIR: &str = "jobs";
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Job {
+    pub name: String,
+    // Path to the backup list of the job
+    pub backup_list: String,
+    // Bucket the job uploads to. None means the bucket of the config
+    pub bucket_name: Option<String>,
+    // Directory the local manifest of the job is kept in. None means 'jobs/NAME' in the manifest directory of the config
+    pub manifest_dir: Option<String>,
+    // Minutes between uploads of the job by 'daemon'. None means the interval of the daemon
+    pub interval: Option<u64>,
+}
+
+impl Job {
+    pub fn new(name: &str, backup_list: &str) -> Job {
+        Job {
+            name: name.to_string(),
+            backup_list: backup_list.to_string(),
+            bucket_name: None,
+            manifest_dir: None,
+            interval: None,
+        }
+    }
+}
+
+/// Checks that 'name' can name a job. It becomes part of paths and file names, so only letters, digits, '-' and '_' are allowed
+pub fn check_name(name: &str) -> Result<(), String> {
+    if name.eq_ignore_ascii_case(ALL) {
+        return Err(format!("'{}' runs every job, it can't name one", ALL));
+    }
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(format!("'{}' can't name a job, use letters, digits, '-' and '_'", name));
+    }
+    Ok(())
+}
+
+/// What the names of the files of job 'name' start with in the bucket
+pub fn prefix(name: &str) -> String {
+    format!("{}{}/", PREFIX, name)
+}
+
+/// Name in the bucket of the file named 'name' by the job the config runs, if any
+pub fn remote_name(config: &Config, name: &str) -> String {
+    match &config.job {
+        Some(job) => format!("{}{}", prefix(job), name),
+        None => name.to_string(),
+    }
+}
+
+/// Directory the local manifest of job 'name' is kept in, if the job doesn't set one
+pub fn default_manifest_dir(manifest_dir: &str, name: &str) -> String {
+    paths::in_dir(&paths::in_dir(manifest_dir, JOBS_DIR), name)
+}
+
+/// Runs job 'name' (the backup list of the config if None) from now on, keeping the local manifest in its directory
+pub fn enter(config: &mut Config, name: Option<&str>) -> Result<(), String> {
+    config.apply_job(name)?;
+    match &config.simulate {
+        Some(simulate) => {
+            let dir = simulate.manifest_dir().to_string_lossy().to_string();
+            match name {
+                Some(name) => manifest::set_dir(&default_manifest_dir(&dir, name)),
+                None => manifest::set_dir(&dir),
+            }
+            simulate.prepare()
+        },
+        None => {
+            manifest::set_dir(config.manifest_dir.as_deref().unwrap_or(""));
+            Ok(())
+        },
+    }
+}
+
+/// What '--job all' runs: None for the backup list of the config if it has one, then the name of each job
+pub fn scopes(config: &Config) -> Vec<Option<String>> {
+    let main = config.backup_list.as_ref().map(|_| None);
+    main.into_iter().chain(config.jobs.iter().flatten().map(|job| Some(job.name.clone()))).collect()
+}
+
+/// Calls 'run' for each of 'names' (see scopes), stopping once interrupted
+/// Afterwards the config runs its own backup list again
+pub fn for_each<F: FnMut(&mut Config)>(config: &mut Config, names: Vec<Option<String>>, mut run: F) {
+    for name in names {
+        if interrupt::interrupted() {
+            break;
+        }
+        match &name {
+            Some(name) => printcoln(Color::Green, format!("Job {}", name)),
+            None => printcoln(Color::Green, "Backup list of the config"),
+        }
+        match enter(config, name.as_deref()) {
+            Ok(_) => run(config),
+            Err(e) => printcoln(Color::Red, e),
+        }
+    }
+    if let Err(e) = enter(config, None) {
+        printcoln(Color::Red, e);
+    }
+}
+
+/// Minutes between uploads of job 'name' by 'daemon', if it sets them
+pub fn interval(config: &Config, name: Option<&str>) -> Option<u64> {
+    config.jobs.iter().flatten().find(|job| Some(job.name.as_str()) == name).and_then(|job| job.interval)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::jobs::{check_name, remote_name, scopes, Job};
+
+    #[test]
+    fn test_jobs() {
+        assert!(check_name("photos_2").is_ok());
+        assert!(check_name("All").is_err());
+        assert!(check_name("../etc").is_err());
+        assert!(check_name("").is_err());
+
+        let mut photos = Job::new("photos", "/photos.txt");
+        photos.bucket_name = Some("photo-bucket".to_string());
+        let mut config = Config::default();
+        config.backup_list = Some("/list.txt".to_string());
+        config.bucket_name = Some("bucket".to_string());
+        config.manifest_dir = Some("/data".to_string());
+        config.jobs = Some(vec![photos, Job::new("docs", "/docs.txt")]);
+        assert_eq!(scopes(&config), vec![None, Some("photos".to_string()), Some("docs".to_string())]);
+        assert!(config.apply_job(Some("music")).is_err());
+
+        config.apply_job(Some("photos")).unwrap();
+        assert_eq!(config.backup_list.as_deref(), Some("/photos.txt"));
+        assert_eq!(config.bucket_name.as_deref(), Some("photo-bucket"));
+        assert_eq!(remote_name(&config, "manifest.json"), ".jobs/photos/manifest.json");
+        // Switching jobs starts from the config's own values
+        config.apply_job(Some("docs")).unwrap();
+        assert_eq!(config.bucket_name.as_deref(), Some("bucket"));
+        assert_eq!(config.manifest_dir, Some(std::path::Path::new("/data/jobs/docs").to_string_lossy().to_string()));
+
+        config.apply_job(None).unwrap();
+        assert_eq!(config.backup_list.as_deref(), Some("/list.txt"));
+        assert_eq!(config.manifest_dir.as_deref(), Some("/data"));
+        assert_eq!(remote_name(&config, "manifest.json"), "manifest.json");
+    }
+}
@@ -0,0 +1,149 @@
+//! A journal of the uploads completed since the local manifest was last saved, s.t. a crash doesn't lose them
+//!
+//! While uploading, the manifest is only saved every MANIFEST_SYNC_INTERVAL. Each completed upload is appended to
+//! the journal right away instead, as a line of JSON. Loading the local manifest replays the journal into it
+//! (see FileManifest::from_file), and saving it empties the journal, since the manifest then holds all of it
+//! A line cut short by the crash is skipped, the upload it records is done again
+//! When manifests are authenticated, each line ends with a tab and the tag of the record (see manifest::record_tag)
+//! Records without a valid tag are skipped as well, s.t. the journal can't change a manifest that can't be changed
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use serde::{Serialize, Deserialize};
+use termcolor::Color;
+use crate::colorutil::printcoln;
+use crate::manifest::{self, FileEntry, FileManifest};
+
+/// A change to the manifest
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Record {
+    // The entry of a file that was uploaded (or recognized as moved), replacing any entry with its path
+    Uploaded(Box<FileEntry>),
+    // The path of an entry that was removed, e.g. the old path of a moved file
+    Removed(String),
+}
+
+pub struct Journal {
+    file: File,
+}
+
+impl Journal {
+    /// Opens the journal at 'path' for appending, creating it if needed
+    pub fn open<T: AsRef<Path>>(path: T) -> std::io::Result<Journal> {
+        Ok(Journal { file: OpenOptions::new().create(true).append(true).open(path)? })
+    }
+
+    /// Appends 'record' in a single write, s.t. it is on disk once this returns even if the process then crashes
+    pub fn append(&mut self, record: &Record) -> std::io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        // Tabs in strings are escaped in JSON, so the tag is the part after the last one
+        if let Some(tag) = manifest::record_tag(&line) {
+            line.push(b'\t');
+            line.extend(tag.as_bytes());
+        }
+        line.push(b'\n');
+        self.file.write_all(&line)
+    }
+}
+
+/// Applies the records in the journal at 'path' to 'manifest', in order. Returns how many there were
+/// Records that fail authentication are skipped, see manifest::record_authentic
+pub fn replay<T: AsRef<Path>>(path: T, manifest: &mut FileManifest) -> usize {
+    replay_with(path, manifest, manifest::record_authentic)
+}
+
+// Like replay, applying only the records 'authentic' accepts, given the record and its tag
+fn replay_with<T: AsRef<Path>, F: Fn(&[u8], Option<&str>) -> bool>(path: T, manifest: &mut FileManifest, authentic: F) -> usize {
+    let file = match File::open(path) {
+        Ok(f) => f,
+        Err(_) => return 0,
+    };
+    let (mut count, mut rejected) = (0, 0);
+    for line in BufReader::new(file).lines().map_while(Result::ok) {
+        let (record, tag) = match line.rsplit_once('\t') {
+            Some((record, tag)) => (record, Some(tag)),
+            None => (line.as_str(), None),
+        };
+        if !authentic(record.as_bytes(), tag) {
+            rejected += 1;
+            continue;
+        }
+        match serde_json::from_str(record) {
+            Ok(Record::Uploaded(entry)) => manifest.put_entry(*entry),
+            Ok(Record::Removed(path)) => manifest.remove_path(path),
+            Err(_) => continue,
+        }
+        count += 1;
+    }
+    if rejected > 0 {
+        printcoln(Color::Yellow, format!("Warning: skipped {} records of the journal that failed authentication, those files are uploaded again", rejected));
+    }
+    count
+}
+
+/// Empties the journal at 'path'. It is truncated rather than removed, s.t. a journal that is open keeps appending to it
+pub fn clear<T: AsRef<Path>>(path: T) -> std::io::Result<()> {
+    match OpenOptions::new().write(true).open(path) {
+        Ok(file) => file.set_len(0),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::journal::{clear, replay, replay_with, Journal, Record};
+    use crate::manifest::FileManifest;
+    use std::io::Write;
+
+    #[test]
+    fn test_journal() {
+        let path = std::env::temp_dir().join("retain-test-journal");
+        let _ = std::fs::remove_file(&path);
+        let mut fm = FileManifest::new(false);
+        assert_eq!(replay(&path, &mut fm), 0);
+
+        let mut uploaded = FileManifest::new(false);
+        uploaded.get_mask("/a.txt", 1);
+        uploaded.get_mask("/b.txt", 2);
+        uploaded.get_entry_mut("/b.txt").unwrap().size = 7;
+        fm.get_mask("/old.txt", 1);
+
+        let mut journal = Journal::open(&path).unwrap();
+        journal.append(&Record::Uploaded(Box::new(uploaded.get_entry("/b.txt").unwrap().clone()))).unwrap();
+        journal.append(&Record::Uploaded(Box::new(uploaded.get_entry("/a.txt").unwrap().clone()))).unwrap();
+        journal.append(&Record::Removed("/old.txt".to_string())).unwrap();
+        // Cut short by a crash
+        std::fs::OpenOptions::new().append(true).open(&path).unwrap().write_all(b"{\"Uploaded\":{\"pa").unwrap();
+
+        assert_eq!(replay(&path, &mut fm), 3);
+        let paths: Vec<&str> = fm.files.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(paths, vec!["/a.txt", "/b.txt"]);
+        assert_eq!(fm.get_entry("/b.txt").unwrap().size, 7);
+
+        // Still appends to the journal once it is cleared
+        clear(&path).unwrap();
+        assert_eq!(replay(&path, &mut FileManifest::new(false)), 0);
+        journal.append(&Record::Removed("/a.txt".to_string())).unwrap();
+        assert_eq!(replay(&path, &mut fm), 1);
+        assert_eq!(fm.files.len(), 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_journal_authentication() {
+        let path = std::env::temp_dir().join("retain-test-journal-auth");
+        std::fs::write(&path, "{\"Removed\":\"/a.txt\"}\tgood\n{\"Removed\":\"/b.txt\"}\tbad\n{\"Removed\":\"/c.txt\"}\n").unwrap();
+        let mut fm = FileManifest::new(false);
+        for path in ["/a.txt", "/b.txt", "/c.txt"] {
+            fm.get_mask(path, 1);
+        }
+        // Only the record with a valid tag is applied
+        let authentic = |record: &[u8], tag: Option<&str>| record.starts_with(b"{") && tag == Some("good");
+        assert_eq!(replay_with(&path, &mut fm, authentic), 1);
+        let paths: Vec<&str> = fm.files.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(paths, vec!["/b.txt", "/c.txt"]);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
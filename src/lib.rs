@@ -0,0 +1,82 @@
+//! The backup engine of retain-rs, usable without its command line interface
+//!
+//! The retain-rs binary (main.rs) only parses the command line and calls into this crate
+//! Other tools can do the same, or use the parts they need:
+//!
+//! * config: the configuration of a backup (credentials, bucket, backup list, encryption), loaded with `Config::from_file`
+//! * filelist: parsing the backup list and walking it for the files to back up (`build_file_list`)
+//! * manifest: the record of every backed up file, kept locally and in the bucket (`FileManifest`)
+//! * encryption: the keys, readers and writers used to encrypt files before they are uploaded
+//! * backend: the storage files are uploaded to, B2 or S3-compatible storage (`backend::connect`)
+//! * b2 and versions: B2 API calls beyond what raze provides, e.g. listing file versions
+//! * subcommands: the operations of the command line
+//!
+//! Uploads and downloads are started with `subcommands::backup::upload::start` and `subcommands::backup::download::start`,
+//! which take their options as `UploadOptions` and `DownloadOptions` and fail if they can't start. The other subcommands
+//! take the arguments of the command line (clap's `ArgMatches`) and are meant for it
+//!
+//! Output is printed through colorutil, which also logs it (see logging). Call `colorutil::set_verbosity` first
+//! to print less or more. Like the command line, the operations print what they do rather than returning it,
+//! a summary of each upload, download and clean is available through summary, notify and metrics
+//!
+//! ```no_run
+//! use retain_core::config::Config;
+//! use retain_core::filelist;
+//! use retain_core::subcommands::backup::upload::{self, UploadOptions};
+//!
+//! let mut config = Config::from_file("retain.toml")?;
+//! let files = filelist::build_file_list(config.backup_list.as_ref().unwrap());
+//! println!("{} files are backed up", files.len());
+//! upload::start(&mut config, &UploadOptions { threads: Some(4), ..UploadOptions::default() })?;
+//! # Ok::<(), String>(())
+//! ```
+
+pub mod colorutil;
+pub mod config;
+pub mod subcommands;
+pub mod filelist;
+pub mod scancache;
+pub mod pending;
+pub mod encryption;
+pub mod manifest;
+pub mod journal;
+#[cfg(feature = "sqlite")]
+pub mod manifest_db;
+pub mod b2;
+pub mod backend;
+pub mod throttle;
+pub mod progress;
+pub mod retry;
+pub mod delta;
+pub mod compression;
+pub mod budget;
+pub mod egress;
+pub mod capabilities;
+pub mod pattern;
+pub mod filetree;
+pub mod datetime;
+pub mod versions;
+pub mod retention;
+pub mod gc;
+pub mod lifecycle;
+pub mod interrupt;
+pub mod hardlink;
+pub mod moves;
+pub mod hashing;
+pub mod rebuild;
+pub mod sparse;
+pub mod xattrs;
+pub mod winpath;
+pub mod shadow;
+pub mod secrets;
+pub mod credentials;
+pub mod paths;
+pub mod logging;
+pub mod summary;
+pub mod notify;
+pub mod hooks;
+pub mod jobs;
+pub mod lockfile;
+pub mod noecho;
+pub mod metrics;
+pub mod transfer;
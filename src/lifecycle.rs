@@ -0,0 +1,110 @@
+//! Lifecycle rules of the bucket, which have B2 delete hidden file versions after some days
+//!
+//! 'clean hide' hides files instead of deleting them, and uploading a modified file hides its previous version
+//! Hidden versions are billed like any other until they are deleted. A lifecycle rule deletes them once they have
+//! been hidden for a number of days, s.t. they don't accumulate cost forever
+//!
+//! Only the rule for the whole bucket (an empty prefix) is managed here, other rules are left as they are
+//! The rule must not delete what 'clean' keeps: with a retention policy, old versions are kept for up to its
+//! longest period. Without one, hidden versions are only there to undo a mistake, e.g. cleaning with the wrong backup list
+
+use crate::b2::LifecycleRule;
+use crate::retention::Retention;
+
+// Days hidden versions are kept without a retention policy
+pub const DEFAULT_HIDDEN_DAYS: u32 = 30;
+
+/// The least amount of days hidden versions must be kept for the retention policy, 0 if there is none
+/// A version kept for a month is only hidden once the next month has a version, so a month counts as 31 days
+pub fn minimum_days(retention: Option<Retention>) -> u32 {
+    match retention {
+        Some(r) => r.daily.max(r.weekly * 7).max(r.monthly * 31),
+        None => 0,
+    }
+}
+
+/// The amount of days to keep hidden versions for the retention policy, at least a day
+pub fn recommended_days(retention: Option<Retention>) -> u32 {
+    match retention {
+        Some(_) => minimum_days(retention).max(1),
+        None => DEFAULT_HIDDEN_DAYS,
+    }
+}
+
+/// The rule for the whole bucket, if there is one
+pub fn bucket_rule(rules: &[LifecycleRule]) -> Option<&LifecycleRule> {
+    rules.iter().find(|r| r.file_name_prefix.is_empty())
+}
+
+/// Returns 'rules' with hidden versions in the whole bucket deleted after 'days', or never if None
+/// Other rules, and whether the rule for the whole bucket hides files after uploading them, are kept
+pub fn with_hidden_days(mut rules: Vec<LifecycleRule>, days: Option<u32>) -> Vec<LifecycleRule> {
+    match rules.iter_mut().find(|r| r.file_name_prefix.is_empty()) {
+        Some(rule) => rule.days_from_hiding_to_deleting = days,
+        None => rules.push(LifecycleRule {
+            file_name_prefix: String::new(),
+            days_from_hiding_to_deleting: days,
+            days_from_uploading_to_hiding: None,
+        }),
+    }
+    // A rule that does nothing is removed
+    rules.retain(|r| r.days_from_hiding_to_deleting.is_some() || r.days_from_uploading_to_hiding.is_some());
+    rules
+}
+
+/// Describes what a rule does, e.g. "hidden versions are deleted after 30 days"
+pub fn describe(rule: &LifecycleRule) -> String {
+    let mut parts = vec![];
+    if let Some(days) = rule.days_from_uploading_to_hiding {
+        parts.push(format!("files are hidden {} days after uploading them", days));
+    }
+    if let Some(days) = rule.days_from_hiding_to_deleting {
+        parts.push(format!("hidden versions are deleted after {} days", days));
+    }
+    match rule.file_name_prefix.is_empty() {
+        true => format!("Whole bucket: {}", parts.join(", ")),
+        false => format!("Files starting with '{}': {}", rule.file_name_prefix, parts.join(", ")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::b2::LifecycleRule;
+    use crate::lifecycle::{bucket_rule, minimum_days, recommended_days, with_hidden_days, DEFAULT_HIDDEN_DAYS};
+    use crate::retention::Retention;
+
+    fn rule(prefix: &str, hiding_to_deleting: Option<u32>, uploading_to_hiding: Option<u32>) -> LifecycleRule {
+        LifecycleRule {
+            file_name_prefix: prefix.to_string(),
+            days_from_hiding_to_deleting: hiding_to_deleting,
+            days_from_uploading_to_hiding: uploading_to_hiding,
+        }
+    }
+
+    #[test]
+    fn test_recommended_days() {
+        assert_eq!(recommended_days(None), DEFAULT_HIDDEN_DAYS);
+        assert_eq!(minimum_days(None), 0);
+        // 12 months outlast 7 days and 4 weeks
+        assert_eq!(minimum_days(Some(Retention::default())), 12*31);
+        assert_eq!(minimum_days(Some(Retention { daily: 14, weekly: 1, monthly: 0 })), 14);
+        // Keeping only the latest version still keeps hidden versions for a day
+        assert_eq!(recommended_days(Some(Retention { daily: 0, weekly: 0, monthly: 0 })), 1);
+    }
+
+    #[test]
+    fn test_with_hidden_days() {
+        let other = rule("logs/", Some(1), None);
+        let rules = with_hidden_days(vec![other.clone()], Some(30));
+        assert_eq!(rules, vec![other.clone(), rule("", Some(30), None)]);
+        assert_eq!(bucket_rule(&rules), Some(&rule("", Some(30), None)));
+
+        // Hiding after uploading is kept when the days change or are removed
+        let rules = with_hidden_days(vec![rule("", Some(30), Some(100)), other.clone()], Some(60));
+        assert_eq!(rules, vec![rule("", Some(60), Some(100)), other.clone()]);
+        assert_eq!(with_hidden_days(rules, None), vec![rule("", None, Some(100)), other.clone()]);
+
+        // A rule left doing nothing is removed
+        assert_eq!(with_hidden_days(vec![rule("", Some(30), None), other.clone()], None), vec![other]);
+    }
+}
@@ -0,0 +1,43 @@
+//! Locks held across processes, e.g. by runs of several jobs (see jobs.rs) that share a config
+//!
+//! A lock is a file next to what it protects, locked with flock on Unix and opened without sharing on Windows
+//! The OS releases it when the process exits, so a crashed run never leaves it locked
+
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+
+/// Held until dropped
+pub struct LockFile {
+    _file: File,
+}
+
+impl LockFile {
+    /// Locks the file at 'path', creating it if needed. Waits while another process holds it
+    pub fn acquire<T: AsRef<Path>>(path: T) -> std::io::Result<LockFile> {
+        Ok(LockFile { _file: lock(path.as_ref())? })
+    }
+}
+
+#[cfg(unix)]
+fn lock(path: &Path) -> std::io::Result<File> {
+    use std::os::unix::io::AsRawFd;
+    let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+    // Locks are released when the file is closed
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(file)
+}
+
+#[cfg(windows)]
+fn lock(path: &Path) -> std::io::Result<File> {
+    use std::os::windows::fs::OpenOptionsExt;
+    // Opening a file without sharing fails with ERROR_SHARING_VIOLATION while another process has it open
+    const ERROR_SHARING_VIOLATION: i32 = 32;
+    loop {
+        match OpenOptions::new().read(true).write(true).create(true).truncate(false).share_mode(0).open(path) {
+            Err(e) if e.raw_os_error() == Some(ERROR_SHARING_VIOLATION) => std::thread::sleep(std::time::Duration::from_millis(50)),
+            result => return result,
+        }
+    }
+}
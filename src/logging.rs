@@ -0,0 +1,200 @@
+//! Log file of what each run did, s.t. unattended runs (e.g. from cron or the daemon) leave an audit trail
+//!
+//! Messages printed with colorutil are also emitted as tracing events: red ones as errors, yellow ones as warnings and the rest as info
+//! Without a log file nothing collects the events, and the console output is all there is
+//! With one ('config --log_file'), every event is written to it with a timestamp and its level
+//! Per-file decisions (-v) and B2 requests (-vv) are only logged when they are printed, the rest is logged even with -q
+//!
+//! How the log file is rotated ('config --log_rotation'):
+//! never: every run is appended to the file, which is the default
+//! run: every run gets its own file, named after the time it started, e.g. retain.2020-10-16T14-00-00.log for retain.log
+//! daily/hourly: a file per day or hour, with the date appended, e.g. retain.log.2020-10-16
+//!
+//! Run logs ('config --log_dir'): every run also gets its own file in a directory, e.g. retain.2020-10-16T14-00-00.log
+//! These always hold the per-file decisions (what was uploaded, unchanged, skipped or failed and why), whatever the verbosity
+//! Only the newest ones are kept ('config --log_keep', DEFAULT_LOG_KEEP by default), older ones are removed when a run starts
+//! Configs without a directory use 'logs' in their manifest directory, see paths.rs. 'config --log_dir off' turns them off
+use crate::config::Config;
+use crate::colorutil::{self, Verbosity};
+use serde::{Serialize, Deserialize};
+use std::fmt::{Display, Formatter};
+use std::fs::OpenOptions;
+use std::path::Path;
+use std::sync::Mutex;
+use chrono::Local;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::fmt::writer::{BoxMakeWriter, MakeWriterExt};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::time::FormatTime;
+use tracing::Level;
+
+/// Amount of run logs that are kept when nothing is configured
+pub const DEFAULT_LOG_KEEP: u32 = 30;
+
+// Name the run logs are named after, with the time the run started inserted
+const RUN_LOG_NAME: &str = "retain.log";
+
+/// How the log file is rotated
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    // One file, appended to by every run
+    Never,
+    // A new file for every run
+    Run,
+    Daily,
+    Hourly,
+}
+
+impl Display for LogRotation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            LogRotation::Never => "never",
+            LogRotation::Run => "run",
+            LogRotation::Daily => "daily",
+            LogRotation::Hourly => "hourly",
+        })
+    }
+}
+
+// Timestamps in local time, like the dates printed elsewhere
+struct LocalTime;
+
+impl FormatTime for LocalTime {
+    fn format_time(&self, w: &mut Writer<'_>) -> std::fmt::Result {
+        write!(w, "{}", Local::now().format("%Y-%m-%d %H:%M:%S%.3f"))
+    }
+}
+
+/// Starts writing events to the configured log file and run log, if there are any
+/// Each run starts with the command line it was started with
+pub fn init(config: &Config) -> Result<(), String> {
+    let log_file = match &config.log_file {
+        Some(path) => Some(log_file(Path::new(path), config.log_rotation.unwrap_or(LogRotation::Never))?),
+        None => None,
+    };
+    let run_log = match config.run_log_dir() {
+        Some(dir) => Some(run_log(Path::new(&dir), config.log_keep.unwrap_or(DEFAULT_LOG_KEEP))?),
+        None => None,
+    };
+    // The log file holds what is printed, the run log also holds the per-file decisions
+    let level = match colorutil::verbosity() {
+        Verbosity::Quiet | Verbosity::Normal => Level::INFO,
+        Verbosity::Verbose => Level::DEBUG,
+        Verbosity::Debug => Level::TRACE,
+    };
+    match (log_file, run_log) {
+        (Some(file), Some(run)) => set_writer(BoxMakeWriter::new(file.with_max_level(level).and(run)), level.max(Level::DEBUG))?,
+        (Some(file), None) => set_writer(file, level)?,
+        (None, Some(run)) => set_writer(run, level.max(Level::DEBUG))?,
+        (None, None) => return Ok(()),
+    }
+    tracing::info!("Started: {}", std::env::args().collect::<Vec<String>>().join(" "));
+    Ok(())
+}
+
+// Opens the log file at 'path', rotated by 'rotation'
+fn log_file(path: &Path, rotation: LogRotation) -> Result<BoxMakeWriter, String> {
+    let name = match path.file_name() {
+        Some(n) => n.to_string_lossy().to_string(),
+        None => return Err(format!("{} is not a file", path.display())),
+    };
+    let dir = path.parent().filter(|d| !d.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+
+    Ok(match rotation {
+        LogRotation::Never => BoxMakeWriter::new(Mutex::new(append(path)?)),
+        LogRotation::Run => BoxMakeWriter::new(Mutex::new(append(&run_path(path))?)),
+        LogRotation::Daily => BoxMakeWriter::new(rolling(dir, &name, Rotation::DAILY)?),
+        LogRotation::Hourly => BoxMakeWriter::new(rolling(dir, &name, Rotation::HOURLY)?),
+    })
+}
+
+// Opens the log of a run starting now in 'dir', and removes the oldest ones s.t. 'keep' are left
+fn run_log(dir: &Path, keep: u32) -> Result<BoxMakeWriter, String> {
+    std::fs::create_dir_all(dir).map_err(|e| format!("{} ({})", dir.display(), e))?;
+    let file = append(&run_path(&dir.join(RUN_LOG_NAME)))?;
+    prune(dir, keep).map_err(|e| format!("failed to remove old run logs in {} ({})", dir.display(), e))?;
+    Ok(BoxMakeWriter::new(Mutex::new(file)))
+}
+
+// Removes the oldest run logs in 'dir' s.t. at most 'keep' are left. Returns how many were removed
+fn prune(dir: &Path, keep: u32) -> std::io::Result<usize> {
+    let pattern = run_path(Path::new(RUN_LOG_NAME)).to_string_lossy().len();
+    let mut logs: Vec<String> = std::fs::read_dir(dir)?.flatten()
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| name.len() == pattern && name.starts_with("retain.") && name.ends_with(".log"))
+        .collect();
+    // The time in the names sorts them from oldest to newest
+    logs.sort();
+    let removed = logs.len().saturating_sub(keep.max(1) as usize);
+    for name in &logs[..removed] {
+        std::fs::remove_file(dir.join(name))?;
+    }
+    Ok(removed)
+}
+
+// Path of the log file of a run starting now, with the time before the extension
+fn run_path(path: &Path) -> std::path::PathBuf {
+    let stem = path.file_stem().map_or(String::new(), |s| s.to_string_lossy().to_string());
+    let time = Local::now().format("%Y-%m-%dT%H-%M-%S");
+    match path.extension() {
+        Some(ext) => path.with_file_name(format!("{}.{}.{}", stem, time, ext.to_string_lossy())),
+        None => path.with_file_name(format!("{}.{}", stem, time)),
+    }
+}
+
+fn append(path: &Path) -> Result<std::fs::File, String> {
+    OpenOptions::new().create(true).append(true).open(path).map_err(|e| format!("{} ({})", path.display(), e))
+}
+
+fn rolling(dir: &Path, name: &str, rotation: Rotation) -> Result<RollingFileAppender, String> {
+    RollingFileAppender::builder().rotation(rotation).filename_prefix(name).build(dir).map_err(|e| e.to_string())
+}
+
+// Writes are blocking, s.t. nothing is lost when exiting without unwinding (e.g. std::process::exit)
+fn set_writer<W: for<'w> MakeWriter<'w> + Send + Sync + 'static>(writer: W, level: Level) -> Result<(), String> {
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(writer)
+        .with_timer(LocalTime)
+        .with_target(false)
+        .with_max_level(level)
+        .finish();
+    tracing::subscriber::set_global_default(subscriber).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::logging::{run_path, prune};
+    use std::path::Path;
+
+    #[test]
+    fn test_run_path() {
+        let path = run_path(Path::new("/var/log/retain.log"));
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+        assert_eq!(path.parent(), Some(Path::new("/var/log")));
+        assert!(name.starts_with("retain.") && name.ends_with(".log"));
+        assert_eq!(name.len(), "retain.2020-10-16T14-00-00.log".len());
+        let name = run_path(Path::new("retain")).to_string_lossy().to_string();
+        assert_eq!(name.len(), "retain.2020-10-16T14-00-00".len());
+    }
+
+    #[test]
+    fn test_prune() {
+        let dir = std::env::temp_dir().join("retain-test-prune-logs");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for day in 10..15 {
+            std::fs::write(dir.join(format!("retain.2020-10-{}T14-00-00.log", day)), "").unwrap();
+        }
+        std::fs::write(dir.join("other.log"), "").unwrap();
+
+        assert_eq!(prune(&dir, 3).unwrap(), 2);
+        let mut left: Vec<String> = std::fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name().into_string().unwrap()).collect();
+        left.sort();
+        assert_eq!(left, vec!["other.log", "retain.2020-10-12T14-00-00.log", "retain.2020-10-13T14-00-00.log", "retain.2020-10-14T14-00-00.log"]);
+        assert_eq!(prune(&dir, 3).unwrap(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,14 +1,19 @@
-mod colorutil;
-
 use clap::{Arg, App, SubCommand, crate_version, AppSettings};
-use crate::config::Config;
-
-mod config;
-mod subcommands;
-mod filelist;
-mod encryption;
-mod manifest;
+use retain_core::{colorutil, datetime, filelist, interrupt, jobs, logging, manifest, paths, subcommands, summary, throttle};
+use retain_core::config::Config;
+use retain_core::backend::simulate::Simulate;
+use retain_core::encryption::keys::Keys;
+use retain_core::encryption::BlockSize;
+use retain_core::colorutil::{printcoln, Verbosity};
+use termcolor::Color;
 
+// Validates that the value is a number >= 1, e.g. a thread count
+fn is_positive_number(s: String) -> Result<(), String> {
+    match s.parse::<usize>() {
+        Ok(n) if n >= 1 => Ok(()),
+        _ => Err("Must be a number, at least 1".to_string()),
+    }
+}
 
 fn main() {
     let mut app = App::new("retain-rs")
@@ -16,10 +21,46 @@ fn main() {
         .author("Kongou <github.com/KongouDesu>")
         .about("Secure backup tool targeting Backblaze B2")
         .arg(Arg::with_name("location")
-            .help("Location of config file")
+            .help("Location of config file. Files ending in .toml are TOML, anything else is JSON. Defaults to retain.cfg if it is in the working directory, otherwise retain.toml in the config directory of the platform (e.g. ~/.config/retain-rs)")
             .short("c")
             .long("config")
-            .default_value("retain.cfg"))
+            .takes_value(true)
+            .value_name("FILE"))
+        .arg(Arg::with_name("ignoremanifestauth")
+            .help("Load manifests that fail authentication, e.g. after changing the secret key")
+            .long("ignore-manifest-auth"))
+        .arg(Arg::with_name("trustuntagged")
+            .help("Load manifests without authentication once, e.g. after enabling encryption. The local manifest is then saved with it")
+            .long("trust-untagged"))
+        .arg(Arg::with_name("onefilesystem")
+            .help("Don't walk into directories on other file systems than the path in the backup list they are in, e.g. /proc or external drives")
+            .long("one-file-system"))
+        .arg(Arg::with_name("simulate")
+            .help("Rehearse uploads, downloads and cleans without the bucket or credentials. Files go to DIR (--simulate=DIR, a temporary directory if not given) \
+            and can be restored from it, or are only recorded with --simulate=discard. The real manifest is left alone")
+            .long("simulate")
+            .takes_value(true)
+            .min_values(0)
+            .max_values(1)
+            .require_equals(true)
+            .value_name("DIR"))
+        .arg(Arg::with_name("job")
+            .help("Run the job named NAME of the config instead of its own backup list, e.g. 'backup upload --job photos'. \
+            'all' uploads the backup list of the config and then every job, with 'backup upload' and 'daemon'")
+            .long("job")
+            .global(true)
+            .takes_value(true)
+            .value_name("NAME"))
+        .arg(Arg::with_name("quiet")
+            .help("Only print errors and the summary at the end, e.g. when run from cron")
+            .short("q")
+            .long("quiet")
+            .conflicts_with("verbose"))
+        .arg(Arg::with_name("verbose")
+            .help("Print what is decided for each file, e.g. which rule excludes it. Given twice (-vv), also print every B2 API request and response")
+            .short("v")
+            .long("verbose")
+            .multiple(true))
         .subcommand(SubCommand::with_name("config")
             .about("Configure this tool")
             .arg(Arg::with_name("appkeyid")
@@ -32,11 +73,63 @@ fn main() {
                 .long("app_key")
                 .takes_value(true)
                 .value_name("APP_KEY"))
+            .arg(Arg::with_name("noncemode")
+                .help("Take the nonces of encrypted files from a counter in the config, or pick them at random")
+                .long("nonce_mode")
+                .possible_values(&["counter","random"])
+                .case_insensitive(true)
+                .value_name("COUNTER/RANDOM"))
+            .arg(Arg::with_name("cipher")
+                .help("Cipher new files are encrypted with. AES-256-GCM is faster on CPUs with AES instructions")
+                .long("cipher")
+                .possible_values(&["xchacha20poly1305","aes256gcm"])
+                .case_insensitive(true)
+                .value_name("CIPHER"))
+            .arg(Arg::with_name("advancenonces")
+                .help("Move the nonce counter past the nonces the manifest records as allocated, needed when the config is an older copy, e.g. restored from a backup")
+                .long("advance_nonces"))
+            .arg(Arg::with_name("blocksize")
+                .help("Length of the blocks new files are encrypted in, 8KiB (default) times a power of two up to 1MiB. Larger blocks are faster for large files")
+                .long("block_size")
+                .takes_value(true)
+                .validator(|s| BlockSize::parse(&s).map(|_| ()))
+                .value_name("SIZE"))
+            .arg(Arg::with_name("protectcredentials")
+                .help("Encrypt the App Key ID and App Key in the config with the secret key, or a key bound to this machine")
+                .long("protect_credentials")
+                .possible_values(&["key","machine","off"])
+                .case_insensitive(true)
+                .value_name("KEY/MACHINE/OFF"))
+            .arg(Arg::with_name("appkeykeyring")
+                .help("Keep the App Key in the OS keyring instead of in the config")
+                .long("app_key_in_keyring"))
             .arg(Arg::with_name("bucketname")
                 .short("b")
                 .long("bucket_name")
                 .takes_value(true)
                 .value_name("BUCKET_NAME"))
+            .arg(Arg::with_name("s3endpoint")
+                .help("URL of S3-compatible storage the bucket is on, e.g. AWS S3, MinIO, Wasabi or B2's S3 API. The App Key ID and App Key are then its access key. Use 'off' for B2")
+                .long("s3_endpoint")
+                .takes_value(true)
+                .value_name("URL"))
+            .arg(Arg::with_name("s3region")
+                .help("Region of the bucket on S3-compatible storage, us-east-1 if not set")
+                .long("s3_region")
+                .takes_value(true)
+                .value_name("REGION"))
+            .arg(Arg::with_name("s3pathstyle")
+                .help("Address the bucket in the path instead of the host name of the S3 endpoint, as e.g. MinIO needs")
+                .long("s3_path_style")
+                .possible_values(&["on","off"])
+                .case_insensitive(true)
+                .value_name("ON/OFF"))
+            .arg(Arg::with_name("localdir")
+                .help("Back up to this directory instead of a bucket, e.g. on an external drive. Use 'off' for the bucket. \
+                For an offline copy in addition to the bucket, use a second config (-c) with the same backup list and key")
+                .long("local_dir")
+                .takes_value(true)
+                .value_name("DIR"))
             .arg(Arg::with_name("filelist")
                 .short("l")
                 .long("list")
@@ -47,11 +140,250 @@ fn main() {
                 .short("s")
                 .long("secret")
                 .takes_value(true)
-                .value_name("SECRET_FILE")))
+                .value_name("SECRET_FILE"))
+            .arg(Arg::with_name("retiredkeys")
+                .help("Comma-separated paths to keyfiles of previous secret keys, used to restore files they encrypted. Use 'none' to clear")
+                .long("retired_keys")
+                .takes_value(true)
+                .value_name("FILES"))
+            .arg(Arg::with_name("publickey")
+                .help("Path to the public key of a key pair. Files are encrypted for it instead of with the secret key. Use 'none' to clear")
+                .long("public_key")
+                .takes_value(true)
+                .value_name("FILE"))
+            .arg(Arg::with_name("privatekey")
+                .help("Path to the private key of a key pair, used to restore files encrypted for its public key. Use 'none' to clear")
+                .long("private_key")
+                .takes_value(true)
+                .value_name("FILE"))
+            .arg(Arg::with_name("uploadlimit")
+                .help("Upload bandwidth limit, e.g. 5MB/s. Use 'off' to disable")
+                .long("upload_limit")
+                .takes_value(true)
+                .value_name("RATE"))
+            .arg(Arg::with_name("uploadthreads")
+                .help("Amount of files to upload concurrently")
+                .long("upload_threads")
+                .takes_value(true)
+                .validator(is_positive_number)
+                .value_name("N"))
+            .arg(Arg::with_name("downloadthreads")
+                .help("Amount of files to download concurrently")
+                .long("download_threads")
+                .takes_value(true)
+                .validator(is_positive_number)
+                .value_name("N"))
+            .arg(Arg::with_name("cleanthreads")
+                .help("Amount of concurrent hide/delete requests when cleaning")
+                .long("clean_threads")
+                .takes_value(true)
+                .validator(is_positive_number)
+                .value_name("N"))
+            .arg(Arg::with_name("maxattempts")
+                .help("How many times a failed request is attempted before giving up")
+                .long("max_attempts")
+                .takes_value(true)
+                .validator(is_positive_number)
+                .value_name("N"))
+            .arg(Arg::with_name("compress")
+                .help("Compress files before uploading them")
+                .long("compress")
+                .possible_values(&["on","off"])
+                .case_insensitive(true)
+                .value_name("ON/OFF"))
+            .arg(Arg::with_name("scancache")
+                .help("Cache directory listings between uploads, s.t. unchanged directories aren't read again")
+                .long("scan_cache")
+                .possible_values(&["on","off"])
+                .case_insensitive(true)
+                .value_name("ON/OFF"))
+            .arg(Arg::with_name("shadowcopy")
+                .help("Read files from a Volume Shadow Copy when uploading, s.t. locked files are backed up (Windows, needs administrator)")
+                .long("shadow_copy")
+                .possible_values(&["on","off"])
+                .case_insensitive(true)
+                .value_name("ON/OFF"))
+            .arg(Arg::with_name("snapshot")
+                .help("Snapshot a file system before uploading and read its files from the snapshot, btrfs:PATH or zfs:PATH. Can be given multiple times, 'off' removes all")
+                .long("snapshot")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("KIND:PATH"))
+            .arg(Arg::with_name("classblimit")
+                .help("Maximum amount of class B (download) API calls per run. Use 'off' to disable")
+                .long("class_b_limit")
+                .takes_value(true)
+                .value_name("N"))
+            .arg(Arg::with_name("classclimit")
+                .help("Maximum amount of class C (list/authorize) API calls per run. Use 'off' to disable")
+                .long("class_c_limit")
+                .takes_value(true)
+                .value_name("N"))
+            .arg(Arg::with_name("limitaction")
+                .help("What to do when an API call limit is reached: pause until the daily caps reset, or abort")
+                .long("limit_action")
+                .possible_values(&["pause","abort"])
+                .case_insensitive(true)
+                .value_name("ACTION"))
+            .arg(Arg::with_name("downloadwarnings")
+                .help("Comma-separated amounts downloaded in a run at which a warning is printed, e.g. 1GB,10GB. Use 'off' to disable")
+                .long("download_warnings")
+                .takes_value(true)
+                .value_name("SIZES"))
+            .arg(Arg::with_name("retention")
+                .help("How many old versions 'clean' keeps: the last of each of the most recent DAILY days, WEEKLY weeks and MONTHLY months (e.g. 7,4,12). Use 'off' to keep all versions")
+                .long("retention")
+                .takes_value(true)
+                .value_name("DAILY,WEEKLY,MONTHLY"))
+            .arg(Arg::with_name("manifesthistory")
+                .help("How many versions of the remote manifest uploaded at the end of a backup are kept, s.t. an older one can be restored with 'manifest restore'")
+                .long("manifest_history")
+                .takes_value(true)
+                .validator(is_positive_number)
+                .value_name("N"))
+            .arg(Arg::with_name("manifestdir")
+                .help("Directory the local manifest is kept in. Moves the current manifest there")
+                .long("manifest_dir")
+                .takes_value(true)
+                .value_name("DIR"))
+            .arg(Arg::with_name("manifestbackend")
+                .help("Where the local manifest is kept: manifest.json, or a SQLite database (manifest.db) which is faster for many files. Converts the current manifest")
+                .long("manifest_backend")
+                .possible_values(&["file","sqlite"])
+                .case_insensitive(true)
+                .value_name("BACKEND"))
+            .arg(Arg::with_name("logfile")
+                .help("File the output of every run is logged to, with timestamps. Use 'off' to stop logging")
+                .long("log_file")
+                .takes_value(true)
+                .value_name("FILE"))
+            .arg(Arg::with_name("logrotation")
+                .help("How the log file is rotated: never (append every run to it), a new file every run, or one per day or hour")
+                .long("log_rotation")
+                .possible_values(&["never","run","daily","hourly"])
+                .case_insensitive(true)
+                .value_name("ROTATION"))
+            .arg(Arg::with_name("logdir")
+                .help("Directory every run writes its own detailed log to, with every file that was uploaded, skipped or failed. Defaults to 'logs' in the manifest directory. Use 'off' to disable")
+                .long("log_dir")
+                .takes_value(true)
+                .value_name("DIR"))
+            .arg(Arg::with_name("logkeep")
+                .help("Amount of run logs kept in the log directory, older ones are removed")
+                .long("log_keep")
+                .takes_value(true)
+                .validator(is_positive_number)
+                .value_name("N"))
+            .arg(Arg::with_name("webhook")
+                .help("URL the summary of every upload, download and clean is POSTed to as JSON. Use 'off' to disable")
+                .long("webhook")
+                .takes_value(true)
+                .value_name("URL"))
+            .arg(Arg::with_name("smtpserver")
+                .help("SMTP server the summary of every upload, download and clean is mailed through, e.g. smtp.example.com:587. Use 'off' to disable")
+                .long("smtp_server")
+                .takes_value(true)
+                .value_name("HOST[:PORT]"))
+            .arg(Arg::with_name("smtpuser")
+                .help("User to log in to the SMTP server with. Use 'none' to send without logging in")
+                .long("smtp_user")
+                .takes_value(true)
+                .value_name("USER"))
+            .arg(Arg::with_name("smtppassword")
+                .help("Password of the SMTP user, or where to find it, e.g. env:SMTP_PASSWORD")
+                .long("smtp_password")
+                .takes_value(true)
+                .value_name("PASSWORD"))
+            .arg(Arg::with_name("emailfrom")
+                .help("Address notification emails are sent from")
+                .long("email_from")
+                .takes_value(true)
+                .value_name("ADDRESS"))
+            .arg(Arg::with_name("emailto")
+                .help("Comma-separated addresses notification emails are sent to")
+                .long("email_to")
+                .takes_value(true)
+                .value_name("ADDRESSES"))
+            .arg(Arg::with_name("notifyon")
+                .help("Which runs are notified of: all of them, or only those that fail")
+                .long("notify_on")
+                .possible_values(&["always","failure"])
+                .case_insensitive(true)
+                .value_name("ALWAYS/FAILURE"))
+            .arg(Arg::with_name("beforehook")
+                .help("Command run before every upload, which stops it if the command fails. Can be given multiple times, 'off' removes all")
+                .long("before_hook")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("COMMAND"))
+            .arg(Arg::with_name("afterhook")
+                .help("Command run after every upload, with its summary as JSON on stdin. Can be given multiple times, 'off' removes all")
+                .long("after_hook")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("COMMAND"))
+            .arg(Arg::with_name("addjob")
+                .help("Add a job: another backup list with its own manifest, run with --job NAME. Can be given multiple times, \
+                giving an existing job a new backup list")
+                .long("add_job")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("NAME:BACKUP_LIST"))
+            .arg(Arg::with_name("removejob")
+                .help("Remove a job. Its files are left in the bucket and its manifest where it is kept. Can be given multiple times")
+                .long("remove_job")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("NAME"))
+            .arg(Arg::with_name("jobbucket")
+                .help("Bucket a job uploads to instead of that of the config. Use NAME:off to use that of the config")
+                .long("job_bucket")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("NAME:BUCKET"))
+            .arg(Arg::with_name("jobinterval")
+                .help("Minutes between uploads of a job by 'daemon'. Use NAME:off for the interval of the daemon")
+                .long("job_interval")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("NAME:MINUTES"))
+            .arg(Arg::with_name("metricsfile")
+                .help("File the metrics of every upload, download and clean are written to, for the textfile collector of Prometheus' node_exporter. Use 'off' to disable")
+                .long("metrics_file")
+                .takes_value(true)
+                .value_name("FILE"))
+            .subcommand(SubCommand::with_name("edit")
+                .about("Interactively walk through the main settings, with the current values filled in")
+                .long_about("Asks for the main settings, with the current values filled in\n\
+                The credentials, bucket and secret key are checked before they are accepted\n\
+                Nothing is changed until the edited config is saved at the end"))
+            .subcommand(SubCommand::with_name("validate")
+                .about("Check that the config works: authenticate, access the bucket, read the keys and parse the backup list")
+                .long_about("Checks that the config works, not only that it is filled in:\n\
+                authenticates with B2, checks the bucket exists and the App Key can list and upload files in it,\n\
+                checks the keys can be read and parses the backup list\n\
+                Exits with a non-zero code if any check fails"))
+            .subcommand(SubCommand::with_name("migrate")
+                .about("Convert the config to TOML, which can hold comments")
+                .long_about("Writes the config as TOML, then keeps the old config as <config>.old\n\
+                Use the new config with --config afterwards")
+                .arg(Arg::with_name("file")
+                    .help("Where to write the TOML config, the config location with a .toml extension by default")
+                    .index(1))))
 
 
         .subcommand(SubCommand::with_name("status")
-            .about("Display the status of the current configuration"))
+            .about("Display the status of the current configuration")
+            .arg(Arg::with_name("json")
+                .help("Print the status as JSON, along with statistics of the manifest and when the last backup was")
+                .long("json")))
         .subcommand(SubCommand::with_name("encryption")
             .about("Enable/disable encryption or encrypt/decrypt a file")
             .long_about("Enable/disable encryption, encrypt/decrypt a file or generate a new key\n\
@@ -64,20 +396,38 @@ fn main() {
                 .case_insensitive(true)
                 .value_name("ON/OFF"))
             .arg(Arg::with_name("keygen")
-                .help("Generate a new secret key and set it as active key")
+                .help("Generate a new secret key and set it as active key. The previous key is kept as a retired key")
                 .short("g")
                 .long("genkey")
                 .takes_value(true)
                 .value_name("FILE"))
+            .arg(Arg::with_name("keypairgen")
+                .help("Generate a new key pair, writing the private key to FILE and the public key to FILE.pub. \
+                Files are then encrypted for the public key, only the private key can restore them")
+                .long("genkeypair")
+                .takes_value(true)
+                .value_name("FILE"))
+            .arg(Arg::with_name("exportmnemonic")
+                .help("Print the secret key as 24 words, s.t. it can be written down and kept offline")
+                .long("export-mnemonic"))
+            .arg(Arg::with_name("importmnemonic")
+                .help("Read a secret key from its 24 words on stdin, write it to FILE and set it as active key. \
+                The previous key is kept as a retired key")
+                .long("import-mnemonic")
+                .takes_value(true)
+                .value_name("FILE"))
+            .arg(Arg::with_name("keyring")
+                .help("Move the secret key into the OS keyring. The key-file is left in place, back it up and delete it")
+                .long("keyring"))
             .arg(Arg::with_name("encrypt")
-                .help("Encrypt the IN_FILE, creating an encrypted version in OUT_FILE")
+                .help("Encrypt the IN_FILE, creating an encrypted version in OUT_FILE. Use - for stdin/stdout")
                 .short("e")
                 .long("encrypt")
                 .number_of_values(2)
                 .takes_value(true)
                 .value_names(&["IN_FILE","OUT_FILE"]))
             .arg(Arg::with_name("decrypt")
-                .help("Decrypt the IN_FILE, placing the decrypted result in OUT_FILE")
+                .help("Decrypt the IN_FILE, placing the decrypted result in OUT_FILE. Use - for stdin/stdout")
                 .short("d")
                 .long("decrypt")
                 .number_of_values(2)
@@ -91,6 +441,7 @@ fn main() {
             If this happens, some files may not be backed up and/or we may be wasting space\n\
             Files no longer found on the local system are also cleaned up\n\
             Note that this never removes any local files\n\
+            If a retention policy is configured, old versions of files not kept by it are deleted\n\
             It is recommended to run 'backup upload' afterwards to ensure everything is synced")
             .arg(Arg::with_name("mode")
                 .help("Whether to hide (soft-delete) or hard-delete unused files")
@@ -101,13 +452,433 @@ fn main() {
             .arg(Arg::with_name("force")
                 .short("f")
                 .long("force")
-                .help("Force cleanup, using local manifest.json")))
+                .help("Force cleanup, using local manifest.json"))
+            .arg(Arg::with_name("dryrun")
+                .short("n")
+                .long("dry-run")
+                .help("Show what would be hidden/deleted and which manifest entries would be dropped, without changing anything"))
+            .arg(Arg::with_name("olderthan")
+                .help("Only drop the entries of files that have been missing locally for at least AGE, e.g. '30d', \
+                s.t. their backups are hidden/deleted. Files are found missing when clean runs, unreferenced remote files are removed regardless")
+                .long("older-than")
+                .takes_value(true)
+                .validator(|s| datetime::parse_age(&s).map(|_| ()))
+                .value_name("AGE"))
+            .arg(Arg::with_name("threads")
+                .help("Amount of concurrent hide/delete requests. Overrides the configured amount")
+                .short("t")
+                .long("threads")
+                .takes_value(true)
+                .validator(is_positive_number)
+                .value_name("N")))
+
+        .subcommand(SubCommand::with_name("gc")
+            .about("Delete remote file versions that nothing refers to")
+            .long_about("Deletes every version in remote that no entry of the local manifest or kept snapshot refers to\n\
+            The latest version of each backed up file is always kept, as are the old versions the retention policy keeps\n\
+            Versions of files that are no longer backed up are deleted, and so is the data of forgotten snapshots\n\
+            Refuses to run if the local manifest is behind the remote one, e.g. after a backup from another machine\n\
+            Use --dry-run first to see what would be deleted and how much space that reclaims")
+            .arg(Arg::with_name("dryrun")
+                .short("n")
+                .long("dry-run")
+                .help("Show the unreferenced versions and the space deleting them would reclaim, without deleting anything"))
+            .arg(Arg::with_name("force")
+                .short("f")
+                .long("force")
+                .help("Collect by the local manifest, even if it is behind the remote one"))
+            .arg(Arg::with_name("threads")
+                .help("Amount of concurrent delete requests. Overrides the configured amount")
+                .short("t")
+                .long("threads")
+                .takes_value(true)
+                .validator(is_positive_number)
+                .value_name("N")))
+
+        .subcommand(SubCommand::with_name("orphans")
+            .about("Report what is out of sync between local files, the manifest and remote")
+            .long_about("Lists remote files the manifest doesn't refer to, manifest entries without a remote file\n\
+            and manifest entries whose local file is gone. Nothing is changed, see 'clean' to fix them"))
+
+        .subcommand(SubCommand::with_name("list")
+            .about("List backed up files")
+            .long_about("Lists the files tracked by the local manifest\n\
+            For each file, shows its size, modified time, when it was last uploaded and its name in remote")
+            .arg(Arg::with_name("path")
+                .help("Only list files whose path starts with PATTERN or matches it as a glob, e.g. /home/user/documents/ or '/home/**/*.txt'. Can be given multiple times")
+                .short("p")
+                .long("path")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("PATTERN"))
+            .arg(Arg::with_name("after")
+                .help("Only list files modified after the given time, e.g. '2020-10-16 14:00'")
+                .long("modified-after")
+                .takes_value(true)
+                .value_name("DATETIME"))
+            .arg(Arg::with_name("before")
+                .help("Only list files modified at or before the given time, e.g. '2020-10-16'")
+                .long("modified-before")
+                .takes_value(true)
+                .value_name("DATETIME"))
+            .arg(Arg::with_name("asof")
+                .help("List the files as they were in the latest snapshot recorded at or before the given time, e.g. '2020-03-03'")
+                .long("as-of")
+                .takes_value(true)
+                .value_name("DATETIME"))
+            .arg(Arg::with_name("snapshots")
+                .help("List the recorded snapshots instead of files")
+                .long("snapshots")
+                .conflicts_with("asof")))
+
+        .subcommand(SubCommand::with_name("search")
+            .about("Search backed up files")
+            .long_about("Finds files in the local manifest whose path or name in remote matches a regex\n\
+            Shows the original path of each file and whether the local copy still matches the backup")
+            .arg(Arg::with_name("regex")
+                .help("Regex to search for, e.g. 'documents/.*\\.pdf$'")
+                .required(true)
+                .index(1)))
+
+        .subcommand(SubCommand::with_name("browse")
+            .about("Browse backed up files and restore some of them")
+            .long_about("Shows the files in the local manifest as a directory tree in a terminal UI\n\
+            Marked files and directories are restored like with 'backup download --path'")
+            .arg(Arg::with_name("threads")
+                .help("Amount of files to download concurrently. Overrides the configured amount")
+                .short("t")
+                .long("threads")
+                .takes_value(true)
+                .validator(is_positive_number)
+                .value_name("N"))
+            .arg(Arg::with_name("maxdownload")
+                .help("Stop starting downloads once this much is downloaded, e.g. 10GB")
+                .long("max-download")
+                .takes_value(true)
+                .validator(|s| throttle::parse_size(s).map(|_| ()))
+                .value_name("SIZE")))
+
+        .subcommand(SubCommand::with_name("export")
+            .about("Export backed up files to a tar archive")
+            .long_about("Downloads backed up files from the bucket and writes them to a tar archive, decrypted and decompressed\n\
+            The archive can be extracted with any tar tool, without retain-rs. It is zstd compressed if OUTPUT ends with .zst\n\
+            Paths in the archive are the original paths without their leading '/' (and ':' after a drive letter)")
+            .arg(Arg::with_name("output")
+                .help("Path of the archive to write, e.g. backup.tar or backup.tar.zst")
+                .short("o")
+                .long("output")
+                .takes_value(true)
+                .required(true)
+                .value_name("OUTPUT"))
+            .arg(Arg::with_name("include")
+                .help("Only export files whose original path starts with PATTERN or matches it as a glob, e.g. /home/user/documents/ or '/home/**/*.txt'. Can be given multiple times")
+                .short("p")
+                .long("include")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("PATTERN"))
+            .arg(Arg::with_name("exclude")
+                .help("Don't export files whose original path starts with PATTERN or matches it as a glob. Applies after --include. Can be given multiple times")
+                .short("x")
+                .long("exclude")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("PATTERN"))
+            .arg(Arg::with_name("maxdownload")
+                .help("Stop starting downloads once this much is downloaded, e.g. 10GB")
+                .long("max-download")
+                .takes_value(true)
+                .validator(|s| throttle::parse_size(s).map(|_| ()))
+                .value_name("SIZE")))
+
+        .subcommand(SubCommand::with_name("stats")
+            .about("Display statistics about backed up files")
+            .long_about("Shows the amount and size of backed up files, in total and for each path in the backup list\n\
+            Also shows how many nonces have been used for encryption and when files were last uploaded"))
+
+        .subcommand(SubCommand::with_name("diff")
+            .about("Show what the next upload would do")
+            .long_about("Compares the files in the backup list with the local manifest\n\
+            Lists new files (+) and modified files (~) that the next upload would back up,\n\
+            and backed up files (-) that are no longer in the backup list or have been deleted"))
+
+        .subcommand(SubCommand::with_name("rules")
+            .about("Show, check and edit the paths and filters of the backup list")
+            .subcommand(SubCommand::with_name("list")
+                .about("Show the paths in the backup list and the filters and directives under each"))
+            .subcommand(SubCommand::with_name("add")
+                .about("Add a path to the backup list, or filters and directives under a path")
+                .long_about("Adds PATH to the end of the backup list if it isn't in it yet, then adds each FILTER under it\n\
+                A filter starts with '-' (exclude) or '+' (include), a directive with '!', e.g. --filter '- target/'\n\
+                In the gitignore dialect, filters are gitignore patterns. Filters are checked before anything is written")
+                .arg(Arg::with_name("path")
+                    .help("The file or directory to back up. Relative paths are made absolute")
+                    .required(true)
+                    .index(1))
+                .arg(Arg::with_name("filter")
+                    .help("A filter or directive to add under PATH. Can be given multiple times, they are added in order")
+                    .short("f")
+                    .long("filter")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .allow_hyphen_values(true)
+                    .value_name("FILTER")))
+            .subcommand(SubCommand::with_name("remove")
+                .about("Remove a path and everything under it from the backup list, or only one filter under it")
+                .arg(Arg::with_name("path")
+                    .help("The path in the backup list")
+                    .required(true)
+                    .index(1))
+                .arg(Arg::with_name("filter")
+                    .help("Only remove this filter or directive under PATH, as it is written in the backup list")
+                    .short("f")
+                    .long("filter")
+                    .takes_value(true)
+                    .allow_hyphen_values(true)
+                    .value_name("FILTER"))
+                .arg(Arg::with_name("yes")
+                    .help("Don't ask to confirm")
+                    .short("y")
+                    .long("yes")))
+            .subcommand(SubCommand::with_name("test")
+                .about("Explain whether a file is backed up, and which path and filter in the backup list decide it")
+                .long_about("Shows whether PATH is backed up and, for each path in the backup list that contains it,\n\
+                the filter or directive that includes or excludes it. PATH doesn't have to exist")
+                .arg(Arg::with_name("path")
+                    .help("The file to check")
+                    .required(true)
+                    .index(1))))
+
+        .subcommand(SubCommand::with_name("watch")
+            .about("Watch for changes and upload them continuously")
+            .long_about("Uploads new and modified files, then keeps watching the paths in the backup list\n\
+            Changed files are uploaded once no further changes happen for a while (see --delay)\n\
+            Files that are deleted are not cleaned up, use 'clean' for that")
+            .arg(Arg::with_name("delay")
+                .help("Seconds to wait for further changes before uploading")
+                .short("d")
+                .long("delay")
+                .takes_value(true)
+                .validator(is_positive_number)
+                .value_name("SECONDS"))
+            .arg(Arg::with_name("limit")
+                .help("Upload bandwidth limit, e.g. 5MB/s. Overrides the configured limit")
+                .long("limit")
+                .takes_value(true)
+                .value_name("RATE"))
+            .arg(Arg::with_name("threads")
+                .help("Amount of files to upload concurrently. Overrides the configured amount")
+                .short("t")
+                .long("threads")
+                .takes_value(true)
+                .validator(is_positive_number)
+                .value_name("N")))
+
+        .subcommand(SubCommand::with_name("daemon")
+            .about("Run backups unattended")
+            .long_about("Uploads new and modified files every few minutes (see --interval), or continuously with --watch\n\
+            Runs in the foreground until stopped. Use --systemd to have systemd run it in the background instead\n\
+            Note that manifest.json is read from the current directory, as with all other commands")
+            .arg(Arg::with_name("interval")
+                .help("Minutes between backups")
+                .short("i")
+                .long("interval")
+                .takes_value(true)
+                .validator(is_positive_number)
+                .value_name("MINUTES"))
+            .arg(Arg::with_name("watch")
+                .help("Watch for changes and upload them continuously, like 'watch'")
+                .short("w")
+                .long("watch")
+                .conflicts_with("interval"))
+            .arg(Arg::with_name("delay")
+                .help("Seconds to wait for further changes before uploading, with --watch")
+                .short("d")
+                .long("delay")
+                .takes_value(true)
+                .requires("watch")
+                .validator(is_positive_number)
+                .value_name("SECONDS"))
+            .arg(Arg::with_name("limit")
+                .help("Upload bandwidth limit, e.g. 5MB/s. Overrides the configured limit")
+                .long("limit")
+                .takes_value(true)
+                .value_name("RATE"))
+            .arg(Arg::with_name("threads")
+                .help("Amount of files to upload concurrently. Overrides the configured amount")
+                .short("t")
+                .long("threads")
+                .takes_value(true)
+                .validator(is_positive_number)
+                .value_name("N"))
+            .arg(Arg::with_name("systemd")
+                .help("Instead of running, write a systemd service (and timer, unless --watch) to DIR that runs this with the current config and directory")
+                .long("systemd")
+                .takes_value(true)
+                .value_name("DIR")))
+
+        .subcommand(SubCommand::with_name("manifest")
+            .about("List, restore or rebuild the manifest")
+            .long_about("Lists the versions of the remote manifest that are kept, or restores one as the local manifest\n\
+            The current local manifest is kept as manifest.json.old\n\
+            The amount of versions kept is set with 'config --manifest_history'\n\
+            If the manifest is lost, 'rebuild' records the files in the backup list that the bucket holds unchanged,\n\
+            s.t. the next upload doesn't upload them again. This only works without encryption")
+            .arg(Arg::with_name("action")
+                .help("List the kept versions, restore one of them, or rebuild the manifest from the bucket")
+                .required(true)
+                .possible_values(&["history","restore","rebuild"])
+                .case_insensitive(true)
+                .index(1))
+            .arg(Arg::with_name("asof")
+                .help("Restore the latest version uploaded at or before the given time, e.g. '2020-10-16 14:00'")
+                .long("as-of")
+                .takes_value(true)
+                .conflicts_with("id")
+                .value_name("DATETIME"))
+            .arg(Arg::with_name("id")
+                .help("Restore the version with the given file id, as listed by 'manifest history'")
+                .long("id")
+                .takes_value(true)
+                .value_name("FILE_ID")))
+
+        .subcommand(SubCommand::with_name("repair")
+            .about("Reconstruct a lost or corrupt manifest from the files in the bucket")
+            .long_about("Makes a new local manifest with an entry for every file in the bucket, without looking at local files\n\
+            Without encryption, names map back to the original paths. With encryption, names are masked and the paths are lost:\n\
+            those entries are kept under a directory for lost files, s.t. 'backup download' can still restore their content\n\
+            The current local manifest is kept as manifest.json.old. If it still loads, prefer 'manifest restore' or 'manifest rebuild'")
+            .arg(Arg::with_name("lostdir")
+                .help("Directory the entries with masked names are put in. Defaults to 'retain-lost' in the current directory")
+                .long("lost-dir")
+                .takes_value(true)
+                .value_name("DIR"))
+            .arg(Arg::with_name("force")
+                .help("Replace the local manifest even if it or the remote manifest can be loaded")
+                .short("f")
+                .long("force")))
+
+        .subcommand(SubCommand::with_name("snapshot")
+            .about("Name, list and restore snapshots of the backup")
+            .long_about("Each upload that changes anything records a snapshot of the backed up files\n\
+            A named snapshot pins the current state, e.g. before a risky operation. It is never forgotten by 'clean'\n\
+            Restoring a snapshot needs the storage to still have the old versions of its files, see --retention")
+            .subcommand(SubCommand::with_name("create")
+                .about("Name the current state of the backup")
+                .arg(Arg::with_name("name")
+                    .help("Name of the snapshot, e.g. 'pre-upgrade'")
+                    .required(true)
+                    .index(1)))
+            .subcommand(SubCommand::with_name("list")
+                .about("List the snapshots, with their names"))
+            .subcommand(SubCommand::with_name("restore")
+                .about("Restore the files as they were in a named snapshot")
+                .long_about("Downloads every file that isn't exactly as it was in the snapshot\n\
+                Files that were backed up after the snapshot are left alone")
+                .arg(Arg::with_name("snapshot")
+                    .help("Name of the snapshot")
+                    .required(true)
+                    .index(1))
+                .arg(Arg::with_name("threads")
+                    .help("Amount of files to download concurrently. Overrides the configured amount")
+                    .short("t")
+                    .long("threads")
+                    .takes_value(true)
+                    .validator(is_positive_number)
+                    .value_name("N"))
+                .arg(Arg::with_name("path")
+                    .help("Only restore files whose original path starts with PATTERN or matches it as a glob. Can be given multiple times")
+                    .short("p")
+                    .long("path")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .value_name("PATTERN"))
+                .arg(Arg::with_name("exclude")
+                    .help("Don't restore files whose original path starts with PATTERN or matches it as a glob. Can be given multiple times")
+                    .short("x")
+                    .long("exclude")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .value_name("PATTERN"))
+                .arg(Arg::with_name("maxdownload")
+                    .help("Stop starting downloads once this much is downloaded, e.g. 10GB")
+                    .long("max-download")
+                    .takes_value(true)
+                    .validator(|s| throttle::parse_size(s).map(|_| ()))
+                    .value_name("SIZE"))))
+
+        .subcommand(SubCommand::with_name("bucket")
+            .about("Show and change settings of the bucket")
+            .subcommand(SubCommand::with_name("lifecycle")
+                .about("Show or set how long B2 keeps hidden file versions")
+                .long_about("Shows the lifecycle rules of the bucket, or sets the rule for the whole bucket\n\
+                'clean hide' hides files instead of deleting them, and uploading a modified file hides its previous version\n\
+                Hidden versions are billed until they are deleted, a lifecycle rule has B2 delete them after some days\n\
+                With a retention policy, the rule must keep hidden versions at least as long as the policy does\n\
+                Old versions of the manifest are deleted by the rule too, regardless of --manifest_history")
+                .arg(Arg::with_name("days")
+                    .help("Delete hidden versions after this many days")
+                    .long("hidden-days")
+                    .takes_value(true)
+                    .validator(is_positive_number)
+                    .value_name("DAYS"))
+                .arg(Arg::with_name("recommended")
+                    .help("Delete hidden versions after the days recommended for the retention policy, 30 days without one")
+                    .long("recommended")
+                    .conflicts_with("days"))
+                .arg(Arg::with_name("remove")
+                    .help("Keep hidden versions until 'clean' deletes them")
+                    .long("remove")
+                    .conflicts_with_all(&["days", "recommended"]))
+                .arg(Arg::with_name("force")
+                    .help("Set the days even if they are fewer than the retention policy needs")
+                    .short("f")
+                    .long("force"))))
 
         .subcommand(SubCommand::with_name("init")
             .about("Enter interactive initialization mode")
             .long_about("Used to interactively set up the program\n\
             Walks through setting auth, choosing a bucket, etc.\n\
-            Provides important information about encryption and how to choose what files gets uploaded"))
+            Provides important information about encryption and how to choose what files gets uploaded\n\
+            Anything given with the flags below (or RETAIN_APP_KEY_ID, RETAIN_APP_KEY, RETAIN_BUCKET_NAME and RETAIN_SECRET_KEY) isn't asked for\n\
+            With all of them, init runs unattended, e.g. from a provisioning script. A given value that is rejected (e.g. a wrong App Key) exits with an error instead of asking again")
+            .arg(Arg::with_name("appkeyid")
+                .long("app-key-id")
+                .takes_value(true)
+                .value_name("APP_KEY_ID"))
+            .arg(Arg::with_name("appkey")
+                .long("app-key")
+                .takes_value(true)
+                .value_name("APP_KEY"))
+            .arg(Arg::with_name("bucket")
+                .long("bucket")
+                .takes_value(true)
+                .value_name("BUCKET_NAME"))
+            .arg(Arg::with_name("createbucket")
+                .help("Create the bucket without asking if it doesn't exist. It is private and deletes hidden versions after the days recommended for the retention policy, 30 days without one")
+                .long("create-bucket"))
+            .arg(Arg::with_name("list")
+                .help("Path of the backup list, which is created if it doesn't exist")
+                .long("list")
+                .takes_value(true)
+                .value_name("FILE_LIST"))
+            .arg(Arg::with_name("encrypt")
+                .help("Whether files are encrypted. Once enabled, it can't be disabled without uploading all files again")
+                .long("encrypt")
+                .possible_values(&["on","off"])
+                .case_insensitive(true)
+                .value_name("ON/OFF"))
+            .arg(Arg::with_name("keyfile")
+                .help("Secret key to encrypt with. Created if it doesn't exist, an existing key-file is used as it is. Defaults to a new key next to the config")
+                .long("keyfile")
+                .takes_value(true)
+                .value_name("FILE")))
 
         .subcommand(SubCommand::with_name("backup")
             .about("Upload, download or synchronize with remote storage")
@@ -118,32 +889,157 @@ fn main() {
                 .case_insensitive(true)
                 .min_values(1)
                 .max_values(1)
-                .index(1)));
+                .index(1))
+            .arg(Arg::with_name("limit")
+                .help("Upload bandwidth limit for this run, e.g. 5MB/s. Overrides the configured limit")
+                .long("limit")
+                .takes_value(true)
+                .value_name("RATE"))
+            .arg(Arg::with_name("threads")
+                .help("Amount of files to transfer concurrently. Overrides the configured amount")
+                .short("t")
+                .long("threads")
+                .takes_value(true)
+                .validator(is_positive_number)
+                .value_name("N"))
+            .arg(Arg::with_name("resume")
+                .help("When uploading, only upload the files the last upload didn't get to or failed to upload, instead of scanning the backup list")
+                .long("resume"))
+            .arg(Arg::with_name("path")
+                .help("Only download files whose original path starts with PATTERN or matches it as a glob, e.g. /home/user/documents/ or '/home/**/*.txt'. Can be given multiple times")
+                .short("p")
+                .long("path")
+                .visible_alias("include")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("PATTERN"))
+            .arg(Arg::with_name("exclude")
+                .help("Don't download files whose original path starts with PATTERN or matches it as a glob, e.g. '**/Videos/**'. Applies after --path/--include. Can be given multiple times")
+                .short("x")
+                .long("exclude")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("PATTERN"))
+            .arg(Arg::with_name("asof")
+                .help("Restore files as they were at the given time, e.g. '2020-10-16 14:00'. Newer local files are replaced")
+                .long("as-of")
+                .takes_value(true)
+                .value_name("DATETIME"))
+            .arg(Arg::with_name("maxdownload")
+                .help("Stop starting downloads once this much is downloaded, e.g. 10GB. With 'config --limit_action pause', \
+                the download pauses until the daily caps reset instead")
+                .long("max-download")
+                .takes_value(true)
+                .validator(|s| throttle::parse_size(s).map(|_| ()))
+                .value_name("SIZE")));
 
     let args = app.get_matches();
+    colorutil::set_verbosity(match args.occurrences_of("verbose") {
+        _ if args.is_present("quiet") => Verbosity::Quiet,
+        0 => Verbosity::Normal,
+        1 => Verbosity::Verbose,
+        _ => Verbosity::Debug,
+    });
 
     // Load config file
-    let cfg_location = args.value_of("location").unwrap();
-    let mut config = Config::from_file(cfg_location);
+    let cfg_location = args.value_of("location").map_or_else(paths::default_config, |s| s.to_string());
+    let mut config = match Config::from_file(&cfg_location) {
+        Ok(config) => config,
+        Err(e) => {
+            printcoln(Color::Red, format!("Error: {}", e));
+            std::process::exit(1);
+        }
+    };
+    // Environment overrides apply to everything but 'config', which edits the config file itself
+    // 'config validate' checks the config as it is used, so they apply to that
+    let edits_config = args.subcommand_matches("config").map_or(false, |c| c.subcommand_name() != Some("validate"));
+    if !edits_config {
+        config.apply_env();
+    }
+    if args.is_present("simulate") {
+        config.simulate = Some(Simulate::from_arg(args.value_of("simulate")));
+    }
     //println!("{:?}", config);
+    if let Some(simulate) = &config.simulate {
+        printcoln(Color::Yellow, format!("Simulating, nothing is sent to the bucket: {}", simulate));
+    }
+    // Jobs are run one at a time by the commands that run them all (see jobs.rs), everything else runs one job
+    let job = args.value_of("job").filter(|job| *job != jobs::ALL);
+    let runs_all = match args.subcommand() {
+        ("backup", Some(backup_args)) => backup_args.value_of("action") == Some("upload"),
+        ("daemon", Some(daemon_args)) => !daemon_args.is_present("watch"),
+        _ => false,
+    };
+    if args.value_of("job") == Some(jobs::ALL) && !runs_all {
+        printcoln(Color::Red, format!("--job {} only works with 'backup upload' and 'daemon' without --watch, name a job instead", jobs::ALL));
+        std::process::exit(1);
+    }
+    if job.is_some() && edits_config {
+        printcoln(Color::Red, "'config' edits the config itself, jobs are edited with --add_job and --remove_job");
+        std::process::exit(1);
+    }
+    if let Err(e) = jobs::enter(&mut config, job) {
+        printcoln(Color::Red, e);
+        std::process::exit(1);
+    }
+    filelist::set_one_file_system(args.is_present("onefilesystem"));
+    if let Err(e) = logging::init(&config) {
+        printcoln(Color::Yellow, format!("Warning: failed to open the log file, this run isn't logged ({})", e));
+    }
+
+    // With encryption, manifests are authenticated with the secret key
+    if config.encrypt == Some(true) {
+        if let Ok(keys) = Keys::from_config(&config) {
+            if keys.all().next().is_none() {
+                printcoln(Color::Yellow, "Warning: manifests aren't authenticated with only a key pair, configure a secret key as well to authenticate them");
+            }
+            manifest::set_mac_keys(&keys, args.is_present("ignoremanifestauth"), args.is_present("trustuntagged"));
+        }
+    }
 
     match args.subcommand() {
         ("config", config_args) => {
             subcommands::configure(&mut config, config_args);
-            // Save config
-            config.save_to(cfg_location).unwrap();
+            // Save config, 'config migrate' may have moved it
+            config.save();
         },
-        ("status", _status_args) => subcommands::status(&config),
+        ("status", status_args) => subcommands::status(&config, status_args),
         ("backup", backup_args) => subcommands::backup::backup(&mut config, backup_args),
         ("encryption", encrypt_args) => subcommands::encrypt::encrypt(&mut config, encrypt_args),
         ("clean", clean_args) => subcommands::clean::clean(&mut config, clean_args),
-        ("init", _) => subcommands::init::init(&mut config),
+        ("gc", gc_args) => subcommands::gc::gc(&config, gc_args),
+        ("orphans", _) => subcommands::orphans::orphans(&config),
+        ("repair", repair_args) => subcommands::repair::repair(&config, repair_args),
+        ("list", list_args) => subcommands::list::list(list_args),
+        ("search", search_args) => subcommands::search::search(search_args),
+        ("browse", browse_args) => subcommands::browse::browse(&config, browse_args),
+        ("export", export_args) => subcommands::export::export(&config, export_args),
+        ("snapshot", snapshot_args) => subcommands::snapshot::snapshot(&config, snapshot_args),
+        ("stats", _) => subcommands::stats::stats(&config),
+        ("diff", _) => subcommands::diff::diff(&config),
+        ("rules", rules_args) => subcommands::rules::rules(&config, rules_args),
+        ("watch", watch_args) => subcommands::watch::watch(&mut config, watch_args),
+        ("daemon", daemon_args) => subcommands::daemon::daemon(&mut config, daemon_args),
+        ("manifest", manifest_args) => subcommands::manifest::manifest(&config, manifest_args),
+        ("bucket", bucket_args) => subcommands::bucket::bucket(&config, bucket_args),
+        ("init", init_args) => subcommands::init::init(&mut config, init_args),
         _ => {
             println!("{}", args.usage());
             println!("\tUse -h for full help");
         }
     };
 
+    // A run stopped by Ctrl-C has saved what it did, the exit code tells it didn't finish
+    if interrupt::interrupted() {
+        std::process::exit(interrupt::EXIT_CODE);
+    }
+    // Failures of an upload, download or clean are reflected in the exit code, e.g. for cron
+    if summary::failed() {
+        std::process::exit(1);
+    }
+
 
 
 
@@ -11,6 +11,23 @@
 ///
 /// Note that if encryption is disabled, the "masked name" will instead be the input path, but
 /// formatted as an absolute path, using '/' separators and works with BackBlaze web view
+///
+/// The manifest is stored as zstd compressed CBOR, both locally and in remote
+/// For compatibility it is still called manifest.json, and manifests in older formats are still read:
+/// plain JSON (version 0) and zstd compressed JSON (also version 0)
+/// These are migrated when loaded and written in the current format the next time the manifest is saved
+///
+/// When encryption is enabled, manifests are authenticated with an HMAC-SHA256 keyed from the secret key
+/// With only a key pair there is no secret key, and manifests aren't authenticated
+/// The tag covers the CBOR encoded manifest and is appended to the file, or stored in the database
+/// Loading a manifest with a missing or wrong tag fails, unless --ignore-manifest-auth is given
+/// A manifest without a tag, made before encryption was enabled or by an older version, is loaded with --trust-untagged
+/// The local manifest is then saved again right away, with a tag, s.t. it is only trusted that once
+///
+/// Locally, the manifest can instead be kept in a SQLite database, manifest.db (see manifest_db.rs)
+/// It is used whenever it exists. 'config --manifest_backend' switches between the two
+///
+/// The local manifest is kept in the manifest directory of the config, see paths.rs
 
 use serde::{Serialize, Deserialize};
 use std::error::Error;
@@ -18,62 +35,531 @@ use std::cmp::Ordering;
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
 use std::borrow::Cow;
+use std::collections::{BTreeMap, HashSet};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use termcolor::Color;
+use crate::compression::COMPRESSION_LEVEL;
+use crate::colorutil::printcoln;
+use crate::xattrs;
+use crate::winpath;
+use crate::moves::Content;
+use crate::paths;
+use crate::journal;
+use crate::secrets;
+use crate::encryption::keys::Keys;
+#[cfg(feature = "sqlite")]
+use crate::manifest_db::ManifestDb;
 
 // Amount of Alphanumeric characters used to make a masked name
 const MASK_SIZE: usize = 64;
 
+// Every zstd frame starts with this. Plain JSON never does
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+
+// Format version written by this program. Manifests with a higher version can't be loaded
+// 0: JSON, 1: CBOR
+pub const MANIFEST_VERSION: u32 = 1;
+
+// Appended to authenticated manifest files, after the tag
+const MAC_MAGIC: [u8; 8] = *b"rrs-hmac";
+const MAC_LENGTH: usize = 32;
+
+// Keys used to authenticate manifests, derived from the secret keys. Empty if encryption is disabled or there are none
+// The first is derived from the active key and used for new tags, the others are only used to verify
+static MAC_KEYS: Mutex<Vec<Vec<u8>>> = Mutex::new(Vec::new());
+// Whether manifests that fail authentication are loaded anyway
+static IGNORE_MAC: AtomicBool = AtomicBool::new(false);
+// Whether manifests without a tag are loaded anyway
+static TRUST_UNTAGGED: AtomicBool = AtomicBool::new(false);
+// Directory of the local manifest. Empty is the working directory
+static MANIFEST_DIR: Mutex<String> = Mutex::new(String::new());
+
+/// Authenticates manifests with keys derived from 'keys' from now on
+/// Manifests made with a retired key are accepted, s.t. they can still be loaded after changing the key
+/// If 'ignore' is set, manifests that fail authentication are loaded anyway, with a warning
+/// If 'trust_untagged' is set, only manifests without a tag are, see verify_mac
+pub fn set_mac_keys(keys: &Keys, ignore: bool, trust_untagged: bool) {
+    // Separate keys are derived, s.t. the secret keys are only used directly for encryption
+    *MAC_KEYS.lock().unwrap() = keys.mac_material().map(|key| {
+        let mut derive = Hmac::<Sha256>::new_varkey(key).expect("HMAC accepts any key length");
+        derive.update(b"retain-rs manifest authentication");
+        derive.finalize().into_bytes().to_vec()
+    }).collect();
+    IGNORE_MAC.store(ignore, AtomicOrdering::SeqCst);
+    TRUST_UNTAGGED.store(trust_untagged, AtomicOrdering::SeqCst);
+}
+
+fn hmac(key: &[u8], cbor: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).expect("HMAC accepts any key length");
+    mac.update(cbor);
+    mac
+}
+
+// Tag for the given CBOR encoded manifest, None if manifests aren't authenticated
+fn mac(cbor: &[u8]) -> Option<Vec<u8>> {
+    let keys = MAC_KEYS.lock().unwrap();
+    Some(hmac(keys.first()?, cbor).finalize().into_bytes().to_vec())
+}
+
+/// Hex tag authenticating a record of the journal (see journal.rs), None if manifests aren't authenticated
+pub fn record_tag(record: &[u8]) -> Option<String> {
+    mac(record).map(|tag| secrets::encode_hex(&tag))
+}
+
+/// Whether a record of the journal with hex 'tag' may be applied: its tag is valid for one of the keys,
+/// or manifests aren't authenticated (or that is ignored, see --ignore-manifest-auth and --trust-untagged)
+pub fn record_authentic(record: &[u8], tag: Option<&str>) -> bool {
+    let keys = MAC_KEYS.lock().unwrap().clone();
+    if keys.is_empty() || IGNORE_MAC.load(AtomicOrdering::SeqCst) {
+        return true;
+    }
+    match tag.and_then(|tag| secrets::decode_hex(tag).ok()) {
+        Some(tag) => keys.iter().any(|key| hmac(key, record).verify(&tag).is_ok()),
+        None => TRUST_UNTAGGED.load(AtomicOrdering::SeqCst),
+    }
+}
+
+// Splits a manifest file into its content and its tag, if it has one
+fn split_mac(bytes: &[u8]) -> (&[u8], Option<&[u8]>) {
+    if bytes.len() >= MAC_LENGTH + MAC_MAGIC.len() && bytes.ends_with(&MAC_MAGIC) {
+        let (content, trailer) = bytes.split_at(bytes.len() - MAC_LENGTH - MAC_MAGIC.len());
+        (content, Some(&trailer[..MAC_LENGTH]))
+    } else {
+        (bytes, None)
+    }
+}
+
+// Checks the tag of a CBOR encoded manifest, if manifests are authenticated
+// Returns whether it was loaded without a tag, s.t. it can be saved again with one
+fn verify_mac(cbor: &[u8], tag: Option<&[u8]>) -> Result<bool, Box<dyn Error>> {
+    let keys = MAC_KEYS.lock().unwrap().clone();
+    if keys.is_empty() {
+        return Ok(false);
+    }
+    let (problem, flag) = match tag {
+        Some(tag) => match keys.iter().any(|key| hmac(key, cbor).verify(tag).is_ok()) {
+            true => return Ok(false),
+            false => ("failed authentication, it was modified or made with a different key", "--ignore-manifest-auth"),
+        },
+        None => ("is not authenticated, it was made without encryption or by an older version of retain-rs", "--trust-untagged"),
+    };
+    if IGNORE_MAC.load(AtomicOrdering::SeqCst) || (tag.is_none() && TRUST_UNTAGGED.load(AtomicOrdering::SeqCst)) {
+        printcoln(Color::Yellow, format!("Warning: manifest {}. Loading it anyway", problem));
+        Ok(tag.is_none())
+    } else {
+        Err(format!("manifest {}. If you trust it, use {} to load it anyway", problem, flag).into())
+    }
+}
+
+// Name of the manifest in remote. It is never masked, s.t. it can always be found
+pub const REMOTE_MANIFEST: &str = "manifest.json";
+
+// Amount of versions of the remote manifest that are kept, unless configured otherwise
+pub const DEFAULT_MANIFEST_HISTORY: u32 = 10;
+
+// Names of the local manifest, for each backend
+pub const MANIFEST_FILE: &str = "manifest.json";
+pub const MANIFEST_DB: &str = "manifest.db";
+
+// Name of the directory listings kept between uploads, see scancache.rs
+const SCAN_CACHE: &str = "scancache.cbor";
+// Name of the record of files the last upload didn't get to, see pending.rs
+const PENDING: &str = "pending.json";
+// Name of the journal of uploads not saved to the local manifest yet, see journal.rs
+const JOURNAL: &str = "manifest.journal";
+
+/// Keeps the local manifest in 'dir' from now on. Empty is the working directory
+pub fn set_dir(dir: &str) {
+    *MANIFEST_DIR.lock().unwrap() = dir.to_string();
+}
+
+fn in_dir(name: &str) -> String {
+    paths::in_dir(&MANIFEST_DIR.lock().unwrap(), name)
+}
+
+/// Location of the local manifest: manifest.db if it exists, otherwise manifest.json
+pub fn local_path() -> String {
+    let db = in_dir(MANIFEST_DB);
+    if std::path::Path::new(&db).exists() {
+        db
+    } else {
+        in_dir(MANIFEST_FILE)
+    }
+}
+
+/// Location the previous local manifest is kept at when it is replaced
+pub fn old_path() -> String {
+    in_dir(&format!("{}.old", MANIFEST_FILE))
+}
+
+/// Location of the directory listings cached between uploads, next to the local manifest
+pub fn scan_cache_path() -> String {
+    in_dir(SCAN_CACHE)
+}
+
+/// Location of the files the last upload didn't get to, next to the local manifest
+pub fn pending_path() -> String {
+    in_dir(PENDING)
+}
+
+/// Location of the journal of uploads since the local manifest was saved
+pub fn journal_path() -> String {
+    in_dir(JOURNAL)
+}
+
+/// Moves the local manifest to a database (or back to a file), keeping the old one as <name>.old
+pub fn switch_backend(to_db: bool) -> Result<(), Box<dyn Error>> {
+    let (from, to) = if to_db { (MANIFEST_FILE, MANIFEST_DB) } else { (MANIFEST_DB, MANIFEST_FILE) };
+    let (from, to) = (in_dir(from), in_dir(to));
+    if local_path() == to {
+        return Ok(());
+    }
+    // Scoped s.t. the database is closed before it is moved
+    {
+        let manifest = FileManifest::from_file(&from)?;
+        manifest.to_file(&to)?;
+    }
+    std::fs::rename(&from, format!("{}.old", from))?;
+    Ok(())
+}
+
+/// Moves the local manifest (and the old ones kept next to it) to 'dir', and keeps it there from now on
+pub fn move_to_dir(dir: &str) -> Result<(), Box<dyn Error>> {
+    for name in &[MANIFEST_FILE, MANIFEST_DB] {
+        for name in vec![name.to_string(), format!("{}.old", name)] {
+            let (from, to) = (in_dir(&name), paths::in_dir(dir, &name));
+            if std::path::Path::new(&from).exists() {
+                if std::path::Path::new(&to).exists() {
+                    return Err(format!("{} already exists", to).into());
+                }
+                paths::create_parent(&to)?;
+                std::fs::rename(&from, &to)?;
+            }
+        }
+    }
+    set_dir(dir);
+    Ok(())
+}
+
 #[derive(Serialize,Deserialize,Debug)]
 pub struct FileManifest {
+    // Format version, missing (0) in JSON manifests
+    #[serde(default)]
+    pub version: u32,
     // If true, mask names, if false, translate to B2 friendly paths
     pub mask: bool,
     // Original name, modified timestamp, masked name
     pub files: Vec<FileEntry>,
+    // Upload runs that changed something, oldest first, see record_snapshot
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub snapshots: Vec<Snapshot>,
+    // Versions of files that were replaced or removed, kept for the snapshots that have them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub past: Vec<PastVersion>,
+    // Nonces allocated by the config when the manifest was last saved, see Config::check_nonces
+    #[serde(default, skip_serializing_if = "is_zero", with = "crate::config::nonce_count")]
+    pub nonces: u128,
+    // Set if loaded from a database, s.t. saving to it only writes what changed
+    #[cfg(feature = "sqlite")]
+    #[serde(skip)]
+    db: Option<ManifestDb>,
+    // The entries as they were before they were first changed since the last snapshot, by path (None if there was none)
+    // See record_changes
+    #[serde(skip)]
+    touched: BTreeMap<String, Option<FileEntry>>,
 }
 
-#[derive(Serialize,Deserialize,Debug)]
+fn is_zero<T: Default + PartialEq>(n: &T) -> bool {
+    *n == T::default()
+}
+
+// Unnamed snapshots kept by uploads, older ones are forgotten s.t. past versions don't pile up if 'clean' never runs
+const SNAPSHOT_LIMIT: usize = 1000;
+
+#[derive(Serialize,Deserialize,Debug,Clone)]
 pub struct FileEntry {
     pub path: String,
     // Timestamp is modified time in milliseconds since Unix Epoch
     pub timestamp: u64,
     pub mask: String,
+    // Size of the local file in bytes when it was uploaded. 0 for entries made before sizes were tracked
+    #[serde(default)]
+    pub size: u64,
+    // Hex SHA1 of the content of the local file as of the last upload, used to find moved files (see moves.rs). Empty if not tracked
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub sha1: String,
+    // Quick fingerprint of the content (size, head and tail, see moves::fingerprint), to find moved files without hashing them. Empty if not tracked
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub fingerprint: String,
+    // Block hashes of the file as of the last upload, used for delta uploads. Empty if not tracked
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub blocks: Vec<String>,
+    // Remote names of deltas to apply on top of the full upload, in order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deltas: Vec<String>,
+    // IDs of the uploads of 'deltas', s.t. a restore downloads exactly those versions (see delta_id)
+    // Incomplete for storage without IDs, or deltas uploaded before IDs were recorded
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub delta_ids: Vec<String>,
+    // Whether the remote file is zstd compressed (before encryption)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub compressed: bool,
+    // Time of the last successful upload in milliseconds since Unix Epoch. 0 if unknown
+    #[serde(default)]
+    pub uploaded: u64,
+    // Set if the file is a hard link to another backed up file, which holds the content. Nothing is uploaded for it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link: Option<String>,
+    // Data extents (offset, length) of a sparse file. Only these were uploaded, one after another. Empty if uploaded in full
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sparse: Vec<(u64, u64)>,
+    // Extended attributes (name, value) as of the last upload
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub xattrs: Vec<(String, Vec<u8>)>,
+    // ID of the full upload in B2, s.t. a restore downloads exactly that version
+    // None for storage without IDs, or entries made before IDs were recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_id: Option<String>,
+    // Generation of the first snapshot with this version of the file. 0 if it is older than the snapshots
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub since: u64,
+    // When 'clean --older-than' first found the file missing, in milliseconds since Unix Epoch. 0 if it wasn't
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub missing_since: u64,
+}
+
+/// The state of the backup after an upload run, see FileManifest::record_snapshot, or when it was named by the user
+/// A snapshot has every current entry with 'since' up to its generation, and every past version it is in
+#[derive(Serialize,Deserialize,Debug,Clone,PartialEq)]
+pub struct Snapshot {
+    // Starts at 1 and increases by 1 for each snapshot
+    pub generation: u64,
+    // When it was recorded, in milliseconds since Unix Epoch
+    pub time: u64,
+    // Set for snapshots made with 'snapshot create', which are never forgotten
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+/// A version of a file that was replaced or removed
+/// It is in the snapshots from generation 'entry.since' up to (not including) 'until'
+/// Its remote data only remains if the storage keeps old versions, see 'clean'
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct PastVersion {
+    pub entry: FileEntry,
+    pub until: u64,
+}
+
+impl FileEntry {
+    /// Records the hashes of the content that was uploaded, see moves.rs
+    pub fn set_content(&mut self, content: Content) {
+        self.sha1 = content.sha1;
+        self.fingerprint = content.fingerprint;
+    }
+
+    /// Records that delta 'name' was uploaded as 'id', see delta_id
+    pub fn push_delta(&mut self, name: String, id: Option<String>) {
+        match id {
+            Some(id) if self.delta_ids.len() == self.deltas.len() => self.delta_ids.push(id),
+            _ => self.delta_ids.clear(),
+        }
+        self.deltas.push(name);
+    }
+
+    /// ID of the upload of delta 'n', if the IDs of all deltas are known
+    pub fn delta_id(&self, n: usize) -> Option<&str> {
+        match self.delta_ids.len() == self.deltas.len() {
+            true => self.delta_ids.get(n).map(|id| id.as_str()),
+            false => None,
+        }
+    }
+
+    /// Whether the exact version of every remote file of the entry is known, s.t. restoring it never gets a newer one
+    pub fn is_pinned(&self) -> bool {
+        self.link.is_some() || (self.file_id.is_some() && self.delta_ids.len() == self.deltas.len())
+    }
+
+    /// Records that the file (or a delta of it) was just uploaded, along with its current extended attributes
+    pub fn set_uploaded(&mut self) {
+        self.uploaded = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64);
+        self.xattrs = xattrs::read(&winpath::extended(&self.path));
+    }
 }
 
 
 impl FileManifest {
+    pub fn new(mask: bool) -> Self {
+        FileManifest {
+            version: MANIFEST_VERSION,
+            mask,
+            files: vec![],
+            snapshots: vec![],
+            past: vec![],
+            nonces: 0,
+            #[cfg(feature = "sqlite")]
+            db: None,
+            touched: BTreeMap::new(),
+        }
+    }
+
+    /// Loads a manifest from a file, or from a database if the path ends with '.db'
+    /// The local manifest (see local_path) includes the uploads in the journal, which a crash kept from being saved
     pub fn from_file<T: AsRef<str>>(path: T) -> Result<Self,Box<dyn Error>> {
-        Ok(serde_json::from_slice::<Self>(&std::fs::read(path.as_ref())?)?)
+        let (mut manifest, untagged) = match path.as_ref().ends_with(".db") {
+            true => Self::from_db(path.as_ref())?,
+            false => Self::decode(&std::fs::read(path.as_ref())?)?,
+        };
+        if path.as_ref() == local_path() {
+            journal::replay(journal_path(), &mut manifest);
+            // Trusted once, from now on it has a tag
+            if untagged {
+                manifest.to_file(path.as_ref())?;
+                printcoln(Color::Yellow, "The manifest is authenticated from now on");
+            }
+        }
+        Ok(manifest)
+    }
+
+    #[cfg(feature = "sqlite")]
+    fn from_db(path: &str) -> Result<(Self, bool),Box<dyn Error>> {
+        // Opening would create it
+        if !std::path::Path::new(path).is_file() {
+            return Err(format!("{} not found", path).into());
+        }
+        let db = ManifestDb::open(path)?;
+        let (mask, files, (snapshots, past), nonces, tag) = db.load()?;
+        let mut manifest = Self::new(mask);
+        manifest.files = files;
+        manifest.snapshots = snapshots;
+        manifest.past = past;
+        manifest.nonces = nonces;
+        let untagged = verify_mac(&serde_cbor::to_vec(&manifest)?, tag.as_deref())?;
+        manifest.db = Some(db);
+        Ok((manifest, untagged))
+    }
+
+    #[cfg(not(feature = "sqlite"))]
+    fn from_db(path: &str) -> Result<(Self, bool),Box<dyn Error>> {
+        Err(format!("can't load {}, retain-rs was built without SQLite support (feature 'sqlite')", path).into())
+    }
+
+    /// Loads a manifest in any known format, migrating it to the current version
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self,Box<dyn Error>> {
+        Self::decode(bytes).map(|(manifest, _)| manifest)
+    }
+
+    // Like from_bytes, also returning whether it was loaded without a tag (see verify_mac)
+    fn decode(bytes: &[u8]) -> Result<(Self, bool),Box<dyn Error>> {
+        let (bytes, tag) = split_mac(bytes);
+        let decompressed;
+        let bytes = if bytes.starts_with(&ZSTD_MAGIC) {
+            decompressed = zstd::decode_all(bytes)?;
+            &decompressed[..]
+        } else {
+            bytes
+        };
+        let mut manifest = match bytes.iter().find(|b| !b.is_ascii_whitespace()) {
+            Some(b'{') => serde_json::from_slice::<Self>(bytes)?,
+            _ => serde_cbor::from_slice::<Self>(bytes)?,
+        };
+        let untagged = verify_mac(bytes, tag)?;
+        if manifest.version > MANIFEST_VERSION {
+            return Err(format!("manifest has format version {}, but this version of retain-rs only supports up to {}",
+                               manifest.version, MANIFEST_VERSION).into());
+        }
+        // Nothing in the entries changed between versions so far, only the encoding
+        manifest.version = MANIFEST_VERSION;
+        Ok((manifest, untagged))
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>,Box<dyn Error>> {
+        let cbor = serde_cbor::to_vec(self)?;
+        let mut bytes = zstd::encode_all(&cbor[..], COMPRESSION_LEVEL)?;
+        if let Some(tag) = mac(&cbor) {
+            bytes.extend(tag);
+            bytes.extend(&MAC_MAGIC);
+        }
+        Ok(bytes)
     }
 
+    /// Saves the manifest to a file, or to a database if the path ends with '.db'
     pub fn to_file<T: AsRef<str>>(&self, path: T) -> Result<(),Box<dyn Error>> {
-        Ok(std::fs::write(path.as_ref(),serde_json::to_vec(self)?)?)
+        paths::create_parent(path.as_ref())?;
+        if path.as_ref().ends_with(".db") {
+            self.to_db(path.as_ref())?;
+        } else {
+            // Written to a temporary file first, s.t. a failed write never leaves a partial manifest
+            paths::write_atomic(path.as_ref(), self.to_bytes()?)?;
+        }
+        // Everything in the journal is saved now, the journal is only cleared once the manifest is in place
+        if path.as_ref() == local_path() {
+            journal::clear(journal_path())?;
+        }
+        Ok(())
+    }
+
+    /// Saves the local manifest (see local_path), returning it as it is stored in remote
+    pub fn save_local(&self) -> Result<Vec<u8>,Box<dyn Error>> {
+        let bytes = self.to_bytes()?;
+        match local_path() {
+            path if path.ends_with(".db") => self.to_file(path)?,
+            path => {
+                paths::create_parent(&path)?;
+                paths::write_atomic(&path, &bytes)?;
+                journal::clear(journal_path())?;
+            },
+        }
+        Ok(bytes)
+    }
+
+    #[cfg(feature = "sqlite")]
+    fn to_db(&self, path: &str) -> Result<(),Box<dyn Error>> {
+        match &self.db {
+            Some(db) if db.path() == path => db.save(self, mac(&serde_cbor::to_vec(self)?)),
+            _ => ManifestDb::open(path)?.save(self, mac(&serde_cbor::to_vec(self)?)),
+        }
+    }
+
+    #[cfg(not(feature = "sqlite"))]
+    fn to_db(&self, path: &str) -> Result<(),Box<dyn Error>> {
+        Err(format!("can't save {}, retain-rs was built without SQLite support (feature 'sqlite')", path).into())
     }
 
     /// Returns the mask used for the given path
     /// If no entry exists, a new mask is generated
     /// If we aren't encrypting (self.mask is false), we use a B2-friendly name instead
     pub fn get_mask<T: AsRef<str>>(&mut self, path: T, timestamp: u64) -> (u64,String) {
+        self.touch(path.as_ref());
         match self.files.binary_search_by(|e| (e.path[..]).cmp(path.as_ref())) {
             Ok(n) => (self.files[n].timestamp,self.files[n].mask.to_string()),
             Err(n) => {
                 let rng = thread_rng();
                 let new_mask = match self.mask {
                     true => rng.sample_iter(Alphanumeric).take(MASK_SIZE).collect(),
-                    false => {
-                        // On Unix-whatever, everything is prefixed with the '/' root
-                        // B2's web interface will not emulate folders unless we strip it
-                        let p;
-                        if cfg!(windows) {
-                            p = path.as_ref().to_string();
-                        } else {
-                            p = path.as_ref()[1..].to_string();
-                        }
-                        p.replace("\\","/") // Standardize separators
-                    }
+                    false => winpath::remote_name(path.as_ref()),
                 };
                 self.files.insert(n, FileEntry {
                     path: path.as_ref().to_string(),
                     timestamp,
                     mask: new_mask,
+                    size: 0,
+                    sha1: String::new(),
+                    fingerprint: String::new(),
+                    blocks: vec![],
+                    deltas: vec![],
+                    delta_ids: vec![],
+                    compressed: false,
+                    uploaded: 0,
+                    link: None,
+                    sparse: vec![],
+                    xattrs: vec![],
+                    file_id: None,
+                    since: 0,
+                    missing_since: 0,
                 });
                 (timestamp,self.files[n].mask.to_string())
             },
@@ -82,12 +568,214 @@ impl FileManifest {
 
     // If an entry with the supplied path exists, update its timestamp to the supplied value
     pub fn update_timestamp<T: AsRef<str>>(&mut self, path: T, timestamp: u64) {
+        self.touch(path.as_ref());
         match self.files.binary_search_by(|e| (e.path[..]).cmp(path.as_ref())) {
             Ok(n) => self.files[n].timestamp = timestamp,
             Err(_) => (),
         };
     }
 
+    // If an entry with the supplied path exists, update its size to the supplied value
+    pub fn update_size<T: AsRef<str>>(&mut self, path: T, size: u64) {
+        self.touch(path.as_ref());
+        match self.files.binary_search_by(|e| (e.path[..]).cmp(path.as_ref())) {
+            Ok(n) => self.files[n].size = size,
+            Err(_) => (),
+        };
+    }
+
+    // Returns the entry with the given path, if it exists
+    pub fn get_entry_mut<T: AsRef<str>>(&mut self, path: T) -> Option<&mut FileEntry> {
+        self.touch(path.as_ref());
+        match self.files.binary_search_by(|e| (e.path[..]).cmp(path.as_ref())) {
+            Ok(n) => Some(&mut self.files[n]),
+            Err(_) => None,
+        }
+    }
+
+    // Returns the entry with the given path, if it exists
+    pub fn get_entry<T: AsRef<str>>(&self, path: T) -> Option<&FileEntry> {
+        match self.files.binary_search_by(|e| (e.path[..]).cmp(path.as_ref())) {
+            Ok(n) => Some(&self.files[n]),
+            Err(_) => None,
+        }
+    }
+
+    /// Records 'path' as a hard link to 'target', unless it already is with the same modified time
+    pub fn set_link<T: AsRef<str>>(&mut self, path: T, target: &str, timestamp: u64, size: u64) {
+        self.get_mask(path.as_ref(), timestamp);
+        let entry = self.get_entry_mut(path).unwrap();
+        if entry.link.as_deref() == Some(target) && entry.timestamp == timestamp {
+            return;
+        }
+        entry.link = Some(target.to_string());
+        entry.timestamp = timestamp;
+        entry.size = size;
+        entry.set_content(Content::default());
+        entry.blocks.clear();
+        entry.deltas.clear();
+        entry.delta_ids.clear();
+        entry.compressed = false;
+        entry.sparse.clear();
+        entry.set_uploaded();
+    }
+
+    /// If 'path' was recorded as a hard link, forgets it s.t. the file is uploaded again
+    pub fn clear_link<T: AsRef<str>>(&mut self, path: T) {
+        if let Some(entry) = self.get_entry_mut(path) {
+            if entry.link.take().is_some() {
+                entry.timestamp = 0;
+            }
+        }
+    }
+
+    /// Moves the entry of 'from' to 'to', replacing the entry of 'to' if there is one
+    /// It keeps its mask, s.t. the remote files of 'from' now belong to 'to'. Returns false if 'from' has no entry
+    pub fn relocate<T: AsRef<str>>(&mut self, from: T, to: &str) -> bool {
+        self.touch(from.as_ref());
+        self.touch(to);
+        let mut entry = match self.files.binary_search_by(|e| (e.path[..]).cmp(from.as_ref())) {
+            Ok(n) => self.files.remove(n),
+            Err(_) => return false,
+        };
+        entry.path = to.to_string();
+        match self.files.binary_search_by(|e| (e.path[..]).cmp(to)) {
+            Ok(n) => self.files[n] = entry,
+            Err(n) => self.files.insert(n, entry),
+        }
+        true
+    }
+
+    /// Records that 'allocated' nonces were allocated, see Config::check_nonces. The record never goes down
+    pub fn record_nonces(&mut self, allocated: u128) {
+        self.nonces = self.nonces.max(allocated);
+    }
+
+    /// Adds 'entry', replacing the entry with its path if there is one
+    pub fn put_entry(&mut self, entry: FileEntry) {
+        self.touch(&entry.path);
+        match self.files.binary_search_by(|e| (e.path[..]).cmp(&entry.path)) {
+            Ok(n) => self.files[n] = entry,
+            Err(n) => self.files.insert(n, entry),
+        }
+    }
+
+    /// Generation of the next snapshot
+    pub fn next_generation(&self) -> u64 {
+        self.snapshots.last().map_or(1, |s| s.generation + 1)
+    }
+
+    // Remembers the entry of 'path' as it is before it changes, see record_changes
+    fn touch(&mut self, path: &str) {
+        if !self.touched.contains_key(path) {
+            let entry = self.get_entry(path).cloned();
+            self.touched.insert(path.to_string(), entry);
+        }
+    }
+
+    /// Marks the entries that changed since the last snapshot as starting at the next one, and keeps the versions
+    /// they replaced as past versions if a snapshot has them, without recording a snapshot (e.g. for an interrupted upload)
+    /// The next snapshot that is recorded has the changes
+    pub fn record_changes(&mut self) {
+        let generation = self.next_generation();
+        for (path, old) in std::mem::take(&mut self.touched) {
+            let current = self.files.binary_search_by(|e| e.path.as_str().cmp(&path)).ok();
+            if let (Some(o), Some(n)) = (&old, current) {
+                let entry = &self.files[n];
+                if (&o.mask, o.uploaded, &o.link) == (&entry.mask, entry.uploaded, &entry.link) {
+                    continue;
+                }
+            }
+            if let Some(n) = current {
+                self.files[n].since = generation;
+            }
+            // Versions made since the last snapshot aren't in any
+            if let Some(mut entry) = old.filter(|o| o.since < generation && !self.snapshots.is_empty()) {
+                // Only uploads use the block hashes
+                entry.blocks.clear();
+                self.past.push(PastVersion { entry, until: generation });
+            }
+        }
+    }
+
+    /// Records a snapshot of the files as they are now, if anything changed since the last one (see record_changes)
+    /// Versions that were replaced or removed since are kept as past versions, as earlier snapshots have them
+    /// Unnamed snapshots beyond the newest SNAPSHOT_LIMIT are forgotten
+    /// Returns the generation of the snapshot, None if nothing changed
+    pub fn record_snapshot(&mut self, time: u64) -> Option<u64> {
+        self.record_changes();
+        let generation = self.next_generation();
+        let changed = match self.snapshots.is_empty() {
+            true => !self.files.is_empty(),
+            false => self.files.iter().any(|e| e.since == generation) || self.past.iter().any(|p| p.until == generation),
+        };
+        if !changed {
+            return None;
+        }
+        self.snapshots.push(Snapshot { generation, time, name: None });
+        let unnamed: Vec<u64> = self.snapshots.iter().filter(|s| s.name.is_none()).map(|s| s.generation).collect();
+        if unnamed.len() > SNAPSHOT_LIMIT {
+            self.forget_snapshots(&unnamed[..unnamed.len() - SNAPSHOT_LIMIT].iter().copied().collect());
+        }
+        Some(generation)
+    }
+
+    /// Records a snapshot of the files as they are now named 'name', which must not be taken yet
+    /// Returns its generation
+    pub fn create_snapshot(&mut self, name: &str, time: u64) -> Result<u64, String> {
+        if name.is_empty() {
+            return Err("a snapshot name can't be empty".to_string());
+        }
+        if self.named_snapshot(name).is_some() {
+            return Err(format!("there already is a snapshot named '{}'", name));
+        }
+        // Entries retired since the latest snapshot already end at this generation
+        let generation = self.next_generation();
+        self.snapshots.push(Snapshot { generation, time, name: Some(name.to_string()) });
+        Ok(generation)
+    }
+
+    /// The snapshot named 'name', if there is one
+    pub fn named_snapshot(&self, name: &str) -> Option<&Snapshot> {
+        self.snapshots.iter().find(|s| s.name.as_deref() == Some(name))
+    }
+
+    /// The latest snapshot recorded at or before 'time' (ms since Unix Epoch)
+    pub fn snapshot_as_of(&self, time: u64) -> Option<&Snapshot> {
+        self.snapshots.iter().rev().find(|s| s.time <= time)
+    }
+
+    /// The files in the snapshot 'generation', sorted by path
+    pub fn snapshot_files(&self, generation: u64) -> Vec<FileEntry> {
+        let mut files: Vec<FileEntry> = self.files.iter()
+            .filter(|e| e.since <= generation)
+            .chain(self.past.iter().filter(|p| p.entry.since <= generation && generation < p.until).map(|p| &p.entry))
+            .cloned()
+            .collect();
+        files.sort_unstable_by(|a, b| Ord::cmp(&a.path, &b.path));
+        files
+    }
+
+    /// Removes the entry at 'index', keeping it as a past version if a snapshot has it
+    pub fn retire(&mut self, index: usize) {
+        let path = self.files[index].path.clone();
+        self.touch(&path);
+        self.files.remove(index);
+        self.record_changes();
+    }
+
+    /// Forgets the snapshots with the given generations, except the latest and named ones, and the past versions only they had
+    /// Returns the past versions that were forgotten
+    pub fn forget_snapshots(&mut self, generations: &HashSet<u64>) -> Vec<FileEntry> {
+        let latest = self.snapshots.last().map(|s| s.generation);
+        self.snapshots.retain(|s| Some(s.generation) == latest || s.name.is_some() || !generations.contains(&s.generation));
+        let kept: Vec<u64> = self.snapshots.iter().map(|s| s.generation).collect();
+        let (past, forgotten): (Vec<PastVersion>, Vec<PastVersion>) = std::mem::take(&mut self.past).into_iter()
+            .partition(|p| kept.iter().any(|g| p.entry.since <= *g && *g < p.until));
+        self.past = past;
+        forgotten.into_iter().map(|p| p.entry).collect()
+    }
+
     // Returns (timestamp,mask) if an entry with the given path exists, otherwise None
     pub fn get_from_path<T: AsRef<str>>(&mut self, path: T) -> Option<(u64,String)> {
         match self.files.binary_search_by(|e| (e.path[..].cmp(path.as_ref()))) {
@@ -109,6 +797,7 @@ impl FileManifest {
     // Remove the entry matching the given path, if it exists
     #[allow(dead_code)]
     pub fn remove_path<T: AsRef<str>>(&mut self, path: T) {
+        self.touch(path.as_ref());
         let idx = self.files.binary_search_by(|e| (e.path[..]).cmp(path.as_ref()));
         match idx {
             Ok(n) => self.files.remove(n),
@@ -120,6 +809,10 @@ impl FileManifest {
     #[allow(dead_code)]
     pub fn remove_mask<T: AsRef<str>>(&mut self, mask: T) {
         let idx = self.files.binary_search_by(|e| (e.mask[..]).cmp(mask.as_ref()));
+        if let Ok(n) = idx {
+            let path = self.files[n].path.clone();
+            self.touch(&path);
+        }
         match idx {
             Ok(n) => self.files.remove(n),
             Err(_) => return,
@@ -129,14 +822,12 @@ impl FileManifest {
 
 #[cfg(test)]
 mod tests {
-    use crate::manifest::{FileManifest, MASK_SIZE};
+    use crate::manifest::{FileManifest, FileEntry, SNAPSHOT_LIMIT, MASK_SIZE, MANIFEST_VERSION, MAC_MAGIC, hmac, split_mac};
+    use hmac::Mac;
 
     #[test]
     fn test_masking() {
-        let mut fm = FileManifest {
-            files: vec![],
-            mask: true
-        };
+        let mut fm = FileManifest::new(true);
         let mask = fm.get_mask("file.txt", 4908);
         assert_eq!(mask.1.len(),MASK_SIZE);
         let mask2 = fm.get_mask("file.txt", 4908);
@@ -159,10 +850,7 @@ mod tests {
 
     #[test]
     fn test_nomask() {
-        let mut fm = FileManifest {
-            files: vec![],
-            mask: false
-        };
+        let mut fm = FileManifest::new(false);
         let mask = fm.get_mask("file.txt", 4908);
         if cfg!(windows) {
             assert_eq!(mask.1.len(), "file.txt".len());
@@ -172,4 +860,178 @@ mod tests {
             assert_eq!("ile.txt", mask.1);
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_relocate() {
+        let mut fm = FileManifest::new(true);
+        let (_, mask) = fm.get_mask("/home/a.txt", 5);
+        fm.get_mask("/home/b.txt", 6);
+        assert!(fm.relocate("/home/a.txt", "/home/z/a.txt"));
+        assert!(fm.get_entry("/home/a.txt").is_none());
+        assert_eq!(fm.get_entry("/home/z/a.txt").unwrap().mask, mask);
+        // Entries stay sorted by path
+        assert_eq!(fm.files.iter().map(|e| e.path.as_str()).collect::<Vec<_>>(), vec!["/home/b.txt", "/home/z/a.txt"]);
+        assert!(!fm.relocate("/home/a.txt", "/home/c.txt"));
+    }
+
+    #[test]
+    fn test_formats() {
+        let mut fm = FileManifest::new(true);
+        for i in 0..1000 {
+            fm.get_mask(format!("/home/user/documents/file{}.txt", i), i);
+        }
+        let bytes = fm.to_bytes().unwrap();
+        // Version 0 manifests, as written by older versions
+        fm.version = 0;
+        let json = serde_json::to_vec(&fm).unwrap();
+        let compressed_json = zstd::encode_all(&json[..], 3).unwrap();
+        assert!(bytes.len() < json.len());
+
+        for bytes in vec![bytes, json, compressed_json] {
+            let loaded = FileManifest::from_bytes(&bytes).unwrap();
+            assert_eq!(loaded.version, MANIFEST_VERSION);
+            assert_eq!(loaded.files.len(), 1000);
+            assert_eq!(loaded.files[0].mask, fm.files[0].mask);
+        }
+
+        fm.version = MANIFEST_VERSION + 1;
+        assert!(FileManifest::from_bytes(&fm.to_bytes().unwrap()).is_err());
+
+        // The nonce record may not fit in a CBOR or JSON number
+        fm.version = MANIFEST_VERSION;
+        for nonces in [65536, u128::MAX] {
+            fm.record_nonces(nonces);
+            assert_eq!(FileManifest::from_bytes(&fm.to_bytes().unwrap()).unwrap().nonces, nonces);
+            assert_eq!(FileManifest::from_bytes(&serde_json::to_vec(&fm).unwrap()).unwrap().nonces, nonces);
+        }
+        fm.record_nonces(0);
+        assert_eq!(fm.nonces, u128::MAX);
+    }
+
+    #[test]
+    fn test_authentication() {
+        let content = b"manifest content".to_vec();
+        let tag = hmac(b"key", &content).finalize().into_bytes().to_vec();
+        let mut bytes = content.clone();
+        bytes.extend(&tag);
+        bytes.extend(&MAC_MAGIC);
+
+        let (split_content, split_tag) = split_mac(&bytes);
+        assert_eq!(split_content, &content[..]);
+        assert!(hmac(b"key", split_content).verify(split_tag.unwrap()).is_ok());
+        assert!(hmac(b"other key", split_content).verify(split_tag.unwrap()).is_err());
+        assert!(hmac(b"key", b"modified content").verify(split_tag.unwrap()).is_err());
+        // Unauthenticated manifests are left as is
+        assert_eq!(split_mac(&content), (&content[..], None));
+    }
+
+    #[test]
+    fn test_snapshots() {
+        let mut fm = FileManifest::new(false);
+        for path in &["/a.txt", "/b.txt", "/c.txt"] {
+            fm.get_mask(path, 1);
+            fm.get_entry_mut(path).unwrap().uploaded = 1;
+            fm.get_entry_mut(path).unwrap().blocks = vec!["block".to_string()];
+        }
+        assert_eq!(fm.record_snapshot(1000), Some(1));
+        // Nothing changed
+        fm.get_entry_mut("/a.txt").unwrap();
+        assert_eq!(fm.record_snapshot(2000), None);
+
+        // b.txt is uploaded again, c.txt is removed and d.txt is added
+        fm.get_entry_mut("/b.txt").unwrap().uploaded = 2;
+        fm.remove_path("/c.txt");
+        fm.get_mask("/d.txt", 2);
+        assert_eq!(fm.record_snapshot(3000), Some(2));
+        let paths = |files: Vec<FileEntry>| files.iter().map(|e| (e.path.clone(), e.uploaded)).collect::<Vec<_>>();
+        assert_eq!(paths(fm.snapshot_files(1)), vec![("/a.txt".to_string(), 1), ("/b.txt".to_string(), 1), ("/c.txt".to_string(), 1)]);
+        assert_eq!(paths(fm.snapshot_files(2)), vec![("/a.txt".to_string(), 1), ("/b.txt".to_string(), 2), ("/d.txt".to_string(), 0)]);
+        // Past versions don't keep the block hashes of delta uploads
+        assert_eq!(fm.past.len(), 2);
+        assert!(fm.past.iter().all(|p| p.entry.blocks.is_empty()));
+        assert_eq!(fm.snapshot_as_of(2500).unwrap().generation, 1);
+        assert!(fm.snapshot_as_of(500).is_none());
+
+        // A retired entry stays in the snapshots that have it
+        let a = fm.files.binary_search_by(|e| e.path.as_str().cmp("/a.txt")).unwrap();
+        fm.retire(a);
+        assert_eq!(fm.snapshot_files(2).len(), 3);
+
+        // Forgetting snapshot 1 forgets the versions only it had, the latest is always kept
+        let forgotten = fm.forget_snapshots(&[1, 2].iter().copied().collect());
+        assert_eq!(paths(forgotten), vec![("/b.txt".to_string(), 1), ("/c.txt".to_string(), 1)]);
+        assert_eq!(fm.snapshots.len(), 1);
+        assert_eq!(fm.snapshot_files(2).len(), 3);
+    }
+
+    #[test]
+    fn test_named_snapshots() {
+        let mut fm = FileManifest::new(false);
+        for path in &["/a.txt", "/b.txt"] {
+            fm.get_mask(path, 1);
+        }
+        assert_eq!(fm.record_snapshot(1000), Some(1));
+        // A named snapshot has the files as they are now, even if no upload ran since the latest snapshot
+        fm.retire(0);
+        assert_eq!(fm.create_snapshot("pre-upgrade", 2000), Ok(2));
+        assert!(fm.create_snapshot("pre-upgrade", 3000).is_err());
+        assert!(fm.create_snapshot("", 3000).is_err());
+        assert_eq!(fm.named_snapshot("pre-upgrade").unwrap().generation, 2);
+        assert_eq!(fm.snapshot_files(1).len(), 2);
+        assert_eq!(fm.snapshot_files(2).len(), 1);
+
+        // Named snapshots are never forgotten
+        fm.get_mask("/c.txt", 2);
+        assert_eq!(fm.record_snapshot(4000), Some(3));
+        fm.forget_snapshots(&[1, 2, 3].iter().copied().collect());
+        assert_eq!(fm.snapshots.iter().map(|s| s.generation).collect::<Vec<u64>>(), vec![2, 3]);
+        assert_eq!(fm.snapshot_files(2).len(), 1);
+    }
+
+    #[test]
+    fn test_interrupted_snapshot() {
+        let mut fm = FileManifest::new(false);
+        fm.get_mask("/a.txt", 1);
+        assert_eq!(fm.record_snapshot(1000), Some(1));
+        // An interrupted upload records no snapshot, the next one has its changes
+        fm.get_entry_mut("/a.txt").unwrap().uploaded = 2;
+        fm.record_changes();
+        assert_eq!(fm.snapshots.len(), 1);
+        assert_eq!(fm.snapshot_files(1)[0].uploaded, 0);
+        fm.get_entry_mut("/a.txt").unwrap().uploaded = 3;
+        assert_eq!(fm.record_snapshot(2000), Some(2));
+        // The version of the interrupted upload was in no snapshot
+        assert_eq!(fm.past.len(), 1);
+        assert_eq!(fm.snapshot_files(2)[0].uploaded, 3);
+        // Fields that are 0 are left out, s.t. entries serialize as they did before the fields existed
+        assert!(!serde_json::to_string(&fm.past[0].entry).unwrap().contains("missing_since"));
+    }
+
+    #[test]
+    fn test_delta_ids() {
+        let mut fm = FileManifest::new(false);
+        fm.get_mask("/big.bin", 1);
+        let entry = fm.get_entry_mut("/big.bin").unwrap();
+        entry.file_id = Some("full".to_string());
+        entry.push_delta("big.bin.delta1".to_string(), Some("d1".to_string()));
+        assert_eq!(entry.delta_id(0), Some("d1"));
+        assert!(entry.is_pinned());
+        // Once the ID of one is unknown, the deltas are downloaded by name
+        entry.push_delta("big.bin.delta2".to_string(), None);
+        entry.push_delta("big.bin.delta3".to_string(), Some("d3".to_string()));
+        assert_eq!((entry.delta_id(0), entry.delta_id(2)), (None, None));
+        assert!(!entry.is_pinned());
+    }
+
+    #[test]
+    fn test_snapshot_limit() {
+        let mut fm = FileManifest::new(false);
+        fm.get_mask("/a.txt", 1);
+        for time in 0..SNAPSHOT_LIMIT as u64 + 5 {
+            fm.get_entry_mut("/a.txt").unwrap().uploaded = time + 1;
+            fm.record_snapshot(time);
+        }
+        assert_eq!(fm.snapshots.len(), SNAPSHOT_LIMIT);
+        assert_eq!(fm.past.len(), SNAPSHOT_LIMIT - 1);
+    }
+}
@@ -0,0 +1,180 @@
+//! SQLite storage for the local manifest, used instead of manifest.json when manifest.db exists
+//! Only available when built with the 'sqlite' feature
+//!
+//! Each entry is a row, indexed by path and by mask. The manifest's authentication tag, if any, is kept in 'meta'
+//! Snapshots and the past versions they refer to are kept in 'meta' as well, as a single CBOR value, and so is the nonce record
+//! Saving only writes entries that changed since the manifest was loaded or last saved, in a single
+//! transaction. With WAL journaling, an interrupted save leaves the previous state intact
+//!
+//! The remote manifest is unaffected: it is always uploaded in the regular format
+
+use rusqlite::{params, Connection, OptionalExtension, NO_PARAMS};
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::error::Error;
+use std::hash::Hasher;
+use std::sync::Mutex;
+use crate::manifest::{FileManifest, FileEntry, Snapshot, PastVersion, MANIFEST_VERSION};
+
+pub struct ManifestDb {
+    path: String,
+    conn: Mutex<Connection>,
+    // Fingerprint of each entry as stored in the database. None if unknown, which rewrites every entry
+    saved: Mutex<Option<HashMap<String, u64>>>,
+}
+
+impl std::fmt::Debug for ManifestDb {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ManifestDb({})", self.path)
+    }
+}
+
+impl ManifestDb {
+    /// Opens the database at 'path', creating it if it doesn't exist
+    pub fn open(path: &str) -> Result<Self, Box<dyn Error>> {
+        let conn = Connection::open(path)?;
+        // Returns the new mode as a row
+        conn.query_row("PRAGMA journal_mode=WAL", NO_PARAMS, |_| Ok(()))?;
+        conn.execute_batch("PRAGMA synchronous=NORMAL;
+            CREATE TABLE IF NOT EXISTS meta (key TEXT PRIMARY KEY, value NOT NULL);
+            CREATE TABLE IF NOT EXISTS files (path TEXT PRIMARY KEY, mask TEXT NOT NULL, entry BLOB NOT NULL);
+            CREATE INDEX IF NOT EXISTS files_mask ON files (mask);")?;
+        Ok(ManifestDb {
+            path: path.to_string(),
+            conn: Mutex::new(conn),
+            saved: Mutex::new(None),
+        })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Loads every entry, sorted by path like FileManifest expects, the snapshots and past versions,
+    /// the nonces recorded as allocated and the authentication tag if there is one
+    #[allow(clippy::type_complexity)]
+    pub fn load(&self) -> Result<(bool, Vec<FileEntry>, (Vec<Snapshot>, Vec<PastVersion>), u128, Option<Vec<u8>>), Box<dyn Error>> {
+        let conn = self.conn.lock().unwrap();
+        let version: Option<i64> = conn.query_row("SELECT value FROM meta WHERE key = 'version'", NO_PARAMS, |r| r.get(0)).optional()?;
+        if version.unwrap_or(0) > MANIFEST_VERSION as i64 {
+            return Err(format!("manifest has format version {}, but this version of retain-rs only supports up to {}",
+                               version.unwrap(), MANIFEST_VERSION).into());
+        }
+        let mask: Option<i64> = conn.query_row("SELECT value FROM meta WHERE key = 'mask'", NO_PARAMS, |r| r.get(0)).optional()?;
+        let mask = match mask {
+            Some(m) => m != 0,
+            None => return Err(format!("{} does not contain a manifest", self.path).into()),
+        };
+        let tag: Option<Vec<u8>> = conn.query_row("SELECT value FROM meta WHERE key = 'mac'", NO_PARAMS, |r| r.get(0)).optional()?;
+        let history: Option<Vec<u8>> = conn.query_row("SELECT value FROM meta WHERE key = 'snapshots'", NO_PARAMS, |r| r.get(0)).optional()?;
+        let history = match history {
+            Some(blob) => serde_cbor::from_slice(&blob)?,
+            None => (vec![], vec![]),
+        };
+        // A u128 doesn't fit in an SQLite integer
+        let nonces: Option<String> = conn.query_row("SELECT value FROM meta WHERE key = 'nonces'", NO_PARAMS, |r| r.get(0)).optional()?;
+        let nonces = nonces.map_or(Ok(0), |n| n.parse::<u128>())?;
+
+        let mut files = Vec::new();
+        let mut saved = HashMap::new();
+        let mut query = conn.prepare("SELECT entry FROM files ORDER BY path")?;
+        let mut rows = query.query(NO_PARAMS)?;
+        while let Some(row) = rows.next()? {
+            let blob: Vec<u8> = row.get(0)?;
+            let entry: FileEntry = serde_cbor::from_slice(&blob)?;
+            saved.insert(entry.path.clone(), fingerprint(&blob));
+            files.push(entry);
+        }
+        *self.saved.lock().unwrap() = Some(saved);
+        Ok((mask, files, history, nonces, tag))
+    }
+
+    /// Writes the entries of 'manifest' that changed since the last load or save, and removes deleted entries
+    /// 'tag' replaces the stored authentication tag
+    pub fn save(&self, manifest: &FileManifest, tag: Option<Vec<u8>>) -> Result<(), Box<dyn Error>> {
+        let mut conn = self.conn.lock().unwrap();
+        let mut saved = self.saved.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute("INSERT OR REPLACE INTO meta (key, value) VALUES ('version', ?1), ('mask', ?2)",
+                   params![MANIFEST_VERSION as i64, manifest.mask as i64])?;
+        match tag {
+            Some(tag) => tx.execute("INSERT OR REPLACE INTO meta (key, value) VALUES ('mac', ?1)", params![tag])?,
+            None => tx.execute("DELETE FROM meta WHERE key = 'mac'", NO_PARAMS)?,
+        };
+        let history = serde_cbor::to_vec(&(&manifest.snapshots, &manifest.past))?;
+        tx.execute("INSERT OR REPLACE INTO meta (key, value) VALUES ('snapshots', ?1)", params![history])?;
+        tx.execute("INSERT OR REPLACE INTO meta (key, value) VALUES ('nonces', ?1)", params![manifest.nonces.to_string()])?;
+        // Taken s.t. a failed save is followed by a full rewrite
+        let previous = match saved.take() {
+            Some(s) => s,
+            None => {
+                tx.execute("DELETE FROM files", NO_PARAMS)?;
+                HashMap::new()
+            }
+        };
+
+        let mut current = HashMap::with_capacity(manifest.files.len());
+        {
+            let mut insert = tx.prepare("INSERT OR REPLACE INTO files (path, mask, entry) VALUES (?1, ?2, ?3)")?;
+            for entry in &manifest.files {
+                let blob = serde_cbor::to_vec(entry)?;
+                let hash = fingerprint(&blob);
+                if previous.get(&entry.path) != Some(&hash) {
+                    insert.execute(params![entry.path, entry.mask, blob])?;
+                }
+                current.insert(entry.path.clone(), hash);
+            }
+            let mut delete = tx.prepare("DELETE FROM files WHERE path = ?1")?;
+            for path in previous.keys().filter(|p| !current.contains_key(*p)) {
+                delete.execute(params![path])?;
+            }
+        }
+        tx.commit()?;
+        *saved = Some(current);
+        Ok(())
+    }
+}
+
+fn fingerprint(bytes: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(bytes);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::manifest::FileManifest;
+
+    #[test]
+    fn test_save_load() {
+        let path = std::env::temp_dir().join("retain-test-manifest.db");
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+
+        let mut fm = FileManifest::new(true);
+        for i in 0..100 {
+            fm.get_mask(format!("/home/user/file{}.txt", i), i);
+        }
+        fm.to_file(path).unwrap();
+
+        // Changes after loading are written incrementally
+        let mut loaded = FileManifest::from_file(path).unwrap();
+        assert_eq!(loaded.files.len(), 100);
+        assert_eq!(loaded.files[5].mask, fm.files[5].mask);
+        loaded.update_timestamp("/home/user/file5.txt", 12345);
+        loaded.remove_path("/home/user/file7.txt");
+        loaded.get_mask("/home/user/new.txt", 1);
+        loaded.to_file(path).unwrap();
+
+        let reloaded = FileManifest::from_file(path).unwrap();
+        assert_eq!(reloaded.files.len(), 100);
+        assert!(reloaded.mask);
+        assert_eq!(reloaded.files.iter().find(|e| e.path == "/home/user/file5.txt").unwrap().timestamp, 12345);
+        assert!(reloaded.files.iter().all(|e| e.path != "/home/user/file7.txt"));
+        assert!(reloaded.files.iter().any(|e| e.path == "/home/user/new.txt"));
+
+        drop(loaded);
+        drop(reloaded);
+        let _ = std::fs::remove_file(path);
+    }
+}
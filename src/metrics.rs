@@ -0,0 +1,127 @@
+//! Metrics of the last runs in the Prometheus text format, for the textfile collector of node_exporter
+//!
+//! With 'config --metrics_file', every upload, download and clean writes its metrics there when it ends
+//! Point it into node_exporter's --collector.textfile.directory, with a name ending in .prom
+//!
+//! Each run only replaces its own values (labelled run="upload" etc.), the values of other runs are kept
+//! The time of the last successful run is kept as well when a run fails, s.t. an alert can fire if it gets too old
+
+use crate::notify::Report;
+use crate::paths;
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Name and help text of every metric, in the order they are written
+// Each is a gauge labelled with the run it belongs to, except for the amount of tracked files
+const METRICS: [(&str, &str); 8] = [
+    ("retain_last_run_timestamp_seconds", "Time the last run ended"),
+    ("retain_last_success_timestamp_seconds", "Time the last run without failures ended"),
+    ("retain_last_run_success", "Whether the last run finished without failures"),
+    ("retain_last_run_duration_seconds", "How long the last run took"),
+    ("retain_last_run_bytes_transferred", "Bytes uploaded or downloaded by the last run"),
+    ("retain_last_run_files_succeeded", "Files uploaded, downloaded or removed by the last run"),
+    ("retain_last_run_files_failed", "Files that failed in the last run"),
+    ("retain_tracked_files", "Files in the manifest after the last upload"),
+];
+
+/// Writes the metrics of the run to the file at 'path', keeping those of other runs
+pub fn write(path: &str, report: &Report) -> Result<(), String> {
+    let existing = std::fs::read_to_string(path).unwrap_or_default();
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let contents = render(&existing, report, now);
+    // Written to a temporary file first, s.t. node_exporter never reads half a file
+    paths::create_parent(path).map_err(|e| e.to_string())?;
+    paths::write_atomic(path, contents).map_err(|e| e.to_string())
+}
+
+// The contents of the metrics file after the run, given its current contents
+// Values are keyed by metric name and run, the run is empty for unlabelled metrics
+fn render(existing: &str, report: &Report, now: u64) -> String {
+    let mut values: BTreeMap<(String, String), String> = existing.lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(parse_line)
+        .collect();
+    let mut set = |name: &str, run: &str, value: String| {
+        values.insert((name.to_string(), run.to_string()), value);
+    };
+    let run = report.run;
+    set("retain_last_run_timestamp_seconds", run, now.to_string());
+    if report.success {
+        set("retain_last_success_timestamp_seconds", run, now.to_string());
+    }
+    set("retain_last_run_success", run, (report.success as u8).to_string());
+    set("retain_last_run_duration_seconds", run, report.seconds.to_string());
+    set("retain_last_run_bytes_transferred", run, report.bytes.unwrap_or(0).to_string());
+    set("retain_last_run_files_succeeded", run, report.done.to_string());
+    set("retain_last_run_files_failed", run, report.failed.len().to_string());
+    if let Some(tracked) = report.tracked {
+        set("retain_tracked_files", "", tracked.to_string());
+    }
+
+    let mut text = String::new();
+    for (name, help) in METRICS.iter() {
+        let lines: Vec<_> = values.iter().filter(|((n, _), _)| n == name).collect();
+        if lines.is_empty() {
+            continue;
+        }
+        text.push_str(&format!("# HELP {} {}\n# TYPE {} gauge\n", name, help, name));
+        for ((_, run), value) in lines {
+            match run.as_str() {
+                "" => text.push_str(&format!("{} {}\n", name, value)),
+                run => text.push_str(&format!("{}{{run=\"{}\"}} {}\n", name, run, value)),
+            }
+        }
+    }
+    text
+}
+
+// Parses a line written by 'render', e.g. 'retain_last_run_success{run="upload"} 1'
+fn parse_line(line: &str) -> Option<((String, String), String)> {
+    let (metric, value) = line.trim().rsplit_once(' ')?;
+    let (name, run) = match metric.split_once('{') {
+        Some((name, labels)) => (name, labels.strip_prefix("run=\"")?.strip_suffix("\"}")?),
+        None => (metric, ""),
+    };
+    Some(((name.to_string(), run.to_string()), value.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metrics::render;
+    use crate::notify::Report;
+
+    #[test]
+    fn test_render() {
+        let mut report = Report {
+            run: "upload",
+            success: true,
+            completed: true,
+            bucket: None,
+            job: None,
+            scanned: 10,
+            excluded: 0,
+            unchanged: 8,
+            done: 2,
+            failed: vec![],
+            bytes: Some(4096),
+            seconds: 12,
+            nonces: 0,
+            tracked: Some(10),
+        };
+        let text = render("", &report, 1000);
+        assert!(text.contains("# TYPE retain_last_run_success gauge\nretain_last_run_success{run=\"upload\"} 1\n"));
+        assert!(text.contains("retain_last_success_timestamp_seconds{run=\"upload\"} 1000\n"));
+        assert!(text.contains("retain_tracked_files 10\n"));
+
+        // A failed download keeps the values of the upload
+        report.run = "download";
+        report.success = false;
+        report.tracked = None;
+        let text = render(&text, &report, 2000);
+        assert!(text.contains("retain_last_success_timestamp_seconds{run=\"upload\"} 1000\n"));
+        assert!(!text.contains("retain_last_success_timestamp_seconds{run=\"download\"}"));
+        assert!(text.contains("retain_last_run_timestamp_seconds{run=\"download\"} 2000\n"));
+        assert!(text.contains("retain_tracked_files 10\n"));
+        assert_eq!(text.matches("# HELP").count(), 8);
+    }
+}
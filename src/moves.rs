@@ -0,0 +1,305 @@
+//! Detection of moved and renamed files
+//!
+//! Every upload records a quick fingerprint (size, head and tail) and the SHA1 of the file's content in the manifest.
+//! A new path with the same size as an entry whose file no longer exists is fingerprinted, and only if that matches
+//! is it hashed in full. If the hashes match too, the file was moved and isn't uploaded again:
+//!
+//! * With masked names, the entry is relocated to the new path. Its mask doesn't depend on the path, so the remote
+//!   files are reused as they are and nothing is sent
+//! * Otherwise the remote name follows the path, the files are copied within the bucket (b2_copy_file) to the new
+//!   path's name. The remote files of the old path are no longer referenced and are removed by 'clean'
+//!
+//! Either way the entry keeps its deltas, compression and sparse extents. Renaming a directory then costs at most a copy per file
+
+use crate::b2::MAX_COPY_SIZE;
+use crate::manifest::FileManifest;
+use crate::winpath;
+use crate::shadow;
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+// Amount of bytes at the start and at the end of a file that its fingerprint covers
+const FINGERPRINT_SPAN: u64 = 64*1024;
+
+// A vanished entry that a new file may have been moved from
+struct Candidate {
+    path: String,
+    fingerprint: String,
+    sha1: String,
+}
+
+/// Entries of the manifest whose file no longer exists, by size
+/// Each can be claimed by one new path, s.t. two copies of a moved file don't both take its place
+#[derive(Default)]
+pub struct Vanished {
+    by_size: HashMap<u64, Vec<Candidate>>,
+}
+
+impl Vanished {
+    /// Finds the entries of 'manifest' whose file no longer exists, that can be reused
+    /// Entries without a recorded hash, hard links (and the files they link to) and empty files are left out
+    pub fn find(manifest: &FileManifest) -> Self {
+        let linked: HashSet<&str> = manifest.files.iter().filter_map(|e| e.link.as_deref()).collect();
+        let mut by_size: HashMap<u64, Vec<Candidate>> = HashMap::new();
+        for entry in &manifest.files {
+            if entry.sha1.is_empty() || entry.link.is_some() || entry.size == 0 || entry.size > MAX_COPY_SIZE
+                || linked.contains(entry.path.as_str()) {
+                continue;
+            }
+            if !Path::new(&winpath::extended(&entry.path)).exists() {
+                by_size.entry(entry.size).or_default().push(Candidate {
+                    path: entry.path.clone(),
+                    fingerprint: entry.fingerprint.clone(),
+                    sha1: entry.sha1.clone(),
+                });
+            }
+        }
+        Vanished { by_size }
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_size.values().map(|v| v.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_size.is_empty()
+    }
+
+    /// Whether a file of the given size may have been moved, i.e. whether it is worth fingerprinting
+    pub fn has_size(&self, size: u64) -> bool {
+        self.by_size.contains_key(&size)
+    }
+
+    /// Whether a file with the given size and fingerprint may have been moved, i.e. whether it is worth hashing
+    /// Entries recorded before fingerprints were tracked match any fingerprint
+    pub fn may_match(&self, size: u64, fingerprint: &str) -> bool {
+        self.by_size.get(&size).into_iter().flatten()
+            .any(|c| c.fingerprint.is_empty() || c.fingerprint == fingerprint)
+    }
+
+    /// Claims the vanished entry with the given size, fingerprint and hash, returning its path
+    pub fn claim(&mut self, size: u64, fingerprint: &str, sha1: &str) -> Option<String> {
+        let candidates = self.by_size.get_mut(&size)?;
+        let idx = candidates.iter()
+            .position(|c| (c.fingerprint.is_empty() || c.fingerprint == fingerprint) && c.sha1 == sha1)?;
+        let claimed = candidates.remove(idx);
+        if candidates.is_empty() {
+            self.by_size.remove(&size);
+        }
+        Some(claimed.path)
+    }
+}
+
+/// What is recorded of a file's content to recognize it once it is moved
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Content {
+    pub sha1: String,
+    pub fingerprint: String,
+}
+
+impl Content {
+    pub fn of(data: &[u8]) -> Self {
+        Content { sha1: sha1::Sha1::from(data).digest().to_string(), fingerprint: fingerprint_data(data) }
+    }
+
+    pub fn of_file(path: &str) -> std::io::Result<Self> {
+        Ok(Content { sha1: hash_file(path)?, fingerprint: fingerprint(path)? })
+    }
+}
+
+/// Hashes what the readers it wraps read, s.t. the content of a file is known once it is uploaded without reading it again
+/// The hashes also match what was uploaded if the file changed in the meantime
+/// Every attempt of an upload wraps the file anew, the content is that of what was read since the last wrap
+#[derive(Clone)]
+pub struct ContentHasher(Arc<Mutex<Hashing>>);
+
+// What was read so far: its SHA1, its size and the bytes its fingerprint covers
+struct Hashing {
+    sha1: sha1::Sha1,
+    size: u64,
+    head: Vec<u8>,
+    // The bytes after the head, only the last FINGERPRINT_SPAN of them are needed
+    rest: Vec<u8>,
+}
+
+impl Hashing {
+    fn new() -> Self {
+        Hashing { sha1: sha1::Sha1::new(), size: 0, head: Vec::new(), rest: Vec::new() }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        self.sha1.update(data);
+        self.size += data.len() as u64;
+        let to_head = data.len().min(FINGERPRINT_SPAN as usize - self.head.len());
+        self.head.extend_from_slice(&data[..to_head]);
+        self.rest.extend_from_slice(&data[to_head..]);
+        // Trimmed once it holds twice as much as needed, s.t. it isn't shifted on every read
+        if self.rest.len() > 2 * FINGERPRINT_SPAN as usize {
+            self.rest.drain(..self.rest.len() - FINGERPRINT_SPAN as usize);
+        }
+    }
+}
+
+impl Default for ContentHasher {
+    fn default() -> Self {
+        ContentHasher(Arc::new(Mutex::new(Hashing::new())))
+    }
+}
+
+impl ContentHasher {
+
+    /// Wraps 'inner', starting over
+    pub fn wrap<R: Read>(&self, inner: R) -> HashingReader<R> {
+        *self.0.lock().unwrap() = Hashing::new();
+        HashingReader { inner, hashing: self.0.clone() }
+    }
+
+    /// The content of what was read since the last wrap
+    pub fn content(&self) -> Content {
+        let hashing = self.0.lock().unwrap();
+        let tail = &hashing.rest[hashing.rest.len() - hashing.rest.len().min(FINGERPRINT_SPAN as usize)..];
+        Content { sha1: hashing.sha1.digest().to_string(), fingerprint: fingerprint_of(hashing.size, &hashing.head, tail) }
+    }
+}
+
+/// Reader made by ContentHasher::wrap
+pub struct HashingReader<R> {
+    inner: R,
+    hashing: Arc<Mutex<Hashing>>,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hashing.lock().unwrap().update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Hex SHA1 of the content of the file at 'path', read from its snapshot if there is one (see shadow.rs)
+pub fn hash_file(path: &str) -> std::io::Result<String> {
+    hash_reader(std::fs::File::open(shadow::source(path))?)
+}
+
+/// Hex SHA1 of everything 'reader' reads
+pub fn hash_reader<R: Read>(mut reader: R) -> std::io::Result<String> {
+    let mut hash = sha1::Sha1::new();
+    let mut buf = vec![0; 1024*1024];
+    loop {
+        match reader.read(&mut buf)? {
+            0 => break,
+            n => hash.update(&buf[..n]),
+        }
+    }
+    Ok(hash.digest().to_string())
+}
+
+/// Quick fingerprint of the file at 'path', which only reads its start and end
+pub fn fingerprint(path: &str) -> std::io::Result<String> {
+    let mut file = std::fs::File::open(shadow::source(path))?;
+    let size = file.metadata()?.len();
+    let mut head = vec![0; size.min(FINGERPRINT_SPAN) as usize];
+    file.read_exact(&mut head)?;
+    let mut tail = vec![0; (size - head.len() as u64).min(FINGERPRINT_SPAN) as usize];
+    file.seek(SeekFrom::End(-(tail.len() as i64)))?;
+    file.read_exact(&mut tail)?;
+    Ok(fingerprint_of(size, &head, &tail))
+}
+
+/// Fingerprint of 'data', the same as that of a file with this content
+pub fn fingerprint_data(data: &[u8]) -> String {
+    let head = &data[..data.len().min(FINGERPRINT_SPAN as usize)];
+    let rest = &data[head.len()..];
+    let tail = &rest[rest.len() - rest.len().min(FINGERPRINT_SPAN as usize)..];
+    fingerprint_of(data.len() as u64, head, tail)
+}
+
+// Hex SHA1 of the size, followed by the first and last bytes (which don't overlap)
+fn fingerprint_of(size: u64, head: &[u8], tail: &[u8]) -> String {
+    let mut hash = sha1::Sha1::new();
+    hash.update(&size.to_le_bytes());
+    hash.update(head);
+    hash.update(tail);
+    hash.digest().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::manifest::FileManifest;
+    use crate::moves::{fingerprint, fingerprint_data, hash_file, Content, ContentHasher, Vanished};
+    use std::io::Read;
+
+    #[test]
+    fn test_vanished() {
+        let dir = std::env::temp_dir().join("retain-test-moves");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+        std::fs::write(path("new"), b"content").unwrap();
+        std::fs::write(path("kept"), b"content").unwrap();
+        let sha1 = hash_file(&path("new")).unwrap();
+        assert_eq!(sha1, sha1::Sha1::from(b"content").digest().to_string());
+        let print = fingerprint(&path("new")).unwrap();
+
+        let mut manifest = FileManifest::new(true);
+        for name in &["old", "kept", "unhashed"] {
+            manifest.get_mask(path(name), 1);
+            let entry = manifest.get_entry_mut(path(name)).unwrap();
+            entry.size = 7;
+            if *name != "unhashed" {
+                entry.sha1 = sha1.clone();
+                entry.fingerprint = print.clone();
+            }
+        }
+
+        // Only 'old' is gone and has a hash
+        let mut vanished = Vanished::find(&manifest);
+        assert_eq!(vanished.len(), 1);
+        assert!(vanished.has_size(7));
+        assert!(vanished.may_match(7, &print));
+        assert!(!vanished.may_match(7, "other"));
+        assert_eq!(vanished.claim(7, &print, "other"), None);
+        assert_eq!(vanished.claim(7, &print, &sha1), Some(path("old")));
+        // Each entry can only be claimed once
+        assert_eq!(vanished.claim(7, &print, &sha1), None);
+        assert!(vanished.is_empty());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let file = std::env::temp_dir().join("retain-test-fingerprint");
+        let file = file.to_str().unwrap();
+        // Small files are covered in full, larger ones by their start and end
+        for size in &[0, 10, 100*1024, 300*1024] {
+            let mut data: Vec<u8> = (0..*size).map(|n| (n % 251) as u8).collect();
+            std::fs::write(file, &data).unwrap();
+            assert_eq!(fingerprint(file).unwrap(), fingerprint_data(&data));
+            if *size == 300*1024 {
+                // The middle isn't covered
+                data[150*1024] ^= 1;
+                assert_eq!(fingerprint(file).unwrap(), fingerprint_data(&data));
+            }
+        }
+        assert_eq!(Content::of_file(file).unwrap(), Content::of(&std::fs::read(file).unwrap()));
+        assert_ne!(fingerprint_data(b"a"), fingerprint_data(b"b"));
+        std::fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn test_content_hasher() {
+        let hasher = ContentHasher::default();
+        for size in [0, 10, 100*1024, 300*1024] {
+            let data: Vec<u8> = (0..size).map(|n| (n % 251) as u8).collect();
+            // Read in odd-sized chunks, a failed attempt is started over
+            hasher.wrap(&b"an earlier attempt"[..]).read_to_end(&mut Vec::new()).unwrap();
+            let mut reader = hasher.wrap(&data[..]);
+            let mut buf = [0u8; 7777];
+            while reader.read(&mut buf).unwrap() != 0 {}
+            assert_eq!(hasher.content(), Content::of(&data));
+        }
+    }
+}
@@ -0,0 +1,88 @@
+//! Reading secrets from the terminal without showing them, e.g. the App Key in 'init' and 'config edit'
+//!
+//! Echoing is turned off on the terminal stdin is read from, and turned back on when the guard is dropped
+//! If stdin isn't a terminal (e.g. input piped in by a script), there is nothing to turn off
+
+/// Echoing is off until dropped
+pub struct NoEcho {
+    #[cfg(unix)]
+    original: libc::termios,
+    #[cfg(windows)]
+    original: u32,
+}
+
+impl NoEcho {
+    /// Turns off echoing on the terminal of stdin. None if stdin isn't a terminal
+    pub fn start() -> Option<NoEcho> {
+        disable()
+    }
+}
+
+impl Drop for NoEcho {
+    fn drop(&mut self) {
+        restore(self);
+    }
+}
+
+#[cfg(unix)]
+fn disable() -> Option<NoEcho> {
+    let mut original = unsafe { std::mem::zeroed::<libc::termios>() };
+    if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut original) } != 0 {
+        return None;
+    }
+    let mut hidden = original;
+    // The line is still echoed when it is entered, s.t. the prompt moves on
+    hidden.c_lflag &= !libc::ECHO;
+    hidden.c_lflag |= libc::ECHONL;
+    match unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &hidden) } {
+        0 => Some(NoEcho { original }),
+        _ => None,
+    }
+}
+
+#[cfg(unix)]
+fn restore(echo: &NoEcho) {
+    unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &echo.original) };
+}
+
+#[cfg(windows)]
+mod console {
+    pub const STD_INPUT_HANDLE: u32 = -10i32 as u32;
+    pub const ENABLE_ECHO_INPUT: u32 = 0x4;
+
+    extern "system" {
+        pub fn GetStdHandle(std_handle: u32) -> *mut std::ffi::c_void;
+        pub fn GetConsoleMode(handle: *mut std::ffi::c_void, mode: *mut u32) -> i32;
+        pub fn SetConsoleMode(handle: *mut std::ffi::c_void, mode: u32) -> i32;
+    }
+}
+
+#[cfg(windows)]
+fn disable() -> Option<NoEcho> {
+    let mut original = 0;
+    unsafe {
+        let handle = console::GetStdHandle(console::STD_INPUT_HANDLE);
+        if console::GetConsoleMode(handle, &mut original) == 0 {
+            return None;
+        }
+        match console::SetConsoleMode(handle, original & !console::ENABLE_ECHO_INPUT) {
+            0 => None,
+            _ => Some(NoEcho { original }),
+        }
+    }
+}
+
+#[cfg(windows)]
+fn restore(echo: &NoEcho) {
+    unsafe { console::SetConsoleMode(console::GetStdHandle(console::STD_INPUT_HANDLE), echo.original) };
+    // The end of the line isn't echoed either
+    println!();
+}
+
+#[cfg(not(any(unix, windows)))]
+fn disable() -> Option<NoEcho> {
+    None
+}
+
+#[cfg(not(any(unix, windows)))]
+fn restore(_echo: &NoEcho) {}
@@ -0,0 +1,121 @@
+//! Default locations of the config, the secret key and the local manifest
+//!
+//! They follow the conventions of the platform, s.t. nothing depends on the working directory (e.g. when run from cron)
+//! Linux: the config in $XDG_CONFIG_HOME/retain-rs (~/.config/retain-rs), the manifest in $XDG_DATA_HOME/retain-rs (~/.local/share/retain-rs)
+//! The run logs (see logging.rs) are kept in 'logs' in the manifest directory, on every platform
+//! macOS: both in ~/Library/Application Support/retain-rs
+//! Windows: both in %APPDATA%\retain-rs
+//!
+//! Setups made before this, with everything in the working directory, keep working:
+//! retain.cfg in the working directory is used if it exists, and configs without a manifest directory keep it there
+
+use std::path::{Path, PathBuf};
+
+// Config in the working directory, as used before the platform locations
+pub const LEGACY_CONFIG: &str = "retain.cfg";
+
+// Name of the config in the platform config directory
+const CONFIG_NAME: &str = "retain.toml";
+
+// Name of the key generated by 'init', next to the config
+const KEY_NAME: &str = "retain-rs-key";
+
+// Sub-directory of the manifest directory the run logs are kept in
+const LOG_DIR: &str = "logs";
+
+// Sub-directory of the platform directories
+const APP_DIR: &str = "retain-rs";
+
+fn config_dir() -> Option<PathBuf> {
+    dirs::config_dir().map(|d| d.join(APP_DIR))
+}
+
+/// Location of the config when none is given with --config
+/// If the platform has no config directory (e.g. no home directory), it is in the working directory
+pub fn default_config() -> String {
+    if Path::new(LEGACY_CONFIG).exists() {
+        return LEGACY_CONFIG.to_string();
+    }
+    match config_dir() {
+        Some(dir) => dir.join(CONFIG_NAME).to_string_lossy().to_string(),
+        None => LEGACY_CONFIG.to_string(),
+    }
+}
+
+/// Directory for the manifest of a new config at 'config'
+/// Each config gets its own, s.t. several configs (e.g. for different buckets) don't share a manifest
+/// If the platform has no data directory, it is the working directory
+pub fn default_manifest_dir(config: &str) -> String {
+    let data_dir = match dirs::data_dir() {
+        Some(d) => d.join(APP_DIR),
+        None => return String::new(),
+    };
+    let dir = if config == default_config() {
+        data_dir
+    } else {
+        let name = Path::new(config).file_stem().map_or("default".into(), |s| s.to_string_lossy());
+        data_dir.join(name.as_ref())
+    };
+    dir.to_string_lossy().to_string()
+}
+
+/// Directory for the run logs of a config without one, in its manifest directory
+pub fn log_dir(manifest_dir: &str) -> String {
+    in_dir(manifest_dir, LOG_DIR)
+}
+
+/// Location of the key 'init' generates for the config at 'config', next to it
+pub fn default_key(config: &str) -> String {
+    Path::new(config).with_file_name(KEY_NAME).to_string_lossy().to_string()
+}
+
+/// Joins 'name' onto 'dir', where an empty 'dir' is the working directory
+pub fn in_dir(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_string()
+    } else {
+        Path::new(dir).join(name).to_string_lossy().to_string()
+    }
+}
+
+/// Creates the parent directory of 'path', if it has one
+pub fn create_parent(path: &str) -> std::io::Result<()> {
+    match Path::new(path).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => std::fs::create_dir_all(parent),
+        _ => Ok(()),
+    }
+}
+
+/// Writes 'contents' next to 'path' first and then moves it into place, s.t. an interrupted write never leaves a partial file
+pub fn write_atomic<C: AsRef<[u8]>>(path: &str, contents: C) -> std::io::Result<()> {
+    let temp = format!("{}.tmp", path);
+    std::fs::write(&temp, contents)?;
+    std::fs::rename(&temp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::paths::{default_key, in_dir, write_atomic};
+    use std::path::Path;
+
+    #[test]
+    fn test_paths() {
+        // Relative to the working directory, as before the platform locations
+        assert_eq!(default_key("retain.cfg"), "retain-rs-key");
+        assert_eq!(in_dir("", "manifest.json"), "manifest.json");
+
+        assert_eq!(Path::new(&default_key("config/retain.toml")), Path::new("config").join("retain-rs-key"));
+        assert_eq!(Path::new(&in_dir("data", "manifest.json")), Path::new("data").join("manifest.json"));
+    }
+
+    #[test]
+    fn test_write_atomic() {
+        let path = std::env::temp_dir().join("retain-test-write-atomic");
+        let path = path.to_str().unwrap();
+        write_atomic(path, "first").unwrap();
+        write_atomic(path, "second").unwrap();
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "second");
+        assert!(!Path::new(&format!("{}.tmp", path)).exists());
+        std::fs::remove_file(path).unwrap();
+    }
+}
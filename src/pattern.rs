@@ -0,0 +1,151 @@
+//! Matching of local paths against glob patterns or prefixes, e.g. to select which files to restore
+//!
+//! A pattern without any of the characters `*?[` is a prefix: it matches every path starting with it,
+//! s.t. `/home/user/documents/` matches everything in that directory
+//!
+//! Otherwise it is a glob, matched against the entire path:
+//! * `*` matches anything except a separator
+//! * `**` matches anything, including separators
+//! * `?` matches a single character except a separator
+//! * `[abc]` and `[a-z]` match one of the given characters, `[!abc]` any other character
+//!
+//! Both '/' and '\' are treated as separators, s.t. patterns work the same for paths from any platform
+
+use regex::Regex;
+
+#[derive(Clone, Debug)]
+pub enum PathPattern {
+    Prefix(String),
+    Glob(Regex),
+}
+
+impl PathPattern {
+    /// Parses a pattern, returning an error if it is an invalid glob
+    pub fn parse(pattern: &str) -> Result<Self, String> {
+        let pattern = normalize(pattern);
+        if !pattern.contains(|c| c == '*' || c == '?' || c == '[') {
+            return Ok(PathPattern::Prefix(pattern));
+        }
+
+        let mut regex = String::from("^");
+        let mut chars = pattern.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '*' => {
+                    if chars.peek() == Some(&'*') {
+                        chars.next();
+                        regex.push_str(".*");
+                    } else {
+                        regex.push_str("[^/]*");
+                    }
+                },
+                '?' => regex.push_str("[^/]"),
+                '[' => {
+                    regex.push('[');
+                    if chars.peek() == Some(&'!') {
+                        chars.next();
+                        regex.push('^');
+                    }
+                    loop {
+                        match chars.next() {
+                            Some(']') => break,
+                            Some(c) if c == '\\' || c == '[' || c == '^' => {
+                                regex.push('\\');
+                                regex.push(c);
+                            },
+                            Some(c) => regex.push(c),
+                            None => return Err(format!("Unclosed '[' in pattern '{}'", pattern)),
+                        }
+                    }
+                    regex.push(']');
+                },
+                c => regex.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        regex.push('$');
+
+        match Regex::new(&regex) {
+            Ok(r) => Ok(PathPattern::Glob(r)),
+            Err(e) => Err(format!("Invalid pattern '{}' ({})", pattern, e)),
+        }
+    }
+
+    /// Pattern matching only 'path' itself, even if it contains any of `*?[`
+    pub fn exact(path: &str) -> Self {
+        let regex = format!("^{}$", regex::escape(&normalize(path)));
+        PathPattern::Glob(Regex::new(&regex).unwrap())
+    }
+
+    /// Pattern matching everything within the directory 'dir'
+    pub fn within(dir: &str) -> Self {
+        let mut dir = normalize(dir);
+        if !dir.ends_with('/') {
+            dir.push('/');
+        }
+        PathPattern::Prefix(dir)
+    }
+
+    /// Whether 'path' matches this pattern
+    pub fn matches(&self, path: &str) -> bool {
+        let path = normalize(path);
+        match self {
+            PathPattern::Prefix(prefix) => path.starts_with(prefix.as_str()),
+            PathPattern::Glob(regex) => regex.is_match(&path),
+        }
+    }
+}
+
+// Use '/' as the only separator
+fn normalize(path: &str) -> String {
+    path.replace("\\", "/")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pattern::PathPattern;
+
+    #[test]
+    fn test_prefix() {
+        let p = PathPattern::parse("/home/user/documents/").unwrap();
+        assert!(p.matches("/home/user/documents/notes.txt"));
+        assert!(p.matches("/home/user/documents/a/b/c.txt"));
+        assert!(!p.matches("/home/user/downloads/notes.txt"));
+
+        let p = PathPattern::parse("C:\\Users\\user\\").unwrap();
+        assert!(p.matches("C:\\Users\\user\\notes.txt"));
+    }
+
+    #[test]
+    fn test_glob() {
+        let p = PathPattern::parse("/home/user/*.txt").unwrap();
+        assert!(p.matches("/home/user/notes.txt"));
+        assert!(!p.matches("/home/user/documents/notes.txt"));
+
+        let p = PathPattern::parse("/home/**/*.txt").unwrap();
+        assert!(p.matches("/home/user/documents/notes.txt"));
+        assert!(!p.matches("/home/user/documents/notes.pdf"));
+
+        let p = PathPattern::parse("/home/user/photo?.[jp][pn]g").unwrap();
+        assert!(p.matches("/home/user/photo1.jpg"));
+        assert!(p.matches("/home/user/photo2.png"));
+        assert!(!p.matches("/home/user/photo10.jpg"));
+
+        let p = PathPattern::parse("**/[!.]*").unwrap();
+        assert!(p.matches("/home/user/notes.txt"));
+        assert!(!p.matches("/home/user/.bashrc"));
+
+        assert!(PathPattern::parse("/home/[abc").is_err());
+    }
+
+    #[test]
+    fn test_exact_and_within() {
+        let p = PathPattern::exact("/home/user/[draft] notes?.txt");
+        assert!(p.matches("/home/user/[draft] notes?.txt"));
+        assert!(!p.matches("/home/user/d notes1.txt"));
+        assert!(!p.matches("/home/user/[draft] notes?.txt.bak"));
+
+        let p = PathPattern::within("C:\\Users\\user");
+        assert!(p.matches("C:\\Users\\user\\notes.txt"));
+        assert!(!p.matches("C:\\Users\\username\\notes.txt"));
+    }
+}
@@ -0,0 +1,80 @@
+//! The files an upload didn't get to, kept s.t. 'backup upload --resume' uploads just those
+//!
+//! A full upload that is interrupted or has failures records the files still queued and those that failed
+//! next to the local manifest. Resuming uploads only those, without scanning the backup list and comparing
+//! every file with the manifest again. A full upload that finishes without failures removes the record
+//! Uploads of only some paths (e.g. by 'watch') leave it alone, they don't know what else is left
+
+use std::path::Path;
+use serde::{Serialize, Deserialize};
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
+pub struct Pending {
+    // Files that weren't uploaded yet when the upload stopped, including those stopped midway
+    pub queued: Vec<String>,
+    // Files that failed to upload
+    pub failed: Vec<String>,
+}
+
+impl Pending {
+    /// Loads the record at 'path', None if there is none
+    pub fn load<T: AsRef<Path>>(path: T) -> Result<Option<Pending>, String> {
+        match std::fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).map(Some).map_err(|e| e.to_string()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    /// Saves the record to 'path', or removes it if nothing is left
+    pub fn save<T: AsRef<Path>>(&self, path: T) -> Result<(), String> {
+        if self.is_empty() {
+            return match std::fs::remove_file(path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.to_string()),
+                _ => Ok(()),
+            };
+        }
+        let bytes = serde_json::to_vec_pretty(self).map_err(|e| e.to_string())?;
+        std::fs::write(path, bytes).map_err(|e| e.to_string())
+    }
+
+    /// Every file left, sorted and without duplicates
+    pub fn paths(&self) -> Vec<String> {
+        let mut paths: Vec<String> = self.queued.iter().chain(self.failed.iter()).cloned().collect();
+        paths.sort();
+        paths.dedup();
+        paths
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queued.is_empty() && self.failed.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pending::Pending;
+
+    #[test]
+    fn test_pending() {
+        let path = std::env::temp_dir().join("retain-test-pending.json");
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(Pending::load(&path), Ok(None));
+
+        let pending = Pending {
+            queued: vec!["/b.txt".to_string(), "/a.txt".to_string()],
+            failed: vec!["/c.txt".to_string(), "/a.txt".to_string()],
+        };
+        pending.save(&path).unwrap();
+        let loaded = Pending::load(&path).unwrap().unwrap();
+        assert_eq!(loaded, pending);
+        assert_eq!(loaded.paths(), vec!["/a.txt", "/b.txt", "/c.txt"]);
+
+        // Nothing left removes the record
+        Pending::default().save(&path).unwrap();
+        assert!(!path.exists());
+        std::fs::write(&path, "garbage").unwrap();
+        assert!(Pending::load(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
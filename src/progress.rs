@@ -0,0 +1,279 @@
+//! Progress display for uploads and downloads
+//!
+//! Shows one bar per transfer that can be in progress at once, with the file it is currently transferring,
+//! and an overall bar with total bytes, throughput, files remaining and ETA
+//!
+//! Bytes are counted by wrapping the data source in a ProgressReader, which advances
+//! both the worker's bar and the overall bar. Buffered transfers (see transfer.rs) count their chunks with a ProgressCounter instead
+//!
+//! Every REPORT_INTERVAL the throughput, amount transferred and ETA are also reported as a line of text
+//! It is printed when the bars aren't drawn (e.g. output is redirected to a file), and always logged (see logging.rs)
+
+use indicatif::{HumanBytes, HumanDuration, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use crate::colorutil::{self, printcoln, printverbose, Verbosity};
+use termcolor::Color;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+// Time between reports of the progress
+const REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+pub struct Progress {
+    overall: ProgressBar,
+    workers: Vec<ProgressBar>,
+    remaining: Arc<AtomicUsize>,
+    // Bytes actually read, including retries. The overall bar also counts skipped files
+    transferred: Arc<AtomicU64>,
+    // Thread drawing the bars. Joined when finishing
+    draw_thread: Mutex<Option<JoinHandle<()>>>,
+    // Thread reporting progress, which stops when the sender is dropped. Joined when finishing
+    report_thread: Mutex<Option<(Sender<()>, JoinHandle<()>)>>,
+    start: Instant,
+    // Whether the reports are printed, rather than only logged
+    print_reports: bool,
+}
+
+impl Progress {
+    /// Starts displaying progress for transferring 'total_files' files, 'total_bytes' in total
+    /// 'workers' is the amount of threads transferring files
+    pub fn new(total_bytes: u64, total_files: usize, workers: usize) -> Self {
+        // Nothing is drawn when quiet
+        let multi = match colorutil::verbosity() {
+            Verbosity::Quiet => MultiProgress::with_draw_target(ProgressDrawTarget::hidden()),
+            _ => MultiProgress::new(),
+        };
+        // Bars aren't drawn if stderr isn't a terminal, reports are printed instead
+        let print_reports = ProgressDrawTarget::stderr().is_hidden();
+
+        let overall = multi.add(ProgressBar::new(total_bytes));
+        overall.set_style(ProgressStyle::default_bar()
+            .template("[{elapsed_precise}] [{wide_bar:.green}] {bytes}/{total_bytes} ({bytes_per_sec}, ETA {eta}) {msg}")
+            .progress_chars("=> "));
+        overall.set_message(&format!("{} files remaining", total_files));
+
+        let mut worker_bars = Vec::with_capacity(workers);
+        for i in 0..workers {
+            let bar = multi.add(ProgressBar::new(0));
+            bar.set_style(ProgressStyle::default_bar()
+                .template("{prefix:>3} [{bar:30}] {bytes:>10}/{total_bytes:<10} {bytes_per_sec:>12} {wide_msg}")
+                .progress_chars("=> "));
+            bar.set_prefix(&format!("#{}", i+1));
+            worker_bars.push(bar);
+        }
+
+        // MultiProgress only draws while something is waiting on join()
+        let handle = std::thread::spawn(move || {
+            multi.join_and_clear().unwrap_or(());
+        });
+
+        let mut progress = Progress {
+            overall,
+            workers: worker_bars,
+            remaining: Arc::new(AtomicUsize::new(total_files)),
+            transferred: Arc::new(AtomicU64::new(0)),
+            draw_thread: Mutex::new(Some(handle)),
+            report_thread: Mutex::new(None),
+            start: Instant::now(),
+            print_reports,
+        };
+        let (stop, stopped) = mpsc::channel();
+        let reporter = progress.reporter();
+        let handle = std::thread::spawn(move || {
+            let mut last = (Instant::now(), 0);
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(REPORT_INTERVAL) {
+                last = reporter.report(last);
+            }
+        });
+        progress.report_thread = Mutex::new(Some((stop, handle)));
+        progress
+    }
+
+    // What the report thread needs to report progress
+    fn reporter(&self) -> Reporter {
+        Reporter {
+            overall: self.overall.clone(),
+            workers: self.workers.clone(),
+            remaining: self.remaining.clone(),
+            print: self.print_reports,
+        }
+    }
+
+    /// Returns the bar belonging to the given worker
+    pub fn worker(&self, idx: usize) -> ProgressBar {
+        self.workers[idx].clone()
+    }
+
+    /// Prints a line above the bars without breaking them
+    /// These are failures of single files, so they are logged as warnings and printed even when quiet
+    pub fn println<T: AsRef<str>>(&self, text: T) {
+        tracing::warn!("{}", text.as_ref());
+        match colorutil::verbosity() {
+            Verbosity::Quiet => println!("{}", text.as_ref()),
+            _ => self.overall.println(text.as_ref()),
+        }
+    }
+
+    /// Marks the start of a transfer on the given worker bar
+    pub fn begin(&self, bar: &ProgressBar, name: &str, length: u64) {
+        bar.reset();
+        bar.set_length(length);
+        bar.set_message(name);
+    }
+
+    /// Undo the progress of the worker's current file, e.g. before retrying it
+    pub fn rewind(&self, bar: &ProgressBar) {
+        self.overall.set_position(self.overall.position().saturating_sub(bar.position()));
+        bar.set_position(0);
+        bar.reset_eta();
+    }
+
+    /// Counts the bytes of the worker's current file that an earlier attempt already downloaded
+    pub fn resume(&self, bar: &ProgressBar, bytes: u64) {
+        bar.inc(bytes);
+        self.overall.inc(bytes);
+    }
+
+    /// Marks a file as done, regardless of whether it succeeded
+    /// If fewer bytes than expected were counted for it, the difference is counted now
+    pub fn file_done(&self, bar: &ProgressBar) {
+        if bar.position() < bar.length() {
+            self.overall.inc(bar.length() - bar.position());
+        }
+        let remaining = self.remaining.fetch_sub(1, Ordering::SeqCst).saturating_sub(1);
+        self.overall.set_message(&format!("{} files remaining", remaining));
+        bar.set_length(0);
+        bar.set_message("");
+    }
+
+    /// Count bytes of a file that was skipped without being transferred
+    pub fn skip(&self, bytes: u64) {
+        self.overall.inc(bytes);
+        let remaining = self.remaining.fetch_sub(1, Ordering::SeqCst).saturating_sub(1);
+        self.overall.set_message(&format!("{} files remaining", remaining));
+    }
+
+    /// Adds to the total amount of bytes, for transfers whose size wasn't known up front
+    pub fn add_length(&self, bytes: u64) {
+        self.overall.inc_length(bytes);
+    }
+
+    /// Removes from the total amount of bytes, for transfers that turned out smaller than expected
+    pub fn remove_length(&self, bytes: u64) {
+        self.overall.set_length(self.overall.length().saturating_sub(bytes));
+    }
+
+    /// Wraps a reader, s.t. reading from it advances the given worker bar and the overall bar
+    pub fn wrap_read<R: Read>(&self, reader: R, bar: &ProgressBar) -> ProgressReader<R> {
+        ProgressReader {
+            inner: reader,
+            counter: self.counter(bar),
+        }
+    }
+
+    /// Counts bytes on the given worker bar and the overall bar, for transfers that aren't read through a reader
+    pub fn counter(&self, bar: &ProgressBar) -> ProgressCounter {
+        ProgressCounter {
+            bar: bar.clone(),
+            overall: self.overall.clone(),
+            transferred: self.transferred.clone(),
+        }
+    }
+
+    /// Amount of bytes transferred so far
+    pub fn transferred(&self) -> u64 {
+        self.transferred.load(Ordering::SeqCst)
+    }
+
+    /// Stops displaying progress, reporting the total amount transferred
+    pub fn finish(&self) {
+        if let Some((stop, handle)) = self.report_thread.lock().unwrap().take() {
+            drop(stop);
+            handle.join().unwrap_or(());
+        }
+        let elapsed = self.start.elapsed();
+        let rate = (self.overall.position() as f64 / elapsed.as_secs_f64().max(0.001)) as u64;
+        let summary = format!("Transferred {} in {} ({}/s)", HumanBytes(self.overall.position()), HumanDuration(elapsed), HumanBytes(rate));
+        if self.print_reports {
+            printcoln(Color::Green, summary);
+        } else {
+            tracing::info!("{}", summary);
+        }
+        for bar in &self.workers {
+            bar.finish_and_clear();
+        }
+        self.overall.finish_and_clear();
+        if let Some(handle) = self.draw_thread.lock().unwrap().take() {
+            handle.join().unwrap_or(());
+        }
+    }
+}
+
+pub struct ProgressReader<R: Read> {
+    inner: R,
+    counter: ProgressCounter,
+}
+
+impl<R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        let n = self.inner.read(buf)?;
+        self.counter.add(n as u64);
+        Ok(n)
+    }
+}
+
+#[derive(Clone)]
+pub struct ProgressCounter {
+    bar: ProgressBar,
+    overall: ProgressBar,
+    transferred: Arc<AtomicU64>,
+}
+
+impl ProgressCounter {
+    pub fn add(&self, bytes: u64) {
+        self.bar.inc(bytes);
+        self.overall.inc(bytes);
+        self.transferred.fetch_add(bytes, Ordering::SeqCst);
+    }
+}
+
+// Reports progress from another thread than the workers
+struct Reporter {
+    overall: ProgressBar,
+    workers: Vec<ProgressBar>,
+    remaining: Arc<AtomicUsize>,
+    print: bool,
+}
+
+impl Reporter {
+    // Reports the current throughput (since 'last', the time and position of the previous report), the amount transferred and the ETA
+    // Returns the time and position of this report
+    fn report(&self, last: (Instant, u64)) -> (Instant, u64) {
+        let (now, position, length) = (Instant::now(), self.overall.position(), self.overall.length());
+        let rate = (position.saturating_sub(last.1) as f64 / now.duration_since(last.0).as_secs_f64().max(0.001)) as u64;
+        let eta = match rate {
+            0 => "unknown".to_string(),
+            rate => HumanDuration(Duration::from_secs(length.saturating_sub(position) / rate)).to_string(),
+        };
+        let text = format!("Transferred {} of {} ({}/s), {} files remaining, ETA {}", HumanBytes(position), HumanBytes(length),
+                           HumanBytes(rate), self.remaining.load(Ordering::SeqCst), eta);
+        // The bars already show this when they are drawn, printing it would break them
+        if self.print {
+            printcoln(Color::White, text);
+        } else {
+            tracing::info!("{}", text);
+        }
+        for (i, bar) in self.workers.iter().enumerate().filter(|(_, bar)| bar.length() > 0) {
+            let text = format!("\t#{}: {} of {}", i+1, HumanBytes(bar.position()), HumanBytes(bar.length()));
+            if self.print {
+                printverbose(text);
+            } else {
+                tracing::debug!("{}", text);
+            }
+        }
+        (now, position)
+    }
+}
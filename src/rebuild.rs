@@ -0,0 +1,222 @@
+//! Rebuilding the manifest from the bucket, when it is lost but the files are still there
+//!
+//! Without encryption, a file is stored under its path (see winpath::remote_name), so each file in the backup list
+//! can be looked up in the bucket. It is recorded as backed up if the remote file has its size and either the SHA1
+//! of its content, or its modified time if the storage doesn't record a SHA1 (e.g. B2 large files). Anything else
+//! is uploaded again by the next upload, e.g. files that changed since, or were compressed or uploaded as deltas
+//!
+//! Encrypted backups can't be rebuilt this way: names are masked, and the content only matches after decrypting it
+//!
+//! 'repair' reconstructs a manifest from the names in the bucket alone instead, without looking at local files.
+//! Unmasked names map back to their paths, while masked names are kept as entries under a directory for lost files,
+//! s.t. their content can still be restored even though their original paths are lost
+
+use crate::backend::RemoteFile;
+use crate::manifest::{self, FileManifest};
+use crate::paths;
+use crate::moves::Content;
+use crate::hashing;
+use crate::winpath;
+use std::collections::HashMap;
+use std::time::UNIX_EPOCH;
+
+/// A manifest rebuilt from the bucket
+pub struct Rebuilt {
+    pub manifest: FileManifest,
+    // Files recorded as backed up
+    pub matched: usize,
+    // Files the next upload uploads again
+    pub unmatched: usize,
+}
+
+/// Records each of 'paths' that 'remote' holds unchanged, in a new unmasked manifest
+pub fn rebuild(paths: &[String], remote: &[RemoteFile]) -> Rebuilt {
+    let by_name: HashMap<&str, &RemoteFile> = remote.iter().map(|f| (f.name.as_str(), f)).collect();
+    // Files whose size matches are hashed first, in parallel (see hashing.rs)
+    let mut candidates = Vec::new();
+    for path in paths {
+        // Files uploaded before drives were folders (see winpath.rs) are under their old name
+        let name = winpath::remote_name(path);
+        let remote = match by_name.get(name.as_str()).or_else(|| by_name.get(winpath::legacy_remote_name(path).as_str())) {
+            Some(r) => *r,
+            None => continue,
+        };
+        let metadata = match std::fs::metadata(winpath::extended(path)) {
+            Ok(m) => m,
+            Err(_) => continue,
+        };
+        let modified = metadata.modified().ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_millis() as u64);
+        if remote.size == Some(metadata.len()) {
+            candidates.push((path, remote, metadata.len(), modified));
+        }
+    }
+    let hashed: Vec<String> = candidates.iter().filter(|c| c.1.sha1.is_some()).map(|c| c.0.clone()).collect();
+    let contents = hashing::contents(&hashed);
+
+    let mut manifest = FileManifest::new(false);
+    let mut matched = 0;
+    for (path, remote, size, modified) in candidates {
+        let content = match &remote.sha1 {
+            Some(sha1) => match contents.get(path) {
+                Some(content) if content.sha1 == *sha1 => content.clone(),
+                _ => continue,
+            },
+            None if remote.modified == Some(modified) => Content::default(),
+            None => continue,
+        };
+        manifest.get_mask(path, modified);
+        let entry = manifest.get_entry_mut(path).unwrap();
+        entry.mask = remote.name.clone();
+        entry.size = size;
+        entry.file_id = remote.id.clone();
+        entry.set_content(content);
+        entry.set_uploaded();
+        matched += 1;
+    }
+    Rebuilt { manifest, matched, unmatched: paths.len() - matched }
+}
+
+/// A manifest reconstructed from the names in the bucket, see repair
+pub struct Repaired {
+    pub manifest: FileManifest,
+    // Entries whose original path is known
+    pub named: usize,
+    // Entries under masked names, kept in the directory for lost files
+    pub lost: usize,
+}
+
+/// Reconstructs a manifest from the files in 'remote', for when the manifest is lost or corrupt
+/// With 'masked' names, each entry is put in 'lost_dir' under its mask. Deltas are attached to their full upload
+/// The size and SHA1 of masked content aren't those of the file, so only unmasked entries record them.
+/// Whether content was compressed isn't known from its name, compressed files are restored as they are stored
+pub fn repair(remote: &[RemoteFile], masked: bool, lost_dir: &str) -> Repaired {
+    let names: HashMap<&str, &RemoteFile> = remote.iter().map(|f| (f.name.as_str(), f)).collect();
+    // Deltas are named '<full upload>.delta<n>', see upload.rs. Anything else with such a name is a file of its own
+    let delta_of = |name: &str| name.rsplit_once(".delta")
+        .filter(|(base, _)| names.contains_key(base))
+        .and_then(|(base, n)| Some((base.to_string(), n.parse::<u32>().ok()?)));
+    let mut deltas: HashMap<String, Vec<(u32, String, Option<String>)>> = HashMap::new();
+    for file in remote {
+        if let Some((base, n)) = delta_of(&file.name) {
+            deltas.entry(base).or_default().push((n, file.name.clone(), file.id.clone()));
+        }
+    }
+
+    let mut manifest = FileManifest::new(masked);
+    for file in remote.iter().filter(|f| f.name != manifest::REMOTE_MANIFEST && delta_of(&f.name).is_none()) {
+        let path = match masked {
+            true => paths::in_dir(lost_dir, &file.name),
+            false => winpath::path_from_remote_name(&file.name, cfg!(windows)),
+        };
+        manifest.get_mask(&path, file.modified.unwrap_or(0));
+        let entry = manifest.get_entry_mut(&path).unwrap();
+        entry.mask = file.name.clone();
+        entry.file_id = file.id.clone();
+        if let Some(mut d) = deltas.remove(&file.name) {
+            d.sort();
+            for (_, name, id) in d {
+                entry.push_delta(name, id);
+            }
+        }
+        // After applying deltas, the file no longer has the size and SHA1 of its full upload
+        if !masked && entry.deltas.is_empty() {
+            entry.size = file.size.unwrap_or(0);
+            entry.sha1 = file.sha1.clone().unwrap_or_default();
+        }
+    }
+    let count = manifest.files.len();
+    match masked {
+        true => Repaired { manifest, named: 0, lost: count },
+        false => Repaired { manifest, named: count, lost: 0 },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::RemoteFile;
+    use crate::rebuild::{rebuild, repair};
+    use crate::winpath;
+    use crate::paths;
+
+    #[test]
+    fn test_rebuild() {
+        let dir = std::env::temp_dir().join("retain-test-rebuild");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+        let sha1 = |data: &[u8]| Some(sha1::Sha1::from(data).digest().to_string());
+        for name in &["same", "changed", "large", "touched", "new"] {
+            std::fs::write(path(name), b"content").unwrap();
+        }
+        let modified = |name: &str| std::fs::metadata(path(name)).unwrap().modified().unwrap()
+            .duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as u64;
+        let remote = |name: &str, size: u64, sha1: Option<String>, modified: u64| RemoteFile {
+            name: winpath::remote_name(&path(name)),
+            id: None,
+            modified: Some(modified),
+            size: Some(size),
+            sha1,
+        };
+        let mut files = vec![
+            remote("same", 7, sha1(b"content"), 0),
+            remote("changed", 7, sha1(b"CONTENT"), modified("changed")),
+            // Without a SHA1 the modified time has to match
+            remote("large", 7, None, modified("large")),
+            remote("touched", 7, None, modified("touched") + 1000),
+        ];
+        files[0].id = Some("id-same".to_string());
+
+        let paths: Vec<String> = ["same", "changed", "large", "touched", "new"].iter().map(|n| path(n)).collect();
+        let rebuilt = rebuild(&paths, &files);
+        assert_eq!((rebuilt.matched, rebuilt.unmatched), (2, 3));
+        assert!(!rebuilt.manifest.mask);
+        let same = rebuilt.manifest.get_entry(path("same")).unwrap();
+        assert_eq!((same.size, same.sha1.clone()), (7, sha1(b"content").unwrap()));
+        assert_eq!(same.mask, winpath::remote_name(&path("same")));
+        assert_eq!(same.file_id.as_deref(), Some("id-same"));
+        assert!(rebuilt.manifest.get_entry(path("large")).is_some());
+        assert!(rebuilt.manifest.get_entry(path("touched")).is_none());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_repair() {
+        let remote = |name: &str| RemoteFile {
+            name: name.to_string(),
+            id: Some(format!("id-{}", name)),
+            modified: Some(1000),
+            size: Some(7),
+            sha1: Some("abc".to_string()),
+        };
+        let files = vec![
+            remote("home/a.txt"),
+            remote("home/big.bin"),
+            remote("home/big.bin.delta2"),
+            remote("home/big.bin.delta1"),
+            // Not a delta, there is no 'home/notes'
+            remote("home/notes.delta1"),
+            remote("manifest.json"),
+        ];
+        let repaired = repair(&files, false, "/lost");
+        assert_eq!((repaired.named, repaired.lost), (3, 0));
+        let paths: Vec<&str> = repaired.manifest.files.iter().map(|e| e.path.as_str()).collect();
+        let expected = |name: &str| winpath::path_from_remote_name(name, cfg!(windows));
+        assert_eq!(paths, vec![expected("home/a.txt"), expected("home/big.bin"), expected("home/notes.delta1")]);
+        let a = &repaired.manifest.files[0];
+        assert_eq!((a.mask.as_str(), a.size, a.sha1.as_str(), a.timestamp, a.file_id.as_deref()), ("home/a.txt", 7, "abc", 1000, Some("id-home/a.txt")));
+        let big = &repaired.manifest.files[1];
+        assert_eq!(big.deltas, vec!["home/big.bin.delta1", "home/big.bin.delta2"]);
+        assert_eq!(big.delta_id(1), Some("id-home/big.bin.delta2"));
+        assert_eq!((big.size, big.sha1.as_str()), (0, ""));
+
+        // Masked names are kept under the directory for lost files
+        let repaired = repair(&[remote("Xk3f9"), remote("manifest.json")], true, "/lost");
+        assert_eq!((repaired.named, repaired.lost), (0, 1));
+        let lost = &repaired.manifest.files[0];
+        assert_eq!((lost.path.as_str(), lost.mask.as_str(), lost.size), (paths::in_dir("/lost", "Xk3f9").as_str(), "Xk3f9", 0));
+        assert!(repaired.manifest.mask);
+    }
+}
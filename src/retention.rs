@@ -0,0 +1,233 @@
+//! Grandfather-father-son retention of old file versions
+//!
+//! Every upload of a file that already exists in remote adds a new version, and old versions are kept
+//! until they are deleted. With a retention policy, 'clean' deletes old versions that are not needed
+//! to keep the last version of each of the 'daily' most recent days, 'weekly' most recent weeks and
+//! 'monthly' most recent months that have a version
+//!
+//! The policy is applied to each file separately, including manifest.json
+//! The latest version of a file is never deleted, nor are hide markers or unfinished large files
+
+use serde::{Serialize, Deserialize};
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use chrono::{Datelike, Local, NaiveDate, TimeZone};
+use crate::b2::FileVersion;
+use crate::manifest::{FileEntry, Snapshot};
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Retention {
+    pub daily: u32,
+    pub weekly: u32,
+    pub monthly: u32,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Retention {
+            daily: 7,
+            weekly: 4,
+            monthly: 12,
+        }
+    }
+}
+
+impl std::fmt::Display for Retention {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} daily, {} weekly, {} monthly", self.daily, self.weekly, self.monthly)
+    }
+}
+
+impl Retention {
+    /// Parses a policy given as "DAILY,WEEKLY,MONTHLY", e.g. "7,4,12"
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let counts = s.split(',')
+            .map(|c| u32::from_str(c.trim()))
+            .collect::<Result<Vec<u32>, _>>()
+            .map_err(|_| format!("Invalid retention '{}', counts must be numbers", s))?;
+        match counts.as_slice() {
+            [daily, weekly, monthly] => Ok(Retention { daily: *daily, weekly: *weekly, monthly: *monthly }),
+            _ => Err(format!("Invalid retention '{}', expected DAILY,WEEKLY,MONTHLY (e.g. 7,4,12)", s)),
+        }
+    }
+
+    /// Returns the versions that are not kept by this policy and can be deleted
+    pub fn expired(&self, versions: Vec<FileVersion>) -> Vec<FileVersion> {
+        let mut by_name: HashMap<String, Vec<FileVersion>> = HashMap::new();
+        for version in versions {
+            if version.action == "upload" {
+                by_name.entry(version.file_name.clone()).or_default().push(version);
+            }
+        }
+
+        let mut expired = Vec::new();
+        for (_, mut uploads) in by_name {
+            // Newest first, s.t. the last version of each period is the first one we see
+            uploads.sort_by(|a, b| b.upload_timestamp.cmp(&a.upload_timestamp));
+            let keep = self.kept(&uploads.iter().map(|v| v.upload_timestamp).collect::<Vec<u64>>());
+            expired.extend(uploads.into_iter().enumerate().filter(|(i, _)| !keep.contains(i)).map(|(_, v)| v));
+        }
+        expired
+    }
+
+    /// Removes the versions that 'entries' (e.g. those of the snapshots that are kept) need from 'expired'
+    /// That is the full upload and every delta of each entry. Entries made before their IDs were recorded
+    /// may need any version of their remote files, so those are all kept
+    pub fn unreferenced<'a, I: Iterator<Item=&'a FileEntry>>(expired: &mut Vec<FileVersion>, entries: I) {
+        let mut ids = HashSet::new();
+        let mut names = HashSet::new();
+        // Hard links have no remote files of their own
+        for entry in entries.filter(|e| e.link.is_none()) {
+            match &entry.file_id {
+                Some(id) => { ids.insert(id.as_str()); },
+                None => { names.insert(entry.mask.as_str()); },
+            }
+            if entry.delta_ids.len() == entry.deltas.len() {
+                ids.extend(entry.delta_ids.iter().map(|id| id.as_str()));
+            } else {
+                names.extend(entry.deltas.iter().map(|name| name.as_str()));
+            }
+        }
+        expired.retain(|v| !ids.contains(v.file_id.as_str()) && !names.contains(v.file_name.as_str()));
+    }
+
+    /// Returns the generations of the snapshots that are not kept by this policy, see manifest.rs
+    /// Snapshots are kept like versions of a file: the last one of each day, week and month
+    pub fn expired_snapshots(&self, snapshots: &[Snapshot]) -> HashSet<u64> {
+        let mut newest_first: Vec<&Snapshot> = snapshots.iter().collect();
+        newest_first.sort_by_key(|s| std::cmp::Reverse(s.time));
+        let keep = self.kept(&newest_first.iter().map(|s| s.time).collect::<Vec<u64>>());
+        newest_first.iter().enumerate().filter(|(i, _)| !keep.contains(i)).map(|(_, s)| s.generation).collect()
+    }
+
+    // Indices of the times (ms since Unix Epoch, newest first) that this policy keeps, the newest is always kept
+    fn kept(&self, times: &[u64]) -> HashSet<usize> {
+        let mut keep = HashSet::new();
+        keep.insert(0);
+        keep_per_period(times, self.daily, |d| (d.year(), d.ordinal()), &mut keep);
+        keep_per_period(times, self.weekly, |d| (d.iso_week().year(), d.iso_week().week()), &mut keep);
+        keep_per_period(times, self.monthly, |d| (d.year(), d.month()), &mut keep);
+        keep
+    }
+}
+
+// Marks the newest time in each of the 'count' most recent periods as kept
+// 'period' maps a date to the period it is in, 'times' must be sorted newest first
+fn keep_per_period<F: Fn(NaiveDate) -> (i32, u32)>(times: &[u64], count: u32, period: F, keep: &mut HashSet<usize>) {
+    let mut last = None;
+    let mut kept = 0;
+    for (i, time) in times.iter().enumerate() {
+        if kept == count {
+            break;
+        }
+        let p = period(local_date(*time));
+        if last != Some(p) {
+            keep.insert(i);
+            last = Some(p);
+            kept += 1;
+        }
+    }
+}
+
+fn local_date(millis: u64) -> NaiveDate {
+    match Local.timestamp_millis_opt(millis as i64).earliest() {
+        Some(dt) => dt.naive_local().date(),
+        None => NaiveDate::from_ymd_opt(1970, 1, 1).unwrap(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::retention::Retention;
+    use crate::b2::FileVersion;
+    use crate::manifest::{FileEntry, Snapshot};
+
+    const DAY: u64 = 24*3600*1000;
+    // 2020-01-01 12:00 UTC, a Wednesday
+    const START: u64 = 1577880000000;
+
+    fn upload(name: &str, day: u64) -> FileVersion {
+        FileVersion {
+            file_id: format!("{}-{}", name, day),
+            file_name: name.to_string(),
+            action: "upload".to_string(),
+            content_length: 0,
+            upload_timestamp: START + day*DAY,
+        }
+    }
+
+    fn expired_ids(retention: Retention, versions: Vec<FileVersion>) -> Vec<String> {
+        let mut ids: Vec<String> = retention.expired(versions).into_iter().map(|v| v.file_id).collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Retention::parse("7,4,12").unwrap(), Retention::default());
+        assert_eq!(Retention::parse(" 1, 0, 3").unwrap(), Retention { daily: 1, weekly: 0, monthly: 3 });
+        assert!(Retention::parse("7,4").is_err());
+        assert!(Retention::parse("7,4,x").is_err());
+    }
+
+    #[test]
+    fn test_expired() {
+        // Only the latest version is kept without any periods
+        let none = Retention { daily: 0, weekly: 0, monthly: 0 };
+        let versions = vec![upload("a", 0), upload("a", 1), upload("a", 2), upload("b", 0)];
+        assert_eq!(expired_ids(none, versions.clone()), vec!["a-0", "a-1"]);
+
+        // Two uploads on the same day, only the last of them is kept
+        let mut same_day = upload("a", 2);
+        same_day.file_id = "a-2-early".to_string();
+        same_day.upload_timestamp -= 3600*1000;
+        let mut with_same_day = versions.clone();
+        with_same_day.push(same_day);
+        let daily = Retention { daily: 2, weekly: 0, monthly: 0 };
+        assert_eq!(expired_ids(daily, with_same_day), vec!["a-0", "a-2-early"]);
+
+        // Daily uploads for 60 days: keep 3 days, 2 weeks and 3 months
+        let versions: Vec<FileVersion> = (0..60).map(|d| upload("a", d)).collect();
+        let policy = Retention { daily: 3, weekly: 2, monthly: 3 };
+        let expired = expired_ids(policy, versions);
+        let kept: Vec<String> = (0..60).map(|d| format!("a-{}", d)).filter(|id| !expired.contains(id)).collect();
+        // Day 59 is Saturday 2020-02-29, 53 is the Sunday before and 30 is 2020-01-31
+        assert_eq!(kept, vec!["a-30", "a-53", "a-57", "a-58", "a-59"]);
+
+        // Hide markers are never expired
+        let mut hide = upload("a", 0);
+        hide.action = "hide".to_string();
+        assert!(none.expired(vec![hide]).is_empty());
+    }
+
+    #[test]
+    fn test_expired_snapshots() {
+        // Daily snapshots for 60 days are kept like daily uploads of a file, in any order
+        let mut snapshots: Vec<Snapshot> = (0..60).map(|d| Snapshot { generation: d + 1, time: START + d*DAY, name: None }).collect();
+        snapshots.reverse();
+        let policy = Retention { daily: 3, weekly: 2, monthly: 3 };
+        let expired = policy.expired_snapshots(&snapshots);
+        let mut kept: Vec<u64> = (1..61).filter(|g| !expired.contains(g)).collect();
+        kept.sort();
+        assert_eq!(kept, vec![31, 54, 58, 59, 60]);
+    }
+
+    #[test]
+    fn test_unreferenced() {
+        let none = Retention { daily: 0, weekly: 0, monthly: 0 };
+        let versions = vec![upload("a", 0), upload("a", 1), upload("a.d0", 0), upload("a.d0", 1),
+                            upload("b", 0), upload("b", 1), upload("b.d0", 0), upload("b.d0", 1)];
+        // 'a' has the IDs of its base and delta, 'b' has neither
+        let a: FileEntry = serde_json::from_str(r#"{"path":"a","timestamp":0,"mask":"a","deltas":["a.d0"],"delta_ids":["a.d0-0"],"file_id":"a-0"}"#).unwrap();
+        let b: FileEntry = serde_json::from_str(r#"{"path":"b","timestamp":0,"mask":"b","deltas":["b.d0"]}"#).unwrap();
+        let mut expired = none.expired(versions);
+        Retention::unreferenced(&mut expired, [a, b].iter());
+        assert!(expired.is_empty());
+
+        // Once 'a' has no delta, its old delta version can go
+        let mut expired = none.expired(vec![upload("a", 0), upload("a", 1), upload("a.d0", 0), upload("a.d0", 1)]);
+        let a: FileEntry = serde_json::from_str(r#"{"path":"a","timestamp":0,"mask":"a","file_id":"a-0"}"#).unwrap();
+        Retention::unreferenced(&mut expired, std::iter::once(&a));
+        assert_eq!(expired.into_iter().map(|v| v.file_id).collect::<Vec<String>>(), vec!["a.d0-0"]);
+    }
+}
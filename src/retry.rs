@@ -0,0 +1,193 @@
+//! Retry policy for B2 requests
+//!
+//! Failed requests are retried with exponential backoff: the n'th retry waits up to BASE_DELAY * 2^n,
+//! capped at MAX_DELAY. A random jitter of up to half the delay is subtracted, s.t. threads that
+//! failed at the same time (e.g. B2 being briefly unavailable) don't all retry at the same time
+//!
+//! Only errors that may go away by themselves are retried: network errors, timeouts, 5xx and 429
+//! Other 4xx errors (bad auth, invalid names, missing files, etc.) fail immediately
+//!
+//! A 429 or 503 may come with a Retry-After header, saying how long to wait. The request is then retried after that
+//! long instead, and since the server is likely overloaded by all of them, every other worker holds off until then
+//! too before making its next request (see hold_off). Calls made through raze don't expose headers, those back off as usual
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+use rand::{thread_rng, Rng};
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+
+// Amount of attempts when nothing is configured
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+const BASE_DELAY: Duration = Duration::from_secs(2);
+const MAX_DELAY: Duration = Duration::from_secs(120);
+// Shortest and longest wait asked for with Retry-After that is honored
+// A wait of 0 or a date in the past would otherwise retry right away, while the server is still overloaded
+const MIN_RETRY_AFTER: Duration = Duration::from_secs(1);
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(600);
+
+// Until when the workers hold off, set when a server asks to wait with Retry-After
+static HOLD_OFF_UNTIL: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// How long to wait before retrying, after 'attempt' (starting at 0) failed
+pub fn delay(attempt: u32) -> Duration {
+    let max = BASE_DELAY.checked_mul(1u32.checked_shl(attempt).unwrap_or(u32::MAX))
+        .unwrap_or(MAX_DELAY)
+        .min(MAX_DELAY);
+    let jitter = thread_rng().gen_range(0.0, 0.5);
+    max.mul_f64(1.0 - jitter)
+}
+
+/// How long to wait before retrying, after 'attempt' failed with an error that may have come with a Retry-After
+/// The wait that was asked for is honored, and every worker holds off until it has passed
+pub fn delay_for(attempt: u32, retry_after: Option<Duration>) -> Duration {
+    match retry_after {
+        Some(wait) => {
+            let wait = wait.clamp(MIN_RETRY_AFTER, MAX_RETRY_AFTER);
+            let until = Instant::now() + wait;
+            let mut hold = HOLD_OFF_UNTIL.lock().unwrap();
+            *hold = Some(hold.map_or(until, |h| h.max(until)));
+            wait
+        },
+        None => delay(attempt),
+    }
+}
+
+/// How long workers should wait before making a request, if a server asked to wait
+pub fn held_off() -> Option<Duration> {
+    let hold = HOLD_OFF_UNTIL.lock().unwrap();
+    hold.and_then(|until| until.checked_duration_since(Instant::now())).filter(|d| !d.is_zero())
+}
+
+/// Waits until no server asks to wait anymore, before making a request
+pub fn hold_off() {
+    if let Some(wait) = held_off() {
+        std::thread::sleep(wait);
+    }
+}
+
+/// Waits until no server asks to wait anymore, without blocking the thread
+pub async fn hold_off_async() {
+    if let Some(wait) = held_off() {
+        tokio::time::delay_for(wait).await;
+    }
+}
+
+/// How long the response with the given status and headers asks to wait, for a 429 or 503
+pub fn retry_after(status: u16, headers: &HeaderMap) -> Option<Duration> {
+    match status {
+        429 | 503 => headers.get(RETRY_AFTER).and_then(|v| v.to_str().ok()).and_then(parse_retry_after),
+        _ => None,
+    }
+}
+
+/// Parses the value of a Retry-After header, either an amount of seconds or an HTTP date
+pub fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    let date = SystemTime::UNIX_EPOCH + Duration::from_secs(date.timestamp().max(0) as u64);
+    // A date in the past means it can be retried right away, as far as delay_for allows
+    Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+/// How long the error returned by the calls in `crate::b2` asks to wait, see retry_after
+pub fn retry_after_of(err: &(dyn std::error::Error + 'static)) -> Option<Duration> {
+    err.downcast_ref::<crate::b2::B2ApiError>().and_then(|e| e.retry_after)
+}
+
+/// Whether a B2 error with the given HTTP status and error code is worth retrying
+pub fn is_retryable_status(status: u16, code: &str) -> bool {
+    match status {
+        408 | 429 => true,
+        // Expired tokens are fixed by getting a new one
+        401 => is_expired_token(status, code),
+        500..=599 => true,
+        _ => false,
+    }
+}
+
+/// Whether a B2 error with the given HTTP status and error code says the auth token expired
+/// The account is then authorized again before the next attempt, see backend/b2.rs
+pub fn is_expired_token(status: u16, code: &str) -> bool {
+    status == 401 && code == "expired_auth_token"
+}
+
+/// Whether an error returned by the calls in `crate::b2` says the auth token expired
+pub fn is_expired_error(err: &(dyn std::error::Error + 'static)) -> bool {
+    err.downcast_ref::<crate::b2::B2ApiError>().is_some_and(|e| is_expired_token(e.status, &e.code))
+}
+
+/// Whether an error returned by raze is worth retrying
+pub fn is_retryable(err: &raze::Error) -> bool {
+    match err {
+        raze::Error::B2Error(e) => is_retryable_status(e.status, &e.code),
+        // Network and I/O errors
+        _ => true,
+    }
+}
+
+/// Whether an error returned by the calls in `crate::b2` is worth retrying
+pub fn is_retryable_boxed(err: &Box<dyn std::error::Error>) -> bool {
+    is_retryable_error(err.as_ref())
+}
+
+/// Whether an error returned by the calls in `crate::b2` is worth retrying, including the async ones
+pub fn is_retryable_error(err: &(dyn std::error::Error + 'static)) -> bool {
+    match err.downcast_ref::<crate::b2::B2ApiError>() {
+        Some(e) => is_retryable_status(e.status, &e.code),
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::retry::{delay, delay_for, held_off, is_expired_token, is_retryable_status, parse_retry_after, retry_after, MAX_DELAY};
+    use reqwest::header::{HeaderMap, HeaderValue, RETRY_AFTER};
+    use std::time::Duration;
+
+    #[test]
+    fn test_delay() {
+        for attempt in 0..40 {
+            let d = delay(attempt);
+            assert!(d <= MAX_DELAY);
+            assert!(d >= Duration::from_secs(1).min(MAX_DELAY / 2));
+        }
+        // Grows with each attempt (until capped)
+        assert!(delay(5) > delay(0));
+    }
+
+    #[test]
+    fn test_retryable() {
+        assert!(is_retryable_status(503, "service_unavailable"));
+        assert!(is_retryable_status(500, "internal_error"));
+        assert!(is_retryable_status(429, "too_many_requests"));
+        assert!(is_retryable_status(401, "expired_auth_token"));
+        assert!(!is_retryable_status(401, "unauthorized"));
+        assert!(!is_retryable_status(400, "bad_request"));
+        assert!(!is_retryable_status(404, "not_found"));
+        assert!(is_expired_token(401, "expired_auth_token"));
+        assert!(!is_expired_token(401, "bad_auth_token"));
+    }
+
+    #[test]
+    fn test_retry_after() {
+        assert_eq!(parse_retry_after("30"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"), Some(Duration::from_secs(0)));
+        assert_eq!(parse_retry_after("soon"), None);
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("7"));
+        assert_eq!(retry_after(429, &headers), Some(Duration::from_secs(7)));
+        assert_eq!(retry_after(503, &headers), Some(Duration::from_secs(7)));
+        assert_eq!(retry_after(500, &headers), None);
+        assert_eq!(retry_after(429, &HeaderMap::new()), None);
+
+        // The wait asked for is used instead of backing off, and holds off every worker
+        assert_eq!(delay_for(0, Some(Duration::from_secs(3))), Duration::from_secs(3));
+        assert!(held_off().unwrap() > Duration::from_secs(2));
+        assert!(delay_for(0, None) <= Duration::from_secs(2));
+        // Asking to wait for nothing still waits a bit
+        assert_eq!(delay_for(0, Some(Duration::from_secs(0))), Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,140 @@
+//! Caching the listings of directories between scans, s.t. directories that didn't change aren't read again
+//!
+//! The modified time of a directory changes whenever an entry is added to, removed from or renamed in it
+//! If it is the same as when the directory was last read, the listing from then is used instead of reading it
+//! Changes deeper down don't change the modified time of the directories above, so every directory is still checked
+//! Changes to the contents of files don't change it either, those are found by comparing the files with the manifest as usual
+//!
+//! A directory modified less than a second before the previous scan started is read again, as it may have changed
+//! during that scan without its modified time changing (file systems with coarse timestamps)
+//! The cache only holds what is in each directory, the backup list is applied to it on every scan s.t. editing it takes effect
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Serialize, Deserialize};
+
+// How long before the previous scan a directory must have been modified for its listing to be trusted
+const RACY_MILLIS: u64 = 1000;
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct ScanCache {
+    // When the scan that made the cache started, in ms since the Unix epoch
+    started: u64,
+    // The listing of each directory that was walked, by path
+    dirs: HashMap<String, Listing>,
+}
+
+/// The names of the entries of a directory, by what they are
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq)]
+pub struct Listing {
+    // Modified time of the directory when it was read, in ms since the Unix epoch
+    modified: u64,
+    pub files: Vec<String>,
+    pub dirs: Vec<String>,
+    pub links: Vec<String>,
+}
+
+impl ScanCache {
+    /// An empty cache for a scan starting now
+    pub fn new() -> ScanCache {
+        ScanCache { started: now(), dirs: HashMap::new() }
+    }
+
+    /// Loads the cache at 'path'. If it doesn't exist or can't be read, the cache is empty and everything is read
+    pub fn load<T: AsRef<Path>>(path: T) -> ScanCache {
+        std::fs::read(path).ok()
+            .and_then(|bytes| serde_cbor::from_slice(&bytes).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save<T: AsRef<Path>>(&self, path: T) -> Result<(), String> {
+        let bytes = serde_cbor::to_vec(self).map_err(|e| e.to_string())?;
+        std::fs::write(path, bytes).map_err(|e| e.to_string())
+    }
+
+    /// The cached listing of 'dir', if it didn't change since it was read
+    pub fn get(&self, dir: &str, modified: u64) -> Option<&Listing> {
+        match self.dirs.get(dir) {
+            Some(listing) if modified != 0 && listing.modified == modified && modified + RACY_MILLIS < self.started => Some(listing),
+            _ => None,
+        }
+    }
+
+    pub fn insert(&mut self, dir: String, listing: Listing) {
+        self.dirs.insert(dir, listing);
+    }
+
+    /// Amount of directories in the cache
+    pub fn len(&self) -> usize {
+        self.dirs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dirs.is_empty()
+    }
+}
+
+/// Modified time of 'dir' in ms since the Unix epoch, 0 if it can't be read
+pub fn modified(dir: &Path) -> u64 {
+    std::fs::symlink_metadata(dir).and_then(|m| m.modified()).ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Reads the entries of 'dir', whose modified time is 'modified'. Symlinks are not followed
+pub fn read_dir(dir: &Path, modified: u64) -> std::io::Result<Listing> {
+    let mut listing = Listing { modified, ..Listing::default() };
+    for entry in std::fs::read_dir(dir)?.flatten() {
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        match entry.file_type() {
+            Ok(t) if t.is_dir() => listing.dirs.push(name),
+            Ok(t) if t.is_file() => listing.files.push(name),
+            Ok(t) if t.is_symlink() => listing.links.push(name),
+            _ => (),
+        }
+    }
+    Ok(listing)
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::scancache::{ScanCache, modified, read_dir};
+
+    #[test]
+    fn test_scan_cache() {
+        let dir = std::env::temp_dir().join("retain-test-scan-cache");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("a.txt"), "").unwrap();
+        let name = dir.to_string_lossy().to_string();
+
+        let mut cache = ScanCache::new();
+        let time = modified(&dir);
+        let listing = read_dir(&dir, time).unwrap();
+        assert_eq!((listing.files.clone(), listing.dirs.clone()), (vec!["a.txt".to_string()], vec!["sub".to_string()]));
+        cache.insert(name.clone(), listing.clone());
+        // Just modified, so it may still change without its modified time changing
+        assert_eq!(cache.get(&name, time), None);
+
+        cache.started = time + 5000;
+        assert_eq!(cache.get(&name, time), Some(&listing));
+        assert_eq!(cache.get(&name, time + 1), None);
+        assert_eq!(cache.get("/elsewhere", time), None);
+
+        let path = std::env::temp_dir().join("retain-test-scan-cache.cbor");
+        cache.save(&path).unwrap();
+        assert_eq!(ScanCache::load(&path).get(&name, time), Some(&listing));
+        std::fs::write(&path, "garbage").unwrap();
+        assert!(ScanCache::load(&path).is_empty());
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,128 @@
+//! Secrets kept in the OS keyring instead of in files or in the config
+//!
+//! Wherever a secret is configured, i.e. the path of the secret key or the App Key, 'keyring:<name>' refers to
+//! the entry <name> of the 'retain-rs' service in the OS keyring instead
+//! That is the Secret Service on Linux, the Keychain on macOS and the Credential Manager on Windows
+//! The keyring can only be used when built with the 'os-keyring' feature
+//!
+//! Keyring entries hold text, so the secret key is stored hex encoded
+//!
+//! Similarly, 'env:<name>' refers to the environment variable <name>, which holds the secret itself (keys hex encoded)
+//! This is how environment overrides (see Config::apply_env) keep secrets out of the config file
+
+pub const KEYRING_PREFIX: &str = "keyring:";
+pub const ENV_PREFIX: &str = "env:";
+
+// Names of the entries secrets are moved to
+pub const SECRET_KEY_ENTRY: &str = "secret-key";
+pub const APP_KEY_ENTRY: &str = "app-key";
+
+#[cfg(feature = "os-keyring")]
+const SERVICE: &str = "retain-rs";
+
+/// Returns the name of the keyring entry 'location' refers to, or None if it isn't in the keyring
+pub fn keyring_entry(location: &str) -> Option<&str> {
+    location.strip_prefix(KEYRING_PREFIX)
+}
+
+/// Returns the name of the environment variable 'location' refers to, or None if it isn't one
+pub fn env_var(location: &str) -> Option<&str> {
+    location.strip_prefix(ENV_PREFIX)
+}
+
+/// Whether 'value' refers to a secret stored elsewhere, rather than being a key-file or the secret itself
+pub fn is_reference(value: &str) -> bool {
+    keyring_entry(value).is_some() || env_var(value).is_some()
+}
+
+/// Reads the secret key at 'location', which is either a key-file, a keyring entry or an environment variable
+pub fn read_key(location: &str) -> std::io::Result<Vec<u8>> {
+    if !is_reference(location) {
+        return std::fs::read(location);
+    }
+    resolve(location).and_then(|hex| decode_hex(&hex))
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+}
+
+/// Stores a secret key in the keyring, returning the location to configure
+pub fn store_key(name: &str, key: &[u8]) -> Result<String, String> {
+    store(name, &encode_hex(key))
+}
+
+/// Resolves a configured secret, which is either the secret itself, a keyring entry or an environment variable
+pub fn resolve(value: &str) -> Result<String, String> {
+    if let Some(name) = keyring_entry(value) {
+        return get(name);
+    }
+    match env_var(value) {
+        Some(name) => std::env::var(name).map_err(|_| format!("Environment variable {} is not set", name)),
+        None => Ok(value.to_string()),
+    }
+}
+
+/// Stores a secret in the keyring, returning the location to configure
+pub fn store(name: &str, secret: &str) -> Result<String, String> {
+    set(name, secret)?;
+    Ok(format!("{}{}", KEYRING_PREFIX, name))
+}
+
+#[cfg(feature = "os-keyring")]
+fn get(name: &str) -> Result<String, String> {
+    keyring::Entry::new(SERVICE, name)
+        .and_then(|entry| entry.get_password())
+        .map_err(|e| format!("Failed to read '{}' from the OS keyring ({})", name, e))
+}
+
+#[cfg(feature = "os-keyring")]
+fn set(name: &str, secret: &str) -> Result<(), String> {
+    keyring::Entry::new(SERVICE, name)
+        .and_then(|entry| entry.set_password(secret))
+        .map_err(|e| format!("Failed to store '{}' in the OS keyring ({})", name, e))
+}
+
+#[cfg(not(feature = "os-keyring"))]
+fn get(name: &str) -> Result<String, String> {
+    Err(format!("Can't read '{}', retain-rs was built without OS keyring support (feature 'os-keyring')", name))
+}
+
+#[cfg(not(feature = "os-keyring"))]
+fn set(name: &str, _secret: &str) -> Result<(), String> {
+    Err(format!("Can't store '{}', retain-rs was built without OS keyring support (feature 'os-keyring')", name))
+}
+
+pub fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn decode_hex(hex: &str) -> Result<Vec<u8>, String> {
+    let hex = hex.trim();
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return Err("not valid hex".to_string());
+    }
+    (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i+2], 16).map_err(|_| "not valid hex".to_string()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::secrets::{keyring_entry, env_var, resolve, read_key, encode_hex, decode_hex};
+
+    #[test]
+    fn test_locations() {
+        assert_eq!(keyring_entry("keyring:secret-key"), Some("secret-key"));
+        assert_eq!(keyring_entry("retain-rs-key"), None);
+        // Plain values are the secret itself
+        assert_eq!(resolve("K001abcdef"), Ok("K001abcdef".to_string()));
+        assert_eq!(env_var("env:RETAIN_TEST_SECRET"), Some("RETAIN_TEST_SECRET"));
+        std::env::set_var("RETAIN_TEST_SECRET", "0a0b");
+        assert_eq!(resolve("env:RETAIN_TEST_SECRET"), Ok("0a0b".to_string()));
+        assert_eq!(read_key("env:RETAIN_TEST_SECRET").unwrap(), vec![10, 11]);
+        assert!(resolve("env:RETAIN_TEST_UNSET").is_err());
+
+        let key: Vec<u8> = (0..32).map(|i| i * 7).collect();
+        assert_eq!(decode_hex(&encode_hex(&key)), Ok(key));
+        assert!(decode_hex("abc").is_err());
+        assert!(decode_hex("zz").is_err());
+    }
+}
@@ -0,0 +1,445 @@
+//! Reading files from snapshots of the file systems they are on, s.t. what is backed up is consistent and locked files can be read
+//!
+//! Files that change while they are read are backed up half old and half new, and on Windows files that are open
+//! for writing elsewhere (Outlook PSTs, running databases, registry hives) can't be opened at all. Snapshots are
+//! copies of a file system from the moment they are made, which nothing else writes to or has open
+//! An upload reads files from the snapshots there are instead of from where they are. The manifest keeps the regular
+//! paths, only where the content is read from changes. Files created after a snapshot was made are read where they are
+//!
+//! On Windows, 'shadow_copy' makes a Volume Shadow Copy of every volume an upload reads from, through WMI
+//! (Win32_ShadowCopy) with PowerShell, which needs an administrator. Network shares are read as they are
+//!
+//! Elsewhere, the 'snapshots' in the config are made before the backup list is scanned. Each is of a directory ('path'):
+//! * btrfs: a read-only snapshot of the subvolume at 'path', made in 'path'/.retain-snapshot-NAME
+//! * zfs: a snapshot of the dataset mounted at 'path', read from 'path'/.zfs/snapshot/NAME
+//! * command: 'create' is run with sh (e.g. lvcreate -s and mount for LVM), after which the snapshot is mounted at 'mount'
+//!   'remove' is run to remove it again
+//! ```toml
+//! [[snapshots]]
+//! path = "/home"
+//! kind = "btrfs"
+//!
+//! [[snapshots]]
+//! path = "/srv"
+//! kind = "command"
+//! create = "lvcreate -s -n srv-snap -L 5G vg/srv && mount -o ro /dev/vg/srv-snap /mnt/srv-snap"
+//! mount = "/mnt/srv-snap"
+//! remove = "umount /mnt/srv-snap && lvremove -f vg/srv-snap"
+//! ```
+//! NAME is unique to the run, 'retain-rs-PID' or 'retain-rs-JOB-PID' for a job (see jobs.rs), s.t. runs at the same
+//! time don't remove the snapshots of each other. Directories snapshots are read from, including those of other runs,
+//! are never walked when scanning, s.t. their files aren't backed up twice
+//!
+//! Snapshots are removed when the upload is done, or when the process exits on Ctrl-C. If the process is killed,
+//! btrfs and zfs snapshots of runs that aren't running anymore are removed by the next upload. Shadow copies are listed by 'vssadmin list shadows' and have to be deleted with
+//! 'vssadmin delete shadows /shadow={ID}', command snapshots by running 'remove'
+//! A snapshot that can't be made is reported, and the files it would have held are read as they are
+//!
+//! Paths are mapped to the snapshots by their form only, s.t. that can be tested on any platform
+
+use std::path::Path;
+use std::process::Command;
+use std::sync::Mutex;
+use serde::{Serialize, Deserialize};
+use crate::colorutil::printcoln;
+use crate::{interrupt, winpath};
+use termcolor::Color;
+
+// Names of btrfs subvolumes and zfs snapshots start with these, followed by the name of the run (see snapshot_name)
+const SNAPSHOT_PREFIX: &str = "retain-rs-";
+const BTRFS_PREFIX: &str = ".retain-snapshot-";
+
+// The snapshots of the upload in progress
+static ACTIVE: Mutex<Vec<Snapshot>> = Mutex::new(Vec::new());
+
+/// A file system that is snapshotted before uploading, see above
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct SnapshotHook {
+    // Directory whose files are read from the snapshot
+    pub path: String,
+    pub kind: SnapshotKind,
+    // For 'command': the shell commands that create and remove the snapshot, and where it is mounted once created
+    pub create: Option<String>,
+    pub remove: Option<String>,
+    pub mount: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SnapshotKind {
+    Btrfs,
+    Zfs,
+    Command,
+}
+
+impl SnapshotHook {
+    /// Parses a hook from the command line, KIND:PATH with a kind that doesn't need commands
+    pub fn parse(s: &str) -> Result<SnapshotHook, String> {
+        let (kind, path) = match s.split_once(':') {
+            Some(("btrfs", path)) => (SnapshotKind::Btrfs, path),
+            Some(("zfs", path)) => (SnapshotKind::Zfs, path),
+            _ => return Err(format!("Invalid snapshot {}, expected btrfs:PATH or zfs:PATH (commands are set in the config file)", s)),
+        };
+        match Path::new(path).is_absolute() {
+            true => Ok(SnapshotHook { path: path.to_string(), kind, create: None, remove: None, mount: None }),
+            false => Err(format!("Invalid snapshot {}, the path must be absolute", s)),
+        }
+    }
+}
+
+/// A snapshot of the files in 'root', which are read from 'mount'
+#[derive(Clone, Debug, PartialEq)]
+pub struct Snapshot {
+    // Directory or volume it is of, e.g. /home or C:\
+    pub root: String,
+    // Where it is read from, e.g. /home/.retain-snapshot or \\?\GLOBALROOT\Device\HarddiskVolumeShadowCopy3
+    pub mount: String,
+    removal: Removal,
+}
+
+// How a snapshot is removed
+#[derive(Clone, Debug, PartialEq)]
+enum Removal {
+    // ID of the shadow copy
+    #[cfg_attr(not(windows), allow(dead_code))]
+    ShadowCopy(String),
+    // Path of the subvolume
+    Btrfs(String),
+    // Name of the snapshot, dataset@retain-rs-PID
+    Zfs(String),
+    Command(String),
+}
+
+/// The snapshots of an upload, which are read from until this is dropped. Dropping it removes them
+pub struct Snapshots {
+    taken: Vec<Snapshot>,
+}
+
+impl Snapshots {
+    /// Makes the snapshots of 'hooks' for the upload of 'job'. Hooks that fail are left out, with the reason they failed
+    pub fn take(hooks: &[SnapshotHook], job: Option<&str>) -> (Snapshots, Vec<(String, String)>) {
+        let name = snapshot_name(job, std::process::id());
+        Self::make(hooks.iter().map(|hook| (hook.path.clone(), snapshot_hook(hook, &name))))
+    }
+
+    /// Makes a shadow copy of every local volume in 'paths'
+    pub fn shadow_copies(paths: &[String]) -> (Snapshots, Vec<(String, String)>) {
+        let mut volumes: Vec<String> = paths.iter().filter_map(|path| volume(path)).collect();
+        volumes.sort();
+        volumes.dedup();
+        Self::make(volumes.into_iter().map(|volume| {
+            let snapshot = shadow_copy(&volume);
+            (volume, snapshot)
+        }))
+    }
+
+    fn make<I: Iterator<Item = (String, Result<Snapshot, String>)>>(results: I) -> (Snapshots, Vec<(String, String)>) {
+        let mut taken = Vec::new();
+        let mut failed = Vec::new();
+        for (root, result) in results {
+            match result {
+                Ok(snapshot) => taken.push(snapshot),
+                Err(e) => failed.push((root, e)),
+            }
+        }
+        if !taken.is_empty() {
+            // Ctrl-C exits the process until the upload handles it, the snapshots are removed first
+            interrupt::set_handler();
+        }
+        ACTIVE.lock().unwrap().extend(taken.iter().cloned());
+        (Snapshots { taken }, failed)
+    }
+
+    /// Amount of snapshots that are read from
+    pub fn len(&self) -> usize {
+        self.taken.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.taken.is_empty()
+    }
+}
+
+impl Drop for Snapshots {
+    fn drop(&mut self) {
+        let mut active = ACTIVE.lock().unwrap();
+        // Those that are not active anymore were removed by remove_active
+        let (mine, others) = active.drain(..).partition(|s| self.taken.contains(s));
+        *active = others;
+        drop(active);
+        remove_all(mine);
+    }
+}
+
+/// Removes the snapshots that are read from, for exiting the process without dropping them (see interrupt.rs)
+pub fn remove_active() {
+    let active = std::mem::take(&mut *ACTIVE.lock().unwrap());
+    remove_all(active);
+}
+
+fn remove_all(snapshots: Vec<Snapshot>) {
+    for snapshot in snapshots {
+        if let Err(e) = remove(&snapshot.removal) {
+            printcoln(Color::Yellow, format!("Warning: failed to remove the snapshot of {} ({})", snapshot.root, e));
+        }
+    }
+}
+
+// Name of the snapshots of run 'pid' of 'job'
+fn snapshot_name(job: Option<&str>, pid: u32) -> String {
+    match job {
+        Some(job) => format!("{}{}-{}", SNAPSHOT_PREFIX, job, pid),
+        None => format!("{}{}", SNAPSHOT_PREFIX, pid),
+    }
+}
+
+// Whether 'name' is that of a snapshot (without its prefix) of a run that isn't running anymore
+fn is_stale(name: &str) -> bool {
+    match name.rsplit('-').next().and_then(|pid| pid.parse::<u32>().ok()) {
+        Some(pid) => pid != std::process::id() && !is_running(pid),
+        None => false,
+    }
+}
+
+#[cfg(unix)]
+fn is_running(pid: u32) -> bool {
+    // Signal 0 only checks whether the process exists. EPERM means it does, but belongs to someone else
+    let exists = unsafe { libc::kill(pid as libc::pid_t, 0) } == 0;
+    exists || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn is_running(_pid: u32) -> bool {
+    true
+}
+
+/// The path the content of the file at 'path' is read from: its path in the snapshot it is in if there is
+/// one and the file is in it, otherwise where it is now (see winpath::extended)
+pub fn source(path: &str) -> String {
+    let snapshot = ACTIVE.lock().unwrap().iter()
+        .filter(|s| relative(path, &s.root).is_some())
+        .max_by_key(|s| s.root.len())
+        .cloned();
+    if let Some(shadowed) = snapshot.and_then(|s| in_snapshot(path, &s)) {
+        if Path::new(&shadowed).exists() {
+            return shadowed;
+        }
+    }
+    winpath::extended(path)
+}
+
+/// Whether 'dir' is where a snapshot is read from, by this run or the btrfs snapshot of another
+pub fn is_mount(dir: &Path) -> bool {
+    if dir.file_name().and_then(|name| name.to_str()).is_some_and(|name| name.starts_with(BTRFS_PREFIX)) {
+        return true;
+    }
+    let dir = winpath::normalize(&dir.to_string_lossy());
+    ACTIVE.lock().unwrap().iter().any(|s| Path::new(&dir) == Path::new(&s.mount))
+}
+
+/// Root of the local volume 'path' is on, e.g. C:\ for C:\Users\me\file.txt or C:/Users/me/file.txt
+/// None for network shares and relative paths
+pub fn volume(path: &str) -> Option<String> {
+    let path = winpath::normalize(path);
+    let bytes = path.as_bytes();
+    match bytes.len() >= 3 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':' && (bytes[2] == b'\\' || bytes[2] == b'/') {
+        true => Some(format!(r"{}:\", path[..1].to_ascii_uppercase())),
+        false => None,
+    }
+}
+
+/// The path of 'path' in 'snapshot', None if it isn't in what the snapshot is of
+pub fn in_snapshot(path: &str, snapshot: &Snapshot) -> Option<String> {
+    let separator = if volume(&snapshot.root).is_some() { '\\' } else { '/' };
+    let rest = relative(path, &snapshot.root)?;
+    match rest.is_empty() {
+        true => Some(snapshot.mount.clone()),
+        false => Some(format!("{}{}{}", snapshot.mount.trim_end_matches(separator), separator, rest)),
+    }
+}
+
+// The part of 'path' below 'root', None if it isn't in it
+fn relative(path: &str, root: &str) -> Option<String> {
+    let path = winpath::normalize(path);
+    if volume(root).is_some() {
+        return match volume(&path) {
+            // Windows accepts '/' as separator too, the snapshot is joined with '\'
+            Some(v) if v == root => Some(path[root.len()..].replace('/', "\\")),
+            _ => None,
+        };
+    }
+    match path.strip_prefix(root.trim_end_matches('/')) {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => Some(rest.trim_start_matches('/').to_string()),
+        _ => None,
+    }
+}
+
+// Makes the snapshot of 'hook', named 'name' if the kind names it
+fn snapshot_hook(hook: &SnapshotHook, name: &str) -> Result<Snapshot, String> {
+    let root = match hook.path.trim_end_matches('/') {
+        "" => "/".to_string(),
+        root => root.to_string(),
+    };
+    match hook.kind {
+        SnapshotKind::Btrfs => {
+            let dir = root.trim_end_matches('/');
+            // Left behind by uploads that were killed
+            for entry in std::fs::read_dir(&root).map_err(|e| e.to_string())?.flatten() {
+                let file_name = entry.file_name().to_string_lossy().to_string();
+                if file_name.strip_prefix(BTRFS_PREFIX).and_then(|rest| rest.strip_prefix(SNAPSHOT_PREFIX)).is_some_and(is_stale) {
+                    let _ = run(Command::new("btrfs").args(["subvolume", "delete", &format!("{}/{}", dir, file_name)]));
+                }
+            }
+            let mount = format!("{}/{}{}", dir, BTRFS_PREFIX, name);
+            run(Command::new("btrfs").args(["subvolume", "snapshot", "-r", &root, &mount]))?;
+            Ok(Snapshot { root, mount: mount.clone(), removal: Removal::Btrfs(mount) })
+        },
+        SnapshotKind::Zfs => {
+            // Output looks like: tank/home	/home
+            let output = run(Command::new("zfs").args(["list", "-H", "-o", "name,mountpoint", &root]))?;
+            let (dataset, mountpoint) = match output.split_once('\t') {
+                Some((dataset, mountpoint)) if mountpoint == root => (dataset.to_string(), mountpoint.to_string()),
+                _ => return Err(format!("{} is not the mount point of a dataset", root)),
+            };
+            // Left behind by uploads that were killed
+            let existing = run(Command::new("zfs").args(["list", "-H", "-t", "snapshot", "-o", "name", "-d", "1", &dataset]))?;
+            for snapshot in existing.lines() {
+                let stale = snapshot.split_once('@').and_then(|(_, s)| s.strip_prefix(SNAPSHOT_PREFIX)).is_some_and(is_stale);
+                if stale {
+                    let _ = run(Command::new("zfs").args(["destroy", snapshot]));
+                }
+            }
+            let snapshot = format!("{}@{}", dataset, name);
+            run(Command::new("zfs").args(["snapshot", &snapshot]))?;
+            let mount = format!("{}/.zfs/snapshot/{}", mountpoint.trim_end_matches('/'), name);
+            Ok(Snapshot { root, mount, removal: Removal::Zfs(snapshot) })
+        },
+        SnapshotKind::Command => {
+            let (create, remove, mount) = match (&hook.create, &hook.remove, &hook.mount) {
+                (Some(create), Some(remove), Some(mount)) => (create, remove, mount),
+                _ => return Err("a command snapshot needs 'create', 'remove' and 'mount'".to_string()),
+            };
+            run(Command::new("sh").args(["-c", create]))?;
+            Ok(Snapshot { root, mount: mount.trim_end_matches('/').to_string(), removal: Removal::Command(remove.clone()) })
+        },
+    }
+}
+
+fn remove(removal: &Removal) -> Result<(), String> {
+    match removal {
+        Removal::ShadowCopy(id) => remove_shadow_copy(id),
+        Removal::Btrfs(path) => run(Command::new("btrfs").args(["subvolume", "delete", path])).map(|_| ()),
+        Removal::Zfs(name) => run(Command::new("zfs").args(["destroy", name])).map(|_| ()),
+        Removal::Command(command) => run(Command::new("sh").args(["-c", command])).map(|_| ()),
+    }
+}
+
+// Runs 'command', returning what it printed
+fn run(command: &mut Command) -> Result<String, String> {
+    let output = command.output().map_err(|e| format!("failed to run {:?} ({})", command, e))?;
+    match output.status.success() {
+        true => Ok(String::from_utf8_lossy(&output.stdout).trim().to_string()),
+        false => Err(format!("{:?} failed: {}", command, String::from_utf8_lossy(&output.stderr).trim())),
+    }
+}
+
+#[cfg(windows)]
+fn shadow_copy(volume: &str) -> Result<Snapshot, String> {
+    let script = format!(
+        "$r = (Get-WmiObject -List Win32_ShadowCopy).Create('{}', 'ClientAccessible'); \
+         if ($r.ReturnValue -ne 0) {{ Write-Output \"error $($r.ReturnValue)\"; exit }}; \
+         $s = Get-WmiObject Win32_ShadowCopy | Where-Object {{ $_.ID -eq $r.ShadowID }}; \
+         Write-Output \"$($s.ID) $($s.DeviceObject)\"", volume);
+    // Output looks like: {XXXXXXXX-XXXX-XXXX-XXXX-XXXXXXXXXXXX} \\?\GLOBALROOT\Device\HarddiskVolumeShadowCopy3
+    let output = powershell(&script)?;
+    let mut parts = output.split_whitespace();
+    match (parts.next(), parts.next()) {
+        (Some(id), Some(device)) if id.starts_with('{') && device.starts_with(r"\\?\") =>
+            Ok(Snapshot { root: volume.to_string(), mount: device.to_string(), removal: Removal::ShadowCopy(id.to_string()) }),
+        // Return values are listed at https://learn.microsoft.com/en-us/previous-versions/windows/desktop/vsswmi/create-method-in-class-win32-shadowcopy
+        _ if output == "error 1" => Err("access denied, run as administrator".to_string()),
+        _ if output.is_empty() => Err("no output from PowerShell".to_string()),
+        _ => Err(output),
+    }
+}
+
+#[cfg(windows)]
+fn remove_shadow_copy(id: &str) -> Result<(), String> {
+    powershell(&format!("Get-WmiObject Win32_ShadowCopy | Where-Object {{ $_.ID -eq '{}' }} | ForEach-Object {{ $_.Delete() }}", id))
+        .map(|_| ())
+        .map_err(|e| format!("{}, see 'vssadmin delete shadows'", e))
+}
+
+#[cfg(windows)]
+fn powershell(script: &str) -> Result<String, String> {
+    run(Command::new("powershell").args(["-NoProfile", "-NonInteractive", "-Command", script]))
+}
+
+#[cfg(not(windows))]
+fn shadow_copy(_volume: &str) -> Result<Snapshot, String> {
+    Err("shadow copies are only available on Windows".to_string())
+}
+
+#[cfg(not(windows))]
+fn remove_shadow_copy(_id: &str) -> Result<(), String> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::shadow::{volume, in_snapshot, is_mount, is_stale, snapshot_name, Snapshot, SnapshotHook, SnapshotKind, Removal};
+
+    #[test]
+    fn test_shadow_paths() {
+        assert_eq!(volume(r"C:\Users\me\file.txt"), Some(r"C:\".to_string()));
+        assert_eq!(volume(r"\\?\d:\data.db"), Some(r"D:\".to_string()));
+        assert_eq!(volume(r"\\server\share\file.txt"), None);
+        assert_eq!(volume("/home/me/file.txt"), None);
+        assert_eq!(volume("C:"), None);
+        assert_eq!(volume("c:/Users/me/file.txt"), Some(r"C:\".to_string()));
+
+        let snapshot = Snapshot {
+            root: r"C:\".to_string(),
+            mount: r"\\?\GLOBALROOT\Device\HarddiskVolumeShadowCopy3".to_string(),
+            removal: Removal::ShadowCopy("{0}".to_string()),
+        };
+        assert_eq!(in_snapshot(r"C:\Users\me\Outlook.pst", &snapshot).unwrap(),
+                   r"\\?\GLOBALROOT\Device\HarddiskVolumeShadowCopy3\Users\me\Outlook.pst");
+        assert_eq!(in_snapshot(r"\\?\C:\Windows\System32\config\SAM", &snapshot).unwrap(),
+                   r"\\?\GLOBALROOT\Device\HarddiskVolumeShadowCopy3\Windows\System32\config\SAM");
+        assert_eq!(in_snapshot("C:/Users/me/Outlook.pst", &snapshot).unwrap(),
+                   r"\\?\GLOBALROOT\Device\HarddiskVolumeShadowCopy3\Users\me\Outlook.pst");
+        assert_eq!(in_snapshot(r"D:\data.db", &snapshot), None);
+    }
+
+    #[test]
+    fn test_snapshot_paths() {
+        let snapshot = Snapshot {
+            root: "/home".to_string(),
+            mount: "/home/.zfs/snapshot/retain-rs".to_string(),
+            removal: Removal::Zfs("tank/home@retain-rs".to_string()),
+        };
+        assert_eq!(in_snapshot("/home/me/notes.txt", &snapshot).unwrap(), "/home/.zfs/snapshot/retain-rs/me/notes.txt");
+        assert_eq!(in_snapshot("/home", &snapshot).unwrap(), "/home/.zfs/snapshot/retain-rs");
+        assert_eq!(in_snapshot("/homework/notes.txt", &snapshot), None);
+        let whole = Snapshot { root: "/".to_string(), mount: "/mnt/snap/".to_string(), removal: Removal::Command("true".to_string()) };
+        assert_eq!(in_snapshot("/etc/fstab", &whole).unwrap(), "/mnt/snap/etc/fstab");
+
+        assert_eq!(SnapshotHook::parse("btrfs:/home").unwrap().kind, SnapshotKind::Btrfs);
+        assert_eq!(SnapshotHook::parse("zfs:/srv/data").unwrap().path, "/srv/data");
+        assert!(SnapshotHook::parse("lvm:/srv").is_err());
+        assert!(SnapshotHook::parse("zfs:srv").is_err());
+    }
+
+    #[test]
+    fn test_snapshot_names() {
+        let pid = std::process::id();
+        assert_eq!(snapshot_name(None, 42), "retain-rs-42");
+        assert_eq!(snapshot_name(Some("my-photos"), 42), "retain-rs-my-photos-42");
+        // Those of this run are in use, and names without a PID aren't those of a run
+        assert!(!is_stale(&snapshot_name(Some("my-photos"), pid)["retain-rs-".len()..]));
+        assert!(!is_stale("photos"));
+        assert!(is_mount(std::path::Path::new("/home/.retain-snapshot-retain-rs-42")));
+        assert!(!is_mount(std::path::Path::new("/home/.retain-snapshots")));
+    }
+}
@@ -0,0 +1,187 @@
+//! Sparse file support
+//!
+//! Sparse files (e.g. VM images) contain holes that take up no disk space and read as zeroes
+//! Instead of uploading the holes, only the data extents are uploaded one after another,
+//! and their offsets and lengths are stored in the manifest
+//! Restoring writes each extent at its offset, which leaves the holes in between unallocated
+//!
+//! Holes are found with SEEK_DATA/SEEK_HOLE, which is only used on Linux and FreeBSD
+//! Elsewhere, and on file systems without support for it, files are uploaded in full
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+// Files smaller than this are always uploaded in full
+pub const SPARSE_THRESHOLD: u64 = 1024*1024;
+
+/// Returns the data extents (offset, length) of the file, or None if it has no holes
+#[cfg(any(target_os = "linux", target_os = "freebsd"))]
+pub fn data_extents(file: &File, size: u64) -> std::io::Result<Option<Vec<(u64, u64)>>> {
+    use std::os::unix::io::AsRawFd;
+    let fd = file.as_raw_fd();
+    let mut extents = Vec::new();
+    let mut pos = 0;
+    while pos < size {
+        let data = unsafe { libc::lseek(fd, pos as libc::off_t, libc::SEEK_DATA) };
+        if data < 0 {
+            let err = std::io::Error::last_os_error();
+            match err.raw_os_error() {
+                // The rest of the file is a hole
+                Some(libc::ENXIO) => break,
+                // Holes can't be found on this file system
+                Some(libc::EINVAL) => return Ok(None),
+                _ => return Err(err),
+            }
+        }
+        let hole = unsafe { libc::lseek(fd, data, libc::SEEK_HOLE) };
+        if hole < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let end = (hole as u64).min(size);
+        if end > data as u64 {
+            extents.push((data as u64, end - data as u64));
+        }
+        pos = hole as u64;
+    }
+    if extents.len() == 1 && extents[0] == (0, size) {
+        Ok(None)
+    } else {
+        Ok(Some(extents))
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "freebsd")))]
+pub fn data_extents(_file: &File, _size: u64) -> std::io::Result<Option<Vec<(u64, u64)>>> {
+    Ok(None)
+}
+
+/// Total length of the given extents, i.e. the amount of data that is uploaded
+pub fn data_length(extents: &[(u64, u64)]) -> u64 {
+    extents.iter().map(|e| e.1).sum()
+}
+
+/// Reads the data extents of a file one after another
+pub struct SparseReader {
+    file: File,
+    extents: Vec<(u64, u64)>,
+    // Index of the current extent and how much of it is left
+    idx: usize,
+    remaining: u64,
+}
+
+impl SparseReader {
+    pub fn open(path: &str, extents: &[(u64, u64)]) -> std::io::Result<Self> {
+        Ok(SparseReader {
+            file: File::open(path)?,
+            extents: extents.to_vec(),
+            idx: 0,
+            remaining: 0,
+        })
+    }
+}
+
+impl Read for SparseReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.remaining == 0 {
+            let (offset, length) = match self.extents.get(self.idx) {
+                Some(e) => *e,
+                None => return Ok(0),
+            };
+            self.file.seek(SeekFrom::Start(offset))?;
+            self.remaining = length;
+            self.idx += 1;
+        }
+        let max = buf.len().min(self.remaining as usize);
+        let n = self.file.read(&mut buf[..max])?;
+        if n == 0 {
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "file shrunk while reading it"));
+        }
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+/// Writes data produced by a SparseReader back to the extents it came from
+/// Flushing sets the file to its full size, s.t. a trailing hole is recreated too
+pub struct SparseWriter {
+    file: File,
+    extents: Vec<(u64, u64)>,
+    size: u64,
+    idx: usize,
+    remaining: u64,
+}
+
+impl SparseWriter {
+    /// 'file' should be empty, s.t. everything outside the extents is a hole
+    pub fn new(file: File, extents: &[(u64, u64)], size: u64) -> Self {
+        SparseWriter {
+            file,
+            extents: extents.to_vec(),
+            size,
+            idx: 0,
+            remaining: 0,
+        }
+    }
+}
+
+impl Write for SparseWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        while self.remaining == 0 {
+            let (offset, length) = match self.extents.get(self.idx) {
+                Some(e) => *e,
+                None => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "more data than the sparse map describes")),
+            };
+            self.file.seek(SeekFrom::Start(offset))?;
+            self.remaining = length;
+            self.idx += 1;
+        }
+        let max = buf.len().min(self.remaining as usize);
+        let n = self.file.write(&buf[..max])?;
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.set_len(self.size)?;
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sparse::{data_extents, data_length, SparseReader, SparseWriter};
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    #[test]
+    fn test_sparse_roundtrip() {
+        let dir = std::env::temp_dir();
+        let source = dir.join("retain-test-sparse-in");
+        let restored = dir.join("retain-test-sparse-out");
+        let size = 8*1024*1024;
+        {
+            let mut file = std::fs::File::create(&source).unwrap();
+            file.set_len(size).unwrap();
+            file.write_all(&[1u8; 4096]).unwrap();
+            file.seek(SeekFrom::Start(4*1024*1024)).unwrap();
+            file.write_all(&[2u8; 4096]).unwrap();
+        }
+
+        // File systems without hole support report the whole file as data
+        let file = std::fs::File::open(&source).unwrap();
+        let extents = data_extents(&file, size).unwrap().unwrap_or_else(|| vec![(0, size)]);
+        assert!(data_length(&extents) >= 8192);
+
+        let mut data = Vec::new();
+        SparseReader::open(source.to_str().unwrap(), &extents).unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(data.len() as u64, data_length(&extents));
+
+        let mut writer = SparseWriter::new(std::fs::File::create(&restored).unwrap(), &extents, size);
+        writer.write_all(&data).unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+        assert!(std::fs::read(&source).unwrap() == std::fs::read(&restored).unwrap());
+
+        let _ = std::fs::remove_file(&source);
+        let _ = std::fs::remove_file(&restored);
+    }
+}
@@ -1,51 +1,293 @@
-use crate::config::Config;
-use crate::colorutil::printcoln;
+use crate::config::{Config, DEFAULT_THREADS};
+use clap::ArgMatches;
+use crate::colorutil::{printcoln, printsummary};
 use termcolor::Color;
-use chacha20poly1305::Key;
-use std::sync::{Mutex, mpsc};
-use raze::api::{ListBucketParams, B2DownloadFileByNameParams};
-use crate::manifest::FileManifest;
+use std::sync::{Arc, Mutex};
+use crate::manifest::{self, FileManifest};
 use std::fs::File;
-use std::io::Write;
+use std::io::{Cursor, Read, Write};
 use crate::encryption::writer::DecryptingWriter;
-use scoped_pool::Pool;
-use std::sync::atomic::{AtomicUsize, Ordering, AtomicBool};
-use std::time::Duration;
-use std::process::abort;
-
-// This will start retrieving files previously backed up
-// This will:
-// 1. Check that everything in the config is set
-// 2. Read the manifest.json
-// 3. For each file in the manifest, check if it is present on the drive
-// 4. All files not present are retrieved from remote
-// 5. If the file is found, check if the remote version is more recent
-// 6. If it is more recent, replace existing file with remote one
-pub fn start(config: &Config) {
+use crate::encryption::keys::Keys;
+use std::sync::atomic::{Ordering, AtomicBool};
+use crate::manifest::FileEntry;
+use crate::progress::Progress;
+use crate::summary::Summary;
+use crate::encryption::{self, get_encrypted_size, BlockSize, NonceMode, DATA_LENGTH};
+use crate::retry;
+use crate::transfer;
+use crate::delta;
+use std::fs::OpenOptions;
+use crate::budget::{Budget, LimitAction};
+use crate::egress::DownloadCap;
+use crate::throttle;
+use indicatif::HumanBytes;
+use crate::capabilities::{self, Operation};
+use crate::pattern::PathPattern;
+use crate::versions;
+use crate::b2::FileVersion;
+use crate::backend::{self, Backend, BackendError, Download};
+use crate::datetime;
+use crate::hardlink;
+use crate::xattrs;
+use crate::sparse::{self, SparseWriter};
+use crate::winpath;
+use crate::moves;
+use std::collections::{HashMap, HashSet};
+use indicatif::ProgressBar;
+
+/// Options of a download, the command line sets them with 'backup download', 'snapshot restore', 'browse' and 'export'
+#[derive(Clone, Debug, Default)]
+pub struct DownloadOptions {
+    // Only the files matching one of these are downloaded, every file if there are none
+    pub patterns: Vec<PathPattern>,
+    // Files matching any of these are skipped, even if they match 'patterns'
+    pub excludes: Vec<PathPattern>,
+    // None means the configured amount of download threads
+    pub threads: Option<usize>,
+    // Bytes to download at most, None means no limit (see egress.rs)
+    pub max_download: Option<u64>,
+    // Restore the state as of this time (ms since Unix Epoch) instead of the latest
+    pub as_of: Option<u64>,
+    // Restore the files as they were in the snapshot with this name instead of the latest (see subcommands/snapshot.rs)
+    pub snapshot: Option<String>,
+}
+
+impl DownloadOptions {
+    /// Reads the options from the command line, the patterns from the paths given
+    pub fn from_args(args: &ArgMatches) -> Result<Self, String> {
+        if args.is_present("resume") {
+            return Err("--resume only applies to 'backup upload'".to_string());
+        }
+        Ok(DownloadOptions {
+            patterns: patterns_of(args, "path")?,
+            excludes: patterns_of(args, "exclude")?,
+            // Validated by clap
+            threads: args.value_of("threads").map(|s| s.parse::<usize>().unwrap()),
+            max_download: args.value_of("maxdownload").map(|s| throttle::parse_size(s).unwrap()),
+            as_of: args.value_of("asof").map(datetime::parse_datetime).transpose()?,
+            snapshot: args.value_of("snapshot").map(|s| s.to_string()),
+        })
+    }
+}
+
+/// Parses the path patterns given for the argument 'name'
+pub fn patterns_of(args: &ArgMatches, name: &str) -> Result<Vec<PathPattern>, String> {
+    args.values_of(name).into_iter().flatten().map(PathPattern::parse).collect()
+}
+
+/// Starts retrieving files previously backed up
+/// This will:
+/// 1. Check that everything in the config is set
+/// 2. Read the manifest.json
+/// 3. For each file in the manifest, check if it is present on the drive
+/// 4. All files not present are retrieved from remote
+/// 5. If the file is found, check if the remote version is more recent
+/// 6. If it is more recent, replace existing file with remote one
+///
+/// Fails if the download can't start. Files that fail to download are in the summary instead (see summary.rs)
+pub fn start(config: &Config, options: &DownloadOptions) -> Result<(), String> {
     let t_start = std::time::Instant::now();
+    let summary = Summary::new("download", "downloaded", config);
     // If this succeeds, all values are set and we can unwrap them
-    match &config.is_configured() {
-        Ok(_) => (),
-        Err(err) => {
-            printcoln(Color::Red, format!("Invalid config ({})", err));
-            return;
+    config.is_configured().map_err(|err| format!("Invalid config ({})", err))?;
+
+    let threads = options.threads.unwrap_or(config.download_threads.unwrap_or(DEFAULT_THREADS));
+
+    let max_attempts = config.max_attempts.unwrap_or(retry::DEFAULT_MAX_ATTEMPTS);
+
+    let budget = Arc::new(Budget::new(config));
+    let cap = DownloadCap::new(options.max_download, config.download_warnings.as_deref().unwrap_or(&[]),
+                               config.limit_action.unwrap_or(LimitAction::Abort));
+
+    let as_of = options.as_of;
+    let (keys, backend) = connect(config, budget.clone(), as_of.is_some(), t_start)?;
+
+    // When restoring to a point in time, every remote name is resolved to the version that was current at that time
+    // This includes manifest.json, s.t. we restore the files that were backed up at that time
+    let versions = match as_of {
+        Some(t) => {
+            printcoln(Color::Green, format!("[{:.3}] Restoring as of {}", t_start.elapsed().as_secs_f32(), datetime::format_millis(t)));
+            printcoln(Color::Yellow, format!("[{:.3}] Retrieving list of remote file versions, this may take a while...", t_start.elapsed().as_secs_f32()));
+            let v = backend.versions().map_err(|e| format!("[{:.3}] Failed to retrieve file versions ({})", t_start.elapsed().as_secs_f32(), e))?;
+            Some(versions::versions_as_of(v, t))
+        },
+        None => None,
+    };
+
+    let snapshot = options.snapshot.as_deref();
+    let mut manifest = match (&versions, snapshot) {
+        (_, Some(name)) => snapshot_manifest(backend.as_ref(), &budget, keys.as_ref(), name, t_start)?,
+        // The local manifest is left alone, since it describes the current state
+        (Some(versions), None) => manifest_as_of(backend.as_ref(), &budget, versions, keys.as_ref())
+            .map_err(|e| format!("[{:.3}] Failed to retrieve manifest ({})", t_start.elapsed().as_secs_f32(), e))?,
+        (None, None) => latest_manifest(backend.as_ref(), &budget, keys.as_ref(), t_start)?,
+    };
+
+    // Hard links are looked up in the complete manifest, since their target may not match the given paths
+    let all_files = manifest.files.clone();
+    summary.scanned(all_files.len());
+
+    // Only keep entries that are missing locally or outdated, s.t. we know how much there is to download
+    // The remote size of entries from before sizes were tracked and of compressed entries is unknown,
+    // their size is added once their download starts
+    select(&mut manifest.files, &options.patterns, &options.excludes, &summary, t_start);
+    // Hard links are made once the files they link to are downloaded
+    let mut links = Vec::new();
+    manifest.files.retain(|e| {
+        if e.link.is_some() {
+            links.push(e.clone());
+        }
+        e.link.is_none()
+    });
+    // When restoring to a point in time or a snapshot, files are restored unless they are exactly as they were then
+    let exact = versions.is_some() || snapshot.is_some();
+    if let Some(versions) = &versions {
+        manifest.files.retain(|e| {
+            let found = versions.contains_key(&e.mask);
+            if !found {
+                printcoln(Color::Yellow, format!("[{:.3}] No version of {} from that time was found, skipping it", t_start.elapsed().as_secs_f32(), e.path));
+            }
+            found
+        });
+    }
+    manifest.files.retain(|e| {
+        let needed = needs_download(e, exact);
+        if !needed {
+            // What an earlier download left of it isn't needed anymore
+            remove_part(&e.path);
+            summary.unchanged(1);
+        }
+        needed
+    });
+
+    // Links are remade if their target is downloaded, since that replaces the file they linked to
+    // A link whose target isn't downloaded and doesn't exist locally is downloaded as a copy of the target instead
+    let downloaded: HashSet<String> = manifest.files.iter().map(|e| e.path.clone()).collect();
+    links.retain(|link| {
+        let target_path = link.link.as_ref().unwrap();
+        let target = match all_files.binary_search_by(|e| e.path.cmp(target_path)) {
+            Ok(n) => &all_files[n],
+            Err(_) => {
+                printcoln(Color::Yellow, format!("[{:.3}] {} links to {}, which isn't backed up, skipping it", t_start.elapsed().as_secs_f32(), link.path, target_path));
+                return false;
+            }
+        };
+        if versions.as_ref().map_or(false, |v| !v.contains_key(&target.mask)) {
+            printcoln(Color::Yellow, format!("[{:.3}] No version of {} from that time was found, skipping it", t_start.elapsed().as_secs_f32(), link.path));
+            return false;
+        }
+        if downloaded.contains(target_path) {
+            return true;
+        }
+        if !needs_download(link, exact) {
+            summary.unchanged(1);
+            return false;
+        }
+        if std::path::Path::new(target_path).is_file() {
+            return true;
         }
+        let mut copy = target.clone();
+        copy.path = link.path.clone();
+        manifest.files.push(copy);
+        false
+    });
+    let total_bytes: u64 = manifest.files.iter()
+        .map(|e| remote_size(e, config.encrypt.unwrap()))
+        .sum();
+    let file_count = manifest.files.len();
+
+    let queue = manifest.files;
+    printcoln(Color::Green, format!("[{:.3}] Loaded manifest ({} files to download)", t_start.elapsed().as_secs_f32(), file_count));
+    if let Some(max) = cap.max().filter(|m| total_bytes > *m) {
+        printcoln(Color::Yellow, format!("[{:.3}] At least {} is to be downloaded, at most {} is downloaded {}",
+                                         t_start.elapsed().as_secs_f32(), HumanBytes(total_bytes), HumanBytes(max),
+                                         match config.limit_action.unwrap_or(LimitAction::Abort) {
+                                             LimitAction::Pause => "per day",
+                                             LimitAction::Abort => "in this run",
+                                         }));
     }
 
+    let mut runtime = transfer::runtime().map_err(|e| format!("[{:.3}] Failed to start the transfer runtime ({})", t_start.elapsed().as_secs_f32(), e))?;
+
+    // Stop starting new downloads when interrupted
+    // Large files in progress stop at the next block, keeping their .part files to continue from (see transfer.rs)
+    let cancel = transfer::Cancel::on_interrupt(move || {
+        printcoln(Color::Yellow, format!("[{:.3}] Interrupt received", t_start.elapsed().as_secs_f32()));
+        printcoln(Color::Yellow, format!("[{:.3}] Stopping downloads in progress, the next download continues from their .part files...", t_start.elapsed().as_secs_f32()));
+    });
+
+    let progress = Progress::new(total_bytes, file_count, threads);
+    let downloader = Arc::new(Downloader {
+        backend,
+        versions,
+        keys,
+        exact,
+        max_attempts,
+        bars: Mutex::new((0..threads).map(|i| progress.worker(i)).collect()),
+        progress,
+        budget,
+        cap,
+        summary,
+        budget_exceeded: AtomicBool::new(false),
+        unverified: Mutex::new(Vec::new()),
+        cancel: cancel.clone(),
+    });
+
+    // Up to 'threads' entries are downloaded at a time
+    // If the local file exists, their modified times are compared. If remote is more recent, local is replaced
+    // Otherwise, the local version is kept
+    // If the local file does not exist, it is retrieved from remote
+    runtime.block_on(transfer::for_each_bounded(queue, threads, &cancel, |entry| downloader.clone().download_entry(entry)));
+    downloader.progress.finish();
+    if cancel.is_cancelled() {
+        // No files are open and no new ones can be opened
+        printcoln(Color::Yellow, format!("[{:.3}] Download cancelled, the next download continues where it stopped", t_start.elapsed().as_secs_f32()));
+        return Ok(());
+    }
+
+    let summary = &downloader.summary;
+    for link in &links {
+        let target = link.link.as_ref().unwrap();
+        match hardlink::restore_link(target, &link.path) {
+            Ok(_) => summary.record(&link.path, true),
+            Err(e) => {
+                printcoln(Color::Red, format!("[{:.3}] Failed to link {} to {} ({})", t_start.elapsed().as_secs_f32(), link.path, target, e));
+                summary.fail(&link.path);
+            },
+        }
+    }
+    if !links.is_empty() {
+        printcoln(Color::Green, format!("[{:.3}] Restored {} hard links", t_start.elapsed().as_secs_f32(), links.len()));
+    }
+    let unverified = downloader.unverified.lock().unwrap();
+    if !unverified.is_empty() {
+        printsummary(Color::Red, format!("[{:.3}] {} restored files failed verification, they don't match the backed up version:",
+                                         t_start.elapsed().as_secs_f32(), unverified.len()));
+        for (path, reason) in unverified.iter() {
+            printsummary(Color::Red, format!("\t{} ({})", path, reason));
+        }
+    }
+    printsummary(Color::Green, format!("[{:.3}] API calls: {}", t_start.elapsed().as_secs_f32(), downloader.budget.summary()));
+    if downloader.budget_exceeded.load(Ordering::SeqCst) {
+        printcoln(Color::Yellow, format!("[{:.3}] Not all files were downloaded, run 'backup download' again to download the rest",
+                                         t_start.elapsed().as_secs_f32()));
+    }
+
+    printsummary(Color::Green, format!("[{:.3}] Download Completed!", t_start.elapsed().as_secs_f32()));
+    summary.finish(Some(downloader.progress.transferred()));
+    Ok(())
+}
+
+// Loads the keys if encryption is enabled, and connects to the bucket
+// Fails if either fails, or if the App Key can't download (file versions, if 'versions' is set)
+fn connect(config: &Config, budget: Arc<Budget>, versions: bool, t_start: std::time::Instant) -> Result<(Option<Keys>, Box<dyn Backend>), String> {
     // Get encryption status
-    let mut key = None;
+    let mut keys = None;
     match config.encrypt.unwrap() {
         true => {
             printcoln(Color::Green, "Encryption is enabled");
-            match std::fs::read(config.secret_key.as_ref().unwrap()) {
-                Ok(bytes) => {
-                    key = Some(Key::clone_from_slice(&bytes));
-                }
-                Err(err) => {
-                    printcoln(Color::Red, format!("[{:.3}] Failed to open key-file {:?}", t_start.elapsed().as_secs_f32(), err));
-                    return;
-                }
-            }
+            // Retired keys are loaded too, s.t. files they encrypted can be restored
+            keys = Some(Keys::from_config(config).map_err(|err| format!("[{:.3}] {}", t_start.elapsed().as_secs_f32(), err))?);
             printcoln(Color::Green, format!("[{:.3}] Init OK", t_start.elapsed().as_secs_f32()));
         }
         false => {
@@ -53,326 +295,775 @@ pub fn start(config: &Config) {
         }
     }
 
-    // Authenticate
+    // Connect to the bucket
     // We need to do this early in order to retrieve manifest.json from remote
-    let client = reqwest::blocking::Client::builder().timeout(None).build().unwrap();
-    printcoln(Color::Green, format!("[{:.3}] Authenticating...", t_start.elapsed().as_secs_f32()));
-
-    let keystring = format!("{}:{}", config.app_key_id.as_ref().unwrap(), config.app_key.as_ref().unwrap());
-    let auth = match raze::api::b2_authorize_account(&client,keystring) {
-        Ok(a) => a,
-        Err(_e) => {
-            printcoln(Color::Red, format!("[{:.3}] Authentication failure", t_start.elapsed().as_secs_f32()));
+    let backend = backend::connect(config, budget, t_start).map_err(|e| format!("[{:.3}] {}", t_start.elapsed().as_secs_f32(), e))?;
+    capabilities::require(&backend.check_access(Operation::Download { versions }), t_start)
+        .map_err(|e| format!("[{:.3}] {}", t_start.elapsed().as_secs_f32(), e))?;
+    printcoln(Color::Green, format!("[{:.3}] Downloading from {}", t_start.elapsed().as_secs_f32(), backend.describe()));
+    Ok((keys, backend))
+}
+
+// Keeps the entries matching one of 'patterns' (all of them if there are none) and none of 'excludes'
+// Paths are matched against the original paths, which are kept in the manifest even when names are masked
+fn select(files: &mut Vec<FileEntry>, patterns: &[PathPattern], excludes: &[PathPattern], summary: &Summary, t_start: std::time::Instant) {
+    if !patterns.is_empty() {
+        let before = files.len();
+        files.retain(|e| patterns.iter().any(|p| p.matches(&e.path)));
+        summary.excluded(before - files.len());
+        printcoln(Color::Green, format!("[{:.3}] {} files match the given paths", t_start.elapsed().as_secs_f32(), files.len()));
+    }
+    if !excludes.is_empty() {
+        let before = files.len();
+        files.retain(|e| !excludes.iter().any(|p| p.matches(&e.path)));
+        summary.excluded(before - files.len());
+        printcoln(Color::Green, format!("[{:.3}] {} files excluded", t_start.elapsed().as_secs_f32(), before - files.len()));
+    }
+}
+
+/// Downloads backed up files one at a time, each to a path of choice instead of its own (see subcommands/export.rs)
+pub struct Fetcher {
+    downloader: Downloader,
+    bar: ProgressBar,
+    t_start: std::time::Instant,
+}
+
+impl Fetcher {
+    /// Connects to the bucket and retrieves the latest manifest, like 'backup download'
+    /// Returns the entries matching the patterns and excludes of 'options'. A hard link is kept if its target is too,
+    /// else it is replaced by a copy of its target. The other options only matter to 'start'
+    pub fn connect(config: &Config, options: &DownloadOptions, t_start: std::time::Instant) -> Result<(Self, Vec<FileEntry>), String> {
+        let summary = Summary::new("export", "exported", config);
+        config.is_configured().map_err(|err| format!("Invalid config ({})", err))?;
+        let budget = Arc::new(Budget::new(config));
+        let cap = DownloadCap::new(options.max_download, config.download_warnings.as_deref().unwrap_or(&[]),
+                                   config.limit_action.unwrap_or(LimitAction::Abort));
+
+        let (keys, backend) = connect(config, budget.clone(), false, t_start)?;
+        let all_files = latest_manifest(backend.as_ref(), &budget, keys.as_ref(), t_start)?.files;
+        summary.scanned(all_files.len());
+        let mut files = all_files.clone();
+        select(&mut files, &options.patterns, &options.excludes, &summary, t_start);
+        let selected: HashSet<String> = files.iter().filter(|e| e.link.is_none()).map(|e| e.path.clone()).collect();
+        files.retain_mut(|link| {
+            let target_path = match &link.link {
+                Some(target) if !selected.contains(target) => target,
+                _ => return true,
+            };
+            match all_files.binary_search_by(|e| e.path.cmp(target_path)) {
+                Ok(n) => {
+                    let path = std::mem::take(&mut link.path);
+                    *link = FileEntry { path, ..all_files[n].clone() };
+                    true
+                },
+                Err(_) => {
+                    printcoln(Color::Yellow, format!("[{:.3}] {} links to {}, which isn't backed up, skipping it", t_start.elapsed().as_secs_f32(), link.path, target_path));
+                    false
+                },
+            }
+        });
+
+        let total_bytes: u64 = files.iter()
+            .filter(|e| e.link.is_none())
+            .map(|e| remote_size(e, keys.is_some()))
+            .sum();
+        let progress = Progress::new(total_bytes, files.iter().filter(|e| e.link.is_none()).count(), 1);
+        let bar = progress.worker(0);
+        let downloader = Downloader {
+            backend,
+            versions: None,
+            keys,
+            exact: false,
+            max_attempts: config.max_attempts.unwrap_or(retry::DEFAULT_MAX_ATTEMPTS),
+            bars: Mutex::new(vec![]),
+            progress,
+            budget,
+            cap,
+            summary,
+            budget_exceeded: AtomicBool::new(false),
+            unverified: Mutex::new(Vec::new()),
+            // Exports don't handle interrupts, pressing Ctrl-C exits right away
+            cancel: transfer::Cancel::never(),
+        };
+        Ok((Fetcher { downloader, bar, t_start }, files))
+    }
+
+    /// Downloads the content of 'entry' to 'path', decrypting and decompressing it and applying its deltas
+    /// Returns whether it succeeded. Once the download limit or API call budget is used up, nothing more is downloaded
+    pub fn fetch(&self, entry: &FileEntry, path: &str) -> bool {
+        let downloader = &self.downloader;
+        let expected_size = remote_size(entry, downloader.keys.is_some());
+        if downloader.budget_exceeded.load(Ordering::SeqCst) {
+            downloader.progress.skip(expected_size);
+            return false;
+        }
+        if let Err(e) = downloader.cap.admit(expected_size) {
+            downloader.progress.println(format!("Not downloading {} ({})", entry.path, e));
+            downloader.budget_exceeded.store(true, Ordering::SeqCst);
+            downloader.progress.skip(expected_size);
+            return false;
+        }
+        // The content is restored as if 'path' was where it was backed up from
+        let mut target = entry.clone();
+        target.path = path.to_string();
+        target.xattrs.clear();
+        downloader.progress.begin(&self.bar, &entry.path, expected_size);
+        let ok = downloader.download_blocking(&self.bar, &target, expected_size);
+        downloader.progress.file_done(&self.bar);
+        ok
+    }
+
+    /// Counts the export of a file in the summary
+    pub fn record(&self, path: &str, ok: bool) {
+        self.downloader.summary.record(path, ok);
+    }
+
+    /// Reports the API calls made and sends the summary
+    pub fn finish(&self) {
+        let downloader = &self.downloader;
+        downloader.progress.finish();
+        printsummary(Color::Green, format!("[{:.3}] API calls: {}", self.t_start.elapsed().as_secs_f32(), downloader.budget.summary()));
+        if downloader.budget_exceeded.load(Ordering::SeqCst) {
+            printcoln(Color::Yellow, format!("[{:.3}] Not all files were exported", self.t_start.elapsed().as_secs_f32()));
+        }
+        downloader.summary.finish(Some(downloader.progress.transferred()));
+    }
+}
+
+// Everything needed to download files, shared by all download tasks
+struct Downloader {
+    backend: Box<dyn Backend>,
+    // Set when restoring to a point in time, the version of each file to restore
+    versions: Option<HashMap<String, FileVersion>>,
+    keys: Option<Keys>, // Set if encryption is enabled
+    // Whether files that differ from the backed up version in any way are replaced, see needs_download
+    exact: bool,
+    max_attempts: u32,
+    // Bars of downloads that aren't in progress, there is one for every download that may be in progress at once
+    bars: Mutex<Vec<ProgressBar>>,
+    progress: Progress,
+    budget: Arc<Budget>,
+    // Bytes downloaded in this run, see egress.rs
+    cap: DownloadCap,
+    summary: Summary,
+    // Set once the API call budget or the maximum download is used up, after which no more downloads are started
+    budget_exceeded: AtomicBool,
+    // Restored files that don't match what was backed up (path, reason), see verify
+    unverified: Mutex<Vec<(String, String)>>,
+    // Stops writing the files being downloaded once interrupted
+    cancel: transfer::Cancel,
+}
+
+impl Downloader {
+    // Downloads an entry if it is still missing or outdated locally
+    async fn download_entry(self: Arc<Self>, entry: FileEntry) {
+        let progress = &self.progress;
+        let expected_size = remote_size(&entry, self.keys.is_some());
+        if self.budget_exceeded.load(Ordering::SeqCst) {
+            progress.skip(expected_size);
             return;
-        },
-    };
-    printcoln(Color::Green, format!("[{:.3}] Success", t_start.elapsed().as_secs_f32()));
-
-    // Get the bucket we're using
-    // This is were manifest.json is and were we download files from
-    // Note that since we supply a bucket name and names are unique, we should get 0 or 1 results
-    printcoln(Color::Green, format!("[{:.3}] Resolving bucket name", t_start.elapsed().as_secs_f32()));
-    let params = ListBucketParams {
-        bucket_id: None,
-        bucket_name: Some(config.bucket_name.as_ref().unwrap().to_string()),
-        bucket_types: None
-    };
-    let buckets = match raze::api::b2_list_buckets(&client, &auth, params) {
-        Ok(buckets) => buckets,
-        Err(err) => {
-            printcoln(Color::Red, format!("[{:.3}] Failed to retrieve bucket list", t_start.elapsed().as_secs_f32()));
-            printcoln(Color::Red, format!("[{:.3}] Reason: {:?}", t_start.elapsed().as_secs_f32(), err));
+        }
+        // Check metadata
+        // This was checked when building the queue, but the file may have changed since
+        if !needs_download(&entry, self.exact) {
+            self.summary.unchanged(1);
+            progress.skip(expected_size);
             return;
         }
-    };
-
-    let bucket_name = config.bucket_name.as_ref().unwrap();
-    let bucket_id = match buckets.get(0) {
-        Some(res) => &res.bucket_id,
-        None => {
-            printcoln(Color::Red, format!("[{:.3}] No bucket with the name '{}'", t_start.elapsed().as_secs_f32(), bucket_name));
+        if let Err(e) = self.cap.admit(expected_size) {
+            progress.println(format!("Not downloading {} ({})", entry.path, e));
+            self.budget_exceeded.store(true, Ordering::SeqCst);
+            progress.skip(expected_size);
             return;
         }
-    };
-    printcoln(Color::Green, format!("[{:.3}] {} -> {}", t_start.elapsed().as_secs_f32(), bucket_name, bucket_id));
-
 
-    printcoln(Color::Green, format!("[{:.3}] Retrieving remote file manifest", t_start.elapsed().as_secs_f32()));
-    let params = B2DownloadFileByNameParams {
-        bucket_name: config.bucket_name.as_ref().unwrap().to_string(),
-        file_name: "manifest.json".to_string(),
-        authorization: None // Uses B2auth as fallback
-    };
-    // Try to download the remote manifest.json
-    let mut manifest = match raze::api::b2_download_file_by_name(&client, &auth, params) {
-        Ok(response) => {
-            // Move local manifest.json to manifest.json.old
-            printcoln(Color::Green, format!("[{:.3}] Backing up old manifest...", t_start.elapsed().as_secs_f32()));
-            std::fs::rename("manifest.json","manifest.json.old");
+        let bar = self.bars.lock().unwrap().pop().expect("More downloads in progress than bars");
+        progress.begin(&bar, &entry.path, expected_size);
+        let ok = if entry.size < transfer::BUFFERED_SIZE && entry.deltas.is_empty() {
+            self.download_buffered(&bar, &entry, expected_size).await
+        } else {
+            let (downloader, worker_bar, entry) = (self.clone(), bar.clone(), entry.clone());
+            transfer::blocking(move || downloader.download_blocking(&worker_bar, &entry, expected_size)).await
+        };
+        // A file stopped by cancelling didn't fail, the next download continues it
+        if ok || !self.cancel.is_cancelled() {
+            self.summary.record(&entry.path, ok);
+        }
+        progress.file_done(&bar);
+        self.bars.lock().unwrap().push(bar);
+    }
 
-            // Create new manifest.json and fill it with the response we just got
-            printcoln(Color::Green, format!("[{:.3}] Loading new manifest", t_start.elapsed().as_secs_f32()));
-            let mut file = match File::create("manifest.json") {
-                Ok(f) => f,
-                Err(err) => {
-                    printcoln(Color::Red, format!("[{:.3}] Failed to open manifest.json ({:?})", t_start.elapsed().as_secs_f32(), err));
-                    return;
-                }
-            };
-            // If encryption is on, decrypt the remote data first
-            match config.encrypt.unwrap() {
-                true => {
-                    let mut writer = DecryptingWriter::target(file, &key.unwrap());
-                    writer.write_all(&response.bytes().unwrap());
-                    writer.flush();
+    // Downloads an entry small enough to hold in memory (see transfer::BUFFERED_SIZE) with the async client
+    // The file is only written once all of it is downloaded
+    // Returns whether it succeeded
+    async fn download_buffered(self: &Arc<Self>, bar: &ProgressBar, entry: &FileEntry, expected_size: u64) -> bool {
+        // Try up to 'max_attempts' times
+        for attempt in 0..self.max_attempts {
+            retry::hold_off_async().await;
+            self.progress.rewind(bar);
+            if !self.spend(entry) {
+                return false;
+            }
+            let (retryable, retry_after, reason) = match self.fetch(bar, entry, expected_size).await {
+                Ok(data) => {
+                    self.cap.add(data.len() as u64);
+                    let (downloader, restored) = (self.clone(), entry.clone());
+                    match transfer::blocking(move || downloader.restore(&restored, Cursor::new(data), None)).await {
+                        Ok(_) => return true,
+                        // Corrupt data stays corrupt, skip the file instead of retrying
+                        Err(e) if e.kind() == std::io::ErrorKind::InvalidData =>
+                            (false, None, format!("Failed to restore {} - It is corrupt or can't be decrypted ({})", entry.path, e)),
+                        Err(e) => (true, None, format!("Failed to write {} ({:?})", entry.path, e)),
+                    }
                 },
-                false => {
-                    file.write_all(&response.bytes().unwrap());
-                    file.flush();
-                }
+                Err(e) => (e.retryable, e.retry_after, format!("Download failed: {}", e)),
+            };
+            if !self.retry_after(entry, attempt, retryable, reason) {
+                return false;
             }
+            tokio::time::delay_for(retry::delay_for(attempt, retry_after)).await;
+        }
+        false
+    }
 
+    // Downloads the content of an entry into memory
+    async fn fetch(&self, bar: &ProgressBar, entry: &FileEntry, expected_size: u64) -> Result<Vec<u8>, BackendError> {
+        let id = file_id_of(&entry.mask, entry.file_id.as_deref(), self.versions.as_ref())?;
+        let response = self.backend.download_buffered(&entry.mask, id).await?;
+        // Size wasn't recorded in the manifest, use what the response says
+        if expected_size == 0 {
+            if let Some(len) = response.content_length() {
+                bar.set_length(len);
+                self.progress.add_length(len);
+            }
+        }
+        transfer::read_body(response, &self.progress.counter(bar)).await
+            .map_err(|e| BackendError { retryable: true, retry_after: None, reason: e.to_string() })
+    }
 
-            // Try to load the manifest
-            match FileManifest::from_file("manifest.json") {
-                Ok(fm) => fm,
-                Err(err) => {
-                    printcoln(Color::Red, format!("[{:.3}] Failed to load remote file manifest ({})", t_start.elapsed().as_secs_f32(), err));
-                    printcoln(Color::Red, format!("[{:.3}] This should not happen. Falling back to local manifest!", t_start.elapsed().as_secs_f32()));
-                    match FileManifest::from_file("manifest.json.old") {
-                        Ok(fm) => {
-                            std::fs::rename("manifest.json.old", "manifest.json");
-                            fm
-                        },
-                        Err(err2) => {
-                            std::fs::rename("manifest.json.old", "manifest.json");
-                            printcoln(Color::Red, format!("[{:.3}] Failed to load LOCAL file manifest ({})", t_start.elapsed().as_secs_f32(), err2));
-                            printcoln(Color::Red, format!("[{:.3}] LOCAL and REMOTE manifests are invalid", t_start.elapsed().as_secs_f32()));
-                            printcoln(Color::Red, format!("[{:.3}] This should never happen!", t_start.elapsed().as_secs_f32()));
-                            printcoln(Color::Red, format!("[{:.3}] Is manifest.json missing or corrupted?", t_start.elapsed().as_secs_f32()));
-                            printcoln(Color::Red, format!("[{:.3}] Was 'download' ran before 'init'?", t_start.elapsed().as_secs_f32()));
-                            return;
+    // Downloads an entry with the blocking calls, writing the file while it is being downloaded
+    // s.t. memory usage doesn't depend on its size
+    // The file is written to its .part file first, an attempt that is interrupted continues from there (see resumable)
+    // Returns whether it succeeded
+    fn download_blocking(&self, bar: &ProgressBar, entry: &FileEntry, expected_size: u64) -> bool {
+        let progress = &self.progress;
+        // Try up to 'max_attempts' times
+        for attempt in 0..self.max_attempts {
+            retry::hold_off();
+            progress.rewind(bar);
+            if !self.spend(entry) {
+                return false;
+            }
+            let resume = self.resumable(entry);
+            // Resuming an encrypted file downloads its header as well
+            if resume.is_some() && self.keys.is_some() && !self.spend(entry) {
+                return false;
+            }
+            let started = match resume {
+                Some(kept) => self.download_rest(entry, kept),
+                None => download_file(self.backend.as_ref(), &entry.mask, entry.file_id.as_deref(), self.versions.as_ref())
+                    .map(|response| (response, None)),
+            };
+            let (retryable, retry_after, reason) = match started {
+                Ok((response, resumed)) => {
+                    // Size wasn't recorded in the manifest, use what the response says
+                    if expected_size == 0 {
+                        if let Some(len) = response.length {
+                            bar.set_length(len);
+                            progress.add_length(len);
                         }
                     }
+                    if let Some(resumed) = &resumed {
+                        progress.resume(bar, resumed.offset);
+                    }
+                    let body = self.cancel.wrap_read(progress.wrap_read(self.cap.wrap_read(response.body), bar));
+                    match self.restore(entry, body, resumed.as_ref()) {
+                        Ok(_) => return true,
+                        Err(_) if self.cancel.is_cancelled() => {
+                            progress.println(format!("Stopped downloading {}, the next download continues it", entry.path));
+                            return false;
+                        },
+                        // The rest doesn't fit what was downloaded before, e.g. because a newer version was uploaded since
+                        // The .part file is gone, try again from the start
+                        Err(e) if e.kind() == std::io::ErrorKind::InvalidData && resumed.is_some() =>
+                            (true, None, format!("Failed to resume download of {}, starting over ({})", entry.path, e)),
+                        // Corrupt data stays corrupt, skip the file instead of retrying
+                        Err(e) if e.kind() == std::io::ErrorKind::InvalidData =>
+                            (false, None, format!("Failed to restore {} - It is corrupt or can't be decrypted ({})", entry.path, e)),
+                        // The next attempt continues from what was written
+                        Err(e) => (true, None, format!("Download of {} interrupted ({:?})", entry.path, e)),
+                    }
+                },
+                // E.g. the storage doesn't support ranges, try again from the start
+                Err(e) if resume.is_some() && !e.retryable => {
+                    remove_part(&entry.path);
+                    (true, e.retry_after, format!("Failed to resume download of {}, starting over ({})", entry.path, e.reason))
+                },
+                // An expired auth token is renewed by the backend before the next attempt
+                Err(e) => (e.retryable, e.retry_after, format!("Download failed: {}", e.reason)),
+            };
+            if !self.retry_after(entry, attempt, retryable, reason) {
+                return false;
+            }
+            // Back off and retry
+            std::thread::sleep(retry::delay_for(attempt, retry_after));
+        }
+        false
+    }
+
+    // How many bytes of an entry an earlier attempt left in its .part file, if the download can continue from there
+    // Decompression and sparse extents can't be picked up midway, those always start over
+    // A .part file of another version of the file (see part_info) is removed
+    fn resumable(&self, entry: &FileEntry) -> Option<u64> {
+        if entry.compressed || !entry.sparse.is_empty() || entry.size == 0 {
+            return None;
+        }
+        let length = std::fs::metadata(part_path(&entry.path)).ok()?.len();
+        if std::fs::read_to_string(info_path(&entry.path)).ok() != Some(part_info(entry)) {
+            remove_part(&entry.path);
+            return None;
+        }
+        let kept = match self.keys {
+            // The last two blocks are only written once the whole file is decrypted, so those are always downloaded again
+            // Blocks are at least DATA_LENGTH, the block size of the file is only known from its header, see download_rest
+            Some(_) => length.min(entry.size.saturating_sub(2*DATA_LENGTH as u64)),
+            None => length.min(entry.size - 1),
+        };
+        match kept {
+            0 => None,
+            kept => Some(kept),
+        }
+    }
+
+    // Downloads what is left of an entry after the first 'kept' bytes
+    // Encrypted files continue at the block after those bytes, which needs the header from the start of the file
+    // That is a download of its own, counted against the budget by the caller and against the download cap here
+    fn download_rest(&self, entry: &FileEntry, kept: u64) -> Result<(Download, Option<Resumed>), BackendError> {
+        let id = file_id_of(&entry.mask, entry.file_id.as_deref(), self.versions.as_ref())?;
+        let (kept, offset, header) = match self.keys {
+            Some(_) => {
+                let mut start = self.backend.download_from(&entry.mask, id, 0, Some(encryption::MAX_HEADER_LENGTH as u64))?;
+                let header = encryption::read_header(&mut self.cap.wrap_read(&mut start.body))
+                    .map_err(|e| BackendError { retryable: true, retry_after: None, reason: e.to_string() })?;
+                // Only whole blocks are kept, an unknown block size fails when decrypting
+                let block = encryption::block_size_of(&header).unwrap_or_default();
+                let data_length = block.data_length() as u64;
+                let kept = kept.min(entry.size.saturating_sub(2*data_length)) / data_length * data_length;
+                (kept, header.len() as u64 + kept / data_length * block.block_length() as u64, Some(header))
+            },
+            None => (kept, kept, None),
+        };
+        let response = self.backend.download_from(&entry.mask, id, offset, None)?;
+        Ok((response, Some(Resumed { kept, offset, header })))
+    }
+
+    // Counts the download of an entry against the budget
+    // Returns false if the budget is used up, in which case no more downloads are started
+    fn spend(&self, entry: &FileEntry) -> bool {
+        match self.budget.spend(self.backend.download_call(self.versions.is_some())) {
+            Ok(_) => true,
+            Err(e) => {
+                self.progress.println(format!("Not downloading {} ({})", entry.path, e));
+                self.budget_exceeded.store(true, Ordering::SeqCst);
+                false
+            }
+        }
+    }
+
+    // Prints why an attempt at downloading an entry failed
+    // Returns whether to try again
+    fn retry_after(&self, entry: &FileEntry, attempt: u32, retryable: bool, reason: String) -> bool {
+        // Failing because the run was cancelled isn't a failure of the file
+        if self.cancel.is_cancelled() {
+            self.progress.println(format!("Stopped downloading {}, the next download continues it", entry.path));
+            return false;
+        }
+        self.progress.println(reason);
+        if !retryable {
+            self.progress.println(format!("Failed to download {:?}, error is not retryable", entry.path));
+            false
+        } else if attempt == self.max_attempts-1 {
+            self.progress.println(format!("Failed to download {:?} after {} attempts", entry.path, self.max_attempts));
+            false
+        } else {
+            true
+        }
+    }
+
+    // Writes the content of an entry to its path, then applies its deltas and restores its extended attributes
+    // The content is written to the .part file, which replaces the file once complete. With 'resumed', it is the rest of the content
+    // Fails if the file can't be written or doesn't match the backed up version, extended attributes that can't be restored are only reported
+    fn restore<R: Read>(&self, entry: &FileEntry, mut data: R, resumed: Option<&Resumed>) -> std::io::Result<()> {
+        // Create all directories needed if they cannot be found
+        let local_path = winpath::extended(&entry.path);
+        if let Some(p) = std::path::Path::new(&local_path).parent() {
+            std::fs::create_dir_all(p).unwrap_or(());
+        }
+        // Create or overwrite the .part file, or continue after what is kept of it
+        // Which version it is of is recorded next to it, s.t. only that one is continued
+        let part = part_path(&entry.path);
+        let file = match resumed {
+            Some(resumed) => {
+                let file = OpenOptions::new().append(true).open(&part)?;
+                file.set_len(resumed.kept)?;
+                file
+            },
+            None => {
+                let file = File::create(&part)?;
+                std::fs::write(info_path(&entry.path), part_info(entry))?;
+                file
+            },
+        };
+        // Sparse entries are written to their data extents, leaving holes in between
+        let file: Box<dyn Write> = match entry.sparse.is_empty() {
+            true => Box::new(file),
+            false => Box::new(SparseWriter::new(file, &entry.sparse, entry.size)),
+        };
+        // Compressed entries are decompressed on their way to the file
+        let target: Box<dyn Write> = match entry.compressed {
+            true => Box::new(zstd::stream::write::Decoder::new(file)?),
+            false => Box::new(file),
+        };
+        // Either decrypt+write or just write the file
+        let mut writer: Box<dyn Write> = match (&self.keys, resumed.and_then(|r| r.header.as_ref())) {
+            (Some(keys), Some(header)) => {
+                let data_length = encryption::block_size_of(header).unwrap_or_default().data_length() as u64;
+                Box::new(DecryptingWriter::resume(target, keys, header, resumed.unwrap().kept / data_length)?)
+            },
+            (Some(keys), None) => Box::new(DecryptingWriter::with_keys(target, keys)),
+            (None, _) => target,
+        };
+        // Copies in chunks of IO_BUFFER_LENGTH. flush() finishes decryption and decompression
+        if let Err(e) = transfer::copy(&mut data, &mut writer).and_then(|_| writer.flush()) {
+            // Corrupt data isn't worth continuing from
+            if e.kind() == std::io::ErrorKind::InvalidData {
+                drop(writer);
+                remove_part(&entry.path);
+            }
+            return Err(e);
+        }
+        drop(writer);
+        std::fs::rename(&part, &local_path)?;
+        std::fs::remove_file(info_path(&entry.path)).unwrap_or(());
+
+        // Large files may have deltas on top of the full upload
+        if let Err(e) = apply_deltas(self.backend.as_ref(), &self.budget, &self.cap, self.versions.as_ref(), self.keys.as_ref(), entry, self.max_attempts) {
+            self.progress.println(format!("Failed to apply deltas to {} - It is an older version ({})", entry.path, e));
+        }
+
+        let failed = xattrs::restore(&winpath::extended(&entry.path), &entry.xattrs);
+        if !failed.is_empty() {
+            self.progress.println(format!("Failed to restore extended attributes of {} ({})", entry.path, failed.join(", ")));
+        }
+
+        if let Err(reason) = verify(entry) {
+            // A resumed download may have continued from an older version, it is downloaded again from the start
+            if resumed.is_none() {
+                self.unverified.lock().unwrap().push((entry.path.clone(), reason.clone()));
+            }
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("verification failed, {}", reason)));
+        }
+        Ok(())
+    }
+}
+
+// Where an interrupted download continues, see Downloader::download_rest
+struct Resumed {
+    // Bytes of the file kept from the earlier attempt
+    kept: u64,
+    // Where the rest starts in the remote file
+    offset: u64,
+    // Start of the remote file if it is encrypted, see encryption::read_header
+    header: Option<Vec<u8>>,
+}
+
+/// Where an entry is written while it is being downloaded
+pub fn part_path(path: &str) -> String {
+    format!("{}.part", winpath::extended(path))
+}
+
+// Where the version a .part file is of is recorded, see part_info
+fn info_path(path: &str) -> String {
+    format!("{}.info", part_path(path))
+}
+
+// Identifies the version of an entry, s.t. a .part file is only continued by a download of the same one
+// Storage without IDs is identified by the name, size and upload time instead
+fn part_info(entry: &FileEntry) -> String {
+    format!("{}\n{}\n{}", entry.file_id.as_deref().unwrap_or(&entry.mask), entry.size, entry.uploaded)
+}
+
+/// Removes what an interrupted download of the file at 'path' left
+pub fn remove_part(path: &str) {
+    std::fs::remove_file(part_path(path)).unwrap_or(());
+    std::fs::remove_file(info_path(path)).unwrap_or(());
+}
+
+// Retrieves the latest remote manifest, replacing the local one (which is kept as manifest.json.old)
+// Falls back to the local manifest if the remote one can't be retrieved or loaded
+// Fails if neither can be loaded
+fn latest_manifest(backend: &dyn Backend, budget: &Budget, keys: Option<&Keys>, t_start: std::time::Instant) -> Result<FileManifest, String> {
+    printcoln(Color::Green, format!("[{:.3}] Retrieving remote file manifest", t_start.elapsed().as_secs_f32()));
+    // Try to download the remote manifest.json
+    budget.spend(backend.download_call(false)).map_err(|e| format!("[{:.3}] Aborting: {}", t_start.elapsed().as_secs_f32(), e))?;
+    let remote = match backend.download(manifest::REMOTE_MANIFEST, None) {
+        Ok(response) => {
+            printcoln(Color::Green, format!("[{:.3}] Loading new manifest", t_start.elapsed().as_secs_f32()));
+            read_manifest(response.body, keys)
+        },
+        Err(err) => Err(err.into()),
+    };
+
+    match remote {
+        Ok(manifest) => {
+            // Keep the local manifest as manifest.json.old
+            printcoln(Color::Green, format!("[{:.3}] Backing up old manifest...", t_start.elapsed().as_secs_f32()));
+            if let Ok(old) = FileManifest::from_file(manifest::local_path()) {
+                if let Err(err) = old.to_file(manifest::old_path()) {
+                    printcoln(Color::Red, format!("[{:.3}] Failed to back up old manifest ({})", t_start.elapsed().as_secs_f32(), err));
                 }
             }
+            if let Err(err) = manifest.to_file(manifest::local_path()) {
+                printcoln(Color::Red, format!("[{:.3}] Failed to save new manifest ({})", t_start.elapsed().as_secs_f32(), err));
+            }
+            Ok(manifest)
         },
         Err(err) => {
-            printcoln(Color::Red, format!("[{:.3}] Failed to retrieve remote manifest ({:?})", t_start.elapsed().as_secs_f32(), err));
+            printcoln(Color::Red, format!("[{:.3}] Failed to retrieve remote manifest ({})", t_start.elapsed().as_secs_f32(), err));
             printcoln(Color::Red, format!("[{:.3}] This should not happen. Falling back to local manifest!", t_start.elapsed().as_secs_f32()));
-            match FileManifest::from_file("manifest.json") {
-                Ok(fm) => {
-                    fm
-                },
-                Err(err2) => {
-                    printcoln(Color::Red, format!("[{:.3}] Failed to load LOCAL file manifest ({})", t_start.elapsed().as_secs_f32(), err2));
-                    printcoln(Color::Red, format!("[{:.3}] REMOTE could not be retrieved and could not load LOCAL", t_start.elapsed().as_secs_f32()));
-                    printcoln(Color::Red, format!("[{:.3}] This should never happen!", t_start.elapsed().as_secs_f32()));
-                    printcoln(Color::Red, format!("[{:.3}] Is manifest.json missing or corrupted?", t_start.elapsed().as_secs_f32()));
-                    printcoln(Color::Red, format!("[{:.3}] Was 'download' ran before 'init'?", t_start.elapsed().as_secs_f32()));
-                    return;
-                }
-            }
+            FileManifest::from_file(manifest::local_path()).map_err(|err2| {
+                format!("[{:.3}] Failed to load LOCAL file manifest ({}). REMOTE could not be loaded and could not load LOCAL, this should never happen! \
+                         Is {} missing or corrupted? Was 'download' ran before 'init'?", t_start.elapsed().as_secs_f32(), err2, manifest::local_path())
+            })
         }
+    }
+}
+
+// The manifest with the files in the snapshot named 'name'
+// The local manifest is used if it has the snapshot, since it may have been named after the last upload
+fn snapshot_manifest(backend: &dyn Backend, budget: &Budget, keys: Option<&Keys>, name: &str, t_start: std::time::Instant) -> Result<FileManifest, String> {
+    let mut manifest = match FileManifest::from_file(manifest::local_path()) {
+        Ok(m) if m.named_snapshot(name).is_some() => m,
+        _ => latest_manifest(backend, budget, keys, t_start)?,
     };
+    let snapshot = match manifest.named_snapshot(name) {
+        Some(s) => s.clone(),
+        None => return Err(format!("[{:.3}] There is no snapshot named '{}', see 'snapshot list'", t_start.elapsed().as_secs_f32(), name)),
+    };
+    printcoln(Color::Green, format!("[{:.3}] Restoring snapshot '{}' (generation {}, from {})",
+                                    t_start.elapsed().as_secs_f32(), name, snapshot.generation, datetime::format_millis(snapshot.time)));
+    // Versions that were replaced since are downloaded by their IDs, by name a newer version would be
+    let (files, unpinned): (Vec<FileEntry>, Vec<FileEntry>) = manifest.snapshot_files(snapshot.generation).into_iter()
+        .partition(|e| e.is_pinned() || manifest.get_entry(&e.path).is_some_and(|current| current.since == e.since));
+    for entry in &unpinned {
+        printcoln(Color::Red, format!("[{:.3}] {} can't be restored as it was in the snapshot, the IDs of its remote files weren't recorded",
+                                      t_start.elapsed().as_secs_f32(), entry.path));
+    }
+    let left = manifest.files.iter().filter(|e| files.binary_search_by(|f| f.path.cmp(&e.path)).is_err()).count();
+    if left > 0 {
+        printcoln(Color::Yellow, format!("[{:.3}] {} files were backed up after the snapshot, they are left alone", t_start.elapsed().as_secs_f32(), left));
+    }
+    manifest.files = files;
+    Ok(manifest)
+}
 
-    let manifest_mutex = Mutex::new(&mut manifest);
-    printcoln(Color::Green, format!("[{:.3}] Loaded manifest", t_start.elapsed().as_secs_f32()));
-
-
-    // Setup interrupt handler
-    let (tx,rx) = mpsc::channel();
-    ctrlc::set_handler(move || {
-        tx.send(1).unwrap();
-    }).expect("Failed to set Ctrl-C handler!");
-
-    let pool = Pool::new(9);
-    // Amount of threads downloading/writing files
-    let busy_threads = AtomicUsize::new(pool.workers()-1);
-    // Whether or not threads can open new files for writing
-    let allow_open_file = AtomicBool::new(true);
-    // How many threads currently have a file open for writing
-    let open_files = AtomicUsize::new(0);
-
-    // This pool consists of 2 parts
-    // 1. A thread watching for interrupts (Ctrl-C) and if the pool is done
-    // 2. 'n' threads each downloading a file
-    // Each download thread pops elements from the file manifest,
-    // and tries to find the the local file matching the entry
-    // If it exists, it compares modified times. If remote is more recent, local is replaced
-    // Otherwise, the local version is kept
-    // If the local file does not exist, it is retrieved from remote
-    pool.scoped(|scope| {
-        // Spawn sync task
-        let client = &client;
-        let auth = &auth;
-        let manifest = &manifest_mutex;
-        let busy_threads = &busy_threads;
-        let allow_open_file = &allow_open_file;
-        let open_files = &open_files;
-        scope.execute(move || {
-            loop {
-                // Every 5 secs, check if there are still more items left in queue
-                // We need to know, s.t. we can terminate this thread when there is no more work
-                // If we received an Ok(n), we received an interrupt signal and should terminate as soon as possible
-                let res = rx.recv_timeout(Duration::from_secs(5));
-
-                if res.is_ok() {
-                    printcoln(Color::Yellow, format!("[{:.3}] Interrupt received", t_start.elapsed().as_secs_f32()));
-                    printcoln(Color::Yellow, format!("[{:.3}] Waiting for pending writes - This should only take a few seconds", t_start.elapsed().as_secs_f32()));
-                    printcoln(Color::Yellow, format!("[{:.3}] Please be patient if the files are very large and/or we're in debug mode", t_start.elapsed().as_secs_f32()));
-                    printcoln(Color::Yellow, format!("[{:.3}] WARNING: INTERRUPTING THIS _WILL_ LEAVE BROKEN FILES!", t_start.elapsed().as_secs_f32()));
-                    printcoln(Color::Yellow, format!("[{:.3}] IF INTERRUPTED NOW, YOU MUST MANUALLY CHECK THE LAST 8 FILES FOR CORRUPTION", t_start.elapsed().as_secs_f32()));
-                    // Disallow opening of new files
-                    allow_open_file.swap(false, Ordering::SeqCst);
-                    // Empty manifest files means empty queue of files to check
-                    manifest.lock().unwrap().files.clear();
-                    // We must now wait until open_files = 0
-                    while open_files.load(Ordering::SeqCst) > 0 {
-                        std::thread::sleep(Duration::from_millis(100));
-                    }
-                    // No files are open and no new ones can be opened
-                    // Exit the program
-                    printcoln(Color::Green, format!("[{:.3}] Exit OK - No issues detected", t_start.elapsed().as_secs_f32()));
-                    abort();
-                }
+/// Reads a downloaded manifest, decrypting it if keys are given
+pub fn read_manifest<R: Read>(mut response: R, keys: Option<&Keys>) -> Result<FileManifest, Box<dyn std::error::Error>> {
+    let mut bytes = Vec::new();
+    match keys {
+        Some(keys) => {
+            let mut writer = DecryptingWriter::with_keys(&mut bytes, keys);
+            std::io::copy(&mut response, &mut writer)?;
+            writer.flush()?;
+        },
+        None => {
+            std::io::copy(&mut response, &mut bytes)?;
+        },
+    }
+    FileManifest::from_bytes(&bytes)
+}
 
-                // If all threads are done, exit this thread and with it the entire pool
-                let active_threads = busy_threads.load(Ordering::SeqCst);
-                if active_threads == 0 {
-                    break;
-                }
+// Retrieves the version of the manifest that was current at the time 'versions' were resolved for
+fn manifest_as_of(backend: &dyn Backend, budget: &Budget, versions: &HashMap<String, FileVersion>, keys: Option<&Keys>) -> Result<FileManifest, Box<dyn std::error::Error>> {
+    let version = match versions.get("manifest.json") {
+        Some(v) => v,
+        None => return Err("no manifest.json was backed up at that time".into()),
+    };
+    printcoln(Color::Green, format!("Using manifest uploaded at {}", datetime::format_millis(version.upload_timestamp)));
+    budget.spend(backend.download_call(true))?;
+    let response = backend.download(manifest::REMOTE_MANIFEST, Some(&version.file_id))?;
+    read_manifest(response.body, keys)
+}
+
+/// Checks the nonce counter against the nonces the remote manifest records as allocated too, see Config::check_nonces
+/// The local manifest may be behind it, e.g. if a copy of the config on another machine uploaded since
+/// Fails if the counter is behind. A remote manifest that can't be read (e.g. there is none yet) is only reported
+pub fn check_remote_nonces(config: &Config, backend: &dyn Backend, budget: &Budget, t_start: std::time::Instant) -> Result<(), String> {
+    if config.nonce_mode == Some(NonceMode::Random) {
+        return Ok(());
+    }
+    // Only a secret or private key can read it
+    let keys = match Keys::from_config(config) {
+        Ok(keys) if keys.all().next().is_some() || config.private_key.is_some() => keys,
+        _ => return Ok(()),
+    };
+    let remote = budget.spend(backend.download_call(false)).map_err(|e| e.to_string())
+        .and_then(|_| backend.download(manifest::REMOTE_MANIFEST, None).map_err(|e| e.to_string()))
+        .and_then(|response| read_manifest(response.body, Some(&keys)).map_err(|e| e.to_string()));
+    match remote.map(|remote| (config.check_nonces(remote.nonces), remote.nonces)) {
+        Ok((Ok(_), _)) => Ok(()),
+        // Recorded in the local manifest as well, s.t. 'config --advance_nonces' continues after them
+        Ok((Err(e), recorded)) => {
+            if let Ok(mut local) = FileManifest::from_file(manifest::local_path()) {
+                local.record_nonces(recorded);
+                local.save_local().unwrap_or_default();
             }
-        });
+            Err(e)
+        },
+        Err(e) => {
+            printcoln(Color::Yellow, format!("[{:.3}] Warning: couldn't check the nonces the remote manifest records as allocated ({})", t_start.elapsed().as_secs_f32(), e));
+            Ok(())
+        },
+    }
+}
 
-        // Spawn download tasks
-        for i in 0..pool.workers()-1 {
-            let manifest = &manifest_mutex;
-
-            scope.execute(move || {
-                loop {
-                    // Try to get a new entry
-                    let p = {
-                        manifest.lock().unwrap().files.pop()
-                    };
-                    let entry = match p {
-                        Some(e) => e,
-                        None => {
-                            // List is empty, nothing more to upload
-                            // Decrement busy threads by 1
-                            busy_threads.fetch_sub(1, Ordering::SeqCst);
-                            break;
-                        }
-                    };
-
-                    // Check metadata
-                    let mut do_download = false;
-                    match std::fs::metadata(&entry.path) {
-                        Ok(meta) => {
-                            let modified_time = match meta.modified().unwrap().duration_since(std::time::UNIX_EPOCH) {
-                                Ok(v) => v.as_millis() as u64, // Convert seconds to milliseconds
-                                Err(_e) => 0u64
-                            };
-                            if modified_time < entry.timestamp {
-                                do_download = true;
-                            }
-                        },
-                        Err(e) => {
-                            do_download = true;
-                        }
-                    };
-                    if !do_download {
-                        continue;
-                    }
+// Check if the file an entry refers to is missing locally, or older than the backed up version
+// If 'exact' is set, any difference from the backed up version counts, i.e. newer files are replaced too
+fn needs_download(entry: &FileEntry, exact: bool) -> bool {
+    match std::fs::metadata(winpath::extended(&entry.path)) {
+        Ok(meta) => {
+            let modified_time = match meta.modified().unwrap().duration_since(std::time::UNIX_EPOCH) {
+                Ok(v) => v.as_millis() as u64, // Convert seconds to milliseconds
+                Err(_e) => 0u64
+            };
+            if exact {
+                modified_time != entry.timestamp
+            } else {
+                modified_time < entry.timestamp
+            }
+        },
+        Err(_) => true,
+    }
+}
 
-                    println!("Downloading {:?} -> {:?}", entry.mask, entry.path);
-
-                    // Try up to 5 times
-                    for attempts in 0..5 {
-                        let params = B2DownloadFileByNameParams {
-                            bucket_name: bucket_name.to_string(),
-                            file_name: entry.mask.to_string(),
-                            authorization: None // Falls back to B2Auth
-                        };
-
-                        let result = raze::api::b2_download_file_by_name(&client, &auth, params);
-                        match result {
-                            Ok(response) => {
-                                // We just downloaded the file, now we must handle writing and decrypting it
-                                // First of all, indicate we intend to open a file
-                                // Note that this _must_ be done before checking if we're allowed to actually open the file
-                                // in order to avoid a race condition
-                                open_files.fetch_add(1, Ordering::SeqCst);
-
-                                // Check if we are allowed to write this file
-                                if !allow_open_file.load(Ordering::SeqCst) {
-                                    // We cannot open files (and never will be allowed to again)
-                                    // This happens when the program is interrupted, e.g. Ctrl-C was pressed
-                                    // In this case, we end the thread since it's gonna die shortly anyways
-                                    open_files.fetch_sub(1, Ordering::SeqCst);
-                                    busy_threads.fetch_sub(1, Ordering::SeqCst);
-                                    return;
-                                };
-
-                                // Create all directories needed if they cannot be found
-                                match std::path::Path::new(&entry.path).parent() {
-                                    Some(p) => {
-                                        std::fs::create_dir_all(p);
-                                    },
-                                    None => (),
-                                };
-                                // Try to create/overwrite the file
-                                let mut file = match File::create(&entry.path) {
-                                    Ok(f) => f,
-                                    Err(err) => {
-                                        println!("Failed to create/open {} - Retrying ({:?})", entry.path, err);
-                                        open_files.fetch_sub(1, Ordering::SeqCst);
-                                        continue;
-                                    }
-                                };
-                                // Either decrypt+write or just write the file
-                                match config.encrypt.unwrap() {
-                                    true => {
-                                        let mut writer = DecryptingWriter::target(file, &key.as_ref().unwrap());
-                                        writer.write_all(&response.bytes().unwrap());
-                                        writer.flush();
-                                    },
-                                    false => {
-                                        file.write_all(&response.bytes().unwrap());
-                                        file.flush();
-                                    }
-                                };
-
-                                // File closed, keep track
-                                open_files.fetch_sub(1, Ordering::SeqCst);
-
-                            },
-                            Err(e) => {
-                                println!("Download failed: {:?}", e);
-                                match e {
-                                    raze::Error::B2Error(e) => {
-                                        // TODO: consider adding re-auth here
-                                        // Both 'auth' and 'upauth' can expire
-                                        println!("Reason: {:?}", e);
-                                    },
-                                    _ => (),
-                                }
-
-                                if attempts == 4 {
-                                    println!("Failed to download {:?} after 5 attempts", entry.path);
-                                } else {
-                                    // Sleep and retry
-                                    std::thread::sleep(Duration::from_millis(5000));
-                                    continue;
-                                }
-                            }
-                        }
+// Checks a restored file against the size and SHA1 of its content recorded when it was backed up
+// Entries from before these were tracked and sparse files, which aren't hashed, are only checked as far as possible
+// The file itself is read, never a snapshot of it (see shadow.rs)
+fn verify(entry: &FileEntry) -> Result<(), String> {
+    let local_path = winpath::extended(&entry.path);
+    if entry.size != 0 {
+        let length = std::fs::metadata(&local_path).map_err(|e| e.to_string())?.len();
+        if length != entry.size {
+            return Err(format!("{} bytes instead of {}", length, entry.size));
+        }
+    }
+    if !entry.sha1.is_empty() {
+        let sha1 = std::fs::File::open(&local_path).and_then(moves::hash_reader).map_err(|e| e.to_string())?;
+        if sha1 != entry.sha1 {
+            return Err("SHA1 doesn't match".to_string());
+        }
+    }
+    Ok(())
+}
+
+// Size of an entry in B2, or 0 if it isn't known up front
+fn remote_size(entry: &FileEntry, encrypt: bool) -> u64 {
+    // Only the data of sparse files is uploaded
+    let size = if entry.sparse.is_empty() { entry.size } else { sparse::data_length(&entry.sparse) };
+    if entry.size == 0 || entry.compressed {
+        0
+    } else if encrypt {
+        // The manifest doesn't record the block size, larger blocks only make the file a little smaller
+        get_encrypted_size(size, BlockSize::default())
+    } else {
+        size
+    }
+}
+
+// Downloads the deltas of an entry and applies them, in order, to the restored file
+fn apply_deltas(backend: &dyn Backend, budget: &Budget, cap: &DownloadCap, versions: Option<&HashMap<String, FileVersion>>,
+                keys: Option<&Keys>, entry: &FileEntry, max_attempts: u32) -> Result<(), Box<dyn std::error::Error>> {
+    if entry.deltas.is_empty() {
+        return Ok(());
+    }
+    let mut file = OpenOptions::new().read(true).write(true).open(winpath::extended(&entry.path))?;
+    for (n, name) in entry.deltas.iter().enumerate() {
+        let mut attempt = 0;
+        let mut response = loop {
+            budget.spend(backend.download_call(versions.is_some() || entry.delta_id(n).is_some()))?;
+            match download_file(backend, name, entry.delta_id(n), versions) {
+                Ok(r) => break r,
+                Err(e) => {
+                    attempt += 1;
+                    if !e.retryable || attempt == max_attempts {
+                        return Err(format!("Failed to download delta {} ({})", name, e.reason).into());
                     }
+                    std::thread::sleep(retry::delay_for(attempt-1, e.retry_after));
                 }
-            });
-        }
-    });
+            }
+        };
+        // Deltas can be large, they are stored next to the file instead of in memory
+        let delta_path = format!("{}.delta.tmp", entry.path);
+        let mut body = cap.wrap_read(&mut response.body);
+        let copied = File::create(&delta_path).and_then(|mut delta_file| match keys {
+            Some(keys) => {
+                let mut writer = DecryptingWriter::with_keys(delta_file, keys);
+                std::io::copy(&mut body, &mut writer).and_then(|_| writer.flush())
+            },
+            None => std::io::copy(&mut body, &mut delta_file).and_then(|_| delta_file.flush()),
+        });
+        let applied = copied.and_then(|_| File::open(&delta_path))
+            .and_then(|delta_file| delta::apply_delta(&mut file, std::io::BufReader::new(delta_file)));
+        std::fs::remove_file(&delta_path).unwrap_or(());
+        applied?;
+    }
+    Ok(())
+}
 
-    printcoln(Color::Green, format!("[{:.3}] Download Completed!", t_start.elapsed().as_secs_f32()));
+// Downloads the file named 'name', see file_id_of for which version
+fn download_file(backend: &dyn Backend, name: &str, recorded: Option<&str>, versions: Option<&HashMap<String, FileVersion>>) -> Result<Download, BackendError> {
+    backend.download(name, file_id_of(name, recorded, versions)?)
+}
 
-}
\ No newline at end of file
+// The ID of the version of the file named 'name' to download: the one in 'versions' when restoring to a point in time,
+// else the one recorded in the manifest. Without either, the latest version is downloaded by name
+fn file_id_of<'a>(name: &str, recorded: Option<&'a str>, versions: Option<&'a HashMap<String, FileVersion>>) -> Result<Option<&'a str>, BackendError> {
+    match versions {
+        Some(versions) => match versions.get(name) {
+            Some(v) => Ok(Some(&v.file_id)),
+            None => Err(BackendError { retryable: false, retry_after: None, reason: format!("no version of {} from that time", name) }),
+        },
+        None => Ok(recorded),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::subcommands::backup::download::verify;
+    use crate::manifest::FileManifest;
+    use crate::moves::Content;
+
+    #[test]
+    fn test_verify() {
+        let file = std::env::temp_dir().join("retain-test-verify");
+        let path = file.to_str().unwrap();
+        std::fs::write(path, b"restored content").unwrap();
+        let mut manifest = FileManifest::new(false);
+        manifest.get_mask(path, 1);
+        let entry = manifest.get_entry_mut(path).unwrap();
+
+        // Nothing recorded, nothing to check
+        assert_eq!(verify(entry), Ok(()));
+        entry.size = 16;
+        entry.set_content(Content::of(b"restored content"));
+        assert_eq!(verify(entry), Ok(()));
+
+        // Same size, other content
+        std::fs::write(path, b"modified content").unwrap();
+        assert_eq!(verify(entry), Err("SHA1 doesn't match".to_string()));
+        std::fs::write(path, b"short").unwrap();
+        assert_eq!(verify(entry), Err("5 bytes instead of 16".to_string()));
+        std::fs::remove_file(path).unwrap();
+        assert!(verify(entry).is_err());
+    }
+}
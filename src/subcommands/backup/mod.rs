@@ -1,14 +1,22 @@
 use clap::ArgMatches;
 use crate::config::Config;
+use crate::colorutil::printcoln;
+use termcolor::Color;
+use upload::UploadOptions;
+use download::DownloadOptions;
 
-mod upload;
-mod download;
+pub mod upload;
+pub mod download;
 
 pub fn backup(config: &mut Config, args: Option<&ArgMatches>) {
-    match args.unwrap().value_of("action").unwrap() {
-        "upload" => upload::start(config),
-        "download" => download::start(&config),
+    let args = args.unwrap();
+    let result = match args.value_of("action").unwrap() {
+        "upload" => UploadOptions::from_args(args).and_then(|options| upload::start(config, &options)),
+        "download" => DownloadOptions::from_args(args).and_then(|options| download::start(config, &options)),
         "sync" => unimplemented!(),
         _ => panic!("Invalid action")
+    };
+    if let Err(e) = result {
+        printcoln(Color::Red, e);
     }
 }
\ No newline at end of file
@@ -1,60 +1,151 @@
-use crate::config::Config;
+use crate::config::{Config, DEFAULT_THREADS};
 use crate::filelist;
-use crate::colorutil::printcoln;
+use crate::scancache::ScanCache;
+use crate::pending::Pending;
+use crate::journal::{Journal, Record};
+use crate::colorutil::{printcoln, printsummary, printverbose};
 use termcolor::Color;
-use scoped_pool::Pool;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
 use std::sync::{Arc, Mutex};
-use raze::api::{BucketResult, ListBucketParams, Sha1Variant};
-use raze::Error;
-use crate::encryption::{get_encrypted_size, get_nonces_required};
+use std::collections::{HashMap, HashSet};
+use crate::encryption::{get_nonces_required, BlockSize};
 use crate::encryption::reader::EncryptingReader;
-use chacha20poly1305::Key;
-use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
-use ctrlc;
-use std::sync::mpsc;
-use std::process::abort;
-
-// Start backing up files
-// This will:
-// 1. Check that everything in the config is set
-// 2. Build the list of files defined in the backup-list
-// 3. Authenticate with the B2 API
-// 4. Upload new and changed files
-pub fn start(config: &mut Config) {
-    let t_start = std::time::Instant::now();
-    // If this succeeds, all values are set and we can unwrap them
-    match config.is_configured() {
-        Ok(_) => (),
-        Err(err) => {
-            printcoln(Color::Red, format!("Invalid config ({})", err));
-            return;
+use crate::encryption::keys::EncryptionKey;
+use crate::encryption::cipher::Cipher;
+use crate::subcommands::backup::download;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::io::{Read, Cursor, BufReader};
+use indicatif::ProgressBar;
+use crate::delta::{self, DeltaReader};
+use crate::b2;
+use crate::backend::{self, Backend};
+use crate::throttle::{self, TokenBucket, ThrottledReader};
+use clap::ArgMatches;
+use crate::progress::Progress;
+use crate::summary::Summary;
+use crate::manifest::{self, FileEntry, FileManifest};
+use crate::retry;
+use crate::transfer;
+use crate::compression::{self, CompressedFile};
+use crate::budget::Budget;
+use crate::capabilities::{self, Operation};
+use crate::hardlink;
+use crate::moves::{self, Content, ContentHasher, Vanished};
+use crate::hashing;
+use crate::jobs;
+use crate::interrupt;
+use crate::sparse::{self, SparseReader};
+use crate::winpath;
+use crate::shadow::{self, Snapshots};
+
+// How often the manifest is synced while uploading
+const MANIFEST_SYNC_INTERVAL: Duration = Duration::from_secs(5*60);
+
+/// Options of an upload, the command line sets them with 'backup upload', 'watch' and 'daemon'
+#[derive(Clone, Debug, Default)]
+pub struct UploadOptions {
+    // Bytes per second, None means the configured upload limit
+    pub limit: Option<u64>,
+    // None means the configured amount of upload threads
+    pub threads: Option<usize>,
+    // Only upload what the last upload didn't get to, see pending.rs
+    pub resume: bool,
+    // Upload the backup list of the config and then that of each job, see jobs.rs
+    pub all_jobs: bool,
+}
+
+impl UploadOptions {
+    /// Reads the options from the command line
+    pub fn from_args(args: &ArgMatches) -> Result<Self, String> {
+        Ok(UploadOptions {
+            limit: args.value_of("limit").map(throttle::parse_rate).transpose().map_err(|e| format!("Invalid upload limit: {}", e))?,
+            // Validated by clap
+            threads: args.value_of("threads").map(|s| s.parse::<usize>().unwrap()),
+            resume: args.is_present("resume"),
+            all_jobs: args.value_of("job") == Some(jobs::ALL),
+        })
+    }
+}
+
+/// Starts backing up files
+/// This will:
+/// 1. Check that everything in the config is set
+/// 2. Build the list of files defined in the backup-list
+/// 3. Connect to the bucket (see backend)
+/// 4. Upload new and changed files
+///
+/// With 'all_jobs', this is done for the backup list of the config and then for each job (see jobs.rs)
+/// Fails if the upload can't start. Files that fail to upload are in the summary instead (see summary.rs)
+pub fn start(config: &mut Config, options: &UploadOptions) -> Result<(), String> {
+    if !options.all_jobs {
+        return run(config, options, None);
+    }
+    let mut failed = 0;
+    jobs::for_each(config, jobs::scopes(config), |config| {
+        if let Err(e) = run(config, options, None) {
+            printcoln(Color::Red, e);
+            failed += 1;
         }
+    });
+    match failed {
+        0 => Ok(()),
+        n => Err(format!("{} of the uploads failed to start", n)),
     }
+}
+
+/// Uploads the backup list of the job the config runs, also with 'all_jobs', s.t. the caller decides which jobs run
+pub fn upload_job(config: &mut Config, options: &UploadOptions) -> Result<(), String> {
+    run(config, options, None)
+}
+
+/// Uploads the given files if they are new or modified, instead of everything in the backup list
+/// The paths must already be checked against the backup list
+pub fn upload_paths(config: &mut Config, options: &UploadOptions, paths: Vec<String>) -> Result<(), String> {
+    run(config, options, Some(paths))
+}
+
+fn run(config: &mut Config, options: &UploadOptions, paths: Option<Vec<String>>) -> Result<(), String> {
+    let t_start = std::time::Instant::now();
+    // Hooks only run around full uploads, not around the changes 'watch' uploads, nor around simulated ones (see hooks.rs)
+    let hooks = config.hooks.clone().filter(|_| paths.is_none() && config.simulate.is_none());
+    let summary = Summary::new("upload", "uploaded", config).with_hooks(hooks.clone());
+    // If this succeeds, all values are set and we can unwrap them
+    config.is_configured().map_err(|err| format!("Invalid config ({})", err))?;
 
     // Ensures list is found and structure is valid
-    match filelist::verify_structure(config.backup_list.as_ref().unwrap()) {
-        Ok(_) => (),
-        Err(e) => {
-            printcoln(Color::Red, format!("Backup list is invalid: {}", e));
-            return;
-        }
+    filelist::verify_structure(config.backup_list.as_ref().unwrap()).map_err(|e| format!("Backup list is invalid: {}", e))?;
+
+    // A limit given in the options takes precedence over the configured one
+    let upload_limit = options.limit.or(config.upload_limit);
+    // Every upload thread draws from the same bucket
+    let bucket = match upload_limit {
+        Some(rate) => {
+            printcoln(Color::Yellow, format!("Upload limited to {}", throttle::format_rate(rate)));
+            Some(TokenBucket::shared(rate))
+        },
+        None => None,
+    };
+
+    let threads = options.threads.unwrap_or(config.upload_threads.unwrap_or(DEFAULT_THREADS));
+
+    let max_attempts = config.max_attempts.unwrap_or(retry::DEFAULT_MAX_ATTEMPTS);
+
+    let budget = Arc::new(Budget::new(config));
+
+    let compress = config.compress.unwrap_or(false);
+    if compress {
+        printcoln(Color::Green, "Compression is enabled");
     }
 
     let mut key = None;
+    let cipher = config.cipher.unwrap_or(Cipher::XChaCha20Poly1305);
     match config.encrypt.unwrap() {
         true => {
             printcoln(Color::Green, "Encryption is enabled");
             // TODO: Verify encryption works on this platform(?)
-            match std::fs::read(config.secret_key.as_ref().unwrap()) {
-                Ok(bytes) => {
-                    key = Some(Key::clone_from_slice(&bytes));
-                }
-                Err(err) => {
-                    printcoln(Color::Red, format!("[{:.3}] Failed to open key-file {:?}", t_start.elapsed().as_secs_f32(), err));
-                    return;
-                }
-            }
+            // With a public key configured, files are encrypted for it
+            key = Some(EncryptionKey::from_config(config).map_err(|err| format!("[{:.3}] {}", t_start.elapsed().as_secs_f32(), err))?);
             printcoln(Color::Green, format!("[{:.3}] Init OK", t_start.elapsed().as_secs_f32()));
         }
         false => {
@@ -63,282 +154,966 @@ pub fn start(config: &mut Config) {
     }
 
     printcoln(Color::Green, format!("[{:.3}] Loading local file manifest", t_start.elapsed().as_secs_f32()));
-    let mut manifest = match crate::manifest::FileManifest::from_file("manifest.json") {
-        Ok(fm) => fm,
-        Err(err) => {
-            printcoln(Color::Red, format!("[{:.3}] Failed to load file manifest ({})", t_start.elapsed().as_secs_f32(), err));
-            printcoln(Color::Yellow, format!("[{:.3}] If it is missing due to the program being set up without using the init command:", t_start.elapsed().as_secs_f32()));
-            printcoln(Color::Yellow, format!("[{:.3}] * Run init to generate a new one, starting tracking from scratch", t_start.elapsed().as_secs_f32()));
-            printcoln(Color::Yellow, format!("[{:.3}] * Or ensure your previous manifest can be found", t_start.elapsed().as_secs_f32()));
-            return;
-        }
+    let manifest = FileManifest::from_file(manifest::local_path())
+        .map_err(|err| format!("[{:.3}] Failed to load file manifest ({}). If it is missing due to the program being set up without using the init command, \
+                                run init to generate a new one, starting tracking from scratch, or ensure your previous manifest can be found",
+                               t_start.elapsed().as_secs_f32(), err))?;
+    // An older copy of the config would reuse nonces
+    if key.is_some() {
+        config.check_nonces(manifest.nonces).map_err(|e| format!("[{:.3}] {}", t_start.elapsed().as_secs_f32(), e))?;
+    }
+    // Completed uploads are journaled until the manifest is saved
+    let journal = match Journal::open(manifest::journal_path()) {
+        Ok(journal) => Some(journal),
+        Err(e) => {
+            printcoln(Color::Yellow, format!("Warning: failed to open the journal, uploads are only recorded when the manifest is saved ({})", e));
+            None
+        },
     };
-    let manifest_mutex = Mutex::new(&mut manifest);
+    let manifest_mutex = Mutex::new(manifest);
     printcoln(Color::Green, format!("[{:.3}] Loaded manifest", t_start.elapsed().as_secs_f32()));
 
-    printcoln(Color::Green, format!("[{:.3}] Building list of files to upload...", t_start.elapsed().as_secs_f32()));
-    let filelist = filelist::build_file_list(config.backup_list.as_ref().unwrap());
-    printcoln(Color::Green, format!("[{:.3}] Complete ({} files)", t_start.elapsed().as_secs_f32(), filelist.len()));
+    // What is left is only recorded by full uploads, see pending.rs
+    let records_pending = paths.is_none();
+    let paths = match paths {
+        None if options.resume => match Pending::load(manifest::pending_path()) {
+            Ok(Some(pending)) => {
+                printcoln(Color::Green, format!("[{:.3}] Resuming the last upload ({} files queued, {} failed)",
+                                                t_start.elapsed().as_secs_f32(), pending.queued.len(), pending.failed.len()));
+                // Files removed since are left for 'clean'
+                Some(pending.paths().into_iter().filter(|p| std::path::Path::new(&winpath::extended(p)).exists()).collect())
+            },
+            Ok(None) => {
+                printcoln(Color::Green, format!("[{:.3}] Nothing to resume, the last upload finished", t_start.elapsed().as_secs_f32()));
+                return Ok(());
+            },
+            Err(e) => return Err(format!("[{:.3}] Failed to load what the last upload didn't get to ({})", t_start.elapsed().as_secs_f32(), e)),
+        },
+        paths => paths,
+    };
 
-    let file_queue = Arc::new(Mutex::new(filelist));
-    let client = reqwest::blocking::Client::builder().timeout(None).build().unwrap();
+    if let Some(hooks) = &hooks {
+        hooks.run_before("upload").map_err(|e| format!("[{:.3}] {}, not uploading", t_start.elapsed().as_secs_f32(), e))?;
+    }
 
-    printcoln(Color::Green, format!("[{:.3}] Authenticating...", t_start.elapsed().as_secs_f32()));
+    // The configured file systems are snapshotted before scanning, and their files read from the snapshots (see shadow.rs)
+    // They are removed when this is dropped, at the end of the upload
+    let _snapshots = match &config.snapshots {
+        Some(hooks) if !hooks.is_empty() => {
+            printcoln(Color::Green, format!("[{:.3}] Creating snapshots...", t_start.elapsed().as_secs_f32()));
+            let (snapshots, failed) = Snapshots::take(hooks, config.job.as_deref());
+            for (path, e) in failed {
+                printcoln(Color::Yellow, format!("Warning: failed to snapshot {}, its files are read as they are ({})", path, e));
+            }
+            if !snapshots.is_empty() {
+                printcoln(Color::Green, format!("[{:.3}] Reading {} file systems from snapshots", t_start.elapsed().as_secs_f32(), snapshots.len()));
+            }
+            Some(snapshots)
+        },
+        _ => None,
+    };
 
-    let keystring = format!("{}:{}", config.app_key_id.as_ref().unwrap(), config.app_key.as_ref().unwrap());
-    let auth = match raze::api::b2_authorize_account(&client,keystring) {
-        Ok(a) => a,
-        Err(_e) => {
-            printcoln(Color::Red, format!("[{:.3}] Authentication failure", t_start.elapsed().as_secs_f32()));
-            return;
+    let filelist = match paths {
+        Some(paths) => {
+            summary.scanned(paths.len());
+            paths
+        },
+        None => {
+            printcoln(Color::Green, format!("[{:.3}] Building list of files to upload...", t_start.elapsed().as_secs_f32()));
+            let (filelist, excluded) = match config.scan_cache.unwrap_or(false) {
+                true => {
+                    let cache = ScanCache::load(manifest::scan_cache_path());
+                    let (filelist, excluded, next) = filelist::collect_files_cached(config.backup_list.as_ref().unwrap(), &cache);
+                    if let Err(e) = next.save(manifest::scan_cache_path()) {
+                        printcoln(Color::Yellow, format!("Warning: failed to save scan cache ({})", e));
+                    }
+                    (filelist, excluded)
+                },
+                false => filelist::collect_files(config.backup_list.as_ref().unwrap()),
+            };
+            printcoln(Color::Green, format!("[{:.3}] Complete ({} files)", t_start.elapsed().as_secs_f32(), filelist.len()));
+            summary.scanned(filelist.len() + excluded);
+            summary.excluded(excluded);
+            filelist
+        }
+    };
+
+    // Files are read from snapshots of their volumes s.t. locked files can be read, see shadow.rs
+    // The snapshots are deleted when this is dropped, at the end of the upload
+    let _shadow_copies = match config.shadow_copy.unwrap_or(false) {
+        true if !cfg!(windows) => {
+            printcoln(Color::Yellow, "Warning: shadow copies are only available on Windows, files are read as they are");
+            None
+        },
+        true => {
+            printcoln(Color::Green, format!("[{:.3}] Creating shadow copies...", t_start.elapsed().as_secs_f32()));
+            let (copies, failed) = Snapshots::shadow_copies(&filelist);
+            for (volume, e) in failed {
+                printcoln(Color::Yellow, format!("Warning: failed to create a shadow copy of {}, its files are read as they are ({})", volume, e));
+            }
+            if !copies.is_empty() {
+                printcoln(Color::Green, format!("[{:.3}] Reading {} volumes from shadow copies", t_start.elapsed().as_secs_f32(), copies.len()));
+            }
+            Some(copies)
         },
+        false => None,
     };
-    printcoln(Color::Green, format!("[{:.3}] Success", t_start.elapsed().as_secs_f32()));
-    printcoln(Color::Green, format!("[{:.3}] Resolving bucket name", t_start.elapsed().as_secs_f32()));
-
-    // Note that since we supply a bucket name and names are unique, we should get 0 or 1 results
-    let params = ListBucketParams {
-        bucket_id: None,
-        bucket_name: Some(config.bucket_name.as_ref().unwrap().to_string()),
-        bucket_types: None
+
+    // Only queue files that are new or modified, s.t. we know how much there is to upload
+    // Hard links to a file that is uploaded under another path are only recorded
+    let mut total_bytes = 0;
+    let mut link_count = 0;
+    let filelist: Vec<String> = {
+        let mut manifest = manifest_mutex.lock().unwrap();
+        // When only some paths are given, the paths they were linked to last time are included s.t. those stay the target
+        let mut candidates = filelist.clone();
+        candidates.extend(filelist.iter().filter_map(|path| manifest.get_entry(path)?.link.clone()));
+        let links = hardlink::find_links(&candidates);
+        filelist.into_iter().filter(|path| {
+            if let Some(target) = links.get(path) {
+                match std::fs::metadata(shadow::source(path)) {
+                    Ok(metadata) => {
+                        let modified_time = metadata.modified().ok()
+                            .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                            .map_or(0, |d| d.as_millis() as u64);
+                        manifest.set_link(path, target, modified_time, metadata.len());
+                        printverbose(format!("Hard link {} to {}, recorded without uploading it", path, target));
+                        link_count += 1;
+                    },
+                    Err(e) => {
+                        printcoln(Color::Yellow, format!("Failed to get metadata, skipping file {} ({:?})", path, e));
+                        summary.fail(path);
+                    },
+                }
+                return false;
+            }
+            // No longer a hard link to another backed up file, so its content is uploaded
+            manifest.clear_link(path);
+            match needs_upload(&mut manifest, path) {
+                Ok(Some((_, size))) => {
+                    total_bytes += size;
+                    true
+                },
+                Ok(None) => {
+                    printverbose(format!("Unchanged {}", path));
+                    summary.unchanged(1);
+                    false
+                },
+                Err(e) => {
+                    printcoln(Color::Yellow, format!("Failed to get metadata, skipping file {} ({:?})", path, e));
+                    summary.fail(path);
+                    false
+                }
+            }
+        }).collect()
     };
-    let buckets = match raze::api::b2_list_buckets(&client, &auth, params) {
-        Ok(buckets) => buckets,
-        Err(err) => {
-            printcoln(Color::Red, format!("[{:.3}] Failed to retrieve bucket list", t_start.elapsed().as_secs_f32()));
-            printcoln(Color::Red, format!("[{:.3}] Reason: {:?}", t_start.elapsed().as_secs_f32(), err));
-            return;
+    printcoln(Color::Green, format!("[{:.3}] {} new or modified files", t_start.elapsed().as_secs_f32(), filelist.len()));
+    if link_count > 0 {
+        printcoln(Color::Green, format!("[{:.3}] {} hard links to other files are recorded without uploading them", t_start.elapsed().as_secs_f32(), link_count));
+    }
+    let file_count = filelist.len();
+
+    // New paths may be files that were moved, which reuse the remote files instead of being uploaded (see moves.rs)
+    let vanished = {
+        let manifest = manifest_mutex.lock().unwrap();
+        match filelist.iter().any(|path| manifest.get_entry(path).is_none()) {
+            true => Vanished::find(&manifest),
+            false => Vanished::default(),
         }
     };
+    if !vanished.is_empty() {
+        printverbose(format!("{} backed up files no longer exist, new files are checked for whether they were moved", vanished.len()));
+    }
+    // The new files that may be one of them are hashed up front, in parallel (see hashing.rs)
+    let hashed_at = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64);
+    let moved_hashes = match vanished.is_empty() {
+        true => HashMap::new(),
+        false => {
+            let new_paths: Vec<String> = {
+                let manifest = manifest_mutex.lock().unwrap();
+                filelist.iter().filter(|path| manifest.get_entry(path).is_none()).cloned().collect()
+            };
+            let hashes = hashing::sha1s_where(&new_paths, |path| {
+                match (std::fs::metadata(shadow::source(path)), moves::fingerprint(path)) {
+                    (Ok(metadata), Ok(f)) => vanished.may_match(metadata.len(), &f),
+                    _ => false,
+                }
+            });
+            printverbose(format!("[{:.3}] Hashed {} files that may have been moved", t_start.elapsed().as_secs_f32(), hashes.len()));
+            hashes
+        },
+    };
 
-    let bucket_name = config.bucket_name.as_ref().unwrap();
-    let bucket_id = match buckets.get(0) {
-        Some(res) => &res.bucket_id,
-        None => {
-            printcoln(Color::Red, format!("[{:.3}] No bucket with the name '{}'", t_start.elapsed().as_secs_f32(), bucket_name));
-            return;
+    let backend = backend::connect(config, budget.clone(), t_start).map_err(|e| format!("[{:.3}] {}", t_start.elapsed().as_secs_f32(), e))?;
+    capabilities::require(&backend.check_access(Operation::Upload), t_start).map_err(|e| format!("[{:.3}] {}", t_start.elapsed().as_secs_f32(), e))?;
+    if key.is_some() {
+        download::check_remote_nonces(config, backend.as_ref(), &budget, t_start).map_err(|e| format!("[{:.3}] {}", t_start.elapsed().as_secs_f32(), e))?;
+    }
+
+    printcoln(Color::Green, format!("[{:.3}] Beginning upload to {}", t_start.elapsed().as_secs_f32(), backend.describe()));
+    let progress = Progress::new(total_bytes, file_count, threads);
+    let bars = (0..threads).map(|i| progress.worker(i)).collect();
+
+    let mut runtime = match transfer::runtime() {
+        Ok(r) => r,
+        Err(e) => {
+            progress.finish();
+            return Err(format!("[{:.3}] Failed to start the transfer runtime ({})", t_start.elapsed().as_secs_f32(), e));
         }
     };
-    printcoln(Color::Green, format!("[{:.3}] {} -> {}", t_start.elapsed().as_secs_f32(), bucket_name, bucket_id));
-
-    printcoln(Color::Green, format!("[{:.3}] Beginning upload", t_start.elapsed().as_secs_f32()));
-
-    let do_encrypt = config.encrypt.unwrap();
-    // Load last known nonce
-    let mut config_handle = Mutex::new(config);
-
-    // Setup interrupt handler
-    let (tx,rx) = mpsc::channel();
-    ctrlc::set_handler(move || {
-        tx.send(1).unwrap();
-    }).expect("Failed to set Ctrl-C handler!");
-
-
-    // Pool size = num threads = concurrent uploads
-    // 1 extra thread is used to sync+upload the manifest every few minutes
-    let pool = Pool::new(9);
-    let busy_threads = AtomicUsize::new(pool.workers()-1);
-    pool.scoped(|scope| {
-        // Spawn sync task
-        let files = file_queue.clone();
-        let client = &client;
-        let auth = &auth;
-        let manifest = &manifest_mutex;
-        let upauth = raze::api::b2_get_upload_url(&client, &auth, bucket_id).unwrap();
-        let config_handle = &config_handle;
-        let busy_threads = &busy_threads;
-        scope.execute(move || {
-            let mut last_sync = std::time::Instant::now();
-            loop {
-                // Every 5 secs, check if there are still more items left in queue
-                // We need to know, s.t. we can terminate this thread when there is no more work
-                // If we received an Ok(n), we received an interrupt signal and should terminate as soon as possible
-                let res = rx.recv_timeout(Duration::from_secs(5));
-
-                if res.is_ok() {
-                    printcoln(Color::Yellow, format!("[{:.3}] Interrupt received", t_start.elapsed().as_secs_f32()));
-                    printcoln(Color::Yellow, format!("[{:.3}] Saving manifest locally...", t_start.elapsed().as_secs_f32()));
-                    manifest.lock().unwrap().to_file("manifest.json").unwrap();
-                    printcoln(Color::Yellow, format!("[{:.3}] Warning: manifest was only saved locally due to an interruption", t_start.elapsed().as_secs_f32()));
-                    printcoln(Color::Yellow, format!("[{:.3}] Using the remote manifest may result in desynchronization", t_start.elapsed().as_secs_f32()));
-                    printcoln(Color::Yellow, format!("[{:.3}] If interrupted due to errors, you should run 'retain-rs check' to re-sync local and remote", t_start.elapsed().as_secs_f32()));
-                    abort();
-                }
 
-                let active_threads = busy_threads.load(Ordering::SeqCst);
+    // Stop starting new uploads when interrupted. Large files in progress stop at the next block and aren't recorded
+    // in the manifest (see transfer.rs), files that are held in memory are finished
+    let cancel = transfer::Cancel::on_interrupt(move || {
+        printcoln(Color::Yellow, format!("[{:.3}] Interrupt received", t_start.elapsed().as_secs_f32()));
+        printcoln(Color::Yellow, format!("[{:.3}] Stopping uploads in progress, files that aren't done are uploaded by the next upload...", t_start.elapsed().as_secs_f32()));
+    });
+
+    let uploader = Arc::new(Uploader {
+        backend,
+        key,
+        cipher,
+        block: config.block_size.unwrap_or_default(),
+        // Used to allocate nonces, handed back once the uploads are done
+        config: Mutex::new(std::mem::take(config)),
+        throttle: bucket,
+        progress,
+        max_attempts,
+        compress,
+        budget,
+        summary,
+        manifest: manifest_mutex,
+        vanished: Mutex::new(vanished),
+        moved_hashes,
+        hashed_at,
+        moved: AtomicUsize::new(0),
+        bars: Mutex::new(bars),
+        manifest_synced: AtomicBool::new(false),
+        synced_ids: Mutex::new(Vec::new()),
+        journal: Mutex::new(journal),
+        queued: Mutex::new(filelist.iter().cloned().collect()),
+        stopped: Mutex::new(Vec::new()),
+        records_pending,
+        in_progress: Mutex::new(HashMap::new()),
+        cancel: cancel.clone(),
+        t_start,
+    });
+    // Pressing Ctrl-C again exits without waiting for the uploads in progress, what is done is saved first
+    let exiting = uploader.clone();
+    let _exit_hook = interrupt::on_exit(move || exiting.save_on_exit());
 
-                // Check if it's time to sync the manifest
-                // Every 5 minutes or if all workers are done
-                if last_sync.elapsed().as_secs_f32() >= 60.0*5.0 || active_threads == 0 {
-                    if active_threads == 0 {
-                        printcoln(Color::Green, format!("[{:.3}] Finalizing manifest sync", t_start.elapsed().as_secs_f32()));
+    // The manifest is synced every MANIFEST_SYNC_INTERVAL while uploading
+    runtime.block_on(async {
+        let (done, mut uploads_done) = oneshot::channel::<()>();
+        let uploads = async {
+            transfer::for_each_bounded(filelist, threads, &cancel, |path| {
+                let uploader = uploader.clone();
+                async move {
+                    let before = uploader.uploaded_at(&path);
+                    let previous = uploader.manifest.lock().unwrap().get_entry(&path).cloned();
+                    uploader.in_progress.lock().unwrap().insert(path.clone(), previous);
+                    uploader.clone().upload_path(path.clone()).await;
+                    uploader.queued.lock().unwrap().remove(&path);
+                    if uploader.uploaded_at(&path).is_some() && uploader.uploaded_at(&path) != before {
+                        uploader.journal(&path);
                     }
-                    manifest.lock().unwrap().to_file("manifest.json").unwrap();
-
-                    let filesize = std::fs::metadata("manifest.json").unwrap().len();
-                    let file = std::fs::File::open("manifest.json").unwrap();
-
-                    let params = raze::api::FileParameters {
-                        file_path: "manifest.json", // NEVER mask so we can find it anytime
-                        file_size: if do_encrypt { get_encrypted_size(filesize) } else { filesize },
-                        content_type: None, // auto
-                        content_sha1: Sha1Variant::HexAtEnd,
-                        last_modified_millis: 0,
-                    };
-
-                    let file = if do_encrypt {
-                        let (start_nonce,allocated) = {
-                            let mut n = config_handle.lock().unwrap();
-                            let req = get_nonces_required(filesize);
-                            let start = n.consume_nonces(req);
-                            (start, req)
-                        };
-                        let file = raze::util::ReadHashAtEnd::wrap(
-                            EncryptingReader::wrap(file,
-                                                   &key.unwrap(),
-                                                   start_nonce,
-                                                   allocated));
-                        raze::api::b2_upload_file(&client, &upauth, file, params)
-                    } else {
-                        let file = raze::util::ReadHashAtEnd::wrap(file);
-                        raze::api::b2_upload_file(&client, &upauth, file, params)
-                    };
+                    uploader.in_progress.lock().unwrap().remove(&path);
+                }
+            }).await;
+            done.send(()).unwrap_or(());
+        };
+        let syncing = async {
+            while let Err(_) = tokio::time::timeout(MANIFEST_SYNC_INTERVAL, &mut uploads_done).await {
+                let uploader = uploader.clone();
+                transfer::blocking(move || uploader.sync_manifest()).await;
+            }
+        };
+        futures::join!(uploads, syncing);
+    });
+    uploader.progress.finish();
+    *config = std::mem::take(&mut *uploader.config.lock().unwrap());
+
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64);
+    // An interrupted run is no consistent state of the backup, its changes are in the snapshot of the next run
+    if cancel.is_cancelled() {
+        uploader.manifest.lock().unwrap().record_changes();
+    } else if let Some(generation) = uploader.manifest.lock().unwrap().record_snapshot(now) {
+        printcoln(Color::Green, format!("[{:.3}] Recorded snapshot {}", t_start.elapsed().as_secs_f32(), generation));
+    }
+
+    uploader.save_pending();
+
+    if cancel.is_cancelled() {
+        printcoln(Color::Yellow, format!("[{:.3}] Saving manifest locally...", t_start.elapsed().as_secs_f32()));
+        uploader.manifest.lock().unwrap().save_local().unwrap();
+        printcoln(Color::Yellow, format!("[{:.3}] Warning: manifest was only saved locally due to an interruption", t_start.elapsed().as_secs_f32()));
+        printcoln(Color::Yellow, format!("[{:.3}] Using the remote manifest may result in desynchronization", t_start.elapsed().as_secs_f32()));
+        printcoln(Color::Yellow, format!("[{:.3}] If interrupted due to errors, you should run 'retain-rs check' to re-sync local and remote", t_start.elapsed().as_secs_f32()));
+        return Ok(());
+    }
+    printcoln(Color::Green, format!("[{:.3}] Finalizing manifest sync", t_start.elapsed().as_secs_f32()));
+    uploader.sync_manifest();
+
+    // The manifest is automatically written to disk and synced to remote
+    // This happens every 5 minutes while uploading and when the backup finishes
+    // Only the most recent versions are kept, older ones are pruned once the final one is uploaded
+    // The syncs before the final one are deleted first, so only the versions of finished runs are counted
+    let summary = &uploader.summary;
+    let budget = &uploader.budget;
+    if !uploader.manifest_synced.load(Ordering::SeqCst) {
+        summary.fail(manifest::REMOTE_MANIFEST);
+    } else {
+        let mut intermediate = std::mem::take(&mut *uploader.synced_ids.lock().unwrap());
+        intermediate.pop();
+        for id in intermediate {
+            if let Err(e) = uploader.backend.delete(manifest::REMOTE_MANIFEST, Some(&id)) {
+                printcoln(Color::Red, format!("[{:.3}] Failed to delete an intermediate version of the manifest ({})", t_start.elapsed().as_secs_f32(), e));
+            }
+        }
+        let keep = config.manifest_history.unwrap_or(manifest::DEFAULT_MANIFEST_HISTORY);
+        match uploader.backend.prune_manifest_history(manifest::REMOTE_MANIFEST, keep) {
+            Ok(0) => (),
+            Ok(n) => printcoln(Color::Green, format!("[{:.3}] Pruned {} old versions of the manifest", t_start.elapsed().as_secs_f32(), n)),
+            Err(e) => printcoln(Color::Red, format!("[{:.3}] Failed to prune old versions of the manifest ({})", t_start.elapsed().as_secs_f32(), e)),
+        }
+    }
+    let moved = uploader.moved.load(Ordering::SeqCst);
+    if moved > 0 {
+        printcoln(Color::Green, format!("[{:.3}] {} moved files were recognized instead of uploaded again", t_start.elapsed().as_secs_f32(), moved));
+    }
+    printsummary(Color::Green, format!("[{:.3}] API calls: {}", t_start.elapsed().as_secs_f32(), budget.summary()));
+
+    printsummary(Color::Green, format!("[{:.3}] Backup Completed! ({} new or modified files)", t_start.elapsed().as_secs_f32(), file_count));
+    summary.tracked(uploader.manifest.lock().unwrap().files.len());
+    summary.finish(Some(uploader.progress.transferred()));
+    Ok(())
+}
+
+// Everything needed to upload files, shared by all upload tasks
+struct Uploader {
+    backend: Box<dyn Backend>,
+    key: Option<EncryptionKey>, // Set if encryption is enabled
+    cipher: Cipher,
+    block: BlockSize, // Largest block size files are encrypted in, see BlockSize::fitting
+    config: Mutex<Config>, // Used to allocate nonces
+    throttle: Option<Arc<Mutex<TokenBucket>>>,
+    progress: Progress,
+    max_attempts: u32,
+    compress: bool, // Whether to compress files before uploading them
+    budget: Arc<Budget>,
+    summary: Summary, // Every file goes through 'upload' or 'upload_buffered' once, which records whether it succeeded
+    manifest: Mutex<FileManifest>,
+    // Entries whose file no longer exists, which new files may have been moved from
+    vanished: Mutex<Vanished>,
+    // SHA1 of the new files that may have been moved, hashed at 'hashed_at' (ms since the Unix epoch)
+    moved_hashes: HashMap<String, String>,
+    hashed_at: u64,
+    // Amount of files that were copied within the bucket instead of uploaded
+    moved: AtomicUsize,
+    // Bars of uploads that aren't in progress, there is one for every upload that may be in progress at once
+    bars: Mutex<Vec<ProgressBar>>,
+    // Whether the last manifest sync succeeded
+    manifest_synced: AtomicBool,
+    // IDs of the manifest versions uploaded by this run, the last one is the newest
+    synced_ids: Mutex<Vec<String>>,
+    // Where completed uploads are recorded right away, see journal.rs
+    journal: Mutex<Option<Journal>>,
+    // Files that weren't uploaded yet, and those stopped midway by cancelling, see pending.rs
+    queued: Mutex<HashSet<String>>,
+    stopped: Mutex<Vec<String>>,
+    // Whether this run records the files that are left, see save_pending
+    records_pending: bool,
+    // Entries of the files being uploaded as they were before, see save_on_exit
+    in_progress: Mutex<HashMap<String, Option<FileEntry>>>,
+    // Stops reading the files being uploaded once interrupted
+    cancel: transfer::Cancel,
+    t_start: Instant,
+}
+
+impl Uploader {
+    // Uploads the file at 'path' if it is still new or modified, and records it in the manifest
+    async fn upload_path(self: Arc<Self>, path: String) {
+        let progress = &self.progress;
+
+        // Check if the file is already backed up and if it has been modified since
+        // This was checked when building the queue, but the file may have changed since
+        let checked = needs_upload(&mut self.manifest.lock().unwrap(), &path);
+        let (modified_time, filesize) = match checked {
+            Ok(Some(v)) => v,
+            Ok(None) => {
+                printverbose(format!("Unchanged {}", path));
+                self.summary.unchanged(1);
+                progress.skip(0);
+                return;
+            },
+            Err(e) => {
+                progress.println(format!("Failed to get metadata, skipping file {:?} ({:?})", path, e));
+                self.summary.fail(&path);
+                progress.skip(0);
+                return;
+            }
+        };
+
+        // A new path may be a file that was moved, its remote files are then reused instead
+        let is_new = self.manifest.lock().unwrap().get_entry(&path).is_none();
+        if is_new && self.vanished.lock().unwrap().has_size(filesize) {
+            let (uploader, source) = (self.clone(), path.clone());
+            if transfer::blocking(move || uploader.reuse_moved(&source, modified_time, filesize)).await {
+                progress.skip(filesize);
+                return;
+            }
+        }
 
-                    if active_threads == 0 {
-                        break;
+        // Get the name to use in B2
+        // Either masked name or web-compatible path
+        let previous = self.manifest.lock().unwrap().get_entry(&path).cloned();
+        let name_in_b2 = {
+            let mut manifest = self.manifest.lock().unwrap();
+            manifest.update_timestamp(&path, modified_time);
+            let name_in_b2 = manifest.get_mask(&path, modified_time).1;
+            manifest.update_size(&path, filesize);
+            name_in_b2
+        };
+
+        let bar = self.bars.lock().unwrap().pop().expect("More uploads in progress than bars");
+        if filesize < transfer::BUFFERED_SIZE {
+            if let Some((compressed, content, file_id)) = self.upload_buffered(&bar, &path, &name_in_b2, filesize, modified_time).await {
+                let mut manifest = self.manifest.lock().unwrap();
+                let entry = manifest.get_entry_mut(&path).unwrap();
+                entry.file_id = file_id;
+                entry.compressed = compressed;
+                entry.set_content(content);
+                entry.sparse.clear();
+                entry.set_uploaded();
+            }
+            progress.file_done(&bar);
+        } else {
+            let (uploader, worker_bar, source) = (self.clone(), bar.clone(), path.clone());
+            transfer::blocking(move || uploader.upload_blocking(&worker_bar, &source, &name_in_b2, filesize, modified_time)).await;
+            if self.cancel.is_cancelled() {
+                self.roll_back(&path, previous);
+            }
+        }
+        self.bars.lock().unwrap().push(bar);
+    }
+
+    // When the entry of 'path' was last uploaded, None if it never was or there is no entry
+    fn uploaded_at(&self, path: &str) -> Option<u64> {
+        self.manifest.lock().unwrap().get_entry(path).map(|e| e.uploaded).filter(|&t| t > 0)
+    }
+
+    // Records the entry of 'path' in the journal, see journal.rs
+    fn journal(&self, path: &str) {
+        let entry = match self.manifest.lock().unwrap().get_entry(path) {
+            Some(entry) => entry.clone(),
+            None => return,
+        };
+        self.append(&Record::Uploaded(Box::new(entry)));
+    }
+
+    fn append(&self, record: &Record) {
+        if let Some(journal) = self.journal.lock().unwrap().as_mut() {
+            if let Err(e) = journal.append(record) {
+                self.progress.println(format!("Failed to write to the journal ({}), uploads are only recorded when the manifest is saved", e));
+            }
+        }
+    }
+
+    // Records the files that are left for 'backup upload --resume', if this run does
+    fn save_pending(&self) {
+        if !self.records_pending {
+            return;
+        }
+        let mut queued: Vec<String> = self.queued.lock().unwrap().drain().collect();
+        queued.extend(self.stopped.lock().unwrap().drain(..));
+        queued.sort();
+        let pending = Pending { queued, failed: self.summary.failures() };
+        match pending.save(manifest::pending_path()) {
+            Ok(_) if !pending.is_empty() => printcoln(Color::Yellow, format!("[{:.3}] {} files are left, 'backup upload --resume' uploads just those",
+                                                                              self.t_start.elapsed().as_secs_f32(), pending.paths().len())),
+            Ok(_) => (),
+            Err(e) => printcoln(Color::Yellow, format!("Warning: failed to record the files that are left ({})", e)),
+        }
+    }
+
+    // Saves the manifest locally and the files that are left when Ctrl-C exits the process during the uploads
+    // The entries of files still being uploaded are put back as they were, like roll_back does
+    fn save_on_exit(&self) {
+        printcoln(Color::Yellow, format!("[{:.3}] Exiting, saving manifest locally...", self.t_start.elapsed().as_secs_f32()));
+        let in_progress: Vec<(String, Option<FileEntry>)> = self.in_progress.lock().unwrap().drain().collect();
+        let mut manifest = self.manifest.lock().unwrap();
+        for (path, previous) in in_progress {
+            match previous {
+                Some(previous) => manifest.put_entry(previous),
+                None => manifest.remove_path(&path),
+            }
+            self.stopped.lock().unwrap().push(path);
+        }
+        manifest.record_changes();
+        if let Err(e) = manifest.save_local() {
+            printcoln(Color::Red, format!("Failed to save the manifest ({}), the journal has the completed uploads", e));
+        }
+        drop(manifest);
+        self.save_pending();
+    }
+
+    // Puts the entry of 'path' back as it was before an upload that was stopped by cancelling, unless it was uploaded
+    // Its timestamp is set before uploading, so without this the next upload would take it as backed up
+    fn roll_back(&self, path: &str, previous: Option<FileEntry>) {
+        let mut manifest = self.manifest.lock().unwrap();
+        let uploaded = manifest.get_entry(path).map(|e| e.uploaded);
+        match previous {
+            Some(previous) if uploaded == Some(previous.uploaded) => *manifest.get_entry_mut(path).unwrap() = previous,
+            None if uploaded == Some(0) => manifest.remove_path(path),
+            _ => return,
+        }
+        self.stopped.lock().unwrap().push(path.to_string());
+        self.progress.println(format!("Stopped uploading {:?}, it is uploaded by the next upload", path));
+    }
+
+    // If the new file at 'path' has the content of a vanished entry, its remote files are reused for it
+    // With masked names the entry is relocated to 'path', otherwise they are copied to the new path's name
+    // Returns whether they were reused, if not it is uploaded as usual
+    fn reuse_moved(&self, path: &str, modified_time: u64, filesize: u64) -> bool {
+        // Only hash the whole file if its fingerprint matches
+        let fingerprint = match moves::fingerprint(path) {
+            Ok(f) if self.vanished.lock().unwrap().may_match(filesize, &f) => f,
+            _ => return false,
+        };
+        let sha1 = match self.moved_hashes.get(path) {
+            // Unless it may have been modified since it was hashed
+            Some(sha1) if modified_time + 1000 < self.hashed_at => sha1.clone(),
+            _ => match moves::hash_file(path) {
+                Ok(sha1) => sha1,
+                Err(_) => return false,
+            },
+        };
+        let from = match self.vanished.lock().unwrap().claim(filesize, &fingerprint, &sha1) {
+            Some(from) => from,
+            None => return false,
+        };
+
+        let mut manifest = self.manifest.lock().unwrap();
+        if manifest.mask {
+            manifest.relocate(&from, path);
+        } else {
+            let old = manifest.get_entry(&from).unwrap().clone();
+            let new_name = manifest.get_mask(path, modified_time).1;
+            drop(manifest);
+
+            // The full upload first, then each delta under the new name
+            let mut copies = vec![(old.mask.clone(), new_name.clone())];
+            let deltas: Vec<String> = (1..=old.deltas.len()).map(|n| format!("{}.delta{}", new_name, n)).collect();
+            copies.extend(old.deltas.iter().cloned().zip(deltas.iter().cloned()));
+            let mut ids = Vec::new();
+            for (source, target) in copies.iter() {
+                match self.backend.copy(source, target) {
+                    Ok(id) => ids.push(id),
+                    Err(e) => {
+                        self.progress.println(format!("Failed to copy {:?} from {:?} ({}) - Uploading it instead", path, from, e));
+                        return false;
                     }
                 }
             }
-        });
-
-        // Spawn upload tasks
-        for i in 0..pool.workers()-1 {
-            let files = file_queue.clone();
-
-            let manifest = &manifest_mutex;
-            scope.execute(move || {
-                let upauth = raze::api::b2_get_upload_url(&client, &auth, bucket_id).unwrap();
-                loop {
-                    // Try to get a file to upload
-                    let p = {
-                        files.lock().unwrap().pop()
-                    };
-                    let path = match p {
-                        Some(p) => p,
-                        None => {
-                            // List is empty, nothing more to upload
-                            // Decrement busy threads by 1
-                            busy_threads.fetch_sub(1, Ordering::SeqCst);
-                            break;
-                        }
-                    };
-
-                    // Check if the file is already backed up and if it has been modified since
-                    // Get modified time and filesize by querying metadata
-                    let do_upload: bool;
-                    let metadata = match std::fs::metadata(&path) {
-                        Ok(m) => m,
-                        Err(e) => {
-                            println!("Failed to get metadata, skipping file ({:?})", e);
-                            continue;
-                        }
-                    };
-                    let modified_time = match metadata.modified().unwrap().duration_since(std::time::UNIX_EPOCH) {
-                        Ok(v) => v.as_millis() as u64, // Convert seconds to milliseconds
-                        Err(_e) => 0u64
-                    };
-                    let filesize = metadata.len(); // Used later as well
-
-                    // Returns 'None' if entry hasn't been uploaded
-                    match manifest.lock().unwrap().get_from_path(&path) {
-                        Some(t) => {
-                            do_upload = modified_time > t.0;
-                        },
-                        None => {
-                            do_upload = true;
-                        }
+
+            manifest = self.manifest.lock().unwrap();
+            manifest.remove_path(&from);
+            let entry = manifest.get_entry_mut(path).unwrap();
+            entry.blocks = old.blocks;
+            entry.deltas.clear();
+            entry.delta_ids.clear();
+            for (delta, id) in deltas.into_iter().zip(ids.drain(1..)) {
+                entry.push_delta(delta, id);
+            }
+            entry.compressed = old.compressed;
+            entry.sparse = old.sparse;
+            entry.file_id = ids.pop().flatten();
+        }
+        let entry = manifest.get_entry_mut(path).unwrap();
+        entry.timestamp = modified_time;
+        entry.size = filesize;
+        entry.set_content(Content { sha1, fingerprint });
+        entry.set_uploaded();
+        drop(manifest);
+        self.append(&Record::Removed(from));
+        self.summary.record(path, true);
+        self.moved.fetch_add(1, Ordering::SeqCst);
+        true
+    }
+
+    // Uploads a file that is too large to buffer, using the blocking calls
+    // It may be sparse, or changed little enough since the last upload to only upload the changed blocks
+    fn upload_blocking(&self, bar: &ProgressBar, path: &str, name_in_b2: &str, filesize: u64, modified_time: u64) {
+        let progress = &self.progress;
+
+        // Only the data of sparse files is uploaded, without the holes
+        if filesize >= sparse::SPARSE_THRESHOLD {
+            match std::fs::File::open(shadow::source(path)).and_then(|f| sparse::data_extents(&f, filesize)) {
+                Ok(Some(extents)) => {
+                    let data_size = sparse::data_length(&extents);
+                    progress.remove_length(filesize - data_size);
+                    progress.begin(bar, path, data_size);
+                    if let Some(file_id) = self.upload(bar, path, name_in_b2, data_size, modified_time,
+                                                       || SparseReader::open(&shadow::source(path), &extents)) {
+                        let mut manifest = self.manifest.lock().unwrap();
+                        let entry = manifest.get_entry_mut(path).unwrap();
+                        entry.file_id = file_id;
+                        // Sparse files aren't hashed, they aren't recognized when moved
+                        entry.set_content(Content::default());
+                        entry.blocks.clear();
+                        entry.deltas.clear();
+                        entry.delta_ids.clear();
+                        entry.compressed = false;
+                        entry.sparse = extents;
+                        entry.set_uploaded();
                     }
-                    if !do_upload {
-                        continue;
+                    progress.file_done(bar);
+                    return;
+                },
+                Ok(None) => (),
+                Err(e) => progress.println(format!("Failed to find holes in {:?} ({:?}) - Uploading it in full", path, e)),
+            }
+        }
+
+        // Files below the delta threshold are always uploaded in full, and hashed while they are
+        if filesize < delta::DELTA_THRESHOLD {
+            if let Some((compressed, file_id, content)) = self.upload_full(bar, path, name_in_b2, filesize, modified_time) {
+                let mut manifest = self.manifest.lock().unwrap();
+                let entry = manifest.get_entry_mut(path).unwrap();
+                entry.file_id = file_id;
+                entry.compressed = compressed;
+                entry.set_content(content);
+                entry.sparse.clear();
+                entry.set_uploaded();
+            }
+            progress.file_done(bar);
+            return;
+        }
+
+        // Large files are compared block-by-block with the previous upload
+        // If only a small part changed, only the changed blocks are uploaded
+        // Sparse uploads have no blocks, so a file that is no longer sparse is uploaded in full
+        let (previous, delta_count) = match self.manifest.lock().unwrap().get_entry_mut(path) {
+            Some(e) => (e.blocks.clone(), e.deltas.len()),
+            None => (vec![], 0),
+        };
+        let changes = match std::fs::File::open(shadow::source(path)).and_then(|f| delta::changed_blocks(f, &previous)) {
+            Ok(c) => c,
+            Err(e) => {
+                progress.println(format!("Failed to read file {:?} ({:?}) - It will not be uploaded", path, e));
+                self.summary.fail(path);
+                progress.skip(filesize);
+                return;
+            }
+        };
+
+        if changes.use_delta(delta_count, &previous) {
+            // The whole file was just hashed, only its fingerprint is left to record
+            let fingerprint = moves::fingerprint(path).unwrap_or_default();
+            let delta_name = format!("{}.delta{}", name_in_b2, delta_count+1);
+            progress.begin(bar, path, changes.delta_size());
+            // The ID of the full upload is kept, that of the delta is recorded along with it
+            if let Some(delta_id) = self.upload(bar, path, &delta_name, changes.delta_size(), modified_time,
+                                                || DeltaReader::open(&shadow::source(path), &changes)) {
+                let mut manifest = self.manifest.lock().unwrap();
+                let entry = manifest.get_entry_mut(path).unwrap();
+                entry.set_content(Content { sha1: changes.sha1, fingerprint });
+                entry.blocks = changes.hashes;
+                entry.push_delta(delta_name, delta_id);
+                entry.set_uploaded();
+            }
+        } else {
+            if let Some((compressed, file_id, content)) = self.upload_full(bar, path, name_in_b2, filesize, modified_time) {
+                // Old deltas no longer apply, they are removed remotely by 'clean'
+                let mut manifest = self.manifest.lock().unwrap();
+                let entry = manifest.get_entry_mut(path).unwrap();
+                entry.file_id = file_id;
+                entry.set_content(content);
+                entry.blocks = changes.hashes;
+                entry.deltas.clear();
+                entry.delta_ids.clear();
+                entry.compressed = compressed;
+                entry.sparse.clear();
+                entry.set_uploaded();
+            }
+        }
+        progress.file_done(bar);
+    }
+
+    // Uploads a file small enough to hold in memory (see transfer::BUFFERED_SIZE) with the async client
+    // Reading, compressing and encrypting it happens once on a blocking thread, every attempt sends the same data
+    // Returns whether the uploaded data is compressed, the hashes of the file's content and the ID of the upload, or None if the upload failed
+    async fn upload_buffered(self: &Arc<Self>, bar: &ProgressBar, path: &str, name_in_b2: &str,
+                             size: u64, modified_time: u64) -> Option<(bool, Content, Option<String>)> {
+        let progress = &self.progress;
+        progress.begin(bar, path, size);
+
+        let (uploader, source) = (self.clone(), path.to_string());
+        let (data, compressed, content) = match transfer::blocking(move || uploader.read_buffered(&source)).await {
+            Ok(v) => v,
+            Err(e) => {
+                progress.println(format!("Failed to read file {:?} ({:?}) - It will not be uploaded", path, e));
+                self.summary.fail(path);
+                return None;
+            }
+        };
+        // Compression and encryption change the amount sent
+        let length = data.len() as u64;
+        progress.remove_length(size);
+        progress.add_length(length);
+        bar.set_length(length);
+        let sha1 = sha1::Sha1::from(&data).digest().to_string();
+        let data = Arc::new(data);
+
+        // Try uploading up to 'max_attempts' times
+        for attempt in 0..self.max_attempts {
+            retry::hold_off_async().await;
+            progress.rewind(bar);
+            let body = transfer::body(data.clone(), self.throttle.clone(), progress.counter(bar));
+            let result = self.backend.upload_buffered(name_in_b2, data.clone(), body, &sha1, modified_time).await;
+            let e = match result {
+                Ok(file_id) => {
+                    self.summary.record(path, true);
+                    return Some((compressed, content, file_id));
+                },
+                Err(e) => e,
+            };
+            progress.println(format!("Upload failed: {}", e));
+            if !e.retryable {
+                progress.println(format!("Failed to upload {:?}, error is not retryable", path));
+                break;
+            } else if attempt == self.max_attempts-1 {
+                progress.println(format!("Failed to upload {:?} after {} attempts", path, self.max_attempts));
+            } else {
+                // Back off and retry
+                tokio::time::delay_for(retry::delay_for(attempt, e.retry_after)).await;
+            }
+        }
+        self.summary.fail(path);
+        None
+    }
+
+    // Reads the file at 'path' for a buffered upload, compressing it if enabled and worthwhile, then encrypting it if enabled
+    // Returns the data to upload, whether it is compressed and the hashes of the file's content
+    fn read_buffered(&self, path: &str) -> std::io::Result<(Vec<u8>, bool, Content)> {
+        let mut data = std::fs::read(shadow::source(path))?;
+        let content = Content::of(&data);
+        let mut compressed = false;
+        if self.compress && compression::is_compressible(path) {
+            let smaller = zstd::stream::encode_all(&data[..], compression::COMPRESSION_LEVEL)?;
+            // Only upload the compressed version if it is actually smaller
+            if smaller.len() < data.len() {
+                data = smaller;
+                compressed = true;
+            }
+        }
+        if let Some(key) = &self.key {
+            let size = data.len() as u64;
+            let (mut reader, encrypted_size) = self.encrypt(key, Cursor::new(data), size);
+            let mut encrypted = Vec::with_capacity(encrypted_size as usize);
+            reader.read_to_end(&mut encrypted)?;
+            data = encrypted;
+        }
+        Ok((data, compressed, content))
+    }
+
+    // Saves the manifest locally and uploads it, adding a new version of it
+    fn sync_manifest(&self) {
+        let allocated = self.config.lock().unwrap().nonces_allocated();
+        let bytes = {
+            let mut manifest = self.manifest.lock().unwrap();
+            manifest.record_nonces(allocated);
+            manifest.save_local().unwrap()
+        };
+        let filesize = bytes.len() as u64;
+        let file = Cursor::new(bytes);
+
+        // NEVER mask so we can find it anytime
+        let result = if let Some(key) = &self.key {
+            let (file, encrypted_size) = self.encrypt(key, file, filesize);
+            let file = ThrottledReader::wrap(file, self.throttle.clone());
+            self.backend.upload(manifest::REMOTE_MANIFEST, Box::new(file), encrypted_size, 0)
+        } else {
+            let file = ThrottledReader::wrap(file, self.throttle.clone());
+            self.backend.upload(manifest::REMOTE_MANIFEST, Box::new(file), filesize, 0)
+        };
+        // Uploading adds a new version, the previous manifest stays available until pruned
+        match result {
+            Ok(id) => {
+                self.synced_ids.lock().unwrap().extend(id);
+                self.manifest_synced.store(true, Ordering::SeqCst);
+            },
+            Err(e) => {
+                printcoln(Color::Red, format!("[{:.3}] Failed to sync manifest ({})", self.t_start.elapsed().as_secs_f32(), e));
+                self.manifest_synced.store(false, Ordering::SeqCst);
+            }
+        }
+    }
+
+    // Uploads the data returned by 'open' as 'name_in_b2', encrypting it if encryption is enabled
+    // 'open' is called once per attempt and 'size' is the (unencrypted) size of the data it returns
+    // 'path' is only used for messages and the summary
+    // Returns the ID of the upload if it succeeded (None within if the storage has no IDs), else None
+    fn upload<R, F>(&self, bar: &ProgressBar, path: &str, name_in_b2: &str,
+                    size: u64, modified_time: u64, open: F) -> Option<Option<String>>
+        where R: Read + Send + 'static,
+              F: Fn() -> std::io::Result<R> {
+        let result = self.try_upload(bar, path, name_in_b2, size, modified_time, open);
+        // A file stopped by cancelling didn't fail, it is uploaded by the next upload
+        if result.is_some() || !self.cancel.is_cancelled() {
+            self.summary.record(path, result.is_some());
+        }
+        result
+    }
+
+    fn try_upload<R, F>(&self, bar: &ProgressBar, path: &str, name_in_b2: &str,
+                        size: u64, modified_time: u64, open: F) -> Option<Option<String>>
+        where R: Read + Send + 'static,
+              F: Fn() -> std::io::Result<R> {
+        let progress = &self.progress;
+
+        // Large files are uploaded in parts, each part is retried individually
+        if size > b2::LARGE_FILE_THRESHOLD {
+            let source = match open() {
+                Ok(f) => self.cancel.wrap_read(progress.wrap_read(f, bar)),
+                Err(e) => {
+                    progress.println(format!("Failed to open file {:?} ({:?}) - It will not be uploaded", path, e));
+                    return None;
+                }
+            };
+            let result = match &self.key {
+                Some(key) => {
+                    let (reader, _) = self.encrypt(key, source, size);
+                    self.backend.upload_large(name_in_b2, &mut ThrottledReader::wrap(reader, self.throttle.clone()), modified_time, self.max_attempts)
+                },
+                None => self.backend.upload_large(name_in_b2, &mut ThrottledReader::wrap(source, self.throttle.clone()), modified_time, self.max_attempts),
+            };
+            return match result {
+                Ok(file_id) => Some(file_id),
+                Err(_) if self.cancel.is_cancelled() => None,
+                Err(e) => {
+                    progress.println(format!("Failed to upload {:?} ({})", path, e));
+                    None
+                }
+            };
+        }
+
+        // Try uploading up to 'max_attempts' times
+        for attempt in 0..self.max_attempts {
+            retry::hold_off();
+            progress.rewind(bar);
+            let source = match open() {
+                Ok(f) => self.cancel.wrap_read(progress.wrap_read(f, bar)),
+                Err(e) => {
+                    progress.println(format!("Failed to open file {:?} ({:?}) - It will not be uploaded", path, e));
+                    return None;
+                }
+            };
+
+            let result = match &self.key {
+                Some(key) => {
+                    let (file, encrypted_size) = self.encrypt(key, source, size);
+                    let file = ThrottledReader::wrap(file, self.throttle.clone());
+                    self.backend.upload(name_in_b2, Box::new(file), encrypted_size, modified_time)
+                },
+                None => {
+                    let file = ThrottledReader::wrap(source, self.throttle.clone());
+                    self.backend.upload(name_in_b2, Box::new(file), size, modified_time)
+                }
+            };
+
+            match result {
+                Ok(file_id) => return Some(file_id),
+                Err(e) => {
+                    // Both the account's auth and the upload URL's can expire. The backend authorizes again
+                    // or gets a new upload URL before the next attempt
+                    if self.cancel.is_cancelled() {
+                        return None;
                     }
-                    manifest.lock().unwrap().update_timestamp(&path, modified_time);
-
-                    // Get the name to use in B2
-                    // Either masked name or web-compatible path
-                    let name_in_b2 = manifest.lock().unwrap().get_mask(&path, modified_time).1;
-
-                    //println!("Uploading {:?} -> {:?}", path, name_in_b2);
-                    println!("Uploading {}", path);
-
-                    // Try uploading up to 5 times
-                    for attempts in 0..5 {
-                        let file = match std::fs::File::open(&path) {
-                            Ok(f) => f,
-                            Err(e) => {
-                                println!("Failed to open file {:?} ({:?}) - It will not be uploaded", path, e);
-                                break;
-                            }
-                        };
-
-                        let params = raze::api::FileParameters {
-                            file_path: &name_in_b2,
-                            file_size: if do_encrypt { get_encrypted_size(filesize) } else { filesize },
-                            content_type: None, // auto
-                            content_sha1: Sha1Variant::HexAtEnd,
-                            last_modified_millis: modified_time,
-                        };
-
-                        let (start_nonce,allocated) = {
-                            let mut n = config_handle.lock().unwrap();
-                            let req = get_nonces_required(filesize);
-                            let start = n.consume_nonces(req);
-                            (start, req)
-                        };
-                        // println!("Using nonce {} through {} ({})", start_nonce, start_nonce+allocated-1, allocated);
-
-                        // TODO Handle bandwidth limiting by wrapping in throttled reader
-                        let result = if do_encrypt {
-                            let file = raze::util::ReadHashAtEnd::wrap(
-                                EncryptingReader::wrap(file,
-                                                        &key.unwrap(),
-                                                        start_nonce,
-                                                        allocated));
-                            raze::api::b2_upload_file(&client, &upauth, file, params)
-                        } else {
-                            let file = raze::util::ReadHashAtEnd::wrap(file);
-                            raze::api::b2_upload_file(&client, &upauth, file, params)
-                        };
-
-                        match result {
-                            Ok(_) => break,
-                            Err(e) => {
-                                println!("Upload failed: {:?}", e);
-                                match e {
-                                    raze::Error::B2Error(e) => {
-                                        // TODO: consider adding re-auth here
-                                        // Both 'auth' and 'upauth' can expire
-                                        println!("Reason: {:?}", e);
-                                    },
-                                    _ => (),
-                                }
-
-                                if attempts == 4 {
-                                    println!("Failed to upload {:?} after 5 attempts", path);
-                                } else {
-                                    // Sleep and retry
-                                    std::thread::sleep(Duration::from_millis(5000));
-                                    continue;
-                                }
-                            }
-                        }
+                    progress.println(format!("Upload failed: {}", e));
+                    if !e.retryable {
+                        progress.println(format!("Failed to upload {:?}, error is not retryable", path));
+                        return None;
+                    } else if attempt == self.max_attempts-1 {
+                        progress.println(format!("Failed to upload {:?} after {} attempts", path, self.max_attempts));
+                    } else {
+                        // Back off and retry
+                        std::thread::sleep(retry::delay_for(attempt, e.retry_after));
                     }
                 }
-            });
+            }
         }
-    });
+        None
+    }
 
-    // The manifest is automatically written to disk and synced to B2
-    // This happens every 5 minutes while uploading and when the backup finishes
+    // Uploads the file at 'path' in full, compressing it first if compression is enabled and worthwhile
+    // The file is hashed while it is read, for compressing or uploading it
+    // Returns whether the uploaded data is compressed, the ID of the upload and the content of the file, or None if the upload failed
+    fn upload_full(&self, bar: &ProgressBar, path: &str, name_in_b2: &str,
+                   size: u64, modified_time: u64) -> Option<(bool, Option<String>, Content)> {
+        let progress = &self.progress;
+        progress.begin(bar, path, size);
+        let hasher = ContentHasher::default();
+
+        if self.compress && compression::is_compressible(path) {
+            match std::fs::File::open(shadow::source(path)).and_then(|f| CompressedFile::compress(hasher.wrap(f))) {
+                // Only upload the compressed version if it is actually smaller
+                Ok(temp) if temp.size < size => {
+                    progress.remove_length(size - temp.size);
+                    bar.set_length(temp.size);
+                    let content = hasher.content();
+                    return self.upload(bar, path, name_in_b2, temp.size, modified_time, || open_buffered(&temp.path))
+                        .map(|file_id| (true, file_id, content));
+                },
+                Ok(_) => (),
+                Err(e) => progress.println(format!("Failed to compress {:?} ({:?}) - Uploading it uncompressed", path, e)),
+            }
+        }
+
+        self.upload(bar, path, name_in_b2, size, modified_time, || open_buffered(shadow::source(path)).map(|f| hasher.wrap(f)))
+            .map(|file_id| (false, file_id, hasher.content()))
+    }
+
+    // Wraps 'reader', which returns 'size' bytes, encrypting it with 'key' in the block size that fits it
+    // Returns the reader and the size of the encrypted data
+    fn encrypt<R: Read>(&self, key: &EncryptionKey, reader: R, size: u64) -> (EncryptingReader<R>, u64) {
+        let block = self.block.fitting(size);
+        let (start_nonce, allocated) = self.allocate_nonces(size, block);
+        (key.wrap(reader, self.cipher, block, start_nonce, allocated), key.encrypted_size(size, block))
+    }
+
+    // Allocate the nonces needed to encrypt 'size' bytes in blocks of 'block', returning (start nonce, amount allocated)
+    fn allocate_nonces(&self, size: u64, block: BlockSize) -> (u128, u128) {
+        let mut n = self.config.lock().unwrap();
+        let req = get_nonces_required(size, block);
+        let start = n.consume_nonces(req);
+        self.summary.nonces(req);
+        (start, req)
+    }
+}
+
+// Opens the file at 'path' for uploading, read in chunks of transfer::IO_BUFFER_LENGTH
+fn open_buffered<P: AsRef<std::path::Path>>(path: P) -> std::io::Result<BufReader<std::fs::File>> {
+    std::fs::File::open(path).map(|file| BufReader::with_capacity(transfer::IO_BUFFER_LENGTH, file))
+}
+
+// Check if the file at 'path' is new or has been modified since it was last backed up
+// If it has, returns its modified time (in milliseconds since Unix Epoch) and size
+fn needs_upload(manifest: &mut FileManifest, path: &str) -> Result<Option<(u64,u64)>, std::io::Error> {
+    let metadata = std::fs::metadata(shadow::source(path))?;
+    let modified_time = match metadata.modified()?.duration_since(std::time::UNIX_EPOCH) {
+        Ok(v) => v.as_millis() as u64, // Convert seconds to milliseconds
+        Err(_e) => 0u64
+    };
 
-    printcoln(Color::Green, format!("[{:.3}] Backup Completed!", t_start.elapsed().as_secs_f32()));
-}
\ No newline at end of file
+    // Returns 'None' if entry hasn't been uploaded
+    let do_upload = match manifest.get_from_path(path) {
+        Some(t) => modified_time > t.0,
+        None => true,
+    };
+    if do_upload {
+        Ok(Some((modified_time, metadata.len())))
+    } else {
+        Ok(None)
+    }
+}
@@ -0,0 +1,255 @@
+use clap::ArgMatches;
+use crate::colorutil::printcoln;
+use crate::config::Config;
+use crate::filetree::{FileTree, ROOT};
+use crate::manifest::{self, FileManifest};
+use crate::subcommands::backup::download::{self, DownloadOptions};
+use indicatif::HumanBytes;
+use ratatui::DefaultTerminal;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color as TuiColor, Style, Stylize};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{List, ListItem, ListState, Paragraph};
+use ratatui::Frame;
+use termcolor::Color;
+
+/// State of the browser: the directory being shown and what is selected in it
+struct Browser {
+    tree: FileTree,
+    dir: usize,
+    // Children of 'dir' that are listed, those whose name contains the filter if one is set
+    listed: Vec<usize>,
+    state: ListState,
+    filter: String,
+    // Whether keys are typed into the filter
+    typing: bool,
+    // Whether the restore of the selection is to be confirmed
+    confirming: bool,
+}
+
+/// What to do after a key is handled
+enum Action {
+    Continue,
+    Quit,
+    Restore,
+}
+
+/// Shows the backed up files in a terminal UI, to find and mark files and directories and restore them
+/// The marked files are restored like with 'backup download --path', with the options in 'args'
+pub fn browse(config: &Config, args: Option<&ArgMatches>) {
+    let args = args.unwrap();
+    let manifest = match FileManifest::from_file(manifest::local_path()) {
+        Ok(fm) => fm,
+        Err(err) => {
+            printcoln(Color::Red, format!("Failed to load file manifest ({})", err));
+            return;
+        }
+    };
+    if manifest.files.is_empty() {
+        printcoln(Color::Yellow, "No files are backed up yet");
+        return;
+    }
+
+    let mut browser = Browser::new(FileTree::build(&manifest.files));
+    // Start in the first directory with more than one thing in it, instead of one directory per path component
+    while browser.listed.len() == 1 && browser.tree.is_dir(browser.listed[0]) {
+        browser.open(browser.listed[0]);
+    }
+
+    let mut terminal = match ratatui::try_init() {
+        Ok(t) => t,
+        Err(e) => {
+            printcoln(Color::Red, format!("Failed to start the terminal UI ({})", e));
+            return;
+        }
+    };
+    let result = browser.run(&mut terminal);
+    ratatui::restore();
+
+    match result {
+        Ok(true) => {
+            let (files, size) = browser.tree.selected();
+            printcoln(Color::Green, format!("Restoring {} files ({})", files, HumanBytes(size)));
+            let result = DownloadOptions::from_args(args)
+                .and_then(|options| download::start(config, &DownloadOptions { patterns: browser.tree.patterns(), ..options }));
+            if let Err(e) = result {
+                printcoln(Color::Red, e);
+            }
+        },
+        Ok(false) => (),
+        Err(e) => printcoln(Color::Red, format!("Terminal UI failed ({})", e)),
+    }
+}
+
+impl Browser {
+    fn new(tree: FileTree) -> Self {
+        let mut browser = Browser {
+            tree,
+            dir: ROOT,
+            listed: vec![],
+            state: ListState::default(),
+            filter: String::new(),
+            typing: false,
+            confirming: false,
+        };
+        browser.open(ROOT);
+        browser
+    }
+
+    // Shows until the user quits or confirms a restore, returning whether to restore
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> std::io::Result<bool> {
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+            if let Event::Key(key) = event::read()? {
+                if key.kind != KeyEventKind::Press {
+                    continue;
+                }
+                match self.handle(key.code, terminal.size()?.height as usize) {
+                    Action::Continue => (),
+                    Action::Quit => return Ok(false),
+                    Action::Restore => return Ok(true),
+                }
+            }
+        }
+    }
+
+    fn handle(&mut self, key: KeyCode, height: usize) -> Action {
+        if self.confirming {
+            match key {
+                KeyCode::Char('y') | KeyCode::Char('Y') => return Action::Restore,
+                _ => self.confirming = false,
+            }
+            return Action::Continue;
+        }
+        if self.typing {
+            match key {
+                KeyCode::Char(c) => self.filter.push(c),
+                KeyCode::Backspace => {
+                    self.filter.pop();
+                },
+                KeyCode::Esc => {
+                    self.filter.clear();
+                    self.typing = false;
+                },
+                KeyCode::Enter => self.typing = false,
+                _ => return self.handle_navigation(key, height),
+            }
+            self.relist();
+            return Action::Continue;
+        }
+        match key {
+            KeyCode::Char('q') => return Action::Quit,
+            KeyCode::Esc if self.filter.is_empty() => return Action::Quit,
+            KeyCode::Esc => {
+                self.filter.clear();
+                self.relist();
+            },
+            KeyCode::Char('/') => self.typing = true,
+            KeyCode::Char(' ') => {
+                if let Some(node) = self.selected() {
+                    let marked = !self.tree.nodes[node].marked;
+                    self.tree.set_marked(node, marked);
+                    self.state.select_next();
+                }
+            },
+            KeyCode::Char('a') => {
+                // Marks or unmarks everything listed
+                let marked = !self.listed.iter().all(|n| self.tree.nodes[*n].marked);
+                for node in self.listed.clone() {
+                    self.tree.set_marked(node, marked);
+                }
+            },
+            KeyCode::Char('r') if !self.tree.selection().is_empty() => self.confirming = true,
+            _ => return self.handle_navigation(key, height),
+        }
+        Action::Continue
+    }
+
+    fn handle_navigation(&mut self, key: KeyCode, height: usize) -> Action {
+        // The list is all but the header and footer
+        let page = height.saturating_sub(3).max(1) as u16;
+        match key {
+            KeyCode::Up | KeyCode::Char('k') => self.state.select_previous(),
+            KeyCode::Down | KeyCode::Char('j') => self.state.select_next(),
+            KeyCode::PageUp => self.state.scroll_up_by(page),
+            KeyCode::PageDown => self.state.scroll_down_by(page),
+            KeyCode::Home | KeyCode::Char('g') => self.state.select_first(),
+            KeyCode::End | KeyCode::Char('G') => self.state.select_last(),
+            KeyCode::Enter | KeyCode::Right | KeyCode::Char('l') => {
+                if let Some(node) = self.selected().filter(|n| self.tree.is_dir(*n)) {
+                    self.open(node);
+                }
+            },
+            KeyCode::Backspace | KeyCode::Left | KeyCode::Char('h') => {
+                if let Some(parent) = self.tree.nodes[self.dir].parent {
+                    let from = self.dir;
+                    self.open(parent);
+                    self.state.select(self.listed.iter().position(|n| *n == from));
+                }
+            },
+            _ => (),
+        }
+        Action::Continue
+    }
+
+    fn selected(&self) -> Option<usize> {
+        self.state.selected().and_then(|i| self.listed.get(i).copied())
+    }
+
+    // Shows the directory 'node', clearing the filter
+    fn open(&mut self, node: usize) {
+        self.dir = node;
+        self.filter.clear();
+        self.typing = false;
+        self.relist();
+    }
+
+    fn relist(&mut self) {
+        let filter = self.filter.to_lowercase();
+        let tree = &self.tree;
+        self.listed = tree.nodes[self.dir].children.iter().copied()
+            .filter(|n| tree.nodes[*n].name.to_lowercase().contains(&filter))
+            .collect();
+        self.state.select(if self.listed.is_empty() { None } else { Some(0) });
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [header, list, footer] = Layout::vertical([Constraint::Length(1), Constraint::Min(1), Constraint::Length(2)])
+            .areas(frame.area());
+
+        let path = match self.dir {
+            ROOT => String::new(),
+            dir => format!("{}/", self.tree.path(dir)),
+        };
+        let dir = &self.tree.nodes[self.dir];
+        let title = format!(" {}  ({} files, {})", path, dir.files, HumanBytes(dir.size));
+        frame.render_widget(Paragraph::new(title).bold(), header);
+
+        let width = list.width as usize;
+        let items: Vec<ListItem> = self.listed.iter().map(|n| {
+            let node = &self.tree.nodes[*n];
+            let mark = if node.marked { Span::styled("[x] ", Style::new().fg(TuiColor::Green)) } else { Span::raw("[ ] ") };
+            let (name, details) = match self.tree.is_dir(*n) {
+                true => (Span::styled(format!("{}/", node.name), Style::new().fg(TuiColor::Blue).bold()),
+                         format!("{} files  {:>10}", node.files, HumanBytes(node.size).to_string())),
+                false => (Span::raw(node.name.clone()), format!("{:>10}", HumanBytes(node.size).to_string())),
+            };
+            let padding = width.saturating_sub(4 + name.width() + details.len() + 2);
+            ListItem::new(Line::from(vec![mark, name, Span::raw(" ".repeat(padding)), Span::raw(details)]))
+        }).collect();
+        let list_widget = List::new(items).highlight_style(Style::new().reversed()).highlight_symbol("> ");
+        frame.render_stateful_widget(list_widget, list, &mut self.state);
+
+        let (files, size) = self.tree.selected();
+        let status = match (self.confirming, self.typing, self.filter.is_empty()) {
+            (true, _, _) => Line::from(format!("Restore {} files ({})? Only those missing or older locally are downloaded [y/N]", files, HumanBytes(size)))
+                .fg(TuiColor::Yellow),
+            (false, true, _) => Line::from(format!("Filter: {}_", self.filter)),
+            (false, false, false) => Line::from(format!("Filter: {} (Esc to clear)", self.filter)),
+            (false, false, true) => Line::from(format!("{} files ({}) marked", files, HumanBytes(size))),
+        };
+        let help = Line::from("Space mark  a mark all  Enter open  Backspace up  / filter  r restore  q quit").dim();
+        frame.render_widget(Paragraph::new(vec![status, help]), footer);
+    }
+}
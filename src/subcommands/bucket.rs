@@ -0,0 +1,123 @@
+use crate::config::Config;
+use clap::ArgMatches;
+use crate::colorutil::printcoln;
+use termcolor::Color;
+use reqwest::blocking::Client;
+use raze::api::B2Auth;
+use std::time::Duration;
+use crate::budget::Budget;
+use crate::b2::{self, Bucket};
+use crate::lifecycle;
+
+/// Shows and changes settings of the bucket
+/// 'bucket lifecycle' shows the lifecycle rules, and sets how long hidden versions are kept to match 'clean'
+pub fn bucket(config: &Config, args: Option<&ArgMatches>) {
+    match args.map(|a| a.subcommand()) {
+        Some(("lifecycle", Some(lifecycle_args))) => lifecycle(config, lifecycle_args),
+        _ => printcoln(Color::White, "Nothing to do, see 'bucket -h'"),
+    }
+}
+
+fn lifecycle(config: &Config, args: &ArgMatches) {
+    match config.is_configured() {
+        Ok(_) => (),
+        Err(err) => {
+            printcoln(Color::Red, format!("Invalid config ({})", err));
+            return;
+        }
+    }
+    if config.simulate.is_some() || config.s3.is_some() || config.local_dir.is_some() {
+        printcoln(Color::Red, "Lifecycle rules need the native B2 API, they can't be set on a simulation, S3-compatible storage or a local directory");
+        return;
+    }
+
+    let minimum = lifecycle::minimum_days(config.retention);
+    let recommended = lifecycle::recommended_days(config.retention);
+    // None if the rule is to be shown, Some(None) if it is to be removed
+    let change = if args.is_present("remove") {
+        Some(None)
+    } else if args.is_present("recommended") {
+        Some(Some(recommended))
+    } else {
+        // Validated by clap
+        args.value_of("days").map(|d| Some(d.parse::<u32>().unwrap()))
+    };
+    if let Some(Some(days)) = change {
+        if days < minimum && !args.is_present("force") {
+            printcoln(Color::Red, format!("The retention policy ({}) keeps old versions for up to {} days, deleting them after {} days would undo it",
+                                          config.retention.unwrap(), minimum, days));
+            printcoln(Color::Yellow, "Use --force to set it anyway, or --recommended to match the retention policy");
+            return;
+        }
+    }
+
+    let budget = Budget::new(config);
+    let client = reqwest::blocking::Client::builder().timeout(Duration::from_secs(60)).build().unwrap();
+    let (auth, bucket) = match connect(&client, &budget, config) {
+        Ok(v) => v,
+        Err(e) => {
+            printcoln(Color::Red, e);
+            return;
+        }
+    };
+
+    let bucket = match change {
+        Some(days) => {
+            let rules = lifecycle::with_hidden_days(bucket.lifecycle_rules, days);
+            if let Err(e) = budget.spend("b2_update_bucket") {
+                printcoln(Color::Red, format!("Aborting: {}", e));
+                return;
+            }
+            match b2::b2_update_bucket(&client, &auth, &bucket.bucket_id, &rules) {
+                Ok(b) => {
+                    match days {
+                        Some(days) => printcoln(Color::Green, format!("Hidden versions in {} are now deleted after {} days", b.bucket_name, days)),
+                        None => printcoln(Color::Green, format!("Hidden versions in {} are now kept until 'clean' deletes them", b.bucket_name)),
+                    }
+                    b
+                },
+                Err(e) => {
+                    printcoln(Color::Red, format!("Failed to update the lifecycle rules of {} ({})", bucket.bucket_name, e));
+                    return;
+                }
+            }
+        },
+        None => bucket,
+    };
+
+    if bucket.lifecycle_rules.is_empty() {
+        printcoln(Color::White, format!("{} has no lifecycle rules, hidden versions are kept until 'clean' deletes them", bucket.bucket_name));
+    }
+    for rule in &bucket.lifecycle_rules {
+        println!("{}", lifecycle::describe(rule));
+    }
+    let current = lifecycle::bucket_rule(&bucket.lifecycle_rules).and_then(|r| r.days_from_hiding_to_deleting);
+    match (current, config.retention) {
+        (Some(days), Some(retention)) if days < minimum => {
+            printcoln(Color::Red, format!("Hidden versions are deleted after {} days, but the retention policy ({}) keeps them for up to {} days",
+                                          days, retention, minimum));
+            printcoln(Color::Yellow, "Run 'bucket lifecycle --recommended' to match it");
+        },
+        (Some(_), _) => (),
+        (None, Some(retention)) => printcoln(Color::Yellow, format!("Recommended for the retention policy ({}): delete hidden versions after {} days, with --recommended",
+                                                                    retention, recommended)),
+        (None, None) => printcoln(Color::Yellow, format!("Recommended: delete hidden versions after {} days, with --recommended", recommended)),
+    }
+}
+
+// Authenticates and looks up the configured bucket, with its lifecycle rules
+fn connect(client: &Client, budget: &Budget, config: &Config) -> Result<(B2Auth, Bucket), String> {
+    let keystring = config.keystring()?;
+    budget.spend("b2_authorize_account").map_err(|e| format!("Aborting: {}", e))?;
+    let auth = raze::api::b2_authorize_account(client, keystring)
+        .map_err(|_| "Authentication failure".to_string())?;
+
+    let bucket_name = config.bucket_name.as_ref().unwrap();
+    budget.spend("b2_list_buckets").map_err(|e| format!("Aborting: {}", e))?;
+    let buckets = b2::b2_list_buckets(client, &auth, bucket_name)
+        .map_err(|e| format!("Failed to retrieve bucket list ({})", e))?;
+    match buckets.into_iter().next() {
+        Some(bucket) => Ok((auth, bucket)),
+        None => Err(format!("No bucket with the name '{}'", bucket_name)),
+    }
+}
@@ -1,24 +1,49 @@
-use crate::config::Config;
+use crate::config::{Config, DEFAULT_THREADS};
 use clap::ArgMatches;
-use crate::colorutil::printcoln;
+use crate::colorutil::{printcoln, printsummary};
+use crate::summary::Summary;
 use termcolor::Color;
 use crate::filelist;
-use chacha20poly1305::Key;
-use raze::api::{ListBucketParams, Sha1Variant};
-use std::time::{Duration, UNIX_EPOCH};
+use std::time::UNIX_EPOCH;
 use std::fs::metadata;
 use std::path::Path;
-use crate::encryption::{get_encrypted_size, get_nonces_required};
-use crate::encryption::reader::EncryptingReader;
+use crate::retention::Retention;
+use crate::encryption::get_nonces_required;
+use crate::encryption::keys::EncryptionKey;
+use crate::encryption::cipher::Cipher;
+use crate::subcommands::backup::download;
+use scoped_pool::Pool;
+use std::sync::{Arc, Mutex};
+use crate::retry;
+use crate::manifest;
+use crate::budget::Budget;
+use crate::backend::{self, Backend};
+use crate::capabilities::{self, Operation};
+use crate::datetime;
 
 // Ensures the local manifest matches the files present in remote
 // Cleans up all files in remote that can't be found in the backup-list
 pub fn clean(config: &mut Config, args: Option<&ArgMatches>) {
     let t_start = std::time::Instant::now();
+    let summary = Summary::new("clean", "hidden or deleted", config);
     let args = args.unwrap();
     let mode = args.value_of("mode").unwrap(); // Can't fail: enforced by clap
+    let threads = match args.value_of("threads") {
+        Some(s) => s.parse::<usize>().unwrap(), // Validated by clap
+        None => config.clean_threads.unwrap_or(DEFAULT_THREADS),
+    };
+    // When doing a dry run, only report what would happen
+    // Nothing is hidden/deleted in remote and manifest.json is left untouched
+    let dry_run = args.is_present("dryrun");
+    // Files missing locally for less than this (ms) keep their backup, validated by clap
+    let grace = args.value_of("olderthan").map(|s| datetime::parse_age(s).unwrap());
+    let max_attempts = config.max_attempts.unwrap_or(retry::DEFAULT_MAX_ATTEMPTS);
+    let budget = Arc::new(Budget::new(config));
 
     printcoln(Color::Yellow, "Starting cleanup");
+    if dry_run {
+        printcoln(Color::Yellow, "Dry run: no changes will be made");
+    }
 
     // Start doing all the preparation work necessary
     // This will authenticate, resolve bucket name, get the encryption settings
@@ -46,12 +71,13 @@ pub fn clean(config: &mut Config, args: Option<&ArgMatches>) {
     match config.encrypt.unwrap() {
         true => {
             printcoln(Color::Green, "Encryption is enabled");
-            match std::fs::read(config.secret_key.as_ref().unwrap()) {
-                Ok(bytes) => {
-                    key = Some(Key::clone_from_slice(&bytes));
+            // With a public key configured, files are encrypted for it
+            match EncryptionKey::from_config(config) {
+                Ok(k) => {
+                    key = Some(k);
                 }
                 Err(err) => {
-                    printcoln(Color::Red, format!("[{:.3}] Failed to open key-file {:?}", t_start.elapsed().as_secs_f32(), err));
+                    printcoln(Color::Red, format!("[{:.3}] {}", t_start.elapsed().as_secs_f32(), err));
                     return;
                 }
             }
@@ -63,7 +89,7 @@ pub fn clean(config: &mut Config, args: Option<&ArgMatches>) {
     }
 
     printcoln(Color::Green, format!("[{:.3}] Loading local file manifest", t_start.elapsed().as_secs_f32()));
-    let mut manifest = match crate::manifest::FileManifest::from_file("manifest.json") {
+    let mut manifest = match manifest::FileManifest::from_file(manifest::local_path()) {
         Ok(fm) => fm,
         Err(err) => {
             printcoln(Color::Red, format!("[{:.3}] Failed to load file manifest ({})", t_start.elapsed().as_secs_f32(), err));
@@ -73,91 +99,78 @@ pub fn clean(config: &mut Config, args: Option<&ArgMatches>) {
             return;
         }
     };
+    // An older copy of the config would reuse nonces
+    if key.is_some() {
+        if let Err(e) = config.check_nonces(manifest.nonces) {
+            printcoln(Color::Red, format!("[{:.3}] {}", t_start.elapsed().as_secs_f32(), e));
+            return;
+        }
+    }
     printcoln(Color::Green, format!("[{:.3}] Loaded manifest", t_start.elapsed().as_secs_f32()));
 
     printcoln(Color::Green, format!("[{:.3}] Building list of files...", t_start.elapsed().as_secs_f32()));
     let filelist = filelist::build_file_list(config.backup_list.as_ref().unwrap());
     printcoln(Color::Green, format!("[{:.3}] Complete ({} files)", t_start.elapsed().as_secs_f32(), filelist.len()));
 
-    let client = reqwest::blocking::Client::builder().timeout(Duration::from_secs(60)).build().unwrap();
-    printcoln(Color::Green, format!("[{:.3}] Authenticating...", t_start.elapsed().as_secs_f32()));
-
-    let keystring = format!("{}:{}", config.app_key_id.as_ref().unwrap(), config.app_key.as_ref().unwrap());
-    let auth = match raze::api::b2_authorize_account(&client,keystring) {
-        Ok(a) => a,
-        Err(_e) => {
-            printcoln(Color::Red, format!("[{:.3}] Authentication failure", t_start.elapsed().as_secs_f32()));
-            return;
-        },
-    };
-    printcoln(Color::Green, format!("[{:.3}] Success", t_start.elapsed().as_secs_f32()));
-    printcoln(Color::Green, format!("[{:.3}] Resolving bucket name", t_start.elapsed().as_secs_f32()));
-
-    // Note that since we supply a bucket name and names are unique, we should get 0 or 1 results
-    let params = ListBucketParams {
-        bucket_id: None,
-        bucket_name: Some(config.bucket_name.as_ref().unwrap().to_string()),
-        bucket_types: None
-    };
-    let buckets = match raze::api::b2_list_buckets(&client, &auth, params) {
-        Ok(buckets) => buckets,
-        Err(err) => {
-            printcoln(Color::Red, format!("[{:.3}] Failed to retrieve bucket list", t_start.elapsed().as_secs_f32()));
-            printcoln(Color::Red, format!("[{:.3}] Reason: {:?}", t_start.elapsed().as_secs_f32(), err));
+    let backend = match backend::connect(config, budget.clone(), t_start) {
+        Ok(b) => b,
+        Err(e) => {
+            printcoln(Color::Red, format!("[{:.3}] {}", t_start.elapsed().as_secs_f32(), e));
             return;
         }
     };
-
-    let bucket_name = config.bucket_name.as_ref().unwrap();
-    let bucket_id = match buckets.get(0) {
-        Some(res) => &res.bucket_id,
-        None => {
-            printcoln(Color::Red, format!("[{:.3}] No bucket with the name '{}'", t_start.elapsed().as_secs_f32(), bucket_name));
+    // A dry run only lists, what would fail is reported all the same
+    let delete = mode.eq_ignore_ascii_case("delete");
+    let access = backend.check_access(Operation::Clean { delete, retention: config.retention.is_some() });
+    if !capabilities::report(&access, t_start) && !dry_run {
+        return;
+    }
+    if key.is_some() && !dry_run {
+        if let Err(e) = download::check_remote_nonces(config, backend.as_ref(), &budget, t_start) {
+            printcoln(Color::Red, format!("[{:.3}] {}", t_start.elapsed().as_secs_f32(), e));
             return;
         }
-    };
-    printcoln(Color::Green, format!("[{:.3}] {} -> {}", t_start.elapsed().as_secs_f32(), bucket_name, bucket_id));
+    }
 
-    let do_encrypt = config.encrypt.unwrap();
     // Prep work done
 
     // First, we need to retrieve the list of files on remote
     printcoln(Color::Yellow, format!("[{:.3}] Retrieving list of remote files, this may take a while...",  t_start.elapsed().as_secs_f32()));
-    let mut remote_files = match raze::util::list_all_files(&client, &auth, bucket_id, 10000) {
+    let mut remote_files = match backend.list() {
         Ok(f) => f,
         Err(e) => {
-            printcoln(Color::Red, format!("[{:.3}] Failed to retrieve file list ({:?})", t_start.elapsed().as_secs_f32(), e));
+            printcoln(Color::Red, format!("[{:.3}] Failed to retrieve file list ({})", t_start.elapsed().as_secs_f32(), e));
             return;
         },
     };
 
+    // With a retention policy, old versions it doesn't keep are deleted regardless of mode
+    let mut expired = Vec::new();
+    if let Some(retention) = config.retention {
+        printcoln(Color::Yellow, format!("[{:.3}] Retention policy: {}, retrieving list of file versions...", t_start.elapsed().as_secs_f32(), retention));
+        match backend.versions() {
+            // Versions of the manifest are pruned separately, see below
+            Ok(v) => expired = retention.expired(v.into_iter().filter(|v| v.file_name != manifest::REMOTE_MANIFEST).collect()),
+            Err(e) => {
+                printcoln(Color::Red, format!("[{:.3}] Failed to retrieve file versions ({})", t_start.elapsed().as_secs_f32(), e));
+                return;
+            }
+        }
+        printcoln(Color::Green, format!("[{:.3}] {} old versions are not kept", t_start.elapsed().as_secs_f32(), expired.len()));
+    }
+
     // Check if the remote manifest.json is newer than the local one
     // If it is, abort
     // This is skipped if the --force flag is applied
     if args.is_present("force") {
-        let modified_local = match metadata("manifest.json").unwrap().modified().unwrap().duration_since(UNIX_EPOCH) {
+        let modified_local = match metadata(manifest::local_path()).unwrap().modified().unwrap().duration_since(UNIX_EPOCH) {
             Ok(v) => v.as_secs() * 1000,
             Err(e) => 0,
         };
 
-        let sf = raze::api::B2FileInfo {
-            file_name: "manifest.json".to_string(),
-            file_id: None,
-            account_id: "".to_string(),
-            bucket_id: "".to_string(),
-            content_length: 0,
-            content_sha1: None,
-            content_type: None,
-            action: "".to_owned(),
-            upload_timestamp: 0,
-            file_info: None
-        };
-        match remote_files.binary_search(&sf) {
+        match remote_files.binary_search_by(|f| f.name.as_str().cmp(manifest::REMOTE_MANIFEST)) {
             Ok(idx) => {
-                if remote_files[idx].file_info.as_ref().expect("Remote manifest.json missing file-info")
-                    .get("src_last_modified_millis")
-                    .expect("Remote manifest.json missing modified time")
-                    .parse::<u64>().unwrap() > modified_local {
+                if remote_files[idx].modified.expect("Remote manifest.json missing modified time") > modified_local {
                     printcoln(Color::Red, format!("[{:.3}] Error: remote manifest is more recent than local", t_start.elapsed().as_secs_f32()));
                     printcoln(Color::Red, format!("[{:.3}] This is likely caused by either:", t_start.elapsed().as_secs_f32()));
                     printcoln(Color::Red, format!("[{:.3}] 1. More recent backup from a different setup/location", t_start.elapsed().as_secs_f32()));
@@ -170,7 +183,7 @@ pub fn clean(config: &mut Config, args: Option<&ArgMatches>) {
             Err(_) => {
                 printcoln(Color::Red, format!("[{:.3}] Failed to find manifest.json", t_start.elapsed().as_secs_f32()));
                 printcoln(Color::Red, format!("[{:.3}] Ensure you have backed up at least once before", t_start.elapsed().as_secs_f32()));
-                printcoln(Color::Red, format!("[{:.3}] Ensure the bucket name is correct (Currently: {})", t_start.elapsed().as_secs_f32(), bucket_name));
+                printcoln(Color::Red, format!("[{:.3}] Ensure the bucket is correct (Currently: {})", t_start.elapsed().as_secs_f32(), backend.describe()));
                 return;
             }
         }
@@ -179,93 +192,200 @@ pub fn clean(config: &mut Config, args: Option<&ArgMatches>) {
     // Done checking (or skipped) manifest.json recency check
     // Now, remove all entries in manifest that cannot be found locally
     // That is, check if the file each entry points to is still present, remove it if it doesn't
+    // With a grace period, an entry is only removed once its file has been missing for that long
     // TODO: Replace with 'manifest.files.drain_filter(|e| !Path::new(&e.path).exists())' when stable
+    let now = std::time::SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64);
+    let mut in_grace = 0;
     let mut i = 0;
     while i != manifest.files.len() {
-        if !Path::new(&manifest.files[i].path).exists() {
-            manifest.files.remove(i);
-        } else {
+        let entry = &mut manifest.files[i];
+        if Path::new(&entry.path).exists() {
+            entry.missing_since = 0;
             i += 1;
+            continue;
+        }
+        if let Some(grace) = grace {
+            if entry.missing_since == 0 {
+                entry.missing_since = now;
+            }
+            if now.saturating_sub(entry.missing_since) < grace {
+                if dry_run {
+                    printcoln(Color::White, format!("Would keep manifest entry {} (missing since {})", &entry.path, datetime::format_millis(entry.missing_since)));
+                }
+                in_grace += 1;
+                i += 1;
+                continue;
+            }
         }
+        if dry_run {
+            printcoln(Color::White, format!("Would drop manifest entry {}", &entry.path));
+        }
+        manifest.retire(i);
+    }
+    if in_grace > 0 {
+        printcoln(Color::Yellow, format!("[{:.3}] {} files are missing locally, their backups are kept until they have been missing for {}",
+                                         t_start.elapsed().as_secs_f32(), in_grace, args.value_of("olderthan").unwrap()));
+    }
+    // Snapshots the retention policy doesn't keep are forgotten, along with the past versions only they had
+    // Without a policy, old versions aren't kept, so neither are the snapshots that need them
+    let expired_snapshots = match config.retention {
+        Some(retention) => retention.expired_snapshots(&manifest.snapshots),
+        None => manifest.snapshots.iter().map(|s| s.generation).collect(),
+    };
+    let snapshot_count = manifest.snapshots.len();
+    let forgotten = manifest.forget_snapshots(&expired_snapshots);
+    if manifest.snapshots.len() < snapshot_count {
+        printcoln(Color::Green, format!("[{:.3}] {} old snapshots are not kept, along with {} past versions of files",
+                                        t_start.elapsed().as_secs_f32(), snapshot_count - manifest.snapshots.len(), forgotten.len()));
+    }
+    // With a policy, the versions that the snapshots it keeps have are kept as well, including the base and deltas they are restored from
+    if config.retention.is_some() {
+        Retention::unreferenced(&mut expired, manifest.files.iter().chain(manifest.past.iter().map(|p| &p.entry)));
     }
-    manifest.to_file("manifest.json").expect("Failed to save manifest.json");
+    // Kept in the format used in remote for syncing it later
+    let remote_manifest = if dry_run {
+        vec![]
+    } else {
+        manifest.record_nonces(config.nonces_allocated());
+        manifest.save_local().expect("Failed to save manifest")
+    };
 
     // Now that we know all files in the manifest are present, clean up remote:
     // All remote files that we cannot find in our local manifest will be cleaned up
 
     // First, create a sorted list of masks, since remote files are known by their mask
     // This lets us binary search for them
+    // With a retention policy, files that were removed are kept while a snapshot has them
+    let past = match config.retention {
+        Some(_) => manifest.past.into_iter().map(|p| p.entry).collect(),
+        None => vec![],
+    };
     let mut mask_list = Vec::with_capacity(manifest.files.len());
-    for elem in manifest.files {
+    for elem in manifest.files.into_iter().chain(past) {
         mask_list.push(elem.mask);
+        // Deltas of large files are stored next to the full upload
+        mask_list.extend(elem.deltas);
     }
     mask_list.sort();
     // Second, check if each remote file still exists
     // We remove manifest.json from the remote file list to make sure we don't remove it
-    let sf = raze::api::B2FileInfo {
-        file_name: "manifest.json".to_string(),
-        file_id: None,
-        account_id: "".to_string(),
-        bucket_id: "".to_string(),
-        content_length: 0,
-        content_sha1: None,
-        content_type: None,
-        action: "".to_owned(),
-        upload_timestamp: 0,
-        file_info: None
-    };
-    if let Ok(idx) = remote_files.binary_search(&sf) {
+    if let Ok(idx) = remote_files.binary_search_by(|f| f.name.as_str().cmp(manifest::REMOTE_MANIFEST)) {
         remote_files.remove(idx);
     };
     // Start checking
-    for elem in remote_files {
-        if let Err(n) = mask_list.binary_search(&elem.file_name) {
-            match mode {
-                "hide" => {
-                    printcoln(Color::White, format!("Hiding {}", &elem.file_name));
-                    raze::api::b2_hide_file(&client, &auth, bucket_id, elem.file_name);
-                },
-                "delete" => {
-                    printcoln(Color::White, format!("Deleting {}", &elem.file_name));
-                    raze::api::b2_delete_file_version(&client, &auth, elem.file_name, elem.file_id.unwrap());
-                }
-                _ => unreachable!()
-            }
+    // Every remote file not found in the mask list is queued up and removed by the worker threads
+    let remote_count = remote_files.len();
+    remote_files.retain(|elem| mask_list.binary_search(&elem.name).is_err());
+    summary.scanned(remote_count);
+    summary.unchanged(remote_count - remote_files.len());
+    if dry_run {
+        for elem in &remote_files {
+            printcoln(Color::White, format!("Would {} {}", mode, &elem.name));
         }
+        for version in &expired {
+            printcoln(Color::White, format!("Would delete old version of {} ({})", &version.file_name, datetime::format_millis(version.upload_timestamp)));
+        }
+        printsummary(Color::Green, format!("[{:.3}] Dry run finished, {} remote files would be {}, {} old versions would be deleted", t_start.elapsed().as_secs_f32(),
+                                        remote_files.len(), if mode == "hide" { "hidden" } else { "deleted" }, expired.len()));
+        printsummary(Color::Green, format!("[{:.3}] API calls: {}", t_start.elapsed().as_secs_f32(), budget.summary()));
+        summary.finish(None);
+        return;
     }
+    // Each entry is (file name, file id, "hide" or "delete")
+    let mut removals: Vec<(String, Option<String>, &str)> = remote_files.into_iter()
+        .map(|elem| (elem.name, elem.id, mode))
+        .collect();
+    removals.extend(expired.into_iter().map(|version| (version.file_name, Some(version.file_id), "delete")));
+    let removal_count = removals.len();
+    remove(backend.as_ref(), removals, threads, max_attempts, &summary);
 
     printcoln(Color::Green, format!("[{:.3}] Syncing manifest...", t_start.elapsed().as_secs_f32()));
-    // Note: manifest.json already saved to disk at this point
-    let filesize = std::fs::metadata("manifest.json").unwrap().len();
-    let file = std::fs::File::open("manifest.json").unwrap();
-
-    let params = raze::api::FileParameters {
-        file_path: "manifest.json", // NEVER mask so we can find it anytime
-        file_size: if do_encrypt { get_encrypted_size(filesize) } else { filesize },
-        content_type: None, // auto
-        content_sha1: Sha1Variant::HexAtEnd,
-        last_modified_millis: 0,
-    };
+    // Note: manifest already saved to disk at this point
+    let filesize = remote_manifest.len() as u64;
+    let file = std::io::Cursor::new(remote_manifest);
 
-    let upauth = raze::api::b2_get_upload_url(&client, &auth, bucket_id).expect("Failed to get upload auth");
-
-    let file = if do_encrypt {
+    // NEVER mask so we can find it anytime
+    let result = if let Some(key) = key {
+        let block = config.block_size.unwrap_or_default().fitting(filesize);
         let (start_nonce,allocated) = {
-            let req = get_nonces_required(filesize);
+            let req = get_nonces_required(filesize, block);
             let start = config.consume_nonces(req);
             (start, req)
         };
-        let file = raze::util::ReadHashAtEnd::wrap(
-            EncryptingReader::wrap(file,
-                                   &key.unwrap(),
-                                   start_nonce,
-                                   allocated));
-        raze::api::b2_upload_file(&client, &upauth, file, params)
+        let file = key.wrap(file,
+                            config.cipher.unwrap_or(Cipher::XChaCha20Poly1305),
+                            block,
+                            start_nonce,
+                            allocated);
+        backend.upload(manifest::REMOTE_MANIFEST, Box::new(file), key.encrypted_size(filesize, block), 0)
     } else {
-        let file = raze::util::ReadHashAtEnd::wrap(file);
-        raze::api::b2_upload_file(&client, &upauth, file, params)
+        backend.upload(manifest::REMOTE_MANIFEST, Box::new(file), filesize, 0)
     };
+    // Only prune old versions of the manifest once the new one is safely uploaded
+    match result {
+        Ok(_) => {
+            let keep = config.manifest_history.unwrap_or(manifest::DEFAULT_MANIFEST_HISTORY);
+            match backend.prune_manifest_history(manifest::REMOTE_MANIFEST, keep) {
+                Ok(n) => printcoln(Color::Green, format!("[{:.3}] Pruned {} old versions of the manifest", t_start.elapsed().as_secs_f32(), n)),
+                Err(e) => printcoln(Color::Red, format!("[{:.3}] Failed to prune old versions of the manifest ({})", t_start.elapsed().as_secs_f32(), e)),
+            }
+        },
+        Err(e) => {
+            printcoln(Color::Red, format!("[{:.3}] Failed to sync manifest ({})", t_start.elapsed().as_secs_f32(), e));
+            summary.fail(manifest::REMOTE_MANIFEST);
+        },
+    }
 
-    printcoln(Color::Green, format!("[{:.3}] Cleanup finished", t_start.elapsed().as_secs_f32()));
+    printsummary(Color::Green, format!("[{:.3}] API calls: {}", t_start.elapsed().as_secs_f32(), budget.summary()));
+    printsummary(Color::Green, format!("[{:.3}] Cleanup finished ({} files hidden or deleted)", t_start.elapsed().as_secs_f32(), removal_count));
+    summary.finish(None);
 
-}
\ No newline at end of file
+}
+
+/// Hides or deletes each of 'removals' (file name, file id, "hide" or "delete") using 'threads' threads
+/// Each removal is retried up to 'max_attempts' times and recorded in 'summary'
+pub fn remove(backend: &dyn Backend, removals: Vec<(String, Option<String>, &str)>, threads: usize, max_attempts: u32, summary: &Summary) {
+    let queue = Mutex::new(removals);
+    let pool = Pool::new(threads);
+    pool.scoped(|scope| {
+        for _ in 0..pool.workers() {
+            let queue = &queue;
+            scope.execute(move || {
+                loop {
+                    let (file_name, file_id, action) = match queue.lock().unwrap().pop() {
+                        Some(e) => e,
+                        None => break,
+                    };
+                    match action {
+                        "hide" => printcoln(Color::White, format!("Hiding {}", &file_name)),
+                        "delete" => printcoln(Color::White, format!("Deleting {}", &file_name)),
+                        _ => unreachable!()
+                    }
+                    let mut ok = false;
+                    for attempt in 0..max_attempts {
+                        retry::hold_off();
+                        let result = match action {
+                            "hide" => backend.hide(&file_name),
+                            "delete" => backend.delete(&file_name, file_id.as_deref()),
+                            _ => unreachable!()
+                        };
+                        match result {
+                            Ok(_) => {
+                                ok = true;
+                                break;
+                            },
+                            Err(e) => {
+                                printcoln(Color::Red, format!("Failed to {} {} ({})", action, &file_name, e));
+                                if !e.retryable || attempt == max_attempts-1 {
+                                    break;
+                                }
+                                std::thread::sleep(retry::delay_for(attempt, e.retry_after));
+                            }
+                        }
+                    }
+                    summary.record(&file_name, ok);
+                }
+            });
+        }
+    });
+}
@@ -1,35 +1,175 @@
-use crate::config::{Config};
+use crate::config::{Config, Format};
 use clap::ArgMatches;
 use std::str::FromStr;
 use crate::colorutil::printcoln;
 use termcolor::Color;
+use crate::throttle;
+use indicatif::HumanBytes;
+use crate::budget::LimitAction;
+use crate::retention::Retention;
+use crate::manifest;
+use crate::secrets;
+use crate::encryption::{NonceMode, BlockSize};
+use crate::encryption::cipher::Cipher;
+use crate::credentials::{self, Protection};
+use crate::logging::LogRotation;
+use crate::notify::{self, Notifications, NotifyOn};
+use crate::shadow::SnapshotHook;
+use crate::hooks::Hook;
+use crate::jobs::{self, Job};
 
 /// Updates the configuration according to the provided args
 pub fn configure(config: &mut Config, args: Option<&ArgMatches>) {
     if args.is_none() {
-        println!("Nothing to configure");
+        printcoln(Color::White, "Nothing to configure");
         return;
     }
     let args = args.unwrap();
 
+    if let Some(migrate_args) = args.subcommand_matches("migrate") {
+        migrate(config, migrate_args);
+        return;
+    }
+
+    if args.subcommand_matches("edit").is_some() {
+        super::edit::edit(config);
+        return;
+    }
+
+    if args.subcommand_matches("validate").is_some() {
+        super::validate::validate(config);
+        return;
+    }
+
     if let Some(s) = args.value_of("appkeyid") {
         config.app_key_id = Some(s.to_string());
-        println!("Set App Key ID: {}", s);
+        printcoln(Color::White, format!("Set App Key ID: {}", s));
     }
 
     if let Some(s) = args.value_of("appkey") {
         config.app_key = Some(s.to_string());
-        println!("Set App Key: {}", s);
+        // The key itself isn't printed, s.t. it doesn't end up in the log
+        printcoln(Color::White, "Set App Key");
+    }
+
+    if let Some(s) = args.value_of("noncemode") {
+        config.nonce_mode = Some(if s.eq_ignore_ascii_case("random") { NonceMode::Random } else { NonceMode::Counter });
+        printcoln(Color::White, format!("Set Nonce Mode: {}", s.to_lowercase()));
+    }
+
+    // Needed when the config is an older copy than the one that last saved the manifest, see Config::check_nonces
+    if args.is_present("advancenonces") {
+        match manifest::FileManifest::from_file(manifest::local_path()) {
+            Ok(fm) if fm.nonces > config.nonces_allocated() => {
+                config.advance_nonces(fm.nonces);
+                printcoln(Color::White, format!("Set Nonce Counter: {}", fm.nonces));
+            },
+            Ok(_) => printcoln(Color::White, format!("The nonce counter ({}) is not behind the manifest", config.nonces_allocated())),
+            Err(e) => printcoln(Color::Red, format!("Failed to load the local manifest ({})", e)),
+        }
+    }
+
+    // Files that are already backed up keep their cipher until they are uploaded again
+    if let Some(s) = args.value_of("cipher") {
+        let cipher = if s.eq_ignore_ascii_case("aes256gcm") { Cipher::Aes256Gcm } else { Cipher::XChaCha20Poly1305 };
+        config.cipher = Some(cipher);
+        printcoln(Color::White, format!("Set Cipher: {}", cipher));
+    }
+
+    // Files record their block size, so it can be changed at any time
+    if let Some(s) = args.value_of("blocksize") {
+        let block = BlockSize::parse(s).unwrap(); // Validated by clap
+        config.block_size = Some(block);
+        printcoln(Color::White, format!("Set Block Size: {}", HumanBytes(block.block_length() as u64)));
+    }
+
+    // The credentials are encrypted (or decrypted) when the config is saved after this
+    if let Some(s) = args.value_of("protectcredentials") {
+        let protection = match s.to_lowercase().as_str() {
+            "key" => Some(Protection::Key),
+            "machine" => Some(Protection::Machine),
+            _ => None,
+        };
+        // Make sure the key is available, s.t. the credentials aren't saved in plain text unexpectedly
+        match protection.map_or(Ok(String::new()), |p| credentials::seal("", p, config.secret_key.as_deref())) {
+            Ok(_) => {
+                config.protect_credentials = protection;
+                printcoln(Color::White, format!("Set Protect Credentials: {}", s.to_lowercase()));
+            },
+            Err(e) => printcoln(Color::Red, format!("Invalid credential protection: {}", e)),
+        }
+    }
+
+    if args.is_present("appkeykeyring") {
+        match config.app_key.as_ref().filter(|k| secrets::keyring_entry(k).is_none()) {
+            Some(k) => match secrets::store(secrets::APP_KEY_ENTRY, k) {
+                Ok(location) => {
+                    config.app_key = Some(location);
+                    printcoln(Color::White, "Set App Key: stored in the OS keyring");
+                },
+                Err(e) => printcoln(Color::Red, e),
+            },
+            None => printcoln(Color::Red, "No App Key to store in the OS keyring, set one with --app_key"),
+        }
     }
 
     if let Some(s) = args.value_of("bucketname") {
         config.bucket_name = Some(s.to_string());
-        println!("Set Bucket Name: {}", s);
+        printcoln(Color::White, format!("Set Bucket Name: {}", s));
+    }
+
+    // A local directory is used instead of the bucket while set
+    if let Some(s) = args.value_of("localdir") {
+        if s.eq_ignore_ascii_case("off") {
+            config.local_dir = None;
+            printcoln(Color::White, "Set Local Directory: off (using the bucket)");
+        } else if !std::path::Path::new(s).is_dir() {
+            printcoln(Color::Red, format!("{} is not a directory, create it (or mount the drive) first", s));
+        } else {
+            config.local_dir = Some(s.to_string());
+            printcoln(Color::White, format!("Set Local Directory: {}", s));
+        }
+    }
+
+    // Setting an endpoint switches from B2 to S3-compatible storage, the other S3 options need one
+    if let Some(s) = args.value_of("s3endpoint") {
+        if s.eq_ignore_ascii_case("off") {
+            config.s3 = None;
+            printcoln(Color::White, "Set S3 Endpoint: off (using B2)");
+        } else if let Err(e) = reqwest::Url::parse(s) {
+            printcoln(Color::Red, format!("Invalid S3 endpoint '{}' ({}), expected e.g. https://s3.us-west-004.backblazeb2.com", s, e));
+        } else {
+            let mut s3 = config.s3.take().unwrap_or_default();
+            s3.endpoint = s.to_string();
+            config.s3 = Some(s3);
+            printcoln(Color::White, format!("Set S3 Endpoint: {}", s));
+        }
+    }
+
+    if let Some(s) = args.value_of("s3region") {
+        match config.s3.as_mut() {
+            Some(s3) => {
+                s3.region = Some(s.to_string());
+                printcoln(Color::White, format!("Set S3 Region: {}", s));
+            },
+            None => printcoln(Color::Red, "Set an S3 endpoint (--s3_endpoint) before the region"),
+        }
+    }
+
+    // Possible values are enforced by clap
+    if let Some(s) = args.value_of("s3pathstyle") {
+        match config.s3.as_mut() {
+            Some(s3) => {
+                s3.path_style = Some(s.eq_ignore_ascii_case("on"));
+                printcoln(Color::White, format!("Set S3 Path Style: {}", s.to_lowercase()));
+            },
+            None => printcoln(Color::Red, "Set an S3 endpoint (--s3_endpoint) before the path style"),
+        }
     }
 
     if let Some(s) = args.value_of("filelist") {
         config.backup_list = Some(s.to_string());
-        println!("Set File List Path: {}", s);
+        printcoln(Color::White, format!("Set File List Path: {}", s));
         if !std::path::Path::new(s).is_file() {
             printcoln(Color::Red, "Warning: file is either missing or inaccessible")
         }
@@ -37,10 +177,439 @@ pub fn configure(config: &mut Config, args: Option<&ArgMatches>) {
 
     if let Some(s) = args.value_of("secret") {
         config.secret_key = Some(s.to_string());
-        println!("Set Keyfile Path: {}", s);
-        if !std::path::Path::new(s).is_file() {
+        printcoln(Color::White, format!("Set Keyfile Path: {}", s));
+        if !secrets::is_reference(s) && !std::path::Path::new(s).is_file() {
             printcoln(Color::Red, "Warning: keyfile is either missing or inaccessible")
         }
     }
 
-}
\ No newline at end of file
+    if let Some(s) = args.value_of("retiredkeys") {
+        if s.eq_ignore_ascii_case("none") {
+            config.retired_keys = None;
+            printcoln(Color::White, "Set Retired Keys: none");
+        } else {
+            let paths: Vec<String> = s.split(',').map(|p| p.trim().to_string()).filter(|p| !p.is_empty()).collect();
+            printcoln(Color::White, format!("Set Retired Keys: {}", paths.join(", ")));
+            for path in paths.iter().filter(|p| !std::path::Path::new(p).is_file()) {
+                printcoln(Color::Red, format!("Warning: keyfile {} is either missing or inaccessible", path));
+            }
+            config.retired_keys = Some(paths);
+        }
+    }
+
+    if let Some(s) = args.value_of("publickey") {
+        if s.eq_ignore_ascii_case("none") {
+            config.public_key = None;
+            printcoln(Color::White, "Set Public Key: none");
+        } else {
+            config.public_key = Some(s.to_string());
+            printcoln(Color::White, format!("Set Public Key: {}", s));
+            if !std::path::Path::new(s).is_file() {
+                printcoln(Color::Red, "Warning: keyfile is either missing or inaccessible")
+            }
+        }
+    }
+
+    if let Some(s) = args.value_of("privatekey") {
+        if s.eq_ignore_ascii_case("none") {
+            config.private_key = None;
+            printcoln(Color::White, "Set Private Key: none");
+        } else {
+            config.private_key = Some(s.to_string());
+            printcoln(Color::White, format!("Set Private Key: {}", s));
+            if !secrets::is_reference(s) && !std::path::Path::new(s).is_file() {
+                printcoln(Color::Red, "Warning: keyfile is either missing or inaccessible")
+            }
+        }
+    }
+
+    if let Some(s) = args.value_of("uploadlimit") {
+        if s.eq_ignore_ascii_case("off") {
+            config.upload_limit = None;
+            printcoln(Color::White, "Set Upload Limit: off");
+        } else {
+            match throttle::parse_rate(s) {
+                Ok(rate) => {
+                    config.upload_limit = Some(rate);
+                    printcoln(Color::White, format!("Set Upload Limit: {}", throttle::format_rate(rate)));
+                },
+                Err(e) => printcoln(Color::Red, format!("Invalid upload limit: {}", e)),
+            }
+        }
+    }
+
+    // Thread counts are validated by clap
+    if let Some(s) = args.value_of("uploadthreads") {
+        config.upload_threads = Some(usize::from_str(s).unwrap());
+        printcoln(Color::White, format!("Set Upload Threads: {}", s));
+    }
+
+    if let Some(s) = args.value_of("downloadthreads") {
+        config.download_threads = Some(usize::from_str(s).unwrap());
+        printcoln(Color::White, format!("Set Download Threads: {}", s));
+    }
+
+    if let Some(s) = args.value_of("cleanthreads") {
+        config.clean_threads = Some(usize::from_str(s).unwrap());
+        printcoln(Color::White, format!("Set Clean Threads: {}", s));
+    }
+
+    // At least 1 is validated by clap, but it may not fit in a u32
+    if let Some(s) = args.value_of("maxattempts") {
+        match u32::from_str(s) {
+            Ok(n) => {
+                config.max_attempts = Some(n);
+                printcoln(Color::White, format!("Set Max Attempts: {}", s));
+            },
+            Err(_) => printcoln(Color::Red, format!("Invalid max attempts '{}', it must be at most {}", s, u32::MAX)),
+        }
+    }
+
+    if let Some(s) = args.value_of("compress") {
+        config.compress = Some(s.eq_ignore_ascii_case("on"));
+        printcoln(Color::White, format!("Set Compression: {}", s.to_lowercase()));
+    }
+
+    if let Some(s) = args.value_of("scancache") {
+        config.scan_cache = Some(s.eq_ignore_ascii_case("on"));
+        printcoln(Color::White, format!("Set Scan Cache: {}", s.to_lowercase()));
+    }
+
+    if let Some(s) = args.value_of("shadowcopy") {
+        config.shadow_copy = Some(s.eq_ignore_ascii_case("on"));
+        printcoln(Color::White, format!("Set Shadow Copy: {}", s.to_lowercase()));
+    }
+
+    if let Some(values) = args.values_of("snapshot") {
+        let mut snapshots = config.snapshots.take().unwrap_or_default();
+        for s in values {
+            if s.eq_ignore_ascii_case("off") {
+                snapshots.clear();
+                printcoln(Color::White, "Removed Snapshots");
+                continue;
+            }
+            match SnapshotHook::parse(s) {
+                Ok(hook) => {
+                    snapshots.retain(|h| h.path != hook.path);
+                    snapshots.push(hook);
+                    printcoln(Color::White, format!("Set Snapshot: {}", s));
+                },
+                Err(e) => printcoln(Color::Red, e),
+            }
+        }
+        config.snapshots = if snapshots.is_empty() { None } else { Some(snapshots) };
+    }
+
+    if let Some(s) = args.value_of("classblimit") {
+        if let Some(limit) = parse_limit(s) {
+            config.class_b_limit = limit;
+            printcoln(Color::White, format!("Set Class B Limit: {}", s));
+        }
+    }
+
+    if let Some(s) = args.value_of("classclimit") {
+        if let Some(limit) = parse_limit(s) {
+            config.class_c_limit = limit;
+            printcoln(Color::White, format!("Set Class C Limit: {}", s));
+        }
+    }
+
+    // Possible values are enforced by clap
+    if let Some(s) = args.value_of("limitaction") {
+        config.limit_action = Some(if s.eq_ignore_ascii_case("pause") { LimitAction::Pause } else { LimitAction::Abort });
+        printcoln(Color::White, format!("Set Limit Action: {}", s.to_lowercase()));
+    }
+
+    if let Some(s) = args.value_of("downloadwarnings") {
+        if s.eq_ignore_ascii_case("off") {
+            config.download_warnings = None;
+            printcoln(Color::White, "Set Download Warnings: off");
+        } else {
+            match s.split(',').map(throttle::parse_size).collect::<Result<Vec<u64>, String>>() {
+                Ok(sizes) => {
+                    printcoln(Color::White, format!("Set Download Warnings: {}", sizes.iter().map(|s| HumanBytes(*s).to_string()).collect::<Vec<_>>().join(", ")));
+                    config.download_warnings = Some(sizes);
+                },
+                Err(e) => printcoln(Color::Red, format!("Invalid download warnings: {}", e)),
+            }
+        }
+    }
+
+    if let Some(s) = args.value_of("retention") {
+        if s.eq_ignore_ascii_case("off") {
+            config.retention = None;
+            printcoln(Color::White, "Set Retention: off");
+        } else {
+            match Retention::parse(s) {
+                Ok(retention) => {
+                    config.retention = Some(retention);
+                    printcoln(Color::White, format!("Set Retention: {}", retention));
+                },
+                Err(e) => printcoln(Color::Red, e),
+            }
+        }
+    }
+
+    if let Some(s) = args.value_of("manifesthistory") {
+        match u32::from_str(s) {
+            Ok(n) => {
+                config.manifest_history = Some(n);
+                printcoln(Color::White, format!("Set Manifest History: {}", s));
+            },
+            Err(_) => printcoln(Color::Red, format!("Invalid manifest history '{}', it must be at most {}", s, u32::MAX)),
+        }
+    }
+
+    // Before the backend is switched, s.t. that happens in the new directory
+    if let Some(s) = args.value_of("manifestdir") {
+        match manifest::move_to_dir(s) {
+            Ok(_) => {
+                config.manifest_dir = Some(s.to_string());
+                printcoln(Color::White, format!("Set Manifest Directory: {} ({})", s, manifest::local_path()));
+            },
+            Err(e) => printcoln(Color::Red, format!("Failed to move the manifest to {} ({})", s, e)),
+        }
+    }
+
+    // Possible values are enforced by clap
+    if let Some(s) = args.value_of("manifestbackend") {
+        match manifest::switch_backend(s.eq_ignore_ascii_case("sqlite")) {
+            Ok(_) => printcoln(Color::White, format!("Set Manifest Backend: {} ({})", s.to_lowercase(), manifest::local_path())),
+            Err(e) => printcoln(Color::Red, format!("Failed to switch manifest backend ({})", e)),
+        }
+    }
+
+    // Takes effect from the next run
+    if let Some(s) = args.value_of("logfile") {
+        if s.eq_ignore_ascii_case("off") {
+            config.log_file = None;
+            printcoln(Color::White, "Set Log File: off");
+        } else {
+            config.log_file = Some(s.to_string());
+            printcoln(Color::White, format!("Set Log File: {}", s));
+        }
+    }
+
+    // Possible values are enforced by clap
+    if let Some(s) = args.value_of("logrotation") {
+        let rotation = match s.to_lowercase().as_str() {
+            "run" => LogRotation::Run,
+            "daily" => LogRotation::Daily,
+            "hourly" => LogRotation::Hourly,
+            _ => LogRotation::Never,
+        };
+        config.log_rotation = Some(rotation);
+        printcoln(Color::White, format!("Set Log Rotation: {}", rotation));
+    }
+
+    if let Some(s) = args.value_of("logdir") {
+        if s.eq_ignore_ascii_case("off") {
+            config.run_logs = Some(false);
+            printcoln(Color::White, "Set Log Directory: off");
+        } else {
+            config.log_dir = Some(s.to_string());
+            config.run_logs = None;
+            printcoln(Color::White, format!("Set Log Directory: {}", s));
+        }
+    }
+
+    // Validated by clap
+    if let Some(s) = args.value_of("logkeep") {
+        config.log_keep = Some(s.parse().unwrap());
+        printcoln(Color::White, format!("Set Log Keep: {}", s));
+    }
+
+    let mut notifications = config.notifications.take().unwrap_or_default();
+    if let Some(s) = args.value_of("webhook") {
+        notifications.webhook = if s.eq_ignore_ascii_case("off") { None } else { Some(s.to_string()) };
+        printcoln(Color::White, format!("Set Webhook: {}", notify::redact_url(s)));
+    }
+
+    if let Some(s) = args.value_of("smtpserver") {
+        notifications.smtp_server = if s.eq_ignore_ascii_case("off") { None } else { Some(s.to_string()) };
+        printcoln(Color::White, format!("Set SMTP Server: {}", s));
+    }
+
+    if let Some(s) = args.value_of("smtpuser") {
+        notifications.smtp_user = if s.eq_ignore_ascii_case("none") { None } else { Some(s.to_string()) };
+        printcoln(Color::White, format!("Set SMTP User: {}", s));
+    }
+
+    // Not echoed, it may be the password itself
+    if let Some(s) = args.value_of("smtppassword") {
+        notifications.smtp_password = Some(s.to_string());
+        printcoln(Color::White, "Set SMTP Password");
+    }
+
+    if let Some(s) = args.value_of("emailfrom") {
+        notifications.email_from = Some(s.to_string());
+        printcoln(Color::White, format!("Set Email From: {}", s));
+    }
+
+    if let Some(s) = args.value_of("emailto") {
+        notifications.email_to = Some(s.to_string());
+        printcoln(Color::White, format!("Set Email To: {}", s));
+    }
+
+    // Possible values are enforced by clap
+    if let Some(s) = args.value_of("notifyon") {
+        let on = if s.eq_ignore_ascii_case("failure") { NotifyOn::Failure } else { NotifyOn::Always };
+        notifications.notify_on = Some(on);
+        printcoln(Color::White, format!("Set Notify On: {}", on));
+    }
+    // The section is left out when nothing in it is set
+    if notifications != Notifications::default() {
+        config.notifications = Some(notifications);
+    }
+
+    let mut hooks = config.hooks.take().unwrap_or_default();
+    for (arg, list, name) in [("beforehook", &mut hooks.before, "Before"), ("afterhook", &mut hooks.after, "After")] {
+        if let Some(values) = args.values_of(arg) {
+            let mut commands = list.take().unwrap_or_default();
+            for s in values {
+                if s.eq_ignore_ascii_case("off") {
+                    commands.clear();
+                    printcoln(Color::White, format!("Removed {} Hooks", name));
+                } else {
+                    commands.push(Hook::new(s));
+                    printcoln(Color::White, format!("Added {} Hook {}", name, commands.len()));
+                }
+            }
+            *list = if commands.is_empty() { None } else { Some(commands) };
+        }
+    }
+    if !hooks.is_empty() {
+        config.hooks = Some(hooks);
+    }
+
+    let mut jobs = config.jobs.take().unwrap_or_default();
+    for s in args.values_of("addjob").into_iter().flatten() {
+        let (name, backup_list) = match job_value(s) {
+            Some(v) => v,
+            None => continue,
+        };
+        match jobs.iter_mut().find(|job| job.name == name) {
+            Some(job) => job.backup_list = backup_list.to_string(),
+            None => jobs.push(Job::new(name, backup_list)),
+        }
+        printcoln(Color::White, format!("Set Job {}: {}", name, backup_list));
+    }
+    for name in args.values_of("removejob").into_iter().flatten() {
+        match jobs.iter().position(|job| job.name == name) {
+            Some(idx) => {
+                jobs.remove(idx);
+                printcoln(Color::White, format!("Removed Job {}", name));
+            },
+            None => printcoln(Color::Red, format!("There is no job named '{}'", name)),
+        }
+    }
+    for (arg, setting) in [("jobbucket", "Bucket"), ("jobinterval", "Interval")] {
+        for s in args.values_of(arg).into_iter().flatten() {
+            let (name, value) = match job_value(s) {
+                Some(v) => v,
+                None => continue,
+            };
+            let job = match jobs.iter_mut().find(|job| job.name == name) {
+                Some(job) => job,
+                None => {
+                    printcoln(Color::Red, format!("There is no job named '{}', add it with --add_job first", name));
+                    continue;
+                },
+            };
+            let off = value.eq_ignore_ascii_case("off");
+            match arg {
+                "jobbucket" => job.bucket_name = if off { None } else { Some(value.to_string()) },
+                _ => match value.parse::<u64>() {
+                    Ok(minutes) if minutes >= 1 => job.interval = Some(minutes),
+                    _ if off => job.interval = None,
+                    _ => {
+                        printcoln(Color::Red, format!("Invalid interval for job {}, must be at least 1 minute or 'off'", name));
+                        continue;
+                    },
+                },
+            }
+            printcoln(Color::White, format!("Set Job {} {}: {}", name, setting, value));
+        }
+    }
+    config.jobs = if jobs.is_empty() { None } else { Some(jobs) };
+
+    if let Some(s) = args.value_of("metricsfile") {
+        if s.eq_ignore_ascii_case("off") {
+            config.metrics_file = None;
+            printcoln(Color::White, "Set Metrics File: off");
+        } else {
+            config.metrics_file = Some(s.to_string());
+            printcoln(Color::White, format!("Set Metrics File: {}", s));
+        }
+    }
+
+}
+
+// Converts the config to TOML, moving it to the new location
+// The old config is renamed, s.t. the two can't both be used and re-use nonces from the same counter
+fn migrate(config: &mut Config, args: &ArgMatches) {
+    if Format::of(&config.location) == Format::Toml {
+        printcoln(Color::White, format!("{} is already a TOML config", config.location));
+        return;
+    }
+    let default = std::path::Path::new(&config.location).with_extension("toml");
+    let target = args.value_of("file").map_or(default.to_string_lossy().to_string(), |s| s.to_string());
+    if Format::of(&target) != Format::Toml {
+        printcoln(Color::Red, format!("Error: {} doesn't end in .toml, it would be read as JSON", target));
+        return;
+    }
+    if std::path::Path::new(&target).exists() {
+        printcoln(Color::Red, format!("Error: {} already exists", target));
+        return;
+    }
+    if let Err(e) = config.save_to(&target) {
+        printcoln(Color::Red, format!("Error: {} could not be written ({})", target, e));
+        return;
+    }
+    let old = format!("{}.old", config.location);
+    if std::path::Path::new(&config.location).exists() {
+        // Only the old one is kept then, see above
+        if let Err(e) = std::fs::rename(&config.location, &old) {
+            std::fs::remove_file(&target).unwrap_or(());
+            printcoln(Color::Red, format!("Error: {} could not be renamed, nothing was converted ({})", config.location, e));
+            return;
+        }
+    }
+    printcoln(Color::Green, format!("Converted {} to {}, the old config is kept as {}", config.location, target, old));
+    printcoln(Color::Yellow, format!("Use the new config with --config {}", target));
+    config.location = target;
+}
+
+// Parses an API call limit, where 'off' means no limit
+// Returns None (after printing why) if it is invalid
+fn parse_limit(s: &str) -> Option<Option<u64>> {
+    if s.eq_ignore_ascii_case("off") {
+        return Some(None);
+    }
+    match u64::from_str(s) {
+        Ok(n) => Some(Some(n)),
+        Err(_) => {
+            printcoln(Color::Red, format!("Invalid limit '{}', must be a number or 'off'", s));
+            None
+        }
+    }
+}
+
+// Splits a NAME:VALUE argument of a job, the value may hold ':' itself (e.g. C:\list.txt)
+// Returns None (after printing why) if it is invalid
+fn job_value(s: &str) -> Option<(&str, &str)> {
+    let (name, value) = match s.split_once(':') {
+        Some((name, value)) if !value.is_empty() => (name, value),
+        _ => {
+            printcoln(Color::Red, format!("Invalid job setting '{}', must be NAME:VALUE", s));
+            return None;
+        }
+    };
+    match jobs::check_name(name) {
+        Ok(_) => Some((name, value)),
+        Err(e) => {
+            printcoln(Color::Red, e);
+            None
+        }
+    }
+}
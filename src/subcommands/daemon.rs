@@ -0,0 +1,195 @@
+use crate::config::Config;
+use clap::ArgMatches;
+use crate::colorutil::printcoln;
+use termcolor::Color;
+use crate::subcommands::backup::upload::{self, UploadOptions};
+use crate::subcommands::watch;
+use crate::datetime;
+use crate::filelist;
+use crate::jobs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Minutes between backups, unless given with --interval
+pub const DEFAULT_INTERVAL: u64 = 60;
+
+/// Runs backups unattended, either every few minutes or continuously by watching for changes
+/// Alternatively writes systemd units that do the same
+pub fn daemon(config: &mut Config, args: Option<&ArgMatches>) {
+    let args = args.unwrap();
+    // Validated by clap
+    let interval = match args.value_of("interval") {
+        Some(s) => s.parse::<u64>().unwrap(),
+        None => DEFAULT_INTERVAL,
+    };
+
+    if let Some(dir) = args.value_of("systemd") {
+        let interval = jobs::interval(config, config.job.as_deref()).unwrap_or(interval);
+        if let Err(e) = write_systemd_units(config, args, Path::new(dir), interval) {
+            printcoln(Color::Red, format!("Failed to write systemd units ({})", e));
+        }
+        return;
+    }
+
+    if args.is_present("watch") {
+        watch::watch(config, Some(args));
+        return;
+    }
+
+    // Each job is uploaded on its own interval if it sets one (see jobs.rs), and when it is due
+    let all = args.value_of("job") == Some(jobs::ALL);
+    let names = if all { jobs::scopes(config) } else { vec![config.job.clone()] };
+    if names.is_empty() {
+        printcoln(Color::Red, "The config has no backup list and no jobs, there is nothing to back up");
+        return;
+    }
+    let options = match UploadOptions::from_args(args) {
+        Ok(options) => options,
+        Err(e) => {
+            printcoln(Color::Red, e);
+            return;
+        }
+    };
+    // A job that fails to upload is tried again when it is next due
+    let upload = |config: &mut Config| {
+        if let Err(e) = upload::upload_job(config, &options) {
+            printcoln(Color::Red, e);
+        }
+    };
+    let mut schedule: Vec<(Option<String>, u64, SystemTime)> = names.into_iter()
+        .map(|name| {
+            let minutes = jobs::interval(config, name.as_deref()).unwrap_or(interval);
+            (name, minutes, UNIX_EPOCH)
+        })
+        .collect();
+    loop {
+        let now = SystemTime::now();
+        let due: Vec<Option<String>> = schedule.iter().filter(|(_, _, at)| *at <= now).map(|(name, _, _)| name.clone()).collect();
+        if all {
+            jobs::for_each(config, due.clone(), upload);
+        } else {
+            upload(config);
+        }
+        let finished = SystemTime::now();
+        for (_, minutes, at) in schedule.iter_mut().filter(|(name, _, _)| due.contains(name)) {
+            *at = finished + Duration::from_secs(*minutes*60);
+        }
+        let next = schedule.iter().map(|(_, _, at)| *at).min().unwrap_or(finished);
+        let next_millis = next.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64);
+        printcoln(Color::Green, format!("Next backup at {}", datetime::format_millis(next_millis)));
+        std::thread::sleep(next.duration_since(SystemTime::now()).unwrap_or_default());
+    }
+}
+
+// Writes retain-rs.service (and retain-rs.timer for scheduled backups) to 'dir', named retain-rs-NAME for job NAME
+// The units run this executable with the current config, in the current directory s.t. manifest.json is found
+fn write_systemd_units(config: &Config, args: &ArgMatches, dir: &Path, interval: u64) -> Result<(), std::io::Error> {
+    let exe = std::env::current_exe()?;
+    let working_dir = std::env::current_dir()?;
+    let config_path = absolute(&config.location)?;
+
+    let mut command = vec![exe.to_string_lossy().to_string(), "--config".to_string(), config_path.to_string_lossy().to_string()];
+    if filelist::one_file_system() {
+        command.push("--one-file-system".to_string());
+    }
+    let job = args.value_of("job");
+    if let Some(job) = job {
+        command.extend(vec!["--job".to_string(), job.to_string()]);
+    }
+    let name = match job {
+        Some(job) if job != jobs::ALL => format!("retain-rs-{}", job),
+        _ => "retain-rs".to_string(),
+    };
+    let watch = args.is_present("watch");
+    if watch {
+        command.extend(vec!["daemon".to_string(), "--watch".to_string()]);
+        if let Some(delay) = args.value_of("delay") {
+            command.extend(vec!["--delay".to_string(), delay.to_string()]);
+        }
+    } else {
+        command.extend(vec!["backup".to_string(), "upload".to_string()]);
+    }
+    if let Some(limit) = args.value_of("limit") {
+        command.extend(vec!["--limit".to_string(), limit.to_string()]);
+    }
+    if let Some(threads) = args.value_of("threads") {
+        command.extend(vec!["--threads".to_string(), threads.to_string()]);
+    }
+    let command = command.iter().map(|arg| quote(arg)).collect::<Vec<String>>().join(" ");
+
+    std::fs::create_dir_all(dir)?;
+    let service = if watch {
+        format!("[Unit]\n\
+                 Description=retain-rs backup, watching for changes\n\
+                 Wants=network-online.target\n\
+                 After=network-online.target\n\
+                 \n\
+                 [Service]\n\
+                 Type=simple\n\
+                 WorkingDirectory={}\n\
+                 ExecStart={}\n\
+                 Restart=on-failure\n\
+                 RestartSec=60\n\
+                 \n\
+                 [Install]\n\
+                 WantedBy=default.target\n", quote(&working_dir.to_string_lossy()), command)
+    } else {
+        format!("[Unit]\n\
+                 Description=retain-rs backup\n\
+                 Wants=network-online.target\n\
+                 After=network-online.target\n\
+                 \n\
+                 [Service]\n\
+                 Type=oneshot\n\
+                 WorkingDirectory={}\n\
+                 ExecStart={}\n", quote(&working_dir.to_string_lossy()), command)
+    };
+    let service_unit = format!("{}.service", name);
+    std::fs::write(dir.join(&service_unit), service)?;
+    printcoln(Color::Green, format!("Wrote {}", dir.join(&service_unit).display()));
+
+    let unit = if watch {
+        service_unit
+    } else {
+        let timer = format!("[Unit]\n\
+                             Description=Run retain-rs backup every {} minutes\n\
+                             \n\
+                             [Timer]\n\
+                             OnBootSec=5min\n\
+                             OnUnitActiveSec={}min\n\
+                             \n\
+                             [Install]\n\
+                             WantedBy=timers.target\n", interval, interval);
+        let timer_unit = format!("{}.timer", name);
+        std::fs::write(dir.join(&timer_unit), timer)?;
+        printcoln(Color::Green, format!("Wrote {}", dir.join(&timer_unit).display()));
+        if job == Some(jobs::ALL) && config.jobs.iter().flatten().any(|job| job.interval.is_some()) {
+            printcoln(Color::Yellow, "The timer uploads every job each time, the intervals of jobs are only kept by 'daemon' itself");
+        }
+        timer_unit
+    };
+
+    printcoln(Color::White, "To install them for the current user, copy them to ~/.config/systemd/user/ and run:");
+    printcoln(Color::White, "\tsystemctl --user daemon-reload");
+    printcoln(Color::White, format!("\tsystemctl --user enable --now {}", unit));
+    printcoln(Color::White, "Note that user units only run while logged in, unless lingering is enabled ('loginctl enable-linger')");
+    Ok(())
+}
+
+fn absolute(path: &str) -> Result<PathBuf, std::io::Error> {
+    let path = Path::new(path);
+    if path.is_absolute() {
+        Ok(path.to_path_buf())
+    } else {
+        Ok(std::env::current_dir()?.join(path))
+    }
+}
+
+// Quotes an argument for use in a unit file if it contains whitespace or quotes
+fn quote(arg: &str) -> String {
+    if arg.contains(|c: char| c.is_whitespace() || c == '"' || c == '\\') {
+        format!("\"{}\"", arg.replace("\\", "\\\\").replace("\"", "\\\""))
+    } else {
+        arg.to_string()
+    }
+}
@@ -0,0 +1,70 @@
+use crate::config::Config;
+use crate::colorutil::printcoln;
+use termcolor::Color;
+use crate::manifest::{self, FileManifest};
+use crate::filelist;
+use indicatif::HumanBytes;
+
+/// Shows what the next upload would do: which files are new or modified,
+/// and which files in the manifest are no longer in the backup list
+pub fn diff(config: &Config) {
+    let backup_list = match &config.backup_list {
+        Some(list) => list,
+        None => {
+            printcoln(Color::Red, "File List Path is missing");
+            return;
+        }
+    };
+    if let Err(e) = filelist::verify_structure(backup_list) {
+        printcoln(Color::Red, format!("Backup list is invalid: {}", e));
+        return;
+    }
+
+    let manifest = match FileManifest::from_file(manifest::local_path()) {
+        Ok(fm) => fm,
+        Err(err) => {
+            printcoln(Color::Red, format!("Failed to load file manifest ({})", err));
+            return;
+        }
+    };
+
+    let mut filelist = filelist::build_file_list(backup_list);
+    filelist.sort();
+
+    let (mut new, mut modified) = (0, 0);
+    let mut pending_bytes = 0;
+    for path in &filelist {
+        let metadata = match std::fs::metadata(path) {
+            Ok(m) => m,
+            Err(_) => continue, // Removed while building the list
+        };
+        let modified_time = match metadata.modified().map(|t| t.duration_since(std::time::UNIX_EPOCH)) {
+            Ok(Ok(v)) => v.as_millis() as u64,
+            _ => 0,
+        };
+        match manifest.files.binary_search_by(|e| e.path.as_str().cmp(path)) {
+            Err(_) => {
+                printcoln(Color::Green, format!("+ {}", path));
+                new += 1;
+                pending_bytes += metadata.len();
+            },
+            Ok(n) if modified_time > manifest.files[n].timestamp => {
+                printcoln(Color::Yellow, format!("~ {}", path));
+                modified += 1;
+                pending_bytes += metadata.len();
+            },
+            Ok(_) => (),
+        }
+    }
+
+    // Files that are tracked, but no longer included by the backup list (or deleted)
+    let mut removed = 0;
+    for entry in &manifest.files {
+        if filelist.binary_search(&entry.path).is_err() {
+            printcoln(Color::Red, format!("- {}", entry.path));
+            removed += 1;
+        }
+    }
+
+    printcoln(Color::White, format!("{} new, {} modified ({} to upload), {} no longer in the backup list", new, modified, HumanBytes(pending_bytes), removed));
+}
@@ -0,0 +1,243 @@
+use crate::config::Config;
+use crate::colorutil::{printcoln, printcol};
+use termcolor::Color;
+use std::io::{stdin, BufRead};
+use std::path::Path;
+use raze::api::{B2Auth, ListBucketParams};
+use reqwest::blocking::Client;
+use crate::secrets;
+use crate::throttle;
+use crate::retention::Retention;
+use crate::encryption::KEY_LENGTH;
+use crate::noecho::NoEcho;
+
+/// Walks through the main settings with the current values filled in, s.t. they are kept by just pressing enter
+/// Unlike 'init', nothing is generated or erased. Nothing changes until the end, where the edited config can be saved
+pub fn edit(config: &mut Config) {
+    // The credentials and bucket are checked with the native B2 API
+    if config.s3.is_some() || config.local_dir.is_some() {
+        printcoln(Color::Red, "'config edit' only works for B2, set the options of S3-compatible storage or a local directory with 'config' instead");
+        return;
+    }
+    printcoln(Color::Yellow, format!("Editing {}", config.location));
+    printcoln(Color::Yellow, "Press enter to keep the current value, shown in brackets");
+    printcoln(Color::Yellow, "End the input (Ctrl-D, or Ctrl-Z on Windows) to quit without saving");
+    println!();
+
+    let edited = match edit_values(config) {
+        Some(c) => c,
+        None => {
+            println!();
+            printcoln(Color::Yellow, "Quit without saving, nothing was changed");
+            return;
+        }
+    };
+    println!();
+    match confirm("Save the changes?") {
+        Some(true) => {
+            *config = edited;
+            // Saved by 'config' when done
+            printcoln(Color::Green, format!("Saved {}", config.location));
+        },
+        _ => printcoln(Color::Yellow, "Nothing was changed"),
+    }
+}
+
+// Asks for each value, returning the edited config. None if the input ended
+fn edit_values(config: &Config) -> Option<Config> {
+    let mut edited = config.clone();
+    let client = Client::builder().timeout(None).build().unwrap();
+
+    // Credentials are checked by authenticating with them
+    printcoln(Color::Yellow, "B2 authentication");
+    let mut auth = None;
+    loop {
+        let app_key_id = prompt("App Key ID", edited.app_key_id.as_deref())?;
+        // The App Key is never shown, nor is what is typed
+        let app_key = {
+            let _echo = NoEcho::start();
+            prompt("App Key", edited.app_key.as_ref().map(|_| "hidden"))?
+        };
+        let app_key_id = Some(app_key_id).filter(|s| !s.is_empty()).or_else(|| edited.app_key_id.clone());
+        let app_key = Some(app_key).filter(|s| !s.is_empty()).or_else(|| edited.app_key.clone());
+        let unchanged = app_key_id == edited.app_key_id && app_key == edited.app_key;
+        let (app_key_id, app_key) = match (app_key_id, app_key) {
+            (Some(id), Some(key)) => (id, key),
+            _ => {
+                printcoln(Color::Red, "Both the App Key ID and App Key are needed");
+                continue;
+            }
+        };
+        printcoln(Color::Yellow, "Trying to authenticate...");
+        let result = match secrets::resolve(&app_key) {
+            Ok(key) => raze::api::b2_authorize_account(&client, format!("{}:{}", app_key_id, key))
+                .map_err(|_| "Authentication failure".to_string()),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(a) => {
+                printcoln(Color::Green, "Success");
+                auth = Some(a);
+            },
+            Err(e) if unchanged => {
+                printcoln(Color::Red, e);
+                printcoln(Color::Yellow, "Keeping the current credentials, the bucket can't be checked without them");
+            },
+            Err(e) => {
+                printcoln(Color::Red, e);
+                continue;
+            },
+        }
+        edited.app_key_id = Some(app_key_id);
+        edited.app_key = Some(app_key);
+        break;
+    }
+
+    loop {
+        let bucket = prompt("Bucket name", edited.bucket_name.as_deref())?;
+        let bucket = match Some(bucket).filter(|s| !s.is_empty()).or_else(|| edited.bucket_name.clone()) {
+            Some(b) => b,
+            None => continue,
+        };
+        match &auth {
+            Some(auth) if !bucket_exists(&client, auth, &bucket) => {
+                printcoln(Color::Red, format!("'{}' does not appear to be a valid bucket", bucket));
+                printcoln(Color::Red, "Ensure you spelled it correctly and the auth has permission access it");
+                continue;
+            },
+            _ => edited.bucket_name = Some(bucket),
+        }
+        break;
+    }
+
+    println!();
+    let list = prompt("Backup list file", edited.backup_list.as_deref())?;
+    if let Some(list) = Some(list).filter(|s| !s.is_empty()) {
+        edited.backup_list = Some(list);
+    }
+    if let Some(list) = edited.backup_list.as_ref().filter(|l| !Path::new(l).is_file()) {
+        printcoln(Color::Red, format!("Warning: {} is either missing or inaccessible", list));
+    }
+
+    // Turning encryption on or off affects what is already uploaded, so it is only asked if it isn't set yet
+    println!();
+    match edited.encrypt {
+        Some(on) => printcoln(Color::Yellow, format!("Encryption is {}, change it with 'encryption --enable'", if on {"on"} else {"off"})),
+        None => edited.encrypt = Some(confirm("Enable encryption?")?),
+    }
+    if edited.encrypt == Some(true) && edited.public_key.is_none() {
+        edit_secret_key(&mut edited)?;
+    }
+
+    println!();
+    printcoln(Color::Yellow, "Use 'off' to remove a limit or retention policy");
+    loop {
+        let current = edited.upload_limit.map(throttle::format_rate).unwrap_or_else(|| "off".to_string());
+        match prompt("Upload limit", Some(&current))?.as_str() {
+            "" => (),
+            s if s.eq_ignore_ascii_case("off") => edited.upload_limit = None,
+            s => match throttle::parse_rate(s) {
+                Ok(rate) => edited.upload_limit = Some(rate),
+                Err(e) => {
+                    printcoln(Color::Red, format!("Invalid upload limit: {}", e));
+                    continue;
+                }
+            },
+        }
+        break;
+    }
+
+    loop {
+        let current = if edited.compress.unwrap_or(false) {"on"} else {"off"};
+        match prompt("Compression (on/off)", Some(current))?.to_lowercase().as_str() {
+            "" => (),
+            "on" => edited.compress = Some(true),
+            "off" => edited.compress = Some(false),
+            _ => continue,
+        }
+        break;
+    }
+
+    loop {
+        let current = edited.retention.map_or("off".to_string(), |r| format!("{},{},{}", r.daily, r.weekly, r.monthly));
+        match prompt("Retention (daily,weekly,monthly)", Some(&current))?.as_str() {
+            "" => (),
+            s if s.eq_ignore_ascii_case("off") => edited.retention = None,
+            s => match Retention::parse(s) {
+                Ok(retention) => edited.retention = Some(retention),
+                Err(e) => {
+                    printcoln(Color::Red, e);
+                    continue;
+                }
+            },
+        }
+        break;
+    }
+
+    Some(edited)
+}
+
+// Asks for the secret key, which must be a readable key
+// A replaced key is kept as a retired key, s.t. files it encrypted can still be restored
+fn edit_secret_key(config: &mut Config) -> Option<()> {
+    loop {
+        let entered = prompt("Secret key file", config.secret_key.as_deref())?;
+        let location = match Some(entered).filter(|s| !s.is_empty()).or_else(|| config.secret_key.clone()) {
+            Some(l) => l,
+            None => {
+                printcoln(Color::Red, "Encryption needs a secret key, generate one with 'encryption --genkey FILE'");
+                continue;
+            }
+        };
+        match secrets::read_key(&location) {
+            Ok(key) if key.len() == KEY_LENGTH => (),
+            Ok(key) => {
+                printcoln(Color::Red, format!("{} is not a key, it is {} bytes instead of {}", location, key.len(), KEY_LENGTH));
+                continue;
+            },
+            Err(e) => {
+                printcoln(Color::Red, format!("{} could not be read ({})", location, e));
+                continue;
+            },
+        }
+        if let Some(previous) = config.secret_key.replace(location.clone()).filter(|p| *p != location) {
+            printcoln(Color::Yellow, format!("Previous key {} becomes a retired key, keep it to restore files it encrypted", previous));
+            config.retired_keys.get_or_insert_with(Vec::new).push(previous);
+        }
+        return Some(());
+    }
+}
+
+// Whether 'bucket' exists and the auth can access it
+// Asking for one bucket by name works for keys restricted to a bucket too
+fn bucket_exists(client: &Client, auth: &B2Auth, bucket: &str) -> bool {
+    printcoln(Color::Yellow, "Verifying bucket...");
+    let params = ListBucketParams {
+        bucket_id: None,
+        bucket_name: Some(bucket.to_string()),
+        bucket_types: None
+    };
+    raze::api::b2_list_buckets(client, auth, params).map_or(false, |list| list.len() == 1)
+}
+
+// Asks for a value, showing 'current' if there is one
+// Returns what was entered (empty to keep the current value), or None if the input ended
+fn prompt(name: &str, current: Option<&str>) -> Option<String> {
+    match current {
+        Some(c) => printcol(Color::White, format!("{} [{}]: ", name, c)),
+        None => printcol(Color::White, format!("{}: ", name)),
+    }
+    let line = stdin().lock().lines().next()?.ok()?;
+    Some(line.trim().to_string())
+}
+
+// Asks a yes/no question. None if the input ended
+pub(super) fn confirm(question: &str) -> Option<bool> {
+    loop {
+        match prompt(&format!("{} (y/n)", question), None)?.to_lowercase().as_str() {
+            "y" => return Some(true),
+            "n" => return Some(false),
+            _ => continue,
+        }
+    }
+}
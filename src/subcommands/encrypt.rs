@@ -1,18 +1,35 @@
 use crate::config::Config;
 use clap::ArgMatches;
-use crate::colorutil::printcoln;
+use crate::colorutil::{printcoln, eprintcoln};
 use termcolor::Color;
 use std::io::{Read, Write};
-use crate::encryption::{key_from_file, get_nonces_required};
-use crate::encryption::reader::EncryptingReader;
+use crate::encryption::{key_from_file, get_nonces_required, random_start_nonce};
+use crate::encryption::cipher::Cipher;
 use rand::{thread_rng, Rng};
-use crate::encryption::writer::DecryptingWriter;
+use crate::encryption::decrypting_reader::DecryptingReader;
+use crate::encryption::keys::{Keys, EncryptionKey, key_id};
+use crate::encryption::{keypair, mnemonic};
+use crate::secrets;
+use crate::transfer;
+use crate::manifest::{self, FileManifest};
+
+// Used in place of a file to read from stdin or write to stdout
+const STDIO: &str = "-";
+
+// Nonces allocated when encrypting stdin, enough for 2^48 blocks (~2 EB)
+const STREAM_NONCES: u128 = 1 << 48;
 
 pub fn encrypt(config: &mut Config, args: Option<&ArgMatches>) {
     let args = args.unwrap(); // Guaranteed by Clap
 
-    if args.is_present("keygen") {
-        let mut output = match std::fs::File::create(args.value_of("keygen").unwrap()) {
+    if let Some(path) = args.value_of("keygen") {
+        // Overwriting a key would make everything it encrypted unrecoverable
+        let known = in_use(config).any(|p| p == path);
+        if known && std::path::Path::new(path).exists() {
+            printcoln(Color::Red, format!("Error: {} is a key that is in use, choose another file for the new key", path));
+            return;
+        }
+        let mut output = match std::fs::File::create(path) {
             Ok(f) => f,
             Err(err) => {
                 printcoln(Color::Red, format!("Error: Keyfile could not be opened ({:?})", err));
@@ -23,102 +40,247 @@ pub fn encrypt(config: &mut Config, args: Option<&ArgMatches>) {
         let mut key_bytes = [0u8; 32];
         rng.try_fill(&mut key_bytes).expect("Failed to generate key");
         output.write_all(&mut key_bytes).unwrap();
-        config.secret_key = Some(args.value_of("keygen").unwrap().to_string());
+        set_active_key(config, path);
+    }
+
+    if let Some(path) = args.value_of("importmnemonic") {
+        if in_use(config).any(|p| p == path) && std::path::Path::new(path).exists() {
+            printcoln(Color::Red, format!("Error: {} is a key that is in use, choose another file for the imported key", path));
+            return;
+        }
+        println!("Enter the {} words of the key, then end the input (Ctrl-D, or Ctrl-Z on Windows):", mnemonic::WORD_COUNT);
+        let mut words = String::new();
+        if let Err(err) = std::io::stdin().read_to_string(&mut words) {
+            printcoln(Color::Red, format!("Error: Failed to read the words ({:?})", err));
+            return;
+        }
+        let key = match mnemonic::from_words(&words) {
+            Ok(k) => k,
+            Err(e) => {
+                printcoln(Color::Red, format!("Error: Invalid words ({})", e));
+                return;
+            }
+        };
+        if let Err(err) = std::fs::write(path, &key[..]) {
+            printcoln(Color::Red, format!("Error: Keyfile could not be written ({:?})", err));
+            return;
+        }
+        printcoln(Color::Green, format!("Imported key {:08x} to {}", key_id(&key), path));
+        set_active_key(config, path);
+    }
+
+    if let Some(path) = args.value_of("keypairgen") {
+        let public_path = format!("{}.pub", path);
+        if in_use(config).any(|p| p == path || *p == public_path) {
+            printcoln(Color::Red, format!("Error: {} is a key that is in use, choose another file for the new key pair", path));
+            return;
+        }
+        let (private, public) = keypair::generate();
+        if let Err(err) = std::fs::write(path, private.to_bytes()).and_then(|_| std::fs::write(&public_path, public.as_bytes())) {
+            printcoln(Color::Red, format!("Error: Keyfile could not be written ({:?})", err));
+            return;
+        }
+        config.public_key = Some(public_path.clone());
         config.save();
+        printcoln(Color::Green, format!("New files are now encrypted for the public key {}", public_path));
+        printcoln(Color::Yellow, format!("Move {} off this machine and configure it with --private_key where you restore", path));
     }
 
     // Ensure a secret key is defined
-    match config.secret_key {
-        Some(_) => (),
-        None => {
-            printcoln(Color::Red, "Error: No secret key set");
-            return;
+    if config.secret_key.is_none() && config.public_key.is_none() && config.private_key.is_none() {
+        printcoln(Color::Red, "Error: No secret key set");
+        return;
+    }
+
+    if args.is_present("exportmnemonic") {
+        let key = match config.secret_key.as_ref().map(key_from_file) {
+            Some(Ok(k)) => k,
+            Some(Err(err)) => {
+                printcoln(Color::Red, format!("Error: Secret key could not be read ({:?})", err));
+                return;
+            }
+            None => {
+                printcoln(Color::Red, "Error: No secret key set");
+                return;
+            }
+        };
+        printcoln(Color::Yellow, "Anyone with these words can decrypt your backups. Keep them somewhere safe and offline");
+        println!("Key {:08x}:", key_id(&key));
+        println!("{}", mnemonic::to_words(&key));
+    }
+
+    if args.is_present("keyring") {
+        let location = match config.secret_key.clone() {
+            Some(l) => l,
+            None => {
+                printcoln(Color::Red, "Error: No secret key set");
+                return;
+            }
+        };
+        if secrets::keyring_entry(&location).is_some() {
+            printcoln(Color::Yellow, "The secret key is already in the OS keyring");
+        } else {
+            let key = match key_from_file(&location) {
+                Ok(k) => k,
+                Err(err) => {
+                    printcoln(Color::Red, format!("Error: Secret key could not be read ({:?})", err));
+                    return;
+                }
+            };
+            // Named by key ID, s.t. a retired key in the keyring is never overwritten by a newer one
+            let name = format!("{}-{:08x}", secrets::SECRET_KEY_ENTRY, key_id(&key));
+            match secrets::store_key(&name, &key) {
+                Ok(entry) => {
+                    config.secret_key = Some(entry);
+                    config.save();
+                    printcoln(Color::Green, format!("Moved the secret key into the OS keyring as '{}'", name));
+                    printcoln(Color::Yellow, format!("{} is no longer used. Keep a copy of it somewhere safe, then delete it", location));
+                },
+                Err(e) => printcoln(Color::Red, format!("Error: {}", e)),
+            }
         }
     }
 
     if let Some(mut files) = args.values_of("encrypt") {
         let infile = files.next().unwrap();
         let outfile = files.next().unwrap();
-        let input = match std::fs::File::open(infile) {
+        let print = reporter(outfile);
+        // An older copy of the config would reuse nonces
+        if let Ok(fm) = FileManifest::from_file(manifest::local_path()) {
+            if let Err(e) = config.check_nonces(fm.nonces) {
+                print(Color::Red, format!("Error: {}", e));
+                return;
+            }
+        }
+        let mut input = match open_input(infile) {
             Ok(f) => f,
             Err(err) => {
-                printcoln(Color::Red, format!("Error: Input file {} could not be opened ({:?})", infile, err));
+                print(Color::Red, format!("Error: Input file {} could not be opened ({:?})", infile, err));
                 return;
             }
         };
-        let inp_size = std::fs::metadata(infile).unwrap().len();
-        let mut output = match std::fs::File::create(outfile) {
+        let output = match create_output(outfile) {
             Ok(f) => f,
             Err(err) => {
-                printcoln(Color::Red, format!("Error: Output file {} could not be opened ({:?})", outfile, err));
+                print(Color::Red, format!("Error: Output file {} could not be opened ({:?})", outfile, err));
                 return;
             }
         };
 
-        let key = match key_from_file(config.secret_key.as_ref().unwrap()) {
+        let key = match EncryptionKey::from_config(config) {
             Ok(k) => k,
             Err(err) => {
-                printcoln(Color::Red, format!("Error: Secret key could not be read ({:?})", err));
+                print(Color::Red, format!("Error: {}", err));
                 return;
             }
         };
 
-        let (start_nonce,allocated) = {
-            let req = get_nonces_required(inp_size);
+        let cipher = config.cipher.unwrap_or(Cipher::XChaCha20Poly1305);
+        let block = config.block_size.unwrap_or_default();
+        let (block,start_nonce,allocated) = if infile == STDIO {
+            // The size of stdin isn't known up front, so it gets a random range with room for any stream
+            (block, random_start_nonce(cipher), STREAM_NONCES)
+        } else {
+            let size = std::fs::metadata(infile).unwrap().len();
+            let block = block.fitting(size);
+            let req = get_nonces_required(size, block);
             let start = config.consume_nonces(req);
-            (start, req)
+            record_nonces(config.nonces_allocated());
+            (block, start, req)
         };
-        let mut reader = EncryptingReader::wrap(input, &key, start_nonce, allocated);
-
-        let mut buf = [0u8; 4096];
-        while let Ok(n) = reader.read(&mut buf) {
-            if n != 0 {
-                output.write_all(&mut buf[..n]).unwrap();
-            } else {
-                break;
-            }
+        let mut writer = key.target(output, cipher, block, start_nonce, allocated);
+        match transfer::copy(&mut input, &mut writer).and_then(|_| writer.finish()) {
+            Ok(_) => print(Color::Green, "Successfully encrypted file!".to_string()),
+            Err(err) => print(Color::Red, format!("Error: Encryption failed ({})", err)),
         }
-        printcoln(Color::Green, "Successfully encrypted file!");
     }
 
     if let Some(mut files) = args.values_of("decrypt") {
         let infile = files.next().unwrap();
         let outfile = files.next().unwrap();
-        let mut input = match std::fs::File::open(infile) {
+        let print = reporter(outfile);
+        let input = match open_input(infile) {
             Ok(f) => f,
             Err(err) => {
-                printcoln(Color::Red, format!("Error: Input file {} could not be opened ({:?})", infile, err));
+                print(Color::Red, format!("Error: Input file {} could not be opened ({:?})", infile, err));
                 return;
             }
         };
-        let output = match std::fs::File::create(outfile) {
+        let mut output = match create_output(outfile) {
             Ok(f) => f,
             Err(err) => {
-                printcoln(Color::Red, format!("Error: Output file {} could not be opened ({:?})", outfile, err));
+                print(Color::Red, format!("Error: Output file {} could not be opened ({:?})", outfile, err));
                 return;
             }
         };
 
-        let key = match key_from_file(config.secret_key.as_ref().unwrap()) {
+        let keys = match Keys::from_config(config) {
             Ok(k) => k,
             Err(err) => {
-                printcoln(Color::Red, format!("Error: Secret key could not be read ({:?})", err));
+                print(Color::Red, format!("Error: {}", err));
                 return;
             }
         };
 
-        let mut writer = DecryptingWriter::target(output, &key);
+        let mut reader = DecryptingReader::wrap(input, &keys);
+        match transfer::copy(&mut reader, &mut output).and_then(|_| output.flush()) {
+            Ok(_) => print(Color::Green, "Successfully decrypted file!".to_string()),
+            Err(err) => print(Color::Red, format!("Error: Decryption failed ({})", err)),
+        }
+    }
+}
 
-        let mut buf = [0u8; 4096];
-        while let Ok(n) = input.read(&mut buf) {
-            writer.write_all(&mut buf[..n]).unwrap();
-            if n == 0 {
-                writer.flush().unwrap();
-                break;
+// Opens a file to encrypt or decrypt, or stdin
+// Records the nonces allocated by the config in the local manifest, if there is one
+// Uploads check against it, s.t. an older copy of the config doesn't reuse the nonces used here (see Config::check_nonces)
+fn record_nonces(allocated: u128) {
+    if let Ok(mut fm) = FileManifest::from_file(manifest::local_path()) {
+        if fm.nonces < allocated {
+            fm.record_nonces(allocated);
+            if let Err(e) = fm.save_local() {
+                eprintcoln(Color::Yellow, format!("Warning: failed to record the allocated nonces in the manifest ({})", e));
             }
         }
+    }
+}
 
+fn open_input(path: &str) -> std::io::Result<Box<dyn Read>> {
+    match path {
+        STDIO => Ok(Box::new(std::io::stdin())),
+        _ => Ok(Box::new(std::fs::File::open(path)?)),
+    }
+}
+
+// Creates the file to write the result to, or stdout
+fn create_output(path: &str) -> std::io::Result<Box<dyn Write>> {
+    match path {
+        STDIO => Ok(Box::new(std::io::stdout())),
+        _ => Ok(Box::new(std::fs::File::create(path)?)),
+    }
+}
 
-        printcoln(Color::Green, "Successfully decrypted file!");
+// Prints messages to stderr when the result is written to stdout, s.t. they don't end up in the output
+fn reporter(outfile: &str) -> fn(Color, String) {
+    match outfile {
+        STDIO => |color, text| eprintcoln(color, text),
+        _ => |color, text| printcoln(color, text),
     }
-}
\ No newline at end of file
+}
+
+// Sets the key at 'path' as the active secret key
+// The previous key is kept, s.t. files it encrypted can still be restored
+fn set_active_key(config: &mut Config, path: &str) {
+    if let Some(previous) = config.secret_key.replace(path.to_string()) {
+        config.retired_keys.get_or_insert_with(Vec::new).push(previous.clone());
+        printcoln(Color::Yellow, format!("Previous key {} is now a retired key, keep it to restore files it encrypted", previous));
+    }
+    config.save();
+}
+
+// Locations of all configured keys
+fn in_use(config: &Config) -> impl Iterator<Item=&String> {
+    config.secret_key.iter()
+        .chain(config.retired_keys.iter().flatten())
+        .chain(config.public_key.iter())
+        .chain(config.private_key.iter())
+}
@@ -0,0 +1,120 @@
+use clap::ArgMatches;
+use crate::colorutil::{printcoln, printsummary};
+use crate::config::Config;
+use crate::manifest::FileEntry;
+use crate::subcommands::backup::download::{self, DownloadOptions, Fetcher};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::time::Instant;
+use tar::{Builder, EntryType, Header};
+use termcolor::Color;
+
+/// Writes backed up files to a tar archive, downloading, decrypting and decompressing them on the way
+/// The archive is zstd compressed if the output path ends with '.zst'
+pub fn export(config: &Config, args: Option<&ArgMatches>) {
+    let args = args.unwrap();
+    let t_start = Instant::now();
+    let output = args.value_of("output").unwrap();
+    let options = download::patterns_of(args, "include")
+        .and_then(|patterns| Ok(DownloadOptions { patterns, ..DownloadOptions::from_args(args)? }));
+    let (fetcher, files) = match options.and_then(|options| Fetcher::connect(config, &options, t_start)) {
+        Ok(f) => f,
+        Err(e) => {
+            printcoln(Color::Red, e);
+            return;
+        }
+    };
+    let file = match File::create(output) {
+        Ok(f) => f,
+        Err(e) => {
+            printcoln(Color::Red, format!("[{:.3}] Failed to create {} ({})", t_start.elapsed().as_secs_f32(), output, e));
+            return;
+        }
+    };
+    printcoln(Color::Green, format!("[{:.3}] Exporting {} files to {}", t_start.elapsed().as_secs_f32(), files.len(), output));
+
+    let result = match output.ends_with(".zst") {
+        true => zstd::stream::write::Encoder::new(file, 0)
+            .and_then(|encoder| archive(&fetcher, &files, output, encoder))
+            .and_then(|encoder| encoder.finish().map(|_| ())),
+        false => archive(&fetcher, &files, output, BufWriter::new(file))
+            .and_then(|mut writer| writer.flush()),
+    };
+    if let Err(e) = &result {
+        fetcher.record(output, false);
+        printcoln(Color::Red, format!("[{:.3}] Failed to write {}, the archive is incomplete ({})", t_start.elapsed().as_secs_f32(), output, e));
+    }
+    fetcher.finish();
+    if result.is_ok() {
+        printsummary(Color::Green, format!("[{:.3}] Export Completed!", t_start.elapsed().as_secs_f32()));
+    }
+}
+
+// Writes the archive of 'files' to 'out', downloading each file next to 'output' first (see staging_path)
+// Files that fail to download are left out, failing to write the archive stops the export
+fn archive<W: Write>(fetcher: &Fetcher, files: &[FileEntry], output: &str, out: W) -> std::io::Result<W> {
+    let mut builder = Builder::new(out);
+    // Hard links are added after the files, s.t. their target is already in the archive
+    let (links, contents): (Vec<&FileEntry>, Vec<&FileEntry>) = files.iter().partition(|e| e.link.is_some());
+    let mut exported = HashSet::new();
+    for entry in contents {
+        let staging = staging_path(output, entry);
+        if !fetcher.fetch(entry, &staging) {
+            remove_staging(&staging);
+            fetcher.record(&entry.path, false);
+            continue;
+        }
+        let result = File::open(&staging).and_then(|file| {
+            let mut header = header(entry, EntryType::Regular, file.metadata()?.len());
+            builder.append_data(&mut header, archive_path(&entry.path), file)
+        });
+        remove_staging(&staging);
+        result?;
+        fetcher.record(&entry.path, true);
+        exported.insert(entry.path.as_str());
+    }
+    for link in links {
+        let target = link.link.as_deref().unwrap();
+        if !exported.contains(target) {
+            fetcher.record(&link.path, false);
+            continue;
+        }
+        let mut header = header(link, EntryType::Link, 0);
+        builder.append_link(&mut header, archive_path(&link.path), archive_path(target))?;
+        fetcher.record(&link.path, true);
+    }
+    builder.into_inner()
+}
+
+// Where 'entry' is downloaded to before it is added to the archive
+// Named after the version of the file, s.t. a partial download is never resumed as another file
+fn staging_path(output: &str, entry: &FileEntry) -> String {
+    format!("{}.{}.download", output, entry.file_id.as_deref().unwrap_or(&entry.mask))
+}
+
+// Removes the download of an entry, along with what a failed attempt left of it
+fn remove_staging(staging: &str) {
+    std::fs::remove_file(staging).unwrap_or(());
+    download::remove_part(staging);
+}
+
+fn header(entry: &FileEntry, kind: EntryType, size: u64) -> Header {
+    let mut header = Header::new_gnu();
+    header.set_entry_type(kind);
+    header.set_size(size);
+    header.set_mode(0o644);
+    header.set_mtime(entry.timestamp / 1000);
+    header
+}
+
+// The path of a file in the archive, which has to be relative
+// '/home/user/a.txt' becomes 'home/user/a.txt' and 'C:\Users\a.txt' becomes 'C/Users/a.txt'
+fn archive_path(path: &str) -> String {
+    let path = path.replace('\\', "/");
+    let path = match path.as_bytes() {
+        [drive, b':', ..] if drive.is_ascii_alphabetic() => format!("{}{}", &path[..1], &path[2..]),
+        _ => path,
+    };
+    path.trim_start_matches('/').to_string()
+}
@@ -0,0 +1,120 @@
+use crate::config::{Config, DEFAULT_THREADS};
+use clap::ArgMatches;
+use crate::colorutil::{printcoln, printsummary};
+use crate::summary::Summary;
+use termcolor::Color;
+use std::sync::Arc;
+use indicatif::HumanBytes;
+use crate::retry;
+use crate::manifest;
+use crate::budget::Budget;
+use crate::backend;
+use crate::capabilities::{self, Operation};
+use crate::datetime;
+use crate::gc;
+use crate::subcommands::clean;
+use crate::subcommands::backup::download::read_manifest;
+use crate::encryption::keys::Keys;
+
+/// Deletes the remote file versions that no manifest entry or kept snapshot refers to, see gc.rs
+/// Unlike 'clean', the local files and the manifest are left alone
+/// A local manifest that is behind the remote one is only collected by with --force
+pub fn gc(config: &Config, args: Option<&ArgMatches>) {
+    let t_start = std::time::Instant::now();
+    let summary = Summary::new("gc", "deleted", config);
+    let args = args.unwrap();
+    let threads = match args.value_of("threads") {
+        Some(s) => s.parse::<usize>().unwrap(), // Validated by clap
+        None => config.clean_threads.unwrap_or(DEFAULT_THREADS),
+    };
+    let dry_run = args.is_present("dryrun");
+    let force = args.is_present("force");
+    let max_attempts = config.max_attempts.unwrap_or(retry::DEFAULT_MAX_ATTEMPTS);
+    let budget = Arc::new(Budget::new(config));
+
+    if let Err(err) = config.is_configured() {
+        printcoln(Color::Red, format!("Invalid config ({})", err));
+        return;
+    }
+    if dry_run {
+        printcoln(Color::Yellow, "Dry run: no changes will be made");
+    }
+
+    let manifest = match manifest::FileManifest::from_file(manifest::local_path()) {
+        Ok(fm) => fm,
+        Err(err) => {
+            printcoln(Color::Red, format!("[{:.3}] Failed to load file manifest ({})", t_start.elapsed().as_secs_f32(), err));
+            return;
+        }
+    };
+    printcoln(Color::Green, format!("[{:.3}] Loaded manifest ({} files, {} past versions in {} snapshots)",
+                                    t_start.elapsed().as_secs_f32(), manifest.files.len(), manifest.past.len(), manifest.snapshots.len()));
+
+    let backend = match backend::connect(config, budget.clone(), t_start) {
+        Ok(b) => b,
+        Err(e) => {
+            printcoln(Color::Red, format!("[{:.3}] {}", t_start.elapsed().as_secs_f32(), e));
+            return;
+        }
+    };
+    if !capabilities::report(&backend.check_access(Operation::Gc), t_start) && !dry_run {
+        return;
+    }
+
+    // What the local manifest doesn't refer to is deleted, so it must not miss what was uploaded since, e.g. from another machine
+    let keys = match config.encrypt.unwrap_or(false) {
+        true => Keys::from_config(config).map(Some),
+        false => Ok(None),
+    };
+    let remote = keys.and_then(|keys| budget.spend(backend.download_call(false)).map_err(|e| e.to_string())
+        .and_then(|_| backend.download(manifest::REMOTE_MANIFEST, None).map_err(|e| e.to_string()))
+        .and_then(|response| read_manifest(response.body, keys.as_ref()).map_err(|e| e.to_string())));
+    let stale = match remote {
+        Ok(remote) => gc::behind(&manifest, &remote),
+        Err(e) => Some(format!("couldn't read the remote manifest ({})", e)),
+    };
+    if let Some(reason) = stale {
+        if force || dry_run {
+            printcoln(Color::Yellow, format!("[{:.3}] Warning: the local manifest may be out of date, {}", t_start.elapsed().as_secs_f32(), reason));
+        } else {
+            printcoln(Color::Red, format!("[{:.3}] The local manifest may be out of date, {}", t_start.elapsed().as_secs_f32(), reason));
+            printcoln(Color::Red, format!("[{:.3}] Collecting by it could delete versions the remote manifest still refers to", t_start.elapsed().as_secs_f32()));
+            printcoln(Color::Yellow, "Run 'backup download' first to update it, or use --force to collect by the local manifest anyway");
+            return;
+        }
+    }
+
+    printcoln(Color::Yellow, format!("[{:.3}] Retrieving list of file versions, this may take a while...", t_start.elapsed().as_secs_f32()));
+    let versions = match backend.versions() {
+        Ok(v) => v,
+        Err(e) => {
+            printcoln(Color::Red, format!("[{:.3}] Failed to retrieve file versions ({})", t_start.elapsed().as_secs_f32(), e));
+            return;
+        }
+    };
+    summary.scanned(versions.len());
+    let version_count = versions.len();
+    let garbage = gc::garbage(versions, &manifest, config.retention);
+    summary.unchanged(version_count - garbage.len());
+    let reclaimable: u64 = garbage.iter().map(|v| v.content_length).sum();
+
+    if dry_run {
+        for version in &garbage {
+            printcoln(Color::White, format!("Would delete {} ({}, {})", &version.file_name,
+                                            datetime::format_millis(version.upload_timestamp), HumanBytes(version.content_length)));
+        }
+        printsummary(Color::Green, format!("[{:.3}] Dry run finished, {} of {} versions are unreferenced, {} can be reclaimed",
+                                           t_start.elapsed().as_secs_f32(), garbage.len(), version_count, HumanBytes(reclaimable)));
+        printsummary(Color::Green, format!("[{:.3}] API calls: {}", t_start.elapsed().as_secs_f32(), budget.summary()));
+        summary.finish(None);
+        return;
+    }
+
+    let removal_count = garbage.len();
+    let removals = garbage.into_iter().map(|v| (v.file_name, Some(v.file_id), "delete")).collect();
+    clean::remove(backend.as_ref(), removals, threads, max_attempts, &summary);
+
+    printsummary(Color::Green, format!("[{:.3}] API calls: {}", t_start.elapsed().as_secs_f32(), budget.summary()));
+    printsummary(Color::Green, format!("[{:.3}] Garbage collection finished ({} unreferenced versions, {})", t_start.elapsed().as_secs_f32(), removal_count, HumanBytes(reclaimable)));
+    summary.finish(None);
+}
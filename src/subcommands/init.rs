@@ -2,27 +2,54 @@ use crate::config::Config;
 use crate::colorutil::{printcoln, printcol};
 use termcolor::Color;
 use std::io::{stdin, Read, Write, BufRead};
-use raze::api::ListBucketParams;
+use raze::api::{B2Auth, ListBucketParams};
+use reqwest::blocking::Client;
 use rand::{thread_rng, Rng};
-use crate::manifest::FileManifest;
+use crate::manifest::{self, FileManifest};
+use crate::paths;
 use std::path::Path;
 use std::process::abort;
+use chacha20poly1305::Key;
+use crate::encryption::keys::Keys;
+use crate::encryption;
+use crate::secrets;
+use crate::b2;
+use crate::lifecycle;
+use crate::noecho::NoEcho;
+use clap::ArgMatches;
 
-pub fn init(config: &mut Config) {
+/// Sets up the config, the backup list, the manifest and the secret key
+/// Anything not given with a flag (or an environment override, see config.rs) is asked for, s.t. with all of them nothing is
+/// Values given up front are only tried once, s.t. a provisioning script fails instead of waiting for input
+pub fn init(config: &mut Config, args: Option<&ArgMatches>) {
+    let arg = |name: &str| args.and_then(|a| a.value_of(name)).map(|s| s.to_string());
     printcoln(Color::Yellow,"Welcome to the retain-rs setup util");
     printcoln(Color::Yellow,format!("Initializing config as {}",config.location));
     println!();
 
-    if Path::new("retain-rs-key").exists() {
-        printcoln(Color::Red, "Error: an old encryption key exists at 'retain-rs-key'");
+    // The key is kept next to the config, unless another key-file is given
+    // A given key-file that exists is used as the secret key, e.g. to set up another machine for the same bucket
+    let given_keyfile = arg("keyfile").or_else(|| config.secret_key.clone());
+    let key_path = given_keyfile.clone().unwrap_or_else(|| paths::default_key(&config.location));
+    // Only a key-file that isn't there is created, one that can't be checked (e.g. no permission) is an error
+    let existing_key = secrets::is_reference(&key_path) || match std::fs::metadata(&key_path) {
+        Ok(_) => true,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => false,
+        Err(e) => {
+            printcoln(Color::Red, format!("Error: the key '{}' can't be read ({})", key_path, e));
+            std::process::exit(1);
+        }
+    };
+    if given_keyfile.is_none() && existing_key {
+        printcoln(Color::Red, format!("Error: an old encryption key exists at '{}'", key_path));
         printcoln(Color::Red, "Manually back up the key before re-running init");
         printcoln(Color::Red, "Notice: 'init' is not intended to re-configure the program");
         printcoln(Color::Red, "If you want to change settings, use 'config' instead!");
-        panic!("retain-rs-key already exists! Aborting to avoid potentially overwriting secret key!");
+        panic!("{} already exists! Aborting to avoid potentially overwriting secret key!", key_path);
     }
-    if Path::new("manifest.json").exists() {
-        printcoln(Color::Red, "Warning: an old file manifest exists at 'manifest.json'");
-        printcoln(Color::Red, "Continuing will erase the current manifest.json file");
+    if Path::new(&manifest::local_path()).exists() {
+        printcoln(Color::Red, format!("Warning: an old file manifest exists at '{}'", manifest::local_path()));
+        printcoln(Color::Red, format!("Continuing will erase the current {} file", manifest::local_path()));
         printcoln(Color::Red, "This will cause desynchronization between local and remote");
         printcoln(Color::Red, "If encryption is on, file names for files in remote will be lost");
         printcoln(Color::Red, "Notice: 'init' is not intended to re-configure the program");
@@ -34,22 +61,36 @@ pub fn init(config: &mut Config) {
 
     let client = reqwest::blocking::Client::builder().timeout(None).build().unwrap();
     let mut auth = None;
+    let mut capabilities = vec![];
+    let mut given_id = arg("appkeyid").or_else(|| config.app_key_id.clone());
+    let mut given_key = arg("appkey").or_else(|| config.app_key.clone());
     loop {
-        printcol(Color::White,"App Key ID: ");
-        let appkeyid = stdin().lock().lines().next().unwrap().unwrap();
-        printcol(Color::White,"App Key: ");
-        let appkey = stdin().lock().lines().next().unwrap().unwrap();
+        let given = given_id.is_some() && given_key.is_some();
+        let appkeyid = given_id.take().unwrap_or_else(|| ask("App Key ID", "app-key-id"));
+        let appkey = given_key.take().unwrap_or_else(|| ask_secret("App Key", "app-key"));
         printcoln(Color::Yellow, "Trying to authenticate...");
 
-        let keystring = format!("{}:{}", appkeyid, appkey);
-        match raze::api::b2_authorize_account(&client,keystring) {
-            Ok(a) => {
+        // The App Key may refer to an environment variable
+        let keystring = match secrets::resolve(&appkey) {
+            Ok(key) => format!("{}:{}", appkeyid, key),
+            Err(e) => {
+                printcoln(Color::Red, e);
+                std::process::exit(1);
+            }
+        };
+        match b2::b2_authorize_account(&client, &keystring) {
+            Ok((a, allowed)) => {
                 auth = Some(a);
+                // Without them, a missing bucket isn't offered to be created
+                capabilities = allowed.capabilities;
                 config.app_key_id = Some(appkeyid);
                 config.app_key = Some(appkey);
             },
             Err(_e) => {
                 printcoln(Color::Red, format!("Authentication failure"));
+                if given {
+                    std::process::exit(1);
+                }
                 continue;
             },
         };
@@ -81,9 +122,12 @@ pub fn init(config: &mut Config) {
         },
     };
     printcoln(Color::Yellow, "Select which bucket to use");
+    let can_create = capabilities.iter().any(|c| c == "writeBuckets");
+    let create = args.map_or(false, |a| a.is_present("createbucket"));
+    let mut given_bucket = arg("bucket").or_else(|| config.bucket_name.clone());
     loop {
-        printcol(Color::White, "Bucket name: ");
-        let bucket = stdin().lock().lines().next().unwrap().unwrap();
+        let given = given_bucket.is_some();
+        let bucket = given_bucket.take().unwrap_or_else(|| ask("Bucket name", "bucket"));
         // Check if it's in the list of buckets we retrieved
         // If we got no buckets, make a new request to check the name is valid
         if buckets.len() == 0 {
@@ -102,24 +146,33 @@ pub fn init(config: &mut Config) {
                 config.bucket_name = Some(bucket);
                 printcoln(Color::Green, "OK");
                 break;
+            } else if can_create && create_bucket(&client, &auth, &bucket, config, create) {
+                config.bucket_name = Some(bucket);
+                break;
             } else {
                 printcoln(Color::Red, format!("'{}' does not appear to be a valid bucket", bucket));
                 printcoln(Color::Red, "Ensure you spelled it correctly and the auth has permission access it");
+                if given {
+                    std::process::exit(1);
+                }
             }
         } else { // If we got a list, check what was entered is an entry in that list
-            if buckets.iter().find(|e| e.bucket_name == bucket).is_some() {
+            let found = buckets.iter().find(|e| e.bucket_name == bucket).is_some();
+            if found || (can_create && create_bucket(&client, &auth, &bucket, config, create)) {
                 config.bucket_name = Some(bucket);
                 break;
             } else {
                 printcoln(Color::Red, format!("Could not find {} in list of available buckets", bucket));
+                if given {
+                    std::process::exit(1);
+                }
             }
         }
     }
 
     printcoln(Color::Yellow, "Enter where to store the backup-list file");
     printcoln(Color::Yellow, "This is where you tell what files to include and exclude");
-    printcol(Color::White, "Name: ");
-    let backuplist = stdin().lock().lines().next().unwrap().unwrap();
+    let backuplist = arg("list").unwrap_or_else(|| ask("Name", "list"));
     printcoln(Color::Yellow, format!("Backup file list location: {}", backuplist));
     if !Path::new(&backuplist).exists() {
         std::fs::write(&backuplist, "--- Backup definitions go here, refer to docs for more info ---").unwrap_or_else(|_|
@@ -132,35 +185,49 @@ pub fn init(config: &mut Config) {
     printcoln(Color::Yellow, "-----");
     printcoln(Color::Yellow, "Enable encryption?");
     printcoln(Color::Yellow, "Note that once enabled, you cannot disable it without re-uploading all files!");
-    printcoln(Color::Yellow, "If enabled, a file 'retain-rs-key' will be created");
+    if existing_key {
+        printcoln(Color::Yellow, format!("If enabled, the existing key '{}' is used", key_path));
+    } else {
+        printcoln(Color::Yellow, format!("If enabled, a file '{}' will be created", key_path));
+    }
     printcoln(Color::Yellow, "This is a SECRET key necessary to encrypt/decrypt your data");
     printcoln(Color::Yellow, "You MUST store this file somewhere safe -- If lost, your data cannot be decrypted");
+    let mut given_encrypt = arg("encrypt").map(|s| if s.eq_ignore_ascii_case("on") { "y" } else { "n" }.to_string());
     loop {
-        printcol(Color::White, "Enable encryption? (y/n): ");
-        let encrypt = stdin().lock().lines().next().unwrap().unwrap();
+        let encrypt = given_encrypt.take().unwrap_or_else(|| ask("Enable encryption? (y/n)", "encrypt"));
         match encrypt.as_ref() {
             "y" => {
                 printcoln(Color::Green, "Encryption is ON");
-                FileManifest {
-                    mask: true,
-                    files: vec![]
-                }.to_file("manifest.json").unwrap();
                 config.encrypt = Some(true);
-                config.secret_key = Some("retain-rs-key".to_string());
-                // Generate key
-                let mut rng = thread_rng();
-                let mut key_bytes = [0u8; 32];
-                rng.try_fill(&mut key_bytes).expect("Failed to generate key");
-                std::fs::write("retain-rs-key", key_bytes).expect("Failed to save key");
+                config.secret_key = Some(key_path.clone());
+                let key = if existing_key {
+                    match encryption::key_from_file(&key_path) {
+                        Ok(key) => key,
+                        Err(e) => {
+                            printcoln(Color::Red, format!("Error: the key '{}' can't be used ({})", key_path, e));
+                            std::process::exit(1);
+                        }
+                    }
+                } else {
+                    // Generate key
+                    let mut rng = thread_rng();
+                    let mut key_bytes = [0u8; 32];
+                    rng.try_fill(&mut key_bytes).expect("Failed to generate key");
+                    if let Err(e) = paths::create_parent(&key_path).and_then(|_| std::fs::write(&key_path, key_bytes)) {
+                        printcoln(Color::Red, format!("Error: the key can't be saved to '{}' ({})", key_path, e));
+                        std::process::exit(1);
+                    }
+                    Key::clone_from_slice(&key_bytes)
+                };
+                // The new manifest is authenticated with the key
+                manifest::set_mac_keys(&Keys::new(key, vec![]), false, false);
+                FileManifest::new(true).to_file(manifest::local_path()).unwrap();
                 break;
             },
             "n" => {
                 printcoln(Color::Yellow, "Encryption is OFF");
                 config.encrypt = Some(false);
-                FileManifest {
-                    mask: false,
-                    files: vec![]
-                }.to_file("manifest.json").unwrap();
+                FileManifest::new(false).to_file(manifest::local_path()).unwrap();
                 break;
             }
             _ => {
@@ -172,4 +239,54 @@ pub fn init(config: &mut Config) {
     config.save();
     printcoln(Color::Green, "Init completed!");
     printcoln(Color::Green, "Populate the backup list file and start uploading");
-}
\ No newline at end of file
+}
+
+// Offers to create the bucket 'name', private and deleting hidden versions as 'bucket lifecycle --recommended' would
+// With --create-bucket it is created without asking. Returns whether it was created
+fn create_bucket(client: &Client, auth: &B2Auth, name: &str, config: &Config, create: bool) -> bool {
+    let days = lifecycle::recommended_days(config.retention);
+    if !create {
+        printcoln(Color::Yellow, format!("There is no bucket named '{}' yet, it can be created now", name));
+        printcoln(Color::Yellow, format!("It will be private, and hidden file versions will be deleted after {} days (see 'bucket lifecycle')", days));
+        if ask("Create it? (y/n)", "create-bucket") != "y" {
+            return false;
+        }
+    }
+    let rules = lifecycle::with_hidden_days(vec![], Some(days));
+    match b2::b2_create_bucket(client, auth, name, &rules) {
+        Ok(bucket) => {
+            printcoln(Color::Green, format!("Created bucket {}", bucket.bucket_name));
+            true
+        },
+        Err(e) => {
+            printcoln(Color::Red, format!("Failed to create bucket '{}' ({})", name, e));
+            false
+        },
+    }
+}
+
+// Asks for a value on stdin
+// Exits if there is no input (e.g. in a script), naming the flag to give it with instead
+fn ask(label: &str, flag: &str) -> String {
+    ask_with(label, flag, false)
+}
+
+// Like ask, without showing what is typed
+fn ask_secret(label: &str, flag: &str) -> String {
+    ask_with(label, flag, true)
+}
+
+fn ask_with(label: &str, flag: &str, hidden: bool) -> String {
+    printcol(Color::White, format!("{}: ", label));
+    let echo = if hidden { NoEcho::start() } else { None };
+    let line = stdin().lock().lines().next();
+    drop(echo);
+    match line {
+        Some(Ok(line)) => line.trim().to_string(),
+        _ => {
+            println!();
+            printcoln(Color::Red, format!("Error: no input, give it with --{} when not running interactively", flag));
+            std::process::exit(1);
+        }
+    }
+}
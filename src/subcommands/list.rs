@@ -0,0 +1,98 @@
+use clap::ArgMatches;
+use crate::colorutil::printcoln;
+use termcolor::Color;
+use crate::manifest::{self, FileManifest, FileEntry};
+use crate::pattern::PathPattern;
+use crate::datetime;
+use crate::subcommands::snapshot;
+use indicatif::HumanBytes;
+
+/// Prints the files tracked by the local manifest, optionally filtered by path and modified time
+/// Can also list the recorded snapshots, or the files as they were in one of them
+pub fn list(args: Option<&ArgMatches>) {
+    let args = args.unwrap();
+
+    let patterns = match args.values_of("path") {
+        Some(values) => match values.map(PathPattern::parse).collect::<Result<Vec<PathPattern>, String>>() {
+            Ok(p) => p,
+            Err(e) => {
+                printcoln(Color::Red, e);
+                return;
+            }
+        },
+        None => vec![],
+    };
+    let after = match args.value_of("after").map(datetime::parse_datetime).transpose() {
+        Ok(t) => t,
+        Err(e) => {
+            printcoln(Color::Red, e);
+            return;
+        }
+    };
+    let before = match args.value_of("before").map(datetime::parse_datetime).transpose() {
+        Ok(t) => t,
+        Err(e) => {
+            printcoln(Color::Red, e);
+            return;
+        }
+    };
+
+    let as_of = match args.value_of("asof").map(datetime::parse_datetime).transpose() {
+        Ok(t) => t,
+        Err(e) => {
+            printcoln(Color::Red, e);
+            return;
+        }
+    };
+
+    let manifest = match FileManifest::from_file(manifest::local_path()) {
+        Ok(fm) => fm,
+        Err(err) => {
+            printcoln(Color::Red, format!("Failed to load file manifest ({})", err));
+            return;
+        }
+    };
+
+    if args.is_present("snapshots") {
+        snapshot::print(&manifest);
+        return;
+    }
+
+    // The files as they were in the latest snapshot at the given time, or the current ones
+    let files = match as_of {
+        Some(t) => match manifest.snapshot_as_of(t) {
+            Some(snapshot) => {
+                printcoln(Color::Yellow, format!("Snapshot {} from {}", snapshot.generation, datetime::format_millis(snapshot.time)));
+                manifest.snapshot_files(snapshot.generation)
+            }
+            None => {
+                printcoln(Color::Red, format!("No snapshot was recorded at or before {}", datetime::format_millis(t)));
+                return;
+            }
+        },
+        None => manifest.files,
+    };
+
+    let matches = |entry: &FileEntry| {
+        (patterns.is_empty() || patterns.iter().any(|p| p.matches(&entry.path)))
+            && after.map_or(true, |t| entry.timestamp > t)
+            && before.map_or(true, |t| entry.timestamp <= t)
+    };
+
+    let mut count = 0;
+    let mut total_size = 0;
+    for entry in files.iter().filter(|e| matches(e)) {
+        println!("{}", entry.path);
+        println!("\tSize: {}\tModified: {}\tUploaded: {}",
+                 HumanBytes(entry.size),
+                 datetime::format_millis(entry.timestamp),
+                 if entry.uploaded == 0 { "unknown".to_string() } else { datetime::format_millis(entry.uploaded) });
+        match &entry.link {
+            Some(target) => println!("\tHard link to: {}", target),
+            None => println!("\tRemote: {}", entry.mask),
+        }
+        count += 1;
+        total_size += entry.size;
+    }
+    printcoln(Color::Green, format!("{} of {} files, {} in total", count, files.len(), HumanBytes(total_size)));
+}
@@ -0,0 +1,216 @@
+use crate::config::Config;
+use clap::ArgMatches;
+use crate::colorutil::printcoln;
+use termcolor::Color;
+use crate::encryption::keys::Keys;
+use raze::api::{B2Auth, ListBucketParams};
+use reqwest::blocking::Client;
+use std::time::Duration;
+use std::error::Error;
+use indicatif::HumanBytes;
+use crate::manifest::{self, FileManifest};
+use crate::subcommands::backup::download::read_manifest;
+use crate::budget::Budget;
+use crate::versions;
+use crate::jobs;
+use crate::b2;
+use crate::datetime;
+use crate::backend;
+use crate::filelist;
+use crate::rebuild;
+use std::sync::Arc;
+
+/// Lists the versions of the remote manifest, or restores an older one as the local manifest
+/// A restored manifest replaces the remote one the next time the manifest is synced
+/// If every version is lost, 'manifest rebuild' records the files the bucket still holds unchanged (see rebuild.rs)
+pub fn manifest(config: &Config, args: Option<&ArgMatches>) {
+    let args = args.unwrap();
+    match config.is_configured() {
+        Ok(_) => (),
+        Err(err) => {
+            printcoln(Color::Red, format!("Invalid config ({})", err));
+            return;
+        }
+    }
+    if args.value_of("action").unwrap().eq_ignore_ascii_case("rebuild") {
+        rebuild(config);
+        return;
+    }
+    // The simulated bucket keeps no history, and the real one must not be touched
+    if config.simulate.is_some() {
+        printcoln(Color::Red, "The manifest history can't be simulated, run 'manifest history' and 'manifest restore' without --simulate");
+        return;
+    }
+    if config.s3.is_some() || config.local_dir.is_some() {
+        printcoln(Color::Red, "The manifest history needs the native B2 API, old versions can't be looked up on S3-compatible storage or a local directory");
+        return;
+    }
+    let restore = args.value_of("action").unwrap().eq_ignore_ascii_case("restore");
+    let as_of = match args.value_of("asof").map(datetime::parse_datetime).transpose() {
+        Ok(t) => t,
+        Err(e) => {
+            printcoln(Color::Red, e);
+            return;
+        }
+    };
+    let id = args.value_of("id");
+    if restore && as_of.is_none() && id.is_none() {
+        printcoln(Color::Red, "Choose which manifest to restore with --as-of or --id, see 'manifest history'");
+        return;
+    }
+
+    let keys = match config.encrypt.unwrap() {
+        true => match Keys::from_config(config) {
+            Ok(k) => Some(k),
+            Err(err) => {
+                printcoln(Color::Red, err);
+                return;
+            }
+        },
+        false => None,
+    };
+
+    let budget = Budget::new(config);
+    let client = reqwest::blocking::Client::builder().timeout(Duration::from_secs(60)).build().unwrap();
+    let (auth, bucket_id) = match connect(&client, &budget, config) {
+        Ok(v) => v,
+        Err(e) => {
+            printcoln(Color::Red, e);
+            return;
+        }
+    };
+
+    let versions = match versions::list_versions_of(&client, &auth, &budget, &bucket_id, &jobs::remote_name(config, manifest::REMOTE_MANIFEST)) {
+        Ok(v) => v,
+        Err(e) => {
+            printcoln(Color::Red, format!("Failed to retrieve manifest versions ({})", e));
+            return;
+        }
+    };
+
+    if !restore {
+        for version in &versions {
+            println!("{}\t{}\t{}", datetime::format_millis(version.upload_timestamp), HumanBytes(version.content_length), version.file_id);
+        }
+        printcoln(Color::Green, format!("{} versions of the manifest are kept", versions.len()));
+        return;
+    }
+
+    // Versions are sorted newest first
+    let version = match (id, as_of) {
+        (Some(id), _) => versions.iter().find(|v| v.file_id == id),
+        (None, Some(t)) => versions.iter().find(|v| v.upload_timestamp <= t),
+        (None, None) => unreachable!(),
+    };
+    let version = match version {
+        Some(v) => v,
+        None => {
+            printcoln(Color::Red, "No such version of the manifest, see 'manifest history'");
+            return;
+        }
+    };
+
+    printcoln(Color::Green, format!("Restoring manifest uploaded at {}", datetime::format_millis(version.upload_timestamp)));
+    let restored = match download_version(&client, &auth, &budget, &version.file_id, keys.as_ref()) {
+        Ok(m) => m,
+        Err(e) => {
+            printcoln(Color::Red, format!("Failed to retrieve manifest ({})", e));
+            return;
+        }
+    };
+
+    // Keep the current local manifest as manifest.json.old
+    if let Ok(old) = FileManifest::from_file(manifest::local_path()) {
+        if let Err(err) = old.to_file(manifest::old_path()) {
+            printcoln(Color::Red, format!("Failed to back up current manifest, not restoring ({})", err));
+            return;
+        }
+    }
+    match restored.to_file(manifest::local_path()) {
+        Ok(_) => {
+            printcoln(Color::Green, format!("Restored {} entries, the previous manifest was kept as {}", restored.files.len(), manifest::old_path()));
+            printcoln(Color::Yellow, "Run 'backup upload' to sync it to remote, or 'clean' to remove remote files it doesn't track");
+        },
+        Err(err) => printcoln(Color::Red, format!("Failed to save manifest ({})", err)),
+    }
+}
+
+// Rebuilds the local manifest from the files in the bucket, s.t. the next upload skips what is already there
+fn rebuild(config: &Config) {
+    let t_start = std::time::Instant::now();
+    if config.encrypt == Some(true) {
+        printcoln(Color::Red, "Only unencrypted backups can be rebuilt, encrypted files are stored under masked names");
+        printcoln(Color::Yellow, "Restore an earlier version of the manifest with 'manifest restore' instead");
+        return;
+    }
+    if let Err(e) = filelist::verify_structure(config.backup_list.as_ref().unwrap()) {
+        printcoln(Color::Red, format!("Backup list is invalid: {}", e));
+        return;
+    }
+    printcoln(Color::Green, format!("[{:.3}] Building list of files...", t_start.elapsed().as_secs_f32()));
+    let files = filelist::build_file_list(config.backup_list.as_ref().unwrap());
+    printcoln(Color::Green, format!("[{:.3}] Complete ({} files)", t_start.elapsed().as_secs_f32(), files.len()));
+
+    let backend = match backend::connect(config, Arc::new(Budget::new(config)), t_start) {
+        Ok(b) => b,
+        Err(e) => {
+            printcoln(Color::Red, format!("[{:.3}] {}", t_start.elapsed().as_secs_f32(), e));
+            return;
+        }
+    };
+    printcoln(Color::Yellow, format!("[{:.3}] Retrieving list of remote files, this may take a while...", t_start.elapsed().as_secs_f32()));
+    let remote = match backend.list() {
+        Ok(f) => f,
+        Err(e) => {
+            printcoln(Color::Red, format!("[{:.3}] Failed to retrieve file list ({})", t_start.elapsed().as_secs_f32(), e));
+            return;
+        },
+    };
+    printcoln(Color::Green, format!("[{:.3}] Comparing {} local files with {} remote files, files are hashed where the storage records a SHA1",
+                                    t_start.elapsed().as_secs_f32(), files.len(), remote.len()));
+    let rebuilt = rebuild::rebuild(&files, &remote);
+
+    // Keep the current local manifest as manifest.json.old, as restoring does
+    if let Ok(old) = FileManifest::from_file(manifest::local_path()) {
+        if let Err(err) = old.to_file(manifest::old_path()) {
+            printcoln(Color::Red, format!("Failed to back up current manifest, not rebuilding ({})", err));
+            return;
+        }
+    }
+    match rebuilt.manifest.to_file(manifest::local_path()) {
+        Ok(_) => {
+            printcoln(Color::Green, format!("[{:.3}] Rebuilt the manifest: {} files are backed up unchanged, {} will be uploaded again",
+                                            t_start.elapsed().as_secs_f32(), rebuilt.matched, rebuilt.unmatched));
+            printcoln(Color::Yellow, "Run 'backup upload' to upload the rest and the rebuilt manifest");
+        },
+        Err(err) => printcoln(Color::Red, format!("Failed to save manifest ({})", err)),
+    }
+}
+
+// Downloads and decrypts a version of the remote manifest
+fn download_version(client: &Client, auth: &B2Auth, budget: &Budget, file_id: &str, keys: Option<&Keys>) -> Result<FileManifest, Box<dyn Error>> {
+    budget.spend("b2_download_file_by_id")?;
+    let response = b2::b2_download_file_by_id(client, auth, file_id)?;
+    read_manifest(response, keys)
+}
+
+// Authenticates and resolves the configured bucket name to its id
+fn connect(client: &Client, budget: &Budget, config: &Config) -> Result<(B2Auth, String), String> {
+    let keystring = config.keystring()?;
+    budget.spend("b2_authorize_account").map_err(|e| format!("Aborting: {}", e))?;
+    let auth = raze::api::b2_authorize_account(client, keystring)
+        .map_err(|_| "Authentication failure".to_string())?;
+
+    let params = ListBucketParams {
+        bucket_id: None,
+        bucket_name: Some(config.bucket_name.as_ref().unwrap().to_string()),
+        bucket_types: None
+    };
+    budget.spend("b2_list_buckets").map_err(|e| format!("Aborting: {}", e))?;
+    let buckets = raze::api::b2_list_buckets(client, &auth, params)
+        .map_err(|e| format!("Failed to retrieve bucket list ({:?})", e))?;
+    match buckets.get(0) {
+        Some(res) => Ok((auth, res.bucket_id.clone())),
+        None => Err(format!("No bucket with the name '{}'", config.bucket_name.as_ref().unwrap())),
+    }
+}
@@ -1,6 +1,9 @@
 mod configure;
 pub use configure::configure;
 
+mod edit;
+mod validate;
+
 mod status;
 pub use status::status;
 
@@ -13,4 +16,49 @@ pub mod init;
 pub use init::init;
 
 pub mod clean;
-pub use clean::clean;
\ No newline at end of file
+pub use clean::clean;
+
+pub mod gc;
+pub use gc::gc;
+
+pub mod orphans;
+pub use orphans::orphans;
+
+pub mod repair;
+pub use repair::repair;
+
+pub mod list;
+pub use list::list;
+
+pub mod search;
+pub use search::search;
+
+pub mod browse;
+pub use browse::browse;
+
+pub mod export;
+pub use export::export;
+
+pub mod snapshot;
+pub use snapshot::snapshot;
+
+pub mod stats;
+pub use stats::stats;
+
+pub mod diff;
+pub use diff::diff;
+
+pub mod watch;
+pub use watch::watch;
+
+pub mod daemon;
+pub use daemon::daemon;
+
+pub mod manifest;
+pub use manifest::manifest;
+
+pub mod rules;
+pub use rules::rules;
+
+pub mod bucket;
+pub use bucket::bucket;
@@ -0,0 +1,80 @@
+use crate::config::Config;
+use crate::colorutil::printcoln;
+use termcolor::Color;
+use std::sync::Arc;
+use std::path::Path;
+use indicatif::HumanBytes;
+use crate::manifest;
+use crate::budget::Budget;
+use crate::backend;
+use crate::gc;
+
+/// Lists what is out of sync between the local files, the manifest and remote, without changing anything
+/// These are what 'clean' fixes: remote files the manifest doesn't refer to, entries whose remote file is missing
+/// and entries whose local file is gone
+pub fn orphans(config: &Config) {
+    let t_start = std::time::Instant::now();
+    if let Err(err) = config.is_configured() {
+        printcoln(Color::Red, format!("Invalid config ({})", err));
+        return;
+    }
+
+    let manifest = match manifest::FileManifest::from_file(manifest::local_path()) {
+        Ok(fm) => fm,
+        Err(err) => {
+            printcoln(Color::Red, format!("[{:.3}] Failed to load file manifest ({})", t_start.elapsed().as_secs_f32(), err));
+            return;
+        }
+    };
+    let backend = match backend::connect(config, Arc::new(Budget::new(config)), t_start) {
+        Ok(b) => b,
+        Err(e) => {
+            printcoln(Color::Red, format!("[{:.3}] {}", t_start.elapsed().as_secs_f32(), e));
+            return;
+        }
+    };
+    printcoln(Color::Yellow, format!("[{:.3}] Retrieving list of remote files, this may take a while...", t_start.elapsed().as_secs_f32()));
+    let remote_files = match backend.list() {
+        Ok(f) => f,
+        Err(e) => {
+            printcoln(Color::Red, format!("[{:.3}] Failed to retrieve file list ({})", t_start.elapsed().as_secs_f32(), e));
+            return;
+        },
+    };
+
+    // Past versions kept for snapshots refer to remote files as well
+    let referenced = gc::referenced_names(&manifest);
+    let unreferenced: Vec<_> = remote_files.iter()
+        .filter(|f| f.name != manifest::REMOTE_MANIFEST && !referenced.contains(&f.name))
+        .collect();
+    println!("Remote files not referenced by the manifest:");
+    for file in &unreferenced {
+        println!("\t{} ({})", file.name, file.size.map_or("unknown size".to_string(), |s| HumanBytes(s).to_string()));
+    }
+
+    // Hard links only exist in the manifest
+    let missing_remote: Vec<_> = manifest.files.iter()
+        .filter(|e| e.link.is_none() && remote_files.binary_search_by(|f| f.name.cmp(&e.mask)).is_err())
+        .collect();
+    println!("Manifest entries without a remote file:");
+    for entry in &missing_remote {
+        println!("\t{} (remote: {})", entry.path, entry.mask);
+    }
+
+    let missing_local: Vec<_> = manifest.files.iter().filter(|e| !Path::new(&e.path).exists()).collect();
+    println!("Manifest entries whose local file is gone:");
+    for entry in &missing_local {
+        println!("\t{}", entry.path);
+    }
+
+    let color = match unreferenced.len() + missing_remote.len() + missing_local.len() {
+        0 => Color::Green,
+        _ => Color::Yellow,
+    };
+    printcoln(color, format!("[{:.3}] {} unreferenced remote files ({}), {} entries without a remote file, {} entries without a local file",
+                             t_start.elapsed().as_secs_f32(), unreferenced.len(), HumanBytes(unreferenced.iter().filter_map(|f| f.size).sum()),
+                             missing_remote.len(), missing_local.len()));
+    if !unreferenced.is_empty() || !missing_local.is_empty() {
+        printcoln(Color::Yellow, "'clean' removes the unreferenced remote files and the entries of files that are gone locally");
+    }
+}
@@ -0,0 +1,106 @@
+use crate::config::Config;
+use clap::ArgMatches;
+use crate::colorutil::printcoln;
+use termcolor::Color;
+use std::sync::Arc;
+use crate::manifest::{self, FileManifest};
+use crate::budget::Budget;
+use crate::backend::{self, Backend};
+use crate::encryption::keys::Keys;
+use crate::rebuild;
+use crate::subcommands::backup::download::read_manifest;
+
+/// Reconstructs the local manifest from the files in the bucket when it is lost or corrupt, see rebuild::repair
+/// A manifest that still loads, locally or in remote, is only replaced with --force. Whatever was there is kept as manifest.json.old
+pub fn repair(config: &Config, args: Option<&ArgMatches>) {
+    let t_start = std::time::Instant::now();
+    let args = args.unwrap();
+    if let Err(err) = config.is_configured() {
+        printcoln(Color::Red, format!("Invalid config ({})", err));
+        return;
+    }
+    let local_path = manifest::local_path();
+    let exists = std::path::Path::new(&local_path).exists();
+    match FileManifest::from_file(&local_path) {
+        Ok(fm) if !args.is_present("force") => {
+            printcoln(Color::Red, format!("The local manifest is intact ({} entries), use --force to replace it anyway", fm.files.len()));
+            printcoln(Color::Yellow, "To go back to an earlier version of it, see 'manifest history' and 'manifest restore'");
+            return;
+        },
+        Err(err) if exists => printcoln(Color::Yellow, format!("[{:.3}] The local manifest can't be loaded ({})", t_start.elapsed().as_secs_f32(), err)),
+        _ => (),
+    }
+    let masked = config.encrypt.unwrap();
+    let lost_dir = match args.value_of("lostdir") {
+        Some(dir) => dir.to_string(),
+        None => match std::env::current_dir() {
+            Ok(dir) => dir.join("retain-lost").to_string_lossy().to_string(),
+            Err(e) => {
+                printcoln(Color::Red, format!("Failed to get the current directory ({}), use --lost-dir", e));
+                return;
+            }
+        },
+    };
+
+    let budget = Arc::new(Budget::new(config));
+    let backend = match backend::connect(config, budget.clone(), t_start) {
+        Ok(b) => b,
+        Err(e) => {
+            printcoln(Color::Red, format!("[{:.3}] {}", t_start.elapsed().as_secs_f32(), e));
+            return;
+        }
+    };
+    // The remote manifest still knows every path, the files in the bucket don't
+    if !args.is_present("force") {
+        if let Some(remote) = remote_manifest(config, backend.as_ref(), &budget) {
+            printcoln(Color::Red, format!("[{:.3}] The remote manifest is intact ({} entries), use --force to repair from the files in the bucket anyway",
+                                          t_start.elapsed().as_secs_f32(), remote.files.len()));
+            printcoln(Color::Yellow, "Run 'backup download' to restore the files it records, or see 'manifest history' and 'manifest restore' to make it the local manifest");
+            return;
+        }
+    }
+    printcoln(Color::Yellow, format!("[{:.3}] Retrieving list of remote files, this may take a while...", t_start.elapsed().as_secs_f32()));
+    let remote = match backend.list() {
+        Ok(f) => f,
+        Err(e) => {
+            printcoln(Color::Red, format!("[{:.3}] Failed to retrieve file list ({})", t_start.elapsed().as_secs_f32(), e));
+            return;
+        },
+    };
+    let repaired = rebuild::repair(&remote, masked, &lost_dir);
+
+    // Keep what was there as manifest.json.old, as is if it can't be loaded
+    if exists {
+        let kept = match FileManifest::from_file(&local_path) {
+            Ok(old) => old.to_file(manifest::old_path()).map_err(|e| e.to_string()),
+            Err(_) => std::fs::copy(&local_path, manifest::old_path()).map(|_| ()).map_err(|e| e.to_string()),
+        };
+        if let Err(err) = kept {
+            printcoln(Color::Red, format!("Failed to back up current manifest, not repairing ({})", err));
+            return;
+        }
+    }
+    match repaired.manifest.to_file(&local_path) {
+        Ok(_) => {
+            printcoln(Color::Green, format!("[{:.3}] Repaired the manifest from {} remote files: {} entries with their original path, {} under {}",
+                                            t_start.elapsed().as_secs_f32(), remote.len(), repaired.named, repaired.lost, lost_dir));
+            if repaired.lost > 0 {
+                printcoln(Color::Yellow, format!("Names are masked, so original paths are lost. 'backup download' restores those files to {}", lost_dir));
+                printcoln(Color::Yellow, "Until they are downloaded, 'clean' sees them as missing locally and removes their backups, see 'clean --older-than'");
+            }
+            printcoln(Color::Yellow, "Run 'backup download' to restore the files, the next 'backup upload' replaces the remote manifest with the repaired one");
+        },
+        Err(err) => printcoln(Color::Red, format!("Failed to save manifest ({})", err)),
+    }
+}
+
+// The remote manifest, if it can be downloaded and loaded
+fn remote_manifest(config: &Config, backend: &dyn Backend, budget: &Budget) -> Option<FileManifest> {
+    let keys = match config.encrypt.unwrap() {
+        true => Some(Keys::from_config(config).ok()?),
+        false => None,
+    };
+    budget.spend(backend.download_call(false)).ok()?;
+    let response = backend.download(manifest::REMOTE_MANIFEST, None).ok()?;
+    read_manifest(response.body, keys.as_ref()).ok()
+}
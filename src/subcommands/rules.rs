@@ -0,0 +1,171 @@
+use crate::config::Config;
+use clap::ArgMatches;
+use crate::colorutil::printcoln;
+use termcolor::Color;
+use crate::filelist;
+use crate::paths;
+use std::path::Path;
+
+/// Shows, tests and edits the rules of the backup list
+/// 'rules test PATH' explains whether PATH is uploaded, and which path and filter in the backup list decide it
+/// 'rules list', 'rules add' and 'rules remove' show and change the paths and filters, keeping the rest of the file as written
+pub fn rules(config: &Config, args: Option<&ArgMatches>) {
+    let backup_list = match &config.backup_list {
+        Some(list) => list,
+        None => {
+            printcoln(Color::Red, "File List Path is missing");
+            return;
+        }
+    };
+    match args.map(|a| a.subcommand()) {
+        Some(("test", Some(test_args))) => {
+            if let Err(e) = filelist::verify_structure(backup_list) {
+                printcoln(Color::Red, format!("Backup list is invalid: {}", e));
+                return;
+            }
+            test(backup_list, test_args.value_of("path").unwrap());
+        },
+        Some(("list", _)) => list(backup_list),
+        Some(("add", Some(add_args))) => add(backup_list, add_args),
+        Some(("remove", Some(remove_args))) => remove(backup_list, remove_args),
+        _ => printcoln(Color::White, "Nothing to do, see 'rules -h'"),
+    }
+}
+
+fn list(backup_list: &str) {
+    let text = match std::fs::read_to_string(backup_list) {
+        Ok(t) => t,
+        Err(e) => {
+            printcoln(Color::Red, format!("Failed to read the backup list ({})", e));
+            return;
+        }
+    };
+    let entries = filelist::entries(&text);
+    if entries.is_empty() {
+        printcoln(Color::White, format!("The backup list {} is empty, add a path with 'rules add PATH'", backup_list));
+    }
+    for (root, lines) in entries {
+        printcoln(Color::White, root);
+        for line in lines {
+            println!("    {}", line);
+        }
+    }
+}
+
+// Adds the path, and any filters, to the backup list
+// Nothing is written if any of them is invalid
+fn add(backup_list: &str, args: &ArgMatches) {
+    // Walks don't depend on the working directory, e.g. when run from cron
+    let root = absolute(args.value_of("path").unwrap());
+    // A list that doesn't exist yet is created
+    let mut text = std::fs::read_to_string(backup_list).unwrap_or_default();
+    let filters: Vec<&str> = args.values_of("filter").map_or(Vec::new(), |v| v.collect());
+    let mut added = Vec::new();
+    if filelist::entries(&text).iter().all(|(r, _)| Path::new(r) != Path::new(&root)) {
+        text = match filelist::add_entry(&text, &root, None) {
+            Ok(t) => t,
+            Err(e) => {
+                printcoln(Color::Red, e);
+                return;
+            }
+        };
+        added.push(format!("Added {}", root));
+    } else if filters.is_empty() {
+        printcoln(Color::Red, format!("{} is already in the backup list", root));
+        return;
+    }
+    for filter in filters {
+        text = match filelist::add_entry(&text, &root, Some(filter)) {
+            Ok(t) => t,
+            Err(e) => {
+                printcoln(Color::Red, e);
+                return;
+            }
+        };
+        added.push(format!("Added '{}' under {}", filter.trim(), root));
+    }
+    match paths::write_atomic(backup_list, &text) {
+        Ok(_) => added.iter().for_each(|a| printcoln(Color::Green, a)),
+        Err(e) => printcoln(Color::Red, format!("Failed to write the backup list ({})", e)),
+    }
+}
+
+// Removes the path, or only one of its filters, from the backup list after asking to confirm
+fn remove(backup_list: &str, args: &ArgMatches) {
+    let text = match std::fs::read_to_string(backup_list) {
+        Ok(t) => t,
+        Err(e) => {
+            printcoln(Color::Red, format!("Failed to read the backup list ({})", e));
+            return;
+        }
+    };
+    let path = args.value_of("path").unwrap();
+    let filter = args.value_of("filter");
+    let result = filelist::remove_entry(&text, path, filter)
+        .or_else(|e| if Path::new(path).is_relative() { filelist::remove_entry(&text, &absolute(path), filter) } else { Err(e) });
+    let (text, removed) = match result {
+        Ok(r) => r,
+        Err(e) => {
+            printcoln(Color::Red, e);
+            return;
+        }
+    };
+    printcoln(Color::White, format!("Removes from {}:", backup_list));
+    for line in &removed {
+        println!("    {}", line);
+    }
+    if !args.is_present("yes") && super::edit::confirm("Remove these lines?") != Some(true) {
+        printcoln(Color::Yellow, "Nothing was changed");
+        return;
+    }
+    match paths::write_atomic(backup_list, &text) {
+        Ok(_) => printcoln(Color::Green, format!("Removed {} lines", removed.len())),
+        Err(e) => printcoln(Color::Red, format!("Failed to write the backup list ({})", e)),
+    }
+}
+
+fn absolute(path: &str) -> String {
+    match std::env::current_dir() {
+        Ok(dir) if Path::new(path).is_relative() => dir.join(path).to_string_lossy().to_string(),
+        _ => path.to_string(),
+    }
+}
+
+fn test(backup_list: &str, path: &str) {
+    let mut explained = match filelist::explain(backup_list, path) {
+        Ok(e) => e,
+        Err(e) => {
+            printcoln(Color::Red, format!("Failed to read the backup list ({})", e));
+            return;
+        }
+    };
+    // The paths in the backup list are usually absolute, so try that if a relative path isn't in any of them
+    let mut path = path.to_string();
+    if explained.is_empty() && Path::new(&path).is_relative() {
+        path = absolute(&path);
+        explained = filelist::explain(backup_list, &path).unwrap_or_default();
+    }
+
+    println!("{}", path);
+    for e in &explained {
+        print!("In {}: \t", e.root);
+        match (e.included, &e.rule) {
+            (true, Some(rule)) => printcoln(Color::Green, format!("included by '{}'", rule)),
+            (true, None) => printcoln(Color::Green, "included, no filter matches it"),
+            (false, Some(rule)) => printcoln(Color::Red, format!("excluded by '{}'", rule)),
+            (false, None) => printcoln(Color::Red, "excluded"),
+        }
+    }
+
+    if explained.is_empty() {
+        printcoln(Color::Red, "Not backed up, it is not in any path in the backup list");
+    } else if explained.iter().any(|e| e.included) {
+        match std::fs::metadata(&path) {
+            Ok(m) if m.is_file() => printcoln(Color::Green, "Backed up"),
+            Ok(_) => printcoln(Color::Yellow, "Backed up if it was a file, only files are uploaded"),
+            Err(_) => printcoln(Color::Yellow, "Backed up if it existed, it doesn't exist or can't be read"),
+        }
+    } else {
+        printcoln(Color::Red, "Not backed up");
+    }
+}
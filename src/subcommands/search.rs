@@ -0,0 +1,68 @@
+use clap::ArgMatches;
+use crate::colorutil::{printcoln, printcol};
+use termcolor::Color;
+use crate::manifest::{self, FileManifest, FileEntry};
+use regex::Regex;
+
+/// State of the local copy of a backed up file
+enum LocalState {
+    // Same modified time and size as when it was backed up
+    Matches,
+    // Changed since it was backed up
+    Modified,
+    Missing,
+}
+
+/// Finds backed up files whose path or remote name matches a regex
+/// Remote names are resolved to the original path, and the state of each local copy is shown
+pub fn search(args: Option<&ArgMatches>) {
+    let args = args.unwrap();
+    let regex = match Regex::new(args.value_of("regex").unwrap()) { // Required by clap
+        Ok(r) => r,
+        Err(e) => {
+            printcoln(Color::Red, format!("Invalid regex ({})", e));
+            return;
+        }
+    };
+
+    let manifest = match FileManifest::from_file(manifest::local_path()) {
+        Ok(fm) => fm,
+        Err(err) => {
+            printcoln(Color::Red, format!("Failed to load file manifest ({})", err));
+            return;
+        }
+    };
+
+    let mut count = 0;
+    for entry in manifest.files.iter().filter(|e| regex.is_match(&e.path) || regex.is_match(&e.mask)) {
+        match local_state(entry) {
+            LocalState::Matches => printcol(Color::Green, "[ok]       "),
+            LocalState::Modified => printcol(Color::Yellow, "[modified] "),
+            LocalState::Missing => printcol(Color::Red, "[missing]  "),
+        }
+        println!("{}", entry.path);
+        // Unmasked, the remote name only differs from the path in its form
+        if manifest.mask {
+            println!("\t-> {}", entry.mask);
+        }
+        count += 1;
+    }
+    printcoln(Color::Green, format!("{} of {} files match", count, manifest.files.len()));
+}
+
+fn local_state(entry: &FileEntry) -> LocalState {
+    let metadata = match std::fs::metadata(&entry.path) {
+        Ok(m) if m.is_file() => m,
+        _ => return LocalState::Missing,
+    };
+    let modified_time = match metadata.modified().map(|t| t.duration_since(std::time::UNIX_EPOCH)) {
+        Ok(Ok(v)) => v.as_millis() as u64,
+        _ => 0,
+    };
+    // Entries made before sizes were tracked have size 0, only compare the time for those
+    if modified_time == entry.timestamp && (entry.size == 0 || metadata.len() == entry.size) {
+        LocalState::Matches
+    } else {
+        LocalState::Modified
+    }
+}
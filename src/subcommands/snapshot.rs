@@ -0,0 +1,58 @@
+use crate::config::Config;
+use clap::ArgMatches;
+use crate::colorutil::printcoln;
+use termcolor::Color;
+use crate::manifest::{self, FileManifest};
+use crate::subcommands::backup::download::{self, DownloadOptions};
+use crate::datetime;
+
+/// Names the current state of the backup, lists the snapshots and restores a named one
+/// 'snapshot create NAME' only changes the local manifest, the remote copy has the snapshot after the next upload or clean
+pub fn snapshot(config: &Config, args: Option<&ArgMatches>) {
+    match args.map(|a| a.subcommand()) {
+        Some(("create", Some(create_args))) => create(create_args.value_of("name").unwrap()),
+        Some(("list", _)) => list(),
+        Some(("restore", Some(restore_args))) => {
+            if let Err(e) = DownloadOptions::from_args(restore_args).and_then(|options| download::start(config, &options)) {
+                printcoln(Color::Red, e);
+            }
+        },
+        _ => printcoln(Color::White, "Nothing to do, see 'snapshot -h'"),
+    }
+}
+
+fn create(name: &str) {
+    let mut manifest = match FileManifest::from_file(manifest::local_path()) {
+        Ok(fm) => fm,
+        Err(err) => {
+            printcoln(Color::Red, format!("Failed to load file manifest ({})", err));
+            return;
+        }
+    };
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64);
+    match manifest.create_snapshot(name, now) {
+        Ok(generation) => match manifest.save_local() {
+            Ok(_) => printcoln(Color::Green, format!("Created snapshot '{}' (generation {}) of {} files", name, generation, manifest.files.len())),
+            Err(err) => printcoln(Color::Red, format!("Failed to save file manifest ({})", err)),
+        },
+        Err(e) => printcoln(Color::Red, format!("Can't create snapshot ({})", e)),
+    }
+}
+
+fn list() {
+    match FileManifest::from_file(manifest::local_path()) {
+        Ok(fm) => print(&fm),
+        Err(err) => printcoln(Color::Red, format!("Failed to load file manifest ({})", err)),
+    }
+}
+
+/// Prints each snapshot in 'manifest', with its name if it has one
+pub fn print(manifest: &FileManifest) {
+    for snapshot in &manifest.snapshots {
+        println!("Snapshot {}\tRecorded: {}\tFiles: {}{}", snapshot.generation,
+                 datetime::format_millis(snapshot.time), manifest.snapshot_files(snapshot.generation).len(),
+                 snapshot.name.as_ref().map_or(String::new(), |n| format!("\tName: {}", n)));
+    }
+    printcoln(Color::Green, format!("{} snapshots, {} named", manifest.snapshots.len(),
+                                    manifest.snapshots.iter().filter(|s| s.name.is_some()).count()));
+}
@@ -0,0 +1,126 @@
+use crate::config::Config;
+use crate::colorutil::printcoln;
+use termcolor::Color;
+use crate::manifest::{self, FileManifest, FileEntry};
+use crate::filelist;
+use crate::encryption::{get_encrypted_size, BlockSize, NonceMode};
+use crate::sparse;
+use crate::datetime;
+use indicatif::HumanBytes;
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::Serialize;
+
+// Totals for a group of files
+#[derive(Default, Serialize)]
+pub struct Totals {
+    pub files: usize,
+    pub size: u64,
+    pub remote_size: u64,
+}
+
+impl Totals {
+    fn add(&mut self, entry: &FileEntry, encrypt: bool) {
+        self.files += 1;
+        self.size += entry.size;
+        // Hard links share the content uploaded for their target
+        // Only the data of sparse files is uploaded
+        if entry.link.is_none() {
+            let size = if entry.sparse.is_empty() { entry.size } else { sparse::data_length(&entry.sparse) };
+            // Estimated with the default block size, the manifest doesn't record which one was used
+            self.remote_size += if encrypt { get_encrypted_size(size, BlockSize::default()) } else { size };
+        }
+    }
+}
+
+/// Prints statistics about the files tracked by the local manifest
+pub fn stats(config: &Config) {
+    let manifest = match FileManifest::from_file(manifest::local_path()) {
+        Ok(fm) => fm,
+        Err(err) => {
+            printcoln(Color::Red, format!("Failed to load file manifest ({})", err));
+            return;
+        }
+    };
+    let encrypt = config.encrypt.unwrap_or(false);
+
+    // Group files by the path in the backup list they are in, using the most specific one
+    let mut roots = match &config.backup_list {
+        Some(list) => filelist::list_roots(list).unwrap_or_default().iter().flat_map(|r| filelist::expand_root(r)).collect(),
+        None => vec![],
+    };
+    roots.sort_by(|a, b| b.len().cmp(&a.len()));
+    let mut groups: Vec<(String, Totals)> = roots.iter().map(|r| (r.to_string(), Totals::default())).collect();
+    let mut other = Totals::default();
+
+    let (total, compressed, last_upload) = summarize(&manifest, encrypt);
+    for entry in &manifest.files {
+        match groups.iter_mut().find(|(root, _)| entry.path.starts_with(root.as_str())) {
+            Some((_, totals)) => totals.add(entry, encrypt),
+            None => other.add(entry, encrypt),
+        }
+    }
+
+    print!("Files: \t\t");
+    printcoln(Color::Green, format!("{}", total.files));
+    print!("Total Size: \t");
+    printcoln(Color::Green, format!("{}", HumanBytes(total.size)));
+    print!("Remote Size: \t");
+    printcoln(Color::Green, format!("{}{}", HumanBytes(total.remote_size), if encrypt { " (encrypted)" } else { "" }));
+    if compressed > 0 {
+        printcoln(Color::Yellow, format!("\t\t{} compressed files are counted at their uncompressed size", compressed));
+    }
+
+    print!("Nonces Allocated: \t");
+    if encrypt && config.nonce_mode == Some(NonceMode::Random) {
+        printcoln(Color::Green, format!("{} (random nonces are not counted)", config.nonces_allocated()));
+    } else if encrypt {
+        printcoln(Color::Green, format!("{}", config.nonces_allocated()));
+    } else {
+        printcoln(Color::Yellow, "Encryption Disabled");
+    }
+
+    print!("Last Upload: \t");
+    if last_upload == 0 {
+        printcoln(Color::Yellow, "Unknown");
+    } else {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64);
+        printcoln(Color::Green, format!("{} ({} ago)", datetime::format_millis(last_upload), format_age(now.saturating_sub(last_upload))));
+    }
+
+    println!("Per directory:");
+    groups.sort_by(|a, b| a.0.cmp(&b.0));
+    // Files no longer in the backup list
+    if other.files > 0 {
+        groups.push(("Other".to_string(), other));
+    }
+    for (root, totals) in &groups {
+        println!("\t{}: {} files, {} ({} in remote)", root, totals.files, HumanBytes(totals.size), HumanBytes(totals.remote_size));
+    }
+}
+
+/// Totals of all files in the manifest, along with how many are compressed and when the last upload was (0 if unknown)
+pub fn summarize(manifest: &FileManifest, encrypt: bool) -> (Totals, usize, u64) {
+    let mut total = Totals::default();
+    let mut compressed = 0;
+    let mut last_upload = 0;
+    for entry in &manifest.files {
+        total.add(entry, encrypt);
+        if entry.compressed {
+            compressed += 1;
+        }
+        last_upload = last_upload.max(entry.uploaded);
+    }
+    (total, compressed, last_upload)
+}
+
+// Formats a duration in milliseconds as days, hours or minutes
+fn format_age(millis: u64) -> String {
+    let minutes = millis / (60*1000);
+    if minutes >= 24*60 {
+        format!("{} days, {} hours", minutes / (24*60), minutes % (24*60) / 60)
+    } else if minutes >= 60 {
+        format!("{} hours, {} minutes", minutes / 60, minutes % 60)
+    } else {
+        format!("{} minutes", minutes)
+    }
+}
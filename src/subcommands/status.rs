@@ -1,9 +1,37 @@
-use crate::config::Config;
+use crate::config::{Config, DEFAULT_THREADS};
 use crate::colorutil::{printcoln,printcol};
 use termcolor::Color;
+use crate::throttle;
+use indicatif::HumanBytes;
+use crate::retry;
+use crate::budget::LimitAction;
+use crate::manifest;
+use crate::secrets;
+use crate::credentials::Protection;
+use crate::encryption::NonceMode;
+use crate::encryption::cipher::Cipher;
+use crate::retention::Retention;
+use crate::logging::{self, LogRotation};
+use crate::notify::{self, NotifyOn};
+use crate::jobs::Job;
+use crate::backend::s3::{self, S3Config};
+use crate::subcommands::stats::{self, Totals};
+use crate::datetime;
+use clap::ArgMatches;
+use serde::Serialize;
+use std::time::UNIX_EPOCH;
 
 /// Print out information about the state of the config
-pub fn status(config: &Config) {
+/// With --json, it is printed as JSON instead, along with statistics of the manifest
+pub fn status(config: &Config, args: Option<&ArgMatches>) {
+    if args.map_or(false, |a| a.is_present("json")) {
+        println!("{}", serde_json::to_string_pretty(&json_status(config)).unwrap());
+        return;
+    }
+
+    print!("Config File: \t");
+    printcoln(Color::Green, &config.location);
+
     print!("App Key ID: \t");
     match &config.app_key_id {
         Some(k) => printcoln(Color::Green, k),
@@ -12,27 +40,176 @@ pub fn status(config: &Config) {
 
     print!("App Key: \t");
     match &config.app_key {
+        Some(k) if secrets::keyring_entry(k).is_some() => match secrets::resolve(k) {
+            Ok(_) => printcoln(Color::Green, format!("In the OS keyring ({})", k)),
+            Err(e) => printcoln(Color::Red, e),
+        },
+        Some(k) if secrets::env_var(k).is_some() => match secrets::resolve(k) {
+            Ok(_) => printcoln(Color::Green, format!("From the environment ({})", k)),
+            Err(e) => printcoln(Color::Red, e),
+        },
         Some(k) => printcoln(Color::Green, k),
         None => printcoln(Color::Red, "Unset"),
     };
 
+    print!("Environment: \t");
+    match config.env_overrides() {
+        vars if vars.is_empty() => printcoln(Color::Green, "No overrides"),
+        vars => printcoln(Color::Green, format!("Overridden by {}", vars.join(", "))),
+    };
+
+    print!("Credentials: \t");
+    match config.protect_credentials {
+        Some(Protection::Key) => printcoln(Color::Green, "Encrypted with the secret key"),
+        Some(Protection::Machine) => printcoln(Color::Green, "Encrypted with a key bound to this machine"),
+        None => printcoln(Color::Yellow, "Plain text"),
+    };
+
     print!("Bucket Name: \t");
     match &config.bucket_name {
         Some(k) => printcoln(Color::Green, k),
         None => printcoln(Color::Red, "Unset"),
     };
 
+    print!("Storage: \t");
+    match (&config.simulate, &config.local_dir, &config.s3) {
+        (Some(simulate), _, _) => printcoln(Color::Yellow, format!("Simulated, {}", simulate)),
+        (None, Some(dir), _) => printcoln(Color::Green, format!("Local directory {}", dir)),
+        (None, None, Some(s3)) => printcoln(Color::Green, format!("S3-compatible at {} (region {}{})", s3.endpoint,
+                                                          s3.region.as_deref().unwrap_or(s3::DEFAULT_REGION),
+                                                          if s3.path_style == Some(true) { ", path style" } else { "" })),
+        (None, None, None) => printcoln(Color::Green, "B2"),
+    };
+
     print!("Encryption: \t");
     match &config.encrypt {
         Some(enc) => printcoln(Color::Green, format!("Configured: {}",if *enc {"on"} else {"off"})),
         None => printcoln(Color::Red, "Unset"),
     };
 
+    print!("Upload Limit: \t");
+    match &config.upload_limit {
+        Some(rate) => printcoln(Color::Green, throttle::format_rate(*rate)),
+        None => printcoln(Color::Yellow, "Unlimited"),
+    };
+
+    print!("Threads: \t");
+    printcoln(Color::Green, format!("upload {}, download {}, clean {}",
+                                    config.upload_threads.unwrap_or(DEFAULT_THREADS),
+                                    config.download_threads.unwrap_or(DEFAULT_THREADS),
+                                    config.clean_threads.unwrap_or(DEFAULT_THREADS)));
+
+    print!("Max Attempts: \t");
+    printcoln(Color::Green, format!("{}", config.max_attempts.unwrap_or(retry::DEFAULT_MAX_ATTEMPTS)));
+
+    print!("Compression: \t");
+    printcoln(Color::Green, if config.compress.unwrap_or(false) {"on"} else {"off"});
+
+    print!("Scan Cache: \t");
+    printcoln(Color::Green, if config.scan_cache.unwrap_or(false) {"on"} else {"off"});
+
+    print!("Shadow Copy: \t");
+    printcoln(Color::Green, if config.shadow_copy.unwrap_or(false) {"on"} else {"off"});
+
+    print!("Snapshots: \t");
+    match &config.snapshots {
+        Some(hooks) if !hooks.is_empty() => printcoln(Color::Green, hooks.iter()
+            .map(|h| format!("{} ({})", h.path, format!("{:?}", h.kind).to_lowercase())).collect::<Vec<_>>().join(", ")),
+        _ => printcoln(Color::Green, "none"),
+    }
+
+    print!("API Limits: \t");
+    let format_limit = |l: Option<u64>| l.map_or("unlimited".to_string(), |l| l.to_string());
+    printcoln(Color::Green, format!("class B {}, class C {}, {} when reached",
+                                    format_limit(config.class_b_limit),
+                                    format_limit(config.class_c_limit),
+                                    match config.limit_action.unwrap_or(LimitAction::Abort) {
+                                        LimitAction::Pause => "pause",
+                                        LimitAction::Abort => "abort",
+                                    }));
+
+    print!("Download Warn: \t");
+    match &config.download_warnings {
+        Some(sizes) => printcoln(Color::Green, sizes.iter().map(|s| HumanBytes(*s).to_string()).collect::<Vec<_>>().join(", ")),
+        None => printcoln(Color::Green, "off"),
+    };
+
+    print!("Retention: \t");
+    match &config.retention {
+        Some(retention) => printcoln(Color::Green, retention.to_string()),
+        None => printcoln(Color::Yellow, "Keep all versions"),
+    };
+
+    print!("Manifest: \t");
+    match manifest::local_path() {
+        path if path.ends_with(manifest::MANIFEST_DB) => printcoln(Color::Green, format!("{} (SQLite)", path)),
+        path => printcoln(Color::Green, path),
+    };
+
+    print!("Manifest History: \t");
+    printcoln(Color::Green, format!("{} versions", config.manifest_history.unwrap_or(manifest::DEFAULT_MANIFEST_HISTORY)));
+
+    print!("Log File: \t");
+    match &config.log_file {
+        Some(path) => printcoln(Color::Green, format!("{} (rotated: {})", path, config.log_rotation.unwrap_or(LogRotation::Never))),
+        None => printcoln(Color::Yellow, "None"),
+    };
+
+    print!("Run Logs: \t");
+    match config.run_log_dir() {
+        Some(dir) => printcoln(Color::Green, format!("{} (keeping {})", dir, config.log_keep.unwrap_or(logging::DEFAULT_LOG_KEEP))),
+        None => printcoln(Color::Yellow, "None"),
+    };
+
+    print!("Notifications: \t");
+    match config.notifications.as_ref().filter(|n| !n.is_empty()) {
+        Some(n) => {
+            let mut targets = Vec::new();
+            if let Some(url) = &n.webhook {
+                targets.push(format!("webhook {}", notify::redact_url(url)));
+            }
+            if let Some(server) = &n.smtp_server {
+                targets.push(format!("email to {} via {}", n.email_to.as_deref().unwrap_or("nobody"), server));
+            }
+            printcoln(Color::Green, format!("{} (on: {})", targets.join(", "), n.notify_on.unwrap_or(NotifyOn::Always)));
+        },
+        None => printcoln(Color::Yellow, "None"),
+    };
+
+    print!("Hooks: \t\t");
+    match config.hooks.as_ref().filter(|h| !h.is_empty()) {
+        Some(h) => printcoln(Color::Green, format!("{} before, {} after",
+                                                   h.before.as_ref().map_or(0, |b| b.len()), h.after.as_ref().map_or(0, |a| a.len()))),
+        None => printcoln(Color::Yellow, "None"),
+    };
+
+    print!("Jobs: \t\t");
+    match config.jobs.as_ref().filter(|j| !j.is_empty()) {
+        Some(jobs) => printcoln(Color::Green, jobs.iter()
+            .map(|job| if config.job.as_ref() == Some(&job.name) { format!("{} (selected)", job.name) } else { job.name.clone() })
+            .collect::<Vec<String>>().join(", ")),
+        None => printcoln(Color::Yellow, "None"),
+    };
+
+    print!("Metrics File: \t");
+    match &config.metrics_file {
+        Some(path) => printcoln(Color::Green, path),
+        None => printcoln(Color::Yellow, "None"),
+    };
+
     print!("Secret Key: \t");
     if config.encrypt.is_some() && !config.encrypt.unwrap() {
         printcoln(Color::Yellow, "Encryption Disabled")
     } else {
         match &config.secret_key {
+            Some(s) if secrets::keyring_entry(s).is_some() => match secrets::read_key(s) {
+                Ok(_) => printcoln(Color::Green, format!("In the OS keyring ({})", s)),
+                Err(e) => printcoln(Color::Red, format!("{}", e)),
+            },
+            Some(s) if secrets::env_var(s).is_some() => match secrets::read_key(s) {
+                Ok(_) => printcoln(Color::Green, format!("From the environment ({})", s)),
+                Err(e) => printcoln(Color::Red, format!("{}", e)),
+            },
             Some(s) => {
                 if std::path::Path::new(&s).is_file() {
                     printcoln(Color::Green, format!("{}", s))
@@ -40,11 +217,245 @@ pub fn status(config: &Config) {
                     printcoln(Color::Red, format!("File not found or inaccessible ({})", s))
                 }
             }
+            None if config.public_key.is_some() => {
+                printcoln(Color::Green, "None (using the public key)");
+            }
             None => {
                 printcoln(Color::Red, "No secret keyfile set");
             }
         }
+
+        print!("Public Key: \t");
+        match &config.public_key {
+            Some(s) if std::path::Path::new(&s).is_file() => printcoln(Color::Green, format!("{} (files are encrypted for it)", s)),
+            Some(s) => printcoln(Color::Red, format!("File not found or inaccessible ({})", s)),
+            None => printcoln(Color::Green, "None"),
+        }
+
+        print!("Private Key: \t");
+        match &config.private_key {
+            Some(s) => match secrets::read_key(s) {
+                Ok(_) => printcoln(Color::Green, format!("{}", s)),
+                Err(_) => printcoln(Color::Red, format!("Not found or inaccessible ({})", s)),
+            },
+            None => printcoln(Color::Green, "None"),
+        }
+
+        print!("Cipher: \t");
+        printcoln(Color::Green, format!("{}", config.cipher.unwrap_or(Cipher::XChaCha20Poly1305)));
+
+        print!("Block Size: \t");
+        printcoln(Color::Green, format!("{}", HumanBytes(config.block_size.unwrap_or_default().block_length() as u64)));
+
+        print!("Nonce Mode: \t");
+        match config.nonce_mode.unwrap_or(NonceMode::Counter) {
+            NonceMode::Counter => printcoln(Color::Green, "Counter"),
+            NonceMode::Random => printcoln(Color::Green, "Random"),
+        }
+
+        print!("Retired Keys: \t");
+        match config.retired_keys.as_ref().filter(|keys| !keys.is_empty()) {
+            Some(keys) => match keys.iter().filter(|p| secrets::read_key(p).is_err()).count() {
+                0 => printcoln(Color::Green, keys.join(", ")),
+                missing => printcoln(Color::Red, format!("{} of {} not found or inaccessible ({})", missing, keys.len(), keys.join(", "))),
+            },
+            None => printcoln(Color::Green, "None"),
+        }
     }
 
 
 }
+
+// Output of 'status --json', for monitoring scripts
+// Times are in milliseconds since the Unix Epoch. Secrets are never included, only where they come from
+#[derive(Serialize)]
+struct JsonStatus<'a> {
+    config_file: &'a str,
+    // Why the config can't be used for backups, None if it can
+    config_problem: Option<String>,
+    environment_overrides: Vec<&'static str>,
+    app_key_id: Option<&'a str>,
+    // Where the App Key is: config, keyring or environment
+    app_key: Option<&'static str>,
+    credentials_protection: Option<Protection>,
+    bucket_name: Option<&'a str>,
+    // None means B2
+    s3: Option<&'a S3Config>,
+    local_dir: Option<&'a str>,
+    backup_list: Option<&'a str>,
+    encryption: JsonEncryption<'a>,
+    upload_limit: Option<u64>,
+    upload_threads: usize,
+    download_threads: usize,
+    clean_threads: usize,
+    max_attempts: u32,
+    compression: bool,
+    class_b_limit: Option<u64>,
+    class_c_limit: Option<u64>,
+    limit_action: LimitAction,
+    download_warnings: Option<&'a [u64]>,
+    retention: Option<Retention>,
+    manifest_history: u32,
+    log_file: Option<&'a str>,
+    log_rotation: LogRotation,
+    log_dir: Option<String>,
+    log_keep: u32,
+    notifications: Option<JsonNotifications<'a>>,
+    hooks: Option<JsonHooks>,
+    jobs: Option<&'a [Job]>,
+    // The job the status is of, with --job. None means the backup list of the config
+    job: Option<&'a str>,
+    metrics_file: Option<&'a str>,
+    manifest: JsonManifest,
+    nonces: JsonNonces,
+    last_backup: JsonLastBackup,
+}
+
+#[derive(Serialize)]
+struct JsonEncryption<'a> {
+    enabled: Option<bool>,
+    cipher: Cipher,
+    secret_key: Option<&'a str>,
+    retired_keys: Vec<&'a str>,
+    public_key: Option<&'a str>,
+    private_key: Option<&'a str>,
+    // Configured keys that can't be read
+    unreadable_keys: Vec<&'a str>,
+}
+
+// Commands are left out, they may hold secrets
+#[derive(Serialize)]
+struct JsonHooks {
+    before: usize,
+    after: usize,
+}
+
+// The SMTP login is left out
+#[derive(Serialize)]
+struct JsonNotifications<'a> {
+    // Only the scheme and host, see notify::redact_url
+    webhook: Option<String>,
+    smtp_server: Option<&'a str>,
+    email_from: Option<&'a str>,
+    email_to: Option<&'a str>,
+    notify_on: NotifyOn,
+}
+
+#[derive(Serialize)]
+struct JsonManifest {
+    path: String,
+    backend: &'static str,
+    // Why the manifest couldn't be loaded, None if it was
+    error: Option<String>,
+    totals: Option<Totals>,
+    compressed_files: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct JsonNonces {
+    mode: NonceMode,
+    // Every nonce below this may have been used. Random nonces aren't counted
+    allocated: u128,
+}
+
+#[derive(Serialize)]
+struct JsonLastBackup {
+    last_upload: Option<u64>,
+    last_upload_time: Option<String>,
+    // Last time the local manifest was saved, which every backup does
+    manifest_saved: Option<u64>,
+}
+
+fn json_status(config: &Config) -> JsonStatus<'_> {
+    let encrypt = config.encrypt.unwrap_or(false);
+    let path = manifest::local_path();
+    let manifest_saved = std::fs::metadata(&path).and_then(|m| m.modified()).ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_millis() as u64);
+    let (totals, compressed, last_upload, error) = match manifest::FileManifest::from_file(&path) {
+        Ok(fm) => {
+            let (totals, compressed, last_upload) = stats::summarize(&fm, encrypt);
+            (Some(totals), Some(compressed), Some(last_upload).filter(|t| *t > 0), None)
+        },
+        Err(e) => (None, None, None, Some(e.to_string())),
+    };
+    let keys = config.secret_key.iter()
+        .chain(config.retired_keys.iter().flatten())
+        .chain(config.private_key.iter());
+
+    JsonStatus {
+        config_file: &config.location,
+        config_problem: config.is_configured().err(),
+        environment_overrides: config.env_overrides(),
+        app_key_id: config.app_key_id.as_deref(),
+        app_key: config.app_key.as_ref().map(|k| if secrets::keyring_entry(k).is_some() {
+            "keyring"
+        } else if secrets::env_var(k).is_some() {
+            "environment"
+        } else {
+            "config"
+        }),
+        credentials_protection: config.protect_credentials,
+        bucket_name: config.bucket_name.as_deref(),
+        s3: config.s3.as_ref(),
+        local_dir: config.local_dir.as_deref(),
+        backup_list: config.backup_list.as_deref(),
+        encryption: JsonEncryption {
+            enabled: config.encrypt,
+            cipher: config.cipher.unwrap_or(Cipher::XChaCha20Poly1305),
+            secret_key: config.secret_key.as_deref(),
+            retired_keys: config.retired_keys.iter().flatten().map(|k| k.as_str()).collect(),
+            public_key: config.public_key.as_deref(),
+            private_key: config.private_key.as_deref(),
+            unreadable_keys: keys.filter(|k| secrets::read_key(k).is_err())
+                .chain(config.public_key.iter().filter(|k| !std::path::Path::new(k).is_file()))
+                .map(|k| k.as_str()).collect(),
+        },
+        upload_limit: config.upload_limit,
+        upload_threads: config.upload_threads.unwrap_or(DEFAULT_THREADS),
+        download_threads: config.download_threads.unwrap_or(DEFAULT_THREADS),
+        clean_threads: config.clean_threads.unwrap_or(DEFAULT_THREADS),
+        max_attempts: config.max_attempts.unwrap_or(retry::DEFAULT_MAX_ATTEMPTS),
+        compression: config.compress.unwrap_or(false),
+        class_b_limit: config.class_b_limit,
+        class_c_limit: config.class_c_limit,
+        limit_action: config.limit_action.unwrap_or(LimitAction::Abort),
+        download_warnings: config.download_warnings.as_deref(),
+        retention: config.retention,
+        manifest_history: config.manifest_history.unwrap_or(manifest::DEFAULT_MANIFEST_HISTORY),
+        log_file: config.log_file.as_deref(),
+        log_rotation: config.log_rotation.unwrap_or(LogRotation::Never),
+        log_dir: config.run_log_dir(),
+        log_keep: config.log_keep.unwrap_or(logging::DEFAULT_LOG_KEEP),
+        notifications: config.notifications.as_ref().filter(|n| !n.is_empty()).map(|n| JsonNotifications {
+            webhook: n.webhook.as_deref().map(notify::redact_url),
+            smtp_server: n.smtp_server.as_deref(),
+            email_from: n.email_from.as_deref(),
+            email_to: n.email_to.as_deref(),
+            notify_on: n.notify_on.unwrap_or(NotifyOn::Always),
+        }),
+        hooks: config.hooks.as_ref().filter(|h| !h.is_empty()).map(|h| JsonHooks {
+            before: h.before.as_ref().map_or(0, |b| b.len()),
+            after: h.after.as_ref().map_or(0, |a| a.len()),
+        }),
+        jobs: config.jobs.as_deref().filter(|j| !j.is_empty()),
+        job: config.job.as_deref(),
+        metrics_file: config.metrics_file.as_deref(),
+        manifest: JsonManifest {
+            backend: if path.ends_with(manifest::MANIFEST_DB) { "sqlite" } else { "file" },
+            path,
+            error,
+            totals,
+            compressed_files: compressed,
+        },
+        nonces: JsonNonces {
+            mode: config.nonce_mode.unwrap_or(NonceMode::Counter),
+            allocated: config.nonces_allocated(),
+        },
+        last_backup: JsonLastBackup {
+            last_upload,
+            last_upload_time: last_upload.map(datetime::format_millis),
+            manifest_saved,
+        },
+    }
+}
@@ -0,0 +1,151 @@
+use crate::config::Config;
+use crate::colorutil::printcoln;
+use termcolor::Color;
+use raze::api::{B2Auth, ListBucketParams};
+use reqwest::blocking::Client;
+use crate::secrets;
+use crate::filelist;
+use crate::b2;
+use crate::encryption::KEY_LENGTH;
+use crate::backend::Backend;
+use crate::backend::local::LocalBackend;
+use crate::backend::s3::{S3Backend, S3Config};
+use crate::budget::Budget;
+use crate::capabilities::{self, Operation};
+use std::sync::Arc;
+
+/// Checks that the config actually works, not just that it is filled in
+/// Authenticates with B2, checks the bucket can be listed and uploaded to (only listed on S3-compatible storage), reads the keys and parses the backup list
+/// On B2, what the App Key may do is checked for each operation, s.t. e.g. a key that can't delete files is found before 'clean delete'
+/// With a local directory instead of a bucket, checks it can be listed and written to
+/// Each check is reported as it is done. Exits with a non-zero code if any of them failed, s.t. it can be scripted
+pub fn validate(config: &Config) {
+    let mut failed = 0;
+    let mut check = |name: &str, result: Result<String, String>| {
+        print!("{}: \t", name);
+        match result {
+            Ok(detail) => printcoln(Color::Green, format!("OK ({})", detail)),
+            Err(e) => {
+                printcoln(Color::Red, format!("FAILED ({})", e));
+                failed += 1;
+            },
+        }
+    };
+
+    check("Settings", config.is_configured().map(|_| "everything needed is set".to_string()));
+
+    match (&config.local_dir, &config.s3) {
+        (Some(dir), _) => check("Directory", check_local(dir)),
+        // S3 has no separate authentication, listing the bucket checks the credentials and the bucket at once
+        (None, Some(s3)) => match &config.bucket_name {
+            Some(_) => check("Bucket", check_s3(config, s3)),
+            None => check("Bucket", Err("no bucket name is set".to_string())),
+        },
+        (None, None) => {
+            let client = Client::builder().timeout(None).build().unwrap();
+            let auth = config.keystring().and_then(|keystring| b2::b2_authorize_account(&client, &keystring)
+                .map_err(|_| "authentication failure, check the App Key ID and App Key".to_string()));
+            check("Authentication", auth.as_ref().map(|(a, _)| format!("account {}", a.account_id)).map_err(|e| e.clone()));
+
+            match (&auth, &config.bucket_name) {
+                (Ok((auth, allowed)), Some(name)) => {
+                    check("App Key", capabilities::check_key(allowed, name).map(|_| allowed.capabilities.join(", ")));
+                    check("Bucket", find_bucket(&client, auth, name).map(|bucket_id| format!("{} ({})", name, bucket_id)));
+                    // What each operation would fail at, or only partly do
+                    let retention = config.retention.is_some();
+                    let operations = [
+                        ("Upload", Operation::Upload),
+                        ("Download", Operation::Download { versions: false }),
+                        ("Clean hide", Operation::Clean { delete: false, retention }),
+                        ("Clean delete", Operation::Clean { delete: true, retention }),
+                        ("Garbage collection", Operation::Gc),
+                    ];
+                    for (label, operation) in operations.iter() {
+                        let problems = capabilities::check(allowed, *operation);
+                        check(label, match (problems.fatal.is_empty(), problems.warnings.is_empty()) {
+                            (true, true) => Ok("allowed".to_string()),
+                            (true, false) => Ok(problems.warnings.join("; ")),
+                            (false, _) => Err(problems.fatal.join("; ")),
+                        });
+                    }
+                },
+                (Ok(_), None) => check("Bucket", Err("no bucket name is set".to_string())),
+                (Err(_), _) => printcoln(Color::Yellow, "Bucket: \tSkipped, it can't be checked without authenticating"),
+            }
+        },
+    }
+
+    if config.encrypt == Some(true) {
+        check("Keys", check_keys(config));
+    }
+
+    match &config.backup_list {
+        Some(list) => check("Backup List", filelist::verify_structure(list)
+            .and_then(|_| filelist::list_roots(list).map_err(|e| format!("{:?}", e)))
+            .map(|roots| format!("{} paths", roots.len()))),
+        None => check("Backup List", Err("no backup list is set".to_string())),
+    }
+
+    println!();
+    if failed == 0 {
+        printcoln(Color::Green, "All checks passed");
+    } else {
+        printcoln(Color::Red, format!("{} checks failed", failed));
+        std::process::exit(1);
+    }
+}
+
+// Returns the ID of the bucket named 'name'
+// Asking for the bucket by name works for keys restricted to a bucket too
+fn find_bucket(client: &Client, auth: &B2Auth, name: &str) -> Result<String, String> {
+    let params = ListBucketParams {
+        bucket_id: None,
+        bucket_name: Some(name.to_string()),
+        bucket_types: None
+    };
+    match raze::api::b2_list_buckets(client, auth, params) {
+        Ok(buckets) => buckets.into_iter().find(|b| b.bucket_name == name).map(|b| b.bucket_id)
+            .ok_or(format!("{} doesn't exist, or the App Key can't access it", name)),
+        Err(_) => Err("the App Key can't list buckets (listBuckets)".to_string()),
+    }
+}
+
+// Checks that the credentials can list the bucket on S3-compatible storage
+// Uploading isn't checked, since that can't be done without changing anything in the bucket
+fn check_s3(config: &Config, s3: &S3Config) -> Result<String, String> {
+    let backend = S3Backend::connect(config, s3, Arc::new(Budget::new(config)))?;
+    let files = backend.list().map_err(|e| format!("the bucket can't be listed ({})", e))?;
+    Ok(format!("{}, {} files", backend.describe(), files.len()))
+}
+
+// Checks that the local directory can be listed and written to
+// Writing is checked with a file that is removed right after
+fn check_local(dir: &str) -> Result<String, String> {
+    let backend = LocalBackend::connect(dir)?;
+    let files = backend.list().map_err(|e| format!("the directory can't be listed ({})", e))?;
+    let probe = ".retain-validate";
+    backend.upload(probe, Box::new(std::io::empty()), 0, 0)
+        .and_then(|_| backend.delete(probe, None))
+        .map_err(|e| format!("the directory can't be written to ({})", e))?;
+    Ok(format!("{}, {} files", backend.describe(), files.len()))
+}
+
+// Checks that every configured key-file can be read and is a key
+fn check_keys(config: &Config) -> Result<String, String> {
+    let locations: Vec<&String> = config.secret_key.iter()
+        .chain(config.retired_keys.iter().flatten())
+        .chain(config.public_key.iter())
+        .chain(config.private_key.iter())
+        .collect();
+    if locations.is_empty() {
+        return Err("encryption is on, but no secret key is set".to_string());
+    }
+    for location in &locations {
+        match secrets::read_key(location) {
+            Ok(key) if key.len() == KEY_LENGTH => (),
+            Ok(key) => return Err(format!("{} is {} bytes, a key is {}", location, key.len(), KEY_LENGTH)),
+            Err(e) => return Err(format!("{} can't be read ({})", location, e)),
+        }
+    }
+    Ok(format!("{} keys", locations.len()))
+}
@@ -0,0 +1,131 @@
+use crate::config::Config;
+use clap::ArgMatches;
+use crate::colorutil::printcoln;
+use termcolor::Color;
+use crate::filelist;
+use crate::subcommands::backup::upload::{self, UploadOptions};
+use notify::{watcher, DebouncedEvent, RecursiveMode, Watcher};
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::Duration;
+use walkdir::WalkDir;
+
+// Seconds to wait for more changes after a change before uploading, unless given with --delay
+pub const DEFAULT_WATCH_DELAY: u64 = 10;
+
+/// Watches the paths in the backup list and uploads files as they change
+/// Starts with a regular upload, s.t. changes made while not watching are also backed up
+pub fn watch(config: &mut Config, args: Option<&ArgMatches>) {
+    let args = args.unwrap();
+    match config.is_configured() {
+        Ok(_) => (),
+        Err(err) => {
+            printcoln(Color::Red, format!("Invalid config ({})", err));
+            return;
+        }
+    }
+    let backup_list = config.backup_list.clone().unwrap();
+    match filelist::verify_structure(&backup_list) {
+        Ok(_) => (),
+        Err(e) => {
+            printcoln(Color::Red, format!("Backup list is invalid: {}", e));
+            return;
+        }
+    }
+    let options = match UploadOptions::from_args(args) {
+        Ok(options) => options,
+        Err(e) => {
+            printcoln(Color::Red, e);
+            return;
+        }
+    };
+    // Validated by clap
+    let delay = match args.value_of("delay") {
+        Some(s) => s.parse::<u64>().unwrap(),
+        None => DEFAULT_WATCH_DELAY,
+    };
+
+    // Events for a path are combined by notify until it has been unchanged for a second
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = match watcher(tx, Duration::from_secs(1)) {
+        Ok(w) => w,
+        Err(e) => {
+            printcoln(Color::Red, format!("Failed to start watching for changes ({})", e));
+            return;
+        }
+    };
+    // Globs are watched from the directory they start in, s.t. paths they match later are picked up
+    let roots: HashSet<String> = filelist::list_roots(&backup_list).unwrap_or_default().iter().map(|r| filelist::watch_root(r)).collect();
+    for root in roots {
+        if let Err(e) = watcher.watch(&root, RecursiveMode::Recursive) {
+            printcoln(Color::Red, format!("Failed to watch {} ({})", root, e));
+            return;
+        }
+    }
+
+    report(upload::start(config, &options));
+
+    loop {
+        printcoln(Color::Green, "Watching for changes...");
+        let mut changed = HashSet::new();
+        let mut rescan = false;
+        // Wait for the first change, then collect changes until none happen for 'delay' seconds
+        let mut event = rx.recv().map_err(|_| RecvTimeoutError::Disconnected);
+        loop {
+            match event {
+                Ok(DebouncedEvent::Create(path)) | Ok(DebouncedEvent::Write(path))
+                | Ok(DebouncedEvent::Chmod(path)) | Ok(DebouncedEvent::Rename(_, path)) => {
+                    changed.insert(path);
+                },
+                Ok(DebouncedEvent::Rescan) => rescan = true,
+                Ok(DebouncedEvent::Error(e, path)) => printcoln(Color::Red, format!("Error while watching {:?} ({})", path, e)),
+                Ok(_) => (),
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => {
+                    printcoln(Color::Red, "Stopped receiving changes");
+                    return;
+                },
+            }
+            event = rx.recv_timeout(Duration::from_secs(delay));
+        }
+
+        // Events may have been missed, so check everything
+        if rescan {
+            report(upload::start(config, &options));
+            continue;
+        }
+
+        let paths = changed_files(&backup_list, changed);
+        if !paths.is_empty() {
+            printcoln(Color::Green, format!("{} files changed", paths.len()));
+            report(upload::upload_paths(config, &options, paths));
+        }
+    }
+}
+
+// Prints why an upload failed to start, watching continues regardless
+fn report(result: Result<(), String>) {
+    if let Err(e) = result {
+        printcoln(Color::Red, e);
+    }
+}
+
+// Resolves changed paths to files in the backup list
+// Directories (e.g. moved into a watched directory) are replaced by the files in them
+fn changed_files(backup_list: &str, changed: HashSet<PathBuf>) -> Vec<String> {
+    let mut files = Vec::new();
+    for path in changed {
+        for entry in WalkDir::new(path).into_iter().filter_map(|e| e.ok()) {
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            if let Some(name) = entry.path().to_str() {
+                files.push(name.to_string());
+            }
+        }
+    }
+    files.sort();
+    files.dedup();
+    filelist::filter_included(backup_list, files).unwrap_or_default()
+}
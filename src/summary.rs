@@ -0,0 +1,230 @@
+//! Summary of what an upload, download or clean did, printed at the end of the run (even when quiet)
+//!
+//! If any file failed, or the run stopped early because of an error, the process exits with code 1 (see main.rs)
+//! A run that stops early returns without finishing its summary, which is noticed when the summary is dropped
+//!
+//! Either way, the summary is sent to the configured notifications (see notify.rs), written to the metrics file (see metrics.rs)
+//! and passed to the after hooks of the run, if it has any (see hooks.rs)
+
+use crate::colorutil::{printcoln, printsummary};
+use crate::config::Config;
+use crate::metrics;
+use crate::hooks::Hooks;
+use crate::notify::{self, Notifications, Report};
+use indicatif::{HumanBytes, HumanDuration};
+use termcolor::Color;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::Instant;
+
+// Set once any run failed, for the exit code of the process. A single run is checked with Summary::succeeded
+static FAILED: AtomicBool = AtomicBool::new(false);
+
+/// Whether any run failed, as a whole or for some of its files
+pub fn failed() -> bool {
+    FAILED.load(Ordering::SeqCst)
+}
+
+/// Counts what happens to each file during a run
+/// Shared by all worker threads
+pub struct Summary {
+    // What kind of run this is, e.g. "upload"
+    run: &'static str,
+    // What is done to the files, e.g. "uploaded"
+    action: &'static str,
+    bucket: Option<String>,
+    job: Option<String>,
+    notifications: Option<Notifications>,
+    metrics_file: Option<String>,
+    hooks: Option<Hooks>,
+    start: Instant,
+    scanned: AtomicUsize,
+    excluded: AtomicUsize,
+    unchanged: AtomicUsize,
+    done: AtomicUsize,
+    failed: Mutex<Vec<String>>,
+    // Only uploads consume nonces, and only when encrypting
+    nonces: AtomicU64,
+    // Files in the manifest at the end of the run, if the run knows
+    tracked: Mutex<Option<usize>>,
+    finished: AtomicBool,
+}
+
+impl Summary {
+    pub fn new(run: &'static str, action: &'static str, config: &Config) -> Self {
+        Summary {
+            run,
+            action,
+            bucket: config.bucket_name.clone(),
+            job: config.job.clone(),
+            // A simulated run isn't the real backup, it isn't reported
+            notifications: config.notifications.clone().filter(|_| config.simulate.is_none()),
+            metrics_file: config.metrics_file.clone().filter(|_| config.simulate.is_none()),
+            hooks: None,
+            start: Instant::now(),
+            scanned: AtomicUsize::new(0),
+            excluded: AtomicUsize::new(0),
+            unchanged: AtomicUsize::new(0),
+            done: AtomicUsize::new(0),
+            failed: Mutex::new(Vec::new()),
+            nonces: AtomicU64::new(0),
+            tracked: Mutex::new(None),
+            finished: AtomicBool::new(false),
+        }
+    }
+
+    /// Runs the after hooks in 'hooks' with the summary once the run is done
+    pub fn with_hooks(mut self, hooks: Option<Hooks>) -> Self {
+        self.hooks = hooks;
+        self
+    }
+
+    /// Adds to the amount of files that were looked at, whether or not anything was done to them
+    pub fn scanned(&self, count: usize) {
+        self.scanned.fetch_add(count, Ordering::SeqCst);
+    }
+
+    /// Adds to the amount of files left out by the backup list (or the given paths)
+    pub fn excluded(&self, count: usize) {
+        self.excluded.fetch_add(count, Ordering::SeqCst);
+    }
+
+    /// Adds to the amount of files that were skipped because they were already up to date
+    pub fn unchanged(&self, count: usize) {
+        self.unchanged.fetch_add(count, Ordering::SeqCst);
+    }
+
+    /// Whether the run finished without any file failing
+    pub fn succeeded(&self) -> bool {
+        self.finished.load(Ordering::SeqCst) && self.failed.lock().unwrap().is_empty()
+    }
+
+    /// Counts a file that succeeded or failed
+    /// Logged at the debug level, s.t. run logs hold every file (see logging.rs)
+    pub fn record(&self, path: &str, ok: bool) {
+        match ok {
+            true => {
+                tracing::debug!("{} {}", self.action, path);
+                self.done.fetch_add(1, Ordering::SeqCst);
+            },
+            false => {
+                tracing::debug!("failed {}", path);
+                self.failed.lock().unwrap().push(path.to_string());
+            },
+        }
+    }
+
+    /// Counts a file that failed
+    pub fn fail(&self, path: &str) {
+        self.record(path, false);
+    }
+
+    /// The files that failed so far
+    pub fn failures(&self) -> Vec<String> {
+        self.failed.lock().unwrap().clone()
+    }
+
+    pub fn nonces(&self, amount: u128) {
+        self.nonces.fetch_add(amount as u64, Ordering::SeqCst);
+    }
+
+    /// Sets the amount of files in the manifest
+    pub fn tracked(&self, count: usize) {
+        *self.tracked.lock().unwrap() = Some(count);
+    }
+
+    /// Prints the summary and sends it to the configured notifications
+    /// 'bytes' is the amount transferred, if the run transferred anything
+    pub fn finish(&self, bytes: Option<u64>) {
+        self.finished.store(true, Ordering::SeqCst);
+        let report = self.report(true, bytes);
+        printsummary(Color::Green, format!("Summary: {} files scanned, {} excluded, {} unchanged, {} {}, {} failed",
+                                           report.scanned, report.excluded, report.unchanged, report.done, self.action, report.failed.len()));
+        let elapsed = HumanDuration(self.start.elapsed());
+        match bytes {
+            Some(bytes) => printsummary(Color::Green, format!("Transferred {} in {}", HumanBytes(bytes), elapsed)),
+            None => printsummary(Color::Green, format!("Finished in {}", elapsed)),
+        }
+        if report.nonces > 0 {
+            printsummary(Color::Green, format!("Nonces consumed: {}", report.nonces));
+        }
+        if !report.success {
+            FAILED.store(true, Ordering::SeqCst);
+            printsummary(Color::Red, "Failed:");
+            for path in &report.failed {
+                printsummary(Color::Red, format!("\t{}", path));
+            }
+        }
+        self.publish(&report);
+    }
+
+    // Sends the report to the notifications and the metrics file
+    fn publish(&self, report: &Report) {
+        if let Some(path) = &self.metrics_file {
+            if let Err(e) = metrics::write(path, report) {
+                printcoln(Color::Yellow, format!("Failed to write the metrics file {} ({})", path, e));
+            }
+        }
+        if let Some(notifications) = &self.notifications {
+            notify::send(notifications, report);
+        }
+        if let Some(hooks) = &self.hooks {
+            hooks.run_after(report);
+        }
+    }
+
+    // What the summary says so far. 'completed' is false if the run stopped early
+    fn report(&self, completed: bool, bytes: Option<u64>) -> Report {
+        let mut failed = self.failed.lock().unwrap().clone();
+        failed.sort();
+        Report {
+            run: self.run,
+            success: completed && failed.is_empty(),
+            completed,
+            bucket: self.bucket.clone(),
+            job: self.job.clone(),
+            scanned: self.scanned.load(Ordering::SeqCst),
+            excluded: self.excluded.load(Ordering::SeqCst),
+            unchanged: self.unchanged.load(Ordering::SeqCst),
+            done: self.done.load(Ordering::SeqCst),
+            failed,
+            bytes,
+            seconds: self.start.elapsed().as_secs(),
+            nonces: self.nonces.load(Ordering::SeqCst),
+            tracked: *self.tracked.lock().unwrap(),
+        }
+    }
+}
+
+impl Drop for Summary {
+    // Not finishing means the run stopped because of an error
+    fn drop(&mut self) {
+        if !self.finished.load(Ordering::SeqCst) {
+            FAILED.store(true, Ordering::SeqCst);
+            self.publish(&self.report(false, None));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::summary::Summary;
+
+    #[test]
+    fn test_summary() {
+        let summary = Summary::new("upload", "uploaded", &Config::default());
+        summary.scanned(3);
+        summary.record("/a", true);
+        summary.unchanged(1);
+        assert!(!summary.succeeded());
+        summary.finish(Some(10));
+        assert!(summary.succeeded());
+
+        // Other tests run at the same time, so the state of this run is checked rather than summary::failed
+        let summary = Summary::new("upload", "uploaded", &Config::default());
+        summary.record("/b", false);
+        summary.finish(None);
+        assert!(!summary.succeeded());
+    }
+}
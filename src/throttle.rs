@@ -0,0 +1,205 @@
+//! Bandwidth limiting for uploads
+//!
+//! A single TokenBucket is shared by every upload thread, s.t. the limit applies to the combined rate
+//! Each ThrottledReader takes tokens (bytes) from the bucket before reading from its inner reader,
+//! async uploads take them with 'acquire' before sending each chunk
+//! The bucket refills continuously at the configured rate and holds at most 1 second worth of tokens
+//!
+//! Rates are written as a number followed by an optional unit and an optional "/s", e.g. `5MB/s`
+//! Supported units are B, KB, MB, GB, TB (powers of 1000) and KiB, MiB, GiB, TiB (powers of 1024)
+//! Amounts of data (see parse_size) are written the same way, without the "/s"
+
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub struct TokenBucket {
+    rate: u64, // Bytes per second
+    tokens: f64, // Bytes that can be read right now
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a new bucket allowing 'rate' bytes per second, ready to be shared between threads
+    pub fn shared(rate: u64) -> Arc<Mutex<TokenBucket>> {
+        Arc::new(Mutex::new(TokenBucket {
+            rate,
+            tokens: rate as f64,
+            last_refill: Instant::now(),
+        }))
+    }
+
+    // Take up to 'max' tokens
+    // If no tokens are available, returns how long to wait before trying again
+    fn take(&mut self, max: usize) -> Result<usize, Duration> {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            let n = (self.tokens as usize).min(max);
+            self.tokens -= n as f64;
+            Ok(n)
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / self.rate as f64))
+        }
+    }
+
+    // Return tokens that were taken but not used
+    fn give_back(&mut self, amount: usize) {
+        self.tokens = (self.tokens + amount as f64).min(self.rate as f64);
+    }
+}
+
+/// Wraps another reader, limiting how fast it can be read from
+/// If no bucket is supplied, reads are passed straight through
+pub struct ThrottledReader<R: Read> {
+    inner: R,
+    bucket: Option<Arc<Mutex<TokenBucket>>>,
+}
+
+impl<R: Read> ThrottledReader<R> {
+    pub fn wrap(reader: R, bucket: Option<Arc<Mutex<TokenBucket>>>) -> Self {
+        ThrottledReader {
+            inner: reader,
+            bucket,
+        }
+    }
+}
+
+impl<R: Read> Read for ThrottledReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        let bucket = match &self.bucket {
+            Some(b) => b,
+            None => return self.inner.read(buf),
+        };
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        // Wait until we're allowed to read at least 1 byte
+        // The lock is not held while sleeping, s.t. other threads can check the bucket too
+        let allowed = loop {
+            let res = bucket.lock().unwrap().take(buf.len());
+            match res {
+                Ok(n) => break n,
+                Err(wait) => std::thread::sleep(wait),
+            }
+        };
+
+        let read = self.inner.read(&mut buf[..allowed]);
+        let unused = match &read {
+            Ok(n) => allowed - n,
+            Err(_) => allowed,
+        };
+        if unused > 0 {
+            bucket.lock().unwrap().give_back(unused);
+        }
+        read
+    }
+}
+
+/// Takes up to 'max' tokens from the bucket, waiting until at least 1 is available
+/// Used by async uploads (see transfer.rs), which wait without blocking their thread
+pub async fn acquire(bucket: &Option<Arc<Mutex<TokenBucket>>>, max: usize) -> usize {
+    let bucket = match bucket {
+        Some(b) => b,
+        None => return max,
+    };
+    loop {
+        let res = bucket.lock().unwrap().take(max);
+        match res {
+            Ok(n) => return n,
+            Err(wait) => tokio::time::delay_for(wait).await,
+        }
+    }
+}
+
+/// Parses a rate such as `5MB/s` or `750KiB` into bytes per second
+pub fn parse_rate<T: AsRef<str>>(text: T) -> Result<u64, String> {
+    let text = text.as_ref().trim();
+    let text = text.strip_suffix("/s").unwrap_or(text).trim();
+    let rate = parse_bytes(text).map_err(|_| format!("Invalid rate '{}'", text))?;
+    if rate == 0 {
+        return Err("Rate must be at least 1 byte per second".to_string());
+    }
+    Ok(rate)
+}
+
+/// Parses an amount of data such as `5GB` or `750KiB` into bytes, with the same units as rates
+pub fn parse_size<T: AsRef<str>>(text: T) -> Result<u64, String> {
+    let text = text.as_ref().trim();
+    match parse_bytes(text) {
+        Ok(0) => Err("Size must be at least 1 byte".to_string()),
+        Ok(size) => Ok(size),
+        Err(_) => Err(format!("Invalid size '{}'", text)),
+    }
+}
+
+// Parses a number followed by an optional unit
+fn parse_bytes(text: &str) -> Result<u64, ()> {
+    let split = text.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let number = number.parse::<f64>().map_err(|_| ())?;
+    let multiplier: u64 = match unit.trim().to_lowercase().as_ref() {
+        "" | "b" => 1,
+        "kb" | "k" => 1000,
+        "mb" | "m" => 1000*1000,
+        "gb" | "g" => 1000*1000*1000,
+        "tb" | "t" => 1000*1000*1000*1000,
+        "kib" => 1024,
+        "mib" => 1024*1024,
+        "gib" => 1024*1024*1024,
+        "tib" => 1024*1024*1024*1024,
+        _ => return Err(()),
+    };
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// Formats a rate in bytes per second for display, e.g. `5.00 MB/s`
+pub fn format_rate(rate: u64) -> String {
+    if rate >= 1000*1000*1000 {
+        format!("{:.2} GB/s", rate as f64 / (1000*1000*1000) as f64)
+    } else if rate >= 1000*1000 {
+        format!("{:.2} MB/s", rate as f64 / (1000*1000) as f64)
+    } else if rate >= 1000 {
+        format!("{:.2} KB/s", rate as f64 / 1000 as f64)
+    } else {
+        format!("{} B/s", rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::throttle::{parse_rate, parse_size, format_rate, TokenBucket, ThrottledReader};
+    use std::io::{Cursor, Read};
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(parse_rate("5MB/s"), Ok(5_000_000));
+        assert_eq!(parse_rate("5 mb"), Ok(5_000_000));
+        assert_eq!(parse_rate("1.5KiB/s"), Ok(1536));
+        assert_eq!(parse_rate("300"), Ok(300));
+        assert!(parse_rate("fast").is_err());
+        assert!(parse_rate("5XB/s").is_err());
+        assert!(parse_rate("0MB/s").is_err());
+        assert_eq!(format_rate(5_000_000), "5.00 MB/s");
+        assert_eq!(parse_size("10GB"), Ok(10_000_000_000));
+        assert_eq!(parse_size("1 TiB"), Ok(1 << 40));
+        assert!(parse_size("5GB/s").is_err());
+        assert!(parse_size("0").is_err());
+    }
+
+    #[test]
+    fn test_throttled_read() {
+        // 1000 B/s with a full bucket: the first 1000 bytes are instant, the next 500 take ~0.5s
+        let bucket = TokenBucket::shared(1000);
+        let mut reader = ThrottledReader::wrap(Cursor::new(vec![3u8; 1500]), Some(bucket));
+        let start = std::time::Instant::now();
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).unwrap();
+        assert_eq!(out, vec![3u8; 1500]);
+        assert!(start.elapsed().as_secs_f32() >= 0.4);
+    }
+}
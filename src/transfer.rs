@@ -0,0 +1,245 @@
+//! Runs uploads and downloads on a tokio runtime, a bounded amount at a time
+//!
+//! Files smaller than BUFFERED_SIZE are held in memory and sent or received with async reqwest,
+//! s.t. hundreds of small transfers can overlap without needing a thread each
+//! Reading, compressing, encrypting and writing files happens on tokio's blocking threads, as do the transfers
+//! that need the blocking B2 calls (large, sparse and delta files)
+//!
+//! Cancelling (Ctrl-C) stops new transfers from starting. Transfers in progress read through Cancel::wrap_read,
+//! which fails once cancelled, s.t. large files stop at the next block instead of being finished
+//! Those are rolled back: an upload is not recorded in the manifest, a download keeps its .part file to continue from
+//! The run then returns normally, saving the manifest, and the process exits with interrupt::EXIT_CODE
+
+use crate::colorutil::printcoln;
+use crate::interrupt;
+use crate::progress::ProgressCounter;
+use crate::sparse;
+use crate::throttle::{self, TokenBucket};
+use futures::stream::{self, StreamExt};
+use std::future::Future;
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use termcolor::Color;
+use tokio::runtime::Runtime;
+use tokio::sync::watch;
+
+// Files smaller than this are transferred from/to memory
+// They can't be sparse (see sparse.rs) and have no deltas, which need the blocking calls
+pub const BUFFERED_SIZE: u64 = sparse::SPARSE_THRESHOLD;
+// Size of the chunks a buffered upload is sent in, which each wait for the upload limit
+const CHUNK_SIZE: usize = 64*1024;
+// How long connecting to B2 may take
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+// Size of the buffers files are read and written through, s.t. large files take few system calls
+pub const IO_BUFFER_LENGTH: usize = 1024*1024;
+
+/// Copies everything from 'reader' to 'writer' like std::io::copy, through a buffer of IO_BUFFER_LENGTH
+pub fn copy<R: Read + ?Sized, W: Write + ?Sized>(reader: &mut R, writer: &mut W) -> std::io::Result<u64> {
+    let mut buffer = vec![0u8; IO_BUFFER_LENGTH];
+    let mut copied = 0;
+    loop {
+        let n = match reader.read(&mut buffer) {
+            Ok(0) => return Ok(copied),
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&buffer[..n])?;
+        copied += n as u64;
+    }
+}
+
+/// Creates the runtime transfers run on
+pub fn runtime() -> std::io::Result<Runtime> {
+    tokio::runtime::Builder::new()
+        .threaded_scheduler()
+        .enable_io()
+        .enable_time()
+        .thread_name("retain-transfer")
+        .build()
+}
+
+/// The async client used for buffered transfers
+pub fn client() -> Result<reqwest::Client, String> {
+    reqwest::Client::builder()
+        .connect_timeout(CONNECT_TIMEOUT)
+        .build()
+        .map_err(|e| e.to_string())
+}
+
+/// Tells transfers the run was cancelled
+#[derive(Clone)]
+pub struct Cancel(watch::Receiver<bool>);
+
+impl Cancel {
+    /// Cancelled when Ctrl-C is pressed (see interrupt.rs), calling 'on_cancel' right away
+    /// Pressing it again exits the process, since nothing is subscribed to interrupts anymore
+    pub fn on_interrupt<F: FnOnce() + Send + 'static>(on_cancel: F) -> Self {
+        let rx = interrupt::subscribe();
+        let (tx, cancel) = watch::channel(false);
+        // Ends without cancelling if a later run subscribes to interrupts instead
+        std::thread::spawn(move || {
+            if rx.recv().is_ok() {
+                on_cancel();
+                tx.broadcast(true).unwrap_or(());
+            }
+        });
+        Cancel(cancel)
+    }
+
+    /// Never cancelled, for runs that don't handle interrupts
+    pub fn never() -> Self {
+        Cancel(watch::channel(false).1)
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Wraps 'reader' s.t. reading it fails once cancelled, see Cancellable
+    pub fn wrap_read<R: Read>(&self, reader: R) -> Cancellable<R> {
+        Cancellable { inner: reader, cancel: self.clone() }
+    }
+
+    /// Completes once cancelled, never if the run isn't
+    pub async fn cancelled(&self) {
+        let mut rx = self.0.clone();
+        loop {
+            match rx.recv().await {
+                Some(true) => return,
+                Some(false) => (),
+                None => futures::future::pending::<()>().await,
+            }
+        }
+    }
+}
+
+/// A reader that fails once its run is cancelled, stopping the transfer it feeds
+/// The error is not ErrorKind::Interrupted, which e.g. std::io::copy would retry
+pub struct Cancellable<R> {
+    inner: R,
+    cancel: Cancel,
+}
+
+impl<R: Read> Read for Cancellable<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.cancel.is_cancelled() {
+            return Err(std::io::Error::new(std::io::ErrorKind::Other, "cancelled"));
+        }
+        self.inner.read(buf)
+    }
+}
+
+/// Spawns 'task' for every item, with at most 'limit' running at a time
+/// Returns when every task is done, or when the tasks in progress are done after being cancelled
+/// A panic in a task is passed on, like it would be if the task ran on the calling thread
+pub async fn for_each_bounded<T, F, Fut>(items: Vec<T>, limit: usize, cancel: &Cancel, task: F)
+    where F: Fn(T) -> Fut,
+          Fut: Future<Output = ()> + Send + 'static {
+    stream::iter(items)
+        .take_until(cancel.cancelled())
+        .map(|item| tokio::spawn(task(item)))
+        .buffer_unordered(limit)
+        .for_each(|result| async {
+            if let Err(e) = result {
+                if e.is_panic() {
+                    std::panic::resume_unwind(e.into_panic());
+                }
+                printcoln(Color::Red, format!("Transfer stopped unexpectedly ({})", e));
+            }
+        })
+        .await;
+}
+
+/// Runs blocking work (file I/O, compression, encryption and the blocking B2 calls) on tokio's blocking threads
+/// A panic is passed on, like it would be if the work ran on the calling thread
+pub async fn blocking<F, T>(work: F) -> T
+    where F: FnOnce() -> T + Send + 'static,
+          T: Send + 'static {
+    match tokio::task::spawn_blocking(work).await {
+        Ok(v) => v,
+        Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+        Err(e) => panic!("Blocking work stopped unexpectedly ({})", e),
+    }
+}
+
+/// The body of a buffered upload, sent in chunks of CHUNK_SIZE
+/// Each chunk waits for the upload limit (if any) and advances the progress bars once it is sent
+pub fn body(data: Arc<Vec<u8>>, limit: Option<Arc<Mutex<TokenBucket>>>, counter: ProgressCounter) -> reqwest::Body {
+    let chunks = stream::unfold(0, move |sent| {
+        let data = data.clone();
+        let limit = limit.clone();
+        let counter = counter.clone();
+        async move {
+            if sent >= data.len() {
+                return None;
+            }
+            let n = throttle::acquire(&limit, CHUNK_SIZE.min(data.len() - sent)).await;
+            counter.add(n as u64);
+            Some((Ok::<_, std::io::Error>(data[sent..sent+n].to_vec()), sent+n))
+        }
+    });
+    reqwest::Body::wrap_stream(chunks)
+}
+
+/// Reads the body of a buffered download into memory, advancing the progress bars as it arrives
+pub async fn read_body(mut response: reqwest::Response, counter: &ProgressCounter) -> Result<Vec<u8>, reqwest::Error> {
+    let mut data = Vec::with_capacity(response.content_length().unwrap_or(0) as usize);
+    while let Some(chunk) = response.chunk().await? {
+        counter.add(chunk.len() as u64);
+        data.extend_from_slice(&chunk);
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::transfer::{copy, for_each_bounded, runtime, Cancel};
+    use std::io::Read;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use tokio::sync::watch;
+
+    #[test]
+    fn test_for_each_bounded() {
+        let mut runtime = runtime().unwrap();
+        let (cancel_tx, cancel_rx) = watch::channel(false);
+        let cancel = Cancel(cancel_rx);
+        let running = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+        let done = Arc::new(AtomicUsize::new(0));
+        let task = |_: usize| {
+            let (running, most, done) = (running.clone(), most.clone(), done.clone());
+            async move {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                most.fetch_max(now, Ordering::SeqCst);
+                tokio::time::delay_for(Duration::from_millis(5)).await;
+                running.fetch_sub(1, Ordering::SeqCst);
+                done.fetch_add(1, Ordering::SeqCst);
+            }
+        };
+        runtime.block_on(for_each_bounded((0..20).collect(), 3, &cancel, task));
+        assert_eq!(done.load(Ordering::SeqCst), 20);
+        assert!(most.load(Ordering::SeqCst) <= 3);
+
+        // Nothing is started once cancelled
+        cancel_tx.broadcast(true).unwrap();
+        assert!(cancel.is_cancelled());
+        runtime.block_on(for_each_bounded((0..20).collect(), 3, &cancel, task));
+        assert_eq!(done.load(Ordering::SeqCst), 20);
+    }
+
+    #[test]
+    fn test_cancellable() {
+        let (cancel_tx, cancel_rx) = watch::channel(false);
+        let cancel = Cancel(cancel_rx);
+        let mut reader = cancel.wrap_read(&[1u8, 2, 3, 4][..]);
+        let mut buf = [0u8; 2];
+        assert_eq!(reader.read(&mut buf).unwrap(), 2);
+        cancel_tx.broadcast(true).unwrap();
+        // Not retried by copy, unlike ErrorKind::Interrupted
+        assert!(copy(&mut reader, &mut Vec::new()).is_err());
+    }
+}
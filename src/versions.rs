@@ -0,0 +1,148 @@
+//! Point-in-time lookup of remote files
+//!
+//! B2 keeps every uploaded version of a file until it is deleted. Uploading a file with an existing name
+//! adds a new version, and hiding a file adds a "hide" marker on top of its versions
+//! This lets us find what a file looked like at any earlier point, including manifest.json itself,
+//! as long as the old versions haven't been deleted (e.g. by 'clean delete' or lifecycle rules)
+
+use std::collections::HashMap;
+use std::error::Error;
+use raze::api::B2Auth;
+use reqwest::blocking::Client;
+use crate::b2::{self, FileVersion};
+use crate::budget::Budget;
+
+// Amount of versions to retrieve per call. 10000 is the most B2 returns for the price of one call
+const VERSIONS_PER_CALL: u32 = 10000;
+
+/// Lists every version of every file in the bucket
+pub fn list_all_file_versions(client: &Client, auth: &B2Auth, budget: &Budget, bucket_id: &str) -> Result<Vec<FileVersion>, Box<dyn Error>> {
+    let mut versions = Vec::new();
+    let mut start_name = None;
+    let mut start_id = None;
+    loop {
+        budget.spend("b2_list_file_versions")?;
+        let list = b2::b2_list_file_versions(client, auth, bucket_id, start_name.as_deref(), start_id.as_deref(), VERSIONS_PER_CALL)?;
+        versions.extend(list.files);
+        if list.next_file_name.is_none() {
+            break;
+        }
+        start_name = list.next_file_name;
+        start_id = list.next_file_id;
+    }
+    Ok(versions)
+}
+
+/// Lists the uploaded versions of a single file, newest first
+pub fn list_versions_of(client: &Client, auth: &B2Auth, budget: &Budget, bucket_id: &str, name: &str) -> Result<Vec<FileVersion>, Box<dyn Error>> {
+    let mut versions = Vec::new();
+    let mut start_id = None;
+    loop {
+        budget.spend("b2_list_file_versions")?;
+        // Listing starts at 'name' and continues with the versions of the files after it
+        let list = b2::b2_list_file_versions(client, auth, bucket_id, Some(name), start_id.as_deref(), VERSIONS_PER_CALL)?;
+        let done = list.next_file_name.as_deref() != Some(name);
+        versions.extend(list.files.into_iter().filter(|v| v.file_name == name && v.action == "upload"));
+        if done {
+            break;
+        }
+        start_id = list.next_file_id;
+    }
+    versions.sort_by(|a, b| b.upload_timestamp.cmp(&a.upload_timestamp));
+    Ok(versions)
+}
+
+/// Deletes all but the newest 'keep' versions of the remote manifest, named 'name'
+/// Returns the amount of versions deleted
+pub fn prune_manifest_history(client: &Client, auth: &B2Auth, budget: &Budget, bucket_id: &str, name: &str, keep: u32) -> Result<usize, Box<dyn Error>> {
+    let old = older_than_newest(list_versions_of(client, auth, budget, bucket_id, name)?, keep as usize);
+    for version in &old {
+        budget.record("b2_delete_file_version", 1);
+        raze::api::b2_delete_file_version(client, auth, version.file_name.clone(), version.file_id.clone())
+            .map_err(|e| format!("failed to delete {} ({:?})", version.file_id, e))?;
+    }
+    Ok(old.len())
+}
+
+/// Returns every version except the newest 'keep' ones
+pub fn older_than_newest(mut versions: Vec<FileVersion>, keep: usize) -> Vec<FileVersion> {
+    versions.sort_by(|a, b| b.upload_timestamp.cmp(&a.upload_timestamp));
+    if versions.len() > keep {
+        versions.split_off(keep)
+    } else {
+        Vec::new()
+    }
+}
+
+/// For each file name, finds the version that was current at 'as_of' (milliseconds since Unix Epoch)
+/// Files that didn't exist yet or were hidden at that time are not included
+pub fn versions_as_of(versions: Vec<FileVersion>, as_of: u64) -> HashMap<String, FileVersion> {
+    // Latest upload or hide of each file that happened at or before 'as_of'
+    let mut latest: HashMap<String, FileVersion> = HashMap::new();
+    for version in versions {
+        if version.upload_timestamp > as_of || (version.action != "upload" && version.action != "hide") {
+            continue;
+        }
+        let newer = match latest.get(&version.file_name) {
+            Some(v) => version.upload_timestamp > v.upload_timestamp,
+            None => true,
+        };
+        if newer {
+            latest.insert(version.file_name.clone(), version);
+        }
+    }
+    latest.retain(|_, v| v.action == "upload");
+    latest
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::versions::{versions_as_of, older_than_newest};
+    use crate::b2::FileVersion;
+
+    fn version(name: &str, id: &str, action: &str, timestamp: u64) -> FileVersion {
+        FileVersion {
+            file_id: id.to_string(),
+            file_name: name.to_string(),
+            action: action.to_string(),
+            content_length: 0,
+            upload_timestamp: timestamp,
+        }
+    }
+
+    #[test]
+    fn test_versions_as_of() {
+        let versions = vec![
+            version("a", "a1", "upload", 100),
+            version("a", "a2", "upload", 200),
+            version("a", "a3", "upload", 300),
+            version("b", "b1", "upload", 100),
+            version("b", "b2", "hide", 150),
+            version("c", "c1", "upload", 250),
+            version("d", "d1", "start", 50),
+        ];
+        let at = versions_as_of(versions.clone(), 250);
+        assert_eq!(at["a"].file_id, "a2");
+        assert!(!at.contains_key("b")); // Hidden
+        assert_eq!(at["c"].file_id, "c1");
+        assert!(!at.contains_key("d")); // Unfinished large file
+
+        let at = versions_as_of(versions, 120);
+        assert_eq!(at["a"].file_id, "a1");
+        assert_eq!(at["b"].file_id, "b1");
+        assert!(!at.contains_key("c")); // Didn't exist yet
+    }
+
+    #[test]
+    fn test_older_than_newest() {
+        let versions = vec![
+            version("manifest.json", "m1", "upload", 100),
+            version("manifest.json", "m3", "upload", 300),
+            version("manifest.json", "m2", "upload", 200),
+        ];
+        let old = older_than_newest(versions.clone(), 2);
+        assert_eq!(old.len(), 1);
+        assert_eq!(old[0].file_id, "m1");
+        assert!(older_than_newest(versions, 5).is_empty());
+    }
+}
@@ -0,0 +1,149 @@
+//! Windows path handling
+//!
+//! Windows limits regular paths to 260 characters (MAX_PATH). Longer paths only work in the extended-length
+//! form, prefixed with \\?\ (or \\?\UNC\ for network shares), which also disables any other path processing
+//! Files are opened using that form, but the manifest keeps the regular form s.t. paths look the same everywhere
+//!
+//! When encryption is disabled, the path is used as the name in B2 instead of a mask. The drive or share is kept
+//! as the first folder, e.g. C:\Users\me\file.txt is stored as C/Users/me/file.txt, s.t. files with the same path on
+//! different drives don't collide and B2's web view shows them as folders
+//! Files uploaded before this keep their name (e.g. C:/Users/me/file.txt) in their manifest entry, and
+//! path_from_remote_name reads both forms, s.t. existing backups aren't uploaded again and can be rebuilt
+//!
+//! The conversions only depend on the form of the path, s.t. they can be tested on any platform
+
+const VERBATIM_PREFIX: &str = r"\\?\";
+const VERBATIM_UNC_PREFIX: &str = r"\\?\UNC\";
+
+/// Returns the form of 'path' that is used to access the file
+/// On Windows this is the extended-length form of absolute paths, elsewhere it is 'path' itself
+pub fn extended(path: &str) -> String {
+    if cfg!(windows) {
+        to_extended(path)
+    } else {
+        path.to_string()
+    }
+}
+
+/// Returns the regular form of a path, which is how paths are stored in the manifest
+/// Paths that aren't in the extended-length form are returned as is
+pub fn normalize(path: &str) -> String {
+    if let Some(rest) = path.strip_prefix(VERBATIM_UNC_PREFIX) {
+        format!(r"\\{}", rest)
+    } else if let Some(rest) = path.strip_prefix(VERBATIM_PREFIX) {
+        rest.to_string()
+    } else {
+        path.to_string()
+    }
+}
+
+/// Name of a file in B2 when names aren't masked
+pub fn remote_name(path: &str) -> String {
+    remote_name_for(path, cfg!(windows))
+}
+
+/// Like remote_name, 'windows' selects whether 'path' is a Windows path
+pub fn remote_name_for(path: &str, windows: bool) -> String {
+    let path = normalize(path);
+    if !windows {
+        // On Unix-whatever, everything is prefixed with the '/' root
+        // B2's web interface will not emulate folders unless we strip it
+        return path.get(1..).unwrap_or("").to_string();
+    }
+    if let Some(rest) = path.strip_prefix(r"\\") {
+        format!("UNC/{}", rest.replace('\\', "/"))
+    } else if has_drive_letter(&path) {
+        // C:\dir -> C/dir
+        let rest = path[2..].trim_start_matches(|c| c == '\\' || c == '/');
+        format!("{}/{}", &path[..1], rest.replace('\\', "/"))
+    } else {
+        path.replace('\\', "/") // Standardize separators
+    }
+}
+
+/// Name of a file in B2 before drives and shares were folders, e.g. C:/Users/me/file.txt. Only differs on Windows
+pub fn legacy_remote_name(path: &str) -> String {
+    match cfg!(windows) {
+        true => normalize(path).replace('\\', "/"),
+        false => remote_name(path),
+    }
+}
+
+/// Inverse of remote_name for Windows paths, returns the path a remote name was made from
+/// 'windows' selects how names without a drive or share are interpreted
+pub fn path_from_remote_name(name: &str, windows: bool) -> String {
+    if !windows {
+        return format!("/{}", name);
+    }
+    // Names from before drives were folders are the path with '/' separators, e.g. C:/dir or //server/share
+    if let Some(rest) = name.strip_prefix("UNC/").or_else(|| name.strip_prefix("//")) {
+        return format!(r"\\{}", rest.replace('/', "\\"));
+    }
+    let mut parts = name.splitn(2, '/');
+    match (parts.next(), parts.next()) {
+        (Some(drive), rest) if drive.len() == 1 && drive.chars().all(|c| c.is_ascii_alphabetic()) => {
+            format!(r"{}:\{}", drive, rest.unwrap_or("").replace('/', "\\"))
+        },
+        (Some(drive), rest) if drive.len() == 2 && has_drive_letter(drive) => {
+            format!(r"{}\{}", drive, rest.unwrap_or("").replace('/', "\\"))
+        },
+        _ => name.replace('/', "\\"),
+    }
+}
+
+fn to_extended(path: &str) -> String {
+    if path.starts_with(VERBATIM_PREFIX) {
+        path.to_string()
+    } else if let Some(rest) = path.strip_prefix(r"\\") {
+        format!("{}{}", VERBATIM_UNC_PREFIX, rest.replace('/', "\\"))
+    } else if has_drive_letter(path) && path[2..].starts_with(|c| c == '\\' || c == '/') {
+        // Verbatim paths are used as is, so separators must be backslashes
+        format!("{}{}", VERBATIM_PREFIX, path.replace('/', "\\"))
+    } else {
+        // Relative paths can't be made verbatim
+        path.to_string()
+    }
+}
+
+fn has_drive_letter(path: &str) -> bool {
+    let bytes = path.as_bytes();
+    bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':'
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::winpath::{normalize, remote_name_for, path_from_remote_name, to_extended};
+
+    #[test]
+    fn test_remote_names() {
+        let remote_name = |path| remote_name_for(path, true);
+        assert_eq!(remote_name_for("/home/user/file.txt", false), "home/user/file.txt");
+        assert_eq!(remote_name(r"C:\Users\me\file.txt"), "C/Users/me/file.txt");
+        assert_eq!(remote_name(r"D:\file.txt"), "D/file.txt");
+        assert_eq!(remote_name(r"\\?\D:\file.txt"), "D/file.txt");
+        assert_eq!(remote_name(r"\\server\share\file.txt"), "UNC/server/share/file.txt");
+        assert_eq!(remote_name(r"\\?\UNC\server\share\file.txt"), "UNC/server/share/file.txt");
+
+        for path in vec![r"C:\Users\me\file.txt", r"D:\a b\c.txt", r"\\server\share\dir\file"] {
+            assert_eq!(path_from_remote_name(&remote_name(path), true), path);
+        }
+        assert_eq!(path_from_remote_name(&remote_name_for("/home/user/file.txt", false), false), "/home/user/file.txt");
+        // Names from before drives were folders
+        assert_eq!(path_from_remote_name("C:/Users/me/file.txt", true), r"C:\Users\me\file.txt");
+        assert_eq!(path_from_remote_name("//server/share/file.txt", true), r"\\server\share\file.txt");
+    }
+
+    #[test]
+    fn test_extended() {
+        let long = format!(r"C:\{}\file.txt", "a".repeat(300));
+        assert_eq!(to_extended(&long), format!(r"\\?\{}", long));
+        assert_eq!(to_extended("C:/dir/file.txt"), r"\\?\C:\dir\file.txt");
+        assert_eq!(to_extended(r"\\server\share\file.txt"), r"\\?\UNC\server\share\file.txt");
+        assert_eq!(to_extended(r"relative\file.txt"), r"relative\file.txt");
+        assert_eq!(to_extended(r"\\?\C:\file.txt"), r"\\?\C:\file.txt");
+
+        for path in vec![long.as_str(), r"D:\file.txt", r"\\server\share\file.txt"] {
+            assert_eq!(normalize(&to_extended(path)), path);
+        }
+    }
+}
@@ -0,0 +1,83 @@
+//! Extended attribute (xattr) preservation
+//!
+//! The extended attributes of each file are stored in its manifest entry when it is uploaded,
+//! which keeps them encrypted along with the rest of the manifest, and are set again when it is restored
+//! On Linux only the 'user' and 'security' namespaces are kept, e.g. SELinux labels. The 'trusted' and 'system'
+//! namespaces need privileges and are managed by the kernel, e.g. ACLs. On macOS every attribute is kept
+//!
+//! Changing only an attribute doesn't change the modified time, so it is picked up with the next change to the file
+
+/// Reads the extended attributes of the file at 'path' that are backed up, sorted by name
+/// Attributes that can't be read are left out
+#[cfg(unix)]
+pub fn read(path: &str) -> Vec<(String, Vec<u8>)> {
+    let names = match xattr::list(path) {
+        Ok(names) => names,
+        Err(_) => return vec![],
+    };
+    let mut attrs: Vec<(String, Vec<u8>)> = names
+        .filter_map(|name| name.into_string().ok())
+        .filter(|name| is_backed_up(name))
+        .filter_map(|name| match xattr::get(path, &name) {
+            Ok(Some(value)) => Some((name, value)),
+            _ => None,
+        })
+        .collect();
+    attrs.sort();
+    attrs
+}
+
+#[cfg(not(unix))]
+pub fn read(_path: &str) -> Vec<(String, Vec<u8>)> {
+    vec![]
+}
+
+/// Sets the given extended attributes on the file at 'path'
+/// Returns the names of the attributes that couldn't be set, e.g. because it isn't permitted
+#[cfg(unix)]
+pub fn restore(path: &str, attrs: &[(String, Vec<u8>)]) -> Vec<String> {
+    attrs.iter()
+        .filter(|(name, value)| xattr::set(path, name, value).is_err())
+        .map(|(name, _)| name.clone())
+        .collect()
+}
+
+#[cfg(not(unix))]
+pub fn restore(_path: &str, attrs: &[(String, Vec<u8>)]) -> Vec<String> {
+    attrs.iter().map(|(name, _)| name.clone()).collect()
+}
+
+#[cfg(target_os = "linux")]
+fn is_backed_up(name: &str) -> bool {
+    name.starts_with("user.") || name.starts_with("security.")
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn is_backed_up(_name: &str) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::xattrs::{read, restore};
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_xattrs() {
+        let path = std::env::temp_dir().join("retain-test-xattrs");
+        let path = path.to_str().unwrap();
+        std::fs::write(path, b"content").unwrap();
+        // Not every file system supports user attributes
+        if xattr::set(path, "user.retain", b"value").is_err() {
+            return;
+        }
+        let attrs = read(path);
+        assert_eq!(attrs, vec![("user.retain".to_string(), b"value".to_vec())]);
+
+        xattr::remove(path, "user.retain").unwrap();
+        assert!(restore(path, &attrs).is_empty());
+        assert_eq!(read(path), attrs);
+
+        let _ = std::fs::remove_file(path);
+    }
+}